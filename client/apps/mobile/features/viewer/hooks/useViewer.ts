@@ -17,6 +17,8 @@ export function useViewer() {
 
   const wsRef = useRef<WebSocket | null>(null);
   const pcRef = useRef<RTCPeerConnection | null>(null);
+  // このビューアーの ID（繋ぎ直しても同じ ID を送り、ホストにセッションを引き継がせる）
+  const negotiationIdRef = useRef(Math.random().toString(36).slice(2) + Date.now().toString(36));
 
   const connect = useCallback(() => {
     setStatus("Connecting...");
@@ -108,7 +110,8 @@ export function useViewer() {
         ws.send(JSON.stringify({
           type: "offer",
           sdp: offer.sdp,
-          codec: "h264" // Default to h264 as per common config
+          codec: "h264", // Default to h264 as per common config
+          negotiation_id: negotiationIdRef.current,
         }));
       } catch (err) {
         console.error("PC Setup Error:", err);
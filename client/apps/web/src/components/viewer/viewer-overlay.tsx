@@ -354,6 +354,42 @@ export function ViewerOverlay({
             <p>Inbound: {(stats.inbound?.bytesReceived ?? 0) / 1024} KB</p>
             <p>Frames: {stats.inbound?.framesReceived}</p>
             <p>Loss: {stats.inbound?.packetsLost}</p>
+            {stats.captureLatencyMs !== undefined && (
+              <p>Capture to receive: {stats.captureLatencyMs.toFixed(0)} ms</p>
+            )}
+            {stats.host && (
+              <>
+                <p>Host capture drops: {stats.host.captureDropped}</p>
+                <p>Host encoder drops: {(stats.host.encoderDropRate * 100).toFixed(1)}%</p>
+                <p>Network loss: {(stats.host.networkLossRate * 100).toFixed(1)}%</p>
+                <p>Capture: {stats.host.captureFps.toFixed(1)} fps</p>
+                <p>
+                  QP:{" "}
+                  {stats.host.averageQp === null
+                    ? "unavailable"
+                    : `${stats.host.averageQp.toFixed(1)} (max ${stats.host.maxQp})`}
+                </p>
+                {stats.host.captureToEncode && (
+                  <p>
+                    Capture to encode: p50 {stats.host.captureToEncode.p50Ms.toFixed(1)} / p99{" "}
+                    {stats.host.captureToEncode.p99Ms.toFixed(1)} ms
+                  </p>
+                )}
+                {stats.host.encodeToSend && (
+                  <p>
+                    Encode to send: p50 {stats.host.encodeToSend.p50Ms.toFixed(1)} / p99{" "}
+                    {stats.host.encodeToSend.p99Ms.toFixed(1)} ms
+                  </p>
+                )}
+                <p>
+                  Keyframes/min:{" "}
+                  {Object.entries(stats.host.keyframesLastMinute)
+                    .filter(([, count]) => count > 0)
+                    .map(([reason, count]) => `${reason}=${count}`)
+                    .join(", ") || "0"}
+                </p>
+              </>
+            )}
           </div>
         </div>
       )}
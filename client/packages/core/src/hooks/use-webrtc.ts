@@ -8,8 +8,10 @@ import {
   PeerConnectionError,
   setH264Preferences,
   createDataChannel,
+  listenStatsChannel,
   runDataChannel,
   type LlmConfig,
+  type AnalyzePayload,
   runStatsLoop,
   type WebRTCStats,
   makeMediaStreamHandler,
@@ -31,6 +33,8 @@ export interface WebRTCOptions {
   onAnalyzeResultDelta?: (id: string, delta: string) => void;
   onAnalyzeDone?: (id: string) => void;
   onLlmConfig?: (config: LlmConfig) => void;
+  // Text copied on the host; written to the local clipboard when omitted
+  onClipboardText?: (text: string) => void;
 }
 
 export type { WebRTCStats };
@@ -50,6 +54,7 @@ export function useWebRTC(options: WebRTCOptions) {
     onAnalyzeResultDelta,
     onAnalyzeDone,
     onLlmConfig,
+    onClipboardText,
   } = options;
 
   const [connectionState, setConnectionState] = useState<string>("disconnected");
@@ -57,10 +62,13 @@ export function useWebRTC(options: WebRTCOptions) {
   const [stats, setStats] = useState<WebRTCStats>({});
   const [logs, setLogs] = useState<Array<{ time: string; message: string; type: string }>>([]);
 
+  // このビューアーの ID（繋ぎ直しても同じ ID を送り、ホストにセッションを引き継がせる）
+  const negotiationId = useRef(crypto.randomUUID());
+
   // Queue to send signals to the running Effect
   const sendKeyQueue = useRef<Queue.Queue<{ key: string; down: boolean }> | null>(null);
   const screenshotRequestQueue = useRef<Queue.Queue<void> | null>(null);
-  const analyzeRequestQueue = useRef<Queue.Queue<{ id: string; max_edge: number }> | null>(null);
+  const analyzeRequestQueue = useRef<Queue.Queue<AnalyzePayload> | null>(null);
   const mouseClickQueue = useRef<Queue.Queue<{ x: number; y: number; button: string }> | null>(null);
 
   const debugActionQueue = useRef<Queue.Queue<"close_ws" | "close_pc"> | null>(null);
@@ -103,10 +111,10 @@ export function useWebRTC(options: WebRTCOptions) {
   }, [addLog]);
 
   const requestAnalyze = useCallback(
-    (id: string, max_edge: number = 512) => {
+    (id: string, max_edge: number = 512, model?: string) => {
       if (analyzeRequestQueue.current) {
         Effect.runFork(
-          Queue.offer(analyzeRequestQueue.current, { id, max_edge }).pipe(
+          Queue.offer(analyzeRequestQueue.current, { id, max_edge, model }).pipe(
             Effect.catchAll(() => Effect.void),
           ),
         );
@@ -200,7 +208,7 @@ export function useWebRTC(options: WebRTCOptions) {
 
       const keyQ = yield* Queue.unbounded<{ key: string; down: boolean }>();
       const screenQ = yield* Queue.unbounded<void>();
-      const analyzeQ = yield* Queue.unbounded<{ id: string; max_edge: number }>();
+      const analyzeQ = yield* Queue.unbounded<AnalyzePayload>();
       const mouseClickQ = yield* Queue.unbounded<{ x: number; y: number; button: string }>();
 
       const debugQ = yield* Queue.unbounded<"close_ws" | "close_pc">();
@@ -275,6 +283,7 @@ export function useWebRTC(options: WebRTCOptions) {
 
       // 4. Data Channel (Creation)
       const dc = yield* createDataChannel(pc);
+      yield* listenStatsChannel(pc, (host) => setStats((prev) => ({ ...prev, host })));
 
       // --- Flows ---
 
@@ -284,7 +293,12 @@ export function useWebRTC(options: WebRTCOptions) {
         yield* Effect.promise(() => pc.setLocalDescription(offer));
         addLog("Offerを作成・設定しました", "success");
 
-        yield* sendWs({ type: "offer", sdp: offer.sdp, codec });
+        yield* sendWs({
+          type: "offer",
+          sdp: offer.sdp,
+          codec,
+          negotiation_id: negotiationId.current,
+        });
         addLog("Offerを送信しました", "success");
       });
 
@@ -409,6 +423,18 @@ export function useWebRTC(options: WebRTCOptions) {
           addLog("LLM設定受信", "success");
           onLlmConfig?.(config);
         },
+        (host) => setStats((prev) => ({ ...prev, host })),
+        (text) => {
+          if (onClipboardText) {
+            onClipboardText(text);
+            return;
+          }
+          // Writing fails while the page is not focused; the next copy on the host is sent again
+          navigator.clipboard?.writeText(text).then(
+            () => addLog("ホストのクリップボードを受信", "success"),
+            (e) => console.warn("Failed to write the host clipboard text:", e),
+          );
+        },
       ).pipe(
         // Retry logic for DataChannel
         Effect.retry(Schedule.fixed("1 second")),
@@ -418,7 +444,9 @@ export function useWebRTC(options: WebRTCOptions) {
       );
 
       // Stats Loop (Independent)
-      const handleStats = runStatsLoop(pc, setStats).pipe(
+      const handleStats = runStatsLoop(pc, (next) =>
+        setStats((prev) => ({ ...next, host: prev.host })),
+      ).pipe(
         // Retry logic for Stats
         Effect.retry(Schedule.fixed("1 second")),
         Effect.catchAll((e) => Effect.sync(() => console.error("Stats Error", e))),
@@ -497,6 +525,7 @@ export function useWebRTC(options: WebRTCOptions) {
     onAnalyzeResultDelta,
     onAnalyzeDone,
     onLlmConfig,
+    onClipboardText,
     useMock,
   ]);
 
@@ -1,5 +1,6 @@
 import { Effect, Queue, Schedule, Duration } from "effect";
 import * as v from "valibot";
+import type { HostVideoStats, LatencyPercentiles } from "./stats";
 
 export const createDataChannel = (pc: RTCPeerConnection, label: string = "input") =>
   Effect.acquireRelease(
@@ -27,6 +28,29 @@ export const LlmConfigSchema = v.object({
 
 export type LlmConfig = v.InferOutput<typeof LlmConfigSchema>;
 
+// model: name registered with hostd --llm-model (the default model when omitted)
+export type AnalyzePayload = { id: string; max_edge: number; model?: string };
+
+const LatencyPercentilesSchema = v.object({
+  p50_ms: v.number(),
+  p95_ms: v.number(),
+  p99_ms: v.number(),
+});
+
+const VideoStatsPayloadSchema = v.object({
+  frames: v.number(),
+  encoder_dropped: v.number(),
+  encoder_drop_rate: v.number(),
+  network_loss_rate: v.number(),
+  capture_fps: v.number(),
+  keyframes_last_minute: v.optional(v.record(v.string(), v.number()), {}),
+  average_qp: v.optional(v.nullable(v.number()), null),
+  max_qp: v.optional(v.nullable(v.number()), null),
+  capture_dropped: v.optional(v.number(), 0),
+  capture_to_encode: v.optional(v.nullable(LatencyPercentilesSchema), null),
+  encode_to_send: v.optional(v.nullable(LatencyPercentilesSchema), null),
+});
+
 const IncomingMessageSchema = v.object({
   SCREENSHOT_METADATA: v.optional(
     v.object({
@@ -50,7 +74,24 @@ const IncomingMessageSchema = v.object({
       id: v.string(),
     }),
   ),
+  VIDEO_STATS: v.optional(
+    v.object({
+      payload: VideoStatsPayloadSchema,
+    }),
+  ),
+  SERVICE_ERROR: v.optional(
+    v.object({
+      service: v.string(),
+      message: v.string(),
+    }),
+  ),
   Pong: v.optional(v.unknown()),
+  // Text copied on the host (only when the host runs with --clipboard-sync)
+  ClipboardText: v.optional(
+    v.object({
+      text: v.string(),
+    }),
+  ),
   LlmConfigResponse: v.optional(
     v.object({
       config: LlmConfigSchema,
@@ -58,11 +99,60 @@ const IncomingMessageSchema = v.object({
   ),
 });
 
+const toLatencyPercentiles = (
+  latency: v.InferOutput<typeof LatencyPercentilesSchema> | null,
+): LatencyPercentiles | null =>
+  latency && { p50Ms: latency.p50_ms, p95Ms: latency.p95_ms, p99Ms: latency.p99_ms };
+
+const toHostVideoStats = (
+  payload: v.InferOutput<typeof VideoStatsPayloadSchema>,
+): HostVideoStats => ({
+  frames: payload.frames,
+  encoderDropped: payload.encoder_dropped,
+  encoderDropRate: payload.encoder_drop_rate,
+  networkLossRate: payload.network_loss_rate,
+  captureFps: payload.capture_fps,
+  keyframesLastMinute: payload.keyframes_last_minute,
+  averageQp: payload.average_qp,
+  maxQp: payload.max_qp,
+  captureDropped: payload.capture_dropped,
+  captureToEncode: toLatencyPercentiles(payload.capture_to_encode),
+  encodeToSend: toLatencyPercentiles(payload.encode_to_send),
+});
+
+// ホストが作る "stats" チャネル（再送なし）で届く統計を受け取る
+// Offer を送る前に登録しておく（開いていなければホストは input チャネルで送る）
+export const listenStatsChannel = (
+  pc: RTCPeerConnection,
+  onHostStats: (stats: HostVideoStats) => void,
+) =>
+  Effect.acquireRelease(
+    Effect.sync(() => {
+      const onMessage = (event: MessageEvent) => {
+        if (typeof event.data !== "string") return;
+        try {
+          const msg = v.parse(IncomingMessageSchema, JSON.parse(event.data));
+          if (msg.VIDEO_STATS) onHostStats(toHostVideoStats(msg.VIDEO_STATS.payload));
+        } catch (e) {
+          console.error("Failed to parse stats channel message:", e);
+        }
+      };
+      const onDataChannel = (event: RTCDataChannelEvent) => {
+        if (event.channel.label === "stats") {
+          event.channel.addEventListener("message", onMessage);
+        }
+      };
+      pc.addEventListener("datachannel", onDataChannel);
+      return onDataChannel;
+    }),
+    (onDataChannel) => Effect.sync(() => pc.removeEventListener("datachannel", onDataChannel)),
+  );
+
 export const runDataChannel = (
   dc: RTCDataChannel,
   keyQ: Queue.Queue<{ key: string; down: boolean }>,
   screenQ: Queue.Queue<void>,
-  analyzeQ: Queue.Queue<{ id: string; max_edge: number }>,
+  analyzeQ: Queue.Queue<AnalyzePayload>,
   mouseClickQ: Queue.Queue<{ x: number; y: number; button: string }>,
   onOpen: () => void,
   onScreenshot: (blob: Blob, meta: { id: string; format: string; size: number }) => void,
@@ -72,6 +162,8 @@ export const runDataChannel = (
   getLlmConfigQ: Queue.Queue<void>,
   updateLlmConfigQ: Queue.Queue<LlmConfig>,
   onLlmConfig: (config: LlmConfig) => void,
+  onHostStats?: (stats: HostVideoStats) => void,
+  onClipboardText?: (text: string) => void,
 ) =>
   Effect.gen(function* () {
     const waitForOpen = Effect.async<void>((resume) => {
@@ -155,10 +247,14 @@ export const runDataChannel = (
       Effect.tap((payload) =>
         Effect.sync(() => {
           if (dc.readyState === "open") {
-            // Send AnalyzeRequest with ID and max_edge
+            // Send AnalyzeRequest with ID, max_edge and the optional model name
             dc.send(
               JSON.stringify({
-                AnalyzeRequest: { id: payload.id, max_edge: payload.max_edge },
+                AnalyzeRequest: {
+                  id: payload.id,
+                  max_edge: payload.max_edge,
+                  model: payload.model,
+                },
               }),
             );
           }
@@ -238,6 +334,15 @@ export const runDataChannel = (
             } else if (msg.LlmConfigResponse) {
               console.log("LlmConfig received:", msg.LlmConfigResponse.config);
               onLlmConfig(msg.LlmConfigResponse.config);
+            } else if (msg.VIDEO_STATS) {
+              onHostStats?.(toHostVideoStats(msg.VIDEO_STATS.payload));
+            } else if (msg.ClipboardText) {
+              onClipboardText?.(msg.ClipboardText.text);
+            } else if (msg.SERVICE_ERROR) {
+              console.warn(
+                `Host ${msg.SERVICE_ERROR.service} is unavailable:`,
+                msg.SERVICE_ERROR.message,
+              );
             } else if (msg.Pong) {
               // Handle Pong if needed
             }
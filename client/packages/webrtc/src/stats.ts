@@ -1,5 +1,8 @@
 import { Effect, Schedule, Duration } from "effect";
 
+// 1900 年（NTP）から 1970 年（UNIX）までのミリ秒
+const NTP_UNIX_OFFSET_MS = 2_208_988_800_000;
+
 export interface WebRTCStats {
   inbound?: {
     bytesReceived?: number;
@@ -11,6 +14,40 @@ export interface WebRTCStats {
     framesDropped?: number;
     freezeCount?: number;
   };
+  // ホスト側が VIDEO_STATS で通知するドロップ統計
+  host?: HostVideoStats;
+  // ホストのキャプチャから受信までの遅延（abs-capture-time がネゴシエートされた場合のみ）
+  // ホストとクライアントの時計のずれがそのまま誤差になる
+  captureLatencyMs?: number;
+}
+
+export interface HostVideoStats {
+  frames: number;
+  encoderDropped: number;
+  // CPU/GPU が追いつかずにホストで捨てたフレームの割合 (0-1)
+  encoderDropRate: number;
+  // 受信側が報告したパケットロス率 (0-1)
+  networkLossRate: number;
+  // ホストのキャプチャから届いたフレームレート（0 が続く場合はキャプチャが止まっている）
+  captureFps: number;
+  // 直近 1 分間に送出したキーフレームの理由ごとの数（pli / reconnect / periodic など）
+  keyframesLastMinute: Record<string, number>;
+  // エンコードしたフレームの平均・最大 QP（エンコーダーが報告しない場合は null）
+  // 上限 (51) 近くに張り付いている場合はビットレートが内容に対して足りない
+  averageQp: number | null;
+  maxQp: number | null;
+  // キャプチャ側で捨てられたフレーム数（エンコーダー側の encoderDropped とは別に数える）
+  captureDropped: number;
+  // ホストでのキャプチャからエンコード完了まで、エンコード完了から送出までの遅延の分布（ミリ秒）
+  // 平均では埋もれる詰まりは p99 に現れる。フレームがない区間は null
+  captureToEncode: LatencyPercentiles | null;
+  encodeToSend: LatencyPercentiles | null;
+}
+
+export interface LatencyPercentiles {
+  p50Ms: number;
+  p95Ms: number;
+  p99Ms: number;
 }
 
 export const runStatsLoop = (pc: RTCPeerConnection, onStats: (stats: WebRTCStats) => void) =>
@@ -39,7 +76,16 @@ export const runStatsLoop = (pc: RTCPeerConnection, onStats: (stats: WebRTCStats
         }
       });
 
-      onStats({ inbound, track });
+      // captureTimestamp は送信側の壁時計（NTP 基準のミリ秒）、timestamp は受信時刻（UNIX 基準のミリ秒）
+      let captureLatencyMs: number | undefined;
+      const source = receiver.getSynchronizationSources()[0] as
+        | (RTCRtpSynchronizationSource & { captureTimestamp?: number })
+        | undefined;
+      if (source?.captureTimestamp !== undefined) {
+        captureLatencyMs = source.timestamp - (source.captureTimestamp - NTP_UNIX_OFFSET_MS);
+      }
+
+      onStats({ inbound, track, captureLatencyMs });
     }),
     Schedule.spaced(Duration.seconds(2)),
   );
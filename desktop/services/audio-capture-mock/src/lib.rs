@@ -11,92 +11,6 @@ const SAMPLES_PER_FRAME: usize = 480; // 48000Hz * 10ms / 1000
 const SAMPLES_PER_FRAME_STEREO: usize = SAMPLES_PER_FRAME * 2; // 960
 const TIMER_RESOLUTION_MS: u32 = 1;
 
-/// 線形補間によるリサンプリング（任意Hz → 48kHz）
-fn resample_linear(samples: &[f32], src_rate: u32, dst_rate: u32, channels: u16) -> Vec<f32> {
-    if src_rate == dst_rate {
-        return samples.to_vec();
-    }
-
-    let ratio = src_rate as f64 / dst_rate as f64;
-    let src_frames = samples.len() / channels as usize;
-    let dst_frames = (src_frames as f64 / ratio).ceil() as usize;
-
-    let mut output = Vec::with_capacity(dst_frames * channels as usize);
-
-    for dst_frame_idx in 0..dst_frames {
-        let src_pos = dst_frame_idx as f64 * ratio;
-        let src_frame_idx = src_pos.floor() as usize;
-        let frac = src_pos - src_frame_idx as f64;
-
-        if src_frame_idx + 1 >= src_frames {
-            // 最後のフレームはコピー
-            for ch in 0..channels as usize {
-                let idx = src_frame_idx * channels as usize + ch;
-                output.push(samples.get(idx).copied().unwrap_or(0.0));
-            }
-        } else {
-            // 線形補間: sample0 + (sample1 - sample0) * frac
-            for ch in 0..channels as usize {
-                let idx0 = src_frame_idx * channels as usize + ch;
-                let idx1 = (src_frame_idx + 1) * channels as usize + ch;
-
-                let sample0 = samples[idx0];
-                let sample1 = samples[idx1];
-                let interpolated = sample0 + (sample1 - sample0) * frac as f32;
-
-                output.push(interpolated);
-            }
-        }
-    }
-
-    info!(
-        "Resampled {}Hz → 48000Hz ({} frames → {} frames)",
-        src_rate, src_frames, dst_frames
-    );
-
-    output
-}
-
-/// チャネル数を変換（ステレオに統一）
-fn convert_to_stereo(samples: &[f32], src_channels: u16) -> Vec<f32> {
-    match src_channels {
-        2 => samples.to_vec(), // すでにステレオ
-        1 => {
-            // モノラル → ステレオ（両チャンネルに同じ値をコピー）
-            let mut output = Vec::with_capacity(samples.len() * 2);
-            for &sample in samples {
-                output.push(sample);
-                output.push(sample);
-            }
-            info!(
-                "Converted mono to stereo: {} → {} samples",
-                samples.len(),
-                output.len()
-            );
-            output
-        }
-        _ => {
-            // 3ch以上 → ステレオ（最初の2チャンネルのみ使用）
-            let frames = samples.len() / src_channels as usize;
-            let mut output = Vec::with_capacity(frames * 2);
-
-            for frame_idx in 0..frames {
-                let base_idx = frame_idx * src_channels as usize;
-                output.push(samples[base_idx]); // L
-                output.push(samples[base_idx + 1]); // R
-            }
-
-            info!(
-                "Converted {}ch to stereo: {} → {} samples (using first 2 channels)",
-                src_channels,
-                samples.len(),
-                output.len()
-            );
-            output
-        }
-    }
-}
-
 /// WAVファイルを読み込んで10msフレームに分割
 fn load_audio_samples() -> Result<Vec<Vec<f32>>> {
     let cursor = Cursor::new(WAV_DATA);
@@ -159,14 +73,29 @@ fn load_audio_samples() -> Result<Vec<Vec<f32>>> {
 
     // リサンプリング（任意Hz → 48kHz）
     let resampled = if spec.sample_rate != 48000 {
-        resample_linear(&all_samples, spec.sample_rate, 48000, spec.channels)
+        let resampled =
+            audio_dsp::resample_linear(&all_samples, spec.sample_rate, 48000, spec.channels);
+        info!(
+            "Resampled {}Hz → 48000Hz ({} → {} samples)",
+            spec.sample_rate,
+            all_samples.len(),
+            resampled.len()
+        );
+        resampled
     } else {
         all_samples
     };
 
     // チャネル変換（任意ch → 2ch）
     let stereo_samples = if spec.channels != 2 {
-        convert_to_stereo(&resampled, spec.channels)
+        let stereo = audio_dsp::convert_to_stereo(&resampled, spec.channels);
+        info!(
+            "Converted {}ch to stereo: {} → {} samples",
+            spec.channels,
+            resampled.len(),
+            stereo.len()
+        );
+        stereo
     } else {
         resampled
     };
@@ -256,6 +185,18 @@ impl AudioCaptureService {
                             frame_index = 0;
                             current_timestamp_us = 0;
                         }
+                        Some(AudioCaptureMessage::StartEndpoint { device_id }) => {
+                            info!("Start audio capture (mock) for endpoint: {}", device_id.as_deref().unwrap_or("default"));
+                            is_capturing = true;
+                            frame_index = 0;
+                            current_timestamp_us = 0;
+                        }
+                        Some(AudioCaptureMessage::StartInputDevice { device_id }) => {
+                            info!("Start audio capture (mock) for input device: {}", device_id.as_deref().unwrap_or("default"));
+                            is_capturing = true;
+                            frame_index = 0;
+                            current_timestamp_us = 0;
+                        }
                         Some(AudioCaptureMessage::Stop) => {
                             info!("Stop audio capture (mock)");
                             is_capturing = false;
@@ -0,0 +1,97 @@
+// 音声デバイスが無効になったときのオーディオクライアントの作り直し
+//
+// セッション中に既定のデバイスが切り替わったり、デバイスが抜かれたりすると、
+// GetNextPacketSize / GetBuffer が AUDCLNT_E_DEVICE_INVALIDATED などを返し、以降そのクライアントは使えない。
+// キャプチャを終わらせずに、待ち時間を延ばしながら同じ対象でオーディオクライアントを作り直す。
+// タイムスタンプは全キャプチャスレッド共通の QPC_EPOCH を基準にしているので、作り直した後も
+// 時間軸はそろったまま（止まっていた間はフレームが欠けるだけ）。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use windows::core::HRESULT;
+use windows::Win32::Media::Audio::{
+    AUDCLNT_E_DEVICE_INVALIDATED, AUDCLNT_E_RESOURCES_INVALIDATED, AUDCLNT_E_SERVICE_NOT_RUNNING,
+};
+
+/// 作り直すまでの最初の待ち時間
+const INITIAL_DELAY: Duration = Duration::from_millis(200);
+/// 待ち時間の上限
+const MAX_DELAY: Duration = Duration::from_secs(5);
+/// 前回の作り直しからこれだけ経っていれば、待ち時間を最初に戻す
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// オーディオクライアントを作り直せば続けられるエラーか（デバイスの無効化・切断、音声サービスの再起動）
+pub fn is_device_lost(hr: HRESULT) -> bool {
+    [
+        AUDCLNT_E_DEVICE_INVALIDATED,
+        AUDCLNT_E_RESOURCES_INVALIDATED,
+        AUDCLNT_E_SERVICE_NOT_RUNNING,
+    ]
+    .contains(&hr)
+}
+
+/// 作り直しの待ち時間（続けて失敗するほど長くする）
+#[derive(Debug, Default)]
+pub struct ResetBackoff {
+    attempts: u32,
+    last_reset: Option<Instant>,
+}
+
+impl ResetBackoff {
+    /// 次に作り直すまでの待ち時間
+    pub fn next_delay(&mut self, now: Instant) -> Duration {
+        if self
+            .last_reset
+            .is_some_and(|last| now.saturating_duration_since(last) >= STABLE_AFTER)
+        {
+            self.attempts = 0;
+        }
+        self.last_reset = Some(now);
+        let delay = INITIAL_DELAY
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(MAX_DELAY);
+        self.attempts += 1;
+        delay
+    }
+}
+
+/// `delay` だけ待つ（途中で停止を指示されたら false を返す）
+pub fn sleep_unless_stopped(stop_flag: &AtomicBool, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    while !stop_flag.load(Ordering::Relaxed) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        thread::sleep(remaining.min(Duration::from_millis(50)));
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_resets_when_stable() {
+        let mut backoff = ResetBackoff::default();
+        let t0 = Instant::now();
+        let delays: Vec<Duration> = (0..7)
+            .map(|i| backoff.next_delay(t0 + Duration::from_secs(i)))
+            .collect();
+        assert_eq!(
+            delays,
+            [200, 400, 800, 1600, 3200, 5000, 5000].map(Duration::from_millis)
+        );
+
+        // しばらく安定して動いた後の無効化は最初の待ち時間から
+        assert_eq!(
+            backoff.next_delay(t0 + Duration::from_secs(60)),
+            INITIAL_DELAY
+        );
+
+        assert!(is_device_lost(AUDCLNT_E_DEVICE_INVALIDATED));
+        assert!(!is_device_lost(windows::Win32::Foundation::E_INVALIDARG));
+    }
+}
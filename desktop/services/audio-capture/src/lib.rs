@@ -1,38 +1,144 @@
+mod device_reset;
+mod process_tree;
+mod wav_dump;
+
 use anyhow::{Context, Result};
-use core_types::{AudioCaptureCommandReceiver, AudioCaptureMessage, AudioFrame, AudioFrameSender};
+use audio_dsp::{FrameAccumulator, LinearResampler, Resampler};
+use core_types::{
+    AudioCaptureCommandReceiver, AudioCaptureConfig, AudioCaptureMessage, AudioFrame,
+    AudioFrameSender, LogThrottle, ServiceError,
+};
+use std::mem::ManuallyDrop;
 use std::ptr;
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use std::thread;
-use std::time::Duration;
-use tracing::{debug, error, info};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
 use windows::core::HRESULT;
-use windows::core::{implement, Interface, Ref};
-use windows::Win32::Foundation::{CloseHandle, HANDLE, HWND};
+use windows::core::{implement, Interface, Ref, HSTRING};
+use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+use windows::Win32::Foundation::{
+    CloseHandle, ERROR_NOT_FOUND, E_INVALIDARG, E_NOINTERFACE, E_NOTIMPL, HANDLE, HWND,
+};
 use windows::Win32::Media::Audio::{
-    ActivateAudioInterfaceAsync, IActivateAudioInterfaceAsyncOperation,
-    IActivateAudioInterfaceCompletionHandler, IActivateAudioInterfaceCompletionHandler_Impl,
-    IAudioCaptureClient, IAudioClient, AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED,
-    AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM, AUDCLNT_STREAMFLAGS_LOOPBACK,
+    eCapture, eConsole, eRender, ActivateAudioInterfaceAsync, EDataFlow,
+    IActivateAudioInterfaceAsyncOperation, IActivateAudioInterfaceCompletionHandler,
+    IActivateAudioInterfaceCompletionHandler_Impl, IAudioCaptureClient, IAudioClient, IMMDevice,
+    IMMDeviceEnumerator, MMDeviceEnumerator, AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY,
+    AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+    AUDCLNT_STREAMFLAGS_EVENTCALLBACK, AUDCLNT_STREAMFLAGS_LOOPBACK,
     AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY, AUDIOCLIENT_ACTIVATION_PARAMS,
-    AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+    AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK, DEVICE_STATE_ACTIVE,
     PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE, VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
     WAVEFORMATEX,
 };
 use windows::Win32::Media::Multimedia::WAVE_FORMAT_IEEE_FLOAT;
-use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
-use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
-use windows::Win32::System::Performance::{QueryPerformanceCounter, QueryPerformanceFrequency};
+use windows::Win32::System::Com::StructuredStorage::{PropVariantToStringAlloc, PROPVARIANT};
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoTaskMemFree, CoUninitialize, CLSCTX_ALL,
+    COINIT_MULTITHREADED, STGM_READ,
+};
+use windows::Win32::System::Performance::QueryPerformanceFrequency;
 use windows::Win32::System::Threading::{CreateEventW, SetEvent, WaitForSingleObject, INFINITE};
 use windows::Win32::System::Variant::VT_BLOB;
 use windows::Win32::UI::WindowsAndMessaging::GetWindowThreadProcessId;
 
+use device_reset::{is_device_lost, sleep_unless_stopped, ResetBackoff};
+use process_tree::LateChildWatch;
+use wav_dump::WavDump;
+
+/// キャプチャスレッドのハンドルと停止フラグ
+type CaptureTask = (std::thread::JoinHandle<Result<()>>, Arc<AtomicBool>);
+
+/// イベント駆動時にデータ到着を待つ時間の上限（イベントが来ない環境でもポーリングで拾う）
+const EVENT_WAIT_TIMEOUT_MS: u32 = 10;
+
+/// 送り先が詰まっている間にキャプチャスレッドでためておく 10ms フレームの上限（超えたら古いものから捨てる）
+const MAX_PENDING_AUDIO_FRAMES: usize = 20;
+
+/// プロセスループバックもデバイスループバックも使えない場合に hostd へ伝える理由
+const AUDIO_UNAVAILABLE: &str = "audio capture unavailable on this Windows version";
+
+/// プロセスループバックがこの Windows で未対応（Windows 10 20H1 より前）であることを示すマーカー
+#[derive(Debug)]
+struct ProcessLoopbackUnsupported;
+
+impl std::fmt::Display for ProcessLoopbackUnsupported {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "process loopback is not supported on this Windows version"
+        )
+    }
+}
+
+impl std::error::Error for ProcessLoopbackUnsupported {}
+
+/// キャプチャループが終わった理由
+enum CaptureEnd {
+    /// 停止を指示された
+    Stopped,
+    /// 遅れて起動した子プロセスを含めるために、同じ対象で有効化し直す
+    Reactivate,
+    /// デバイスが無効になった（切り替え・切断）ので、待ってから同じ対象で作り直す
+    DeviceLost(HRESULT),
+}
+
+/// 音声キャプチャの対象
+#[derive(Clone)]
+enum CaptureSource {
+    /// ウィンドウを所有するプロセス（子プロセス含む）の音声
+    Process { hwnd: u64 },
+    /// 指定したレンダーエンドポイントの出力全体（None は既定の出力デバイス）
+    Endpoint { device_id: Option<String> },
+    /// 入力デバイス（None は既定の入力デバイス）
+    InputDevice { device_id: Option<String> },
+}
+
+/// オーディオクライアントの作成先（プロセスループバックは HWND から引いたプロセス ID を持つ）
+enum ClientTarget<'a> {
+    Process { process_id: u32 },
+    Endpoint { device_id: Option<&'a str> },
+    InputDevice { device_id: Option<&'a str> },
+}
+
+impl CaptureSource {
+    /// プロセスループバックなら HWND からプロセス ID を引く
+    fn resolve(&self) -> Result<ClientTarget<'_>> {
+        match self {
+            CaptureSource::Process { hwnd } => {
+                let mut process_id: u32 = 0;
+                unsafe {
+                    GetWindowThreadProcessId(HWND(*hwnd as *mut _), Some(&mut process_id));
+                }
+                if process_id == 0 {
+                    return Err(anyhow::anyhow!("Failed to get process ID from HWND")
+                        .context(ServiceError::WindowGone));
+                }
+                info!("Process ID: {}", process_id);
+                Ok(ClientTarget::Process { process_id })
+            }
+            CaptureSource::Endpoint { device_id } => Ok(ClientTarget::Endpoint {
+                device_id: device_id.as_deref(),
+            }),
+            CaptureSource::InputDevice { device_id } => Ok(ClientTarget::InputDevice {
+                device_id: device_id.as_deref(),
+            }),
+        }
+    }
+}
+
 /// 音声キャプチャサービス
 pub struct AudioCaptureService {
     frame_tx: AudioFrameSender,
     command_rx: AudioCaptureCommandReceiver,
+    config: AudioCaptureConfig,
+    error_tx: Option<mpsc::UnboundedSender<ServiceError>>,
+    dump: Option<WavDump>,
 }
 
 impl AudioCaptureService {
@@ -40,13 +146,42 @@ impl AudioCaptureService {
         Self {
             frame_tx,
             command_rx,
+            config: AudioCaptureConfig::default(),
+            error_tx: None,
+            dump: None,
         }
     }
 
+    /// キャプチャした音声（48kHz ステレオ f32）を `path` の WAV にも書き出す（デバッグ用）
+    /// データ部が `max_bytes` に達したら書き出しをやめる
+    pub fn with_audio_dump(mut self, path: &std::path::Path, max_bytes: u64) -> Result<Self> {
+        self.dump = Some(WavDump::spawn(path, max_bytes)?);
+        Ok(self)
+    }
+
+    /// キャプチャスレッドが失敗した際のエラー（音声キャプチャが使えない等）の通知先を設定
+    pub fn with_error_tx(mut self, error_tx: mpsc::UnboundedSender<ServiceError>) -> Self {
+        self.error_tx = Some(error_tx);
+        self
+    }
+
+    /// バッファ長と待機方法を設定
+    pub fn with_config(mut self, config: AudioCaptureConfig) -> Result<Self> {
+        anyhow::ensure!(
+            AudioCaptureConfig::BUFFER_MS_RANGE.contains(&config.buffer_ms),
+            "Audio buffer must be {}-{}ms, got {}ms",
+            AudioCaptureConfig::BUFFER_MS_RANGE.start(),
+            AudioCaptureConfig::BUFFER_MS_RANGE.end(),
+            config.buffer_ms
+        );
+        self.config = config;
+        Ok(self)
+    }
+
     pub async fn run(mut self) -> Result<()> {
         info!("AudioCaptureService started");
 
-        let mut capture_task: Option<(std::thread::JoinHandle<Result<()>>, Arc<AtomicBool>)> = None;
+        let mut capture_task: Option<CaptureTask> = None;
 
         loop {
             tokio::select! {
@@ -54,21 +189,15 @@ impl AudioCaptureService {
                     match msg {
                         Some(AudioCaptureMessage::Start { hwnd }) => {
                             info!("Start audio capture for HWND: {hwnd}");
-
-                            // 既存のキャプチャタスクを停止
-                            if let Some((handle, stop_flag)) = capture_task.take() {
-                                stop_flag.store(true, Ordering::Relaxed);
-                                let _ = handle.join();
-                            }
-
-                            // 新しいキャプチャタスクを開始
-                            let frame_tx = self.frame_tx.clone();
-                            let stop_flag = Arc::new(AtomicBool::new(false));
-                            let stop_flag_clone = stop_flag.clone();
-                            let handle = thread::spawn(move || {
-                                Self::capture_loop(hwnd, frame_tx, stop_flag_clone)
-                            });
-                            capture_task = Some((handle, stop_flag));
+                            Self::restart_capture(&self.frame_tx, self.config, self.error_tx.clone(), self.dump.clone(), &mut capture_task, CaptureSource::Process { hwnd });
+                        }
+                        Some(AudioCaptureMessage::StartEndpoint { device_id }) => {
+                            info!("Start audio capture for endpoint: {}", device_id.as_deref().unwrap_or("default"));
+                            Self::restart_capture(&self.frame_tx, self.config, self.error_tx.clone(), self.dump.clone(), &mut capture_task, CaptureSource::Endpoint { device_id });
+                        }
+                        Some(AudioCaptureMessage::StartInputDevice { device_id }) => {
+                            info!("Start audio capture for input device: {}", device_id.as_deref().unwrap_or("default"));
+                            Self::restart_capture(&self.frame_tx, self.config, self.error_tx.clone(), self.dump.clone(), &mut capture_task, CaptureSource::InputDevice { device_id });
                         }
                         Some(AudioCaptureMessage::Stop) => {
                             info!("Stop audio capture");
@@ -96,20 +225,111 @@ impl AudioCaptureService {
         Ok(())
     }
 
+    /// 既存のキャプチャスレッドを停止し、新しいソースでキャプチャスレッドを開始
+    fn restart_capture(
+        frame_tx: &AudioFrameSender,
+        config: AudioCaptureConfig,
+        error_tx: Option<mpsc::UnboundedSender<ServiceError>>,
+        dump: Option<WavDump>,
+        capture_task: &mut Option<CaptureTask>,
+        source: CaptureSource,
+    ) {
+        // 既存のキャプチャタスクを停止
+        if let Some((handle, stop_flag)) = capture_task.take() {
+            stop_flag.store(true, Ordering::Relaxed);
+            let _ = handle.join();
+        }
+
+        // 新しいキャプチャタスクを開始
+        let frame_tx = frame_tx.clone();
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let stop_flag_clone = stop_flag.clone();
+        let handle = thread::spawn(move || {
+            let mut reset_backoff = ResetBackoff::default();
+            let mut reset_log = LogThrottle::default();
+            // デバイスが無効になった後は、作り直しに失敗してもデバイスが戻るまで待ち続ける
+            let mut resetting = false;
+            let result = loop {
+                let delay = match Self::capture_loop(
+                    source.clone(),
+                    config,
+                    frame_tx.clone(),
+                    dump.clone(),
+                    stop_flag_clone.clone(),
+                ) {
+                    Ok(CaptureEnd::Reactivate) => {
+                        resetting = false;
+                        continue;
+                    }
+                    Ok(CaptureEnd::DeviceLost(hr)) => {
+                        resetting = true;
+                        let delay = reset_backoff.next_delay(Instant::now());
+                        if let Some(suppressed) = reset_log.check() {
+                            warn!(
+                                "Audio device was invalidated ({}), resetting audio client in {:?} ({} similar suppressed)",
+                                windows::core::Error::from_hresult(hr),
+                                delay,
+                                suppressed
+                            );
+                        }
+                        delay
+                    }
+                    Err(e)
+                        if resetting
+                            && matches!(
+                                ServiceError::from_anyhow(&e),
+                                Some(ServiceError::DeviceError(_))
+                            ) =>
+                    {
+                        let delay = reset_backoff.next_delay(Instant::now());
+                        if let Some(suppressed) = reset_log.check() {
+                            warn!(
+                                "Audio device is not available yet, retrying in {:?}: {:#} ({} similar suppressed)",
+                                delay, e, suppressed
+                            );
+                        }
+                        delay
+                    }
+                    result => break result.map(|_| ()),
+                };
+                if !sleep_unless_stopped(&stop_flag_clone, delay) {
+                    break Ok(());
+                }
+            };
+            // hostd が判別できるエラーは通知する（スレッドの戻り値は誰も待っていないことがある）
+            if let (Err(e), Some(error_tx)) = (&result, &error_tx) {
+                if let Some(service_error) = ServiceError::from_anyhow(e) {
+                    let _ = error_tx.send(service_error.clone());
+                }
+            }
+            result
+        });
+        *capture_task = Some((handle, stop_flag));
+    }
+
     fn capture_loop(
-        hwnd: u64,
+        source: CaptureSource,
+        config: AudioCaptureConfig,
         frame_tx: AudioFrameSender,
+        dump: Option<WavDump>,
         stop_flag: Arc<AtomicBool>,
-    ) -> Result<()> {
-        // HWNDからプロセスIDを取得
-        let mut process_id: u32 = 0;
-        unsafe {
-            GetWindowThreadProcessId(HWND(hwnd as *mut _), Some(&mut process_id));
-        }
-        if process_id == 0 {
-            return Err(anyhow::anyhow!("Failed to get process ID from HWND"));
-        }
-        info!("Process ID: {}", process_id);
+    ) -> Result<CaptureEnd> {
+        // プロセスループバックの場合は先にHWNDからプロセスIDを取得
+        let client_target = source.resolve()?;
+        let process_id = match client_target {
+            ClientTarget::Process { process_id } => Some(process_id),
+            ClientTarget::Endpoint { .. } | ClientTarget::InputDevice { .. } => None,
+        };
+
+        // 有効化の前のプロセスツリー（これより後に増えた子プロセスの音声は入らない）
+        let mut late_child_watch = match (process_id, config.late_child_silence) {
+            (Some(process_id), Some(timeout)) => Some(LateChildWatch::new(
+                timeout,
+                process_tree::process_descendants(process_id),
+                Instant::now(),
+            )),
+            _ => None,
+        };
 
         // COMを初期化
         unsafe {
@@ -153,17 +373,48 @@ impl AudioCaptureService {
             cbSize: 0,
         };
 
-        // ActivateAudioInterfaceAsyncを使用してプロセスループバックモードでオーディオクライアントを取得
-        let audio_client = unsafe {
-            match Self::setup_audio_client(process_id, &wave_format) {
+        // プロセスループバックは ActivateAudioInterfaceAsync、エンドポイントは MMDevice から取得
+        let (audio_client, data_event) = unsafe {
+            let setup_result = match client_target {
+                ClientTarget::Endpoint {
+                    device_id: Some(device_id),
+                } => Self::setup_endpoint_audio_client(device_id, &wave_format, config),
+                ClientTarget::Endpoint { device_id: None } => {
+                    Self::setup_default_endpoint_audio_client(&wave_format, config)
+                }
+                ClientTarget::InputDevice { device_id } => {
+                    Self::setup_input_device_audio_client(device_id, &wave_format, config)
+                }
+                ClientTarget::Process { process_id } => {
+                    Self::setup_process_audio_client(process_id, &wave_format, config)
+                }
+            };
+            match setup_result {
                 Ok(client) => client,
                 Err(e) => {
                     error!("Failed to setup audio client: {:?}", e);
-                    return Err(e);
+                    if ServiceError::from_anyhow(&e).is_some() {
+                        return Err(e);
+                    }
+                    let device = match &source {
+                        CaptureSource::InputDevice { .. } => "audio input device",
+                        _ => "loopback audio client",
+                    };
+                    return Err(e.context(ServiceError::DeviceError(device.to_string())));
                 }
             }
         };
 
+        // 実際に取得されるフォーマット（48kHz/2ch 以外なら送信前に変換する）
+        let capture_rate = wave_format.nSamplesPerSec;
+        let capture_channels = wave_format.nChannels;
+        let mut resampler: Option<Box<dyn Resampler>> = if capture_rate != 48000 {
+            info!("Resampling captured audio {}Hz → 48000Hz", capture_rate);
+            Some(Box::new(LinearResampler::new(capture_rate, 48000, 2)))
+        } else {
+            None
+        };
+
         // キャプチャクライアントを取得
         let capture_client = unsafe {
             audio_client
@@ -172,44 +423,64 @@ impl AudioCaptureService {
         };
 
         // キャプチャを開始
-        unsafe {
-            audio_client
-                .Start()
-                .context("Failed to start audio capture")?;
-        }
-        info!("Audio capture started");
-
-        // 初期QPC値を取得
-        let mut start_qpc: i64 = 0;
-        unsafe {
-            if QueryPerformanceCounter(&mut start_qpc).is_err() {
-                return Err(anyhow::anyhow!("Failed to get initial QPC value"));
+        if let Err(e) = unsafe { audio_client.Start() } {
+            if is_device_lost(e.code()) {
+                return Ok(CaptureEnd::DeviceLost(e.code()));
             }
+            return Err(anyhow::Error::new(e).context("Failed to start audio capture"));
         }
-        let start_qpc = start_qpc as u64;
-        info!("Initial QPC value: {}", start_qpc);
+        info!("Audio capture started");
 
         // 10msフレームサイズ（480サンプル @ 48kHz）
         const FRAME_SIZE_SAMPLES: u32 = 480;
 
-        let mut accumulated_samples: Vec<f32> = Vec::new();
-        let mut last_packet_qpc: u64 = start_qpc;
+        // 送り先が詰まってもキャプチャスレッドは止めず（WASAPI のバッファがあふれないように）、
+        // ためる量に上限を設けて古いフレームから捨てる
+        let mut accumulator = FrameAccumulator::new(FRAME_SIZE_SAMPLES as usize, 48000, 2)
+            .with_max_frames(MAX_PENDING_AUDIO_FRAMES);
+        let mut last_packet_qpc: u64 = 0;
+        let mut qpc_backwards_log = LogThrottle::default();
+        let mut discontinuity_log = LogThrottle::default();
+        let mut backlog_log = LogThrottle::default();
 
         loop {
             if stop_flag.load(Ordering::Relaxed) {
-                return Ok(());
+                return Ok(CaptureEnd::Stopped);
+            }
+            Self::send_ready_frames(&mut accumulator, &frame_tx, dump.as_ref())?;
+            if let (Some(watch), Some(process_id)) = (late_child_watch.as_mut(), process_id) {
+                let added = watch.check(Instant::now(), || {
+                    process_tree::process_descendants(process_id)
+                });
+                if !added.is_empty() {
+                    info!(
+                        "No audio for {:?} and process {} started child processes {:?} after capture began, restarting process loopback to include them",
+                        config.late_child_silence.unwrap_or_default(),
+                        process_id,
+                        added
+                    );
+                    return Ok(CaptureEnd::Reactivate);
+                }
             }
             // GetNextPacketSizeでパケットサイズを確認
-            let next_packet_size = unsafe {
-                capture_client
-                    .GetNextPacketSize()
-                    .context("Failed to get next packet size")?
+            let next_packet_size = match unsafe { capture_client.GetNextPacketSize() } {
+                Ok(size) => size,
+                Err(e) if is_device_lost(e.code()) => return Ok(CaptureEnd::DeviceLost(e.code())),
+                Err(e) => {
+                    return Err(anyhow::Error::new(e).context("Failed to get next packet size"))
+                }
             };
 
             if next_packet_size == 0 {
-                thread::sleep(Duration::from_millis(1));
+                match &data_event {
+                    // データ到着（またはタイムアウト）まで待つ
+                    Some(event) => unsafe {
+                        let _ = WaitForSingleObject(event.0, EVENT_WAIT_TIMEOUT_MS);
+                    },
+                    None => thread::sleep(Duration::from_millis(1)),
+                }
                 if stop_flag.load(Ordering::Relaxed) {
-                    return Ok(());
+                    return Ok(CaptureEnd::Stopped);
                 }
                 continue;
             }
@@ -221,27 +492,44 @@ impl AudioCaptureService {
             let mut device_position = 0u64;
             let mut qpc_position: u64 = 0;
 
-            unsafe {
-                capture_client
-                    .GetBuffer(
-                        &mut buffer,
-                        &mut num_frames_available,
-                        &mut flags,
-                        Some(&mut device_position),
-                        Some(&mut qpc_position as *mut u64),
-                    )
-                    .context("Failed to get buffer")?;
+            let get_buffer = unsafe {
+                capture_client.GetBuffer(
+                    &mut buffer,
+                    &mut num_frames_available,
+                    &mut flags,
+                    Some(&mut device_position),
+                    Some(&mut qpc_position as *mut u64),
+                )
+            };
+            match get_buffer {
+                Ok(()) => {}
+                Err(e) if is_device_lost(e.code()) => return Ok(CaptureEnd::DeviceLost(e.code())),
+                Err(e) => return Err(anyhow::Error::new(e).context("Failed to get buffer")),
             }
 
             // QPCタイミングの検証
             if qpc_position <= last_packet_qpc {
-                info!(
-                    "QPC time went backwards: current={}, last={}",
-                    qpc_position, last_packet_qpc
-                );
+                if let Some(suppressed) = qpc_backwards_log.check() {
+                    info!(
+                        "QPC time went backwards: current={}, last={} ({} similar suppressed)",
+                        qpc_position, last_packet_qpc, suppressed
+                    );
+                }
             }
             last_packet_qpc = qpc_position;
 
+            // グリッチやオーバーランでサンプルが欠落した場合、蓄積中の端数を捨てて
+            // このパケットの qpc_position を基準にタイムスタンプを合わせ直す
+            if flags & (AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32) != 0 {
+                if let Some(suppressed) = discontinuity_log.check() {
+                    warn!(
+                        "Audio data discontinuity at QPC {}, resyncing timestamps ({} similar suppressed)",
+                        qpc_position, suppressed
+                    );
+                }
+                accumulator.resync();
+            }
+
             // サイレントフラグをチェックしてデータを処理
             // bufferをスコープから外すために、データを先にコピー
             let frames_to_process = if (flags & (AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)) == 0
@@ -252,7 +540,7 @@ impl AudioCaptureService {
                 let data_slice = unsafe {
                     std::slice::from_raw_parts(
                         buffer as *const f32,
-                        (num_frames_available * 2) as usize, // ステレオなので2倍
+                        (num_frames_available * capture_channels as u32) as usize,
                     )
                 };
 
@@ -260,6 +548,11 @@ impl AudioCaptureService {
                 let sum_squares: f64 = data_slice.iter().map(|s| (*s as f64) * (*s as f64)).sum();
                 let rms = (sum_squares / data_slice.len() as f64).sqrt();
                 let peak = data_slice.iter().map(|s| s.abs()).fold(0.0f32, f32::max);
+                if peak > 0.0 {
+                    if let Some(watch) = late_child_watch.as_mut() {
+                        watch.on_audible(Instant::now());
+                    }
+                }
 
                 debug!(
                     "GetBuffer: frames={}, RMS={:.6}, peak={:.6}, flags={:#x}",
@@ -280,55 +573,121 @@ impl AudioCaptureService {
 
             // コピーしたデータを処理
             if let Some(data) = frames_to_process {
-                // サンプルを蓄積
-                accumulated_samples.extend_from_slice(&data);
-
-                // 10msフレーム（480サンプル）分がたまったら送信
-                while accumulated_samples.len() >= FRAME_SIZE_SAMPLES as usize * 2 {
-                    let frame_samples: Vec<f32> = accumulated_samples
-                        .drain(..(FRAME_SIZE_SAMPLES as usize * 2))
-                        .collect();
-
-                    // QPCを使用してタイムスタンプを計算
-                    let relative_qpc = qpc_position.saturating_sub(start_qpc);
-                    let time_hns = (relative_qpc as f64 * ticks_to_hns) as i64;
-                    let timestamp_us = (time_hns / 10) as u64; // 100ナノ秒からマイクロ秒へ変換
-
-                    let audio_frame = AudioFrame {
-                        samples: frame_samples,
-                        sample_rate: 48000,
-                        channels: 2,
-                        timestamp_us,
-                    };
+                // ステレオ・48kHz に揃える
+                let data = if capture_channels != 2 {
+                    audio_dsp::convert_to_stereo(&data, capture_channels)
+                } else {
+                    data
+                };
+                let data = match resampler.as_mut() {
+                    Some(resampler) => resampler.process(&data),
+                    None => data,
+                };
 
-                    if let Err(e) = frame_tx.blocking_send(audio_frame) {
-                        error!("Failed to send audio frame: {}", e);
-                        return Err(anyhow::anyhow!("Failed to send audio frame: {}", e));
-                    }
+                // QPCを使用してパケット先頭のタイムスタンプを計算
+                // （映像のキャプチャ時刻 SystemRelativeTime と同じ QPC の時間軸。ゲーム音声とマイクも同じ軸で比べられる）
+                let time_hns = (qpc_position as f64 * ticks_to_hns) as i64;
+                let packet_time_us = (time_hns / 10) as u64; // 100ナノ秒からマイクロ秒へ変換
 
-                    debug!(
-                        "Sent audio frame: {} samples, timestamp: {}us",
-                        FRAME_SIZE_SAMPLES * 2,
-                        timestamp_us
-                    );
+                // サンプルを蓄積し、10msフレーム（480サンプル）分がたまったらループの先頭で送信
+                let dropped = accumulator.extend(&data, packet_time_us);
+                if dropped > 0 {
+                    if let Some(suppressed) = backlog_log.check() {
+                        warn!(
+                            "Audio consumer is not keeping up, dropped {} oldest frames beyond {} pending ({} similar suppressed)",
+                            dropped, MAX_PENDING_AUDIO_FRAMES, suppressed
+                        );
+                    }
                 }
             }
 
             // バッファを解放
-            unsafe {
-                capture_client
-                    .ReleaseBuffer(frames_count)
-                    .context("Failed to release buffer")?;
+            match unsafe { capture_client.ReleaseBuffer(frames_count) } {
+                Ok(()) => {}
+                Err(e) if is_device_lost(e.code()) => return Ok(CaptureEnd::DeviceLost(e.code())),
+                Err(e) => return Err(anyhow::Error::new(e).context("Failed to release buffer")),
+            }
+        }
+    }
+
+    /// そろったフレームを送り先が受け取れるだけ送る（受け取れない分はアキュムレーターに残す）
+    fn send_ready_frames(
+        accumulator: &mut FrameAccumulator,
+        frame_tx: &AudioFrameSender,
+        dump: Option<&WavDump>,
+    ) -> Result<()> {
+        loop {
+            let permit = match frame_tx.try_reserve() {
+                Ok(permit) => permit,
+                Err(mpsc::error::TrySendError::Full(())) => return Ok(()),
+                Err(mpsc::error::TrySendError::Closed(())) => {
+                    error!("Failed to send audio frame: channel closed");
+                    return Err(anyhow::anyhow!(
+                        "Failed to send audio frame: channel closed"
+                    ));
+                }
+            };
+            let Some((samples, timestamp_us)) = accumulator.pop() else {
+                return Ok(());
+            };
+            let audio_frame = AudioFrame {
+                samples,
+                sample_rate: 48000,
+                channels: 2,
+                timestamp_us,
+            };
+            if let Some(dump) = dump {
+                dump.push(&audio_frame);
+            }
+            debug!(
+                "Sent audio frame: {} samples, timestamp: {}us",
+                audio_frame.samples.len(),
+                timestamp_us
+            );
+            permit.send(audio_frame);
+        }
+    }
+
+    /// プロセスループバックでオーディオクライアントを取得する
+    ///
+    /// この Windows がプロセスループバックに未対応なら、既定のレンダーエンドポイントの
+    /// デバイスループバック（ウィンドウ以外の音も含む）で代用する。
+    unsafe fn setup_process_audio_client(
+        process_id: u32,
+        wave_format: &WAVEFORMATEX,
+        config: AudioCaptureConfig,
+    ) -> Result<(IAudioClient, Option<DataEvent>)> {
+        match Self::setup_audio_client(process_id, wave_format, config) {
+            Err(e) if e.downcast_ref::<ProcessLoopbackUnsupported>().is_some() => {
+                warn!(
+                    "Process loopback is unavailable (requires Windows 10 20H1+), falling back to device loopback: {:#}",
+                    e
+                );
+                Self::setup_default_endpoint_audio_client(wave_format, config).map_err(|e| {
+                    e.context("Device loopback fallback failed")
+                        .context(ServiceError::DeviceError(AUDIO_UNAVAILABLE.to_string()))
+                })
             }
+            result => result,
         }
     }
 
     unsafe fn setup_audio_client(
         process_id: u32,
         wave_format: &WAVEFORMATEX,
-    ) -> Result<IAudioClient> {
+        config: AudioCaptureConfig,
+    ) -> Result<(IAudioClient, Option<DataEvent>)> {
         info!("Setting up audio client for process ID: {}", process_id);
+        Self::initialize_audio_client(
+            || Self::activate_process_loopback_client(process_id),
+            wave_format,
+            config,
+            true,
+        )
+    }
 
+    /// プロセスループバックのオーディオクライアントを有効化する（初期化はしない）
+    unsafe fn activate_process_loopback_client(process_id: u32) -> Result<IAudioClient> {
         // AUDIOCLIENT_ACTIVATION_PARAMSを作成
         let mut activation_params = AUDIOCLIENT_ACTIVATION_PARAMS::default();
         activation_params.ActivationType = AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK;
@@ -347,7 +706,9 @@ impl AudioCaptureService {
         );
 
         // PROPVARIANTを構築（VT_BLOBとして）
-        let mut prop_variant = PROPVARIANT::default();
+        // PROPVARIANT の drop は PropVariantClear で blob を解放するが、blob はスタック上の
+        // activation_params を指しているので、途中で return する経路も含めて drop させない
+        let mut prop_variant = ManuallyDrop::new(PROPVARIANT::default());
         (*prop_variant.Anonymous.Anonymous).vt = VT_BLOB;
         (*prop_variant.Anonymous.Anonymous).Anonymous.blob.cbSize =
             std::mem::size_of::<AUDIOCLIENT_ACTIVATION_PARAMS>() as u32;
@@ -364,10 +725,10 @@ impl AudioCaptureService {
         let activate_operation = ActivateAudioInterfaceAsync(
             VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
             &IAudioClient::IID,
-            Some(&prop_variant),
+            Some(&*prop_variant),
             &handler,
         )
-        .context("Failed to activate audio interface")?;
+        .map_err(|e| activation_error(e.code()).context("Failed to activate audio interface"))?;
 
         // イベントがシグナルされるまで待機
         WaitForSingleObject(ev, INFINITE);
@@ -382,28 +743,156 @@ impl AudioCaptureService {
         // Event を閉じる
         CloseHandle(ev).context("Failed to close event")?;
 
-        // PROPVARIANT のライフタイム管理（activation_params への参照を含むため）
-        // activation_operation が完了するまで prop_variant を保持
-        std::mem::forget(prop_variant);
+        if hr.is_err() {
+            return Err(activation_error(hr).context("Process loopback activation failed"));
+        }
 
         // IAudioClient にキャスト
-        let audio_client = audio_interface
+        audio_interface
             .ok_or_else(|| anyhow::anyhow!("Audio interface is None"))?
             .cast::<IAudioClient>()
-            .map_err(|e| anyhow::anyhow!("Failed to cast to IAudioClient: {:?}", e))?;
+            .map_err(|e| anyhow::anyhow!("Failed to cast to IAudioClient: {:?}", e))
+    }
 
-        // オーディオクライアントを初期化
-        let init_result = audio_client.Initialize(
-            AUDCLNT_SHAREMODE_SHARED,
-            AUDCLNT_STREAMFLAGS_LOOPBACK
-                | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM
-                | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY,
-            10_000_000, // 100msバッファ
-            0,
+    /// 既定のレンダーエンドポイントのループバック用オーディオクライアントを取得
+    unsafe fn setup_default_endpoint_audio_client(
+        wave_format: &WAVEFORMATEX,
+        config: AudioCaptureConfig,
+    ) -> Result<(IAudioClient, Option<DataEvent>)> {
+        info!("Setting up audio client for the default render endpoint");
+
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let device = enumerator
+            .GetDefaultAudioEndpoint(eRender, eConsole)
+            .context("Default audio endpoint not found")?;
+        Self::initialize_audio_client(
+            || {
+                device
+                    .Activate(CLSCTX_ALL, None)
+                    .context("Failed to activate audio client for endpoint")
+            },
+            wave_format,
+            config,
+            true,
+        )
+    }
+
+    /// 指定したレンダーエンドポイントのループバック用オーディオクライアントを取得
+    unsafe fn setup_endpoint_audio_client(
+        device_id: &str,
+        wave_format: &WAVEFORMATEX,
+        config: AudioCaptureConfig,
+    ) -> Result<(IAudioClient, Option<DataEvent>)> {
+        info!("Setting up audio client for endpoint: {}", device_id);
+
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let device = enumerator
+            .GetDevice(&HSTRING::from(device_id))
+            .with_context(|| format!("Audio endpoint not found: {}", device_id))?;
+        Self::initialize_audio_client(
+            || {
+                device
+                    .Activate(CLSCTX_ALL, None)
+                    .context("Failed to activate audio client for endpoint")
+            },
             wave_format,
-            None,
+            config,
+            true,
+        )
+    }
+
+    /// 入力デバイス（マイクなど）のキャプチャ用オーディオクライアントを取得
+    unsafe fn setup_input_device_audio_client(
+        device_id: Option<&str>,
+        wave_format: &WAVEFORMATEX,
+        config: AudioCaptureConfig,
+    ) -> Result<(IAudioClient, Option<DataEvent>)> {
+        info!(
+            "Setting up audio client for input device: {}",
+            device_id.unwrap_or("default")
         );
 
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .context("Failed to create device enumerator")?;
+        let device = match device_id {
+            Some(device_id) => enumerator
+                .GetDevice(&HSTRING::from(device_id))
+                .with_context(|| format!("Audio input device not found: {}", device_id))?,
+            None => enumerator
+                .GetDefaultAudioEndpoint(eCapture, eConsole)
+                .context("Default audio input device not found")?,
+        };
+        Self::initialize_audio_client(
+            || {
+                device
+                    .Activate(CLSCTX_ALL, None)
+                    .context("Failed to activate audio client for input device")
+            },
+            wave_format,
+            config,
+            false,
+        )
+    }
+
+    /// `activate` で取得したオーディオクライアントを共有モード（48kHz float へ自動変換）で初期化
+    /// `loopback` が false の場合は入力デバイスをそのままキャプチャする
+    ///
+    /// イベント駆動の場合はデータ到着を通知するイベントも返す。
+    /// イベント駆動で初期化できなければ、クライアントを取得し直してポーリング（None）にフォールバックする
+    /// （Initialize に失敗したクライアントはもう一度 Initialize できない）。
+    unsafe fn initialize_audio_client(
+        activate: impl Fn() -> Result<IAudioClient>,
+        wave_format: &WAVEFORMATEX,
+        config: AudioCaptureConfig,
+        loopback: bool,
+    ) -> Result<(IAudioClient, Option<DataEvent>)> {
+        let mut stream_flags =
+            AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM | AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY;
+        if loopback {
+            stream_flags |= AUDCLNT_STREAMFLAGS_LOOPBACK;
+        }
+        // バッファ長（100ナノ秒単位）
+        let buffer_duration = config.buffer_ms as i64 * 10_000;
+
+        // オーディオクライアントを初期化
+        let mut audio_client = activate()?;
+        let mut event_driven = config.event_driven;
+        if event_driven {
+            let init_result = audio_client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                stream_flags | AUDCLNT_STREAMFLAGS_EVENTCALLBACK,
+                buffer_duration,
+                0,
+                wave_format,
+                None,
+            );
+            if let Err(e) = &init_result {
+                warn!(
+                    "Event-driven audio capture is unavailable, falling back to polling: {:?}",
+                    e
+                );
+                event_driven = false;
+                audio_client = activate().context("Failed to reactivate audio client")?;
+            }
+        }
+        let init_result = if event_driven {
+            Ok(())
+        } else {
+            audio_client.Initialize(
+                AUDCLNT_SHAREMODE_SHARED,
+                stream_flags,
+                buffer_duration,
+                0,
+                wave_format,
+                None,
+            )
+        };
+
         if let Err(e) = init_result {
             error!("Failed to initialize audio client: {:?}", e);
             // packed構造体のフィールドを直接参照できないため、ローカル変数にコピー
@@ -420,7 +909,14 @@ impl AudioCaptureService {
             ));
         }
 
-        info!("Audio client initialized successfully");
+        info!(
+            "Audio client initialized successfully ({})",
+            if event_driven {
+                "event-driven"
+            } else {
+                "polling"
+            }
+        );
 
         // バッファサイズを確認
         let buffer_frames = audio_client.GetBufferSize()?;
@@ -431,10 +927,110 @@ impl AudioCaptureService {
             buffer_frames as f64 * 1000.0 / sample_rate as f64
         );
 
-        Ok(audio_client)
+        if !event_driven {
+            return Ok((audio_client, None));
+        }
+        // EVENTCALLBACK で初期化した場合は Start の前にイベントを設定する必要がある
+        let event = CreateEventW(None, false, false, None)
+            .map(DataEvent)
+            .context("Failed to create audio data event")?;
+        audio_client
+            .SetEventHandle(event.0)
+            .context("Failed to set audio data event")?;
+        Ok((audio_client, Some(event)))
+    }
+}
+
+/// プロセスループバックの有効化に失敗した HRESULT をエラーにする
+///
+/// 20H1 より前の Windows はプロセスループバック用の仮想デバイスやパラメータを知らないため、
+/// 以下のいずれかで失敗する。その場合は `ProcessLoopbackUnsupported` を付与する。
+fn activation_error(hr: HRESULT) -> anyhow::Error {
+    let err = anyhow::Error::new(windows::core::Error::from_hresult(hr));
+    let unsupported = [
+        E_NOTIMPL,
+        E_NOINTERFACE,
+        E_INVALIDARG,
+        ERROR_NOT_FOUND.to_hresult(),
+    ];
+    if unsupported.contains(&hr) {
+        err.context(ProcessLoopbackUnsupported)
+    } else {
+        err
     }
 }
 
+/// データ到着を通知するイベント（キャプチャ終了時に閉じる）
+struct DataEvent(HANDLE);
+
+impl Drop for DataEvent {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.0);
+        }
+    }
+}
+
+/// アクティブなレンダーエンドポイントの (MMDevice ID, フレンドリ名) 一覧を取得
+pub fn list_audio_endpoints() -> Result<Vec<(String, String)>> {
+    list_endpoints(eRender)
+}
+
+/// アクティブな入力デバイスの (MMDevice ID, フレンドリ名) 一覧を取得
+pub fn list_audio_input_devices() -> Result<Vec<(String, String)>> {
+    list_endpoints(eCapture)
+}
+
+fn list_endpoints(data_flow: EDataFlow) -> Result<Vec<(String, String)>> {
+    unsafe {
+        // 呼び出し元スレッドで COM が未初期化の場合のみ初期化・解放する
+        let coinit_result = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let result = enumerate_endpoints(data_flow);
+        if coinit_result.is_ok() {
+            CoUninitialize();
+        }
+        result
+    }
+}
+
+unsafe fn enumerate_endpoints(data_flow: EDataFlow) -> Result<Vec<(String, String)>> {
+    let enumerator: IMMDeviceEnumerator = CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+        .context("Failed to create device enumerator")?;
+    let collection = enumerator
+        .EnumAudioEndpoints(data_flow, DEVICE_STATE_ACTIVE)
+        .context("Failed to enumerate audio endpoints")?;
+    let count = collection.GetCount()?;
+
+    let mut endpoints = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let device = collection.Item(i)?;
+        let id_ptr = device.GetId()?;
+        let id = id_ptr.to_string();
+        CoTaskMemFree(Some(id_ptr.0 as *const _));
+        let id = id.context("Invalid audio endpoint ID")?;
+
+        let name = match device_friendly_name(&device) {
+            Ok(name) => name,
+            Err(e) => {
+                debug!("Failed to get friendly name for {}: {:?}", id, e);
+                id.clone()
+            }
+        };
+        endpoints.push((id, name));
+    }
+
+    Ok(endpoints)
+}
+
+unsafe fn device_friendly_name(device: &IMMDevice) -> Result<String> {
+    let store = device.OpenPropertyStore(STGM_READ)?;
+    let value = store.GetValue(&PKEY_Device_FriendlyName)?;
+    let name_ptr = PropVariantToStringAlloc(&value)?;
+    let name = name_ptr.to_string();
+    CoTaskMemFree(Some(name_ptr.0 as *const _));
+    Ok(name?)
+}
+
 /// ActivateAudioInterfaceAsyncのコールバックハンドラ
 #[implement(IActivateAudioInterfaceCompletionHandler)]
 struct SyncActivationHandler(HANDLE);
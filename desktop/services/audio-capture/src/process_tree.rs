@@ -0,0 +1,154 @@
+// 遅れて起動した子プロセスの音声を拾うためのループバックの有効化し直し
+//
+// プロセスループバックは INCLUDE_TARGET_PROCESS_TREE で子プロセスも含めるが、ランチャーが後から
+// ゲーム本体（音声エンジン）を起動する場合、有効化の後に増えた子プロセスの音声は入らず、
+// キャプチャを止めて始め直すまで無音になる。有効化した時点のプロセスツリーを覚えておき、
+// 音のあるデータが一定時間届かない間にツリーを見直して、子孫のプロセスが増えていれば有効化し直す。
+// ツリーの取得（プロセスのスナップショット）は無音が続いている間だけ、タイムアウトごとに 1 回行う。
+
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use windows::Win32::Foundation::CloseHandle;
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+
+/// 無音が続いている間にプロセスツリーの変化を調べる
+#[derive(Debug)]
+pub struct LateChildWatch {
+    silence_timeout: Duration,
+    /// 有効化した時点（または前回調べた時点）の子孫のプロセス
+    known: BTreeSet<u32>,
+    last_audible: Instant,
+    last_check: Instant,
+}
+
+impl LateChildWatch {
+    /// 有効化した時点の子孫のプロセス `known` から見張る
+    pub fn new(silence_timeout: Duration, known: BTreeSet<u32>, now: Instant) -> Self {
+        Self {
+            silence_timeout,
+            known,
+            last_audible: now,
+            last_check: now,
+        }
+    }
+
+    /// 音のあるデータが届いた
+    pub fn on_audible(&mut self, now: Instant) {
+        self.last_audible = now;
+    }
+
+    /// 無音が `silence_timeout` 続いていれば `descendants` でツリーを調べ、増えた子孫のプロセスを返す
+    /// （有効化し直す必要がなければ空）
+    pub fn check(&mut self, now: Instant, descendants: impl FnOnce() -> BTreeSet<u32>) -> Vec<u32> {
+        if now.duration_since(self.last_audible) < self.silence_timeout
+            || now.duration_since(self.last_check) < self.silence_timeout
+        {
+            return Vec::new();
+        }
+        self.last_check = now;
+        let current = descendants();
+        let added: Vec<u32> = current.difference(&self.known).copied().collect();
+        // 終了したプロセスは忘れる（同じ PID が再利用されたら新しい子として扱う）
+        self.known = current;
+        added
+    }
+}
+
+/// `(PID, 親の PID)` の一覧から `root` の子孫のプロセスを求める（`root` 自身は含めない）
+fn descendants_of(root: u32, processes: &[(u32, u32)]) -> BTreeSet<u32> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for &(pid, parent) in processes {
+        // PID 0 (System Idle Process) は自分自身を親として報告する
+        if pid != parent {
+            children.entry(parent).or_default().push(pid);
+        }
+    }
+    let mut found = BTreeSet::new();
+    let mut pending = vec![root];
+    while let Some(pid) = pending.pop() {
+        for &child in children.get(&pid).into_iter().flatten() {
+            // PID の再利用で親子関係が循環しても止まるように、見つけたものは辿り直さない
+            if child != root && found.insert(child) {
+                pending.push(child);
+            }
+        }
+    }
+    found
+}
+
+/// 実行中のプロセスのスナップショットから `root` の子孫のプロセスを求める（取得できなければ空）
+pub fn process_descendants(root: u32) -> BTreeSet<u32> {
+    let mut processes = Vec::new();
+    unsafe {
+        let snapshot = match CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                debug!("Failed to snapshot processes: {:?}", e);
+                return BTreeSet::new();
+            }
+        };
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut next = Process32FirstW(snapshot, &mut entry);
+        while next.is_ok() {
+            processes.push((entry.th32ProcessID, entry.th32ParentProcessID));
+            next = Process32NextW(snapshot, &mut entry);
+        }
+        let _ = CloseHandle(snapshot);
+    }
+    descendants_of(root, &processes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descendants_follow_the_tree() {
+        // 100 → 200 → 300、100 → 201、無関係な 400、0 は自分が親
+        let processes = [
+            (0, 0),
+            (100, 4),
+            (200, 100),
+            (201, 100),
+            (300, 200),
+            (400, 4),
+        ];
+        assert_eq!(
+            descendants_of(100, &processes),
+            BTreeSet::from([200, 201, 300])
+        );
+        assert!(descendants_of(400, &processes).is_empty());
+        // 循環していても止まる
+        assert_eq!(descendants_of(1, &[(1, 2), (2, 1)]), BTreeSet::from([2]));
+    }
+
+    #[test]
+    fn test_reactivates_after_silence_when_children_were_added() {
+        let timeout = Duration::from_secs(5);
+        let t0 = Instant::now();
+        let s = Duration::from_secs;
+        let mut watch = LateChildWatch::new(timeout, BTreeSet::from([200]), t0);
+        let unexpected = || -> BTreeSet<u32> { panic!("process tree should not be checked") };
+
+        // 無音がタイムアウトに届くまではツリーを調べない
+        assert!(watch.check(t0 + s(4), unexpected).is_empty());
+        // 音が出ていれば調べない
+        watch.on_audible(t0 + s(4));
+        assert!(watch.check(t0 + s(8), unexpected).is_empty());
+
+        // 無音が続いても子が増えていなければ有効化し直さない
+        assert!(watch.check(t0 + s(9), || BTreeSet::from([200])).is_empty());
+        // 次に調べるのはタイムアウトの後
+        assert!(watch.check(t0 + s(12), unexpected).is_empty());
+        assert_eq!(
+            watch.check(t0 + s(14), || BTreeSet::from([200, 300])),
+            [300]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+// キャプチャした音声を WAV に書き出すデバッグ用シンク
+//
+// 「音が割れる / 無音になる」といった報告を Opus を通さずに再現できるよう、
+// キャプチャスレッドが送出するフレームをそのまま保存する。書き込みは専用のタスクで行い、
+// キャプチャスレッドはチャネルへ try_send するだけなので遅延は増えない。
+
+use anyhow::{Context, Result};
+use core_types::AudioFrame;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// 書き込みタスクに溜められるフレーム数（10ms フレームで約 2 秒分）
+const DUMP_QUEUE_FRAMES: usize = 200;
+
+/// 書き込みタスクへの送信側
+#[derive(Clone)]
+pub struct WavDump {
+    tx: mpsc::Sender<AudioFrame>,
+}
+
+impl WavDump {
+    /// `path` に 48kHz ステレオ f32 の WAV を作成し、書き込みタスクを起動する
+    /// データ部が `max_bytes` に達したら以降のフレームは書き込まずにファイルを閉じる
+    pub fn spawn(path: &Path, max_bytes: u64) -> Result<Self> {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = hound::WavWriter::create(path, spec)
+            .with_context(|| format!("Failed to create audio dump: {}", path.display()))?;
+        info!(
+            "Dumping captured audio to {} (up to {} bytes)",
+            path.display(),
+            max_bytes
+        );
+
+        let (tx, rx) = mpsc::channel(DUMP_QUEUE_FRAMES);
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || write_frames(writer, rx, &path, max_bytes));
+        Ok(Self { tx })
+    }
+
+    /// フレームを書き込みタスクに渡す（詰まっている場合は捨てる）
+    pub fn push(&self, frame: &AudioFrame) {
+        if self.tx.try_send(frame.clone()).is_err() {
+            debug!("Audio dump queue is full or closed, dropping frame");
+        }
+    }
+}
+
+fn write_frames(
+    mut writer: hound::WavWriter<BufWriter<File>>,
+    mut rx: mpsc::Receiver<AudioFrame>,
+    path: &Path,
+    max_bytes: u64,
+) {
+    let mut written_bytes: u64 = 0;
+    while let Some(frame) = rx.blocking_recv() {
+        let frame_bytes = (frame.samples.len() * std::mem::size_of::<f32>()) as u64;
+        if written_bytes + frame_bytes > max_bytes {
+            info!(
+                "Audio dump reached {} bytes, stopping: {}",
+                written_bytes,
+                path.display()
+            );
+            break;
+        }
+        if let Err(e) = frame
+            .samples
+            .iter()
+            .try_for_each(|sample| writer.write_sample(*sample))
+        {
+            warn!("Failed to write audio dump {}: {}", path.display(), e);
+            break;
+        }
+        written_bytes += frame_bytes;
+    }
+
+    if let Err(e) = writer.finalize() {
+        warn!("Failed to finalize audio dump {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dump_stops_at_size_cap() {
+        let path = std::env::temp_dir().join(format!("audio_dump_test_{}.wav", std::process::id()));
+        // 10ms フレーム（960 サンプル = 3840 バイト）2 つ分まで
+        let dump = WavDump::spawn(&path, 3840 * 2 + 100).unwrap();
+        for i in 0..5 {
+            let frame = AudioFrame {
+                samples: vec![i as f32 / 10.0; 960],
+                sample_rate: 48000,
+                channels: 2,
+                timestamp_us: i * 10_000,
+            };
+            // 上限に達すると書き込みタスクが終了して送信に失敗する
+            let _ = dump.tx.send(frame).await;
+        }
+        drop(dump);
+
+        // 書き込みタスクがファイルを閉じるまで待つ
+        let reader = loop {
+            if let Ok(reader) = hound::WavReader::open(&path) {
+                if reader.len() > 0 {
+                    break reader;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.len(), 960 * 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
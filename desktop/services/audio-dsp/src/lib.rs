@@ -0,0 +1,694 @@
+use std::collections::VecDeque;
+
+/// インターリーブPCM（f32）のサンプルレート変換
+///
+/// 実装を差し替えられるようにトレイトとして定義する（現状は線形補間のみ）
+pub trait Resampler: Send {
+    /// 入力サンプルを変換して返す
+    fn process(&mut self, samples: &[f32]) -> Vec<f32>;
+}
+
+/// 線形補間によるリサンプラー
+///
+/// キャプチャのように細切れのチャンクを順に渡す用途向け。次の出力位置の端数と直前のチャンクの
+/// 最後のフレームを持ち越すので、チャンクの境界でも補間が途切れない
+/// （最後のフレームは次のチャンクと補間するまで出力しないため、1 フレーム分遅れる）
+pub struct LinearResampler {
+    src_rate: u32,
+    dst_rate: u32,
+    channels: u16,
+    /// 次の出力フレームの位置（`last_frame` を 0 とした入力フレーム単位）
+    position: f64,
+    /// 直前のチャンクの最後のフレーム（まだ何も受け取っていなければ空）
+    last_frame: Vec<f32>,
+}
+
+impl LinearResampler {
+    pub fn new(src_rate: u32, dst_rate: u32, channels: u16) -> Self {
+        Self {
+            src_rate,
+            dst_rate,
+            channels,
+            position: 0.0,
+            last_frame: Vec::new(),
+        }
+    }
+}
+
+impl Resampler for LinearResampler {
+    fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        if self.src_rate == self.dst_rate {
+            return samples.to_vec();
+        }
+        if self.channels == 0 || self.src_rate == 0 || self.dst_rate == 0 {
+            return Vec::new();
+        }
+
+        let channels = self.channels as usize;
+        let ratio = self.src_rate as f64 / self.dst_rate as f64;
+        let chunk_frames = samples.len() / channels;
+        if chunk_frames == 0 {
+            return Vec::new();
+        }
+        // 持ち越したフレームを先頭に置いた入力として扱う
+        let carried = usize::from(!self.last_frame.is_empty());
+        let total_frames = carried + chunk_frames;
+        let sample = |frame: usize, ch: usize| {
+            if frame < carried {
+                self.last_frame[ch]
+            } else {
+                samples[(frame - carried) * channels + ch]
+            }
+        };
+
+        let mut output =
+            Vec::with_capacity(((chunk_frames as f64 / ratio).ceil() as usize + 1) * channels);
+        let mut position = self.position;
+        while position + 1.0 < total_frames as f64 {
+            let frame = position.floor() as usize;
+            let frac = (position - frame as f64) as f32;
+            for ch in 0..channels {
+                let sample0 = sample(frame, ch);
+                let sample1 = sample(frame + 1, ch);
+                output.push(sample0 + (sample1 - sample0) * frac);
+            }
+            position += ratio;
+        }
+
+        // 最後のフレームを次のチャンクの先頭として持ち越す
+        self.position = position - (total_frames - 1) as f64;
+        self.last_frame = samples[(chunk_frames - 1) * channels..chunk_frames * channels].to_vec();
+        output
+    }
+}
+
+/// 線形補間によるリサンプリング（src_rate → dst_rate）
+///
+/// 一度に渡すバッファ全体を変換する。チャンクごとに呼ぶと境界で補間が途切れるので、
+/// 続けて届く音声には [`LinearResampler`] を使う
+pub fn resample_linear(samples: &[f32], src_rate: u32, dst_rate: u32, channels: u16) -> Vec<f32> {
+    if src_rate == dst_rate {
+        return samples.to_vec();
+    }
+    if channels == 0 || src_rate == 0 || dst_rate == 0 {
+        return Vec::new();
+    }
+
+    let channels = channels as usize;
+    let ratio = src_rate as f64 / dst_rate as f64;
+    let src_frames = samples.len() / channels;
+    let dst_frames = (src_frames as f64 / ratio).ceil() as usize;
+
+    let mut output = Vec::with_capacity(dst_frames * channels);
+
+    for dst_frame_idx in 0..dst_frames {
+        let src_pos = dst_frame_idx as f64 * ratio;
+        let src_frame_idx = src_pos.floor() as usize;
+        let frac = src_pos - src_frame_idx as f64;
+
+        if src_frame_idx + 1 >= src_frames {
+            // 最後のフレームはコピー
+            for ch in 0..channels {
+                let idx = src_frame_idx * channels + ch;
+                output.push(samples.get(idx).copied().unwrap_or(0.0));
+            }
+        } else {
+            // 線形補間: sample0 + (sample1 - sample0) * frac
+            for ch in 0..channels {
+                let sample0 = samples[src_frame_idx * channels + ch];
+                let sample1 = samples[(src_frame_idx + 1) * channels + ch];
+                output.push(sample0 + (sample1 - sample0) * frac as f32);
+            }
+        }
+    }
+
+    output
+}
+
+/// チャネル数を変換（ステレオに統一）
+///
+/// モノラルは両チャンネルに複製、3ch以上は最初の2チャンネル（L, R）のみ使用する
+pub fn convert_to_stereo(samples: &[f32], src_channels: u16) -> Vec<f32> {
+    match src_channels {
+        0 => Vec::new(),
+        2 => samples.to_vec(), // すでにステレオ
+        1 => samples
+            .iter()
+            .flat_map(|&sample| [sample, sample])
+            .collect(),
+        _ => samples
+            .chunks_exact(src_channels as usize)
+            .flat_map(|frame| [frame[0], frame[1]])
+            .collect(),
+    }
+}
+
+/// キャプチャしたサンプルを一定長のフレームに切り出し、各フレームにタイムスタンプを付ける
+///
+/// タイムスタンプは蓄積の先頭サンプルの時刻と、そこから切り出したサンプル数から計算する。
+/// WASAPI の DATA_DISCONTINUITY のようにサンプルが欠落した場合は `resync` で蓄積を捨て、
+/// 次のパケットの実時刻を基準にし直す（欠落分がそのまま A/V のずれとして残らないようにする）。
+/// 送り先が詰まって取り出されない間も、`with_max_frames` の上限を超えた分は古いフレームから捨てる。
+pub struct FrameAccumulator {
+    /// 1フレームのサンプル数（チャンネルあたり）
+    frame_samples: usize,
+    sample_rate: u32,
+    channels: u16,
+    samples: Vec<f32>,
+    /// 蓄積の先頭サンプルの時刻（マイクロ秒）
+    base_us: u64,
+    /// `base_us` 以降に切り出したサンプル数（チャンネルあたり）
+    consumed_samples: u64,
+    /// 蓄積しておくフレーム数の上限（None なら制限しない）
+    max_frames: Option<usize>,
+}
+
+impl FrameAccumulator {
+    pub fn new(frame_samples: usize, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            frame_samples,
+            sample_rate,
+            channels,
+            samples: Vec::new(),
+            base_us: 0,
+            consumed_samples: 0,
+            max_frames: None,
+        }
+    }
+
+    /// 取り出されずにたまったサンプルを `frames` フレーム分までに抑える（超えた分は古いものから捨てる）
+    pub fn with_max_frames(mut self, frames: usize) -> Self {
+        self.max_frames = Some(frames.max(1));
+        self
+    }
+
+    /// `time_us` に先頭サンプルがあるパケットを追加し、そろったフレームを (サンプル, 時刻) で返す
+    pub fn push(&mut self, samples: &[f32], time_us: u64) -> Vec<(Vec<f32>, u64)> {
+        self.extend(samples, time_us);
+        std::iter::from_fn(|| self.pop()).collect()
+    }
+
+    /// `time_us` に先頭サンプルがあるパケットを追加する（フレームは `pop` で取り出す）
+    /// 上限を超えたために捨てたフレーム数を返す
+    pub fn extend(&mut self, samples: &[f32], time_us: u64) -> usize {
+        if self.samples.is_empty() {
+            // 蓄積が空なら実時刻に合わせ直す（端数が残っている間は連続しているとみなす）
+            self.base_us = time_us;
+            self.consumed_samples = 0;
+        }
+        self.samples.extend_from_slice(samples);
+
+        let frame_len = self.frame_len();
+        let Some(max_frames) = self.max_frames.filter(|_| frame_len > 0) else {
+            return 0;
+        };
+        let max_len = max_frames * frame_len;
+        if self.samples.len() <= max_len {
+            return 0;
+        }
+        // フレーム単位で捨て、残りのフレームの時刻がずれないようにする
+        let dropped = (self.samples.len() - max_len).div_ceil(frame_len);
+        self.samples.drain(..dropped * frame_len);
+        self.consumed_samples += (dropped * self.frame_samples) as u64;
+        dropped
+    }
+
+    /// そろったフレームを 1 つ (サンプル, 時刻) で取り出す
+    pub fn pop(&mut self) -> Option<(Vec<f32>, u64)> {
+        let frame_len = self.frame_len();
+        if frame_len == 0 || self.sample_rate == 0 || self.samples.len() < frame_len {
+            return None;
+        }
+        let frame: Vec<f32> = self.samples.drain(..frame_len).collect();
+        let timestamp_us =
+            self.base_us + self.consumed_samples * 1_000_000 / self.sample_rate as u64;
+        self.consumed_samples += self.frame_samples as u64;
+        Some((frame, timestamp_us))
+    }
+
+    /// 1フレームのサンプル数（全チャンネル）
+    fn frame_len(&self) -> usize {
+        self.frame_samples * self.channels as usize
+    }
+
+    /// 蓄積中の端数を捨て、次の `push` の時刻を基準にし直す
+    pub fn resync(&mut self) {
+        self.samples.clear();
+        self.consumed_samples = 0;
+    }
+}
+
+/// フレームの時刻が期待位置からこれ以上ずれたら重なり・空きとして扱う（これ以内は到着のゆらぎとして詰める）
+const MIXER_TOLERANCE_US: u64 = 5_000;
+/// 先頭のソースからこれ以上遅れたソースは止まっているとみなし、待たずに無音で埋める
+const MIXER_STALL_US: u64 = 60_000;
+/// これ以上時刻が飛んだ・戻った場合はキャプチャが再起動したとみなして揃え直す
+const MIXER_RESET_US: u64 = 1_000_000;
+
+/// 複数の音声ソースを 1 本のストリームにミックスする
+///
+/// 各ソースのサンプルを共通の時間軸（タイムスタンプ）の上に並べ、一定長のフレームごとに
+/// ソース別のゲインをかけて足し合わせる。デバイスのクロック差で到着レートがわずかに異なる場合は、
+/// 期待位置とのずれが `MIXER_TOLERANCE_US` を超えた時点で重なった分を捨てる・空いた分を無音で埋めて追従する。
+/// 無音の間パケットを送らないループバックのように止まったソースは `MIXER_STALL_US` 以上待たない。
+pub struct AudioMixer {
+    /// 1フレームのサンプル数（チャンネルあたり）
+    frame_samples: usize,
+    sample_rate: u32,
+    channels: u16,
+    sources: Vec<MixerSource>,
+    /// 次に出力するフレームの先頭位置（サンプル）
+    cursor: Option<u64>,
+}
+
+struct MixerSource {
+    gain: f32,
+    muted: bool,
+    samples: VecDeque<f32>,
+    /// `samples` の先頭の位置（サンプル）
+    start: u64,
+    /// 最後に届いたサンプルの終端位置（まだ何も届いていなければ None）
+    received_end: Option<u64>,
+}
+
+impl MixerSource {
+    fn new() -> Self {
+        Self {
+            gain: 1.0,
+            muted: false,
+            samples: VecDeque::new(),
+            start: 0,
+            received_end: None,
+        }
+    }
+
+    /// `pos` より前のサンプルを捨てる
+    fn discard_before(&mut self, pos: u64, channels: usize) {
+        let buffered = (self.samples.len() / channels) as u64;
+        let count = pos.saturating_sub(self.start).min(buffered);
+        self.samples.drain(..count as usize * channels);
+        self.start += count;
+    }
+}
+
+impl AudioMixer {
+    /// `sources` 個のソースを持つミキサーを作成（ゲインは 1.0）
+    pub fn new(sources: usize, frame_samples: usize, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            frame_samples,
+            sample_rate,
+            channels,
+            sources: (0..sources).map(|_| MixerSource::new()).collect(),
+            cursor: None,
+        }
+    }
+
+    pub fn set_gain(&mut self, source: usize, gain: f32) {
+        if let Some(source) = self.sources.get_mut(source) {
+            source.gain = gain.max(0.0);
+        }
+    }
+
+    pub fn set_muted(&mut self, source: usize, muted: bool) {
+        if let Some(source) = self.sources.get_mut(source) {
+            source.muted = muted;
+        }
+    }
+
+    fn us_to_samples(&self, us: u64) -> u64 {
+        us * self.sample_rate as u64 / 1_000_000
+    }
+
+    /// `time_us` に先頭サンプルがあるフレームをソースに追加する
+    pub fn push(&mut self, source: usize, samples: &[f32], time_us: u64) {
+        let channels = self.channels as usize;
+        if source >= self.sources.len() || channels == 0 || self.sample_rate == 0 {
+            return;
+        }
+        let tolerance = self.us_to_samples(MIXER_TOLERANCE_US);
+        let reset = self.us_to_samples(MIXER_RESET_US);
+        let mut pos = self.us_to_samples(time_us);
+        let mut samples = &samples[..samples.len() / channels * channels];
+
+        match self.sources[source].received_end {
+            // 時刻が大きく戻った: キャプチャの再起動なので全ソースを揃え直す
+            Some(expected) if pos + reset < expected => {
+                self.cursor = None;
+                for source in &mut self.sources {
+                    *source = MixerSource {
+                        gain: source.gain,
+                        muted: source.muted,
+                        ..MixerSource::new()
+                    };
+                }
+            }
+            // 空きが大きい場合は無音で埋めずにそこから始め直す
+            Some(expected) if pos > expected + reset => {
+                let source = &mut self.sources[source];
+                source.samples.clear();
+                source.start = pos;
+            }
+            // ソースが遅い（または欠落した）: 空いた分を無音で埋める
+            Some(expected) if pos > expected + tolerance => {
+                let gap = (pos - expected) as usize * channels;
+                let source = &mut self.sources[source];
+                source.samples.extend(std::iter::repeat_n(0.0, gap));
+            }
+            // ソースが速い: 既に並べた位置と重なった分を捨てる
+            Some(expected) if pos + tolerance < expected => {
+                let overlap = ((expected - pos) as usize * channels).min(samples.len());
+                samples = &samples[overlap..];
+                pos = expected;
+            }
+            // ゆらぎの範囲内なら連続しているとみなす
+            Some(expected) => pos = expected,
+            None => {}
+        }
+
+        let source_state = &mut self.sources[source];
+        if source_state.received_end.is_none() {
+            source_state.start = pos;
+        }
+        source_state.samples.extend(samples);
+        let end = source_state.start + (source_state.samples.len() / channels) as u64;
+        source_state.received_end = Some(end);
+        if self.cursor.is_none() {
+            self.cursor = Some(pos);
+        }
+    }
+
+    /// 出力できるフレームがあれば (サンプル, 時刻) を返す
+    ///
+    /// 止まっていないソースすべてのサンプルがそろったフレームから順に出力する。
+    pub fn pop(&mut self) -> Option<(Vec<f32>, u64)> {
+        let channels = self.channels as usize;
+        let frame = self.frame_samples as u64;
+        let mut cursor = self.cursor?;
+        if channels == 0 || frame == 0 {
+            return None;
+        }
+        let stall = self.us_to_samples(MIXER_STALL_US);
+        let latest_end = self.sources.iter().filter_map(|s| s.received_end).max()?;
+        let ready_end = self
+            .sources
+            .iter()
+            .filter_map(|s| s.received_end)
+            .filter(|end| end + stall >= latest_end)
+            .min()?;
+
+        // どのソースにも次のフレームのデータがなければ、次にデータがある位置まで進める
+        let next_data = self
+            .sources
+            .iter()
+            .filter(|s| s.start + (s.samples.len() / channels) as u64 > cursor)
+            .map(|s| s.start.max(cursor))
+            .min()?;
+        if next_data >= cursor + frame {
+            cursor = next_data;
+        }
+        if ready_end < cursor + frame {
+            self.cursor = Some(cursor);
+            return None;
+        }
+
+        let mut mixed = vec![0.0f32; self.frame_samples * channels];
+        for source in &mut self.sources {
+            source.discard_before(cursor, channels);
+            let offset = source.start.saturating_sub(cursor);
+            if offset < frame && !source.muted {
+                let count = ((frame - offset) as usize * channels).min(source.samples.len());
+                let dst = &mut mixed[offset as usize * channels..][..count];
+                for (out, sample) in dst.iter_mut().zip(source.samples.iter()) {
+                    *out += sample * source.gain;
+                }
+            }
+            source.discard_before(cursor + frame, channels);
+        }
+        for sample in &mut mixed {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+
+        self.cursor = Some(cursor + frame);
+        Some((mixed, cursor * 1_000_000 / self.sample_rate as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_same_rate() {
+        let samples = vec![0.1, 0.2, 0.3, 0.4];
+        assert_eq!(resample_linear(&samples, 48000, 48000, 2), samples);
+    }
+
+    #[test]
+    fn test_resample_upsampling() {
+        // 24kHz → 48kHz（モノラル）: 元のサンプルの間に中間値が入る
+        let samples = vec![0.0, 1.0, 0.0];
+        let output = resample_linear(&samples, 24000, 48000, 1);
+        assert_eq!(output, vec![0.0, 0.5, 1.0, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_resample_downsampling() {
+        // 96kHz → 48kHz（ステレオ）: 1フレームおきに間引かれる
+        let samples = vec![0.0, 1.0, 0.1, 0.9, 0.2, 0.8, 0.3, 0.7];
+        let output = resample_linear(&samples, 96000, 48000, 2);
+        assert_eq!(output, vec![0.0, 1.0, 0.2, 0.8]);
+    }
+
+    #[test]
+    fn test_resample_length_44100_to_48000() {
+        // 10ms分 (441フレーム) → 480フレーム
+        let samples = vec![0.0; 441 * 2];
+        let output = resample_linear(&samples, 44100, 48000, 2);
+        assert_eq!(output.len(), 480 * 2);
+    }
+
+    #[test]
+    fn test_resample_empty_input() {
+        assert!(resample_linear(&[], 44100, 48000, 2).is_empty());
+        assert!(resample_linear(&[0.1, 0.2], 44100, 48000, 0).is_empty());
+    }
+
+    #[test]
+    fn test_linear_resampler() {
+        let mut resampler: Box<dyn Resampler> = Box::new(LinearResampler::new(24000, 48000, 1));
+        // 最後のフレームは次のチャンクと補間するまで持ち越す
+        assert_eq!(resampler.process(&[0.0, 1.0]), vec![0.0, 0.5]);
+        assert_eq!(resampler.process(&[0.0]), vec![1.0, 0.5]);
+        assert!(resampler.process(&[]).is_empty());
+        assert_eq!(resampler.process(&[1.0]), vec![0.0, 0.5]);
+    }
+
+    #[test]
+    fn test_linear_resampler_is_continuous_across_chunks() {
+        // 44.1kHz → 48kHz のランプを 10ms ずつ渡しても、まとめて変換した場合と同じ値が続く
+        let ramp: Vec<f32> = (0..441 * 4).map(|i| i as f32).collect();
+        let mut resampler = LinearResampler::new(44100, 48000, 1);
+        let chunked: Vec<f32> = ramp
+            .chunks(441)
+            .flat_map(|chunk| resampler.process(chunk))
+            .collect();
+        let whole = resample_linear(&ramp, 44100, 48000, 1);
+
+        assert!(chunked.len() >= whole.len() - 2);
+        for (i, (a, b)) in chunked.iter().zip(&whole).enumerate() {
+            assert!((a - b).abs() < 1e-3, "sample {}: {} != {}", i, a, b);
+        }
+        // 出力の間隔は一定（境界で飛んだり戻ったりしない）
+        let step = 44100.0 / 48000.0;
+        for pair in chunked.windows(2) {
+            assert!((pair[1] - pair[0] - step).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_linear_resampler_stereo_downsampling() {
+        // 96kHz → 48kHz（ステレオ）: チャンクをまたいでも 1 フレームおきに間引かれる
+        let mut resampler = LinearResampler::new(96000, 48000, 2);
+        assert_eq!(
+            resampler.process(&[0.0, 1.0, 0.1, 0.9, 0.2, 0.8]),
+            vec![0.0, 1.0]
+        );
+        assert_eq!(resampler.process(&[0.3, 0.7, 0.4, 0.6]), vec![0.2, 0.8]);
+        assert_eq!(resampler.process(&[0.5, 0.5]), vec![0.4, 0.6]);
+    }
+
+    #[test]
+    fn test_convert_mono_to_stereo() {
+        assert_eq!(
+            convert_to_stereo(&[0.1, 0.2, 0.3], 1),
+            vec![0.1, 0.1, 0.2, 0.2, 0.3, 0.3]
+        );
+    }
+
+    #[test]
+    fn test_convert_stereo_passthrough() {
+        let samples = vec![0.1, 0.2, 0.3, 0.4];
+        assert_eq!(convert_to_stereo(&samples, 2), samples);
+    }
+
+    #[test]
+    fn test_convert_6ch_to_stereo() {
+        // 5.1ch: L, R, C, LFE, Ls, Rs → L, R のみ
+        let samples = vec![
+            0.1, 0.2, 0.3, 0.4, 0.5, 0.6, //
+            0.7, 0.8, 0.9, 1.0, 1.1, 1.2,
+        ];
+        assert_eq!(convert_to_stereo(&samples, 6), vec![0.1, 0.2, 0.7, 0.8]);
+    }
+
+    #[test]
+    fn test_convert_empty_input() {
+        assert!(convert_to_stereo(&[], 1).is_empty());
+        assert!(convert_to_stereo(&[], 6).is_empty());
+        assert!(convert_to_stereo(&[0.1, 0.2], 0).is_empty());
+    }
+
+    #[test]
+    fn test_frame_accumulator_resync_on_discontinuity() {
+        // 48kHz モノラル、10ms（480サンプル）フレーム
+        let mut accumulator = FrameAccumulator::new(480, 48000, 1);
+
+        // 15ms 分: 1フレーム出て 5ms 分が残る
+        let frames = accumulator.push(&[0.0; 720], 1_000_000);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1, 1_000_000);
+
+        // 連続したパケットは蓄積から時刻を計算する
+        let frames = accumulator.push(&[0.0; 480], 1_015_000);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1, 1_010_000);
+
+        // 50ms 分のサンプルが欠落: 端数を捨てて実時刻に合わせ直す
+        accumulator.resync();
+        let frames = accumulator.push(&[1.0; 480], 1_075_000);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].1, 1_075_000);
+        assert!(frames[0].0.iter().all(|s| *s == 1.0));
+
+        let frames = accumulator.push(&[1.0; 480], 1_085_000);
+        assert_eq!(frames[0].1, 1_085_000);
+    }
+
+    #[test]
+    fn test_frame_accumulator_caps_backlog_while_sender_is_stalled() {
+        // 48kHz モノラル、10ms フレームを 3 フレームまで保持
+        let mut accumulator = FrameAccumulator::new(480, 48000, 1).with_max_frames(3);
+
+        // 送り先が詰まっている間（取り出さない間）に 5 フレーム分が届く
+        for (i, time_us) in (1_000_000..1_050_000).step_by(10_000).enumerate() {
+            let dropped = accumulator.extend(&[i as f32; 480], time_us);
+            assert_eq!(dropped, usize::from(i >= 3));
+        }
+
+        // 古い 2 フレームが捨てられ、残りは元の時刻のまま出る
+        let frames: Vec<_> = std::iter::from_fn(|| accumulator.pop()).collect();
+        let times: Vec<u64> = frames.iter().map(|(_, time_us)| *time_us).collect();
+        assert_eq!(times, [1_020_000, 1_030_000, 1_040_000]);
+        assert!(frames[0].0.iter().all(|s| *s == 2.0));
+
+        // 端数があるときもフレーム単位で捨てる
+        accumulator.extend(&[5.0; 240], 1_050_000);
+        assert_eq!(accumulator.extend(&[6.0; 1440], 1_055_000), 1);
+        let (samples, time_us) = accumulator.pop().unwrap();
+        assert_eq!(time_us, 1_060_000);
+        assert!(samples.iter().all(|s| *s == 6.0));
+        assert_eq!(accumulator.pop().unwrap().1, 1_070_000);
+        assert!(accumulator.pop().is_none());
+    }
+
+    /// 10ms（480サンプル）ステレオの一定値フレーム
+    fn mixer_frame(value: f32) -> Vec<f32> {
+        vec![value; 480 * 2]
+    }
+
+    #[test]
+    fn test_mixer_sums_sources_with_gain() {
+        let mut mixer = AudioMixer::new(2, 480, 48000, 2);
+        mixer.set_gain(1, 0.5);
+        mixer.push(0, &mixer_frame(0.2), 1_000_000);
+        mixer.push(1, &mixer_frame(0.4), 1_000_000);
+
+        let (samples, timestamp_us) = mixer.pop().unwrap();
+        assert_eq!(timestamp_us, 1_000_000);
+        assert!(samples.iter().all(|s| (s - 0.4).abs() < 1e-6));
+        assert!(mixer.pop().is_none());
+    }
+
+    #[test]
+    fn test_mixer_aligns_sources_by_timestamp() {
+        let mut mixer = AudioMixer::new(2, 480, 48000, 2);
+        mixer.push(0, &mixer_frame(0.1), 0);
+        mixer.push(0, &mixer_frame(0.1), 10_000);
+        // 5ms 遅れて始まるソース
+        mixer.push(1, &mixer_frame(0.3), 5_000);
+
+        let (samples, _) = mixer.pop().unwrap();
+        // 前半 5ms（240サンプル × 2ch）は 1 つ目のソースのみ
+        assert!(samples[..480].iter().all(|s| (s - 0.1).abs() < 1e-6));
+        assert!(samples[480..].iter().all(|s| (s - 0.4).abs() < 1e-6));
+        // 2 つ目のソースの続きが届くまで次のフレームは待つ
+        assert!(mixer.pop().is_none());
+        mixer.push(1, &mixer_frame(0.3), 15_000);
+        let (samples, timestamp_us) = mixer.pop().unwrap();
+        assert_eq!(timestamp_us, 10_000);
+        assert!(samples.iter().all(|s| (s - 0.4).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_mixer_muted_source_is_silent() {
+        let mut mixer = AudioMixer::new(2, 480, 48000, 2);
+        mixer.set_muted(1, true);
+        mixer.push(0, &mixer_frame(0.2), 0);
+        mixer.push(1, &mixer_frame(0.5), 0);
+        let (samples, _) = mixer.pop().unwrap();
+        assert!(samples.iter().all(|s| (s - 0.2).abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_mixer_does_not_wait_for_stalled_source() {
+        let mut mixer = AudioMixer::new(2, 480, 48000, 2);
+        mixer.push(0, &mixer_frame(0.2), 0);
+        mixer.push(1, &mixer_frame(0.2), 0);
+        let mut frames = 0;
+        for i in 1..10u64 {
+            mixer.push(0, &mixer_frame(0.2), i * 10_000);
+            while let Some((samples, _)) = mixer.pop() {
+                frames += 1;
+                // 止まったソースの分は無音
+                if frames > 1 {
+                    assert!(samples.iter().all(|s| (s - 0.2).abs() < 1e-6));
+                }
+            }
+        }
+        // 60ms 遅れるまでは待ち、それ以降は先頭のソースだけで出力する
+        assert_eq!(frames, 10);
+    }
+
+    #[test]
+    fn test_mixer_follows_faster_source() {
+        let mut mixer = AudioMixer::new(2, 480, 48000, 2);
+        for i in 0..100u64 {
+            mixer.push(0, &mixer_frame(0.1), i * 10_000);
+            // 1% 速いクロックのソース: 10ms 分のサンプルが 9.9ms ごとに届く
+            mixer.push(1, &mixer_frame(0.1), i * 9_900);
+            while mixer.pop().is_some() {}
+        }
+        // 重なった分を捨てるので溜まり続けない
+        let buffered = mixer.sources[1].samples.len() / 2;
+        assert!(buffered <= 480 * 2, "buffered: {}", buffered);
+    }
+
+    #[test]
+    fn test_mixer_clamps_sum() {
+        let mut mixer = AudioMixer::new(2, 480, 48000, 2);
+        mixer.push(0, &mixer_frame(0.8), 0);
+        mixer.push(1, &mixer_frame(0.8), 0);
+        let (samples, _) = mixer.pop().unwrap();
+        assert!(samples.iter().all(|s| *s == 1.0));
+    }
+}
@@ -1,10 +1,72 @@
-use anyhow::Result;
-use core_types::{AudioEncodeResult, AudioEncoderFactory, AudioFrame};
+use anyhow::{bail, Result};
+use audio_dsp::{LinearResampler, Resampler};
+use core_types::{
+    AudioEncodeResult, AudioEncoderFactory, AudioEncoderShutdown, AudioFrame, LogThrottle,
+};
+use std::borrow::Cow;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 
+/// Opus のアプリケーション（エンコーダーのチューニング）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpusApplication {
+    /// 音楽・効果音向け（デフォルト）
+    #[default]
+    Audio,
+    /// 先読みを減らして遅延を最小にする（音声向けのモードを使わない分、低ビットレートでの音質は落ちる）
+    LowDelay,
+}
+
+impl OpusApplication {
+    fn to_raw(self) -> i32 {
+        match self {
+            OpusApplication::Audio => opus_sys::OPUS_APPLICATION_AUDIO as i32,
+            OpusApplication::LowDelay => opus_sys::OPUS_APPLICATION_RESTRICTED_LOWDELAY as i32,
+        }
+    }
+}
+
+impl std::str::FromStr for OpusApplication {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "audio" => Ok(OpusApplication::Audio),
+            "lowdelay" | "low-delay" => Ok(OpusApplication::LowDelay),
+            other => Err(format!(
+                "unsupported Opus application: {} (expected \"audio\" or \"lowdelay\")",
+                other
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OpusApplication {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpusApplication::Audio => write!(f, "audio"),
+            OpusApplication::LowDelay => write!(f, "lowdelay"),
+        }
+    }
+}
+
+/// Opus のフレーム 1 つ（20ms 以下）の最大バイト数（RFC 6716）
+const MAX_FRAME_BYTES: usize = 1275;
+/// Opus のパケットに入れられる最長の音声（ms）
+const MAX_PACKET_DURATION_MS: u32 = 120;
+
+/// `frame_duration_ms` の Opus パケットの最大バイト数（ビットレートによらない上限）
+///
+/// 20ms を超えるパケットは 20ms 以下のフレームを束ねたもので、TOC・フレーム数と各フレームの長さの分が付く
+pub fn max_packet_bytes(frame_duration_ms: u32) -> usize {
+    let frames = frame_duration_ms.div_ceil(20).max(1) as usize;
+    2 + frames * (2 + MAX_FRAME_BYTES)
+}
+
 /// Opus エンコーダーの Rust ラッパー
+///
+/// 複製できないので、エンコーダーは drop で 1 度だけ破棄される
 pub struct OpusEncoderWrapper {
     encoder: *mut opus_sys::OpusEncoder,
 }
@@ -12,12 +74,21 @@ pub struct OpusEncoderWrapper {
 impl OpusEncoderWrapper {
     /// 新しいエンコーダーを作成
     pub fn new(sample_rate: i32, channels: i32) -> Result<Self> {
+        Self::with_application(sample_rate, channels, OpusApplication::Audio)
+    }
+
+    /// アプリケーションを指定してエンコーダーを作成
+    pub fn with_application(
+        sample_rate: i32,
+        channels: i32,
+        application: OpusApplication,
+    ) -> Result<Self> {
         let mut error: i32 = 0;
         let encoder = unsafe {
             opus_sys::opus_encoder_create(
                 sample_rate,
                 channels,
-                opus_sys::OPUS_APPLICATION_AUDIO as i32,
+                application.to_raw(),
                 &mut error as *mut i32,
             )
         };
@@ -40,8 +111,26 @@ impl OpusEncoderWrapper {
 
     /// f32 サンプルをエンコード
     pub fn encode_float(&mut self, pcm: &[f32], output: &mut [u8]) -> Result<usize> {
+        let encoded_len = self.encode_float_raw(pcm, output);
+        if encoded_len < 0 {
+            return Err(anyhow::anyhow!("Encoding failed: error {}", encoded_len));
+        }
+
+        Ok(encoded_len as usize)
+    }
+
+    /// f32 サンプルを `output` にエンコードする
+    ///
+    /// `output` に収まらなかった（OPUS_BUFFER_TOO_SMALL）場合は、どのパケットも収まる大きさまで
+    /// 広げて 1 度だけやり直す（フレームを捨てると音が途切れるため）
+    pub fn encode_float_into(&mut self, pcm: &[f32], output: &mut Vec<u8>) -> Result<usize> {
+        encode_growing(output, |output| self.encode_float_raw(pcm, output))
+    }
+
+    /// opus_encode_float の戻り値（負ならエラーコード）
+    fn encode_float_raw(&mut self, pcm: &[f32], output: &mut [u8]) -> i32 {
         let frame_size = (pcm.len() / 2) as i32; // ステレオなので /2
-        let encoded_len = unsafe {
+        unsafe {
             opus_sys::opus_encode_float(
                 self.encoder,
                 pcm.as_ptr(),
@@ -49,14 +138,28 @@ impl OpusEncoderWrapper {
                 output.as_mut_ptr(),
                 output.len() as i32,
             )
-        };
-
-        if encoded_len < 0 {
-            return Err(anyhow::anyhow!("Encoding failed: error {}", encoded_len));
         }
+    }
+}
 
-        Ok(encoded_len as usize)
+/// `encode` で `output` にエンコードし、OPUS_BUFFER_TOO_SMALL なら `output` を広げて 1 度だけやり直す
+fn encode_growing(output: &mut Vec<u8>, mut encode: impl FnMut(&mut [u8]) -> i32) -> Result<usize> {
+    let mut encoded_len = encode(output);
+    if encoded_len == opus_sys::OPUS_BUFFER_TOO_SMALL {
+        let grown = (output.len() * 2).max(max_packet_bytes(MAX_PACKET_DURATION_MS));
+        warn!(
+            "Opus packet did not fit in {} bytes, retrying with {} bytes",
+            output.len(),
+            grown
+        );
+        output.resize(grown, 0);
+        encoded_len = encode(output);
     }
+    if encoded_len < 0 {
+        return Err(anyhow::anyhow!("Encoding failed: error {}", encoded_len));
+    }
+
+    Ok(encoded_len as usize)
 }
 
 impl Drop for OpusEncoderWrapper {
@@ -86,12 +189,97 @@ fn is_silent(samples: &[f32]) -> bool {
     rms < SILENCE_THRESHOLD
 }
 
+/// エンコーダーの入力サンプルレート・チャンネル数
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS: usize = 2;
+
+/// 音声フレームのサンプルをエンコーダーの入力形式（48kHz ステレオ）に揃える
+///
+/// エンコーダーはフレーム長をステレオ前提で数えるので、形式が違うまま渡すと音が壊れる。
+/// 一致していればそのまま借用し、違えばリサンプル・チャンネル変換したものを返す。
+/// リサンプラーはフレームをまたいで補間を続けるので、入力形式が変わるまで同じものを使う。
+#[derive(Default)]
+pub struct EncoderFormatConverter {
+    /// 変換中の入力形式 (サンプルレート, チャンネル数) とそのリサンプラー
+    resampler: Option<((u32, u16), LinearResampler)>,
+}
+
+impl EncoderFormatConverter {
+    pub fn convert<'a>(&mut self, frame: &'a AudioFrame) -> Result<Cow<'a, [f32]>> {
+        if frame.sample_rate == 0 || frame.channels == 0 {
+            bail!(
+                "Invalid audio frame format: {}Hz, {}ch",
+                frame.sample_rate,
+                frame.channels
+            );
+        }
+        if frame.samples.len() % frame.channels as usize != 0 {
+            bail!(
+                "Audio frame has {} samples, not a multiple of {} channels",
+                frame.samples.len(),
+                frame.channels
+            );
+        }
+        if frame.sample_rate == SAMPLE_RATE && frame.channels as usize == CHANNELS {
+            self.resampler = None;
+            return Ok(Cow::Borrowed(&frame.samples));
+        }
+        let format = (frame.sample_rate, frame.channels);
+        if self
+            .resampler
+            .as_ref()
+            .is_some_and(|(current, _)| *current != format)
+        {
+            self.resampler = None;
+        }
+        let (_, resampler) = self.resampler.get_or_insert_with(|| {
+            (
+                format,
+                LinearResampler::new(frame.sample_rate, SAMPLE_RATE, frame.channels),
+            )
+        });
+        let resampled = resampler.process(&frame.samples);
+        Ok(Cow::Owned(audio_dsp::convert_to_stereo(
+            &resampled,
+            frame.channels,
+        )))
+    }
+}
+
+/// キャプチャが出力する 10ms フレームを束ねて作れる Opus のフレーム長
+pub const SUPPORTED_FRAME_DURATIONS_MS: [u32; 4] = [10, 20, 40, 60];
+
 /// Opus エンコーダーファクトリ
-pub struct OpusEncoderFactory;
+pub struct OpusEncoderFactory {
+    frame_duration_ms: u32,
+    application: OpusApplication,
+}
 
 impl OpusEncoderFactory {
     pub fn new() -> Self {
-        Self
+        Self {
+            frame_duration_ms: 10,
+            application: OpusApplication::default(),
+        }
+    }
+
+    /// Opus のアプリケーションを設定
+    pub fn with_application(mut self, application: OpusApplication) -> Self {
+        self.application = application;
+        self
+    }
+
+    /// Opus のフレーム長を設定（長いほど低ビットレートで効率が良いが遅延が増える）
+    pub fn with_frame_duration_ms(mut self, frame_duration_ms: u32) -> Result<Self> {
+        if !SUPPORTED_FRAME_DURATIONS_MS.contains(&frame_duration_ms) {
+            bail!(
+                "Unsupported Opus frame duration: {}ms (supported: {:?})",
+                frame_duration_ms,
+                SUPPORTED_FRAME_DURATIONS_MS
+            );
+        }
+        self.frame_duration_ms = frame_duration_ms;
+        Ok(self)
     }
 }
 
@@ -101,15 +289,26 @@ impl AudioEncoderFactory for OpusEncoderFactory {
     ) -> (
         tokio::sync::mpsc::Sender<AudioFrame>,
         tokio::sync::mpsc::UnboundedReceiver<AudioEncodeResult>,
+        AudioEncoderShutdown,
     ) {
         let (frame_tx, mut frame_rx) = mpsc::channel::<AudioFrame>(100);
         let (result_tx, result_rx) = mpsc::unbounded_channel::<AudioEncodeResult>();
+        let (shutdown, mut shutdown_signal) = AudioEncoderShutdown::new();
+        let frame_duration_ms = self.frame_duration_ms;
+        let application = self.application;
 
         tokio::spawn(async move {
-            info!("Opus encoder worker started");
+            info!(
+                "Opus encoder worker started ({}ms frames, application: {})",
+                frame_duration_ms, application
+            );
 
             // エンコーダーを初期化
-            let mut encoder = match OpusEncoderWrapper::new(48000, 2) {
+            let mut encoder = match OpusEncoderWrapper::with_application(
+                SAMPLE_RATE as i32,
+                CHANNELS as i32,
+                application,
+            ) {
                 Ok(enc) => enc,
                 Err(e) => {
                     error!("Failed to create Opus encoder: {}", e);
@@ -122,40 +321,95 @@ impl AudioEncoderFactory for OpusEncoderFactory {
                 warn!("Failed to set Opus bitrate: {}", e);
             }
 
-            let mut encoded_buffer = vec![0u8; 4000];
+            let mut encoded_buffer = vec![0u8; max_packet_bytes(frame_duration_ms)];
+            // 10ms フレームを目標のフレーム長まで溜めるバッファ
+            let samples_per_packet = (SAMPLE_RATE * frame_duration_ms / 1000) as usize * CHANNELS;
+            let mut pending: Vec<f32> = Vec::with_capacity(samples_per_packet * 2);
+            // pending の先頭サンプルのキャプチャ時刻
+            let mut pending_timestamp_us = 0u64;
+            // 変換が必要だった入力形式（変わったときだけログを出す）
+            let mut converted_format: Option<(u32, u16)> = None;
+            let mut converter = EncoderFormatConverter::default();
+            let mut convert_error_log = LogThrottle::default();
+            let mut encode_error_log = LogThrottle::default();
 
-            loop {
-                match frame_rx.recv().await {
+            'worker: loop {
+                let frame = tokio::select! {
+                    frame = frame_rx.recv() => frame,
+                    _ = shutdown_signal.requested() => {
+                        debug!("Opus encoder worker: shutdown requested");
+                        break;
+                    }
+                };
+                match frame {
                     Some(frame) => {
-                        // 無音判定
-                        let silent = is_silent(&frame.samples);
+                        let samples = match converter.convert(&frame) {
+                            Ok(samples) => samples,
+                            Err(e) => {
+                                if let Some(suppressed) = convert_error_log.check() {
+                                    warn!(
+                                        "Dropping audio frame: {} ({} similar suppressed)",
+                                        e, suppressed
+                                    );
+                                }
+                                continue;
+                            }
+                        };
+                        let format = (frame.sample_rate, frame.channels);
+                        if matches!(samples, Cow::Owned(_)) && converted_format != Some(format) {
+                            warn!(
+                                "Audio frames are {}Hz/{}ch, converting to {}Hz/{}ch for Opus",
+                                format.0, format.1, SAMPLE_RATE, CHANNELS
+                            );
+                            converted_format = Some(format);
+                        }
+                        if pending.is_empty() {
+                            pending_timestamp_us = frame.timestamp_us;
+                        }
+                        pending.extend_from_slice(&samples);
+
+                        while pending.len() >= samples_per_packet {
+                            let packet = &pending[..samples_per_packet];
+
+                            // 無音判定
+                            let silent = is_silent(packet);
 
-                        // フレームをエンコード（f32 サンプルを直接エンコード）
-                        let encoded_len =
-                            match encoder.encode_float(&frame.samples, &mut encoded_buffer) {
+                            // フレームをエンコード（f32 サンプルを直接エンコード）
+                            let encoded = encoder.encode_float_into(packet, &mut encoded_buffer);
+                            pending.drain(..samples_per_packet);
+                            let timestamp_us = pending_timestamp_us;
+                            pending_timestamp_us += frame_duration_ms as u64 * 1000;
+                            let encoded_len = match encoded {
                                 Ok(len) => len,
                                 Err(e) => {
-                                    error!("Failed to encode audio frame: {}", e);
+                                    if let Some(suppressed) = encode_error_log.check() {
+                                        error!(
+                                            "Failed to encode audio frame: {} ({} similar suppressed)",
+                                            e, suppressed
+                                        );
+                                    }
                                     continue;
                                 }
                             };
 
-                        // エンコード結果を送信
-                        let result = AudioEncodeResult {
-                            encoded_data: encoded_buffer[..encoded_len].to_vec(),
-                            duration: Duration::from_millis(10), // 10msフレーム
-                            is_silent: silent,
-                        };
+                            // エンコード結果を送信
+                            let result = AudioEncodeResult {
+                                encoded_data: encoded_buffer[..encoded_len].to_vec(),
+                                duration: Duration::from_millis(frame_duration_ms as u64),
+                                is_silent: silent,
+                                timestamp_us,
+                            };
 
-                        if let Err(e) = result_tx.send(result) {
-                            error!("Failed to send encode result: {}", e);
-                            break;
-                        }
+                            if let Err(e) = result_tx.send(result) {
+                                error!("Failed to send encode result: {}", e);
+                                break 'worker;
+                            }
 
-                        debug!(
-                            "Encoded audio frame: {} bytes, silent: {}",
-                            encoded_len, silent
-                        );
+                            debug!(
+                                "Encoded audio frame: {} bytes, silent: {}",
+                                encoded_len, silent
+                            );
+                        }
                     }
                     None => {
                         debug!("Audio frame channel closed");
@@ -164,9 +418,41 @@ impl AudioEncoderFactory for OpusEncoderFactory {
                 }
             }
 
+            // エンコーダーを破棄してから停止を知らせる（`AudioEncoderShutdown::stopped`）
+            drop(encoder);
             info!("Opus encoder worker stopped");
+            drop(shutdown_signal);
         });
 
-        (frame_tx, result_rx)
+        (frame_tx, result_rx, shutdown)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_too_small_is_retried_with_a_larger_buffer() {
+        // 300 バイト未満の出力先には収まらないパケット
+        let mut calls = Vec::new();
+        let mut encode = |output: &mut [u8]| {
+            calls.push(output.len());
+            if output.len() < 300 {
+                opus_sys::OPUS_BUFFER_TOO_SMALL
+            } else {
+                output[..300].fill(1);
+                300
+            }
+        };
+        let mut output = vec![0u8; 16];
+        assert_eq!(encode_growing(&mut output, &mut encode).unwrap(), 300);
+        assert_eq!(output.len(), max_packet_bytes(MAX_PACKET_DURATION_MS));
+        assert_eq!(calls, vec![16, output.len()]);
+
+        // 広げても収まらなければ 2 回目の結果をエラーにする（何度もやり直さない）
+        let mut output = vec![0u8; 16];
+        let result = encode_growing(&mut output, |_| opus_sys::OPUS_BUFFER_TOO_SMALL);
+        assert!(result.is_err());
     }
 }
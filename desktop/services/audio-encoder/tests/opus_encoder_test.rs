@@ -1,5 +1,8 @@
 use anyhow::Result;
-use audio_encoder::{OpusEncoderFactory, OpusEncoderWrapper};
+use audio_encoder::{
+    max_packet_bytes, EncoderFormatConverter, OpusApplication, OpusEncoderFactory,
+    OpusEncoderWrapper,
+};
 use core_types::{AudioEncoderFactory, AudioFrame};
 use std::path::PathBuf;
 use std::sync::Once;
@@ -338,7 +341,7 @@ async fn test_opus_encoder_factory() -> Result<()> {
     init_tracing();
 
     let factory = OpusEncoderFactory::new();
-    let (frame_tx, mut result_rx) = factory.setup();
+    let (frame_tx, mut result_rx, _shutdown) = factory.setup();
 
     let config = SineWaveConfig {
         frequency: 440.0,
@@ -375,3 +378,243 @@ async fn test_opus_encoder_factory() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_opus_encoder_factory_20ms_frames() -> Result<()> {
+    init_tracing();
+
+    let factory = OpusEncoderFactory::new().with_frame_duration_ms(20)?;
+    let (frame_tx, mut result_rx, _shutdown) = factory.setup();
+
+    let config = SineWaveConfig {
+        frequency: 440.0,
+        amplitude: 0.5,
+        duration_secs: 0.025,
+    };
+    let frames = generate_sine_wave(config);
+    assert_eq!(frames.len(), 2);
+
+    for frame in frames {
+        frame_tx.send(frame).await?;
+    }
+    drop(frame_tx);
+
+    // 10ms フレーム2つが 20ms のパケット1つにまとめられる
+    let result = result_rx.recv().await.expect("no encoded result");
+    assert_eq!(result.duration, Duration::from_millis(20));
+    assert!(result_rx.recv().await.is_none());
+
+    let mut decoder = OpusDecoderWrapper::new(48000, 2)?;
+    let mut decoded_buffer = vec![0f32; SAMPLES_PER_FRAME * 2 * 2];
+    let decoded_len = decoder.decode_float(&result.encoded_data, &mut decoded_buffer)?;
+    assert_eq!(decoded_len, SAMPLES_PER_FRAME * 2 * 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_worst_case_40ms_frames_are_not_dropped() -> Result<()> {
+    init_tracing();
+
+    let factory = OpusEncoderFactory::new().with_frame_duration_ms(40)?;
+    let (frame_tx, mut result_rx, _shutdown) = factory.setup();
+
+    // フルスケールのホワイトノイズ（最も圧縮が効かない入力）
+    let mut seed = 0x1234_5678u32;
+    let frame_count = 100;
+    for frame_idx in 0..frame_count {
+        let samples = (0..SAMPLES_PER_FRAME * CHANNELS as usize)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                (seed >> 8) as f32 / (1u32 << 23) as f32 - 1.0
+            })
+            .collect();
+        frame_tx
+            .send(AudioFrame {
+                samples,
+                sample_rate: SAMPLE_RATE,
+                channels: CHANNELS,
+                timestamp_us: frame_idx as u64 * FRAME_DURATION_MS as u64 * 1000,
+            })
+            .await?;
+    }
+    drop(frame_tx);
+
+    let mut decoder = OpusDecoderWrapper::new(48000, 2)?;
+    let mut decoded_buffer = vec![0f32; SAMPLES_PER_FRAME * 2 * 4];
+    let mut packets = 0;
+    while let Some(result) = result_rx.recv().await {
+        assert_eq!(result.duration, Duration::from_millis(40));
+        assert!(!result.encoded_data.is_empty());
+        assert!(result.encoded_data.len() <= max_packet_bytes(40));
+        let decoded_len = decoder.decode_float(&result.encoded_data, &mut decoded_buffer)?;
+        assert_eq!(decoded_len, SAMPLES_PER_FRAME * 2 * 4);
+        packets += 1;
+    }
+    // 10ms フレーム 4 つで 40ms のパケット 1 つ（取りこぼしなし）
+    assert_eq!(packets, frame_count / 4);
+
+    Ok(())
+}
+
+#[test]
+fn test_max_packet_bytes() {
+    assert_eq!(max_packet_bytes(10), 1279);
+    assert_eq!(max_packet_bytes(20), 1279);
+    // 20ms を超えると 20ms のフレームを束ねる
+    assert_eq!(max_packet_bytes(40), 2556);
+    assert_eq!(max_packet_bytes(60), 3833);
+}
+
+#[test]
+fn test_opus_frame_duration_validation() {
+    assert!(OpusEncoderFactory::new().with_frame_duration_ms(20).is_ok());
+    assert!(OpusEncoderFactory::new().with_frame_duration_ms(40).is_ok());
+    assert!(OpusEncoderFactory::new()
+        .with_frame_duration_ms(15)
+        .is_err());
+    assert!(OpusEncoderFactory::new().with_frame_duration_ms(0).is_err());
+}
+
+#[test]
+fn test_encode_with_low_delay_application() -> Result<()> {
+    assert_eq!("lowdelay".parse(), Ok(OpusApplication::LowDelay));
+    assert_eq!("audio".parse(), Ok(OpusApplication::Audio));
+    assert!("voip".parse::<OpusApplication>().is_err());
+
+    let frames = generate_sine_wave(SineWaveConfig {
+        frequency: 440.0,
+        amplitude: 0.5,
+        duration_secs: 0.1,
+    });
+    let mut encoder = OpusEncoderWrapper::with_application(48000, 2, OpusApplication::LowDelay)?;
+    let mut encoded_buffer = vec![0u8; 4000];
+    for frame in &frames {
+        let encoded_len = encoder.encode_float(&frame.samples, &mut encoded_buffer)?;
+        assert!(encoded_len > 0);
+    }
+    Ok(())
+}
+
+/// 44.1kHz モノラルの 440Hz の正弦波
+fn sine_44k(index: u64) -> f32 {
+    0.5 * (2.0 * std::f32::consts::PI * 440.0 * index as f32 / 44100.0).sin()
+}
+
+/// 44.1kHz モノラルの `frame_index` 番目の 10ms フレーム（441 サンプル、位相は続けて並べると繋がる）
+fn mono_44k_frame(frame_index: u64) -> AudioFrame {
+    let samples = (0..441).map(|i| sine_44k(frame_index * 441 + i)).collect();
+    AudioFrame {
+        samples,
+        sample_rate: 44100,
+        channels: 1,
+        timestamp_us: frame_index * 10_000,
+    }
+}
+
+#[test]
+fn test_converter_converts_44k_mono() -> Result<()> {
+    let mut converter = EncoderFormatConverter::default();
+    let frame = mono_44k_frame(0);
+    let samples = converter.convert(&frame)?;
+    // 48kHz ステレオの 10ms 分（最後のサンプルは次のフレームと補間するまで持ち越す）
+    assert_eq!(samples.len(), (SAMPLES_PER_FRAME - 1) * CHANNELS as usize);
+    // モノラルは両チャンネルに複製される
+    assert!(samples.chunks_exact(2).all(|lr| lr[0] == lr[1]));
+
+    let stereo = generate_sine_wave(SineWaveConfig {
+        frequency: 440.0,
+        amplitude: 0.5,
+        duration_secs: 0.01,
+    });
+    let samples = converter.convert(&stereo[0])?;
+    assert!(matches!(samples, std::borrow::Cow::Borrowed(_)));
+
+    let broken = AudioFrame {
+        channels: 0,
+        ..mono_44k_frame(0)
+    };
+    assert!(converter.convert(&broken).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_converter_is_continuous_across_frames() -> Result<()> {
+    // 10ms ずつ変換して繋げた出力が、48kHz で取った正弦波と境界でもずれない
+    let mut converter = EncoderFormatConverter::default();
+    let mut left = Vec::new();
+    for frame_index in 0..5 {
+        let frame = mono_44k_frame(frame_index);
+        let samples = converter.convert(&frame)?;
+        left.extend(samples.chunks_exact(2).map(|lr| lr[0]));
+    }
+    assert_eq!(left.len(), SAMPLES_PER_FRAME * 5 - 1);
+    for (i, sample) in left.iter().enumerate() {
+        let expected =
+            0.5 * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / SAMPLE_RATE as f32).sin();
+        assert!(
+            (sample - expected).abs() < 1e-3,
+            "sample {}: {} != {}",
+            i,
+            sample,
+            expected
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_opus_encoder_factory_converts_mismatched_frames() -> Result<()> {
+    init_tracing();
+
+    let factory = OpusEncoderFactory::new();
+    let (frame_tx, mut result_rx, _shutdown) = factory.setup();
+    // 変換では最後のサンプルを持ち越すので、3 フレーム分で 2 パケットになる
+    for i in 0..3 {
+        frame_tx.send(mono_44k_frame(i)).await?;
+    }
+    drop(frame_tx);
+
+    // 変換後の 10ms フレームごとに 1 パケット
+    let mut decoder = OpusDecoderWrapper::new(48000, 2)?;
+    let mut packets = 0;
+    while let Some(result) = result_rx.recv().await {
+        assert_eq!(result.duration, Duration::from_millis(10));
+        let mut decoded_buffer = vec![0f32; SAMPLES_PER_FRAME * 2];
+        let decoded_len = decoder.decode_float(&result.encoded_data, &mut decoded_buffer)?;
+        assert_eq!(decoded_len, SAMPLES_PER_FRAME * 2);
+        packets += 1;
+    }
+    assert_eq!(packets, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_opus_encoder_worker_shutdown() -> Result<()> {
+    init_tracing();
+
+    let factory = OpusEncoderFactory::new();
+    let stop_timeout = Duration::from_secs(5);
+
+    // 送信側を閉じるとワーカーが終わり、エンコーダーが破棄される
+    let (frame_tx, mut result_rx, mut shutdown) = factory.setup();
+    drop(frame_tx);
+    tokio::time::timeout(stop_timeout, shutdown.stopped()).await?;
+    assert!(result_rx.recv().await.is_none());
+
+    // 送信側が残っていても shutdown で止まり、以降のフレームは受け取らない
+    let (frame_tx, mut result_rx, mut shutdown) = factory.setup();
+    shutdown.shutdown();
+    tokio::time::timeout(stop_timeout, shutdown.stopped()).await?;
+    assert!(result_rx.recv().await.is_none());
+    let frame = generate_sine_wave(SineWaveConfig {
+        frequency: 440.0,
+        amplitude: 0.5,
+        duration_secs: 0.01,
+    })
+    .remove(0);
+    assert!(frame_tx.send(frame).await.is_err());
+
+    Ok(())
+}
@@ -0,0 +1,311 @@
+// キャプチャが途切れた間の音声の穴埋め
+//
+// WASAPI のキャプチャはデバイスの切り替えや一時的な不調で数十〜数百 ms 止まることがあり、
+// その間は音声トラックに何も書かれない。ブラウザのジッターバッファは空になってポップノイズを出し、
+// 再開後にタイムラインを合わせ直す。実フレームが一定時間（30ms）届かなければ、10ms ごとのタイマーで
+// 無音（または聞こえない程度のコンフォートノイズ）のフレームを作り、途切れた分から埋めて送る。
+// 最初の実フレームが届くまでは形式（サンプルレート・チャンネル数）が分からないので埋めない。
+//
+// 再開後の実フレームは届くのが遅れた分だけ前のタイムスタンプを持つことがあるので、最後に作った
+// 埋めるフレームは次のタイマーまで送らずに持っておき、実フレームが届いたらそのタイムスタンプより
+// 前で切る（埋めたフレームと実フレームの時間が重ならないようにする）。
+
+use core_types::AudioFrame;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+/// 実フレームがこれだけ届かなければ途切れたとみなす
+pub const GAP_TIMEOUT: Duration = Duration::from_millis(30);
+/// 埋めるフレームの長さ（キャプチャ・ミキサーと同じ 10ms）
+const FILL_FRAME_DURATION: Duration = Duration::from_millis(10);
+/// コンフォートノイズの振幅（約 -80 dBFS）
+const COMFORT_NOISE_AMPLITUDE: f32 = 1e-4;
+
+/// 途切れた間に送るもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GapFill {
+    /// 無音（Opus の DTX が効くので帯域はほとんど使わない）
+    Silence,
+    /// 聞こえない程度のホワイトノイズ（完全な無音で音が切れたように感じるのを避ける）
+    ComfortNoise,
+}
+
+impl FromStr for GapFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "silence" => Ok(GapFill::Silence),
+            "comfort-noise" => Ok(GapFill::ComfortNoise),
+            _ => Err(format!(
+                "Invalid audio gap fill: {} (expected \"silence\" or \"comfort-noise\")",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GapFill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GapFill::Silence => write!(f, "silence"),
+            GapFill::ComfortNoise => write!(f, "comfort-noise"),
+        }
+    }
+}
+
+/// 実フレームの到着を記録し、途切れた分の埋めるフレームを作る
+#[derive(Debug)]
+pub struct GapFiller {
+    fill: GapFill,
+    /// 最後に実フレームが届いた時刻
+    last_arrival: Option<Instant>,
+    /// 次のフレームが始まるはずの時刻（ここから埋める）
+    next_at: Option<Instant>,
+    /// 最後のフレームの形式
+    sample_rate: u32,
+    channels: u16,
+    /// 次に埋めるフレームのタイムスタンプ
+    next_timestamp_us: u64,
+    /// 最後に作った埋めるフレーム（次の実フレームと重ならないよう、送るのを 1 つ遅らせる）
+    held: Option<AudioFrame>,
+    /// 埋めているか（ログ用）
+    filling: bool,
+    filled_frames: u64,
+    noise_state: u32,
+}
+
+impl GapFiller {
+    pub fn new(fill: GapFill) -> Self {
+        Self {
+            fill,
+            last_arrival: None,
+            next_at: None,
+            sample_rate: 0,
+            channels: 0,
+            next_timestamp_us: 0,
+            held: None,
+            filling: false,
+            filled_frames: 0,
+            noise_state: 0x2545_f491,
+        }
+    }
+
+    /// 実フレームが届いた
+    /// 持っていた埋めるフレームがあれば、`frame` より前で切って返す（実フレームより先に送る）
+    pub fn on_frame(&mut self, frame: &AudioFrame, now: Instant) -> Option<AudioFrame> {
+        let held = self
+            .held
+            .take()
+            .and_then(|fill| clamp_before(fill, frame.timestamp_us));
+        if self.filling {
+            info!(
+                "Audio capture resumed after filling {} ms with {}",
+                self.filled_frames * FILL_FRAME_DURATION.as_millis() as u64,
+                self.fill
+            );
+            self.filling = false;
+            self.filled_frames = 0;
+        }
+        let frames = frame.samples.len() as u64 / frame.channels.max(1) as u64;
+        let duration_us = frames * 1_000_000 / frame.sample_rate.max(1) as u64;
+        self.last_arrival = Some(now);
+        self.next_at = Some(now + Duration::from_micros(duration_us));
+        self.sample_rate = frame.sample_rate;
+        self.channels = frame.channels;
+        self.next_timestamp_us = frame.timestamp_us + duration_us;
+        held
+    }
+
+    /// タイマーの時刻 `now` までに埋めるべきフレームを返す（途切れていなければ空）
+    pub fn on_tick(&mut self, now: Instant) -> Vec<AudioFrame> {
+        let (Some(last_arrival), Some(mut next_at)) = (self.last_arrival, self.next_at) else {
+            return Vec::new();
+        };
+        if now.duration_since(last_arrival) < GAP_TIMEOUT {
+            return Vec::new();
+        }
+        if !self.filling {
+            debug!(
+                "No audio frame for {:?}, filling with {}",
+                GAP_TIMEOUT, self.fill
+            );
+            self.filling = true;
+        }
+        let mut frames = Vec::new();
+        while next_at + FILL_FRAME_DURATION <= now {
+            let fill = self.fill_frame();
+            frames.extend(self.held.replace(fill));
+            next_at += FILL_FRAME_DURATION;
+        }
+        self.next_at = Some(next_at);
+        frames
+    }
+
+    fn fill_frame(&mut self) -> AudioFrame {
+        let samples_per_channel =
+            self.sample_rate as u64 * FILL_FRAME_DURATION.as_micros() as u64 / 1_000_000;
+        let len = samples_per_channel as usize * self.channels as usize;
+        let samples = match self.fill {
+            GapFill::Silence => vec![0.0; len],
+            GapFill::ComfortNoise => (0..len)
+                .map(|_| {
+                    // 線形合同法（再現性があれば十分）
+                    self.noise_state = self
+                        .noise_state
+                        .wrapping_mul(1_664_525)
+                        .wrapping_add(1_013_904_223);
+                    let unit = (self.noise_state >> 8) as f32 / (1u32 << 24) as f32;
+                    (unit * 2.0 - 1.0) * COMFORT_NOISE_AMPLITUDE
+                })
+                .collect(),
+        };
+        let frame = AudioFrame {
+            samples,
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            timestamp_us: self.next_timestamp_us,
+        };
+        self.next_timestamp_us += FILL_FRAME_DURATION.as_micros() as u64;
+        self.filled_frames += 1;
+        frame
+    }
+}
+
+/// 埋めるフレーム `fill` を、タイムスタンプ `next_timestamp_us` の実フレームより前で終わるように切る
+/// （まったく前に収まらなければ None）
+fn clamp_before(mut fill: AudioFrame, next_timestamp_us: u64) -> Option<AudioFrame> {
+    let available_us = next_timestamp_us.checked_sub(fill.timestamp_us)?;
+    let channels = fill.channels.max(1) as usize;
+    let frames = (available_us * fill.sample_rate as u64 / 1_000_000) as usize;
+    let len = (frames * channels).min(fill.samples.len());
+    if len == 0 {
+        return None;
+    }
+    fill.samples.truncate(len);
+    Some(fill)
+}
+
+/// 穴埋めのタスクを起動し、埋めた後のフレームの受信側を返す
+pub fn spawn_gap_filler(
+    fill: GapFill,
+    mut frame_rx: mpsc::Receiver<AudioFrame>,
+) -> (mpsc::Receiver<AudioFrame>, JoinHandle<()>) {
+    let (filled_tx, filled_rx) = mpsc::channel::<AudioFrame>(100);
+    info!("Audio gap fill enabled: {}", fill);
+
+    let handle = tokio::spawn(async move {
+        let mut filler = GapFiller::new(fill);
+        let mut interval = tokio::time::interval(FILL_FRAME_DURATION);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            let frames = tokio::select! {
+                // 届いている実フレームを先に見る（その分を埋めてしまわないように）
+                biased;
+                frame = frame_rx.recv() => {
+                    let Some(frame) = frame else {
+                        debug!("Audio frame channel closed, stopping gap filler");
+                        break;
+                    };
+                    let held = filler.on_frame(&frame, Instant::now());
+                    held.into_iter().chain([frame]).collect()
+                }
+                _ = interval.tick() => filler.on_tick(Instant::now()),
+            };
+            for frame in frames {
+                if filled_tx.send(frame).await.is_err() {
+                    debug!("Gap-filled audio channel closed");
+                    return;
+                }
+            }
+        }
+    });
+
+    (filled_rx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(timestamp_us: u64) -> AudioFrame {
+        AudioFrame {
+            samples: vec![0.5; 960],
+            sample_rate: 48000,
+            channels: 2,
+            timestamp_us,
+        }
+    }
+
+    #[test]
+    fn test_fills_gap_on_10ms_cadence() {
+        let mut filler = GapFiller::new(GapFill::Silence);
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+
+        // 最初の実フレームまでは形式が分からないので埋めない
+        assert!(filler.on_tick(t0 + ms(100)).is_empty());
+
+        assert!(filler.on_frame(&frame(1_000_000), t0).is_none());
+        // 30ms までは途切れたとみなさない（ジッターの範囲）
+        assert!(filler.on_tick(t0 + ms(20)).is_empty());
+        assert!(filler.on_tick(t0 + ms(29)).is_empty());
+
+        // 途切れたと分かった時点で、実フレームの終わり（10ms）から埋める（最後の 1 つは持っておく）
+        let filled = filler.on_tick(t0 + ms(30));
+        let timestamps: Vec<u64> = filled.iter().map(|f| f.timestamp_us).collect();
+        assert_eq!(timestamps, [1_010_000]);
+        assert!(filled.iter().all(|f| f.samples == vec![0.0; 960]));
+        // 以降は 10ms ごとに 1 フレーム
+        assert_eq!(filler.on_tick(t0 + ms(35)).len(), 0);
+        assert_eq!(filler.on_tick(t0 + ms(40))[0].timestamp_us, 1_020_000);
+        assert_eq!(filler.on_tick(t0 + ms(50))[0].timestamp_us, 1_030_000);
+
+        // 実フレームが戻ったら、持っていたフレームを渡して埋めるのをやめる
+        let held = filler.on_frame(&frame(1_050_000), t0 + ms(55)).unwrap();
+        assert_eq!((held.timestamp_us, held.samples.len()), (1_040_000, 960));
+        assert!(filler.on_tick(t0 + ms(60)).is_empty());
+        assert!(filler.on_tick(t0 + ms(84)).is_empty());
+        assert_eq!(filler.on_tick(t0 + ms(85)).len(), 1);
+    }
+
+    #[test]
+    fn test_fill_ends_before_the_next_real_frame() {
+        let mut filler = GapFiller::new(GapFill::Silence);
+        let t0 = Instant::now();
+        let ms = Duration::from_millis;
+        filler.on_frame(&frame(1_000_000), t0);
+        assert_eq!(filler.on_tick(t0 + ms(40)).len(), 2);
+
+        // 届くのが遅れた実フレームが、持っていたフレーム（1_030_000〜）の途中から始まる
+        let held = filler.on_frame(&frame(1_034_000), t0 + ms(45)).unwrap();
+        assert_eq!(held.timestamp_us, 1_030_000);
+        // 4ms 分（48kHz ステレオで 192 サンプル × 2ch）に切る
+        assert_eq!(held.samples.len(), 384);
+
+        // 持っていたフレームより前から始まる場合は送らない
+        assert_eq!(filler.on_tick(t0 + ms(90)).len(), 2);
+        assert!(filler.on_frame(&frame(1_040_000), t0 + ms(95)).is_none());
+    }
+
+    #[test]
+    fn test_comfort_noise_is_quiet() {
+        let mut filler = GapFiller::new(GapFill::ComfortNoise);
+        let t0 = Instant::now();
+        filler.on_frame(&frame(0), t0);
+        let filled = filler.on_tick(t0 + Duration::from_millis(40));
+        assert_eq!(filled.len(), 2);
+        let samples = &filled[0].samples;
+        assert!(samples.iter().any(|s| *s != 0.0));
+        assert!(samples.iter().all(|s| s.abs() <= COMFORT_NOISE_AMPLITUDE));
+
+        assert_eq!("comfort-noise".parse(), Ok(GapFill::ComfortNoise));
+        assert_eq!(GapFill::Silence.to_string(), "silence");
+        assert!("noise".parse::<GapFill>().is_err());
+    }
+}
@@ -1,5 +1,15 @@
+mod gap_fill;
+mod mixer;
+
+pub use gap_fill::GapFill;
+pub use mixer::SOURCE_GAIN_RANGE;
+
 use anyhow::Result;
-use core_types::{AudioEncoderFactory, AudioFrame};
+use core_types::{
+    AudioEncodeResult, AudioEncoderFactory, AudioFrame, AudioSource, AudioStreamMessage,
+    LogThrottle, Metrics,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::mpsc;
@@ -12,6 +22,17 @@ use webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSa
 pub struct AudioStreamService {
     audio_frame_rx: mpsc::Receiver<AudioFrame>,
     audio_encoder_factory: Arc<dyn AudioEncoderFactory>,
+    audio_stream_msg_rx: mpsc::Receiver<AudioStreamMessage>,
+    /// ゲーム音声にミックスする追加のソース（空ならミキサーを通さない）
+    mixed_sources: Vec<(AudioSource, mpsc::Receiver<AudioFrame>)>,
+    /// ソースごとの初期ゲイン
+    source_gains: Vec<(AudioSource, f32)>,
+    /// エンコード結果の写しを送る先（録画用）
+    encoded_tap_tx: Option<mpsc::Sender<AudioEncodeResult>>,
+    /// 監視用のメトリクス（hostd の /metrics で公開する）
+    metrics: Arc<Metrics>,
+    /// キャプチャが途切れた間に送るもの（None なら埋めない）
+    gap_fill: Option<GapFill>,
 }
 
 impl AudioStreamService {
@@ -19,14 +40,65 @@ impl AudioStreamService {
     pub fn new(
         audio_frame_rx: mpsc::Receiver<AudioFrame>,
         audio_encoder_factory: Arc<dyn AudioEncoderFactory>,
+        audio_stream_msg_rx: mpsc::Receiver<AudioStreamMessage>,
     ) -> Self {
         info!("AudioStreamService::new");
         Self {
             audio_frame_rx,
             audio_encoder_factory,
+            audio_stream_msg_rx,
+            mixed_sources: Vec::new(),
+            source_gains: Vec::new(),
+            encoded_tap_tx: None,
+            metrics: Arc::new(Metrics::default()),
+            gap_fill: None,
         }
     }
 
+    /// `source` の音声フレームをゲーム音声にミックスして 1 本のストリームで送る
+    pub fn with_mixed_source(
+        mut self,
+        source: AudioSource,
+        frame_rx: mpsc::Receiver<AudioFrame>,
+    ) -> Self {
+        self.mixed_sources.push((source, frame_rx));
+        self
+    }
+
+    /// ミックス時の `source` のゲイン（1.0 で等倍）
+    pub fn with_source_gain(mut self, source: AudioSource, gain: f32) -> Result<Self> {
+        anyhow::ensure!(
+            SOURCE_GAIN_RANGE.contains(&gain),
+            "Gain for {} must be {}-{}, got {}",
+            source,
+            SOURCE_GAIN_RANGE.start(),
+            SOURCE_GAIN_RANGE.end(),
+            gain
+        );
+        self.source_gains.retain(|(s, _)| *s != source);
+        self.source_gains.push((source, gain));
+        Ok(self)
+    }
+
+    /// エンコード結果の写しを `tap_tx` にも送る（接続の有無に関係なく、詰まっていれば捨てる）
+    pub fn with_encoded_tap(mut self, tap_tx: mpsc::Sender<AudioEncodeResult>) -> Self {
+        self.encoded_tap_tx = Some(tap_tx);
+        self
+    }
+
+    /// 送出した音声フレームと無音の割合を `metrics` に書き込む
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// キャプチャから 30ms 以上フレームが届かなければ、10ms ごとに `fill` のフレームで埋める
+    /// （キャプチャが一時的に止まってもブラウザのジッターバッファが空にならないようにする）
+    pub fn with_gap_fill(mut self, fill: GapFill) -> Self {
+        self.gap_fill = Some(fill);
+        self
+    }
+
     /// サービスを実行（ブロッキング）
     /// 音声トラックとRTPSenderを受け取り、エンコード結果を書き込む
     pub async fn run(
@@ -39,11 +111,39 @@ impl AudioStreamService {
         info!("AudioStreamService started");
 
         // エンコーダーをセットアップ
-        let (audio_encoder_tx, mut audio_result_rx) = self.audio_encoder_factory.setup();
+        let (audio_encoder_tx, mut audio_result_rx, mut audio_encoder_shutdown) =
+            self.audio_encoder_factory.setup();
+
+        // 一時停止フラグ（映像とは独立して音声だけ止められる）
+        let stream_paused = Arc::new(AtomicBool::new(false));
+        let stream_paused_for_router = stream_paused.clone();
+
+        // 追加のソースがあればミキサーを通してから転送する
+        let (audio_frame_rx, mixer_control_tx, mixer_handle) = if self.mixed_sources.is_empty() {
+            (self.audio_frame_rx, None, None)
+        } else {
+            let mut sources = vec![(AudioSource::Game, self.audio_frame_rx)];
+            sources.append(&mut self.mixed_sources);
+            let (control_tx, control_rx) = mpsc::unbounded_channel();
+            let (mixed_rx, handle) = mixer::spawn_mixer(sources, &self.source_gains, control_rx);
+            (mixed_rx, Some(control_tx), Some(handle))
+        };
+
+        // ミックス後のストリームの途切れを埋める
+        let (mut audio_frame_rx, gap_fill_handle) = match self.gap_fill {
+            Some(fill) => {
+                let (filled_rx, handle) = gap_fill::spawn_gap_filler(fill, audio_frame_rx);
+                (filled_rx, Some(handle))
+            }
+            None => (audio_frame_rx, None),
+        };
 
         // 音声フレームをエンコーダーに転送するタスクをスポーン
         let frame_router_handle = tokio::spawn(async move {
-            while let Some(frame) = self.audio_frame_rx.recv().await {
+            while let Some(frame) = audio_frame_rx.recv().await {
+                if stream_paused_for_router.load(Ordering::Relaxed) {
+                    continue;
+                }
                 if audio_encoder_tx.send(frame).await.is_err() {
                     debug!("Audio encoder channel closed");
                     break;
@@ -55,6 +155,7 @@ impl AudioStreamService {
         let mut audio_frame_count: u64 = 0;
         let mut audio_silent_count: u64 = 0;
         let mut last_audio_log = Instant::now();
+        let mut write_error_log = LogThrottle::default();
 
         // 現在のアクティブなトラック情報
         let mut current_audio_track: Option<Arc<TrackLocalStaticSample>> = None;
@@ -108,6 +209,9 @@ impl AudioStreamService {
                 result = audio_result_rx.recv() => {
                     match result {
                         Some(result) => {
+                            if let Some(tap_tx) = &self.encoded_tap_tx {
+                                let _ = tap_tx.try_send(result.clone());
+                            }
                              if let Some(track) = &current_audio_track {
                                 debug!(
                                     "Received audio encode result: {} bytes, silent: {}",
@@ -126,11 +230,16 @@ impl AudioStreamService {
                                 match track.write_sample(&sample).await {
                                     Ok(_) => {
                                         audio_frame_count += 1;
+                                        self.metrics.audio_frames_sent.fetch_add(1, Ordering::Relaxed);
                                         if result.is_silent {
                                             audio_silent_count += 1;
+                                            self.metrics.audio_frames_silent.fetch_add(1, Ordering::Relaxed);
                                         }
                                         let elapsed = last_audio_log.elapsed();
                                         if elapsed.as_secs_f32() >= 5.0 {
+                                            self.metrics.set_audio_silence_ratio(
+                                                audio_silent_count as f32 / audio_frame_count as f32,
+                                            );
                                             if audio_silent_count == audio_frame_count && audio_frame_count > 0
                                             {
                                                 warn!(
@@ -154,7 +263,12 @@ impl AudioStreamService {
                                         }
                                     }
                                     Err(e) => {
-                                        error!("Failed to write audio sample to track: {}", e);
+                                        if let Some(suppressed) = write_error_log.check() {
+                                            error!(
+                                                "Failed to write audio sample to track: {} ({} similar suppressed)",
+                                                e, suppressed
+                                            );
+                                        }
                                     }
                                 }
                              }
@@ -165,6 +279,32 @@ impl AudioStreamService {
                         }
                     }
                 }
+
+                // 3. 一時停止・再開
+                msg = self.audio_stream_msg_rx.recv() => {
+                    match msg {
+                        Some(AudioStreamMessage::Pause) => {
+                            info!("Audio stream paused");
+                            stream_paused.store(true, Ordering::Relaxed);
+                        }
+                        Some(AudioStreamMessage::Resume) => {
+                            info!("Audio stream resumed");
+                            stream_paused.store(false, Ordering::Relaxed);
+                        }
+                        Some(AudioStreamMessage::SetSourceGain { source, gain, muted }) => {
+                            let Some(control_tx) = &mixer_control_tx else {
+                                warn!("Audio mixing is disabled, ignoring gain change for {}", source);
+                                continue;
+                            };
+                            let gain = gain.clamp(*SOURCE_GAIN_RANGE.start(), *SOURCE_GAIN_RANGE.end());
+                            let _ = control_tx.send(mixer::GainChange { source, gain, muted });
+                        }
+                        None => {
+                            info!("Audio stream message channel closed");
+                            break;
+                        }
+                    }
+                }
             }
         }
 
@@ -172,7 +312,16 @@ impl AudioStreamService {
         if let Some(handle) = rtcp_drain_handle {
             handle.abort();
         }
+        // エンコーダーを止めて破棄されるまで待つ（ルーターが送信側を持っていても止まる）
+        audio_encoder_shutdown.shutdown();
+        audio_encoder_shutdown.stopped().await;
         let _ = frame_router_handle.await;
+        if let Some(handle) = mixer_handle {
+            handle.abort();
+        }
+        if let Some(handle) = gap_fill_handle {
+            handle.abort();
+        }
 
         info!("AudioStreamService stopped");
         Ok(())
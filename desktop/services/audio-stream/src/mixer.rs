@@ -0,0 +1,186 @@
+// 複数の音声ソース（ゲーム + マイク）を 1 本のストリームにまとめるタスク
+//
+// ソースごとの受信チャネルを 1 本に合流させ、audio_dsp::AudioMixer で 10ms フレームに揃えてから
+// エンコーダーへのフレームルーターに渡す。ゲイン・ミュートは AudioStreamService から変更する。
+
+use audio_dsp::AudioMixer;
+use core_types::{AudioFrame, AudioSource, LogThrottle};
+use std::ops::RangeInclusive;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// 指定できるゲインの範囲（1.0 で等倍）
+pub const SOURCE_GAIN_RANGE: RangeInclusive<f32> = 0.0..=4.0;
+
+/// ミキサーの出力フレーム（10ms @ 48kHz ステレオ）
+const MIX_FRAME_SAMPLES: usize = 480;
+const MIX_SAMPLE_RATE: u32 = 48000;
+const MIX_CHANNELS: u16 = 2;
+
+/// ゲイン・ミュートの変更
+#[derive(Debug, Clone, Copy)]
+pub struct GainChange {
+    pub source: AudioSource,
+    pub gain: f32,
+    pub muted: bool,
+}
+
+/// ミキサーのタスクを起動し、ミックス後のフレームの受信側を返す
+///
+/// `sources` の順番がミキサー内のソース番号になる。`gains` に無いソースは等倍で始める。
+pub fn spawn_mixer(
+    sources: Vec<(AudioSource, mpsc::Receiver<AudioFrame>)>,
+    gains: &[(AudioSource, f32)],
+    mut control_rx: mpsc::UnboundedReceiver<GainChange>,
+) -> (mpsc::Receiver<AudioFrame>, JoinHandle<()>) {
+    let (mixed_tx, mixed_rx) = mpsc::channel::<AudioFrame>(100);
+    let (merged_tx, mut merged_rx) = mpsc::channel::<(usize, AudioFrame)>(100);
+
+    let names: Vec<AudioSource> = sources.iter().map(|(source, _)| *source).collect();
+    let mut mixer = AudioMixer::new(
+        names.len(),
+        MIX_FRAME_SAMPLES,
+        MIX_SAMPLE_RATE,
+        MIX_CHANNELS,
+    );
+    for (source, gain) in gains {
+        if let Some(index) = names.iter().position(|name| name == source) {
+            mixer.set_gain(index, *gain);
+        }
+    }
+    info!(
+        "Audio mixer enabled: {}",
+        names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>()
+            .join(" + ")
+    );
+
+    // ソースごとの受信チャネルを 1 本に合流させる
+    for (index, (source, mut frame_rx)) in sources.into_iter().enumerate() {
+        let merged_tx = merged_tx.clone();
+        tokio::spawn(async move {
+            while let Some(frame) = frame_rx.recv().await {
+                if merged_tx.send((index, frame)).await.is_err() {
+                    break;
+                }
+            }
+            debug!("Audio source {} closed", source);
+        });
+    }
+    drop(merged_tx);
+
+    let handle = tokio::spawn(async move {
+        let mut format_log = LogThrottle::default();
+        loop {
+            tokio::select! {
+                frame = merged_rx.recv() => {
+                    let Some((index, frame)) = frame else {
+                        debug!("All audio sources closed, stopping mixer");
+                        break;
+                    };
+                    if frame.sample_rate != MIX_SAMPLE_RATE || frame.channels != MIX_CHANNELS {
+                        if let Some(suppressed) = format_log.check() {
+                            warn!(
+                                "Skipping audio frame from {} with unexpected format: {}Hz, {} ch ({} similar suppressed)",
+                                names[index], frame.sample_rate, frame.channels, suppressed
+                            );
+                        }
+                        continue;
+                    }
+                    mixer.push(index, &frame.samples, frame.timestamp_us);
+                    while let Some((samples, timestamp_us)) = mixer.pop() {
+                        let mixed = AudioFrame {
+                            samples,
+                            sample_rate: MIX_SAMPLE_RATE,
+                            channels: MIX_CHANNELS,
+                            timestamp_us,
+                        };
+                        if mixed_tx.send(mixed).await.is_err() {
+                            debug!("Mixed audio channel closed");
+                            return;
+                        }
+                    }
+                }
+                Some(change) = control_rx.recv() => {
+                    let Some(index) = names.iter().position(|name| *name == change.source) else {
+                        warn!("Audio source {} is not mixed, ignoring gain change", change.source);
+                        continue;
+                    };
+                    info!(
+                        "Audio source {}: gain {:.2}{}",
+                        change.source,
+                        change.gain,
+                        if change.muted { " (muted)" } else { "" }
+                    );
+                    mixer.set_gain(index, change.gain);
+                    mixer.set_muted(index, change.muted);
+                }
+            }
+        }
+    });
+
+    (mixed_rx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(value: f32, timestamp_us: u64) -> AudioFrame {
+        AudioFrame {
+            samples: vec![value; MIX_FRAME_SAMPLES * 2],
+            sample_rate: MIX_SAMPLE_RATE,
+            channels: MIX_CHANNELS,
+            timestamp_us,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mixer_task_mixes_sources_and_applies_gain_changes() {
+        let (game_tx, game_rx) = mpsc::channel(10);
+        let (mic_tx, mic_rx) = mpsc::channel(10);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (mut mixed_rx, handle) = spawn_mixer(
+            vec![(AudioSource::Game, game_rx), (AudioSource::Mic, mic_rx)],
+            &[(AudioSource::Mic, 0.5)],
+            control_rx,
+        );
+
+        // 最初のフレームは先に届いたソースだけで出ることがあるので、2 フレーム目から確認する
+        game_tx.send(frame(0.2, 0)).await.unwrap();
+        mic_tx.send(frame(0.4, 0)).await.unwrap();
+        // 時間を止めているので、sleep は他のタスクが処理を終えてから即座に進む
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        game_tx.send(frame(0.2, 10_000)).await.unwrap();
+        mic_tx.send(frame(0.4, 10_000)).await.unwrap();
+        let mixed = loop {
+            let mixed = mixed_rx.recv().await.unwrap();
+            if mixed.timestamp_us == 10_000 {
+                break mixed;
+            }
+        };
+        assert!(mixed.samples.iter().all(|s| (s - 0.4).abs() < 1e-6));
+
+        control_tx
+            .send(GainChange {
+                source: AudioSource::Mic,
+                gain: 1.0,
+                muted: true,
+            })
+            .unwrap();
+        // ゲイン変更がフレームより先に処理されるよう待つ
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        game_tx.send(frame(0.2, 20_000)).await.unwrap();
+        mic_tx.send(frame(0.4, 20_000)).await.unwrap();
+        let mixed = mixed_rx.recv().await.unwrap();
+        assert_eq!(mixed.timestamp_us, 20_000);
+        assert!(mixed.samples.iter().all(|s| (s - 0.2).abs() < 1e-6));
+
+        drop(game_tx);
+        drop(mic_tx);
+        handle.await.unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+// 遅延の分布（固定のバケットで数えるヒストグラム）
+//
+// 平均ではたまに起きる長い詰まり（p99）が埋もれてしまうので、キャプチャからエンコード、
+// エンコードから送出までの遅延をバケットごとに数え、区間ごとに p50 / p95 / p99 を出す。
+// 依存を増やさないよう HDR Histogram の代わりに、短い遅延ほど細かい固定のバケットを使う。
+// 百分位はその値が入ったバケットの上端（ただし区間内の最大値を超えない）で、誤差はバケット幅まで。
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// バケットの上端（マイクロ秒、10ms までは 1ms 刻み）。これを超える値は最後の溢れバケットに入る
+const BUCKET_BOUNDS_US: [u64; 40] = [
+    1_000, 2_000, 3_000, 4_000, 5_000, 6_000, 7_000, 8_000, 9_000, 10_000, 12_000, 14_000, 16_000,
+    18_000, 20_000, 25_000, 30_000, 35_000, 40_000, 45_000, 50_000, 60_000, 70_000, 80_000, 90_000,
+    100_000, 125_000, 150_000, 175_000, 200_000, 250_000, 300_000, 400_000, 500_000, 600_000,
+    700_000, 800_000, 1_000_000, 1_500_000, 2_000_000,
+];
+
+/// 固定のバケットで遅延を数えるヒストグラム
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// バケットごとの数（最後は溢れバケット）
+    counts: [u64; BUCKET_BOUNDS_US.len() + 1],
+    total: u64,
+    max: Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: [0; BUCKET_BOUNDS_US.len() + 1],
+            total: 0,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = BUCKET_BOUNDS_US.partition_point(|&bound| bound < us);
+        self.counts[bucket] += 1;
+        self.total += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// 記録した数
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// `quantile`（0.0-1.0）の値（何も記録していなければ None）
+    pub fn percentile(&self, quantile: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let upper = BUCKET_BOUNDS_US
+                    .get(bucket)
+                    .map_or(self.max, |&us| Duration::from_micros(us));
+                return Some(upper.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// p50 / p95 / p99（何も記録していなければ None）
+    pub fn percentiles(&self) -> Option<LatencyPercentiles> {
+        let ms = |quantile| self.percentile(quantile).map(|d| d.as_secs_f32() * 1000.0);
+        Some(LatencyPercentiles {
+            p50_ms: ms(0.5)?,
+            p95_ms: ms(0.95)?,
+            p99_ms: ms(0.99)?,
+        })
+    }
+
+    /// 区間の集計を始め直す
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// 遅延の百分位（ミリ秒）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LatencyPercentiles {
+    pub p50_ms: f32,
+    pub p95_ms: f32,
+    pub p99_ms: f32,
+}
+
+impl std::fmt::Display for LatencyPercentiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p50 {:.1}ms / p95 {:.1}ms / p99 {:.1}ms",
+            self.p50_ms, self.p95_ms, self.p99_ms
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentiles_of_known_inputs() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.percentiles(), None);
+
+        // 1-100ms を 1ms ずつ 1 回ずつ
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 100);
+        // 50 番目は 50ms（50ms のバケット）、95 番目は 95ms（90-100ms のバケットの上端）
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.percentile(0.95), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_millis(1)));
+
+        // ほとんど速くても、2% の詰まりは p99 に現れる（平均では 6ms 程度に埋もれる）
+        histogram.reset();
+        for _ in 0..980 {
+            histogram.record(Duration::from_micros(2_500));
+        }
+        for _ in 0..20 {
+            histogram.record(Duration::from_millis(180));
+        }
+        let percentiles = histogram.percentiles().unwrap();
+        // 2.5ms は 2-3ms のバケットの上端で数える
+        assert_eq!(percentiles.p50_ms, 3.0);
+        assert_eq!(percentiles.p95_ms, 3.0);
+        assert_eq!(percentiles.p99_ms, 180.0);
+
+        // 最後のバケットを超える値は最大値で返す
+        histogram.reset();
+        histogram.record(Duration::from_secs(5));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_secs(5)));
+    }
+}
@@ -2,12 +2,22 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 use tokio::sync::mpsc::{Receiver, Sender, UnboundedReceiver};
+use tokio::sync::watch;
+
+mod latency_histogram;
+mod metrics;
+mod pipeline_config;
+pub use latency_histogram::{LatencyHistogram, LatencyPercentiles};
+pub use metrics::Metrics;
+pub use pipeline_config::{AudioEncoderConfig, PipelineConfig, VideoEncoderConfig, WebRtcConfig};
 
 /// キャプチャサイズの指定方法
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum CaptureSize {
     /// 元画面サイズを使用
     UseSourceSize,
@@ -15,29 +25,424 @@ pub enum CaptureSize {
     Custom { width: u32, height: u32 },
 }
 
+impl CaptureSize {
+    /// 幅・高さを偶数に切り下げた指定（`UseSourceSize` はそのまま。元画面が奇数ならキャプチャ側で切り落とす）
+    pub fn to_even(&self) -> Self {
+        match self {
+            Self::UseSourceSize => Self::UseSourceSize,
+            Self::Custom { width, height } => {
+                let (width, height) = even_size(*width, *height);
+                Self::Custom { width, height }
+            }
+        }
+    }
+}
+
+/// 要求サイズと元画面のアスペクト比が違うときの合わせ方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AspectMode {
+    /// 要求サイズに引き伸ばす
+    #[default]
+    Stretch,
+    /// 全体が収まるように縮小し、余白を黒で埋める
+    Letterbox,
+    /// 要求サイズを埋めるように拡大し、はみ出た部分を切り落とす
+    Crop,
+}
+
+impl std::str::FromStr for AspectMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "stretch" => Ok(Self::Stretch),
+            "letterbox" => Ok(Self::Letterbox),
+            "crop" => Ok(Self::Crop),
+            other => Err(format!("unsupported aspect mode: {}", other)),
+        }
+    }
+}
+
+/// キャプチャフレームの画素の並び（いずれも 1 画素 4 バイト）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PixelFormat {
+    /// R, G, B, A の順
+    #[default]
+    Rgba8,
+    /// B, G, R, A の順（Windows のキャプチャ面と同じで、エンコーダーの GPU 前処理で変換が要らない）
+    Bgra8,
+}
+
+impl std::str::FromStr for PixelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "rgba" | "rgba8" => Ok(Self::Rgba8),
+            "bgra" | "bgra8" => Ok(Self::Bgra8),
+            other => Err(format!("unsupported pixel format: {}", other)),
+        }
+    }
+}
+
+/// キャプチャ対象のウィンドウが他のウィンドウに覆われたときの扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OcclusionPolicy {
+    /// そのままキャプチャする（覆ったウィンドウが映ることがある）
+    #[default]
+    CaptureAnyway,
+    /// 覆われている間は覆われる前の最後のフレームを送り続ける
+    FreezeOnOcclusion,
+}
+
+impl std::str::FromStr for OcclusionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "capture-anyway" | "capture" => Ok(Self::CaptureAnyway),
+            "freeze-on-occlusion" | "freeze" => Ok(Self::FreezeOnOcclusion),
+            other => Err(format!("unsupported occlusion policy: {}", other)),
+        }
+    }
+}
+
+/// 保護されたコンテンツ（DRM）で画面が真っ黒にキャプチャされたときの扱い
+///
+/// Windows.Graphics.Capture には保護されたウィンドウを除外する・含める設定はなく、
+/// SetWindowDisplayAffinity や PlayReady で保護された内容は黒く塗りつぶされたフレームとして届く。
+/// 保護を回避してキャプチャする方法はなく（回避は著作権法や利用規約に抵触しうる）、できるのは検出して知らせることだけ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProtectedContentPolicy {
+    /// 真っ黒なフレームが続いたらクライアントに通知する（フレームはそのまま送る）
+    #[default]
+    Notify,
+    /// 検出しない
+    Ignore,
+}
+
+impl std::str::FromStr for ProtectedContentPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "notify" => Ok(Self::Notify),
+            "ignore" => Ok(Self::Ignore),
+            other => Err(format!("unsupported protected content policy: {}", other)),
+        }
+    }
+}
+
+/// RGBA / BGRA のフレームが真っ黒か（格子状に間引いた画素で判定する。アルファは見ない）
+pub fn is_black_frame(data: &[u8]) -> bool {
+    const MAX_SAMPLES: usize = 4096;
+    /// 圧縮・色変換の誤差を許す明るさの上限
+    const BLACK_THRESHOLD: u8 = 4;
+    let pixels = data.len() / 4;
+    if pixels == 0 {
+        return false;
+    }
+    let step = pixels.div_ceil(MAX_SAMPLES).max(1);
+    data.chunks_exact(4)
+        .step_by(step)
+        .all(|pixel| pixel[..3].iter().all(|&v| v <= BLACK_THRESHOLD))
+}
+
+/// 画面上の矩形（スクリーン座標、right/bottom は含まない）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenRect {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl ScreenRect {
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.left && x < self.right && y >= self.top && y < self.bottom
+    }
+}
+
+/// `target` のうち `occluders` のいずれかに覆われている割合（0.0-1.0）
+///
+/// 重なったウィンドウ同士の和集合を厳密に求める代わりに、格子状に並べた点で見積もる
+pub fn occluded_fraction(target: ScreenRect, occluders: &[ScreenRect]) -> f32 {
+    const GRID: i64 = 32;
+    let width = (target.right - target.left) as i64;
+    let height = (target.bottom - target.top) as i64;
+    if width <= 0 || height <= 0 {
+        return 0.0;
+    }
+    let mut covered = 0;
+    for row in 0..GRID {
+        for col in 0..GRID {
+            // 各セルの中心
+            let x = target.left as i64 + (2 * col + 1) * width / (2 * GRID);
+            let y = target.top as i64 + (2 * row + 1) * height / (2 * GRID);
+            if occluders.iter().any(|r| r.contains(x as i32, y as i32)) {
+                covered += 1;
+            }
+        }
+    }
+    covered as f32 / (GRID * GRID) as f32
+}
+
 /// Capture の初期設定/変更パラメータ
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CaptureConfig {
     pub size: CaptureSize,
+    /// `size` が元画面とアスペクト比が違う場合の合わせ方
+    pub aspect: AspectMode,
     pub fps: u32,
+    /// エンコードに渡すフレームの最大画素数（クライアントの要求サイズとは独立した上限）
+    pub max_encode_pixels: Option<u32>,
+    /// マウスカーソルをキャプチャに含めるか
+    pub show_cursor: bool,
+    /// キャプチャする画素の並び
+    pub pixel_format: PixelFormat,
+    /// 対象のウィンドウが覆われたときの扱い（ウィンドウキャプチャのみ）
+    pub occlusion: OcclusionPolicy,
+    /// 保護されたコンテンツで真っ黒になったときの扱い
+    pub protected_content: ProtectedContentPolicy,
+    /// ダーティ領域を取得し、前のフレームから変化がないフレームを送らない（`Frame::dirty_fraction`）
+    pub skip_unchanged_frames: bool,
+    /// 縮小をキャプチャスレッドで行わず、元のサイズのフレームに出力サイズ（`Frame::scale_to`）を付けて送る
+    /// （ハードウェアエンコーダーの前処理が NV12 変換と同時に GPU で縮小する）
+    pub gpu_scaling: bool,
 }
 
 impl Default for CaptureConfig {
     fn default() -> Self {
         Self {
             size: CaptureSize::UseSourceSize,
+            aspect: AspectMode::default(),
             fps: 45,
+            max_encode_pixels: None,
+            show_cursor: true,
+            pixel_format: PixelFormat::default(),
+            occlusion: OcclusionPolicy::default(),
+            protected_content: ProtectedContentPolicy::default(),
+            skip_unchanged_frames: false,
+            gpu_scaling: false,
         }
     }
 }
 
+/// アスペクト比を保ったまま `max_pixels` 以下に収まるサイズを計算する
+///
+/// 収まっている場合はそのまま返す。縮小時の幅・高さはエンコーダーに合わせて2の倍数に切り下げる。
+pub fn fit_to_max_pixels(width: u32, height: u32, max_pixels: u32) -> (u32, u32) {
+    let pixels = width as u64 * height as u64;
+    if pixels <= max_pixels as u64 || width == 0 || height == 0 {
+        return (width, height);
+    }
+
+    let scale = (max_pixels as f64 / pixels as f64).sqrt();
+    let fit_width = ((width as f64 * scale) as u32 / 2 * 2).max(2);
+    let fit_height = ((height as f64 * scale) as u32 / 2 * 2).max(2);
+    (fit_width, fit_height)
+}
+
+/// 幅・高さを 2 の倍数に切り下げる（エンコーダーは偶数のサイズしか扱えない。最小 2）
+pub fn even_size(width: u32, height: u32) -> (u32, u32) {
+    ((width & !1).max(2), (height & !1).max(2))
+}
+
+/// 幅か高さが奇数の 4 バイト/画素の画像から、右端の列・下端の行を落として偶数のサイズにする
+///
+/// エンコーダーに切り下げさせるとキャプチャしたフレームとエンコードしたフレームの幅がずれるので、
+/// キャプチャ側で `even_size` のサイズにそろえる。すでに偶数（か 2 未満）なら None。
+pub fn crop_to_even(data: &[u8], width: u32, height: u32) -> Option<Vec<u8>> {
+    if (width | height) & 1 == 0 || width < 2 || height < 2 {
+        return None;
+    }
+    let (even_width, even_height) = even_size(width, height);
+    let row_bytes = even_width as usize * 4;
+    let mut cropped = Vec::with_capacity(row_bytes * even_height as usize);
+    for row in data
+        .chunks_exact(width as usize * 4)
+        .take(even_height as usize)
+    {
+        cropped.extend_from_slice(&row[..row_bytes]);
+    }
+    Some(cropped)
+}
+
+/// 4 バイト/画素の画像から `rect` の部分を切り出し、画素データと幅・高さを返す
+/// （画像からはみ出す部分は落とす。重なる部分がなければ None）
+pub fn crop_rect(data: &[u8], width: u32, height: u32, rect: Rect) -> Option<(Vec<u8>, u32, u32)> {
+    let right = rect.x.saturating_add(rect.width).min(width);
+    let bottom = rect.y.saturating_add(rect.height).min(height);
+    if rect.x >= right || rect.y >= bottom {
+        return None;
+    }
+    let (crop_width, crop_height) = (right - rect.x, bottom - rect.y);
+    let start = rect.x as usize * 4;
+    let row_bytes = crop_width as usize * 4;
+    let mut cropped = Vec::with_capacity(row_bytes * crop_height as usize);
+    for row in data
+        .chunks_exact(width as usize * 4)
+        .skip(rect.y as usize)
+        .take(crop_height as usize)
+    {
+        cropped.extend_from_slice(&row[start..start + row_bytes]);
+    }
+    Some((cropped, crop_width, crop_height))
+}
+
+/// 画像上の矩形
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+/// リサイズ時に元画像のどの範囲を出力のどの範囲に描くかを計算する
+///
+/// 戻り値は (元画像側の矩形, 出力側の矩形)。出力側の矩形の外は余白になる。
+pub fn aspect_layout(
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+    aspect: AspectMode,
+) -> (Rect, Rect) {
+    let full_src = Rect::new(0, 0, src_width, src_height);
+    let full_dst = Rect::new(0, 0, dst_width, dst_height);
+    if src_width == 0 || src_height == 0 || dst_width == 0 || dst_height == 0 {
+        return (full_src, full_dst);
+    }
+
+    // 元画像の方が横長か（src_w / src_h > dst_w / dst_h）
+    let (sw, sh, dw, dh) = (
+        src_width as u64,
+        src_height as u64,
+        dst_width as u64,
+        dst_height as u64,
+    );
+    let src_wider = sw * dh > sh * dw;
+
+    match aspect {
+        AspectMode::Stretch => (full_src, full_dst),
+        AspectMode::Letterbox => {
+            let dst = if src_wider {
+                let height = ((sh * dw / sw) as u32).clamp(1, dst_height);
+                Rect::new(0, (dst_height - height) / 2, dst_width, height)
+            } else {
+                let width = ((sw * dh / sh) as u32).clamp(1, dst_width);
+                Rect::new((dst_width - width) / 2, 0, width, dst_height)
+            };
+            (full_src, dst)
+        }
+        AspectMode::Crop => {
+            let src = if src_wider {
+                let width = ((dw * sh / dh) as u32).clamp(1, src_width);
+                Rect::new((src_width - width) / 2, 0, width, src_height)
+            } else {
+                let height = ((dh * sw / dw) as u32).clamp(1, src_height);
+                Rect::new(0, (src_height - height) / 2, src_width, height)
+            };
+            (src, full_dst)
+        }
+    }
+}
+
+/// アスペクト比の合わせ方を指定して 4 バイト/画素の画像をリサイズする（最近傍、余白は不透明な黒）
+pub fn resize_with_aspect(
+    src_data: &[u8],
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+    aspect: AspectMode,
+) -> Vec<u8> {
+    let dst_stride = dst_width * 4;
+    let mut dst_data = vec![0u8; (dst_stride * dst_height) as usize];
+
+    let (src_rect, dst_rect) = aspect_layout(src_width, src_height, dst_width, dst_height, aspect);
+    if dst_rect.width != dst_width || dst_rect.height != dst_height {
+        for pixel in dst_data.chunks_exact_mut(4) {
+            pixel[3] = 255;
+        }
+    }
+
+    for y in 0..dst_rect.height {
+        let src_y = src_rect.y + (y * src_rect.height) / dst_rect.height;
+        for x in 0..dst_rect.width {
+            let src_x = src_rect.x + (x * src_rect.width) / dst_rect.width;
+
+            let src_offset = (src_y * src_width + src_x) * 4;
+            let dst_offset = ((dst_rect.y + y) * dst_width + dst_rect.x + x) * 4;
+
+            if (src_offset + 4) as usize <= src_data.len()
+                && (dst_offset + 4) as usize <= dst_data.len()
+            {
+                dst_data[dst_offset as usize..(dst_offset + 4) as usize]
+                    .copy_from_slice(&src_data[src_offset as usize..(src_offset + 4) as usize]);
+            }
+        }
+    }
+
+    dst_data
+}
+
+/// エンコーダー側で縮小する出力サイズ（`CaptureConfig::gpu_scaling`）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScaleTarget {
+    pub width: u32,
+    pub height: u32,
+    pub aspect: AspectMode,
+}
+
+/// キャプチャ対象
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureTarget {
+    /// ウィンドウ（HWND）
+    Window(u64),
+    /// 親ウィンドウ（HWND）の子ウィンドウのうち、クラス名かタイトルが一致するもの
+    ChildWindow { parent: u64, class_or_title: String },
+    /// モニター（0始まりのインデックス）
+    Monitor(usize),
+    /// プライマリモニター
+    PrimaryMonitor,
+}
+
 /// Capture サービスへのメッセージ
 #[derive(Debug)]
 pub enum CaptureMessage {
-    Start { hwnd: u64 },
+    Start {
+        target: CaptureTarget,
+    },
     Stop,
-    UpdateConfig { size: CaptureSize, fps: u32 },
-    RequestFrame { tx: tokio::sync::oneshot::Sender<Frame> },
+    /// キャプチャを止めてサービスを終了する（hostd の停止時）
+    Shutdown,
+    UpdateConfig {
+        size: CaptureSize,
+        fps: u32,
+    },
+    SetCursorVisible {
+        visible: bool,
+    },
+    RequestFrame {
+        tx: tokio::sync::oneshot::Sender<Frame>,
+    },
 }
 
 /// Capture サービスの実行結果 Future 型
@@ -53,6 +458,59 @@ pub struct Frame {
     pub height: u32,
     pub data: Arc<Vec<u8>>,
     pub windows_timespan: u64,
+    /// キャプチャ設定のフレームレート（エンコーダーのメディアタイプに使う）
+    pub fps: u32,
+    /// `data` の画素の並び
+    pub format: PixelFormat,
+    /// 前のフレームから変化した領域の割合（0.0-1.0、ダーティ領域を取得していない場合は None）
+    ///
+    /// `Some(0.0)` のフレームは前のフレームと同じ内容なので、エンコードせずに捨ててよい
+    pub dirty_fraction: Option<f32>,
+    /// キャプチャサービスが付ける通し番号（1 から 1 ずつ増える。0 は番号なし）
+    ///
+    /// 途中で飛んだ番号は、そこまでのどこかの段で捨てられたフレーム
+    pub sequence: u64,
+    /// エンコーダー側で縮小する出力サイズ（None なら `width` x `height` のままエンコードする）
+    pub scale_to: Option<ScaleTarget>,
+}
+
+impl Frame {
+    /// エンコーダーが出力する映像のサイズ
+    pub fn output_size(&self) -> (u32, u32) {
+        match self.scale_to {
+            Some(target) => (target.width, target.height),
+            None => (self.width, self.height),
+        }
+    }
+}
+
+/// フレームのうちダーティ領域（`regions`、フレーム座標）の割合（0.0-1.0）
+///
+/// 重なりは二重に数える（上限は 1.0）。小さな領域でも 0 にはならないよう、格子での見積もりはしない
+pub fn dirty_fraction(width: u32, height: u32, regions: &[Rect]) -> f32 {
+    let total = width as u64 * height as u64;
+    if total == 0 {
+        return 0.0;
+    }
+    let dirty: u64 = regions
+        .iter()
+        .map(|r| {
+            let w = (r.x.saturating_add(r.width)).min(width).saturating_sub(r.x);
+            let h = (r.y.saturating_add(r.height))
+                .min(height)
+                .saturating_sub(r.y);
+            w as u64 * h as u64
+        })
+        .sum();
+    if dirty == 0 {
+        return 0.0;
+    }
+    ((dirty as f64 / total as f64) as f32).clamp(f32::MIN_POSITIVE, 1.0)
+}
+
+/// 送らなかったフレームの変化を次のフレームに持ち越すときの合成（どちらかが不明なら不明）
+pub fn merge_dirty_fraction(a: Option<f32>, b: Option<f32>) -> Option<f32> {
+    Some((a? + b?).min(1.0))
 }
 
 /// ビデオコーデックの種類
@@ -72,6 +530,107 @@ impl std::str::FromStr for VideoCodec {
     }
 }
 
+impl std::fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VideoCodec::H264 => write!(f, "h264"),
+        }
+    }
+}
+
+/// H.264 のプロファイル
+///
+/// ハードウェアエンコーダーに設定するプロファイルと、Answer SDP の
+/// profile-level-id の両方をここから決める（デフォルトが実際の送出プロファイル）。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum H264Profile {
+    ConstrainedBaseline,
+    Main,
+    #[default]
+    High,
+}
+
+impl H264Profile {
+    /// SPS の profile_idc
+    pub fn profile_idc(self) -> u8 {
+        match self {
+            H264Profile::ConstrainedBaseline => 66,
+            H264Profile::Main => 77,
+            H264Profile::High => 100,
+        }
+    }
+
+    /// SPS の constraint_set フラグ（profile-iop）
+    pub fn profile_iop(self) -> u8 {
+        match self {
+            // constraint_set0_flag + constraint_set1_flag
+            H264Profile::ConstrainedBaseline => 0xe0,
+            H264Profile::Main | H264Profile::High => 0x00,
+        }
+    }
+}
+
+/// キーフレームを要求した理由（ビットレートが跳ねる原因の調査用に理由ごとに集計する）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeReason {
+    /// 受信側からの PLI/FIR
+    Pli,
+    /// 最初のフレーム
+    FirstFrame,
+    /// 接続の確立・再確立（トラックの差し替えを含む）
+    Reconnect,
+    /// 解像度・フレームレートの変更
+    ResolutionChange,
+    /// エンコーダーの再生成（ウォッチドッグ・フリーズ検出・デバイス喪失・手動の再起動）
+    EncoderRestart,
+    /// 一時停止からの再開
+    Resume,
+    /// キャプチャ対象のウィンドウ切り替え
+    CaptureTargetSwitch,
+    /// インスタントリプレイの GOP 区切り
+    Replay,
+    /// 遅れたフレームを捨てた後の復帰
+    LateFrames,
+    /// 録画の開始（ファイルはキーフレームから始める）
+    Recording,
+    /// 一時停止（一時停止中に再送するフレームをキーフレームにする）
+    Pause,
+}
+
+impl KeyframeReason {
+    pub const ALL: [KeyframeReason; 11] = [
+        KeyframeReason::Pli,
+        KeyframeReason::FirstFrame,
+        KeyframeReason::Reconnect,
+        KeyframeReason::ResolutionChange,
+        KeyframeReason::EncoderRestart,
+        KeyframeReason::Resume,
+        KeyframeReason::CaptureTargetSwitch,
+        KeyframeReason::Replay,
+        KeyframeReason::LateFrames,
+        KeyframeReason::Recording,
+        KeyframeReason::Pause,
+    ];
+}
+
+impl std::fmt::Display for KeyframeReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyframeReason::Pli => write!(f, "pli"),
+            KeyframeReason::FirstFrame => write!(f, "first_frame"),
+            KeyframeReason::Reconnect => write!(f, "reconnect"),
+            KeyframeReason::ResolutionChange => write!(f, "resolution_change"),
+            KeyframeReason::EncoderRestart => write!(f, "encoder_restart"),
+            KeyframeReason::Resume => write!(f, "resume"),
+            KeyframeReason::CaptureTargetSwitch => write!(f, "capture_target_switch"),
+            KeyframeReason::Replay => write!(f, "replay"),
+            KeyframeReason::LateFrames => write!(f, "late_frames"),
+            KeyframeReason::Recording => write!(f, "recording"),
+            KeyframeReason::Pause => write!(f, "pause"),
+        }
+    }
+}
+
 /// エンコード要求
 #[derive(Debug)]
 pub struct EncodeJob {
@@ -80,19 +639,74 @@ pub struct EncodeJob {
     pub rgba: Arc<Vec<u8>>,
     pub timestamp: u64,
     pub enqueue_at: Instant,
-    pub request_keyframe: bool,
+    /// キーフレームを要求する場合はその理由
+    pub request_keyframe: Option<KeyframeReason>,
+    /// 想定フレームレート（エンコーダーのレート制御に使う）
+    pub fps: u32,
+    /// `rgba` の画素の並び（BGRA の場合もフィールド名は `rgba` のまま）
+    pub format: PixelFormat,
+    /// 元フレームの通し番号（`Frame::sequence`）
+    pub sequence: u64,
+    /// エンコーダー側で縮小する出力サイズ（`Frame::scale_to`）
+    pub scale_to: Option<ScaleTarget>,
+    /// ビットレートの上限（bps）。接続先のブラウザが Offer の b=AS / b=TIAS で示した帯域
+    /// （None なら制限しない）
+    pub max_bitrate_bps: Option<u32>,
+}
+
+impl EncodeJob {
+    /// エンコーダーが出力する映像のサイズ
+    pub fn output_size(&self) -> (u32, u32) {
+        match self.scale_to {
+            Some(target) => (target.width, target.height),
+            None => (self.width, self.height),
+        }
+    }
+
+    /// GPU で縮小できないエンコーダー向けに、`scale_to` の縮小をここで CPU で行う
+    pub fn scale_on_cpu(&mut self) {
+        if let Some(target) = self.scale_to.take() {
+            if (target.width, target.height) != (self.width, self.height) {
+                self.rgba = Arc::new(resize_with_aspect(
+                    &self.rgba,
+                    self.width,
+                    self.height,
+                    target.width,
+                    target.height,
+                    target.aspect,
+                ));
+                self.width = target.width;
+                self.height = target.height;
+            }
+        }
+    }
 }
 
 /// エンコード結果
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct EncodeResult {
     pub sample_data: Vec<u8>,
     pub is_keyframe: bool,
+    /// 要求に応じて出したキーフレームの場合はその理由（None のキーフレームはエンコーダーの周期的なもの）
+    pub keyframe_reason: Option<KeyframeReason>,
     pub duration: Duration,
     pub width: u32,
     pub height: u32,
+    /// 元フレームのキャプチャ時刻（EncodeJob.timestamp をそのまま返す、100ナノ秒単位）
+    pub capture_timestamp: u64,
+    /// フレームの平均 QP（エンコーダーが報告しない場合は None）
+    pub average_qp: Option<u8>,
+    /// 元フレームの通し番号（EncodeJob.sequence をそのまま返す）
+    pub sequence: u64,
 }
 
+/// H.264 の QP の上限（ここに張り付いている場合はビットレートが内容に対して足りない）
+pub const H264_MAX_QP: u8 = 51;
+
+/// キャプチャ時刻を載せる RTP ヘッダー拡張（abs-capture-time）の URI
+pub const ABS_CAPTURE_TIME_URI: &str =
+    "http://www.webrtc.org/experiments/rtp-hdrext/abs-capture-time";
+
 /// エンコードジョブスロットのシャットダウンエラー
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ShutdownError;
@@ -105,6 +719,181 @@ impl std::fmt::Display for ShutdownError {
 
 impl std::error::Error for ShutdownError {}
 
+/// サービス境界で hostd が判別するためのエラー種別
+/// サービス内部では anyhow を使い、`context` などでこの型を付与して返す
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceError {
+    /// キャプチャ対象のウィンドウが存在しない（閉じられた）
+    WindowGone,
+    /// 指定コーデックのエンコーダーが利用できない
+    EncoderUnavailable(VideoCodec),
+    /// シグナリングサーバーに接続を拒否された
+    SignalingRejected,
+    /// キャプチャデバイスなどの初期化・実行に失敗した
+    DeviceError(String),
+    /// キャプチャのフレーム間隔が想定より大幅に長い（フレームが届かない）
+    CaptureStalled { interval_ms: u64 },
+    /// キャプチャが真っ黒なまま続いている（保護されたコンテンツの可能性が高い）
+    ProtectedContent,
+}
+
+impl ServiceError {
+    /// anyhow::Error に付与された ServiceError を取り出す
+    pub fn from_anyhow(err: &anyhow::Error) -> Option<&ServiceError> {
+        err.downcast_ref::<ServiceError>()
+    }
+}
+
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceError::WindowGone => write!(f, "capture target window is gone"),
+            ServiceError::EncoderUnavailable(codec) => {
+                write!(f, "encoder for {:?} is unavailable", codec)
+            }
+            ServiceError::SignalingRejected => {
+                write!(f, "signaling server rejected the connection")
+            }
+            ServiceError::DeviceError(message) => write!(f, "device error: {}", message),
+            ServiceError::CaptureStalled { interval_ms } => {
+                write!(f, "capture stalled (frame interval {} ms)", interval_ms)
+            }
+            ServiceError::ProtectedContent => {
+                write!(f, "content is protected and cannot be captured")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// 毎フレーム出うる警告ログの間引き
+///
+/// 初回は即座に出し、以降は `every` 回ごと、または前回から `interval` 経過したときだけ出す。
+/// 障害が続いたときにログが膨れ上がり、ログ出力自体が遅延の原因になるのを防ぐ。
+#[derive(Debug)]
+pub struct LogThrottle {
+    every: u64,
+    interval: Duration,
+    suppressed: u64,
+    last_logged: Option<Instant>,
+}
+
+impl LogThrottle {
+    pub fn new(every: u64, interval: Duration) -> Self {
+        Self {
+            every: every.max(1),
+            interval,
+            suppressed: 0,
+            last_logged: None,
+        }
+    }
+
+    /// 発生を記録し、ログを出すべきなら前回から抑制した件数を返す
+    pub fn check(&mut self) -> Option<u64> {
+        self.check_at(Instant::now())
+    }
+
+    pub fn check_at(&mut self, now: Instant) -> Option<u64> {
+        let due = match self.last_logged {
+            None => true,
+            Some(last) => {
+                self.suppressed + 1 >= self.every
+                    || now.saturating_duration_since(last) >= self.interval
+            }
+        };
+        if !due {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last_logged = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+impl Default for LogThrottle {
+    /// 100 回ごと、または 5 秒ごと
+    fn default() -> Self {
+        Self::new(100, Duration::from_secs(5))
+    }
+}
+
+/// フレーム間隔の指数移動平均（EMA）からキャプチャの停止を検知する
+///
+/// フレームが届かない間は EMA が更新されないため、判定時は最後のフレームからの
+/// 経過時間を次の間隔とみなして平均に織り込む。
+#[derive(Debug, Clone)]
+pub struct FrameIntervalMonitor {
+    expected_interval: Duration,
+    /// 平均間隔（秒）
+    average: Option<f64>,
+    last_frame: Option<Instant>,
+}
+
+impl FrameIntervalMonitor {
+    /// EMA の平滑化係数
+    const ALPHA: f64 = 0.1;
+    /// 想定間隔の何倍を超えたら停止とみなすか
+    const STALL_FACTOR: f64 = 3.0;
+
+    pub fn new(fps: u32) -> Self {
+        Self {
+            expected_interval: Duration::from_secs(1) / fps.max(1),
+            average: None,
+            last_frame: None,
+        }
+    }
+
+    /// 想定フレームレートを変える（計測はやり直す）
+    pub fn reset(&mut self, fps: u32) {
+        *self = Self::new(fps);
+    }
+
+    /// フレームの到着を記録する
+    pub fn record_at(&mut self, now: Instant) {
+        if let Some(last) = self.last_frame {
+            let interval = now.saturating_duration_since(last).as_secs_f64();
+            self.average = Some(match self.average {
+                Some(average) => average + Self::ALPHA * (interval - average),
+                None => interval,
+            });
+        }
+        self.last_frame = Some(now);
+    }
+
+    /// 平均フレーム間隔
+    pub fn interval_at(&self, now: Instant) -> Option<Duration> {
+        self.average_at(now).map(Duration::from_secs_f64)
+    }
+
+    /// `now` 時点の平均間隔（最後のフレームからの経過時間が平均より長ければ織り込む）
+    fn average_at(&self, now: Instant) -> Option<f64> {
+        let average = self.average?;
+        let pending = now
+            .saturating_duration_since(self.last_frame?)
+            .as_secs_f64();
+        Some(if pending > average {
+            average + Self::ALPHA * (pending - average)
+        } else {
+            average
+        })
+    }
+
+    /// 計測したフレームレート（間隔が 2 つ以上記録されるまでは None）
+    pub fn fps_at(&self, now: Instant) -> Option<f32> {
+        self.average_at(now)
+            .filter(|average| *average > 0.0)
+            .map(|average| (1.0 / average) as f32)
+    }
+
+    /// 平均間隔が想定の `STALL_FACTOR` 倍を超えているか
+    pub fn is_stalled_at(&self, now: Instant) -> bool {
+        self.average_at(now).is_some_and(|average| {
+            average > self.expected_interval.as_secs_f64() * Self::STALL_FACTOR
+        })
+    }
+}
+
 /// エンコードジョブスロット（Dumb Workerパターン用）
 /// 最新のフレームのみを保持し、古いフレームは自動的にドロップされる
 #[derive(Debug)]
@@ -112,6 +901,10 @@ pub struct EncodeJobSlot {
     job: Mutex<Option<EncodeJob>>,
     condvar: Condvar,
     shutdown: Mutex<bool>,
+    /// ジョブがセットされてからエンコーダーに取り出されるまでの時間の移動平均（マイクロ秒）
+    queue_wait_us: AtomicU64,
+    /// このスロットのジョブを処理するワーカースレッド（`join_worker` で終了を待つ）
+    worker: Mutex<Option<std::thread::JoinHandle<()>>>,
 }
 
 impl EncodeJobSlot {
@@ -121,9 +914,31 @@ impl EncodeJobSlot {
             job: Mutex::new(None),
             condvar: Condvar::new(),
             shutdown: Mutex::new(false),
+            queue_wait_us: AtomicU64::new(0),
+            worker: Mutex::new(None),
         })
     }
 
+    /// 移動平均の重み（新しい値を 1/8 だけ反映する）
+    const QUEUE_WAIT_SMOOTHING: u64 = 8;
+
+    /// ジョブがエンコーダーに取り出されるまでに待った時間の移動平均
+    /// （フレームルーターでの処理時間を含む。エンコーダーが追いつかないと 1 フレームの間隔に近づく）
+    pub fn average_queue_wait(&self) -> Duration {
+        Duration::from_micros(self.queue_wait_us.load(Ordering::Relaxed))
+    }
+
+    fn record_queue_wait(&self, job: &EncodeJob) {
+        let wait = job.enqueue_at.elapsed().as_micros() as u64;
+        let average = self.queue_wait_us.load(Ordering::Relaxed);
+        let updated = if average == 0 {
+            wait
+        } else {
+            (average * (Self::QUEUE_WAIT_SMOOTHING - 1) + wait) / Self::QUEUE_WAIT_SMOOTHING
+        };
+        self.queue_wait_us.store(updated, Ordering::Relaxed);
+    }
+
     /// シャットダウンを通知する
     /// すべての待機中のスレッドを起こし、`take()`が`ShutdownError`を返すようにする
     /// このメソッドは即座に返り、ワーカースレッドの終了を待たない
@@ -134,12 +949,29 @@ impl EncodeJobSlot {
         self.condvar.notify_all();
     }
 
+    /// このスロットのジョブを処理するワーカースレッドを登録する
+    pub fn attach_worker(&self, handle: std::thread::JoinHandle<()>) {
+        *self.worker.lock().unwrap() = Some(handle);
+    }
+
+    /// 登録されたワーカースレッドの終了を待つ（`shutdown()` の後に呼ぶ。未登録なら何もしない）
+    /// ワーカースレッドが panic していれば Err を返す
+    pub fn join_worker(&self) -> std::thread::Result<()> {
+        let handle = self.worker.lock().unwrap().take();
+        match handle {
+            Some(handle) => handle.join(),
+            None => Ok(()),
+        }
+    }
+
     /// 最新のジョブをセット（古いものを置き換え）
     /// 常に成功する（スロットが満杯になることがない）
-    pub fn set(&self, job: EncodeJob) {
+    /// エンコーダーが取り出す前のジョブを置き換えた（= フレームを捨てた）場合は true を返す
+    pub fn set(&self, job: EncodeJob) -> bool {
         let mut guard = self.job.lock().unwrap();
-        *guard = Some(job);
+        let replaced = guard.replace(job).is_some();
         self.condvar.notify_one();
+        replaced
     }
 
     /// ブロッキングでジョブを取得
@@ -154,6 +986,7 @@ impl EncodeJobSlot {
             }
 
             if let Some(job) = guard.take() {
+                self.record_queue_wait(&job);
                 return Ok(job);
             }
 
@@ -177,7 +1010,9 @@ impl EncodeJobSlot {
             return Some(Err(ShutdownError));
         }
 
-        guard.take().map(Ok)
+        let job = guard.take()?;
+        self.record_queue_wait(&job);
+        Some(Ok(job))
     }
 }
 
@@ -195,6 +1030,9 @@ pub enum WebRtcMessage {
     SetOffer {
         sdp: String,
         codec: Option<VideoCodec>,
+        /// ビューアーが接続ごとに付ける ID（同じ ID の Offer は再接続として引き継ぐ）
+        /// シグナリングの session_id はホストごとに 1 つで全ビューアーが共有するので使わない
+        negotiation_id: Option<String>,
     },
     AddIceCandidate {
         candidate: String,
@@ -208,6 +1046,31 @@ pub enum WebRtcMessage {
     SetAnswerForRestart {
         sdp: String,
     },
+    /// 接続を維持したまま映像・音声の送出を一時停止（true のものだけ対象）
+    PauseStream {
+        video: bool,
+        audio: bool,
+    },
+    /// 一時停止した映像・音声の送出を再開（true のものだけ対象）
+    ResumeStream {
+        video: bool,
+        audio: bool,
+    },
+    /// リプレイバッファの内容をファイルに保存
+    SaveReplay,
+    /// セッションの録画を開始・停止
+    StartRecording,
+    StopRecording,
+    /// 再ネゴシエーションせずに映像エンコーダーを作り直し、キーフレームから送り直す
+    RestartEncoder,
+    /// ミックスする音声ソースのゲイン・ミュートを変更
+    SetAudioSourceGain {
+        source: AudioSource,
+        gain: f32,
+        muted: bool,
+    },
+    /// PeerConnection を閉じてサービスを終了（hostd の停止時）
+    Shutdown,
 }
 
 /// シグナリングサービスへの応答メッセージ
@@ -215,6 +1078,8 @@ pub enum WebRtcMessage {
 pub enum SignalingResponse {
     Answer {
         sdp: String,
+        /// ネゴシエーションの結果送出するビデオコーデック
+        codec: VideoCodec,
     },
     Error {
         message: String,
@@ -230,6 +1095,35 @@ pub enum SignalingResponse {
     OfferForRestart {
         sdp: String,
     },
+    /// PeerConnection の接続状態の変化（クライアントの状態表示用）
+    ConnectionState {
+        state: PeerConnectionState,
+    },
+}
+
+/// クライアントに通知する PeerConnection の接続状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerConnectionState {
+    New,
+    Connecting,
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+impl std::fmt::Display for PeerConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerConnectionState::New => write!(f, "new"),
+            PeerConnectionState::Connecting => write!(f, "connecting"),
+            PeerConnectionState::Connected => write!(f, "connected"),
+            PeerConnectionState::Disconnected => write!(f, "disconnected"),
+            PeerConnectionState::Failed => write!(f, "failed"),
+            PeerConnectionState::Closed => write!(f, "closed"),
+        }
+    }
 }
 
 /// DataChannel経由でやり取りするメッセージ
@@ -238,12 +1132,81 @@ pub enum DataChannelMessage {
     Key { key: String, down: bool },
     MouseWheel { delta: i32 },
     ScreenshotRequest,
-    Ping { timestamp: u64 },
-    Pong { timestamp: u64 },
+    /// 生存確認と往復時間の計測。受け取った側はすぐに同じチャネルで `id` と `client_ts` をそのまま Pong で返す
+    /// （`client_ts` は送った側の時刻。以前のクライアントが送る `timestamp` も受け付ける）
+    Ping {
+        #[serde(default)]
+        id: u64,
+        #[serde(alias = "timestamp")]
+        client_ts: u64,
+    },
+    Pong {
+        #[serde(default)]
+        id: u64,
+        #[serde(alias = "timestamp")]
+        client_ts: u64,
+    },
     // Input
     MouseClick { x: f64, y: f64, button: String },
+    /// カーソルの移動（x, y はキャプチャ対象に対する 0.0-1.0 の位置）
+    MouseMove {
+        x: f64,
+        y: f64,
+    },
+    /// キーボード配列や IME に依存しない文字入力（Unicode として注入）
+    TextInput {
+        text: String,
+    },
+    /// スキャンコード (Set 1) をそのまま注入する。通常は `Key` を使い、`Key` のキー名では
+    /// 反応しない・対応表にないキーを使うゲーム向けの逃げ道として使う（`extended` は E0 プレフィックス）
+    RawScanCode {
+        scancode: u16,
+        down: bool,
+        extended: bool,
+    },
+    /// クリップボードのテキスト（クライアント→ホストはホストのクリップボードに設定し、
+    /// ホスト→クライアントはホストでコピーされたときに送る）
+    ClipboardText {
+        text: String,
+    },
+    /// 押下中のキーをすべて離す（切断時やクライアントのフォーカス喪失時）
+    ReleaseAllKeys,
+    /// クライアントが現在押しているキー（定期送信、ホスト側の押しっぱなしを解消する）
+    HeldKeys {
+        keys: Vec<String>,
+    },
     // LLM Analysis
-    AnalyzeRequest { id: String, max_edge: u32 },
+    /// `model` は hostd に `--llm-model` で登録した追加のモデルの名前（省略時はメインのモデル）
+    AnalyzeRequest {
+        id: String,
+        max_edge: u32,
+        #[serde(default)]
+        model: Option<String>,
+    },
+    // Stream control
+    PauseStream {
+        video: bool,
+        audio: bool,
+    },
+    ResumeStream {
+        video: bool,
+        audio: bool,
+    },
+    /// ミックスする音声ソースのゲイン（1.0 で等倍）とミュート
+    SetAudioSourceGain {
+        source: AudioSource,
+        gain: f32,
+        muted: bool,
+    },
+    // Capture control
+    SetCursorVisible {
+        visible: bool,
+    },
+    // Replay
+    SaveReplay,
+    // Recording
+    StartRecording,
+    StopRecording,
     // Outgoing messages (Host -> Client)
     #[serde(rename = "SCREENSHOT_METADATA")]
     ScreenshotMetadata {
@@ -263,6 +1226,10 @@ pub enum DataChannelMessage {
     AnalyzeResponseDone {
         id: String,
     },
+    #[serde(rename = "VIDEO_STATS")]
+    VideoStats {
+        payload: VideoStatsPayload,
+    },
     // LLM Config
     GetLlmConfig,
     UpdateLlmConfig {
@@ -271,6 +1238,37 @@ pub enum DataChannelMessage {
     LlmConfigResponse {
         config: LlmConfig,
     },
+    // Pipeline config
+    /// キャプチャ・エンコード・送出の実効設定を問い合わせる
+    GetPipelineConfig,
+    #[serde(rename = "PIPELINE_CONFIG")]
+    PipelineConfigResponse {
+        config: Box<PipelineConfig>,
+    },
+    // Capture target
+    /// 現在のキャプチャ対象を問い合わせる
+    GetCaptureTarget,
+    /// キャプチャ対象のウィンドウを切り替える（HWND かタイトルのどちらかを指定）
+    SetCaptureTarget {
+        #[serde(default)]
+        hwnd: Option<u64>,
+        #[serde(default)]
+        title: Option<String>,
+    },
+    #[serde(rename = "CAPTURE_TARGET")]
+    CaptureTargetResponse {
+        payload: CaptureTargetPayload,
+    },
+    // Encoder
+    /// 映像が乱れたときにエンコーダーを作り直す（コーデック・解像度はそのまま）
+    RestartEncoder,
+    // Host
+    /// ホスト側のサービスが利用できなくなったことの通知（音声キャプチャの失敗など）
+    #[serde(rename = "SERVICE_ERROR")]
+    ServiceErrorNotification {
+        service: String,
+        message: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -288,6 +1286,19 @@ pub enum TaggerCommand {
     GetConfig {
         reply_tx: tokio::sync::oneshot::Sender<LlmConfig>,
     },
+    /// llama-server を現在の設定で（起動中なら再）起動する。失敗した場合は理由を返す
+    Start {
+        reply_tx: tokio::sync::oneshot::Sender<std::result::Result<(), String>>,
+    },
+    /// llama-server を停止する
+    Stop {
+        reply_tx: tokio::sync::oneshot::Sender<std::result::Result<(), String>>,
+    },
+    /// 名前で登録した追加のモデルを選び、読み込みを終えたらリクエストを送るポートを返す
+    SelectModel {
+        name: String,
+        reply_tx: tokio::sync::oneshot::Sender<std::result::Result<u16, String>>,
+    },
 }
 
 
@@ -301,6 +1312,139 @@ pub struct ScreenshotMetadataPayload {
     pub size: u32,
 }
 
+/// 映像のドロップ統計（一定区間ごと）
+/// エンコーダー側（CPU/GPU が追いつかない）とネットワーク側（輻輳）を区別する
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VideoStatsPayload {
+    /// 区間内にキャプチャから受け取ったフレーム数
+    pub frames: u64,
+    /// エンコーダーが追いつかずに捨てたフレーム数
+    pub encoder_dropped: u64,
+    /// エンコーダー側のドロップ率 (0.0-1.0)
+    pub encoder_drop_rate: f32,
+    /// 受信側が報告したパケットロス率 (0.0-1.0, RTCP Receiver Report)
+    pub network_loss_rate: f32,
+    /// 区間内にキャプチャから届いたフレームレート
+    pub capture_fps: f32,
+    /// 直近 1 分間に送出したキーフレームの理由ごとの数
+    #[serde(default)]
+    pub keyframes_last_minute: KeyframeCounts,
+    /// 区間内にエンコードしたフレームの平均 QP（エンコーダーが報告しない場合は None）
+    #[serde(default)]
+    pub average_qp: Option<f32>,
+    /// 区間内の最大 QP
+    #[serde(default)]
+    pub max_qp: Option<u8>,
+    /// 区間内にキャプチャ側で捨てられたフレーム数（フレームの通し番号の飛びから求める）
+    #[serde(default)]
+    pub capture_dropped: u64,
+    /// 区間内のキャプチャからエンコード完了までの遅延の分布（フレームがなければ None）
+    #[serde(default)]
+    pub capture_to_encode: Option<LatencyPercentiles>,
+    /// 区間内のエンコード完了から送出までの遅延の分布（ペーシングの待ちを含む）
+    #[serde(default)]
+    pub encode_to_send: Option<LatencyPercentiles>,
+}
+
+/// 理由ごとのキーフレーム数
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyframeCounts {
+    pub pli: u64,
+    pub first_frame: u64,
+    pub reconnect: u64,
+    pub resolution_change: u64,
+    pub encoder_restart: u64,
+    pub resume: u64,
+    pub capture_target_switch: u64,
+    pub replay: u64,
+    pub late_frames: u64,
+    pub recording: u64,
+    #[serde(default)]
+    pub pause: u64,
+    /// 要求なしにエンコーダーが挿入したもの（GOP の周期）
+    pub periodic: u64,
+}
+
+impl KeyframeCounts {
+    /// 理由（None は周期的なキーフレーム）の数
+    pub fn get(&self, reason: Option<KeyframeReason>) -> u64 {
+        match reason {
+            Some(KeyframeReason::Pli) => self.pli,
+            Some(KeyframeReason::FirstFrame) => self.first_frame,
+            Some(KeyframeReason::Reconnect) => self.reconnect,
+            Some(KeyframeReason::ResolutionChange) => self.resolution_change,
+            Some(KeyframeReason::EncoderRestart) => self.encoder_restart,
+            Some(KeyframeReason::Resume) => self.resume,
+            Some(KeyframeReason::CaptureTargetSwitch) => self.capture_target_switch,
+            Some(KeyframeReason::Replay) => self.replay,
+            Some(KeyframeReason::LateFrames) => self.late_frames,
+            Some(KeyframeReason::Recording) => self.recording,
+            Some(KeyframeReason::Pause) => self.pause,
+            None => self.periodic,
+        }
+    }
+
+    pub fn record(&mut self, reason: Option<KeyframeReason>) {
+        let count = match reason {
+            Some(KeyframeReason::Pli) => &mut self.pli,
+            Some(KeyframeReason::FirstFrame) => &mut self.first_frame,
+            Some(KeyframeReason::Reconnect) => &mut self.reconnect,
+            Some(KeyframeReason::ResolutionChange) => &mut self.resolution_change,
+            Some(KeyframeReason::EncoderRestart) => &mut self.encoder_restart,
+            Some(KeyframeReason::Resume) => &mut self.resume,
+            Some(KeyframeReason::CaptureTargetSwitch) => &mut self.capture_target_switch,
+            Some(KeyframeReason::Replay) => &mut self.replay,
+            Some(KeyframeReason::LateFrames) => &mut self.late_frames,
+            Some(KeyframeReason::Recording) => &mut self.recording,
+            Some(KeyframeReason::Pause) => &mut self.pause,
+            None => &mut self.periodic,
+        };
+        *count += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        KeyframeReason::ALL
+            .into_iter()
+            .map(Some)
+            .chain([None])
+            .map(|reason| self.get(reason))
+            .sum()
+    }
+}
+
+/// キャプチャ対象の問い合わせ・切り替えの結果
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CaptureTargetPayload {
+    /// 現在のキャプチャ対象の HWND（ウィンドウ以外を対象にしている場合は 0）
+    pub hwnd: u64,
+    pub title: Option<String>,
+    /// 切り替えに失敗した場合の理由（キャプチャ対象は変わっていない）
+    pub error: Option<String>,
+}
+
+/// キャプチャ対象に選べるウィンドウ（JSON でクライアントの選択 UI に渡す）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturableWindow {
+    pub hwnd: u64,
+    pub title: String,
+    pub process_name: String,
+    /// 縮小したサムネイル（PNG の data URL、キャプチャできなかった場合は None）
+    pub thumbnail: Option<String>,
+}
+
+/// InputService から hostd へのキャプチャ対象の操作要求
+#[derive(Debug)]
+pub enum CaptureTargetCommand {
+    Get {
+        reply_tx: tokio::sync::oneshot::Sender<CaptureTargetPayload>,
+    },
+    Set {
+        hwnd: Option<u64>,
+        title: Option<String>,
+        reply_tx: tokio::sync::oneshot::Sender<CaptureTargetPayload>,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub struct ScreenshotChunk {
     pub id: String,
@@ -330,37 +1474,603 @@ pub struct AudioFrame {
     pub samples: Vec<f32>, // インターリーブPCM（L,R,L,R,...）
     pub sample_rate: u32,  // 48000
     pub channels: u16,     // 2
-    pub timestamp_us: u64, // マイクロ秒タイムスタンプ
+    pub timestamp_us: u64, // 先頭サンプルのキャプチャ時刻（マイクロ秒、映像の capture_timestamp と同じ QPC の時間軸）
 }
 
 /// 音声キャプチャサービスへのメッセージ
 #[derive(Debug, Clone)]
 pub enum AudioCaptureMessage {
     Start { hwnd: u64 },
+    /// 指定したレンダーエンドポイント（MMDevice ID）の出力をループバックキャプチャ
+    /// （None は既定の出力デバイス、つまりシステム全体の音声）
+    StartEndpoint {
+        device_id: Option<String>,
+    },
+    /// 入力デバイス（マイク）をキャプチャ（None は既定の入力デバイス）
+    StartInputDevice {
+        device_id: Option<String>,
+    },
     Stop,
 }
 
 pub type AudioFrameSender = Sender<AudioFrame>;
 pub type AudioCaptureCommandReceiver = Receiver<AudioCaptureMessage>;
 
+/// 音声キャプチャ（WASAPI）の設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AudioCaptureConfig {
+    /// 共有モードのバッファ長（ミリ秒）。短いほど遅延は減るが途切れやすくなる
+    pub buffer_ms: u32,
+    /// データが届いたときのイベント通知で待つか（false なら 1ms 間隔のポーリング）
+    pub event_driven: bool,
+    /// プロセスループバックで無音がこの時間続き、対象のプロセスに子プロセスが増えていたら
+    /// ループバックを有効化し直す（後から起動した子プロセスの音声を拾う。None で無効）
+    #[serde(rename = "late_child_silence_ms", with = "option_duration_ms")]
+    pub late_child_silence: Option<Duration>,
+}
+
+/// `Option<Duration>` をミリ秒の整数として読み書きする（`#[serde(with = ...)]` で使う）
+mod option_duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        value: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(duration) => serializer.serialize_some(&(duration.as_millis() as u64)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_millis))
+    }
+}
+
+impl AudioCaptureConfig {
+    /// 指定できるバッファ長の範囲（ミリ秒）
+    pub const BUFFER_MS_RANGE: std::ops::RangeInclusive<u32> = 20..=100;
+    /// 子プロセスの増加を調べ始めるまでの無音の長さ（デフォルト）
+    pub const DEFAULT_LATE_CHILD_SILENCE: Duration = Duration::from_secs(5);
+}
+
+impl Default for AudioCaptureConfig {
+    fn default() -> Self {
+        Self {
+            buffer_ms: 100,
+            event_driven: true,
+            late_child_silence: Some(Self::DEFAULT_LATE_CHILD_SILENCE),
+        }
+    }
+}
+
 /// 音声エンコード結果
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AudioEncodeResult {
     pub encoded_data: Vec<u8>, // Opusエンコード済みデータ
-    pub duration: Duration,    // フレームの長さ（10ms）
+    pub duration: Duration,    // フレームの長さ（10/20/40/60ms）
     pub is_silent: bool,       // 無音フレームかどうか
+    pub timestamp_us: u64, // 先頭サンプルのキャプチャ時刻（AudioFrame.timestamp_us と同じ時間軸）
 }
 
 /// 音声エンコーダーファクトリ
 pub trait AudioEncoderFactory: Send + Sync {
     /// エンコード済みデータの受信チャンネルを返す
     /// 音声フレームを送信するチャンネルを返す
-    fn setup(&self) -> (Sender<AudioFrame>, UnboundedReceiver<AudioEncodeResult>);
+    /// ワーカーを止めるハンドルも返す（フレームの送信側を閉じても止まる）
+    fn setup(
+        &self,
+    ) -> (
+        Sender<AudioFrame>,
+        UnboundedReceiver<AudioEncodeResult>,
+        AudioEncoderShutdown,
+    );
+}
+
+/// 音声エンコーダーのワーカーを止めるハンドル（`EncodeJobSlot::shutdown` の音声版）
+///
+/// フレームの送信側を閉じればワーカーは終わるが、送信側の複製が残っていると終わらない。
+/// `shutdown` はそれを待たずに止め、`stopped` でエンコーダーが破棄されるまで待てる
+/// （チャンネル数やビットレートを変えてエンコーダーを作り直す前に、古いものを確実に片付ける）
+#[derive(Debug)]
+pub struct AudioEncoderShutdown {
+    shutdown_tx: watch::Sender<bool>,
+    stopped_rx: watch::Receiver<()>,
+}
+
+/// ワーカー側で持つ停止の合図（エンコーダーを破棄してから drop すると停止を知らせる）
+#[derive(Debug)]
+pub struct AudioEncoderShutdownSignal {
+    shutdown_rx: watch::Receiver<bool>,
+    _stopped_tx: watch::Sender<()>,
+}
+
+impl AudioEncoderShutdown {
+    /// ハンドルとワーカー側の合図の組を作る
+    pub fn new() -> (Self, AudioEncoderShutdownSignal) {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let (stopped_tx, stopped_rx) = watch::channel(());
+        (
+            Self {
+                shutdown_tx,
+                stopped_rx,
+            },
+            AudioEncoderShutdownSignal {
+                shutdown_rx,
+                _stopped_tx: stopped_tx,
+            },
+        )
+    }
+
+    /// ワーカーに停止を通知する（即座に返り、停止は待たない）
+    pub fn shutdown(&self) {
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    /// ワーカーが終わり、エンコーダーが破棄されるまで待つ
+    pub async fn stopped(&mut self) {
+        while self.stopped_rx.changed().await.is_ok() {}
+    }
+}
+
+impl AudioEncoderShutdownSignal {
+    /// 停止が通知されるまで待つ（ハンドルが捨てられた場合は通知されないものとして待ち続ける）
+    pub async fn requested(&mut self) {
+        if self
+            .shutdown_rx
+            .wait_for(|shutdown| *shutdown)
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    }
 }
 
 /// ビデオストリームサービスへの制御メッセージ
 #[derive(Debug, Clone)]
 pub enum VideoStreamMessage {
-    /// キーフレーム要求 (PLI/FIR RTCP feedback)
-    RequestKeyframe,
+    /// キーフレーム要求（PLI/FIR RTCP feedback・接続確立など）
+    RequestKeyframe {
+        reason: KeyframeReason,
+    },
+    /// エンコーダーへの供給を止める（接続は維持）
+    Pause,
+    /// 供給を再開（キーフレームから送り直す）
+    Resume,
+    /// リプレイバッファの内容を MP4 に保存
+    SaveReplay,
+    /// 録画（映像と音声を fMP4 に書き出す）を開始・停止
+    StartRecording,
+    StopRecording,
+    /// 今のコーデックのままエンコーダーを作り直す（キーフレームから送り直す）
+    RestartEncoder,
+    /// エンコーダーのビットレートの上限を設定する（接続中の上限の最小値。None で制限しない）
+    SetMaxBitrate {
+        bitrate_bps: Option<u32>,
+    },
+}
+
+/// 1 本の音声ストリームにミックスする音声ソース
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AudioSource {
+    /// ゲーム（ウィンドウのプロセスまたはレンダーエンドポイント）のループバック
+    Game,
+    /// 入力デバイス（マイク）
+    Mic,
+}
+
+impl std::fmt::Display for AudioSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AudioSource::Game => write!(f, "game"),
+            AudioSource::Mic => write!(f, "mic"),
+        }
+    }
+}
+
+/// オーディオストリームサービスへの制御メッセージ
+#[derive(Debug, Clone)]
+pub enum AudioStreamMessage {
+    /// エンコーダーへの供給を止める（接続は維持）
+    Pause,
+    /// 供給を再開
+    Resume,
+    /// ミックスする音声ソースのゲイン・ミュートを変更
+    SetSourceGain {
+        source: AudioSource,
+        gain: f32,
+        muted: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job() -> EncodeJob {
+        job_with_sequence(0)
+    }
+
+    fn job_with_sequence(sequence: u64) -> EncodeJob {
+        EncodeJob {
+            width: 2,
+            height: 2,
+            rgba: Arc::new(vec![0; 16]),
+            timestamp: 0,
+            enqueue_at: Instant::now(),
+            request_keyframe: None,
+            fps: 30,
+            format: PixelFormat::Rgba8,
+            sequence,
+            scale_to: None,
+            max_bitrate_bps: None,
+        }
+    }
+
+    #[test]
+    fn test_occluded_fraction() {
+        let rect = |left, top, right, bottom| ScreenRect {
+            left,
+            top,
+            right,
+            bottom,
+        };
+        let target = rect(100, 100, 900, 700);
+        assert_eq!(occluded_fraction(target, &[]), 0.0);
+        // 重ならない・完全に覆う
+        assert_eq!(occluded_fraction(target, &[rect(0, 0, 100, 100)]), 0.0);
+        assert_eq!(occluded_fraction(target, &[rect(0, 0, 1000, 800)]), 1.0);
+        // 左半分
+        assert_eq!(occluded_fraction(target, &[rect(0, 0, 500, 800)]), 0.5);
+        // 重なった 2 枚は二重に数えない（左半分と左上 1/4）
+        assert_eq!(
+            occluded_fraction(target, &[rect(0, 0, 500, 800), rect(0, 0, 500, 400)]),
+            0.5
+        );
+        // 左半分と上半分で 3/4
+        assert_eq!(
+            occluded_fraction(target, &[rect(0, 0, 500, 800), rect(0, 0, 1000, 400)]),
+            0.75
+        );
+        assert_eq!(occluded_fraction(rect(0, 0, 0, 10), &[target]), 0.0);
+    }
+
+    #[test]
+    fn test_dirty_fraction() {
+        assert_eq!(dirty_fraction(100, 100, &[]), 0.0);
+        assert_eq!(dirty_fraction(100, 100, &[Rect::new(0, 0, 50, 100)]), 0.5);
+        // フレームの外にはみ出した分は数えない
+        assert_eq!(dirty_fraction(100, 100, &[Rect::new(50, 0, 100, 100)]), 0.5);
+        assert_eq!(dirty_fraction(100, 100, &[Rect::new(100, 0, 10, 10)]), 0.0);
+        // 点滅するカーソルのような小さな変化も 0 にはしない
+        assert!(dirty_fraction(3840, 2160, &[Rect::new(10, 10, 1, 1)]) > 0.0);
+        assert_eq!(
+            dirty_fraction(
+                100,
+                100,
+                &[Rect::new(0, 0, 100, 100), Rect::new(0, 0, 50, 50)]
+            ),
+            1.0
+        );
+        assert_eq!(dirty_fraction(0, 100, &[Rect::new(0, 0, 1, 1)]), 0.0);
+
+        assert_eq!(merge_dirty_fraction(Some(0.0), Some(0.25)), Some(0.25));
+        assert_eq!(merge_dirty_fraction(Some(0.75), Some(0.5)), Some(1.0));
+        assert_eq!(merge_dirty_fraction(None, Some(0.0)), None);
+    }
+
+    #[test]
+    fn test_parse_occlusion_policy() {
+        assert_eq!("capture-anyway".parse(), Ok(OcclusionPolicy::CaptureAnyway));
+        assert_eq!("Freeze".parse(), Ok(OcclusionPolicy::FreezeOnOcclusion));
+        assert!("hide".parse::<OcclusionPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_detects_black_frame() {
+        let mut frame = [0u8, 0, 0, 255].repeat(64 * 64);
+        assert!(is_black_frame(&frame));
+        // 誤差程度の明るさは黒とみなす
+        frame[0] = 3;
+        assert!(is_black_frame(&frame));
+        // 一部でも描画されていれば黒ではない
+        frame[..4 * 64].copy_from_slice(&[200u8, 180, 160, 255].repeat(64));
+        assert!(!is_black_frame(&frame));
+        assert!(!is_black_frame(&[]));
+        assert_eq!("Ignore".parse(), Ok(ProtectedContentPolicy::Ignore));
+        assert!("skip".parse::<ProtectedContentPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_encode_job_slot_reports_replaced_job() {
+        let slot = EncodeJobSlot::new();
+        assert!(!slot.set(job()));
+        // 取り出される前に上書きしたらドロップ
+        assert!(slot.set(job()));
+        assert!(slot.take().is_ok());
+        assert!(!slot.set(job()));
+    }
+
+    #[test]
+    fn test_scale_job_on_cpu() {
+        // 4x2 の左半分が白、右半分が黒
+        let mut rgba = Vec::new();
+        for _ in 0..2 {
+            rgba.extend([[255u8; 4], [255; 4], [0, 0, 0, 255], [0, 0, 0, 255]].concat());
+        }
+        let mut job = EncodeJob {
+            width: 4,
+            height: 2,
+            rgba: Arc::new(rgba),
+            scale_to: Some(ScaleTarget {
+                width: 2,
+                height: 2,
+                aspect: AspectMode::Letterbox,
+            }),
+            ..job()
+        };
+        assert_eq!(job.output_size(), (2, 2));
+
+        job.scale_on_cpu();
+        assert_eq!((job.width, job.height, job.scale_to), (2, 2, None));
+        // 上の行に縮小した絵、下の行は余白（不透明な黒）
+        assert_eq!(
+            job.rgba.as_slice(),
+            [[255; 4], [0, 0, 0, 255], [0, 0, 0, 255], [0, 0, 0, 255]].concat()
+        );
+        assert_eq!(job.output_size(), (2, 2));
+    }
+
+    #[test]
+    fn test_encode_job_slot_set_reports_replaced_jobs() {
+        let slot = EncodeJobSlot::new();
+        let replaced: Vec<bool> = (1..=4)
+            .map(|sequence| slot.set(job_with_sequence(sequence)))
+            .collect();
+        // エンコーダーに届くのは最新のジョブだけで、間の 3 枚は飛ぶ
+        assert_eq!(replaced, [false, true, true, true]);
+        assert_eq!(slot.take().unwrap().sequence, 4);
+
+        assert!(!slot.set(job_with_sequence(5)));
+        assert_eq!(slot.try_take().unwrap().unwrap().sequence, 5);
+        assert!(!slot.set(job_with_sequence(6)));
+        assert!(slot.set(job_with_sequence(7)));
+        assert_eq!(slot.take().unwrap().sequence, 7);
+    }
+
+    #[tokio::test]
+    async fn test_audio_encoder_shutdown_waits_for_the_worker() {
+        let (mut handle, mut signal) = AudioEncoderShutdown::new();
+        let destroyed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let worker_destroyed = destroyed.clone();
+        tokio::spawn(async move {
+            signal.requested().await;
+            // エンコーダーを破棄してから合図を drop する
+            worker_destroyed.store(true, Ordering::SeqCst);
+            drop(signal);
+        });
+        handle.shutdown();
+        handle.stopped().await;
+        assert!(destroyed.load(Ordering::SeqCst));
+
+        // ハンドルを捨てただけではワーカーを止めない
+        let (handle, mut signal) = AudioEncoderShutdown::new();
+        drop(handle);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(20), signal.requested())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_keyframe_counts_by_reason() {
+        let mut counts = KeyframeCounts::default();
+        counts.record(Some(KeyframeReason::Pli));
+        counts.record(Some(KeyframeReason::Pli));
+        counts.record(Some(KeyframeReason::Reconnect));
+        counts.record(None);
+        assert_eq!(counts.get(Some(KeyframeReason::Pli)), 2);
+        assert_eq!(counts.reconnect, 1);
+        assert_eq!(counts.periodic, 1);
+        assert_eq!(counts.total(), 4);
+    }
+
+    #[test]
+    fn test_fit_to_max_pixels() {
+        // 上限以下ならそのまま
+        assert_eq!(fit_to_max_pixels(1920, 1080, 1920 * 1080), (1920, 1080));
+        assert_eq!(fit_to_max_pixels(1280, 720, 1920 * 1080), (1280, 720));
+
+        // 4K → 1080p 相当の上限
+        assert_eq!(fit_to_max_pixels(3840, 2160, 1920 * 1080), (1920, 1080));
+
+        // 縦長・奇数サイズでもアスペクト比を保ち、上限を超えない
+        for (width, height, max_pixels) in [(2560, 1440, 921_600), (1081, 1921, 500_000)] {
+            let (w, h) = fit_to_max_pixels(width, height, max_pixels);
+            assert!(w * h <= max_pixels);
+            assert_eq!((w % 2, h % 2), (0, 0));
+            let src_aspect = width as f64 / height as f64;
+            let dst_aspect = w as f64 / h as f64;
+            assert!((src_aspect - dst_aspect).abs() / src_aspect < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_even_size_and_crop_odd_source() {
+        assert_eq!(even_size(1920, 1080), (1920, 1080));
+        assert_eq!(even_size(1921, 1081), (1920, 1080));
+        assert_eq!(even_size(1, 0), (2, 2));
+        assert_eq!(
+            CaptureSize::Custom {
+                width: 641,
+                height: 481
+            }
+            .to_even(),
+            CaptureSize::Custom {
+                width: 640,
+                height: 480
+            }
+        );
+        assert_eq!(
+            CaptureSize::UseSourceSize.to_even(),
+            CaptureSize::UseSourceSize
+        );
+
+        // 3x3 の各画素に (x, y) を書き、右端の列と下端の行だけが落ちることを確かめる
+        let pixel = |x: u32, y: u32| [x as u8, y as u8, 0, 255];
+        let image: Vec<u8> = (0..3)
+            .flat_map(|y| (0..3).flat_map(move |x| pixel(x, y)))
+            .collect();
+        let cropped = crop_to_even(&image, 3, 3).unwrap();
+        let expected: Vec<u8> = (0..2)
+            .flat_map(|y| (0..2).flat_map(move |x| pixel(x, y)))
+            .collect();
+        assert_eq!(cropped, expected);
+
+        // 幅だけ・高さだけ奇数でも偶数のサイズになる
+        assert_eq!(
+            crop_to_even(&[0; 5 * 4 * 4], 5, 4).unwrap().len(),
+            4 * 4 * 4
+        );
+        assert_eq!(
+            crop_to_even(&[0; 4 * 5 * 4], 4, 5).unwrap().len(),
+            4 * 4 * 4
+        );
+        // 偶数ならコピーしない
+        assert!(crop_to_even(&image[..2 * 2 * 4], 2, 2).is_none());
+    }
+
+    #[test]
+    fn test_crop_rect() {
+        // 4x3 の各画素に (x, y) を書き、矩形の部分だけが残ることを確かめる
+        let pixel = |x: u32, y: u32| [x as u8, y as u8, 0, 255];
+        let image: Vec<u8> = (0..3)
+            .flat_map(|y| (0..4).flat_map(move |x| pixel(x, y)))
+            .collect();
+        let (cropped, width, height) = crop_rect(&image, 4, 3, Rect::new(1, 1, 2, 2)).unwrap();
+        assert_eq!((width, height), (2, 2));
+        let expected: Vec<u8> = (1..3)
+            .flat_map(|y| (1..3).flat_map(move |x| pixel(x, y)))
+            .collect();
+        assert_eq!(cropped, expected);
+
+        // はみ出す部分は落とし、重ならなければ None
+        let (_, width, height) = crop_rect(&image, 4, 3, Rect::new(2, 1, 10, 10)).unwrap();
+        assert_eq!((width, height), (2, 2));
+        assert!(crop_rect(&image, 4, 3, Rect::new(4, 0, 2, 2)).is_none());
+        assert!(crop_rect(&image, 4, 3, Rect::new(0, 0, 0, 3)).is_none());
+    }
+
+    #[test]
+    fn test_log_throttle() {
+        let mut throttle = LogThrottle::new(3, Duration::from_secs(5));
+        let start = Instant::now();
+
+        // 初回は即座に出す
+        assert_eq!(throttle.check_at(start), Some(0));
+        // 3 回ごとに抑制件数付きで出す
+        assert_eq!(throttle.check_at(start), None);
+        assert_eq!(throttle.check_at(start), None);
+        assert_eq!(throttle.check_at(start), Some(2));
+
+        // 回数に達しなくても時間が経てば出す
+        assert_eq!(throttle.check_at(start), None);
+        let later = start + Duration::from_secs(5);
+        assert_eq!(throttle.check_at(later), Some(1));
+        assert_eq!(throttle.check_at(later), None);
+    }
+
+    #[test]
+    fn test_frame_interval_monitor() {
+        let start = Instant::now();
+        let mut monitor = FrameIntervalMonitor::new(50);
+        let frame = Duration::from_millis(20);
+
+        // 間隔が測れるまでは判定しない
+        assert!(!monitor.is_stalled_at(start + Duration::from_secs(10)));
+        monitor.record_at(start);
+        assert!(!monitor.is_stalled_at(start + Duration::from_secs(10)));
+
+        let mut now = start;
+        for _ in 0..50 {
+            now += frame;
+            monitor.record_at(now);
+        }
+        let fps = monitor.fps_at(now).unwrap();
+        assert!((fps - 50.0).abs() < 0.1, "fps = {}", fps);
+        assert!(!monitor.is_stalled_at(now));
+
+        // 少しの遅れでは停止とみなさない
+        assert!(!monitor.is_stalled_at(now + Duration::from_millis(200)));
+        // フレームが届かない時間が続くと停止
+        assert!(monitor.is_stalled_at(now + Duration::from_millis(500)));
+
+        // 遅いフレームが続いても EMA が追いつくまでは停止としない
+        let mut slow = FrameIntervalMonitor::new(50);
+        let mut now = start;
+        slow.record_at(now);
+        for _ in 0..5 {
+            now += frame;
+            slow.record_at(now);
+        }
+        for _ in 0..3 {
+            now += Duration::from_millis(80);
+            slow.record_at(now);
+        }
+        assert!(!slow.is_stalled_at(now));
+        for _ in 0..30 {
+            now += Duration::from_millis(80);
+            slow.record_at(now);
+        }
+        assert!(slow.is_stalled_at(now));
+        assert!((slow.fps_at(now).unwrap() - 12.5).abs() < 1.0);
+
+        // 想定フレームレートを変えるとやり直す
+        slow.reset(10);
+        assert!(!slow.is_stalled_at(now));
+    }
+
+    #[test]
+    fn test_aspect_layout() {
+        // 16:9 → 4:3 の引き伸ばしは全体対全体
+        assert_eq!(
+            aspect_layout(1920, 1080, 640, 480, AspectMode::Stretch),
+            (Rect::new(0, 0, 1920, 1080), Rect::new(0, 0, 640, 480))
+        );
+
+        // レターボックス: 上下に 60px ずつ余白
+        assert_eq!(
+            aspect_layout(1920, 1080, 640, 480, AspectMode::Letterbox),
+            (Rect::new(0, 0, 1920, 1080), Rect::new(0, 60, 640, 360))
+        );
+        // 縦長の元画像は左右に余白（ピラーボックス）
+        assert_eq!(
+            aspect_layout(1080, 1920, 1280, 720, AspectMode::Letterbox),
+            (Rect::new(0, 0, 1080, 1920), Rect::new(437, 0, 405, 720))
+        );
+
+        // クロップ: 左右 240px ずつ切り落とし
+        assert_eq!(
+            aspect_layout(1920, 1080, 640, 480, AspectMode::Crop),
+            (Rect::new(240, 0, 1440, 1080), Rect::new(0, 0, 640, 480))
+        );
+        assert_eq!(
+            aspect_layout(1080, 1920, 1280, 720, AspectMode::Crop),
+            (Rect::new(0, 656, 1080, 607), Rect::new(0, 0, 1280, 720))
+        );
+
+        // アスペクト比が同じならどのモードでも全体対全体
+        for aspect in [AspectMode::Letterbox, AspectMode::Crop] {
+            assert_eq!(
+                aspect_layout(1920, 1080, 1280, 720, aspect),
+                (Rect::new(0, 0, 1920, 1080), Rect::new(0, 0, 1280, 720))
+            );
+        }
+    }
 }
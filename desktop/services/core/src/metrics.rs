@@ -0,0 +1,189 @@
+// 監視用のメトリクス（Prometheus のテキスト形式）
+//
+// 各サービスが既に集計している統計を、共有の `Metrics` に書き込む。hostd の `--metrics-port` で
+// `/metrics` として公開し、複数のホストをまとめて監視できるようにする。
+// カウンターは起動からの累計（Prometheus 側で rate() を取る）、ゲージは直近の集計区間の値。
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering};
+
+/// サービス間で共有するメトリクス
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// キャプチャから受け取ったフレームの累計
+    pub video_frames_captured: AtomicU64,
+    /// エンコードしたフレームの累計
+    pub video_frames_encoded: AtomicU64,
+    /// エンコーダーが追いつかずに捨てたフレームの累計
+    pub video_frames_dropped: AtomicU64,
+    /// エンコード結果のバイト数の累計
+    pub video_bytes_encoded: AtomicU64,
+    /// 送出した音声フレームの累計
+    pub audio_frames_sent: AtomicU64,
+    /// そのうち無音だったものの累計
+    pub audio_frames_silent: AtomicU64,
+    /// 接続が確立したセッションの累計
+    pub sessions_started: AtomicU64,
+    /// 接続中のセッション数
+    sessions_active: AtomicI64,
+    /// ゲージ（f32 のビット列）
+    video_encode_fps: AtomicU32,
+    video_bitrate_bps: AtomicU32,
+    audio_silence_ratio: AtomicU32,
+}
+
+impl Metrics {
+    pub fn session_started(&self) {
+        self.sessions_started.fetch_add(1, Ordering::Relaxed);
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_ended(&self) {
+        self.sessions_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// 接続中のセッション数
+    pub fn sessions_active(&self) -> u64 {
+        self.sessions_active.load(Ordering::Relaxed).max(0) as u64
+    }
+
+    /// 直近の区間のエンコードフレームレートとビットレート
+    pub fn set_video_rates(&self, encode_fps: f32, bitrate_bps: f32) {
+        store_f32(&self.video_encode_fps, encode_fps);
+        store_f32(&self.video_bitrate_bps, bitrate_bps);
+    }
+
+    /// `set_video_rates` で書き込んだ直近の区間の (エンコードフレームレート, ビットレート)
+    pub fn video_rates(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.video_encode_fps.load(Ordering::Relaxed)),
+            f32::from_bits(self.video_bitrate_bps.load(Ordering::Relaxed)),
+        )
+    }
+
+    /// 直近の区間に送出した音声フレームのうち無音だった割合（0.0-1.0）
+    pub fn set_audio_silence_ratio(&self, ratio: f32) {
+        store_f32(&self.audio_silence_ratio, ratio);
+    }
+
+    /// Prometheus のテキスト形式（version 0.0.4）で書き出す
+    pub fn render(&self) -> String {
+        let counter = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        let gauge = |gauge: &AtomicU32| f32::from_bits(gauge.load(Ordering::Relaxed)).to_string();
+        let metrics = [
+            (
+                "remoterg_video_frames_captured_total",
+                "counter",
+                "Video frames received from capture",
+                counter(&self.video_frames_captured),
+            ),
+            (
+                "remoterg_video_frames_encoded_total",
+                "counter",
+                "Video frames encoded",
+                counter(&self.video_frames_encoded),
+            ),
+            (
+                "remoterg_video_frames_dropped_total",
+                "counter",
+                "Video frames dropped because the encoder could not keep up",
+                counter(&self.video_frames_dropped),
+            ),
+            (
+                "remoterg_video_encoded_bytes_total",
+                "counter",
+                "Bytes of encoded video",
+                counter(&self.video_bytes_encoded),
+            ),
+            (
+                "remoterg_video_encode_fps",
+                "gauge",
+                "Encoded frames per second over the last stats interval",
+                gauge(&self.video_encode_fps),
+            ),
+            (
+                "remoterg_video_bitrate_bps",
+                "gauge",
+                "Encoded video bitrate over the last stats interval",
+                gauge(&self.video_bitrate_bps),
+            ),
+            (
+                "remoterg_audio_frames_sent_total",
+                "counter",
+                "Audio frames written to the track",
+                counter(&self.audio_frames_sent),
+            ),
+            (
+                "remoterg_audio_frames_silent_total",
+                "counter",
+                "Silent audio frames written to the track",
+                counter(&self.audio_frames_silent),
+            ),
+            (
+                "remoterg_audio_silence_ratio",
+                "gauge",
+                "Share of silent audio frames over the last stats interval",
+                gauge(&self.audio_silence_ratio),
+            ),
+            (
+                "remoterg_sessions_started_total",
+                "counter",
+                "WebRTC sessions that reached the connected state",
+                counter(&self.sessions_started),
+            ),
+            (
+                "remoterg_sessions_active",
+                "gauge",
+                "WebRTC sessions currently connected",
+                self.sessions_active().to_string(),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn store_f32(gauge: &AtomicU32, value: f32) {
+    gauge.store(value.to_bits(), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics
+            .video_frames_encoded
+            .fetch_add(42, Ordering::Relaxed);
+        metrics.set_video_rates(60.0, 8_000_000.0);
+        metrics.set_audio_silence_ratio(0.25);
+        assert_eq!(metrics.video_rates(), (60.0, 8_000_000.0));
+        metrics.session_started();
+        metrics.session_started();
+        metrics.session_ended();
+        assert_eq!(metrics.sessions_active(), 1);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE remoterg_video_frames_encoded_total counter\n"));
+        assert!(text.contains("\nremoterg_video_frames_encoded_total 42\n"));
+        assert!(text.contains("\nremoterg_video_encode_fps 60\n"));
+        assert!(text.contains("\nremoterg_video_bitrate_bps 8000000\n"));
+        assert!(text.contains("\nremoterg_audio_silence_ratio 0.25\n"));
+        assert!(text.contains("\nremoterg_sessions_started_total 2\n"));
+        assert!(text.contains("\nremoterg_sessions_active 1\n"));
+        // 各行は "名前 値" か、コメント
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (name, value) = line.split_once(' ').unwrap();
+            assert!(name.starts_with("remoterg_"));
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+// キャプチャからエンコード・送出までのパイプライン全体の実効設定
+//
+// 設定はサービスごとの構造体（`CaptureConfig`, `AudioCaptureConfig`）とエンコーダーのファクトリに
+// 直接渡す値に散らばっていて、クライアントから「いまどの設定で動いているか」を確かめる手段がなかった。
+// hostd が CLI・環境変数・設定ファイルを重ねた設定から起動時に各サービスに渡す値でこの構造体を組み立て、
+// クライアントの GetPipelineConfig に PIPELINE_CONFIG として返す（音声を使えるかなど、起動後に変わる値は
+// 返すときの状態に置き換える）。エンコーダーの既定値はここにまとめ、hostd のフラグの既定値にも使う。
+// 各サービスの設定の構造体とコンストラクタはこれまでどおりで、この構造体は値をまとめて持つだけ。
+
+use crate::{AudioCaptureConfig, CaptureConfig, VideoCodec};
+use serde::{Deserialize, Serialize};
+
+/// パイプライン全体の実効設定
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct PipelineConfig {
+    /// 起動時のキャプチャ設定（品質ラダーが有効なら最上段）
+    pub capture: CaptureConfig,
+    pub video_encoder: VideoEncoderConfig,
+    pub audio_capture: AudioCaptureConfig,
+    pub audio_encoder: AudioEncoderConfig,
+    pub webrtc: WebRtcConfig,
+}
+
+/// 映像エンコーダーの設定（値は hostd のフラグと同じ表記）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VideoEncoderConfig {
+    pub codec: VideoCodec,
+    /// "low-latency"（B フレームなし）か "quality"
+    pub mode: String,
+    /// ハードウェアエンコーダーを使わずソフトウェア（OpenH264）でエンコードする
+    pub force_software: bool,
+    /// 使うハードウェアエンコーダー（番号か名前の一部。None なら最初のもの）
+    pub device: Option<String>,
+    /// YUV の行列（"bt709" か "bt601"）と範囲（"limited" か "full"）
+    pub color_matrix: String,
+    pub color_range: String,
+    /// キャプチャ時刻を SEI に埋め込む
+    pub capture_time_sei: bool,
+}
+
+impl VideoEncoderConfig {
+    pub const DEFAULT_MODE: &'static str = "low-latency";
+    pub const DEFAULT_COLOR_MATRIX: &'static str = "bt709";
+    pub const DEFAULT_COLOR_RANGE: &'static str = "limited";
+}
+
+impl Default for VideoEncoderConfig {
+    fn default() -> Self {
+        Self {
+            codec: VideoCodec::H264,
+            mode: Self::DEFAULT_MODE.to_string(),
+            force_software: false,
+            device: None,
+            color_matrix: Self::DEFAULT_COLOR_MATRIX.to_string(),
+            color_range: Self::DEFAULT_COLOR_RANGE.to_string(),
+            capture_time_sei: false,
+        }
+    }
+}
+
+/// 音声エンコーダー（Opus）の設定
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AudioEncoderConfig {
+    /// フレーム長（10, 20, 40, 60ms）
+    pub frame_duration_ms: u32,
+    /// "audio" か "lowdelay"
+    pub application: String,
+}
+
+impl AudioEncoderConfig {
+    pub const DEFAULT_FRAME_DURATION_MS: u32 = 10;
+    pub const DEFAULT_APPLICATION: &'static str = "audio";
+}
+
+impl Default for AudioEncoderConfig {
+    fn default() -> Self {
+        Self {
+            frame_duration_ms: Self::DEFAULT_FRAME_DURATION_MS,
+            application: Self::DEFAULT_APPLICATION.to_string(),
+        }
+    }
+}
+
+/// WebRTC（送出）の設定
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebRtcConfig {
+    /// 音声トラックを送るか
+    pub audio: bool,
+    /// 接続によるキーフレーム要求をまとめる時間（ミリ秒、0 でまとめない）
+    pub keyframe_coalesce_ms: u64,
+    /// 映像の送出ペーシングのビットレート（kbps、None で無効）
+    pub pacing_kbps: Option<u32>,
+    /// 送出パケットに付ける DSCP
+    pub dscp: Option<String>,
+}
+
+impl WebRtcConfig {
+    pub const DEFAULT_KEYFRAME_COALESCE_MS: u64 = 200;
+}
+
+impl Default for WebRtcConfig {
+    fn default() -> Self {
+        Self {
+            audio: true,
+            keyframe_coalesce_ms: Self::DEFAULT_KEYFRAME_COALESCE_MS,
+            pacing_kbps: None,
+            dscp: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AspectMode, CaptureSize, OcclusionPolicy, PixelFormat};
+    use std::time::Duration;
+
+    #[test]
+    fn test_pipeline_config_round_trips_through_json() {
+        let default = PipelineConfig::default();
+        let json = serde_json::to_string(&default).unwrap();
+        assert_eq!(
+            serde_json::from_str::<PipelineConfig>(&json).unwrap(),
+            default
+        );
+
+        let config = PipelineConfig {
+            capture: CaptureConfig {
+                size: CaptureSize::Custom {
+                    width: 1280,
+                    height: 720,
+                },
+                aspect: AspectMode::Letterbox,
+                fps: 30,
+                max_encode_pixels: Some(1280 * 720),
+                pixel_format: PixelFormat::Bgra8,
+                occlusion: OcclusionPolicy::FreezeOnOcclusion,
+                ..Default::default()
+            },
+            video_encoder: VideoEncoderConfig {
+                mode: "quality".to_string(),
+                device: Some("NVIDIA".to_string()),
+                ..Default::default()
+            },
+            audio_capture: AudioCaptureConfig {
+                buffer_ms: 20,
+                event_driven: false,
+                late_child_silence: Some(Duration::from_secs(3)),
+            },
+            audio_encoder: AudioEncoderConfig {
+                frame_duration_ms: 20,
+                application: "lowdelay".to_string(),
+            },
+            webrtc: WebRtcConfig {
+                audio: false,
+                pacing_kbps: Some(8000),
+                ..Default::default()
+            },
+        };
+        let value = serde_json::to_value(&config).unwrap();
+        // 列挙値は CLI のフラグと同じ表記で出す
+        assert_eq!(value["capture"]["aspect"], "letterbox");
+        assert_eq!(value["capture"]["occlusion"], "freeze-on-occlusion");
+        assert_eq!(value["capture"]["size"]["custom"]["width"], 1280);
+        // 時間はミリ秒の整数で出す
+        assert_eq!(value["audio_capture"]["late_child_silence_ms"], 3000);
+        assert_eq!(
+            serde_json::from_value::<PipelineConfig>(value).unwrap(),
+            config
+        );
+    }
+}
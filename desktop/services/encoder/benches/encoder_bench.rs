@@ -1,4 +1,4 @@
-use core_types::{EncodeJob, EncodeResult, VideoEncoderFactory};
+use core_types::{EncodeJob, EncodeResult, PixelFormat, VideoEncoderFactory};
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
 use std::hint::black_box;
 use std::sync::Arc;
@@ -199,7 +199,12 @@ fn bench_encoder_multiple_frames<F: VideoEncoderFactory>(
                             rgba: black_box(rgba),
                             timestamp: black_box(timestamp),
                             enqueue_at: black_box(Instant::now()),
-                            request_keyframe: false,
+                            request_keyframe: None,
+                            fps: 60,
+                            format: PixelFormat::Rgba8,
+                            sequence: i + 1,
+                            scale_to: None,
+                            max_bitrate_bps: None,
                         };
                         job_slot.set(job);
                         rx.recv().await.unwrap();
@@ -221,7 +226,7 @@ fn bench_openh264(c: &mut Criterion) {
 
 #[cfg(all(feature = "h264", windows))]
 fn bench_mmf(c: &mut Criterion) {
-    let factory = MediaFoundationH264EncoderFactory::new();
+    let factory = MediaFoundationH264EncoderFactory::new(false);
 
     // MMFが利用可能でない場合はスキップ
     if !factory.use_media_foundation() {
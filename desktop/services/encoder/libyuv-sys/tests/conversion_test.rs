@@ -1,4 +1,5 @@
 /// libyuv-sysのRGBA→YUV420変換のテスト
+/// libyuv-sysのBGRA→YUV420変換のテスト
 /// libyuv-sysのRGBA→NV12変換のテスト
 
 /// RGBA画像データを生成するヘルパー関数
@@ -168,6 +169,43 @@ fn test_red_image_conversion() {
     );
 }
 
+#[test]
+fn test_bgra_red_image_conversion() {
+    // 赤い画像をBGRAの並び（B, G, R, A = 0, 0, 255, 255）で変換
+    let width = 64;
+    let height = 64;
+    let bgra = create_rgba_image(width, height, 0, 0, 255, 255);
+
+    let mut y = vec![0u8; width * height];
+    let mut u = vec![0u8; (width * height) / 4];
+    let mut v = vec![0u8; (width * height) / 4];
+
+    unsafe {
+        let result = libyuv_sys::ARGBToI420(
+            bgra.as_ptr(),
+            (width * 4) as i32,
+            y.as_mut_ptr(),
+            width as i32,
+            u.as_mut_ptr(),
+            (width / 2) as i32,
+            v.as_mut_ptr(),
+            (width / 2) as i32,
+            width as i32,
+            height as i32,
+        );
+
+        assert_eq!(result, 0, "ARGBToI420 should return 0 on success");
+    }
+
+    // RGBAの赤と同じくV値が高くなるはず
+    let avg_v: u32 = v.iter().map(|&x| x as u32).sum::<u32>() / v.len() as u32;
+    assert!(
+        avg_v > 140,
+        "Red BGRA image should have high V values, got average: {}",
+        avg_v
+    );
+}
+
 #[test]
 fn test_gray_image_conversion() {
     // グレー画像（RGBA: 128, 128, 128, 255）を変換
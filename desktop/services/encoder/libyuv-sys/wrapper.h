@@ -14,6 +14,18 @@ int ABGRToI420(const uint8_t* src_abgr,
                int width,
                int height);
 
+// ARGBToI420: ARGB (BGRA in memory order) -> I420 (YUV420)
+int ARGBToI420(const uint8_t* src_argb,
+               int src_stride_argb,
+               uint8_t* dst_y,
+               int dst_stride_y,
+               uint8_t* dst_u,
+               int dst_stride_u,
+               uint8_t* dst_v,
+               int dst_stride_v,
+               int width,
+               int height);
+
 // ABGRToNV12: ABGR (RGBA in memory order) -> NV12 (Y plane + interleaved UV plane)
 int ABGRToNV12(const uint8_t* src_abgr,
                int src_stride_abgr,
@@ -23,3 +35,13 @@ int ABGRToNV12(const uint8_t* src_abgr,
                int dst_stride_uv,
                int width,
                int height);
+
+// ARGBToNV12: ARGB (BGRA in memory order) -> NV12 (Y plane + interleaved UV plane)
+int ARGBToNV12(const uint8_t* src_argb,
+               int src_stride_argb,
+               uint8_t* dst_y,
+               int dst_stride_y,
+               uint8_t* dst_uv,
+               int dst_stride_uv,
+               int width,
+               int height);
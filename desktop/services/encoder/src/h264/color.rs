@@ -0,0 +1,89 @@
+// RGB → YUV 変換の色空間（行列とレンジ）
+//
+// BT.601 は SD 時代の係数、BT.709 は HD 以降の係数。ブラウザのデコーダーは
+// ストリームにタグがなければ解像度などから推測するため、送る側で明示しないと
+// 色がずれる（くすむ・黒つぶれする）ことがある。
+// 現在の映像は HD 以上がほとんどなので BT.709 をデフォルトとし、
+// レンジは互換性の高いリミテッド（Y: 16-235, UV: 16-240）をデフォルトとする。
+//
+// 注: OpenH264 経路（libyuv の ABGRToI420）と、Media Foundation の CPU 変換経路
+// （GPU の前処理が使えない場合のフォールバック）は BT.601 リミテッド固定。
+
+use std::str::FromStr;
+
+/// YUV 変換行列
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorMatrix {
+    /// SD 向け (Kr = 0.299, Kb = 0.114)
+    Bt601,
+    /// HD 向け (Kr = 0.2126, Kb = 0.0722)
+    #[default]
+    Bt709,
+}
+
+impl FromStr for ColorMatrix {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s
+            .trim()
+            .to_ascii_lowercase()
+            .replace(['.', '-', '_'], "")
+            .as_str()
+        {
+            "bt601" | "601" => Ok(Self::Bt601),
+            "bt709" | "709" => Ok(Self::Bt709),
+            other => Err(format!("unsupported color matrix: {}", other)),
+        }
+    }
+}
+
+/// YUV の値域
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorRange {
+    /// Y: 16-235, UV: 16-240（TV レンジ）
+    #[default]
+    Limited,
+    /// Y/UV: 0-255（PC レンジ）
+    Full,
+}
+
+impl FromStr for ColorRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "limited" | "tv" => Ok(Self::Limited),
+            "full" | "pc" => Ok(Self::Full),
+            other => Err(format!("unsupported color range: {}", other)),
+        }
+    }
+}
+
+/// エンコード時の色空間
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ColorSpace {
+    pub matrix: ColorMatrix,
+    pub range: ColorRange,
+}
+
+impl ColorSpace {
+    pub fn new(matrix: ColorMatrix, range: ColorRange) -> Self {
+        Self { matrix, range }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("BT.709".parse::<ColorMatrix>(), Ok(ColorMatrix::Bt709));
+        assert_eq!("bt601".parse::<ColorMatrix>(), Ok(ColorMatrix::Bt601));
+        assert!("bt2020".parse::<ColorMatrix>().is_err());
+        assert_eq!("full".parse::<ColorRange>(), Ok(ColorRange::Full));
+        assert_eq!("Limited".parse::<ColorRange>(), Ok(ColorRange::Limited));
+        assert!("auto".parse::<ColorRange>().is_err());
+    }
+}
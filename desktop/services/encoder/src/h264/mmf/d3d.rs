@@ -1,9 +1,13 @@
 use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use windows::core::Interface;
-use windows::Win32::Foundation::HMODULE;
+use windows::Win32::Foundation::{CloseHandle, HANDLE, HMODULE, WAIT_OBJECT_0, WAIT_TIMEOUT};
 use windows::Win32::Graphics::Direct3D::D3D_DRIVER_TYPE_HARDWARE;
 use windows::Win32::Graphics::Direct3D11::{
-    D3D11CreateDevice, ID3D11Device, ID3D11DeviceContext, D3D11_SDK_VERSION,
+    D3D11CreateDevice, ID3D11Device, ID3D11Device5, ID3D11DeviceContext, ID3D11DeviceContext4,
+    ID3D11Fence, ID3D11Multithread, D3D11_FENCE_FLAG_NONE, D3D11_SDK_VERSION,
 };
 use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_NV12;
 use windows::Win32::Graphics::Dxgi::IDXGIDevice;
@@ -11,41 +15,140 @@ use windows::Win32::Media::MediaFoundation::{
     IMFDXGIDeviceManager, IMFTransform, MFCreateDXGIDeviceManager, MFT_MESSAGE_SET_D3D_MANAGER,
     MF_TRANSFORM_ASYNC_UNLOCK,
 };
+use windows::Win32::System::Threading::{CreateEventW, WaitForSingleObject};
+
+/// 前処理の GPU 完了を待つ上限（これを超える場合は GPU が詰まっているのでフレームを諦める）
+const GPU_WAIT_TIMEOUT: Duration = Duration::from_millis(100);
 
 /// D3D11 デバイスとコンテキスト、DXGI デバイスマネージャーを保持する構造体
+///
+/// 前処理器とエンコーダーで clone して共有する。デバイスは COM の参照カウントで
+/// 最後の clone が drop されたときに解放されるため、各 MFT は drop 時に
+/// D3D マネージャーを手放しておく必要がある（`mf::shutdown_transform`）
 #[derive(Clone)]
 pub struct D3D11Resources {
     pub device: ID3D11Device,
     pub context: ID3D11DeviceContext,
     pub device_manager: IMFDXGIDeviceManager,
     reset_token: u32,
+    /// GPU コマンドの完了待ちに使うフェンス
+    gpu_fence: Arc<GpuFence>,
+}
+
+/// GPU コマンドの完了を待つためのフェンスと、完了を通知させるイベント
+struct GpuFence {
+    context: ID3D11DeviceContext4,
+    fence: ID3D11Fence,
+    /// 最後に Signal した値（待つたびに 1 つ進める）
+    value: AtomicU64,
+    event: HANDLE,
+}
+
+impl GpuFence {
+    fn create(device: &ID3D11Device, context: &ID3D11DeviceContext) -> Result<Self> {
+        unsafe {
+            let device5: ID3D11Device5 = device
+                .cast()
+                .context("Failed to cast D3D11 device to ID3D11Device5")?;
+            let context = context
+                .cast()
+                .context("Failed to cast D3D11 context to ID3D11DeviceContext4")?;
+            let fence: ID3D11Fence = device5
+                .CreateFence(0, D3D11_FENCE_FLAG_NONE)
+                .context("Failed to create D3D11 fence")?;
+            let event = CreateEventW(None, false, false, None)
+                .context("Failed to create GPU fence event")?;
+            Ok(Self {
+                context,
+                fence,
+                value: AtomicU64::new(0),
+                event,
+            })
+        }
+    }
+}
+
+impl Drop for GpuFence {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.event);
+        }
+    }
 }
 
 impl D3D11Resources {
     /// D3D11 デバイスと DXGI デバイスマネージャーを作成
     pub fn create() -> Result<Self> {
         let (device, context) = create_d3d11_device()?;
+
+        // 前処理（このスレッド）と MFT のワーカースレッドが同じイミディエイトコンテキストを使うため、
+        // コンテキストへの呼び出しを D3D11 側で直列化させる
+        let multithread: ID3D11Multithread = context
+            .cast()
+            .context("Failed to cast D3D11 context to ID3D11Multithread")?;
+        unsafe {
+            let _ = multithread.SetMultithreadProtected(true);
+        }
+
         let (device_manager, reset_token) = create_dxgi_device_manager(&device)?;
 
+        let gpu_fence = Arc::new(GpuFence::create(&device, &context)?);
+
         Ok(Self {
             device,
             context,
             device_manager,
             reset_token,
+            gpu_fence,
         })
     }
 
-    /// MFT に D3D マネージャーを設定し、非同期ロックを解除
-    pub fn setup_mft(&self, transform: &IMFTransform) -> Result<()> {
+    /// ここまでにイミディエイトコンテキストへ発行した GPU コマンドがすべて完了するまで待つ
+    ///
+    /// フェンスに新しい値を Signal してから Flush し、その値への到達をイベントで待つ。
+    pub fn wait_for_gpu(&self) -> Result<()> {
+        let gpu_fence = &*self.gpu_fence;
+        let value = gpu_fence.value.fetch_add(1, Ordering::Relaxed) + 1;
         unsafe {
-            // 非同期ロックを解除（D3Dマネージャー設定の前に実行）
-            if let Ok(attributes) = transform.GetAttributes() {
-                attributes
-                    .SetUINT32(&MF_TRANSFORM_ASYNC_UNLOCK, 1)
-                    .ok()
-                    .context("Failed to unlock async MFT")?;
+            gpu_fence
+                .context
+                .Signal(&gpu_fence.fence, value)
+                .context("Failed to signal D3D11 fence")?;
+            self.context.Flush();
+            gpu_fence
+                .fence
+                .SetEventOnCompletion(value, gpu_fence.event)
+                .context("Failed to set GPU fence completion event")?;
+
+            match WaitForSingleObject(gpu_fence.event, GPU_WAIT_TIMEOUT.as_millis() as u32) {
+                WAIT_OBJECT_0 => Ok(()),
+                WAIT_TIMEOUT => anyhow::bail!("GPU did not finish within {:?}", GPU_WAIT_TIMEOUT),
+                other => Err(windows::core::Error::from_win32())
+                    .with_context(|| format!("Failed to wait for GPU fence ({:?})", other)),
             }
+        }
+    }
+
+    /// 解放済みリソースの破棄を含め、イミディエイトコンテキストに溜まったコマンドを GPU に送る
+    ///
+    /// D3D11 はテクスチャなどの解放をコンテキストの Flush まで遅延するため、
+    /// リソースを手放した直後に呼んで GPU メモリを回収させる
+    pub fn flush(&self) {
+        unsafe { self.context.Flush() };
+    }
+
+    /// デバイスが失われている（GPU 切り替え・ドライバー更新・TDR など）場合はその理由を返す
+    /// DXGI_ERROR_DEVICE_REMOVED などを返した D3D11 呼び出しの後に確認する
+    pub fn device_removed_reason(&self) -> Option<windows::core::Error> {
+        unsafe { self.device.GetDeviceRemovedReason() }.err()
+    }
+
+    /// MFT に D3D マネージャーを設定し、非同期ロックを解除
+    pub fn setup_mft(&self, transform: &IMFTransform) -> Result<()> {
+        // 非同期ロックを解除（D3Dマネージャー設定の前に実行）
+        unlock_async_mft(transform)?;
 
+        unsafe {
             // D3D マネージャーを設定
             transform
                 .ProcessMessage(
@@ -60,6 +163,19 @@ impl D3D11Resources {
     }
 }
 
+/// 非同期 MFT のロックを解除する（D3D マネージャーを使わない場合もこれは必要）
+pub fn unlock_async_mft(transform: &IMFTransform) -> Result<()> {
+    unsafe {
+        if let Ok(attributes) = transform.GetAttributes() {
+            attributes
+                .SetUINT32(&MF_TRANSFORM_ASYNC_UNLOCK, 1)
+                .ok()
+                .context("Failed to unlock async MFT")?;
+        }
+    }
+    Ok(())
+}
+
 /// D3D11 デバイスとコンテキストを作成
 pub fn create_d3d11_device() -> Result<(ID3D11Device, ID3D11DeviceContext)> {
     unsafe {
@@ -1,35 +1,76 @@
 use anyhow::{Context, Result};
-use tracing::debug;
-use windows::core::Interface;
+use core_types::H264Profile;
+use tracing::{debug, info, warn};
+use windows::core::{Interface, GUID};
 use windows::Win32::Media::MediaFoundation::{
-    CODECAPI_AVEncCommonLowLatency, CODECAPI_AVEncMPVDefaultBPictureCount,
-    CODECAPI_AVEncVideoForceKeyFrame, CODECAPI_AVLowLatencyMode, ICodecAPI, IMFMediaEventGenerator,
-    IMFMediaType, IMFTransform, MFCreateMediaType, MFMediaType_Video, MFVideoFormat_H264,
-    MFVideoFormat_NV12, MFVideoInterlace_Progressive, MFT_MESSAGE_COMMAND_FLUSH,
-    MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_SET_TYPE_TEST_ONLY,
-    MF_E_INVALIDMEDIATYPE, MF_E_NO_MORE_TYPES, MF_LOW_LATENCY, MF_MT_MPEG_SEQUENCE_HEADER,
+    eAVEncH264VProfile, eAVEncH264VProfile_ConstrainedBase, eAVEncH264VProfile_High,
+    eAVEncH264VProfile_Main, CODECAPI_AVEncCommonLowLatency, CODECAPI_AVEncCommonMeanBitRate,
+    CODECAPI_AVEncMPVDefaultBPictureCount, CODECAPI_AVEncVideoForceKeyFrame,
+    CODECAPI_AVLowLatencyMode, ICodecAPI, IMFMediaEventGenerator, IMFMediaType, IMFTransform,
+    MFCreateMediaType, MFMediaType_Video, MFVideoFormat_H264, MFVideoFormat_NV12,
+    MFVideoInterlace_Progressive, MFT_MESSAGE_COMMAND_FLUSH, MFT_MESSAGE_NOTIFY_BEGIN_STREAMING,
+    MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_SET_TYPE_TEST_ONLY, MF_E_INVALIDMEDIATYPE,
+    MF_E_NO_MORE_TYPES, MF_LOW_LATENCY, MF_MT_MPEG2_PROFILE, MF_MT_MPEG_SEQUENCE_HEADER,
 };
+use windows::Win32::System::Variant::VARIANT;
 
-use crate::h264::mmf::d3d::D3D11Resources;
+use crate::h264::color::ColorSpace;
+use crate::h264::mmf::d3d::{unlock_async_mft, D3D11Resources};
+use crate::h264::mmf::mf::{
+    set_color_attributes, shutdown_transform, EncoderDeviceSelector, EncoderLatencyMode,
+};
+use crate::h264::nal;
+
+/// 画質モードで許可する B フレーム数
+const QUALITY_B_PICTURE_COUNT: u32 = 2;
+
+/// 送出する H.264 プロファイル（Answer SDP の profile-level-id と揃える）
+fn mf_h264_profile(profile: H264Profile) -> eAVEncH264VProfile {
+    match profile {
+        H264Profile::ConstrainedBaseline => eAVEncH264VProfile_ConstrainedBase,
+        H264Profile::Main => eAVEncH264VProfile_Main,
+        H264Profile::High => eAVEncH264VProfile_High,
+    }
+}
 
 /// 非同期ハードウェア H.264 エンコーダー
 pub struct H264Encoder {
     transform: IMFTransform,
     event_generator: IMFMediaEventGenerator,
-    d3d_resources: D3D11Resources,
+    /// None の場合は D3D マネージャーを使わず、システムメモリの NV12 を受け取る
+    d3d_resources: Option<D3D11Resources>,
     width: u32,
     height: u32,
+    fps: u32,
+    latency_mode: EncoderLatencyMode,
+    color: ColorSpace,
+    /// 設定中のビットレートの上限（None なら MFT の既定のまま）
+    max_bitrate_bps: Option<u32>,
+    /// 上限を設定する前の平均ビットレート（上限を外すときに戻す）
+    default_mean_bitrate: Option<VARIANT>,
 }
 
 impl H264Encoder {
     /// H.264 エンコーダーを作成
-    pub fn create(d3d_resources: D3D11Resources, width: u32, height: u32) -> Result<Self> {
+    /// `d3d_resources` が None の場合は入力をメモリバッファで渡す（CPU 変換経路）
+    pub fn create(
+        d3d_resources: Option<D3D11Resources>,
+        width: u32,
+        height: u32,
+        fps: u32,
+        device: Option<&EncoderDeviceSelector>,
+        latency_mode: EncoderLatencyMode,
+        color: ColorSpace,
+    ) -> Result<Self> {
         unsafe {
-            let transform = crate::h264::mmf::mf::find_async_h264_encoder()
+            let transform = crate::h264::mmf::mf::find_async_h264_encoder(device)
                 .context("Failed to find async H.264 encoder MFT")?;
 
-            // D3D マネージャーを設定
-            d3d_resources.setup_mft(&transform)?;
+            // D3D マネージャーを設定（使わない場合も非同期ロックの解除は必要）
+            match &d3d_resources {
+                Some(d3d_resources) => d3d_resources.setup_mft(&transform)?,
+                None => unlock_async_mft(&transform)?,
+            }
 
             // IMFMediaEventGeneratorを取得（非同期MFTのイベント駆動に必要）
             let event_generator: IMFMediaEventGenerator = transform
@@ -43,10 +84,15 @@ impl H264Encoder {
                 d3d_resources,
                 width,
                 height,
+                fps,
+                latency_mode,
+                color,
+                max_bitrate_bps: None,
+                default_mean_bitrate: None,
             };
 
-            // 低遅延属性を設定（ベストエフォート、失敗しても無視）
-            encoder.setup_low_latency_attributes()?;
+            // 遅延モードを設定（メディアタイプより先に設定する必要がある）
+            encoder.setup_latency_mode()?;
 
             // メディアタイプを設定
             encoder
@@ -124,7 +170,7 @@ impl H264Encoder {
     fn setup_media_types(&mut self, width: u32, height: u32) -> Result<()> {
         unsafe {
             let frame_size = ((width as u64) << 32) | (height as u64);
-            let frame_rate = (60u64 << 32) | 1u64;
+            let frame_rate = crate::h264::mmf::mf::pack_frame_rate(self.fps);
 
             // 非同期MFTでは、出力メディアタイプを先に設定してから、
             // 入力メディアタイプを設定する必要がある
@@ -232,6 +278,20 @@ impl H264Encoder {
                 .ok()
                 .context("Failed to set output interlace mode")?;
 
+            // VUI に色空間を書き込ませる（ブラウザが正しい係数でデコードできるように）
+            set_color_attributes(&configured_output_type, self.color)
+                .ok()
+                .context("Failed to set output color attributes")?;
+
+            // プロファイルを固定する（MFT ごとのデフォルトに任せると SDP と食い違う）
+            configured_output_type
+                .SetUINT32(
+                    &MF_MT_MPEG2_PROFILE,
+                    mf_h264_profile(H264Profile::default()).0 as u32,
+                )
+                .ok()
+                .context("Failed to set output H.264 profile")?;
+
             // 出力メディアタイプを設定
             self.transform
                 .SetOutputType(0, &configured_output_type, 0)
@@ -318,6 +378,8 @@ impl H264Encoder {
                             MFVideoInterlace_Progressive.0 as u32,
                         )?;
 
+                        set_color_attributes(&configured_input_type, self.color)?;
+
                         // MFT_SET_TYPE_TEST_ONLYでテスト
                         let test_result = self.transform.SetInputType(
                             0,
@@ -399,6 +461,20 @@ impl H264Encoder {
         Ok(())
     }
 
+    /// 入力メディアタイプに設定されているフレームレート (分子, 分母) を取得
+    pub fn frame_rate(&self) -> Result<(u32, u32)> {
+        unsafe {
+            let input_type = self
+                .transform
+                .GetInputCurrentType(0)
+                .context("Failed to get input current type")?;
+            let frame_rate = input_type
+                .GetUINT64(&windows::Win32::Media::MediaFoundation::MF_MT_FRAME_RATE)
+                .context("Failed to get input frame rate")?;
+            Ok(((frame_rate >> 32) as u32, (frame_rate & 0xFFFFFFFF) as u32))
+        }
+    }
+
     /// transform への参照を取得（イベントループから使用）
     pub fn transform(&self) -> &IMFTransform {
         &self.transform
@@ -431,35 +507,59 @@ impl H264Encoder {
         }
     }
 
-    /// 低遅延属性を設定
-    fn setup_low_latency_attributes(&self) -> Result<()> {
+    /// 遅延モードに応じて MF_LOW_LATENCY と CODECAPI のプロパティを設定
+    fn setup_latency_mode(&self) -> Result<()> {
+        let low_latency = self.latency_mode == EncoderLatencyMode::LowLatency;
+        let b_picture_count = if low_latency {
+            0
+        } else {
+            QUALITY_B_PICTURE_COUNT
+        };
+        info!(
+            "MF encoder: {:?} mode (B-frames: {})",
+            self.latency_mode, b_picture_count
+        );
+        if !low_latency {
+            warn!(
+                "MF encoder: B-frames add latency and are not suitable for interactive streaming"
+            );
+        }
+
         unsafe {
-            // Attributes を取得
             let attributes = self.transform.GetAttributes()?;
             attributes
-                .SetUINT32(&MF_LOW_LATENCY, 1)
+                .SetUINT32(&MF_LOW_LATENCY, low_latency as u32)
                 .map_err(|e| anyhow::anyhow!("Failed to set MF_LOW_LATENCY attribute: {}", e))?;
-            attributes
-                .SetUINT32(&CODECAPI_AVLowLatencyMode, 1)
-                .map_err(|e| {
-                    anyhow::anyhow!("Failed to set CODECAPI_AVLowLatencyMode attribute: {}", e)
-                })?;
-            attributes
-                .SetUINT32(&CODECAPI_AVEncCommonLowLatency, 1)
-                .map_err(|e| {
-                    anyhow::anyhow!(
-                        "Failed to set CODECAPI_AVEncCommonLowLatency attribute: {}",
-                        e
-                    )
-                })?;
-            attributes
-                .SetUINT32(&CODECAPI_AVEncMPVDefaultBPictureCount, 0)
-                .map_err(|e| {
-                    anyhow::anyhow!(
-                        "Failed to set CODECAPI_AVEncMPVDefaultBPictureCount attribute: {}",
+
+            // CODECAPI_* は属性に入れるだけでは反映されない MFT があるため ICodecAPI 経由で設定する
+            let codec_api: ICodecAPI = match self.transform.cast() {
+                Ok(codec_api) => codec_api,
+                Err(e) => {
+                    warn!(
+                        "MF encoder: ICodecAPI is not available, latency settings may not apply: {}",
                         e
-                    )
-                })?;
+                    );
+                    return Ok(());
+                }
+            };
+            set_codec_value(
+                &codec_api,
+                &CODECAPI_AVLowLatencyMode,
+                "CODECAPI_AVLowLatencyMode",
+                low_latency.into(),
+            );
+            set_codec_value(
+                &codec_api,
+                &CODECAPI_AVEncCommonLowLatency,
+                "CODECAPI_AVEncCommonLowLatency",
+                low_latency.into(),
+            );
+            set_codec_value(
+                &codec_api,
+                &CODECAPI_AVEncMPVDefaultBPictureCount,
+                "CODECAPI_AVEncMPVDefaultBPictureCount",
+                b_picture_count.into(),
+            );
 
             Ok(())
         }
@@ -483,6 +583,79 @@ impl H264Encoder {
         }
     }
 
+    /// ビットレートの上限を設定する（None で上限を設定する前の値に戻す）
+    /// 平均ビットレートはエンコード中にも変更できる（対応していない MFT では効かない）
+    pub fn set_max_bitrate(&mut self, bitrate_bps: Option<u32>) -> Result<()> {
+        if self.max_bitrate_bps == bitrate_bps {
+            return Ok(());
+        }
+        // 設定できなかった場合もフレームごとにやり直さない
+        self.max_bitrate_bps = bitrate_bps;
+        unsafe {
+            let codec_api: ICodecAPI = self
+                .transform
+                .cast()
+                .ok()
+                .context("Failed to cast transform to ICodecAPI")?;
+            if self.default_mean_bitrate.is_none() {
+                self.default_mean_bitrate =
+                    codec_api.GetValue(&CODECAPI_AVEncCommonMeanBitRate).ok();
+            }
+            // 上限は既定の平均ビットレートより上げない（0 は MFT が決める既定値なので比べない）
+            let default_bps = self
+                .default_mean_bitrate
+                .as_ref()
+                .and_then(|value| u32::try_from(value).ok())
+                .filter(|bps| *bps > 0);
+            let value = match bitrate_bps {
+                Some(bps) => Some(VARIANT::from(default_bps.map_or(bps, |d| d.min(bps)))),
+                None => self.default_mean_bitrate.clone(),
+            };
+            info!(
+                "MF encoder: max bitrate {:?} bps (default mean bitrate {:?} bps)",
+                bitrate_bps, default_bps
+            );
+            if let Some(value) = value {
+                set_codec_value(
+                    &codec_api,
+                    &CODECAPI_AVEncCommonMeanBitRate,
+                    "CODECAPI_AVEncCommonMeanBitRate",
+                    value,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// MF_E_TRANSFORM_STREAM_CHANGE 後に、MFT が提示する新しい出力メディアタイプを設定し直す
+    pub fn renegotiate_output_type(&self) -> Result<()> {
+        unsafe {
+            let output_type = self.transform.GetOutputAvailableType(0, 0).map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to get output available type after stream change: {}",
+                    e
+                )
+            })?;
+            set_color_attributes(&output_type, self.color)
+                .ok()
+                .context("Failed to set output color attributes after stream change")?;
+            output_type
+                .SetUINT32(
+                    &MF_MT_MPEG2_PROFILE,
+                    mf_h264_profile(H264Profile::default()).0 as u32,
+                )
+                .ok()
+                .context("Failed to set output H.264 profile after stream change")?;
+            self.transform
+                .SetOutputType(0, &output_type, 0)
+                .map_err(|e| {
+                    anyhow::anyhow!("Failed to set output type after stream change: {}", e)
+                })?;
+            debug!("MF encoder: output type renegotiated after stream change");
+            Ok(())
+        }
+    }
+
     /// 出力メディアタイプからcodec config (SPS/PPS) を取得（best-effort）
     /// 戻り値: (SPS NAL, PPS NAL) - 取得できない場合はNone
     pub fn get_codec_config(&self) -> Option<(Vec<u8>, Vec<u8>)> {
@@ -509,12 +682,17 @@ impl H264Encoder {
                 Ok(_) if blob_len > 0 && blob_len <= blob_data.len() as u32 => {
                     blob_data.truncate(blob_len as usize);
 
-                    // AVCDecoderConfigurationRecordを解析
-                    if let Some((sps, pps)) = parse_avc_decoder_config(&blob_data) {
+                    // 通常はAVCDecoderConfigurationRecordだが、Annex-BのSPS/PPSを返すMFTもある
+                    let sps_pps = if nal::is_annexb(&blob_data) {
+                        nal::find_sps_pps(&blob_data)
+                    } else {
+                        nal::parse_avc_decoder_config(&blob_data)
+                    };
+                    if let Some((sps, pps)) = sps_pps {
                         debug!("MF encoder: extracted SPS/PPS from codec config (SPS: {} bytes, PPS: {} bytes)", sps.len(), pps.len());
                         return Some((sps, pps));
                     } else {
-                        debug!("MF encoder: failed to parse AVCDecoderConfigurationRecord");
+                        debug!("MF encoder: failed to extract SPS/PPS from codec config");
                     }
                 }
                 Err(e) => {
@@ -534,87 +712,31 @@ impl H264Encoder {
     }
 }
 
-/// AVCDecoderConfigurationRecord (avcC) を解析してSPS/PPSを抽出
-/// フォーマット: ISO/IEC 14496-15 Annex E
-fn parse_avc_decoder_config(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
-    if data.len() < 7 {
-        return None;
-    }
-
-    // avcC構造:
-    // [0] configurationVersion (1 byte) = 1
-    // [1] AVCProfileIndication (1 byte)
-    // [2] profile_compatibility (1 byte)
-    // [3] AVCLevelIndication (1 byte)
-    // [4] lengthSizeMinusOne (1 byte, lower 2 bits) - NAL長のバイト数 - 1
-    // [5] numOfSequenceParameterSets (1 byte, lower 5 bits)
-    // [6+] SPS/PPSデータ
-
-    if data[0] != 1 {
-        debug!("MF encoder: invalid configurationVersion in avcC");
-        return None;
-    }
-
-    let num_sps = (data[5] & 0x1F) as usize;
-    let mut offset = 6;
-
-    // SPSを取得
-    let mut sps: Option<Vec<u8>> = None;
-    for i in 0..num_sps {
-        if offset + 2 > data.len() {
-            debug!("MF encoder: invalid SPS length in avcC");
-            return None;
-        }
-        let sps_len = ((data[offset] as usize) << 8) | (data[offset + 1] as usize);
-        offset += 2;
-
-        if offset + sps_len > data.len() {
-            debug!("MF encoder: SPS data out of bounds in avcC");
-            return None;
-        }
-
-        if i == 0 {
-            // 最初のSPSを使用
-            sps = Some(data[offset..offset + sps_len].to_vec());
+impl Drop for H264Encoder {
+    /// MFT を Shutdown して D3D マネージャーを手放す（作り直しのたびに GPU リソースが残らないように）
+    fn drop(&mut self) {
+        unsafe {
+            shutdown_transform(&self.transform, self.d3d_resources.is_some());
         }
-        offset += sps_len;
     }
+}
 
-    // PPSを取得
-    if offset >= data.len() {
-        debug!("MF encoder: no PPS data in avcC");
-        return None;
+/// ICodecAPI のプロパティを設定し、GetValue で読み戻して反映されたか確認する（ベストエフォート）
+unsafe fn set_codec_value(codec_api: &ICodecAPI, api: &GUID, name: &str, value: VARIANT) {
+    if codec_api.IsSupported(api).is_err() {
+        debug!("MF encoder: {} is not supported", name);
+        return;
     }
-
-    let num_pps = data[offset] as usize;
-    offset += 1;
-
-    let mut pps: Option<Vec<u8>> = None;
-    for i in 0..num_pps {
-        if offset + 2 > data.len() {
-            debug!("MF encoder: invalid PPS length in avcC");
-            return None;
-        }
-        let pps_len = ((data[offset] as usize) << 8) | (data[offset + 1] as usize);
-        offset += 2;
-
-        if offset + pps_len > data.len() {
-            debug!("MF encoder: PPS data out of bounds in avcC");
-            return None;
-        }
-
-        if i == 0 {
-            // 最初のPPSを使用
-            pps = Some(data[offset..offset + pps_len].to_vec());
-        }
-        offset += pps_len;
+    if let Err(e) = codec_api.SetValue(api, &value) {
+        warn!("MF encoder: failed to set {}: {}", name, e);
+        return;
     }
-
-    match (sps, pps) {
-        (Some(s), Some(p)) => Some((s, p)),
-        _ => {
-            debug!("MF encoder: failed to extract both SPS and PPS from avcC");
-            None
-        }
+    match codec_api.GetValue(api) {
+        Ok(actual) if actual == value => debug!("MF encoder: {} = {}", name, actual),
+        Ok(actual) => warn!(
+            "MF encoder: {} did not stick (requested {}, actual {})",
+            name, value, actual
+        ),
+        Err(e) => debug!("MF encoder: {} could not be read back: {}", name, e),
     }
 }
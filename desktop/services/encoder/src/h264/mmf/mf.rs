@@ -1,16 +1,49 @@
 use anyhow::{Context, Result};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
-use tracing::warn;
-use windows::core::Array;
+use tracing::{debug, info, warn};
+use windows::core::{Array, Interface};
+use windows::Win32::Foundation::{RPC_E_CHANGED_MODE, S_FALSE};
 use windows::Win32::Media::MediaFoundation::{
-    IMFActivate, IMFTransform, MFMediaType_Video, MFStartup, MFTEnumEx, MFVideoFormat_ARGB32,
-    MFVideoFormat_H264, MFVideoFormat_NV12, MFSTARTUP_FULL, MFT_CATEGORY_VIDEO_ENCODER,
-    MFT_ENUM_FLAG, MFT_ENUM_FLAG_ASYNCMFT, MFT_ENUM_FLAG_HARDWARE, MFT_REGISTER_TYPE_INFO,
+    IMFActivate, IMFMediaType, IMFShutdown, IMFTransform, MFMediaType_Video, MFNominalRange_0_255,
+    MFNominalRange_16_235, MFShutdown, MFStartup, MFTEnumEx, MFT_FRIENDLY_NAME_Attribute,
+    MFVideoFormat_ARGB32, MFVideoFormat_H264, MFVideoFormat_NV12, MFVideoTransferMatrix_BT601,
+    MFVideoTransferMatrix_BT709, MFSTARTUP_FULL, MFT_CATEGORY_VIDEO_ENCODER, MFT_ENUM_FLAG,
+    MFT_ENUM_FLAG_ASYNCMFT, MFT_ENUM_FLAG_HARDWARE, MFT_MESSAGE_COMMAND_FLUSH,
+    MFT_MESSAGE_NOTIFY_END_STREAMING, MFT_MESSAGE_SET_D3D_MANAGER, MFT_REGISTER_TYPE_INFO,
+    MF_MT_VIDEO_NOMINAL_RANGE, MF_MT_YUV_MATRIX, MF_VERSION,
 };
+use windows::Win32::System::Com::{CoInitializeEx, CoUninitialize, COINIT_MULTITHREADED};
+
+use crate::h264::color::{ColorMatrix, ColorRange, ColorSpace};
 
 // Media Foundationの初期化状態を管理（スレッドセーフ）
 static MF_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
+/// MF_MT_FRAME_RATE の値（上位32bit: 分子, 下位32bit: 分母）を作成
+pub fn pack_frame_rate(fps: u32) -> u64 {
+    ((fps.max(1) as u64) << 32) | 1u64
+}
+
+/// メディアタイプに YUV 行列とレンジを設定する
+/// （Video Processor はこれに従って変換し、エンコーダーは VUI に書き込む）
+pub unsafe fn set_color_attributes(
+    media_type: &IMFMediaType,
+    color: ColorSpace,
+) -> windows::core::Result<()> {
+    let matrix = match color.matrix {
+        ColorMatrix::Bt601 => MFVideoTransferMatrix_BT601,
+        ColorMatrix::Bt709 => MFVideoTransferMatrix_BT709,
+    };
+    let range = match color.range {
+        ColorRange::Limited => MFNominalRange_16_235,
+        ColorRange::Full => MFNominalRange_0_255,
+    };
+    media_type.SetUINT32(&MF_MT_YUV_MATRIX, matrix.0 as u32)?;
+    media_type.SetUINT32(&MF_MT_VIDEO_NOMINAL_RANGE, range.0 as u32)?;
+    Ok(())
+}
+
 /// Media Foundationを初期化（スレッドセーフ）
 pub fn init_media_foundation() -> bool {
     if MF_INITIALIZED.load(Ordering::Acquire) {
@@ -18,7 +51,7 @@ pub fn init_media_foundation() -> bool {
     }
 
     unsafe {
-        match MFStartup(MFSTARTUP_FULL, 0) {
+        match MFStartup(MF_VERSION, MFSTARTUP_FULL) {
             Ok(_) => {
                 MF_INITIALIZED.store(true, Ordering::Release);
                 true
@@ -31,6 +64,67 @@ pub fn init_media_foundation() -> bool {
     }
 }
 
+/// ワーカースレッドでの COM（MTA）と Media Foundation の初期化
+///
+/// D3D11 と非同期 MFT はスレッドのアパートメントに依存し、初期化していないスレッドや
+/// STA のスレッドで作ると環境によってイベントの取得や D3D マネージャーの設定が失敗する。
+/// ワーカースレッドの先頭で作り、D3D/MF のオブジェクトをすべて解放した後に drop する（逆順に解放する）。
+pub struct MfThreadInit {
+    /// CoInitializeEx が成功した（S_FALSE を含む。drop で CoUninitialize する）
+    com_initialized: bool,
+    /// このスレッドで MFStartup した（drop で MFShutdown する）
+    mf_started: bool,
+}
+
+impl MfThreadInit {
+    /// 現在のスレッドで COM を MTA で初期化し、MFStartup する（`thread_name` はログ用）
+    pub fn new(thread_name: &str) -> Result<Self> {
+        let hr = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+        let com_initialized = hr.is_ok();
+        if hr == RPC_E_CHANGED_MODE {
+            // 既に STA で初期化されたスレッド。MTA にはできないのでそのまま続ける
+            warn!(
+                "{}: thread is already in a single-threaded COM apartment, continuing without MTA",
+                thread_name
+            );
+        } else if let Err(e) = hr.ok() {
+            return Err(e).with_context(|| format!("{}: CoInitializeEx failed", thread_name));
+        } else if hr == S_FALSE {
+            info!(
+                "{}: COM was already initialized as MTA on this thread",
+                thread_name
+            );
+        } else {
+            info!("{}: COM initialized (MTA)", thread_name);
+        }
+
+        let mut init = Self {
+            com_initialized,
+            mf_started: false,
+        };
+        unsafe { MFStartup(MF_VERSION, MFSTARTUP_FULL) }
+            .with_context(|| format!("{}: MFStartup failed", thread_name))?;
+        init.mf_started = true;
+        info!("{}: Media Foundation started", thread_name);
+        Ok(init)
+    }
+}
+
+impl Drop for MfThreadInit {
+    fn drop(&mut self) {
+        unsafe {
+            if self.mf_started {
+                if let Err(e) = MFShutdown() {
+                    warn!("MFShutdown failed: {}", e);
+                }
+            }
+            if self.com_initialized {
+                CoUninitialize();
+            }
+        }
+    }
+}
+
 fn enumerate_mfts(
     category: &windows::core::GUID,
     flags: MFT_ENUM_FLAG,
@@ -61,8 +155,64 @@ fn enumerate_mfts(
     Ok(transform_sources)
 }
 
-/// 非同期ハードウェア H.264 エンコーダー MFT を検索
-pub unsafe fn find_async_h264_encoder() -> Result<IMFTransform> {
+/// 複数のハードウェアエンコーダーから使うものを選ぶ指定
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncoderDeviceSelector {
+    /// 列挙順のインデックス
+    Index(usize),
+    /// フレンドリー名の部分一致（大文字小文字を区別しない）
+    Name(String),
+}
+
+impl FromStr for EncoderDeviceSelector {
+    type Err = std::convert::Infallible;
+
+    /// 数値ならインデックス、それ以外は名前の部分一致として扱う
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.trim().parse::<usize>() {
+            Ok(index) => Self::Index(index),
+            Err(_) => Self::Name(s.trim().to_string()),
+        })
+    }
+}
+
+/// エンコーダーの遅延と画質のトレードオフ
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncoderLatencyMode {
+    /// 対話的なストリーミング向け（B フレームなし、低遅延モード）
+    #[default]
+    LowLatency,
+    /// 録画など非対話用途向け（B フレームを許可）
+    Quality,
+}
+
+impl FromStr for EncoderLatencyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low-latency" | "low_latency" | "lowlatency" => Ok(Self::LowLatency),
+            "quality" => Ok(Self::Quality),
+            other => Err(format!("unsupported encoder mode: {}", other)),
+        }
+    }
+}
+
+/// 指定に一致するエンコーダーのインデックスを返す（一致しなければ None）
+pub fn select_encoder_index(names: &[String], selector: &EncoderDeviceSelector) -> Option<usize> {
+    match selector {
+        EncoderDeviceSelector::Index(index) => (*index < names.len()).then_some(*index),
+        EncoderDeviceSelector::Name(pattern) => {
+            let pattern = pattern.to_lowercase();
+            names
+                .iter()
+                .position(|name| name.to_lowercase().contains(&pattern))
+        }
+    }
+}
+
+/// 非同期ハードウェア H.264 エンコーダー MFT を列挙
+unsafe fn enumerate_async_h264_encoders() -> Result<Vec<IMFActivate>> {
     let input_type = MFT_REGISTER_TYPE_INFO {
         guidMajorType: MFMediaType_Video,
         guidSubtype: MFVideoFormat_NV12,
@@ -76,21 +226,74 @@ pub unsafe fn find_async_h264_encoder() -> Result<IMFTransform> {
     // 非同期ハードウェアエンコーダーを検索
     // 参考実装に合わせて SORTANDFILTER フラグを追加（より安定した選択のため）
     // 注意: windows-rs に SORTANDFILTER が定義されていない場合は、ビット値 0x00000001 を使用
-    let mfactivate_list = enumerate_mfts(
+    enumerate_mfts(
         &MFT_CATEGORY_VIDEO_ENCODER, // guidCategory
         MFT_ENUM_FLAG(MFT_ENUM_FLAG_HARDWARE.0 | MFT_ENUM_FLAG_ASYNCMFT.0 | 0x00000001), // SORTANDFILTER
         Some(&input_type),
         Some(&output_type),
-    )?;
+    )
+}
+
+/// MFT のフレンドリー名を取得（取得できない場合は空文字）
+unsafe fn mft_friendly_name(activate: &IMFActivate) -> String {
+    let Ok(len) = activate.GetStringLength(&MFT_FRIENDLY_NAME_Attribute) else {
+        return String::new();
+    };
+    let mut buf = vec![0u16; len as usize + 1];
+    if activate
+        .GetString(&MFT_FRIENDLY_NAME_Attribute, &mut buf, None)
+        .is_err()
+    {
+        return String::new();
+    }
+    String::from_utf16_lossy(&buf[..len as usize])
+}
+
+/// 利用可能なハードウェア H.264 エンコーダーのフレンドリー名を列挙順に返す
+pub fn list_h264_encoders() -> Result<Vec<String>> {
+    if !init_media_foundation() {
+        return Err(anyhow::anyhow!("Media Foundation is not available"));
+    }
+    unsafe {
+        let mfactivate_list = enumerate_async_h264_encoders()?;
+        Ok(mfactivate_list
+            .iter()
+            .map(|activate| mft_friendly_name(activate))
+            .collect())
+    }
+}
+
+/// 非同期ハードウェア H.264 エンコーダー MFT を検索
+/// `device` が指定されていればそれに一致するものを、なければ最初のものを使う
+pub unsafe fn find_async_h264_encoder(
+    device: Option<&EncoderDeviceSelector>,
+) -> Result<IMFTransform> {
+    let mfactivate_list = enumerate_async_h264_encoders()?;
 
     if mfactivate_list.is_empty() {
         return Err(anyhow::anyhow!("No async H.264 encoder MFT found"));
     }
 
-    // 最初のMFTをアクティベート
-    let activate = mfactivate_list
-        .first()
-        .ok_or_else(|| anyhow::anyhow!("No async H.264 encoder MFT found"))?;
+    let names: Vec<String> = mfactivate_list
+        .iter()
+        .map(|activate| mft_friendly_name(activate))
+        .collect();
+    let index = match device {
+        Some(selector) => select_encoder_index(&names, selector).unwrap_or_else(|| {
+            warn!(
+                "No H.264 encoder MFT matches {:?} (available: {:?}), falling back to the first one",
+                selector, names
+            );
+            0
+        }),
+        None => 0,
+    };
+    info!(
+        "Using H.264 encoder MFT #{}: {} (available: {:?})",
+        index, names[index], names
+    );
+
+    let activate = &mfactivate_list[index];
 
     let transform: IMFTransform = activate
         .ActivateObject()
@@ -147,6 +350,31 @@ pub unsafe fn find_video_processor() -> Result<IMFTransform> {
     Ok(transform)
 }
 
+/// 破棄する MFT の後始末（Flush → EndStreaming → D3D マネージャーの解除 → Shutdown）
+///
+/// D3D マネージャーを設定したまま MFT を手放すと、MFT 内部のワーカースレッドやサーフェスプールが
+/// D3D11 デバイスを握り続け、解像度変更のたびに作り直すと GPU メモリが解放されない。
+/// IMFShutdown を実装していない MFT（同期 MFT）では Shutdown を省く。
+/// Drop から呼ぶため失敗はログに残すだけにする
+pub unsafe fn shutdown_transform(transform: &IMFTransform, uses_d3d_manager: bool) {
+    if let Err(e) = transform.ProcessMessage(MFT_MESSAGE_COMMAND_FLUSH, 0) {
+        debug!("MFT teardown: flush failed: {:?}", e);
+    }
+    if let Err(e) = transform.ProcessMessage(MFT_MESSAGE_NOTIFY_END_STREAMING, 0) {
+        debug!("MFT teardown: end streaming failed: {:?}", e);
+    }
+    if uses_d3d_manager {
+        if let Err(e) = transform.ProcessMessage(MFT_MESSAGE_SET_D3D_MANAGER, 0) {
+            debug!("MFT teardown: failed to detach D3D manager: {:?}", e);
+        }
+    }
+    if let Ok(shutdown) = transform.cast::<IMFShutdown>() {
+        if let Err(e) = shutdown.Shutdown() {
+            debug!("MFT teardown: shutdown failed: {:?}", e);
+        }
+    }
+}
+
 /// H.264エンコーダーMFTが存在するか確認（検索のみ）
 pub unsafe fn find_h264_encoder() -> Result<()> {
     let input_type = MFT_REGISTER_TYPE_INFO {
@@ -19,26 +19,95 @@ use tokio::sync::mpsc as tokio_mpsc;
 use tracing::{info, warn};
 
 #[cfg(windows)]
-use self::mf::check_mf_available;
+use self::mf::{check_mf_available, EncoderDeviceSelector, EncoderLatencyMode};
+#[cfg(windows)]
+use crate::h264::color::ColorSpace;
+#[cfg(windows)]
+use crate::h264::worker_thread::WorkerThreadConfig;
 
 /// Media Foundation H.264 エンコーダーファクトリ
 /// 利用可能でない場合はOpenH264にフォールバック
+/// `force_software` を指定すると Media Foundation を確認せずに OpenH264 を使う
+/// （特定のドライバーでハードウェア MFT が乱れた映像を出す・失敗する場合の回避策）
 #[cfg(windows)]
 pub struct MediaFoundationH264EncoderFactory {
     use_mf: bool,
+    encoder_device: Option<EncoderDeviceSelector>,
+    latency_mode: EncoderLatencyMode,
+    color: ColorSpace,
+    gpu_input: bool,
+    worker_thread: WorkerThreadConfig,
+    software_threads: Option<u16>,
+    capture_time_sei: bool,
 }
 
 #[cfg(windows)]
 impl MediaFoundationH264EncoderFactory {
-    pub fn new() -> Self {
+    pub fn new(force_software: bool) -> Self {
         // Media Foundationが利用可能かチェック
-        let use_mf = check_mf_available();
-        if use_mf {
+        let use_mf = !force_software && check_mf_available();
+        if force_software {
+            info!("Software H.264 encoding forced, using OpenH264 instead of Media Foundation");
+        } else if use_mf {
             info!("Media Foundation H.264 encoder is available, using MF encoder");
         } else {
             warn!("Media Foundation H.264 encoder is not available, will fallback to OpenH264");
         }
-        Self { use_mf }
+        Self {
+            use_mf,
+            encoder_device: None,
+            latency_mode: EncoderLatencyMode::default(),
+            color: ColorSpace::default(),
+            gpu_input: true,
+            worker_thread: WorkerThreadConfig::default(),
+            software_threads: None,
+            capture_time_sei: false,
+        }
+    }
+
+    /// 使用するハードウェアエンコーダーを指定（見つからない場合は最初のものにフォールバック）
+    pub fn with_encoder_device(mut self, encoder_device: EncoderDeviceSelector) -> Self {
+        self.encoder_device = Some(encoder_device);
+        self
+    }
+
+    /// 低遅延モード（B フレームなし）か画質モード（B フレームあり）かを指定
+    pub fn with_latency_mode(mut self, latency_mode: EncoderLatencyMode) -> Self {
+        self.latency_mode = latency_mode;
+        self
+    }
+
+    /// NV12 変換とストリームのタグに使う色空間を指定（デフォルトは BT.709 リミテッド）
+    pub fn with_color_space(mut self, color: ColorSpace) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// false にすると D3D11 の前処理を使わず CPU で NV12 に変換する
+    /// （true でも GPU 経路が作れない場合は自動で CPU 変換にフォールバックする）
+    pub fn with_gpu_input(mut self, gpu_input: bool) -> Self {
+        self.gpu_input = gpu_input;
+        self
+    }
+
+    /// エンコードワーカースレッドのコア固定と優先度を指定（OpenH264 へのフォールバック時にも適用する）
+    pub fn with_worker_thread(mut self, worker_thread: WorkerThreadConfig) -> Self {
+        self.worker_thread = worker_thread;
+        self
+    }
+
+    /// OpenH264 で（フォールバック時を含む）エンコードするときの内部スレッド数を指定
+    /// （未指定なら CPU コア数。1 にするとワーカースレッドだけでエンコードする）
+    pub fn with_software_threads(mut self, num_threads: u16) -> Self {
+        self.software_threads = Some(num_threads.max(1));
+        self
+    }
+
+    /// 各フレームの最初のスライスの前にキャプチャ時刻の SEI を入れる
+    /// （クライアントがビットストリームからキャプチャ時刻を取り出して遅延を測れる。フレームごとに 30 バイトほど増える）
+    pub fn with_capture_time_sei(mut self, enabled: bool) -> Self {
+        self.capture_time_sei = enabled;
+        self
     }
 
     pub fn use_media_foundation(&self) -> bool {
@@ -55,10 +124,22 @@ impl VideoEncoderFactory for MediaFoundationH264EncoderFactory {
         tokio_mpsc::UnboundedReceiver<EncodeResult>,
     ) {
         if self.use_mf {
-            pipeline::start_mf_encode_workers()
+            pipeline::start_mf_encode_workers(
+                self.encoder_device.clone(),
+                self.latency_mode,
+                self.color,
+                self.gpu_input,
+                self.worker_thread,
+                self.capture_time_sei,
+            )
         } else {
             // OpenH264にフォールバック
-            crate::h264::openh264::start_encode_workers()
+            crate::h264::openh264::start_encode_workers(crate::h264::openh264::OpenH264Config {
+                num_threads: self.software_threads,
+                worker_thread: self.worker_thread,
+                capture_time_sei: self.capture_time_sei,
+                ..Default::default()
+            })
         }
     }
 
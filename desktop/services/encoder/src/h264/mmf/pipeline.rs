@@ -1,4 +1,8 @@
-use core_types::{EncodeJobSlot, EncodeResult, ShutdownError};
+use anyhow::{Context, Result};
+use core_types::{
+    AspectMode, EncodeJob, EncodeJobSlot, EncodeResult, KeyframeReason, LogThrottle, PixelFormat,
+    ShutdownError, H264_MAX_QP,
+};
 use std::collections::VecDeque;
 use std::mem::ManuallyDrop;
 use std::sync::Arc;
@@ -8,168 +12,373 @@ use tracing::{debug, info, warn};
 use windows::core::Interface;
 use windows::Win32::Graphics::Direct3D11::ID3D11Texture2D;
 use windows::Win32::Media::MediaFoundation::{
-    METransformHaveOutput, METransformNeedInput, MFCreateDXGISurfaceBuffer, MFCreateSample,
-    MFSampleExtension_CleanPoint, MFSampleExtension_VideoEncodePictureType, MFT_OUTPUT_DATA_BUFFER,
-    MF_EVENT_FLAG_NONE, MF_EVENT_TYPE, MF_E_TRANSFORM_NEED_MORE_INPUT,
+    IMFMediaBuffer, METransformHaveOutput, METransformNeedInput, MFCreateDXGISurfaceBuffer,
+    MFCreateMemoryBuffer, MFCreateSample, MFSampleExtension_CleanPoint,
+    MFSampleExtension_VideoEncodePictureType, MFSampleExtension_VideoEncodeQP,
+    MFT_OUTPUT_DATA_BUFFER, MF_EVENT_FLAG_NONE, MF_EVENT_TYPE, MF_E_TRANSFORM_NEED_MORE_INPUT,
     MF_E_TRANSFORM_STREAM_CHANGE,
 };
 
+use crate::h264::color::{ColorMatrix, ColorRange, ColorSpace};
 use crate::h264::mmf::d3d::D3D11Resources;
 use crate::h264::mmf::encoder::H264Encoder;
+use crate::h264::mmf::mf::{EncoderDeviceSelector, EncoderLatencyMode, MfThreadInit};
 use crate::h264::mmf::preprocessor::VideoProcessorPreprocessor;
+use crate::h264::worker_thread::WorkerThreadConfig;
+use crate::h264::{nal, rgba_to_yuv};
 
-/// H.264データがAnnex-B形式（スタートコード）かどうかを判定
-fn is_annexb_format(data: &[u8]) -> bool {
-    if data.len() < 4 {
-        return false;
+/// H.264データをAnnex-B形式に変換（Annex-B / AVCC を自動判定）
+/// 戻り値: (Annex-B形式のデータ, SPS/PPSが含まれているか)
+pub(super) fn annexb_from_mf_data(data: &[u8]) -> (Vec<u8>, bool) {
+    if !nal::is_annexb(data) {
+        debug!("MF encoder: detected AVCC format, converting to Annex-B");
     }
-    // 4バイトスタートコード (00 00 00 01)
-    if data[0] == 0x00 && data[1] == 0x00 && data[2] == 0x00 && data[3] == 0x01 {
-        return true;
+    let annexb = nal::to_annexb(data);
+    if annexb.is_empty() && !data.is_empty() {
+        warn!(
+            "MF encoder: no valid NAL units in encoded data ({} bytes)",
+            data.len()
+        );
     }
-    // 3バイトスタートコード (00 00 01)
-    if data.len() >= 3 && data[0] == 0x00 && data[1] == 0x00 && data[2] == 0x01 {
-        return true;
+    let has_sps_pps = nal::contains_sps_pps(&annexb);
+    if has_sps_pps {
+        debug!("MF encoder: found SPS/PPS in encoded data");
     }
-    false
+    (annexb, has_sps_pps)
 }
 
-/// H.264データをAnnex-B形式に変換（フォーマット自動判定）
-/// 戻り値: (Annex-B形式のデータ, SPS/PPSが含まれているか)
-fn annexb_from_mf_data(data: &[u8]) -> (Vec<u8>, bool) {
-    const START_CODE: &[u8] = &[0x00, 0x00, 0x00, 0x01];
-    let mut result = Vec::new();
-    let mut has_sps_pps = false;
-
-    // 既にAnnex-B形式の場合はそのまま返す
-    if is_annexb_format(data) {
-        // Annex-B形式のまま処理（NALユニットを分割してSPS/PPSを検出）
-        let mut i = 0;
-        while i < data.len() {
-            // スタートコードを探す
-            let start_code_len = if i + 4 <= data.len()
-                && data[i] == 0x00
-                && data[i + 1] == 0x00
-                && data[i + 2] == 0x00
-                && data[i + 3] == 0x01
-            {
-                4
-            } else if i + 3 <= data.len()
-                && data[i] == 0x00
-                && data[i + 1] == 0x00
-                && data[i + 2] == 0x01
-            {
-                3
-            } else {
-                // スタートコードが見つからない場合は残りをコピーして終了
-                if i < data.len() {
-                    result.extend_from_slice(&data[i..]);
-                }
-                break;
-            };
-
-            // 次のスタートコードを探す
-            let mut next_start = None;
-            let mut search_pos = i + start_code_len;
-            while search_pos + 3 <= data.len() {
-                if search_pos + 4 <= data.len()
-                    && data[search_pos] == 0x00
-                    && data[search_pos + 1] == 0x00
-                    && data[search_pos + 2] == 0x00
-                    && data[search_pos + 3] == 0x01
-                {
-                    next_start = Some((search_pos, 4));
-                    break;
-                } else if data[search_pos] == 0x00
-                    && data[search_pos + 1] == 0x00
-                    && data[search_pos + 2] == 0x01
-                {
-                    next_start = Some((search_pos, 3));
-                    break;
-                }
-                search_pos += 1;
-            }
+/// in-bandにSPS/PPSが無いキーフレームの先頭に、codec configから取得したSPS/PPSを注入
+///
+/// 途中から参加した視聴者が最初に受け取るキーフレームでもデコードを開始できるよう、
+/// 最初の1回だけでなく全てのキーフレームに対して行う。既に含まれている場合は何もしない。
+pub(super) fn inject_sps_pps_if_missing(
+    sample_data: Vec<u8>,
+    has_sps_pps_in_data: bool,
+    is_keyframe: bool,
+    codec_config_sps_pps: Option<&(Vec<u8>, Vec<u8>)>,
+) -> Vec<u8> {
+    const START_CODE: &[u8] = &nal::START_CODE;
+
+    if has_sps_pps_in_data || !is_keyframe {
+        return sample_data;
+    }
+    let Some((sps, pps)) = codec_config_sps_pps else {
+        return sample_data;
+    };
+
+    debug!(
+        "MF encoder: injecting SPS/PPS from codec config (SPS: {} bytes, PPS: {} bytes)",
+        sps.len(),
+        pps.len()
+    );
+    let mut injected_data = Vec::with_capacity(
+        START_CODE.len() + sps.len() + START_CODE.len() + pps.len() + sample_data.len(),
+    );
+    injected_data.extend_from_slice(START_CODE);
+    injected_data.extend_from_slice(sps);
+    injected_data.extend_from_slice(START_CODE);
+    injected_data.extend_from_slice(pps);
+    injected_data.extend_from_slice(&sample_data);
+    injected_data
+}
 
-            let nal_end = next_start.unwrap_or((data.len(), 0)).0;
-            let nal_unit = &data[i..nal_end];
-
-            // NALユニットのタイプを確認（SPS/PPS判定）
-            if nal_unit.len() > start_code_len {
-                let nal_header = nal_unit[start_code_len];
-                let nal_type = nal_header & 0x1F;
-                if nal_type == 7 || nal_type == 8 {
-                    has_sps_pps = true;
-                    debug!(
-                        "MF encoder: found SPS/PPS in Annex-B data (type={})",
-                        nal_type
-                    );
-                }
-            }
+/// MFSampleExtension_VideoEncodeQP の値から QP を取り出す（下位 16 ビットが H.264 の QP）
+/// 範囲外の値を返すエンコーダーは報告しないものとして扱う
+pub(super) fn qp_from_attribute(value: u64) -> Option<u8> {
+    u8::try_from(value & 0xFFFF)
+        .ok()
+        .filter(|qp| *qp <= H264_MAX_QP)
+}
+
+/// 入力フレームのメタ情報（出力と対応付けるため）
+struct InputFrameMeta {
+    duration: Duration,
+    width: u32,
+    height: u32,
+    /// このフレームでキーフレームを強制した理由
+    keyframe_reason: Option<KeyframeReason>,
+    capture_timestamp: u64,
+    sequence: u64,
+}
 
-            result.extend_from_slice(nal_unit);
-            i = nal_end;
+/// D3D デバイス喪失から作り直すときの試行回数
+const DEVICE_RECOVERY_ATTEMPTS: u32 = 3;
+/// 作り直しに失敗したときの待ち時間（ドライバー更新やドッキングの完了待ち）
+const DEVICE_RECOVERY_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// GPU 入力経路の前処理がこの回数続けて失敗したら CPU 変換経路に切り替える
+const GPU_INPUT_FAILURE_LIMIT: u32 = 3;
+
+/// エンコードセッションの作成パラメータ
+struct SessionConfig {
+    width: u32,
+    height: u32,
+    fps: u32,
+    encoder_device: Option<EncoderDeviceSelector>,
+    latency_mode: EncoderLatencyMode,
+    color: ColorSpace,
+    /// false の場合は GPU 経路を試さず CPU 変換経路を使う
+    gpu_input: bool,
+}
+
+/// CPU 変換経路の色空間（libyuv の ABGRToNV12 / ARGBToNV12 は BT.601 リミテッド固定）
+const CPU_INPUT_COLOR: ColorSpace = ColorSpace {
+    matrix: ColorMatrix::Bt601,
+    range: ColorRange::Limited,
+};
+
+/// エンコーダーへ渡す NV12 入力の作り方
+enum InputPath {
+    /// D3D11 の Video Processor で NV12 テクスチャを作り、DXGI サーフェスとして渡す
+    Gpu {
+        d3d_resources: D3D11Resources,
+        preprocessor: VideoProcessorPreprocessor,
+    },
+    /// libyuv で NV12 に変換し、システムメモリのバッファとして渡す（GPU 経路が使えない環境向け）
+    Cpu,
+}
+
+impl InputPath {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Gpu { .. } => "GPU (D3D11 Video Processor)",
+            Self::Cpu => "CPU (libyuv NV12 + memory buffer)",
         }
+    }
 
-        return (result, has_sps_pps);
+    fn is_gpu(&self) -> bool {
+        matches!(self, Self::Gpu { .. })
     }
 
-    // AVCC形式（NAL長プレフィックス）として処理
-    debug!("MF encoder: detected AVCC format, converting to Annex-B");
-    let mut i = 0;
-    while i < data.len() {
-        if i + 4 <= data.len() {
-            // NAL長を読み取る（ビッグエンディアン）
-            let nal_length =
-                u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]) as usize;
-
-            i += 4;
-
-            if i + nal_length <= data.len() && nal_length > 0 {
-                let nal_unit = &data[i..i + nal_length];
-
-                // NALユニットのタイプを確認（SPS/PPS判定）
-                if nal_unit.len() > 0 {
-                    let nal_type = nal_unit[0] & 0x1F;
-                    if nal_type == 7 || nal_type == 8 {
-                        has_sps_pps = true;
-                        debug!("MF encoder: found SPS/PPS in AVCC data (type={})", nal_type);
-                    }
-                }
+    /// D3D11 デバイスが失われていればその理由を返す（CPU 経路では常に None）
+    fn device_removed_reason(&self) -> Option<windows::core::Error> {
+        match self {
+            Self::Gpu { d3d_resources, .. } => d3d_resources.device_removed_reason(),
+            Self::Cpu => None,
+        }
+    }
 
-                // スタートコードを追加
-                result.extend_from_slice(START_CODE);
-                result.extend_from_slice(nal_unit);
+    /// フレームを `width` x `height` の NV12 にし、エンコーダーの入力バッファを作る
+    /// GPU での縮小を前提に元のサイズで届いたフレームは、GPU 入力なら Video Processor で、
+    /// CPU 入力なら変換の前に CPU で縮小する
+    unsafe fn create_input_buffer(
+        &mut self,
+        job: &mut EncodeJob,
+        width: u32,
+        height: u32,
+        timestamp: i64,
+    ) -> Result<IMFMediaBuffer> {
+        match self {
+            Self::Gpu { preprocessor, .. } => {
+                let aspect = job
+                    .scale_to
+                    .map_or(AspectMode::Stretch, |target| target.aspect);
+                let nv12_texture = preprocessor
+                    .process(
+                        &job.rgba, job.format, job.width, job.height, aspect, timestamp,
+                    )
+                    .context("preprocess failed")?;
+                MFCreateDXGISurfaceBuffer(&ID3D11Texture2D::IID, &nv12_texture, 0, false)
+                    .context("failed to create DXGI surface buffer")
+            }
+            Self::Cpu => {
+                job.scale_on_cpu();
+                anyhow::ensure!(
+                    job.width >= width && job.height >= height,
+                    "frame {}x{} is smaller than the encoder input {}x{}",
+                    job.width,
+                    job.height,
+                    width,
+                    height
+                );
+                let (w, h, src_width) = (width as usize, height as usize, job.width as usize);
+                let nv12 = match job.format {
+                    PixelFormat::Rgba8 => rgba_to_yuv::rgba_to_nv12(&job.rgba, w, h, src_width),
+                    PixelFormat::Bgra8 => rgba_to_yuv::bgra_to_nv12(&job.rgba, w, h, src_width),
+                };
 
-                i += nal_length;
-            } else {
-                // 無効なNAL長の場合は残りをコピーして終了
-                if i < data.len() {
-                    warn!("MF encoder: invalid NAL length, copying remaining data");
-                    result.extend_from_slice(&data[i..]);
-                }
-                break;
+                let buffer = MFCreateMemoryBuffer(nv12.len() as u32)
+                    .context("failed to create memory buffer")?;
+                let mut data_ptr: *mut u8 = std::ptr::null_mut();
+                buffer
+                    .Lock(&mut data_ptr, None, None)
+                    .context("failed to lock memory buffer")?;
+                std::ptr::copy_nonoverlapping(nv12.as_ptr(), data_ptr, nv12.len());
+                buffer.Unlock().context("failed to unlock memory buffer")?;
+                buffer
+                    .SetCurrentLength(nv12.len() as u32)
+                    .context("failed to set memory buffer length")?;
+                Ok(buffer)
             }
+        }
+    }
+}
+
+/// 入力経路とエンコーダーの一式（デバイス喪失時はまとめて作り直す）
+struct MfSession {
+    /// エンコーダーは前処理器の出力テクスチャを読むため、前処理器より先に drop する（宣言順）
+    encoder: H264Encoder,
+    input: InputPath,
+    /// codec config から取得した SPS/PPS（ストリーム変更時に再取得する）
+    codec_config_sps_pps: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl MfSession {
+    /// 入力経路とエンコーダーを作成し、ストリーミングを開始する
+    /// GPU 経路（D3D11 デバイス・Video Processor）が作れない GPU では CPU 変換経路にフォールバックする
+    fn create(config: &SessionConfig) -> Result<Self> {
+        let gpu_session = if config.gpu_input {
+            Self::create_gpu(config)
         } else {
-            // データが不足している場合は残りをコピー
-            if i < data.len() {
-                result.extend_from_slice(&data[i..]);
+            Err(anyhow::anyhow!("GPU input path is disabled"))
+        };
+        let session = match gpu_session {
+            Ok(session) => session,
+            Err(e) => {
+                warn!(
+                    "MF encoder worker: GPU input path unavailable ({:#}), degraded mode: CPU NV12 conversion with the hardware encoder",
+                    e
+                );
+                Self::create_cpu(config)?
             }
-            break;
+        };
+        info!(
+            "MF encoder worker: using {} input path",
+            session.input.name()
+        );
+        Ok(session)
+    }
+
+    /// D3D11 デバイス・前処理器・エンコーダーを作成する
+    fn create_gpu(config: &SessionConfig) -> Result<Self> {
+        let d3d_resources = D3D11Resources::create().context("failed to create D3D11 resources")?;
+
+        let preprocessor = VideoProcessorPreprocessor::create(
+            d3d_resources.clone(),
+            config.width,
+            config.height,
+            config.fps,
+            config.color,
+        )
+        .context("failed to create preprocessor")?;
+
+        let encoder = H264Encoder::create(
+            Some(d3d_resources.clone()),
+            config.width,
+            config.height,
+            config.fps,
+            config.encoder_device.as_ref(),
+            config.latency_mode,
+            config.color,
+        )
+        .context("failed to create encoder")?;
+
+        Self::start(
+            InputPath::Gpu {
+                d3d_resources,
+                preprocessor,
+            },
+            encoder,
+        )
+    }
+
+    /// D3D マネージャーを使わないエンコーダーを作成する（入力はシステムメモリの NV12）
+    fn create_cpu(config: &SessionConfig) -> Result<Self> {
+        if config.color != CPU_INPUT_COLOR {
+            warn!(
+                "MF encoder worker: CPU input path converts with BT.601 limited, ignoring color space {:?}",
+                config.color
+            );
         }
+        let encoder = H264Encoder::create(
+            None,
+            config.width,
+            config.height,
+            config.fps,
+            config.encoder_device.as_ref(),
+            config.latency_mode,
+            CPU_INPUT_COLOR,
+        )
+        .context("failed to create encoder for CPU input")?;
+
+        Self::start(InputPath::Cpu, encoder)
     }
 
-    (result, has_sps_pps)
-}
+    fn start(input: InputPath, encoder: H264Encoder) -> Result<Self> {
+        // codec configからSPS/PPSを取得（best-effort、取得できない場合はNone）
+        let codec_config_sps_pps = encoder.get_codec_config();
+        if codec_config_sps_pps.is_some() {
+            info!("MF encoder worker: extracted SPS/PPS from codec config");
+        } else {
+            debug!("MF encoder worker: codec config not available, will rely on in-band SPS/PPS");
+        }
 
-/// 入力フレームのメタ情報（出力と対応付けるため）
-struct InputFrameMeta {
-    duration: Duration,
-    width: u32,
-    height: u32,
+        encoder
+            .start_streaming()
+            .context("failed to start streaming")?;
+
+        Ok(Self {
+            input,
+            encoder,
+            codec_config_sps_pps,
+        })
+    }
+
+    /// 前処理器（Video Processor）が動かなくなった場合に CPU 変換経路で作り直す
+    /// エンコーダーは D3D マネージャーなしで作り直し、ハードウェアエンコードは続ける。
+    /// 以降のデバイス喪失からの作り直しでも GPU 経路は試さない
+    fn fall_back_to_cpu(&mut self, config: &mut SessionConfig) -> Result<()> {
+        config.gpu_input = false;
+        *self = Self::create_cpu(config)?;
+        Ok(())
+    }
+
+    /// D3D11 デバイスが失われていれば（GPU 切り替え・ドライバー更新など）セッションを作り直す
+    /// 作り直した場合は true を返す。デバイスが生きている場合や作り直せなかった場合は false
+    fn recover_if_device_removed(&mut self, config: &SessionConfig) -> bool {
+        let Some(reason) = self.input.device_removed_reason() else {
+            return false;
+        };
+        warn!(
+            "MF encoder worker: D3D11 device removed ({:?}), rebuilding device, preprocessor and encoder",
+            reason.code()
+        );
+
+        for attempt in 1..=DEVICE_RECOVERY_ATTEMPTS {
+            match Self::create(config) {
+                Ok(session) => {
+                    *self = session;
+                    info!(
+                        "MF encoder worker: recovered from device removal (attempt {})",
+                        attempt
+                    );
+                    return true;
+                }
+                Err(e) => {
+                    warn!(
+                        "MF encoder worker: failed to rebuild after device removal (attempt {}/{}): {:#}",
+                        attempt, DEVICE_RECOVERY_ATTEMPTS, e
+                    );
+                    std::thread::sleep(DEVICE_RECOVERY_RETRY_INTERVAL);
+                }
+            }
+        }
+        false
+    }
 }
 
 /// Media Foundationエンコードワーカーを起動
-pub fn start_mf_encode_workers() -> (
+/// `encoder_device` でハードウェアエンコーダーを選択（None なら最初に列挙されたもの）
+/// `color` は NV12 変換に使う行列・レンジで、出力ストリームにもタグ付けされる
+/// `gpu_input` が false の場合は D3D11 の前処理を使わず CPU で NV12 に変換する
+/// `worker_thread` はワーカースレッドのコア固定と優先度
+pub fn start_mf_encode_workers(
+    encoder_device: Option<EncoderDeviceSelector>,
+    latency_mode: EncoderLatencyMode,
+    color: ColorSpace,
+    gpu_input: bool,
+    worker_thread: WorkerThreadConfig,
+    capture_time_sei: bool,
+) -> (
     Arc<EncodeJobSlot>,
     tokio_mpsc::UnboundedReceiver<EncodeResult>,
 ) {
@@ -177,9 +386,24 @@ pub fn start_mf_encode_workers() -> (
     let job_slot_clone = Arc::clone(&job_slot);
     let (res_tx, res_rx) = tokio_mpsc::unbounded_channel::<EncodeResult>();
 
-    std::thread::spawn(move || {
+    let worker = std::thread::spawn(move || {
+        worker_thread.apply_to_current_thread("MF encoder worker");
+        // D3D/MF のオブジェクトを作る前に、このスレッドのアパートメントを決めておく
+        // （後に宣言したセッションなどが先に drop され、最後に MFShutdown・CoUninitialize される）
+        let _mf_thread_init = match MfThreadInit::new("MF encoder worker") {
+            Ok(init) => init,
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
         let mut encode_failures = 0u32;
+        let mut gpu_input_failures = 0u32;
         let mut empty_samples = 0u32;
+        // 障害が続いたときに毎フレームの警告でログが膨らまないよう間引く
+        let mut input_error_log = LogThrottle::default();
+        let mut output_error_log = LogThrottle::default();
+        let mut empty_sample_log = LogThrottle::default();
         let mut frame_timestamp = 0i64;
         let mut last_timestamp: Option<u64> = None;
 
@@ -197,66 +421,42 @@ pub fn start_mf_encode_workers() -> (
         };
 
         // 最初のフレームで初期化
-        let encode_width = (first_job.width / 2) * 2;
-        let encode_height = (first_job.height / 2) * 2;
-
-        // D3D11 リソースの作成
-        let d3d_resources = match D3D11Resources::create() {
-            Ok(resources) => resources,
-            Err(e) => {
-                warn!("MF encoder worker: failed to create D3D11 resources: {}", e);
-                return;
-            }
-        };
-
-        // エンコーダーと前処理器の作成
-        let width = encode_width;
-        let height = encode_height;
-
-        let mut preprocessor = match VideoProcessorPreprocessor::create(
-            d3d_resources.clone(),
-            encode_width,
-            encode_height,
-        ) {
-            Ok(preproc) => preproc,
-            Err(e) => {
-                warn!("MF encoder worker: failed to create preprocessor: {}", e);
-                return;
-            }
+        // フレームレートが変わった場合はフレームルーター側でワーカーごと再生成される
+        let (first_width, first_height) = first_job.output_size();
+        let mut session_config = SessionConfig {
+            width: (first_width / 2) * 2,
+            height: (first_height / 2) * 2,
+            fps: first_job.fps,
+            encoder_device,
+            latency_mode,
+            color,
+            gpu_input,
         };
+        let width = session_config.width;
+        let height = session_config.height;
 
-        let encoder = match H264Encoder::create(d3d_resources.clone(), encode_width, encode_height)
-        {
-            Ok(enc) => enc,
+        let mut session = match MfSession::create(&session_config) {
+            Ok(session) => session,
             Err(e) => {
-                warn!("MF encoder worker: failed to create encoder: {}", e);
+                warn!("MF encoder worker: {:#}", e);
                 return;
             }
         };
 
-        // codec configからSPS/PPSを取得（best-effort、取得できない場合はNone）
-        let codec_config_sps_pps = encoder.get_codec_config();
-        if codec_config_sps_pps.is_some() {
-            info!("MF encoder worker: extracted SPS/PPS from codec config");
-        } else {
-            debug!("MF encoder worker: codec config not available, will rely on in-band SPS/PPS");
-        }
-
-        // ストリーミングを開始
-        if let Err(e) = encoder.start_streaming() {
-            warn!("MF encoder worker: failed to start streaming: {}", e);
-            return;
-        }
-
         // 最初のフレームを処理
         let mut pending_job = Some(first_job);
-        let mut first_keyframe_sent = false;
+        // ストリーム変更後はデコーダーが追従できるよう次のフレームをキーフレームにする
+        let mut force_next_keyframe: Option<KeyframeReason> = None;
 
         // 参考実装に従い、常駐イベントループを開始
         loop {
             unsafe {
                 // イベントを待機
-                let event = match encoder.event_generator().GetEvent(MF_EVENT_FLAG_NONE) {
+                let event = match session
+                    .encoder
+                    .event_generator()
+                    .GetEvent(MF_EVENT_FLAG_NONE)
+                {
                     Ok(event) => event,
                     Err(e) => {
                         warn!(
@@ -264,6 +464,11 @@ pub fn start_mf_encode_workers() -> (
                             e,
                             e.code()
                         );
+                        if session.recover_if_device_removed(&session_config) {
+                            input_meta_queue.clear();
+                            force_next_keyframe = Some(KeyframeReason::EncoderRestart);
+                            continue;
+                        }
                         encode_failures += 1;
                         // エラーが続く場合は終了
                         if encode_failures > 10 {
@@ -286,7 +491,7 @@ pub fn start_mf_encode_workers() -> (
                     METransformNeedInput => {
                         // NeedInput イベントが来たときに最新のフレームを取得
                         // try_take()でノンブロッキング取得（最新の1つだけ）
-                        let job = if let Some(job) = pending_job.take() {
+                        let mut job = if let Some(job) = pending_job.take() {
                             job
                         } else {
                             // 最新のフレームを取得（利用可能な場合のみ）
@@ -299,24 +504,71 @@ pub fn start_mf_encode_workers() -> (
                             }
                         };
 
-                        let job_width = (job.width / 2) * 2;
-                        let job_height = (job.height / 2) * 2;
+                        // 接続先が示したビットレートの上限を反映する（変わったときだけ設定される）
+                        if let Err(e) = session.encoder.set_max_bitrate(job.max_bitrate_bps) {
+                            warn!("MF encoder worker: failed to set max bitrate: {:#}", e);
+                        }
+
+                        let (output_width, output_height) = job.output_size();
+                        let job_width = (output_width / 2) * 2;
+                        let job_height = (output_height / 2) * 2;
 
-                        // 前処理（RGBA → NV12 テクスチャ）
-                        let nv12_texture = match preprocessor.process(
-                            &job.rgba,
+                        // 前処理（RGBA / BGRA → NV12）して入力バッファを作る
+                        let input_buffer = match session.input.create_input_buffer(
+                            &mut job,
                             width,
                             height,
                             frame_timestamp,
                         ) {
-                            Ok(texture) => texture,
+                            Ok(buffer) => {
+                                gpu_input_failures = 0;
+                                buffer
+                            }
                             Err(e) => {
-                                warn!(
-                                        "MF encoder worker: preprocess failed for {}x{} frame: {} (HRESULT: {:?})",
-                                        job.width, job.height, e, e.source()
+                                if let Some(suppressed) = input_error_log.check() {
+                                    warn!(
+                                        "MF encoder worker: failed to prepare input for {}x{} frame: {:#} ({} similar suppressed)",
+                                        job.width, job.height, e, suppressed
                                     );
+                                }
+                                // GPU が外れた場合はデバイスから作り直し、同じフレームを新しいエンコーダーに渡す
+                                if session.recover_if_device_removed(&session_config) {
+                                    input_meta_queue.clear();
+                                    force_next_keyframe = Some(KeyframeReason::EncoderRestart);
+                                    pending_job = Some(job);
+                                    continue;
+                                }
+                                // Video Processor だけが失敗し続ける場合は CPU 変換に切り替え、同じフレームを渡し直す
+                                if session.input.is_gpu() {
+                                    gpu_input_failures += 1;
+                                    if gpu_input_failures >= GPU_INPUT_FAILURE_LIMIT {
+                                        warn!(
+                                            "MF encoder worker: D3D11 Video Processor failed {} times in a row, degraded mode: CPU NV12 conversion with the hardware encoder",
+                                            gpu_input_failures
+                                        );
+                                        gpu_input_failures = 0;
+                                        match session.fall_back_to_cpu(&mut session_config) {
+                                            Ok(()) => {
+                                                info!(
+                                                    "MF encoder worker: using {} input path",
+                                                    session.input.name()
+                                                );
+                                                input_meta_queue.clear();
+                                                force_next_keyframe =
+                                                    Some(KeyframeReason::EncoderRestart);
+                                                pending_job = Some(job);
+                                                continue;
+                                            }
+                                            Err(e) => {
+                                                warn!(
+                                                    "MF encoder worker: failed to switch to CPU input path: {:#}",
+                                                    e
+                                                );
+                                            }
+                                        }
+                                    }
+                                }
                                 encode_failures += 1;
-                                input_meta_queue.pop_back(); // メタ情報も削除
                                 continue;
                             }
                         };
@@ -335,32 +587,19 @@ pub fn start_mf_encode_workers() -> (
                         };
                         last_timestamp = Some(job.timestamp);
 
+                        // 要求がなくてもエンコーダー側の都合で強制する場合がある
+                        let keyframe_reason = job.request_keyframe.or(force_next_keyframe);
+
                         // メタ情報をキューに保存
                         input_meta_queue.push_back(InputFrameMeta {
                             duration,
                             width: job_width,
                             height: job_height,
+                            keyframe_reason,
+                            capture_timestamp: job.timestamp,
+                            sequence: job.sequence,
                         });
 
-                        // DXGI サーフェスバッファを作成
-                        let input_buffer = match MFCreateDXGISurfaceBuffer(
-                            &ID3D11Texture2D::IID,
-                            &nv12_texture,
-                            0,
-                            false,
-                        ) {
-                            Ok(buffer) => buffer,
-                            Err(e) => {
-                                warn!(
-                                    "MF encoder worker: failed to create DXGI surface buffer: {}",
-                                    e
-                                );
-                                encode_failures += 1;
-                                input_meta_queue.pop_back(); // メタ情報も削除
-                                continue;
-                            }
-                        };
-
                         // 入力サンプルを作成
                         let input_sample = match MFCreateSample() {
                             Ok(sample) => sample,
@@ -394,7 +633,8 @@ pub fn start_mf_encode_workers() -> (
                         let _ = input_sample.SetSampleDuration(sample_duration_hns);
 
                         // キーフレーム要求がある場合は強制
-                        if job.request_keyframe {
+                        if keyframe_reason.is_some() {
+                            force_next_keyframe = None;
                             if let Err(e) =
                                 input_sample.SetUINT32(&MFSampleExtension_VideoEncodePictureType, 1)
                             {
@@ -406,15 +646,28 @@ pub fn start_mf_encode_workers() -> (
                         }
 
                         // ProcessInput を呼び出す
-                        if let Err(e) = encoder.transform().ProcessInput(0, &input_sample, 0) {
-                            warn!(
-                                "MF encoder worker: ProcessInput failed for {}x{} frame: {} (HRESULT: {:?})",
-                                job_width, job_height, e, e.code()
-                            );
+                        if let Err(e) =
+                            session
+                                .encoder
+                                .transform()
+                                .ProcessInput(0, &input_sample, 0)
+                        {
+                            let logged = input_error_log.check();
+                            if let Some(suppressed) = logged {
+                                warn!(
+                                    "MF encoder worker: ProcessInput failed for {}x{} frame: {} (HRESULT: {:?}, {} similar suppressed)",
+                                    job_width, job_height, e, e.code(), suppressed
+                                );
+                            }
+                            if session.recover_if_device_removed(&session_config) {
+                                input_meta_queue.clear();
+                                force_next_keyframe = Some(KeyframeReason::EncoderRestart);
+                                continue;
+                            }
                             encode_failures += 1;
                             input_meta_queue.pop_back();
                             // エラーが続く場合は警告を出力
-                            if encode_failures > 5 {
+                            if encode_failures > 5 && logged.is_some() {
                                 warn!(
                                     "MF encoder worker: ProcessInput failures exceeded threshold ({} failures)",
                                     encode_failures
@@ -437,10 +690,11 @@ pub fn start_mf_encode_workers() -> (
                         let mut status: u32 = 0;
 
                         let mut output_buffers = [output_data_buffer];
-                        match encoder
-                            .transform()
-                            .ProcessOutput(0, &mut output_buffers, &mut status)
-                        {
+                        match session.encoder.transform().ProcessOutput(
+                            0,
+                            &mut output_buffers,
+                            &mut status,
+                        ) {
                             Ok(_) => {
                                 if let Some(sample) = output_buffers[0].pSample.take() {
                                     let buffer = match sample.GetBufferByIndex(0) {
@@ -498,7 +752,7 @@ pub fn start_mf_encode_workers() -> (
                                     }
 
                                     // Annex-B形式に変換（フォーマット自動判定）
-                                    let (mut sample_data, has_sps_pps_in_data) =
+                                    let (sample_data, has_sps_pps_in_data) =
                                         annexb_from_mf_data(&encoded_data);
 
                                     // キーフレーム判定（MFSampleExtension_CleanPoint + SPS/PPS検出）
@@ -509,39 +763,34 @@ pub fn start_mf_encode_workers() -> (
                                             _ => false, // エラーまたは未設定の場合はfalse
                                         };
                                     // SPS/PPSが含まれている場合もキーフレームとして扱う（ブラウザがデコード開始できるように）
-                                    let mut is_keyframe = is_clean_point || has_sps_pps_in_data;
-
-                                    // in-bandにSPS/PPSが無く、codec configから取得したSPS/PPSがある場合、最初のキーフレームに注入
-                                    if !has_sps_pps_in_data && is_keyframe && !first_keyframe_sent {
-                                        if let Some((ref sps, ref pps)) = codec_config_sps_pps {
-                                            debug!("MF encoder: injecting SPS/PPS from codec config (SPS: {} bytes, PPS: {} bytes)", sps.len(), pps.len());
-                                            const START_CODE: &[u8] = &[0x00, 0x00, 0x00, 0x01];
-                                            let mut injected_data = Vec::with_capacity(
-                                                START_CODE.len()
-                                                    + sps.len()
-                                                    + START_CODE.len()
-                                                    + pps.len()
-                                                    + sample_data.len(),
-                                            );
-                                            injected_data.extend_from_slice(START_CODE);
-                                            injected_data.extend_from_slice(sps.as_slice());
-                                            injected_data.extend_from_slice(START_CODE);
-                                            injected_data.extend_from_slice(pps.as_slice());
-                                            injected_data.extend_from_slice(&sample_data);
-                                            sample_data = injected_data;
-                                            is_keyframe = true; // 注入後は確実にキーフレーム
-                                            first_keyframe_sent = true;
-                                        }
-                                    } else if has_sps_pps_in_data {
+                                    let is_keyframe = is_clean_point || has_sps_pps_in_data;
+                                    // QP はエンコーダーが報告する場合のみ（報告しない MFT もある）
+                                    let average_qp = sample
+                                        .GetUINT64(&MFSampleExtension_VideoEncodeQP)
+                                        .ok()
+                                        .and_then(qp_from_attribute);
+
+                                    // in-bandにSPS/PPSが無いキーフレームには、codec configから取得したSPS/PPSを毎回注入
+                                    if has_sps_pps_in_data {
                                         debug!("MF encoder: detected SPS/PPS in encoded data, marking as keyframe");
-                                        first_keyframe_sent = true;
                                     }
+                                    let sample_data = inject_sps_pps_if_missing(
+                                        sample_data,
+                                        has_sps_pps_in_data,
+                                        is_keyframe,
+                                        session.codec_config_sps_pps.as_ref(),
+                                    );
 
                                     // メタ情報を取得
                                     let meta = match input_meta_queue.pop_front() {
                                         Some(m) => m,
                                         None => {
-                                            warn!("MF encoder worker: no input meta available for output");
+                                            if let Some(suppressed) = empty_sample_log.check() {
+                                                warn!(
+                                                    "MF encoder worker: no input meta available for output ({} similar suppressed)",
+                                                    suppressed
+                                                );
+                                            }
                                             empty_samples += 1;
                                             continue;
                                         }
@@ -549,20 +798,37 @@ pub fn start_mf_encode_workers() -> (
 
                                     if sample_data.is_empty() {
                                         empty_samples += 1;
-                                        warn!(
-                                            "MF encoder worker: empty sample (total empty: {})",
-                                            empty_samples
-                                        );
+                                        if empty_sample_log.check().is_some() {
+                                            warn!(
+                                                "MF encoder worker: empty sample (total empty: {})",
+                                                empty_samples
+                                            );
+                                        }
                                         continue;
                                     }
 
+                                    let sample_data = if capture_time_sei {
+                                        nal::insert_before_first_slice(
+                                            &sample_data,
+                                            &nal::capture_time_sei(meta.capture_timestamp),
+                                        )
+                                    } else {
+                                        sample_data
+                                    };
+
                                     if res_tx
                                         .send(EncodeResult {
                                             sample_data,
                                             is_keyframe: is_keyframe,
+                                            keyframe_reason: meta
+                                                .keyframe_reason
+                                                .filter(|_| is_keyframe),
                                             duration: meta.duration,
                                             width: meta.width,
                                             height: meta.height,
+                                            capture_timestamp: meta.capture_timestamp,
+                                            average_qp,
+                                            sequence: meta.sequence,
                                         })
                                         .is_err()
                                     {
@@ -571,10 +837,12 @@ pub fn start_mf_encode_workers() -> (
                                     }
                                 } else {
                                     empty_samples += 1;
-                                    warn!(
-                                        "MF encoder worker: ProcessOutput returned empty sample (total empty: {})",
-                                        empty_samples
-                                    );
+                                    if empty_sample_log.check().is_some() {
+                                        warn!(
+                                            "MF encoder worker: ProcessOutput returned empty sample (total empty: {})",
+                                            empty_samples
+                                        );
+                                    }
                                 }
                             }
                             Err(e) if e.code() == MF_E_TRANSFORM_NEED_MORE_INPUT => {
@@ -582,21 +850,49 @@ pub fn start_mf_encode_workers() -> (
                                 debug!("MF encoder worker: all output retrieved");
                             }
                             Err(e) if e.code() == MF_E_TRANSFORM_STREAM_CHANGE => {
-                                warn!("MF encoder worker: stream change detected");
-                                // ストリーム変更が発生した場合は再初期化が必要かもしれないが、
-                                // ここでは警告のみ
+                                // 通常のエラーとは別扱い: 出力タイプを再設定してストリームを立て直す
+                                warn!(
+                                    "MF encoder worker: stream change (MF_E_TRANSFORM_STREAM_CHANGE), renegotiating output type"
+                                );
+                                match session.encoder.renegotiate_output_type() {
+                                    Ok(()) => {
+                                        session.codec_config_sps_pps =
+                                            session.encoder.get_codec_config();
+                                        force_next_keyframe = Some(KeyframeReason::EncoderRestart);
+                                        info!(
+                                            "MF encoder worker: output type renegotiated after stream change (codec config SPS/PPS: {})",
+                                            session.codec_config_sps_pps.is_some()
+                                        );
+                                    }
+                                    Err(e) => {
+                                        warn!(
+                                            "MF encoder worker: failed to renegotiate output type after stream change: {}",
+                                            e
+                                        );
+                                        encode_failures += 1;
+                                    }
+                                }
                             }
                             Err(e) => {
                                 let error_code = e.code();
-                                warn!(
-                                    "MF encoder worker: ProcessOutput failed: {} (code: {:?}, status: {})",
-                                    e,
-                                    error_code,
-                                    status
-                                );
+                                let logged = output_error_log.check();
+                                if let Some(suppressed) = logged {
+                                    warn!(
+                                        "MF encoder worker: ProcessOutput failed: {} (code: {:?}, status: {}, {} similar suppressed)",
+                                        e,
+                                        error_code,
+                                        status,
+                                        suppressed
+                                    );
+                                }
+                                if session.recover_if_device_removed(&session_config) {
+                                    input_meta_queue.clear();
+                                    force_next_keyframe = Some(KeyframeReason::EncoderRestart);
+                                    continue;
+                                }
                                 encode_failures += 1;
                                 // エラーが続く場合は警告を出力
-                                if encode_failures > 5 {
+                                if encode_failures > 5 && logged.is_some() {
                                     warn!(
                                         "MF encoder worker: ProcessOutput failures exceeded threshold ({} failures)",
                                         encode_failures
@@ -622,6 +918,7 @@ pub fn start_mf_encode_workers() -> (
             encode_failures, empty_samples
         );
     });
+    job_slot.attach_worker(worker);
 
     (job_slot, res_rx)
 }
@@ -1,6 +1,8 @@
 use anyhow::{Context, Result};
+use core_types::{AspectMode, PixelFormat};
 use std::mem::ManuallyDrop;
 use windows::core::Interface;
+use windows::Win32::Foundation::RECT;
 use windows::Win32::Graphics::Direct3D11::{
     ID3D11ComputeShader, ID3D11ShaderResourceView, ID3D11UnorderedAccessView,
     D3D11_BIND_UNORDERED_ACCESS,
@@ -14,20 +16,34 @@ use windows::Win32::Graphics::Dxgi::Common::{
     DXGI_FORMAT_NV12, DXGI_FORMAT_R8G8B8A8_UNORM, DXGI_SAMPLE_DESC,
 };
 use windows::Win32::Media::MediaFoundation::{
-    IMFDXGIBuffer, IMFTransform, MFCreateDXGISurfaceBuffer, MFCreateMediaType, MFCreateSample,
-    MFMediaType_Video, MFVideoFormat_ARGB32, MFVideoFormat_NV12, MFVideoInterlace_Progressive,
-    MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_OUTPUT_DATA_BUFFER,
-    MF_E_TRANSFORM_NEED_MORE_INPUT, MF_E_TRANSFORM_STREAM_CHANGE,
+    IMFDXGIBuffer, IMFTransform, IMFVideoProcessorControl, MFCreateDXGISurfaceBuffer,
+    MFCreateMediaType, MFCreateSample, MFMediaType_Video, MFVideoFormat_ARGB32, MFVideoFormat_NV12,
+    MFVideoInterlace_Progressive, MFARGB, MFT_MESSAGE_NOTIFY_BEGIN_STREAMING,
+    MFT_MESSAGE_NOTIFY_START_OF_STREAM, MFT_OUTPUT_DATA_BUFFER, MF_E_TRANSFORM_NEED_MORE_INPUT,
+    MF_E_TRANSFORM_STREAM_CHANGE,
 };
 
+use crate::h264::color::ColorSpace;
 use crate::h264::mmf::d3d::D3D11Resources;
 
 /// Video Processor MFT による前処理（RGBA → BGRA → NV12 + リサイズ）
+///
+/// BGRA で渡されたフレームは BGRA テクスチャに直接アップロードし、Compute Shader による並べ替えを省く
+///
+/// 出力（エンコーダーの入力）のサイズは作成時に決まる。キャプチャが縮小せずに元のサイズで送ってきた
+/// フレーム（`EncodeJob::scale_to`）は、NV12 への変換と同時に出力サイズへ縮小する
 pub struct VideoProcessorPreprocessor {
     transform: IMFTransform,
     d3d_resources: D3D11Resources,
+    /// 出力のサイズ
     width: u32,
     height: u32,
+    /// 入力のサイズと、出力とアスペクト比が違う場合の合わせ方
+    input_width: u32,
+    input_height: u32,
+    aspect: AspectMode,
+    fps: u32,
+    color: ColorSpace,
     rgba_texture: Option<ID3D11Texture2D>,
     bgra_texture: Option<ID3D11Texture2D>,
     output_texture: Option<ID3D11Texture2D>,
@@ -37,8 +53,14 @@ pub struct VideoProcessorPreprocessor {
 }
 
 impl VideoProcessorPreprocessor {
-    /// Video Processor MFT を作成
-    pub fn create(d3d_resources: D3D11Resources, width: u32, height: u32) -> Result<Self> {
+    /// `width` x `height` の NV12 を出力する Video Processor MFT を作成
+    pub fn create(
+        d3d_resources: D3D11Resources,
+        width: u32,
+        height: u32,
+        fps: u32,
+        color: ColorSpace,
+    ) -> Result<Self> {
         unsafe {
             let transform = crate::h264::mmf::mf::find_video_processor()
                 .context("Failed to find Video Processor MFT")?;
@@ -51,6 +73,11 @@ impl VideoProcessorPreprocessor {
                 d3d_resources,
                 width,
                 height,
+                input_width: width,
+                input_height: height,
+                aspect: AspectMode::Stretch,
+                fps,
+                color,
                 rgba_texture: None,
                 bgra_texture: None,
                 output_texture: None,
@@ -61,7 +88,7 @@ impl VideoProcessorPreprocessor {
 
             // メディアタイプを設定
             preprocessor
-                .setup_media_types(width, height)
+                .setup_media_types()
                 .context("Failed to setup media types")?;
 
             Ok(preprocessor)
@@ -69,7 +96,7 @@ impl VideoProcessorPreprocessor {
     }
 
     /// メディアタイプを設定
-    fn setup_media_types(&mut self, width: u32, height: u32) -> Result<()> {
+    fn setup_media_types(&mut self) -> Result<()> {
         unsafe {
             // 入力メディアタイプ（BGRA）
             let input_media_type = MFCreateMediaType()
@@ -92,16 +119,16 @@ impl VideoProcessorPreprocessor {
                 .ok()
                 .context("Failed to set input subtype")?;
 
-            let frame_size = ((width as u64) << 32) | (height as u64);
+            let input_frame_size = ((self.input_width as u64) << 32) | (self.input_height as u64);
             input_media_type
                 .SetUINT64(
                     &windows::Win32::Media::MediaFoundation::MF_MT_FRAME_SIZE,
-                    frame_size,
+                    input_frame_size,
                 )
                 .ok()
                 .context("Failed to set input frame size")?;
 
-            let frame_rate = (60u64 << 32) | 1u64;
+            let frame_rate = crate::h264::mmf::mf::pack_frame_rate(self.fps);
             input_media_type
                 .SetUINT64(
                     &windows::Win32::Media::MediaFoundation::MF_MT_FRAME_RATE,
@@ -144,10 +171,11 @@ impl VideoProcessorPreprocessor {
                 .ok()
                 .context("Failed to set output subtype")?;
 
+            let output_frame_size = ((self.width as u64) << 32) | (self.height as u64);
             output_media_type
                 .SetUINT64(
                     &windows::Win32::Media::MediaFoundation::MF_MT_FRAME_SIZE,
-                    frame_size,
+                    output_frame_size,
                 )
                 .ok()
                 .context("Failed to set output frame size")?;
@@ -168,11 +196,19 @@ impl VideoProcessorPreprocessor {
                 .ok()
                 .context("Failed to set output interlace mode")?;
 
+            // 変換に使う YUV 行列とレンジ
+            crate::h264::mmf::mf::set_color_attributes(&output_media_type, self.color)
+                .ok()
+                .context("Failed to set output color attributes")?;
+
             self.transform
                 .SetOutputType(0, &output_media_type, 0)
                 .ok()
                 .context("Failed to set Video Processor output type")?;
 
+            self.set_rectangles()
+                .context("Failed to set Video Processor rectangles")?;
+
             // ストリーム開始を通知（非同期MFTでは BEGIN_STREAMING を先に送る必要がある）
             self.transform
                 .ProcessMessage(MFT_MESSAGE_NOTIFY_BEGIN_STREAMING, 0)
@@ -188,18 +224,58 @@ impl VideoProcessorPreprocessor {
         }
     }
 
-    /// 解像度が変更された場合に再設定
-    pub fn resize(&mut self, width: u32, height: u32) -> Result<()> {
-        if self.width != width || self.height != height {
-            self.width = width;
-            self.height = height;
+    /// 入力のどの範囲を出力のどこに描くかを設定する（余白は不透明な黒）
+    fn set_rectangles(&self) -> Result<()> {
+        let (src, dst) = core_types::aspect_layout(
+            self.input_width,
+            self.input_height,
+            self.width,
+            self.height,
+            self.aspect,
+        );
+        let to_rect = |r: core_types::Rect| RECT {
+            left: r.x as i32,
+            top: r.y as i32,
+            right: (r.x + r.width) as i32,
+            bottom: (r.y + r.height) as i32,
+        };
+        let (src, dst) = (to_rect(src), to_rect(dst));
+        let border = MFARGB {
+            rgbBlue: 0,
+            rgbGreen: 0,
+            rgbRed: 0,
+            rgbAlpha: 255,
+        };
+        unsafe {
+            let control: IMFVideoProcessorControl = self
+                .transform
+                .cast()
+                .context("Video Processor does not support IMFVideoProcessorControl")?;
+            control
+                .SetBorderColor(Some(&border as *const _))
+                .context("Failed to set border color")?;
+            control
+                .SetSourceRectangle(Some(&src as *const _))
+                .context("Failed to set source rectangle")?;
+            control
+                .SetDestinationRectangle(Some(&dst as *const _))
+                .context("Failed to set destination rectangle")?;
+        }
+        Ok(())
+    }
+
+    /// 入力のサイズ・アスペクト比の合わせ方が変わった場合に再設定（出力のサイズは変えない）
+    pub fn set_input(&mut self, width: u32, height: u32, aspect: AspectMode) -> Result<()> {
+        if (self.input_width, self.input_height, self.aspect) != (width, height, aspect) {
+            self.input_width = width;
+            self.input_height = height;
+            self.aspect = aspect;
             self.rgba_texture = None;
             self.bgra_texture = None;
-            self.output_texture = None;
             self.rgba_srv = None;
             self.bgra_uav = None;
-            self.setup_media_types(width, height)
-                .context("Failed to resize Video Processor")?;
+            self.setup_media_types()
+                .context("Failed to reconfigure Video Processor input")?;
         }
         Ok(())
     }
@@ -512,31 +588,49 @@ impl VideoProcessorPreprocessor {
         }
     }
 
-    /// RGBA データを処理して NV12 テクスチャを生成
+    /// `width` x `height` の RGBA / BGRA データを処理して出力サイズの NV12 テクスチャを生成
+    /// （サイズが違う場合は `aspect` に従って縮小する）
     pub fn process(
         &mut self,
-        rgba_data: &[u8],
+        data: &[u8],
+        format: PixelFormat,
         width: u32,
         height: u32,
+        aspect: AspectMode,
         timestamp: i64,
     ) -> Result<ID3D11Texture2D> {
         unsafe {
-            // 解像度が変更された場合は再設定
-            self.resize(width, height)?;
-
-            // RGBA を D3D11 テクスチャにアップロード
-            let rgba_texture = self.upload_rgba_to_texture(rgba_data, width, height)?;
+            // 入力の解像度が変更された場合は再設定
+            self.set_input(width, height, aspect)?;
 
             // BGRA テクスチャを作成
             let bgra_texture = self.create_bgra_texture(width, height)?;
 
-            // GPU側でRGBA→BGRA変換を行う
-            self.convert_rgba_to_bgra(&rgba_texture, &bgra_texture, width, height)?;
+            match format {
+                PixelFormat::Rgba8 => {
+                    // RGBA を D3D11 テクスチャにアップロード
+                    let rgba_texture = self.upload_rgba_to_texture(data, width, height)?;
+
+                    // GPU側でRGBA→BGRA変換を行う
+                    self.convert_rgba_to_bgra(&rgba_texture, &bgra_texture, width, height)?;
+                }
+                PixelFormat::Bgra8 => {
+                    // 並びが同じなので BGRA テクスチャへ直接アップロード
+                    self.d3d_resources.context.UpdateSubresource(
+                        &bgra_texture,
+                        0,
+                        None,
+                        data.as_ptr() as _,
+                        width * 4,
+                        width * 4 * height,
+                    );
+                }
+            }
 
             let input_texture = bgra_texture;
 
             // NV12 出力テクスチャを作成
-            let output_texture = self.create_output_texture(width, height)?;
+            let output_texture = self.create_output_texture(self.width, self.height)?;
 
             // DXGI サーフェスバッファを作成
             // MFCreateDXGISurfaceBufferの最初のパラメータはID3D11Texture2DインターフェースのIIDを指定する必要がある
@@ -640,8 +734,33 @@ impl VideoProcessorPreprocessor {
                 }
             }
 
+            // 返すテクスチャはエンコーダー MFT が ProcessInput 後に別スレッドで読み出す。
+            // アップロード → RGBA→BGRA の Compute Shader → Video Processor の NV12 変換は
+            // GPU に発行しただけで完了しているとは限らないため、ここで完了を待ってから渡す。
+            // 待たないと高負荷時にエンコーダーが書き込み途中のテクスチャを読み、フレームが崩れる
+            self.d3d_resources
+                .wait_for_gpu()
+                .context("Failed to wait for preprocessing on GPU")?;
+
             // Video Processor MFTが提供したテクスチャを返すか、フォールバックとして事前に作成したテクスチャを返す
             Ok(output_texture_result.unwrap_or(output_texture))
         }
     }
 }
+
+impl Drop for VideoProcessorPreprocessor {
+    /// MFT を Shutdown し、前処理用のテクスチャとビューを解放する
+    fn drop(&mut self) {
+        unsafe {
+            crate::h264::mmf::mf::shutdown_transform(&self.transform, true);
+        }
+        self.rgba_srv = None;
+        self.bgra_uav = None;
+        self.compute_shader = None;
+        self.rgba_texture = None;
+        self.bgra_texture = None;
+        self.output_texture = None;
+        // 解放したリソースはコンテキストの Flush まで破棄が遅延されるため、ここで掃き出す
+        self.d3d_resources.flush();
+    }
+}
@@ -1,9 +1,15 @@
 #[cfg(all(windows, feature = "h264"))]
 #[cfg(test)]
 mod tests {
-    use crate::h264::mmf::mf::{check_mf_available, find_h264_encoder, init_media_foundation};
+    use crate::h264::mmf::mf::{
+        check_mf_available, find_h264_encoder, init_media_foundation, list_h264_encoders,
+        select_encoder_index, EncoderDeviceSelector, EncoderLatencyMode,
+    };
     use crate::h264::mmf::MediaFoundationH264EncoderFactory;
-    use core_types::{EncodeJob, ShutdownError, VideoCodec, VideoEncoderFactory};
+    use core_types::{
+        AspectMode, EncodeJob, KeyframeReason, PixelFormat, ShutdownError, VideoCodec,
+        VideoEncoderFactory,
+    };
     use std::sync::Arc;
     use std::{
         sync::Once,
@@ -55,7 +61,12 @@ mod tests {
             rgba: arc_rgba,
             timestamp,
             enqueue_at: Instant::now(),
-            request_keyframe,
+            request_keyframe: request_keyframe.then_some(KeyframeReason::Pli),
+            fps: 60,
+            format: PixelFormat::Rgba8,
+            sequence: 0,
+            scale_to: None,
+            max_bitrate_bps: None,
         }
     }
 
@@ -88,6 +99,52 @@ mod tests {
         }
     }
 
+    /// 列挙したハードウェアエンコーダーに名前が付いていることを確認
+    #[test]
+    fn test_list_h264_encoders() {
+        init_tracing();
+        let encoders = list_h264_encoders().expect("H.264 encoders should be enumerated");
+        assert!(
+            !encoders.is_empty(),
+            "At least one H.264 encoder MFT should exist"
+        );
+        println!("H.264 encoder MFTs: {:?}", encoders);
+    }
+
+    /// エンコーダー指定（インデックス・名前の部分一致）で選択されることを確認
+    #[test]
+    fn test_select_encoder_index() {
+        let names = vec![
+            "Intel® Quick Sync Video H.264 Encoder MFT".to_string(),
+            "NVIDIA H.264 Encoder MFT".to_string(),
+        ];
+        let select =
+            |s: &str| select_encoder_index(&names, &s.parse::<EncoderDeviceSelector>().unwrap());
+
+        assert_eq!(select("1"), Some(1));
+        assert_eq!(select("nvidia"), Some(1));
+        assert_eq!(select("Quick Sync"), Some(0));
+        assert_eq!(select("2"), None);
+        assert_eq!(select("AMD"), None);
+    }
+
+    #[test]
+    fn test_encoder_latency_mode_from_str() {
+        assert_eq!(
+            "low-latency".parse::<EncoderLatencyMode>(),
+            Ok(EncoderLatencyMode::LowLatency)
+        );
+        assert_eq!(
+            "Quality".parse::<EncoderLatencyMode>(),
+            Ok(EncoderLatencyMode::Quality)
+        );
+        assert!("fast".parse::<EncoderLatencyMode>().is_err());
+        assert_eq!(
+            EncoderLatencyMode::default(),
+            EncoderLatencyMode::LowLatency
+        );
+    }
+
     /// Media Foundationが利用可能かチェックできることを確認
     #[test]
     fn test_check_mf_available() {
@@ -103,7 +160,7 @@ mod tests {
     #[test]
     fn test_factory_creation() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -111,11 +168,155 @@ mod tests {
         assert_eq!(factory.codec(), VideoCodec::H264);
     }
 
+    /// エンコーダーのメディアタイプに設定したフレームレートが反映されることを確認
+    #[test]
+    fn test_encoder_media_type_frame_rate() {
+        use crate::h264::color::ColorSpace;
+        use crate::h264::mmf::d3d::D3D11Resources;
+        use crate::h264::mmf::encoder::H264Encoder;
+
+        init_tracing();
+        assert!(
+            init_media_foundation(),
+            "Media Foundation should be initialized"
+        );
+
+        for fps in [30, 45] {
+            let d3d_resources =
+                D3D11Resources::create().expect("D3D11 resources should be created");
+            let encoder = H264Encoder::create(
+                Some(d3d_resources),
+                1280,
+                720,
+                fps,
+                None,
+                EncoderLatencyMode::LowLatency,
+                ColorSpace::default(),
+            )
+            .expect("H.264 encoder should be created");
+            let frame_rate = encoder.frame_rate().expect("Frame rate should be readable");
+            assert_eq!(
+                frame_rate,
+                (fps, 1),
+                "Media type should report the configured fps"
+            );
+        }
+    }
+
+    /// 解像度変更を模して前処理器・エンコーダーを作り直し続けても GPU メモリが増え続けないことを確認
+    #[test]
+    fn test_recreate_encoders_does_not_leak_gpu_memory() {
+        use crate::h264::color::ColorSpace;
+        use crate::h264::mmf::d3d::D3D11Resources;
+        use crate::h264::mmf::encoder::H264Encoder;
+        use crate::h264::mmf::preprocessor::VideoProcessorPreprocessor;
+        use windows::core::Interface;
+        use windows::Win32::Graphics::Dxgi::{
+            IDXGIAdapter3, IDXGIDevice, DXGI_MEMORY_SEGMENT_GROUP_LOCAL,
+            DXGI_QUERY_VIDEO_MEMORY_INFO,
+        };
+
+        /// 作り直しの回数
+        const ITERATIONS: usize = 40;
+        /// ドライバーのプールなどによる揺らぎとして許容する増加量
+        const ALLOWED_GROWTH: u64 = 64 * 1024 * 1024;
+
+        init_tracing();
+        assert!(
+            init_media_foundation(),
+            "Media Foundation should be initialized"
+        );
+
+        // 計測用に別のデバイスを持ち、同じアダプターのプロセス使用量を読む
+        let probe = D3D11Resources::create().expect("D3D11 resources should be created");
+        let adapter: IDXGIAdapter3 = unsafe {
+            probe
+                .device
+                .cast::<IDXGIDevice>()
+                .expect("D3D11 device should be a DXGI device")
+                .GetAdapter()
+                .expect("DXGI adapter should be available")
+                .cast()
+                .expect("DXGI adapter should support IDXGIAdapter3")
+        };
+        let gpu_usage = || {
+            let mut info = DXGI_QUERY_VIDEO_MEMORY_INFO::default();
+            unsafe {
+                adapter
+                    .QueryVideoMemoryInfo(0, DXGI_MEMORY_SEGMENT_GROUP_LOCAL, &mut info)
+                    .expect("Video memory info should be readable");
+            }
+            info.CurrentUsage
+        };
+
+        let recreate = |i: usize| {
+            let (width, height) = if i % 2 == 0 {
+                (1280, 720)
+            } else {
+                (1920, 1080)
+            };
+            let d3d_resources =
+                D3D11Resources::create().expect("D3D11 resources should be created");
+            let mut preprocessor = VideoProcessorPreprocessor::create(
+                d3d_resources.clone(),
+                width,
+                height,
+                30,
+                ColorSpace::default(),
+            )
+            .expect("Preprocessor should be created");
+            let encoder = H264Encoder::create(
+                Some(d3d_resources),
+                width,
+                height,
+                30,
+                None,
+                EncoderLatencyMode::LowLatency,
+                ColorSpace::default(),
+            )
+            .expect("H.264 encoder should be created");
+            encoder
+                .start_streaming()
+                .expect("Encoder should start streaming");
+            // テクスチャも確保させるため 1 フレームだけ前処理を通す
+            let rgba = create_gray_rgba(width, height, 128);
+            preprocessor
+                .process(
+                    &rgba,
+                    PixelFormat::Rgba8,
+                    width,
+                    height,
+                    AspectMode::Stretch,
+                    0,
+                )
+                .expect("Preprocess should succeed");
+        };
+
+        // ドライバーの初回確保分を計測から外す
+        for i in 0..4 {
+            recreate(i);
+        }
+        let baseline = gpu_usage();
+
+        for i in 0..ITERATIONS {
+            recreate(i);
+        }
+        let after = gpu_usage();
+
+        assert!(
+            after <= baseline + ALLOWED_GROWTH,
+            "GPU memory grew from {} to {} bytes after {} recreations",
+            baseline,
+            after,
+            ITERATIONS
+        );
+    }
+
     /// エンコードワーカーが起動できることを確認
     #[test]
     fn test_worker_startup() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -128,7 +329,7 @@ mod tests {
     #[tokio::test]
     async fn test_single_frame_encode() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -178,11 +379,41 @@ mod tests {
         );
     }
 
+    /// GPU の前処理を使わない CPU 変換経路（メモリバッファ入力）でエンコードできることを確認
+    #[tokio::test]
+    async fn test_cpu_input_path_encode() {
+        init_tracing();
+        let factory = MediaFoundationH264EncoderFactory::new(false).with_gpu_input(false);
+        assert!(
+            factory.use_media_foundation(),
+            "Media Foundation encoder should be available"
+        );
+
+        let (job_slot, mut receiver) = factory.setup();
+
+        let width = 1280u32;
+        let height = 720u32;
+        let rgba = create_gray_rgba(width, height, 128);
+        job_slot.set(create_encode_job(width, height, rgba, 0, false));
+
+        let result = timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("Encode timeout")
+            .expect("Failed to receive encode result");
+
+        assert!(
+            !result.sample_data.is_empty(),
+            "Encoded data should not be empty"
+        );
+        assert_eq!((result.width, result.height), (width, height));
+        assert!(result.is_keyframe, "First frame should be a keyframe");
+    }
+
     /// 複数フレームの連続エンコードテスト
     #[tokio::test]
     async fn test_multiple_frames_encode() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -236,7 +467,7 @@ mod tests {
     #[tokio::test]
     async fn test_different_sizes_encode() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -275,7 +506,7 @@ mod tests {
     #[tokio::test]
     async fn test_h264_format_validation() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -344,7 +575,7 @@ mod tests {
     #[tokio::test]
     async fn test_keyframe_generation() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -429,6 +660,7 @@ mod tests {
             result.is_keyframe,
             "Frame with request_keyframe=true should be marked as keyframe"
         );
+        assert_eq!(result.keyframe_reason, Some(KeyframeReason::Pli));
 
         // SPS/PPSが含まれていることを確認
         assert!(
@@ -441,7 +673,7 @@ mod tests {
     #[test]
     fn test_shutdown_basic() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -469,7 +701,7 @@ mod tests {
     #[tokio::test]
     async fn test_shutdown_after_encode() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -525,7 +757,7 @@ mod tests {
     #[tokio::test]
     async fn test_shutdown_during_encode() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -609,7 +841,7 @@ mod tests {
     #[test]
     fn test_shutdown_prevents_new_jobs() {
         init_tracing();
-        let factory = MediaFoundationH264EncoderFactory::new();
+        let factory = MediaFoundationH264EncoderFactory::new(false);
         assert!(
             factory.use_media_foundation(),
             "Media Foundation encoder should be available"
@@ -657,4 +889,65 @@ mod tests {
             "Error should be ShutdownError"
         );
     }
+
+    /// Annex-B形式のデータに含まれるNALタイプを列挙
+    fn annexb_nal_types(data: &[u8]) -> Vec<u8> {
+        let mut types = Vec::new();
+        let mut i = 0;
+        while i + 3 < data.len() {
+            if data[i] == 0x00 && data[i + 1] == 0x00 && data[i + 2] == 0x01 {
+                types.push(data[i + 3] & 0x1F);
+                i += 3;
+            } else {
+                i += 1;
+            }
+        }
+        types
+    }
+
+    #[test]
+    fn test_sps_pps_injected_into_every_keyframe() {
+        use crate::h264::mmf::pipeline::{annexb_from_mf_data, inject_sps_pps_if_missing};
+
+        let sps = vec![0x67, 0x42, 0xc0, 0x1f];
+        let pps = vec![0x68, 0xce, 0x3c, 0x80];
+        let codec_config = Some((sps.clone(), pps.clone()));
+
+        // AVCC形式のIDR / 非IDRスライス（in-bandのSPS/PPSなし）
+        let idr_avcc = [0x00, 0x00, 0x00, 0x03, 0x65, 0x88, 0x84];
+        let non_idr_avcc = [0x00, 0x00, 0x00, 0x03, 0x41, 0x9a, 0x02];
+
+        // 途中参加の視聴者を想定し、2回目以降のキーフレームにも注入されることを確認
+        for _ in 0..3 {
+            let (data, has_sps_pps) = annexb_from_mf_data(&idr_avcc);
+            assert!(!has_sps_pps);
+            let data = inject_sps_pps_if_missing(data, has_sps_pps, true, codec_config.as_ref());
+            assert_eq!(annexb_nal_types(&data), vec![7, 8, 5]);
+
+            let (data, has_sps_pps) = annexb_from_mf_data(&non_idr_avcc);
+            let data = inject_sps_pps_if_missing(data, has_sps_pps, false, codec_config.as_ref());
+            assert_eq!(annexb_nal_types(&data), vec![1]);
+        }
+
+        // in-bandにSPS/PPSがある場合は二重に注入しない
+        let mut inband = vec![0x00, 0x00, 0x00, 0x01];
+        inband.extend_from_slice(&sps);
+        inband.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        inband.extend_from_slice(&pps);
+        inband.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x84]);
+        let (data, has_sps_pps) = annexb_from_mf_data(&inband);
+        assert!(has_sps_pps);
+        let data = inject_sps_pps_if_missing(data, has_sps_pps, true, codec_config.as_ref());
+        assert_eq!(annexb_nal_types(&data), vec![7, 8, 5]);
+    }
+
+    #[test]
+    fn test_qp_from_attribute() {
+        use crate::h264::mmf::pipeline::qp_from_attribute;
+
+        assert_eq!(qp_from_attribute(26), Some(26));
+        // 上位ビットは無視する
+        assert_eq!(qp_from_attribute((30 << 16) | 51), Some(51));
+        assert_eq!(qp_from_attribute(52), None);
+    }
 }
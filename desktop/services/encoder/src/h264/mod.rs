@@ -4,8 +4,17 @@ pub mod openh264;
 #[cfg(feature = "h264")]
 pub mod annexb;
 
+#[cfg(feature = "h264")]
+pub mod nal;
+
+#[cfg(feature = "h264")]
+pub mod color;
+
 #[cfg(feature = "h264")]
 pub mod rgba_to_yuv;
 
+#[cfg(feature = "h264")]
+pub mod worker_thread;
+
 #[cfg(all(feature = "h264", windows))]
 pub mod mmf;
@@ -0,0 +1,589 @@
+// H.264 NAL ユニットの分割・変換ユーティリティ
+//
+// Annex-B (スタートコード区切り) と AVCC (長さプレフィックス) の両方を扱う。
+// エミュレーション防止バイトのおかげでペイロード中に 00 00 01 は現れないため、
+// Annex-B はスタートコードを探すだけで分割できる。
+//
+// キャプチャ時刻の SEI（user_data_unregistered）も扱う。クライアントは各フレームのキャプチャ時刻を
+// RTP ヘッダー拡張に頼らずビットストリームから取り出せる。SEI は非 VCL の NAL なので、
+// 知らないデコーダーは読み飛ばす。
+
+/// 4 バイトのスタートコード
+pub const START_CODE: [u8; 4] = [0x00, 0x00, 0x00, 0x01];
+
+pub const NAL_TYPE_SLICE: u8 = 1;
+pub const NAL_TYPE_IDR: u8 = 5;
+pub const NAL_TYPE_SEI: u8 = 6;
+pub const NAL_TYPE_SPS: u8 = 7;
+pub const NAL_TYPE_PPS: u8 = 8;
+pub const NAL_TYPE_AUD: u8 = 9;
+
+/// SEI の payloadType: user_data_unregistered
+const SEI_USER_DATA_UNREGISTERED: u32 = 5;
+/// キャプチャ時刻 SEI の uuid_iso_iec_11578（remoterg 独自、ASCII で "remoterg-capture"）
+pub const CAPTURE_TIME_SEI_UUID: [u8; 16] = *b"remoterg-capture";
+
+/// NAL ユニット（スタートコード・長さプレフィックスを除いたもの）のタイプ
+pub fn nal_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|header| header & 0x1F)
+}
+
+/// 先頭がスタートコード (3 バイト or 4 バイト) で始まっているか
+pub fn is_annexb(data: &[u8]) -> bool {
+    data.starts_with(&[0x00, 0x00, 0x01]) || data.starts_with(&START_CODE)
+}
+
+/// `from` 以降で最初に見つかった 00 00 01 の位置
+fn find_start_code(data: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(3)
+        .position(|w| w == [0x00, 0x00, 0x01])
+        .map(|i| from + i)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    AnnexB,
+    /// NAL 長のバイト数 (1, 2, 4)
+    Avcc(usize),
+}
+
+/// NAL ユニットのイテレータ
+pub struct NalUnits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: Format,
+}
+
+impl<'a> Iterator for NalUnits<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match self.format {
+            Format::AnnexB => loop {
+                let start = find_start_code(self.data, self.pos)? + 3;
+                let next = find_start_code(self.data, start);
+                self.pos = next.unwrap_or(self.data.len());
+                // 次が 4 バイトスタートコードの場合の先頭 0 や trailing_zero_8bits を落とす
+                let mut end = self.pos;
+                while end > start && self.data[end - 1] == 0x00 {
+                    end -= 1;
+                }
+                if end > start {
+                    return Some(&self.data[start..end]);
+                }
+                next?;
+            },
+            Format::Avcc(length_size) => loop {
+                let header = self.data.get(self.pos..self.pos + length_size)?;
+                let len = header
+                    .iter()
+                    .fold(0usize, |len, &b| (len << 8) | b as usize);
+                let start = self.pos + length_size;
+                // 途中で切れている NAL は返さずに終了する
+                let nal = self.data.get(start..start + len)?;
+                self.pos = start + len;
+                if !nal.is_empty() {
+                    return Some(nal);
+                }
+            },
+        }
+    }
+}
+
+/// NAL ユニットを列挙する（Annex-B か 4 バイト長の AVCC かを自動判定）
+pub fn iter_nal_units(data: &[u8]) -> NalUnits<'_> {
+    let format = if is_annexb(data) {
+        Format::AnnexB
+    } else {
+        Format::Avcc(4)
+    };
+    NalUnits {
+        data,
+        pos: 0,
+        format,
+    }
+}
+
+/// 長さプレフィックスが `length_size` バイトの AVCC データから NAL ユニットを列挙する
+pub fn iter_avcc_nal_units(data: &[u8], length_size: usize) -> NalUnits<'_> {
+    assert!(
+        matches!(length_size, 1 | 2 | 4),
+        "invalid AVCC length size: {}",
+        length_size
+    );
+    NalUnits {
+        data,
+        pos: 0,
+        format: Format::Avcc(length_size),
+    }
+}
+
+/// 4 バイトスタートコード区切りの Annex-B に正規化する
+pub fn to_annexb(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + START_CODE.len() * 4);
+    for nal in iter_nal_units(data) {
+        out.extend_from_slice(&START_CODE);
+        out.extend_from_slice(nal);
+    }
+    out
+}
+
+/// 長さプレフィックスが `nalu_length_size` バイトの AVCC に変換する（MP4 のサンプル用）
+///
+/// 入力は `to_annexb` と同じく Annex-B か 4 バイト長の AVCC。
+/// 長さプレフィックスに収まらない NAL ユニットがあれば None を返す（黙って切り詰めない）
+pub fn to_avcc(data: &[u8], nalu_length_size: usize) -> Option<Vec<u8>> {
+    assert!(
+        matches!(nalu_length_size, 1 | 2 | 4),
+        "invalid AVCC length size: {}",
+        nalu_length_size
+    );
+    let max_len = (1u64 << (nalu_length_size * 8)) - 1;
+    let mut out = Vec::with_capacity(data.len() + nalu_length_size * 4);
+    for nal in iter_nal_units(data) {
+        if nal.len() as u64 > max_len {
+            return None;
+        }
+        let len = (nal.len() as u32).to_be_bytes();
+        out.extend_from_slice(&len[len.len() - nalu_length_size..]);
+        out.extend_from_slice(nal);
+    }
+    Some(out)
+}
+
+/// 指定したタイプの NAL ユニットが含まれるか
+pub fn contains_nal_type(data: &[u8], nal_type_to_find: u8) -> bool {
+    iter_nal_units(data).any(|nal| nal_type(nal) == Some(nal_type_to_find))
+}
+
+/// SPS または PPS が含まれるか
+pub fn contains_sps_pps(data: &[u8]) -> bool {
+    iter_nal_units(data).any(|nal| matches!(nal_type(nal), Some(NAL_TYPE_SPS | NAL_TYPE_PPS)))
+}
+
+/// NAL ユニット列から最初の SPS/PPS を取り出す
+pub fn find_sps_pps(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut sps = None;
+    let mut pps = None;
+    for nal in iter_nal_units(data) {
+        match nal_type(nal) {
+            Some(NAL_TYPE_SPS) => {
+                sps.get_or_insert(nal);
+            }
+            Some(NAL_TYPE_PPS) => {
+                pps.get_or_insert(nal);
+            }
+            _ => {}
+        }
+    }
+    Some((sps?.to_vec(), pps?.to_vec()))
+}
+
+/// エミュレーション防止バイト (00 00 03 の 03) を取り除いて RBSP を得る
+pub fn nal_to_rbsp(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0x00 { zeros + 1 } else { 0 };
+        rbsp.push(b);
+    }
+    rbsp
+}
+
+/// RBSP にエミュレーション防止バイトを入れて NAL のペイロードにする（nal_to_rbsp の逆）
+pub fn rbsp_to_nal(rbsp: &[u8]) -> Vec<u8> {
+    let mut nal = Vec::with_capacity(rbsp.len() + rbsp.len() / 64);
+    let mut zeros = 0;
+    for &b in rbsp {
+        if zeros >= 2 && b <= 0x03 {
+            nal.push(0x03);
+            zeros = 0;
+        }
+        zeros = if b == 0x00 { zeros + 1 } else { 0 };
+        nal.push(b);
+    }
+    nal
+}
+
+/// キャプチャ時刻（100 ナノ秒単位、Frame.windows_timespan）を載せた SEI NAL ユニット
+///
+/// payload は [`CAPTURE_TIME_SEI_UUID`] の後にキャプチャ時刻の 8 バイト（ビッグエンディアン）
+pub fn capture_time_sei(capture_timestamp: u64) -> Vec<u8> {
+    let mut rbsp = vec![SEI_USER_DATA_UNREGISTERED as u8, 24];
+    rbsp.extend_from_slice(&CAPTURE_TIME_SEI_UUID);
+    rbsp.extend_from_slice(&capture_timestamp.to_be_bytes());
+    // rbsp_trailing_bits
+    rbsp.push(0x80);
+
+    let mut nal = vec![NAL_TYPE_SEI];
+    nal.extend(rbsp_to_nal(&rbsp));
+    nal
+}
+
+/// SEI NAL ユニットからキャプチャ時刻を取り出す（キャプチャ時刻の SEI がなければ None）
+pub fn parse_capture_time_sei(nal: &[u8]) -> Option<u64> {
+    if nal_type(nal)? != NAL_TYPE_SEI {
+        return None;
+    }
+    let rbsp = nal_to_rbsp(&nal[1..]);
+    let mut pos = 0;
+    // 最後の 1 バイトは rbsp_trailing_bits
+    while pos + 1 < rbsp.len() {
+        let payload_type = read_sei_value(&rbsp, &mut pos)?;
+        let payload_size = read_sei_value(&rbsp, &mut pos)? as usize;
+        let payload = rbsp.get(pos..pos + payload_size)?;
+        pos += payload_size;
+        if payload_type == SEI_USER_DATA_UNREGISTERED && payload.len() >= 24 {
+            let (uuid, data) = payload.split_at(16);
+            if uuid == CAPTURE_TIME_SEI_UUID {
+                return Some(u64::from_be_bytes(data[..8].try_into().ok()?));
+            }
+        }
+    }
+    None
+}
+
+/// SEI の payloadType / payloadSize（0xFF が続く分だけ 255 を足す）を読み、`pos` を進める
+fn read_sei_value(rbsp: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    loop {
+        let b = *rbsp.get(*pos)?;
+        *pos += 1;
+        value += b as u32;
+        if b != 0xFF {
+            return Some(value);
+        }
+    }
+}
+
+/// 最初のスライス（VCL NAL）の直前に NAL ユニットを挿入し、4 バイトスタートコードの Annex-B で返す
+/// （SEI はアクセスユニット内で最初の VCL NAL より前に置く必要がある）。スライスがなければ末尾に足す
+pub fn insert_before_first_slice(data: &[u8], nal_to_insert: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + START_CODE.len() * 5 + nal_to_insert.len());
+    let mut inserted = false;
+    for nal in iter_nal_units(data) {
+        if !inserted && matches!(nal_type(nal), Some(NAL_TYPE_SLICE..=NAL_TYPE_IDR)) {
+            out.extend_from_slice(&START_CODE);
+            out.extend_from_slice(nal_to_insert);
+            inserted = true;
+        }
+        out.extend_from_slice(&START_CODE);
+        out.extend_from_slice(nal);
+    }
+    if !inserted {
+        out.extend_from_slice(&START_CODE);
+        out.extend_from_slice(nal_to_insert);
+    }
+    out
+}
+
+/// AVCDecoderConfigurationRecord (avcC) を解析して最初の SPS/PPS を取り出す
+/// フォーマット: ISO/IEC 14496-15
+pub fn parse_avc_decoder_config(data: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    // [0] configurationVersion = 1
+    // [1..4] profile / compatibility / level
+    // [4] lengthSizeMinusOne (下位 2 ビット)
+    // [5] numOfSequenceParameterSets (下位 5 ビット)
+    // [6..] (2 バイト長 + SPS) * n, numOfPictureParameterSets, (2 バイト長 + PPS) * n
+    if data.len() < 7 || data[0] != 1 {
+        return None;
+    }
+
+    let num_sps = (data[5] & 0x1F) as usize;
+    let mut offset = 6;
+    let mut sps = None;
+    for _ in 0..num_sps {
+        let nal = read_u16_prefixed(data, &mut offset)?;
+        sps.get_or_insert_with(|| nal.to_vec());
+    }
+
+    let num_pps = *data.get(offset)? as usize;
+    offset += 1;
+    let mut pps = None;
+    for _ in 0..num_pps {
+        let nal = read_u16_prefixed(data, &mut offset)?;
+        pps.get_or_insert_with(|| nal.to_vec());
+    }
+
+    Some((sps?, pps?))
+}
+
+/// 2 バイト長プレフィックス付きのデータを読み、`offset` を進める
+fn read_u16_prefixed<'a>(data: &'a [u8], offset: &mut usize) -> Option<&'a [u8]> {
+    let len = u16::from_be_bytes([*data.get(*offset)?, *data.get(*offset + 1)?]) as usize;
+    let nal = data.get(*offset + 2..*offset + 2 + len)?;
+    *offset += 2 + len;
+    Some(nal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x42, 0xC0, 0x1F];
+    const PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84];
+    const SLICE: &[u8] = &[0x41, 0x9A, 0x02];
+
+    fn collect(data: &[u8]) -> Vec<&[u8]> {
+        iter_nal_units(data).collect()
+    }
+
+    #[test]
+    fn test_annexb_four_byte_start_codes() {
+        let data = [&START_CODE[..], SPS, &START_CODE, PPS, &START_CODE, IDR].concat();
+        assert!(is_annexb(&data));
+        assert_eq!(collect(&data), vec![SPS, PPS, IDR]);
+    }
+
+    #[test]
+    fn test_annexb_three_byte_and_mixed_start_codes() {
+        let data = [&[0x00, 0x00, 0x01][..], SPS, &[0x00, 0x00, 0x01], PPS].concat();
+        assert!(is_annexb(&data));
+        assert_eq!(collect(&data), vec![SPS, PPS]);
+
+        // 3 バイトと 4 バイトの混在、trailing_zero_8bits 付き
+        let data = [
+            &[0x00, 0x00, 0x01][..],
+            SLICE,
+            &[0x00, 0x00],
+            &START_CODE,
+            IDR,
+            &[0x00, 0x00, 0x00, 0x01],
+            SLICE,
+        ]
+        .concat();
+        assert_eq!(collect(&data), vec![SLICE, IDR, SLICE]);
+    }
+
+    #[test]
+    fn test_annexb_emulation_prevention_is_not_a_start_code() {
+        // ペイロード中の 00 00 03 01 はスタートコードではない
+        let nal = [0x65, 0x00, 0x00, 0x03, 0x01, 0x42];
+        let data = [&START_CODE[..], &nal].concat();
+        assert_eq!(collect(&data), vec![&nal[..]]);
+        assert_eq!(nal_to_rbsp(&nal), vec![0x65, 0x00, 0x00, 0x01, 0x42]);
+    }
+
+    #[test]
+    fn test_nal_to_rbsp() {
+        assert_eq!(
+            nal_to_rbsp(&[0x00, 0x00, 0x03, 0x00, 0x00, 0x03]),
+            vec![0, 0, 0, 0]
+        );
+        // 03 の直後の 00 00 は新しく数え直す
+        assert_eq!(
+            nal_to_rbsp(&[0x00, 0x00, 0x03, 0x03, 0x00, 0x00, 0x03, 0x02]),
+            vec![0x00, 0x00, 0x03, 0x00, 0x00, 0x02]
+        );
+        // 00 が 1 つだけなら 03 はそのまま
+        assert_eq!(nal_to_rbsp(&[0x00, 0x03]), vec![0x00, 0x03]);
+    }
+
+    #[test]
+    fn test_avcc_length_prefixes() {
+        let data = [
+            &[0x00, 0x00, 0x00, 0x04][..],
+            SPS,
+            &[0x00, 0x00, 0x00, 0x03],
+            IDR,
+        ]
+        .concat();
+        assert!(!is_annexb(&data));
+        assert_eq!(collect(&data), vec![SPS, IDR]);
+
+        let data = [&[0x00, 0x04][..], PPS, &[0x00, 0x03], SLICE].concat();
+        assert_eq!(
+            iter_avcc_nal_units(&data, 2).collect::<Vec<_>>(),
+            vec![PPS, SLICE]
+        );
+
+        let data = [&[0x03][..], IDR, &[0x00], &[0x03], SLICE].concat();
+        assert_eq!(
+            iter_avcc_nal_units(&data, 1).collect::<Vec<_>>(),
+            vec![IDR, SLICE]
+        );
+    }
+
+    #[test]
+    fn test_truncated_data() {
+        // AVCC: 長さがデータ末尾を超える NAL は返さない
+        let data = [
+            &[0x00, 0x00, 0x00, 0x03][..],
+            IDR,
+            &[0x00, 0x00, 0x00, 0x10, 0x41],
+        ]
+        .concat();
+        assert_eq!(collect(&data), vec![IDR]);
+        // 長さプレフィックス自体が途中で切れている
+        assert_eq!(collect(&[0x00, 0x00, 0x00]), Vec::<&[u8]>::new());
+        // Annex-B: スタートコードだけ / 空
+        assert!(collect(&START_CODE).is_empty());
+        assert!(collect(&[]).is_empty());
+        // 最後の NAL はデータ末尾まで
+        let data = [&START_CODE[..], &IDR[..2]].concat();
+        assert_eq!(collect(&data), vec![&IDR[..2]]);
+    }
+
+    #[test]
+    fn test_to_annexb() {
+        let expected = [&START_CODE[..], SPS, &START_CODE, IDR].concat();
+
+        let avcc = [
+            &[0x00, 0x00, 0x00, 0x04][..],
+            SPS,
+            &[0x00, 0x00, 0x00, 0x03],
+            IDR,
+        ]
+        .concat();
+        assert_eq!(to_annexb(&avcc), expected);
+
+        let three_byte = [&[0x00, 0x00, 0x01][..], SPS, &[0x00, 0x00, 0x01], IDR].concat();
+        assert_eq!(to_annexb(&three_byte), expected);
+        assert_eq!(to_annexb(&expected), expected);
+    }
+
+    #[test]
+    fn test_annexb_avcc_round_trip() {
+        let annexb = [&START_CODE[..], SPS, &START_CODE, PPS, &START_CODE, IDR].concat();
+
+        let avcc = to_avcc(&annexb, 4).unwrap();
+        let expected = [
+            &[0x00, 0x00, 0x00, 0x04][..],
+            SPS,
+            &[0x00, 0x00, 0x00, 0x04],
+            PPS,
+            &[0x00, 0x00, 0x00, 0x03],
+            IDR,
+        ]
+        .concat();
+        assert_eq!(avcc, expected);
+        assert_eq!(to_annexb(&avcc), annexb);
+        // AVCC を渡してもそのまま
+        assert_eq!(to_avcc(&avcc, 4).unwrap(), avcc);
+
+        for length_size in [1, 2] {
+            let avcc = to_avcc(&annexb, length_size).unwrap();
+            assert_eq!(avcc.len(), annexb.len() - (4 - length_size) * 3);
+            let nals: Vec<&[u8]> = iter_avcc_nal_units(&avcc, length_size).collect();
+            assert_eq!(nals, vec![SPS, PPS, IDR]);
+        }
+
+        // 3 バイトスタートコードも 4 バイトに正規化して戻る
+        let three_byte = [&[0x00, 0x00, 0x01][..], SLICE].concat();
+        let round_trip = to_annexb(&to_avcc(&three_byte, 4).unwrap());
+        assert_eq!(round_trip, [&START_CODE[..], SLICE].concat());
+    }
+
+    #[test]
+    fn test_to_avcc_rejects_nal_too_long_for_length_size() {
+        let nal = [&[0x65][..], &[0xAB; 255]].concat();
+        let annexb = [&START_CODE[..], &nal].concat();
+        assert_eq!(to_avcc(&annexb, 1), None);
+        let avcc = to_avcc(&annexb, 2).unwrap();
+        assert_eq!(&avcc[..2], &[0x01, 0x00]);
+        assert_eq!(iter_avcc_nal_units(&avcc, 2).next(), Some(&nal[..]));
+    }
+
+    #[test]
+    fn test_contains_sps_pps() {
+        let keyframe = [&START_CODE[..], SPS, &START_CODE, PPS, &START_CODE, IDR].concat();
+        assert!(contains_sps_pps(&keyframe));
+        assert!(contains_nal_type(&keyframe, NAL_TYPE_IDR));
+
+        let delta = [&START_CODE[..], SLICE].concat();
+        assert!(!contains_sps_pps(&delta));
+        assert!(!contains_nal_type(&delta, NAL_TYPE_IDR));
+
+        let avcc = [&[0x00, 0x00, 0x00, 0x04][..], PPS].concat();
+        assert!(contains_sps_pps(&avcc));
+    }
+
+    #[test]
+    fn test_find_sps_pps() {
+        let keyframe = [&START_CODE[..], SPS, &START_CODE, PPS, &START_CODE, IDR].concat();
+        assert_eq!(find_sps_pps(&keyframe), Some((SPS.to_vec(), PPS.to_vec())));
+        let sps_only = [&START_CODE[..], SPS, &START_CODE, IDR].concat();
+        assert_eq!(find_sps_pps(&sps_only), None);
+    }
+
+    #[test]
+    fn test_parse_avc_decoder_config() {
+        let avcc = [
+            &[0x01, 0x42, 0xC0, 0x1F, 0xFF, 0xE1, 0x00, 0x04][..],
+            SPS,
+            &[0x01, 0x00, 0x04],
+            PPS,
+        ]
+        .concat();
+        assert_eq!(
+            parse_avc_decoder_config(&avcc),
+            Some((SPS.to_vec(), PPS.to_vec()))
+        );
+
+        // 途中で切れている / バージョン不正
+        assert_eq!(parse_avc_decoder_config(&avcc[..avcc.len() - 1]), None);
+        let mut bad_version = avcc.clone();
+        bad_version[0] = 0;
+        assert_eq!(parse_avc_decoder_config(&bad_version), None);
+    }
+
+    #[test]
+    fn test_capture_time_sei_round_trip() {
+        // 0 が続くタイムスタンプではエミュレーション防止バイトが入る
+        for timestamp in [0, 0x0000_0001_0000_0002, 133_000_000_000_000_000, u64::MAX] {
+            let sei = capture_time_sei(timestamp);
+            assert_eq!(nal_type(&sei), Some(NAL_TYPE_SEI));
+            assert!(!sei
+                .windows(3)
+                .any(|w| w[0] == 0 && w[1] == 0 && w[2] < 0x03));
+            assert_eq!(parse_capture_time_sei(&sei), Some(timestamp));
+        }
+        assert!(capture_time_sei(0)
+            .windows(3)
+            .any(|w| w == [0x00, 0x00, 0x03]));
+        assert_eq!(
+            rbsp_to_nal(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x01]),
+            vec![0, 0, 3, 0, 0, 3, 0, 1]
+        );
+        assert_eq!(
+            nal_to_rbsp(&rbsp_to_nal(&[0x00, 0x00, 0x01, 0x00, 0x00, 0x03])),
+            vec![0, 0, 1, 0, 0, 3]
+        );
+
+        // 他の SEI（UUID 違いや別の payloadType）は読まない
+        let mut other = capture_time_sei(42);
+        other[3] ^= 0xFF;
+        assert_eq!(parse_capture_time_sei(&other), None);
+        assert_eq!(
+            parse_capture_time_sei(&[NAL_TYPE_SEI, 0x01, 0x01, 0x00, 0x80]),
+            None
+        );
+        assert_eq!(parse_capture_time_sei(IDR), None);
+        // 他の SEI メッセージの後ろにあっても読める
+        let mut combined = vec![NAL_TYPE_SEI, 0x01, 0x01, 0x00];
+        combined.extend_from_slice(&capture_time_sei(42)[1..]);
+        assert_eq!(parse_capture_time_sei(&combined), Some(42));
+    }
+
+    #[test]
+    fn test_insert_before_first_slice() {
+        let sei = capture_time_sei(7);
+        let keyframe = [&START_CODE[..], SPS, &START_CODE, PPS, &START_CODE, IDR].concat();
+        let with_sei = insert_before_first_slice(&keyframe, &sei);
+        assert_eq!(collect(&with_sei), vec![SPS, PPS, &sei[..], IDR]);
+
+        let three_byte = [&[0x00, 0x00, 0x01][..], SLICE, &[0x00, 0x00, 0x01], SLICE].concat();
+        let with_sei = insert_before_first_slice(&three_byte, &sei);
+        assert_eq!(collect(&with_sei), vec![&sei[..], SLICE, SLICE]);
+        assert_eq!(
+            collect(&insert_before_first_slice(&[], &sei)),
+            vec![&sei[..]]
+        );
+    }
+}
@@ -1,21 +1,130 @@
 use anyhow::Context;
-use core_types::{EncodeJobSlot, EncodeResult, ShutdownError, VideoCodec, VideoEncoderFactory};
-use openh264::encoder::{BitRate, EncoderConfig, FrameRate, RateControlMode};
+use core_types::{
+    EncodeJobSlot, EncodeResult, PixelFormat, ShutdownError, VideoCodec, VideoEncoderFactory,
+    H264_MAX_QP,
+};
+use openh264::encoder::{
+    BitRate, Encoder, EncoderConfig, FrameRate, IntraFramePeriod, RateControlMode,
+};
 use openh264::formats::YUVBuffer;
 use openh264::OpenH264API;
+use openh264_sys2::{SEncoderStatistics, ENCODER_OPTION_GET_STATISTICS};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc as tokio_mpsc;
 use tracing::{info, span, warn, Level};
 
-use super::{annexb, rgba_to_yuv};
+use super::worker_thread::WorkerThreadConfig;
+use super::{annexb, nal, rgba_to_yuv};
+
+/// OpenH264 エンコーダーの設定
+#[derive(Debug, Clone, Copy)]
+pub struct OpenH264Config {
+    /// 目標ビットレート (bps)。None の場合は解像度から決める (幅 x 高さ x 2)
+    pub bitrate_bps: Option<u32>,
+    pub max_frame_rate: f32,
+    pub rate_control_mode: RateControlMode,
+    /// キーフレーム間隔 (フレーム数)。None の場合はエンコーダー任せ
+    pub intra_period: Option<u32>,
+    /// エンコーダー内部のスレッド数。None の場合は CPU コア数（最大16）
+    pub num_threads: Option<u16>,
+    /// エンコードワーカースレッドのコア固定と優先度
+    pub worker_thread: WorkerThreadConfig,
+    /// 各フレームにキャプチャ時刻の SEI を入れる
+    pub capture_time_sei: bool,
+}
+
+impl Default for OpenH264Config {
+    fn default() -> Self {
+        Self {
+            bitrate_bps: None,
+            max_frame_rate: 60.0,
+            // Bufferbasedモードはフレームスキップが不要で、バッファ状態に基づいて品質を調整する
+            rate_control_mode: RateControlMode::Bufferbased,
+            intra_period: None,
+            num_threads: None,
+            worker_thread: WorkerThreadConfig::default(),
+            capture_time_sei: false,
+        }
+    }
+}
+
+impl OpenH264Config {
+    /// `width` x `height` をエンコードするときの目標ビットレート (bps)
+    fn target_bitrate(&self, width: u32, height: u32) -> u32 {
+        self.bitrate_bps.unwrap_or(width * height * 2)
+    }
+
+    /// ビットレートの上限（`EncodeJob::max_bitrate_bps`）を反映した設定
+    /// Bufferbased モードではビットレートが無視されるため、上限がある間は Bitrate モードにする
+    fn limited_to(&self, max_bitrate_bps: Option<u32>, width: u32, height: u32) -> Self {
+        match max_bitrate_bps {
+            Some(max) => Self {
+                bitrate_bps: Some(self.target_bitrate(width, height).min(max)),
+                rate_control_mode: RateControlMode::Bitrate,
+                ..*self
+            },
+            None => *self,
+        }
+    }
+}
 
 /// OpenH264 ファクトリ
-pub struct OpenH264EncoderFactory;
+pub struct OpenH264EncoderFactory {
+    config: OpenH264Config,
+}
 
 impl OpenH264EncoderFactory {
     pub fn new() -> Self {
-        Self
+        Self {
+            config: OpenH264Config::default(),
+        }
+    }
+
+    /// 目標ビットレート (bps) を指定する
+    /// Bufferbased モードではビットレートが無視されるため、Bitrate モードと組み合わせて使う
+    pub fn with_bitrate(mut self, bitrate_bps: u32) -> Self {
+        self.config.bitrate_bps = Some(bitrate_bps);
+        self
+    }
+
+    pub fn with_max_frame_rate(mut self, fps: f32) -> Self {
+        self.config.max_frame_rate = fps;
+        self
+    }
+
+    /// レート制御モードを指定する（ビットレートを守らせたい場合は Bitrate）
+    pub fn with_rate_control_mode(mut self, mode: RateControlMode) -> Self {
+        self.config.rate_control_mode = mode;
+        self
+    }
+
+    /// キーフレーム間隔 (フレーム数) を指定する
+    pub fn with_intra_period(mut self, frames: u32) -> Self {
+        self.config.intra_period = Some(frames);
+        self
+    }
+
+    /// エンコーダー内部のスレッド数を指定する（1 にするとワーカースレッドだけでエンコードする）
+    pub fn with_num_threads(mut self, num_threads: u16) -> Self {
+        self.config.num_threads = Some(num_threads.max(1));
+        self
+    }
+
+    /// エンコードワーカースレッドのコア固定と優先度を指定する
+    pub fn with_worker_thread(mut self, worker_thread: WorkerThreadConfig) -> Self {
+        self.config.worker_thread = worker_thread;
+        self
+    }
+
+    /// 各フレームにキャプチャ時刻の SEI を入れる（フレームごとに 30 バイトほど増える）
+    pub fn with_capture_time_sei(mut self, enabled: bool) -> Self {
+        self.config.capture_time_sei = enabled;
+        self
+    }
+
+    pub fn config(&self) -> &OpenH264Config {
+        &self.config
     }
 }
 
@@ -26,7 +135,7 @@ impl VideoEncoderFactory for OpenH264EncoderFactory {
         Arc<EncodeJobSlot>,
         tokio_mpsc::UnboundedReceiver<EncodeResult>,
     ) {
-        start_encode_workers()
+        start_encode_workers(self.config)
     }
 
     fn codec(&self) -> VideoCodec {
@@ -35,7 +144,9 @@ impl VideoEncoderFactory for OpenH264EncoderFactory {
 }
 
 /// OpenH264エンコードワーカーを生成（前処理→エンコードを直列実行）
-fn start_encode_worker() -> (
+fn start_encode_worker(
+    config: OpenH264Config,
+) -> (
     Arc<EncodeJobSlot>,
     tokio_mpsc::UnboundedReceiver<EncodeResult>,
 ) {
@@ -43,11 +154,19 @@ fn start_encode_worker() -> (
     let job_slot_clone = Arc::clone(&job_slot);
     let (res_tx, res_rx) = tokio_mpsc::unbounded_channel::<EncodeResult>();
 
-    info!("Starting OpenH264 encoder with serial preprocessing");
+    info!(
+        "Starting OpenH264 encoder with serial preprocessing ({:?})",
+        config
+    );
 
     // エンコードスレッド: ジョブを受信→前処理→エンコードを直列実行
-    std::thread::spawn(move || {
+    let worker = std::thread::spawn(move || {
+        config
+            .worker_thread
+            .apply_to_current_thread("OpenH264 encoder worker");
         let mut encoder: Option<openh264::encoder::Encoder> = None;
+        // 今のエンコーダーを作ったときのビットレートの上限
+        let mut encoder_max_bitrate: Option<u32> = None;
         let mut encode_failures = 0u32;
         let mut empty_samples = 0u32;
         let mut successful_encodes = 0u32;
@@ -55,13 +174,15 @@ fn start_encode_worker() -> (
 
         loop {
             // ジョブを取得（ブロッキング、最新のフレームのみ）
-            let job = match job_slot_clone.take() {
+            let mut job = match job_slot_clone.take() {
                 Ok(job) => job,
                 Err(ShutdownError) => {
                     info!("encoder worker: received shutdown signal, exiting");
                     break;
                 }
             };
+            // GPU での縮小を前提に元のサイズで届いたフレームは、ここで CPU で縮小する
+            job.scale_on_cpu();
 
             // タイムスタンプから duration を計算
             // windows_timespan は100ナノ秒単位の SystemRelativeTime（単調増加）
@@ -100,14 +221,34 @@ fn start_encode_worker() -> (
             let dst_width = encode_width as usize;
             let dst_height = encode_height as usize;
 
-            let yuv_data = rgba_to_yuv::rgba_to_yuv420(rgba_src, dst_width, dst_height, src_width);
+            let yuv_data = match job.format {
+                PixelFormat::Rgba8 => {
+                    rgba_to_yuv::rgba_to_yuv420(rgba_src, dst_width, dst_height, src_width)
+                }
+                PixelFormat::Bgra8 => {
+                    rgba_to_yuv::bgra_to_yuv420(rgba_src, dst_width, dst_height, src_width)
+                }
+            };
             let yuv = YUVBuffer::from_vec(yuv_data, dst_width, dst_height);
             drop(_rgba_to_yuv_guard);
 
+            // ビットレートの上限が変わったら作り直す（新しいエンコーダーはキーフレームから始まる）
+            if encoder.is_some() && encoder_max_bitrate != job.max_bitrate_bps {
+                info!(
+                    "encoder worker: max bitrate changed ({:?} -> {:?} bps), recreating encoder",
+                    encoder_max_bitrate, job.max_bitrate_bps
+                );
+                encoder = None;
+            }
+
             // 最初のフレームでエンコーダーを作成
             if encoder.is_none() {
-                match create_encoder(encode_width, encode_height) {
-                    Ok(enc) => encoder = Some(enc),
+                let config = config.limited_to(job.max_bitrate_bps, encode_width, encode_height);
+                match create_encoder(encode_width, encode_height, &config) {
+                    Ok(enc) => {
+                        encoder = Some(enc);
+                        encoder_max_bitrate = job.max_bitrate_bps;
+                    }
                     Err(e) => {
                         warn!("encoder worker: failed to create encoder: {}", e);
                         continue;
@@ -118,7 +259,7 @@ fn start_encode_worker() -> (
             let encoder = encoder.as_mut().expect("encoder should be initialized");
 
             // キーフレーム要求がある場合は強制
-            if job.request_keyframe {
+            if job.request_keyframe.is_some() {
                 encoder.force_intra_frame();
             }
 
@@ -134,6 +275,7 @@ fn start_encode_worker() -> (
                     let _pack_guard = pack_span.enter();
                     let (sample_data, has_sps_pps) = annexb::annexb_from_bitstream(&bitstream);
                     drop(_pack_guard);
+                    let average_qp = last_frame_qp(encoder);
 
                     let sample_size = sample_data.len();
                     drop(_encode_frame_guard);
@@ -148,14 +290,26 @@ fn start_encode_worker() -> (
                     }
 
                     successful_encodes += 1;
+                    let sample_data = if config.capture_time_sei {
+                        nal::insert_before_first_slice(
+                            &sample_data,
+                            &nal::capture_time_sei(job.timestamp),
+                        )
+                    } else {
+                        sample_data
+                    };
 
                     if res_tx
                         .send(EncodeResult {
                             sample_data,
                             is_keyframe: has_sps_pps,
+                            keyframe_reason: job.request_keyframe.filter(|_| has_sps_pps),
                             duration,
                             width: encode_width,
                             height: encode_height,
+                            capture_timestamp: job.timestamp,
+                            average_qp,
+                            sequence: job.sequence,
                         })
                         .is_err()
                     {
@@ -177,35 +331,323 @@ fn start_encode_worker() -> (
             successful_encodes, encode_failures, empty_samples
         );
     });
+    job_slot.attach_worker(worker);
 
     (job_slot, res_rx)
 }
 
 /// エンコードワーカーを起動する
-pub fn start_encode_workers() -> (
+pub fn start_encode_workers(
+    config: OpenH264Config,
+) -> (
     Arc<EncodeJobSlot>,
     tokio_mpsc::UnboundedReceiver<EncodeResult>,
 ) {
     // encoderの整合性を保つため、常に1つのワーカーのみを起動
     // Pフレームが適切に参照フレームを参照できるようにする
-    start_encode_worker()
+    start_encode_worker(config)
 }
 
-fn create_encoder(width: u32, height: u32) -> anyhow::Result<openh264::encoder::Encoder> {
-    let bitrate = (width * height * 2) as u32;
-    // スレッド数はCPUコア数に合わせて調整（最大16スレッド）
-    let num_threads = std::thread::available_parallelism()
-        .map(|n| n.get().min(16) as u16)
-        .unwrap_or(4);
-    let encoder_config = EncoderConfig::new()
+fn create_encoder(
+    width: u32,
+    height: u32,
+    config: &OpenH264Config,
+) -> anyhow::Result<openh264::encoder::Encoder> {
+    let bitrate = config.target_bitrate(width, height);
+    // スレッド数は指定が無ければCPUコア数に合わせて調整（最大16スレッド）
+    let num_threads = config.num_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get().min(16) as u16)
+            .unwrap_or(4)
+    });
+    let mut encoder_config = EncoderConfig::new()
         .bitrate(BitRate::from_bps(bitrate))
-        .max_frame_rate(FrameRate::from_hz(60.0))
+        .max_frame_rate(FrameRate::from_hz(config.max_frame_rate))
         // skip_framesをfalseにして、できるだけすべてのフレームをエンコード
         // 実運用では、フレームをスキップせずにエンコードする方が品質が良い
         .skip_frames(false)
-        // Bufferbasedモードはフレームスキップが不要で、バッファ状態に基づいて品質を調整する
-        .rate_control_mode(RateControlMode::Bufferbased)
+        .rate_control_mode(config.rate_control_mode)
         .num_threads(num_threads);
-    openh264::encoder::Encoder::with_api_config(OpenH264API::from_source(), encoder_config)
+    if let Some(frames) = config.intra_period {
+        encoder_config =
+            encoder_config.intra_frame_period(IntraFramePeriod::from_num_frames(frames));
+    }
+    Encoder::with_api_config(OpenH264API::from_source(), encoder_config)
         .context("Failed to create OpenH264 encoder")
 }
+
+/// 直前にエンコードしたフレームの平均 QP（エンコーダーの統計から取得）
+fn last_frame_qp(encoder: &mut Encoder) -> Option<u8> {
+    let mut stats = SEncoderStatistics::default();
+    // 統計の取得は、初期化済みのエンコーダーに書き込み先を渡すだけ
+    let ret = unsafe {
+        encoder.raw_api().get_option(
+            ENCODER_OPTION_GET_STATISTICS,
+            std::ptr::addr_of_mut!(stats).cast(),
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    u8::try_from(stats.uiAverageFrameQP)
+        .ok()
+        .filter(|qp| *qp <= H264_MAX_QP)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 縞模様の入ったグレースケール YUV フレームを作る
+    /// （乱数ノイズだと OpenH264 の出力バッファを溢れさせるため、滑らかな絵柄にする）
+    fn create_pattern_yuv(width: usize, height: usize, t: usize) -> YUVBuffer {
+        let mut data = vec![128u8; width * height * 3 / 2];
+        for y in 0..height {
+            for x in 0..width {
+                let wave = ((x + y + t * 4) as f32 * 0.15).sin() * 60.0;
+                let texture = ((x * x + y * 3 + t) % 23) as f32;
+                data[y * width + x] = (128.0 + wave + texture) as u8;
+            }
+        }
+        YUVBuffer::from_vec(data, width, height)
+    }
+
+    /// 2 フレームエンコードした合計バイト数
+    fn encoded_size(config: &OpenH264Config) -> usize {
+        let (width, height) = (320, 240);
+        let mut encoder = create_encoder(width, height, config).expect("create encoder");
+        (0..2)
+            .map(|t| {
+                let yuv = create_pattern_yuv(width as usize, height as usize, t);
+                encoder.encode(&yuv).expect("encode").to_vec().len()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_bitrate_affects_encoded_size() {
+        let base = OpenH264Config {
+            rate_control_mode: RateControlMode::Bitrate,
+            max_frame_rate: 30.0,
+            intra_period: Some(30),
+            ..Default::default()
+        };
+        let low = encoded_size(&OpenH264Config {
+            bitrate_bps: Some(100_000),
+            ..base
+        });
+        let high = encoded_size(&OpenH264Config {
+            bitrate_bps: Some(2_000_000),
+            ..base
+        });
+        assert!(low > 0 && high > 0);
+        assert!(
+            low < high,
+            "low bitrate output ({} bytes) should be smaller than high bitrate output ({} bytes)",
+            low,
+            high
+        );
+    }
+
+    #[test]
+    fn test_max_bitrate_caps_the_target_bitrate() {
+        let config = OpenH264Config::default();
+        // Offer の b=AS:1000 から決まった上限で 1 Mbps に抑え、ビットレートを守らせる
+        let limited = config.limited_to(Some(1_000_000), 1920, 1080);
+        assert_eq!(limited.target_bitrate(1920, 1080), 1_000_000);
+        assert!(matches!(
+            limited.rate_control_mode,
+            RateControlMode::Bitrate
+        ));
+
+        // 上限より低い設定はそのまま、上限がなければ設定どおり
+        let low = OpenH264Config {
+            bitrate_bps: Some(500_000),
+            ..config
+        };
+        assert_eq!(
+            low.limited_to(Some(1_000_000), 1920, 1080).bitrate_bps,
+            Some(500_000)
+        );
+        let unlimited = config.limited_to(None, 1920, 1080);
+        assert_eq!(unlimited.bitrate_bps, None);
+        assert!(matches!(
+            unlimited.rate_control_mode,
+            RateControlMode::Bufferbased
+        ));
+    }
+
+    #[test]
+    fn test_reports_frame_qp() {
+        let (width, height) = (320, 240);
+        let config = OpenH264Config {
+            rate_control_mode: RateControlMode::Bitrate,
+            max_frame_rate: 30.0,
+            ..Default::default()
+        };
+        let qp = |bitrate_bps: u32| {
+            let config = OpenH264Config {
+                bitrate_bps: Some(bitrate_bps),
+                ..config
+            };
+            let mut encoder = create_encoder(width, height, &config).expect("create encoder");
+            let yuv = create_pattern_yuv(width as usize, height as usize, 0);
+            encoder.encode(&yuv).expect("encode");
+            last_frame_qp(&mut encoder).expect("qp")
+        };
+        // ビットレートが低いほど QP が上がる
+        assert!(qp(50_000) > qp(5_000_000));
+    }
+
+    #[test]
+    fn test_decoder_ignores_capture_time_sei() {
+        use openh264::decoder::Decoder;
+        use openh264::formats::YUVSource;
+
+        let (width, height) = (320, 240);
+        let mut encoder =
+            create_encoder(width, height, &OpenH264Config::default()).expect("create encoder");
+        let mut decoder = Decoder::new().expect("create decoder");
+        for t in 0..3 {
+            let yuv = create_pattern_yuv(width as usize, height as usize, t);
+            let bitstream = encoder.encode(&yuv).expect("encode");
+            let (sample_data, _) = annexb::annexb_from_bitstream(&bitstream);
+            let timestamp = 1_000_000 + t as u64;
+            let sample_data =
+                nal::insert_before_first_slice(&sample_data, &nal::capture_time_sei(timestamp));
+
+            let sei = nal::iter_nal_units(&sample_data)
+                .find(|unit| nal::nal_type(unit) == Some(nal::NAL_TYPE_SEI))
+                .expect("SEI should be inserted");
+            assert_eq!(nal::parse_capture_time_sei(sei), Some(timestamp));
+            let decoded = decoder
+                .decode(&sample_data)
+                .expect("decode")
+                .expect("frame should be decoded");
+            assert_eq!(decoded.dimensions(), (width as usize, height as usize));
+        }
+    }
+
+    /// 縞模様の RGBA フレーム（`t` ごとに模様がずれる）
+    fn create_pattern_rgba(width: u32, height: u32, t: u64) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity((width * height * 4) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let wave = ((x + y + t as u32 * 4) as f32 * 0.15).sin() * 60.0;
+                let level = (128.0 + wave) as u8;
+                rgba.extend_from_slice(&[level, level / 2, 255 - level, 255]);
+            }
+        }
+        rgba
+    }
+
+    /// ワーカーから次のエンコード結果を受け取る（出てこなければ失敗）
+    fn recv_result(result_rx: &mut tokio_mpsc::UnboundedReceiver<EncodeResult>) -> EncodeResult {
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            match result_rx.try_recv() {
+                Ok(result) => return result,
+                Err(tokio_mpsc::error::TryRecvError::Empty)
+                    if std::time::Instant::now() < deadline =>
+                {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                Err(e) => panic!("no encode result from the worker: {:?}", e),
+            }
+        }
+    }
+
+    /// EncodeJob を渡してから EncodeResult を受け取るまでの経路全体（GPU や Media Foundation は不要）
+    #[test]
+    fn test_encode_workers_produce_decodable_keyframe_then_deltas() {
+        use core_types::{EncodeJob, KeyframeReason};
+        use openh264::decoder::Decoder;
+        use openh264::formats::YUVSource;
+
+        let (width, height) = (320, 240);
+        // 60fps 相当の間隔（100ns 単位）
+        let frame_interval = 10_000_000 / 60;
+        let (job_slot, mut result_rx) = start_encode_workers(OpenH264Config {
+            intra_period: Some(300),
+            ..Default::default()
+        });
+        let mut decoder = Decoder::new().expect("create decoder");
+
+        let mut results = Vec::new();
+        for t in 0..4u64 {
+            // スロットは最新のジョブしか持たないので、1 枚ずつ結果を待ってから次を渡す
+            job_slot.set(EncodeJob {
+                width,
+                height,
+                rgba: Arc::new(create_pattern_rgba(width, height, t)),
+                timestamp: 1_000_000 + t * frame_interval,
+                enqueue_at: std::time::Instant::now(),
+                request_keyframe: (t == 3).then_some(KeyframeReason::Pli),
+                fps: 60,
+                format: PixelFormat::Rgba8,
+                sequence: t + 1,
+                scale_to: None,
+                max_bitrate_bps: None,
+            });
+            let result = recv_result(&mut result_rx);
+
+            assert_eq!((result.width, result.height), (width, height));
+            assert_eq!(result.capture_timestamp, 1_000_000 + t * frame_interval);
+            assert_eq!(result.sequence, t + 1);
+            assert!(result.sample_data.starts_with(&nal::START_CODE));
+            let decoded = decoder
+                .decode(&result.sample_data)
+                .expect("decode")
+                .expect("frame should be decoded");
+            assert_eq!(decoded.dimensions(), (width as usize, height as usize));
+            results.push(result);
+        }
+        job_slot.shutdown();
+
+        let nal_types = |result: &EncodeResult| -> Vec<u8> {
+            nal::iter_nal_units(&result.sample_data)
+                .filter_map(nal::nal_type)
+                .collect()
+        };
+        // 最初のフレームは SPS/PPS 付きの IDR
+        assert!(results[0].is_keyframe);
+        assert_eq!(results[0].keyframe_reason, None);
+        let first = nal_types(&results[0]);
+        for nal_type in [nal::NAL_TYPE_SPS, nal::NAL_TYPE_PPS, nal::NAL_TYPE_IDR] {
+            assert!(first.contains(&nal_type), "{:?}", first);
+        }
+        // 続くフレームは差分
+        for result in &results[1..3] {
+            assert!(!result.is_keyframe);
+            let types = nal_types(result);
+            assert!(types.contains(&nal::NAL_TYPE_SLICE), "{:?}", types);
+            assert!(!types.contains(&nal::NAL_TYPE_IDR), "{:?}", types);
+        }
+        assert_eq!(
+            results[1].duration,
+            Duration::from_nanos(frame_interval * 100)
+        );
+        // 要求されたキーフレームは理由付きで返る
+        assert!(results[3].is_keyframe);
+        assert_eq!(results[3].keyframe_reason, Some(KeyframeReason::Pli));
+        assert!(nal_types(&results[3]).contains(&nal::NAL_TYPE_IDR));
+    }
+
+    #[test]
+    fn test_factory_builder() {
+        let factory = OpenH264EncoderFactory::new()
+            .with_bitrate(2_000_000)
+            .with_max_frame_rate(30.0)
+            .with_rate_control_mode(RateControlMode::Bitrate)
+            .with_intra_period(120)
+            .with_num_threads(0)
+            .with_capture_time_sei(true);
+        let config = factory.config();
+        assert_eq!(config.bitrate_bps, Some(2_000_000));
+        assert_eq!(config.max_frame_rate, 30.0);
+        assert!(matches!(config.rate_control_mode, RateControlMode::Bitrate));
+        assert_eq!(config.intra_period, Some(120));
+        assert_eq!(config.num_threads, Some(1));
+        assert!(config.capture_time_sei);
+    }
+}
@@ -48,6 +48,46 @@ pub fn rgba_to_yuv420(rgba: &[u8], width: usize, height: usize, src_width: usize
     buffer
 }
 
+/// BGRA形式の画像データをYUV420形式に変換する（libyuv使用）
+///
+/// 引数と戻り値は [`rgba_to_yuv420`] と同じ（入力の並びだけが異なる）
+pub fn bgra_to_yuv420(bgra: &[u8], width: usize, height: usize, src_width: usize) -> Vec<u8> {
+    let y_plane_size = width * height;
+    let uv_plane_size = y_plane_size / 4;
+    let total_size = y_plane_size + 2 * uv_plane_size;
+
+    let mut y = vec![0u8; y_plane_size];
+    let mut u = vec![0u8; uv_plane_size];
+    let mut v = vec![0u8; uv_plane_size];
+
+    // libyuvのARGBToI420を使用
+    // ARGBはメモリ上ではBGRAと同じ順序（B, G, R, A）
+    unsafe {
+        let result = libyuv_sys::ARGBToI420(
+            bgra.as_ptr(),
+            (src_width * 4) as i32,
+            y.as_mut_ptr(),
+            width as i32,
+            u.as_mut_ptr(),
+            (width / 2) as i32,
+            v.as_mut_ptr(),
+            (width / 2) as i32,
+            width as i32,
+            height as i32,
+        );
+
+        if result != 0 {
+            tracing::warn!("libyuv ARGBToI420 failed with error code: {}", result);
+        }
+    }
+
+    let mut buffer = Vec::with_capacity(total_size);
+    buffer.extend_from_slice(&y);
+    buffer.extend_from_slice(&u);
+    buffer.extend_from_slice(&v);
+    buffer
+}
+
 /// RGBA形式の画像データをNV12形式に変換する（libyuv使用）
 ///
 /// # Arguments
@@ -93,3 +133,39 @@ pub fn rgba_to_nv12(rgba: &[u8], width: usize, height: usize, src_width: usize)
     buffer.extend_from_slice(&uv);
     buffer
 }
+
+/// BGRA形式の画像データをNV12形式に変換する（libyuv使用）
+///
+/// 引数と戻り値は [`rgba_to_nv12`] と同じ（入力の並びだけが異なる）
+pub fn bgra_to_nv12(bgra: &[u8], width: usize, height: usize, src_width: usize) -> Vec<u8> {
+    let y_plane_size = width * height;
+    let uv_plane_size = y_plane_size / 2;
+    let total_size = y_plane_size + uv_plane_size;
+
+    let mut y = vec![0u8; y_plane_size];
+    let mut uv = vec![0u8; uv_plane_size];
+
+    // libyuvのARGBToNV12を使用
+    // ARGBはメモリ上ではBGRAと同じ順序（B, G, R, A）
+    unsafe {
+        let result = libyuv_sys::ARGBToNV12(
+            bgra.as_ptr(),
+            (src_width * 4) as i32,
+            y.as_mut_ptr(),
+            width as i32,
+            uv.as_mut_ptr(),
+            width as i32,
+            width as i32,
+            height as i32,
+        );
+
+        if result != 0 {
+            tracing::warn!("libyuv ARGBToNV12 failed with error code: {}", result);
+        }
+    }
+
+    let mut buffer = Vec::with_capacity(total_size);
+    buffer.extend_from_slice(&y);
+    buffer.extend_from_slice(&uv);
+    buffer
+}
@@ -0,0 +1,148 @@
+// エンコードワーカースレッドのコア固定と優先度
+//
+// ハイブリッド CPU（P コア / E コア）ではエンコードスレッドが E コアに載ってエンコード時間がぶれることがある。
+// ワーカースレッドの開始時に、指定されたコアへの固定（SetThreadAffinityMask）と優先度の変更
+// （SetThreadPriority）を行う。失敗してもエンコードは続ける。
+
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// ワーカースレッドの優先度
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WorkerThreadPriority {
+    /// 変更しない
+    #[default]
+    Normal,
+    AboveNormal,
+    /// 他のスレッドより常に優先される（負荷が高いと UI やキャプチャが詰まるので注意）
+    TimeCritical,
+}
+
+impl FromStr for WorkerThreadPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "normal" => Ok(Self::Normal),
+            "above-normal" | "above_normal" | "abovenormal" => Ok(Self::AboveNormal),
+            "time-critical" | "time_critical" | "timecritical" => Ok(Self::TimeCritical),
+            other => Err(format!("unsupported encoder thread priority: {}", other)),
+        }
+    }
+}
+
+/// ワーカースレッドのスケジューリング設定（デフォルトは固定なし・優先度変更なし）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkerThreadConfig {
+    /// SetThreadAffinityMask に渡すマスク（None なら固定しない）
+    pub affinity_mask: Option<usize>,
+    pub priority: WorkerThreadPriority,
+}
+
+impl WorkerThreadConfig {
+    /// "2,3" や "4-7" のようなコア番号の指定をアフィニティマスクに変換する
+    pub fn parse_affinity(s: &str) -> std::result::Result<usize, String> {
+        let mut cores = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let parse = |v: &str| match v.trim().parse::<u32>() {
+                Ok(core) if core < usize::BITS => Ok(core),
+                Ok(core) => Err(format!("core number out of range: {}", core)),
+                Err(_) => Err(format!("invalid core number: {}", v)),
+            };
+            match part.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    if start > end {
+                        return Err(format!("invalid core range: {}", part));
+                    }
+                    cores.extend(start..=end);
+                }
+                None => cores.push(parse(part)?),
+            }
+        }
+        if cores.is_empty() {
+            return Err("no cores specified".to_string());
+        }
+        Ok(cores.iter().fold(0, |mask, core| mask | 1 << core))
+    }
+
+    /// 呼び出し元のスレッドに設定を適用する（ワーカースレッドの先頭で呼ぶ）
+    pub fn apply_to_current_thread(&self, name: &str) {
+        if let Some(mask) = self.affinity_mask {
+            match set_affinity(mask) {
+                Ok(()) => info!("{}: pinned to cores (mask {:#x})", name, mask),
+                Err(e) => warn!("{}: failed to pin to cores: {}", name, e),
+            }
+        }
+        if self.priority != WorkerThreadPriority::Normal {
+            match set_priority(self.priority) {
+                Ok(()) => info!("{}: thread priority set to {:?}", name, self.priority),
+                Err(e) => warn!(
+                    "{}: failed to set thread priority {:?}: {}",
+                    name, self.priority, e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn set_affinity(mask: usize) -> anyhow::Result<()> {
+    use windows::Win32::System::Threading::{GetCurrentThread, SetThreadAffinityMask};
+    // 戻り値は以前のマスク（0 なら失敗。存在しないコアだけを指定した場合など）
+    let previous = unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) };
+    if previous == 0 {
+        anyhow::bail!(
+            "SetThreadAffinityMask({:#x}) failed: {}",
+            mask,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn set_priority(priority: WorkerThreadPriority) -> anyhow::Result<()> {
+    use windows::Win32::System::Threading::{
+        GetCurrentThread, SetThreadPriority, THREAD_PRIORITY_ABOVE_NORMAL, THREAD_PRIORITY_NORMAL,
+        THREAD_PRIORITY_TIME_CRITICAL,
+    };
+    let value = match priority {
+        WorkerThreadPriority::Normal => THREAD_PRIORITY_NORMAL,
+        WorkerThreadPriority::AboveNormal => THREAD_PRIORITY_ABOVE_NORMAL,
+        WorkerThreadPriority::TimeCritical => THREAD_PRIORITY_TIME_CRITICAL,
+    };
+    unsafe { SetThreadPriority(GetCurrentThread(), value)? };
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn set_affinity(_mask: usize) -> anyhow::Result<()> {
+    anyhow::bail!("thread affinity is only supported on Windows")
+}
+
+#[cfg(not(windows))]
+fn set_priority(_priority: WorkerThreadPriority) -> anyhow::Result<()> {
+    anyhow::bail!("thread priority is only supported on Windows")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_affinity() {
+        assert_eq!(
+            WorkerThreadConfig::parse_affinity("4-6, 2,5"),
+            Ok(0b111_0100)
+        );
+        assert!(WorkerThreadConfig::parse_affinity("3-1").is_err());
+        assert!(WorkerThreadConfig::parse_affinity("a").is_err());
+        assert!(WorkerThreadConfig::parse_affinity("").is_err());
+        assert!(WorkerThreadConfig::parse_affinity("64").is_err());
+        assert_eq!(
+            "above-normal".parse::<WorkerThreadPriority>(),
+            Ok(WorkerThreadPriority::AboveNormal)
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::Result;
+use core_types::{AudioCaptureMessage, CaptureMessage, CaptureTarget, ServiceError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use video_capture::WindowIdentity;
+
+/// ウィンドウ再出現のポーリング間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// キャプチャ対象ウィンドウが閉じられたら、同じアプリのウィンドウが再び現れるのを待って
+/// キャプチャ（映像・音声）を新しい HWND で再開する
+/// `audio_capture_cmd_tx` が None の場合は映像のみ再開する
+/// `restart_on_stall` が true の場合、フレームが届かなくなったキャプチャを同じ HWND で開始し直す
+/// 再開の指示を送れなくても監視は続け、`error_rx` が閉じたときだけ終了する
+/// `match_process_only` が true の場合、タイトルもクラス名も一致しなければ同じプロセスのウィンドウで再開する
+pub async fn run_capture_supervisor(
+    target_hwnd: Arc<AtomicU64>,
+    mut error_rx: mpsc::UnboundedReceiver<ServiceError>,
+    capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+    audio_capture_cmd_tx: Option<mpsc::Sender<AudioCaptureMessage>>,
+    reappear_timeout: Duration,
+    restart_on_stall: bool,
+    match_process_only: bool,
+) -> Result<()> {
+    // 閉じられた後は HWND から情報を引けないため、開始時に識別情報を控えておく
+    let mut identity = video_capture::window_identity(target_hwnd.load(Ordering::Relaxed));
+    match &identity {
+        Some(identity) => info!("Capture supervisor watching {:?}", identity),
+        // キャプチャ対象が選ばれたら識別情報を取る
+        None if target_hwnd.load(Ordering::Relaxed) == 0 => {}
+        None => {
+            warn!("Capture target window could not be identified, automatic restart is disabled")
+        }
+    }
+
+    // クライアントの要求でキャプチャ対象が切り替わったら識別情報を取り直す
+    let mut watched_hwnd = target_hwnd.load(Ordering::Relaxed);
+    let mut refresh = tokio::time::interval(POLL_INTERVAL);
+
+    loop {
+        let error = tokio::select! {
+            error = error_rx.recv() => match error {
+                Some(error) => error,
+                None => break,
+            },
+            _ = refresh.tick() => {
+                let hwnd = target_hwnd.load(Ordering::Relaxed);
+                if hwnd != watched_hwnd {
+                    watched_hwnd = hwnd;
+                    identity = video_capture::window_identity(hwnd);
+                    info!("Capture target changed, supervisor now watching {:?}", identity);
+                }
+                continue;
+            }
+        };
+
+        if let ServiceError::CaptureStalled { interval_ms } = error {
+            if !restart_on_stall {
+                warn!(
+                    "Capture stalled (frame interval {} ms), waiting for frames to resume",
+                    interval_ms
+                );
+                continue;
+            }
+            let hwnd = target_hwnd.load(Ordering::Relaxed);
+            warn!(
+                "Capture stalled (frame interval {} ms), restarting capture for HWND {}",
+                interval_ms, hwnd
+            );
+            if capture_cmd_tx
+                .send(CaptureMessage::Start {
+                    target: CaptureTarget::Window(hwnd),
+                })
+                .await
+                .is_err()
+            {
+                warn!("Failed to restart stalled capture: capture service is gone");
+            }
+            continue;
+        }
+
+        if error != ServiceError::WindowGone {
+            warn!("Capture error reported: {}", error);
+            continue;
+        }
+
+        let Some(current) = identity.clone() else {
+            warn!("Capture target window closed, but it cannot be restarted (unknown window)");
+            continue;
+        };
+
+        let old_hwnd = target_hwnd.load(Ordering::Relaxed);
+        info!(
+            "Capture target window closed, waiting up to {:?} for {:?} to reappear",
+            reappear_timeout, current
+        );
+
+        let Some(new_hwnd) =
+            wait_for_window(&current, old_hwnd, reappear_timeout, match_process_only).await
+        else {
+            warn!(
+                "Window {:?} did not reappear within {:?}, capture stays stopped",
+                current, reappear_timeout
+            );
+            continue;
+        };
+
+        info!(
+            "Window reappeared (HWND: {} -> {}), restarting capture",
+            old_hwnd, new_hwnd
+        );
+        target_hwnd.store(new_hwnd, Ordering::Relaxed);
+        watched_hwnd = new_hwnd;
+        if capture_cmd_tx
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(new_hwnd),
+            })
+            .await
+            .is_err()
+        {
+            warn!("Failed to restart capture: capture service is gone");
+            continue;
+        }
+        if let Some(audio_capture_cmd_tx) = &audio_capture_cmd_tx {
+            if audio_capture_cmd_tx
+                .send(AudioCaptureMessage::Start { hwnd: new_hwnd })
+                .await
+                .is_err()
+            {
+                warn!("Failed to restart audio capture: audio capture service is gone");
+            }
+        }
+
+        // タイトルが変わっている場合もあるので識別情報を更新
+        if let Some(new_identity) = video_capture::window_identity(new_hwnd) {
+            identity = Some(new_identity);
+        }
+    }
+
+    Ok(())
+}
+
+/// 識別情報に一致する（閉じられたものとは別の）ウィンドウが現れるまで待つ
+async fn wait_for_window(
+    identity: &WindowIdentity,
+    old_hwnd: u64,
+    timeout: Duration,
+    match_process_only: bool,
+) -> Option<u64> {
+    let deadline = Instant::now() + timeout;
+    let mut interval = tokio::time::interval(POLL_INTERVAL);
+    while Instant::now() < deadline {
+        interval.tick().await;
+        match video_capture::find_window(identity, match_process_only) {
+            Some(hwnd) if hwnd != old_hwnd => return Some(hwnd),
+            _ => {}
+        }
+    }
+    None
+}
@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use core_types::{
+    AudioCaptureMessage, CaptureMessage, CaptureTarget, CaptureTargetPayload, KeyframeReason,
+    VideoStreamMessage,
+};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// 切り替え先のキャプチャが最初のフレームを返すまで待つ時間の上限
+const FIRST_FRAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// クライアントの要求でキャプチャ対象のウィンドウを切り替える
+pub struct CaptureTargetSwitcher {
+    target_hwnd: Arc<AtomicU64>,
+    capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+    /// None の場合は音声を切り替えない（エンドポイント指定時）
+    audio_capture_cmd_tx: Option<mpsc::Sender<AudioCaptureMessage>>,
+    video_stream_msg_tx: mpsc::Sender<VideoStreamMessage>,
+}
+
+impl CaptureTargetSwitcher {
+    pub fn new(
+        target_hwnd: Arc<AtomicU64>,
+        capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+        audio_capture_cmd_tx: Option<mpsc::Sender<AudioCaptureMessage>>,
+        video_stream_msg_tx: mpsc::Sender<VideoStreamMessage>,
+    ) -> Self {
+        Self {
+            target_hwnd,
+            capture_cmd_tx,
+            audio_capture_cmd_tx,
+            video_stream_msg_tx,
+        }
+    }
+
+    /// 現在のキャプチャ対象
+    pub fn current(&self) -> CaptureTargetPayload {
+        let hwnd = self.target_hwnd.load(Ordering::Relaxed);
+        CaptureTargetPayload {
+            hwnd,
+            title: video_capture::window_identity(hwnd).map(|identity| identity.title),
+            error: None,
+        }
+    }
+
+    /// HWND（なければタイトル）で指定されたウィンドウに切り替える
+    /// 失敗した場合はキャプチャ対象を変えずに理由を返す
+    pub async fn switch(&self, hwnd: Option<u64>, title: Option<String>) -> CaptureTargetPayload {
+        let result = match resolve_window(hwnd, title.as_deref()) {
+            Ok(hwnd) => self.start(hwnd).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => self.current(),
+            Err(e) => {
+                warn!("Failed to switch capture target: {:#}", e);
+                CaptureTargetPayload {
+                    error: Some(format!("{:#}", e)),
+                    ..self.current()
+                }
+            }
+        }
+    }
+
+    async fn start(&self, hwnd: u64) -> Result<()> {
+        let old_hwnd = self.target_hwnd.load(Ordering::Relaxed);
+        info!("Switching capture target (HWND: {} -> {})", old_hwnd, hwnd);
+
+        if let Err(e) = start_capture(
+            &self.capture_cmd_tx,
+            CaptureTarget::Window(hwnd),
+            FIRST_FRAME_TIMEOUT,
+        )
+        .await
+        {
+            // 前のセッションは止まっているので、元の対象でキャプチャをやり直す
+            if old_hwnd != 0
+                && self
+                    .capture_cmd_tx
+                    .send(CaptureMessage::Start {
+                        target: CaptureTarget::Window(old_hwnd),
+                    })
+                    .await
+                    .is_err()
+            {
+                warn!("Failed to restore capture target (HWND: {})", old_hwnd);
+            }
+            return Err(e);
+        }
+        self.target_hwnd.store(hwnd, Ordering::Relaxed);
+
+        // 映像は切り替わっているので、音声を切り替えられなくても失敗にはしない
+        if let Some(audio_capture_cmd_tx) = &self.audio_capture_cmd_tx {
+            if audio_capture_cmd_tx
+                .send(AudioCaptureMessage::Start { hwnd })
+                .await
+                .is_err()
+            {
+                warn!("Failed to restart audio capture: audio capture service is gone");
+            }
+        }
+
+        // 切り替え直後の映像をすぐに表示できるようにキーフレームを要求
+        if self
+            .video_stream_msg_tx
+            .send(VideoStreamMessage::RequestKeyframe {
+                reason: KeyframeReason::CaptureTargetSwitch,
+            })
+            .await
+            .is_err()
+        {
+            warn!("Failed to request keyframe after capture target switch");
+        }
+        Ok(())
+    }
+}
+
+/// キャプチャ対象を切り替え、新しい対象の最初のフレームが届くまで待つ
+///
+/// CaptureService は Start を受けると既存のセッションを止めてから新しいセッションを始め、
+/// 続くフレーム要求には新しいセッションの次のフレームで応える（開始に失敗していれば要求をすぐに閉じる）
+async fn start_capture(
+    capture_cmd_tx: &mpsc::Sender<CaptureMessage>,
+    target: CaptureTarget,
+    timeout: Duration,
+) -> Result<()> {
+    capture_cmd_tx
+        .send(CaptureMessage::Start { target })
+        .await
+        .context("Failed to restart capture service")?;
+    let (tx, rx) = oneshot::channel();
+    capture_cmd_tx
+        .send(CaptureMessage::RequestFrame { tx })
+        .await
+        .context("Failed to restart capture service")?;
+    match tokio::time::timeout(timeout, rx).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(_)) => anyhow::bail!("Capture failed to start"),
+        Err(_) => anyhow::bail!("No frame was captured within {:?}", timeout),
+    }
+}
+
+/// 切り替え先のウィンドウを決める（HWND が有効か、タイトルに一致するウィンドウがあるか）
+fn resolve_window(hwnd: Option<u64>, title: Option<&str>) -> Result<u64> {
+    if let Some(hwnd) = hwnd {
+        anyhow::ensure!(
+            video_capture::window_identity(hwnd).is_some(),
+            "Window not found: HWND {}",
+            hwnd
+        );
+        return Ok(hwnd);
+    }
+    match title.map(str::trim).filter(|title| !title.is_empty()) {
+        Some(title) => video_capture::find_window_by_title(title)
+            .with_context(|| format!("Window not found: title {:?}", title)),
+        None => anyhow::bail!("Either hwnd or title must be specified"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_types::{Frame, PixelFormat};
+
+    fn frame() -> Frame {
+        Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![0; 16]),
+            windows_timespan: 0,
+            fps: 30,
+            format: PixelFormat::Bgra8,
+            dirty_fraction: None,
+            sequence: 1,
+            scale_to: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_start_capture_waits_for_first_frame() {
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let service = tokio::spawn(async move {
+            assert!(matches!(
+                capture_cmd_rx.recv().await,
+                Some(CaptureMessage::Start {
+                    target: CaptureTarget::Window(42)
+                })
+            ));
+            match capture_cmd_rx.recv().await {
+                Some(CaptureMessage::RequestFrame { tx }) => tx.send(frame()).unwrap(),
+                _ => panic!("expected a frame request after Start"),
+            }
+        });
+
+        start_capture(
+            &capture_cmd_tx,
+            CaptureTarget::Window(42),
+            FIRST_FRAME_TIMEOUT,
+        )
+        .await
+        .unwrap();
+        service.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_start_capture_reports_failed_start() {
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let service = tokio::spawn(async move {
+            while let Some(msg) = capture_cmd_rx.recv().await {
+                // 開始に失敗した CaptureService はフレーム要求を待たせずに閉じる
+                if let CaptureMessage::RequestFrame { tx } = msg {
+                    drop(tx);
+                }
+            }
+        });
+
+        let result = start_capture(
+            &capture_cmd_tx,
+            CaptureTarget::Window(42),
+            FIRST_FRAME_TIMEOUT,
+        )
+        .await;
+        assert!(result.is_err());
+        drop(capture_cmd_tx);
+        service.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_start_capture_times_out_without_frames() {
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let service = tokio::spawn(async move {
+            // フレーム要求を抱えたままフレームが来ない
+            let mut pending = Vec::new();
+            while let Some(msg) = capture_cmd_rx.recv().await {
+                if let CaptureMessage::RequestFrame { tx } = msg {
+                    pending.push(tx);
+                }
+            }
+        });
+
+        let result = start_capture(
+            &capture_cmd_tx,
+            CaptureTarget::Window(42),
+            FIRST_FRAME_TIMEOUT,
+        )
+        .await;
+        assert!(result.is_err());
+        drop(capture_cmd_tx);
+        service.await.unwrap();
+    }
+}
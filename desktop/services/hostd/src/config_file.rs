@@ -0,0 +1,374 @@
+// 設定ファイル（TOML）の読み込み
+//
+// キーは CLI のフラグ名から `--` を除いたもの（例: `--llm-port 9000` は `llm-port = 9000`）。
+// 値の優先順位は CLI > 環境変数 > 設定ファイル > 遅延プロファイル > 既定値で、ファイルで共有した設定を
+// 手元のフラグで一部だけ上書きできる。起動ログには既定値以外の値とその出どころを出す。
+// ファイルや環境変数で有効にした bool の設定を CLI で無効にできるよう、bool のフラグは `--mock=false` の
+// ように値も受け付ける。
+
+use anyhow::{Context, Result};
+use clap::builder::BoolishValueParser;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches};
+use core_types::{
+    AudioCaptureConfig, AudioEncoderConfig, CaptureConfig, PipelineConfig, VideoCodec,
+    VideoEncoderConfig, WebRtcConfig,
+};
+use serde::Deserialize;
+use std::path::Path;
+use tracing::info;
+
+use crate::host::HostConfig;
+use crate::latency_profile::LatencyProfile;
+
+/// 設定値の出どころ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    /// `--latency-profile` のプリセット
+    LatencyProfile(LatencyProfile),
+    ConfigFile,
+    Environment,
+    CommandLine,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::LatencyProfile(profile) => write!(f, "latency profile {}", profile),
+            ConfigSource::ConfigFile => write!(f, "config file"),
+            ConfigSource::Environment => write!(f, "environment"),
+            ConfigSource::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// CLI で解析した設定に設定ファイル `path` の値を重ねる（CLI と環境変数で指定した値はそのまま）
+/// `matches` は `cli` を解析した結果（値の出どころの判定に使う）
+/// 各値の出どころを起動ログに出す
+pub fn load_config(
+    path: Option<&Path>,
+    cli: HostConfig,
+    matches: &ArgMatches,
+) -> Result<HostConfig> {
+    let (config, sources) = match path {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read config file {:?}", path))?;
+            merge_config_file(cli, matches, &text)
+                .with_context(|| format!("Invalid config file {:?}", path))?
+        }
+        None => merge_config_file(cli, matches, "")?,
+    };
+
+    if let Some(path) = path {
+        info!("Loaded config file {:?}", path);
+    }
+    if let Some(profile) = &config.latency_profile {
+        info!("Latency profile: {}", profile);
+    }
+    for (key, value, source) in &sources {
+        if *source != ConfigSource::Default {
+            info!("Config: {} = {} ({})", key, value, source);
+        }
+    }
+    Ok(config)
+}
+
+/// クライアントの GetPipelineConfig に返す実効設定を、重ねた後の設定から組み立てる
+/// `capture` と `audio_capture` は hostd が各サービスに渡した設定
+pub fn pipeline_config(
+    config: &HostConfig,
+    capture: CaptureConfig,
+    audio_capture: AudioCaptureConfig,
+) -> Result<PipelineConfig> {
+    Ok(PipelineConfig {
+        capture,
+        video_encoder: VideoEncoderConfig {
+            codec: VideoCodec::H264,
+            mode: config.encoder_mode.clone(),
+            force_software: config.force_software,
+            device: config.encoder_device.clone(),
+            color_matrix: config.color_matrix.clone(),
+            color_range: config.color_range.clone(),
+            capture_time_sei: config.capture_time_sei,
+        },
+        audio_capture,
+        audio_encoder: AudioEncoderConfig {
+            frame_duration_ms: config.opus_frame_ms,
+            application: config.opus_application.clone(),
+        },
+        webrtc: WebRtcConfig {
+            audio: !config.no_audio,
+            keyframe_coalesce_ms: config.keyframe_coalesce_ms,
+            pacing_kbps: config.pacing_kbps.filter(|kbps| *kbps > 0),
+            dscp: config.dscp.clone(),
+        },
+    })
+}
+
+/// 設定ファイルの内容 `text` と遅延プロファイルを `cli` に重ね、各キーの値と出どころを返す
+fn merge_config_file(
+    cli: HostConfig,
+    matches: &ArgMatches,
+    text: &str,
+) -> Result<(HostConfig, Vec<(String, serde_json::Value, ConfigSource)>)> {
+    let file: toml::Table = toml::from_str(text)?;
+    // 未知のキーや型の誤りをここで検出する（エラーにはキー名が含まれる）
+    HostConfig::deserialize(toml::Value::Table(file.clone()))?;
+
+    let mut merged = serde_json::to_value(&cli)?;
+    for (key, value) in &file {
+        if source_of(matches, key) == ConfigSource::Default {
+            merged[key.as_str()] = serde_json::to_value(value)?;
+        }
+    }
+    // プロファイルはどこでも指定していない設定だけを埋める
+    let profile = merged["latency-profile"]
+        .as_str()
+        .map(str::parse::<LatencyProfile>)
+        .transpose()
+        .map_err(anyhow::Error::msg)?;
+    let mut profile_keys = Vec::new();
+    for (key, value) in profile.iter().flat_map(|profile| profile.settings()) {
+        if source_of(matches, key) == ConfigSource::Default && !file.contains_key(key) {
+            merged[key] = value;
+            profile_keys.push(key);
+        }
+    }
+    let config: HostConfig =
+        serde_json::from_value(merged).context("Failed to apply config file")?;
+    let mut sources = config_sources(&config, matches, &file)?;
+    for (key, _, source) in &mut sources {
+        if let Some(profile) = profile.filter(|_| profile_keys.iter().any(|k| *k == key.as_str())) {
+            *source = ConfigSource::LatencyProfile(profile);
+        }
+    }
+    Ok((config, sources))
+}
+
+/// 設定の各キーの値と出どころ
+fn config_sources(
+    config: &HostConfig,
+    matches: &ArgMatches,
+    file: &toml::Table,
+) -> Result<Vec<(String, serde_json::Value, ConfigSource)>> {
+    let serde_json::Value::Object(values) = serde_json::to_value(config)? else {
+        anyhow::bail!("HostConfig is not serialized as a map");
+    };
+    Ok(values
+        .into_iter()
+        .map(|(key, value)| {
+            let source = match source_of(matches, &key) {
+                ConfigSource::Default if file.contains_key(&key) => ConfigSource::ConfigFile,
+                source => source,
+            };
+            (key, value, source)
+        })
+        .collect())
+}
+
+/// 設定ファイルのキー（kebab-case）の値を CLI と環境変数のどちらで指定したか
+fn source_of(matches: &ArgMatches, key: &str) -> ConfigSource {
+    // clap の引数 ID はフィールド名（snake_case）
+    match matches.value_source(&key.replace('-', "_")) {
+        Some(ValueSource::CommandLine) => ConfigSource::CommandLine,
+        Some(ValueSource::EnvVariable) => ConfigSource::Environment,
+        _ => ConfigSource::Default,
+    }
+}
+
+/// bool のフラグに `--flag=false` の形で値も指定できるようにする（`--flag` だけなら true）
+/// 環境変数と同じく true/false のほか yes/no, on/off, 1/0 も受け付ける
+pub(crate) fn bool_flag_takes_value(arg: Arg) -> Arg {
+    if !matches!(arg.get_action(), ArgAction::SetTrue) {
+        return arg;
+    }
+    arg.action(ArgAction::Set)
+        .value_parser(BoolishValueParser::new())
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_value("false")
+        .default_missing_value("true")
+}
+
+/// `FromStr` と `Display` で文字列として読み書きする値（`#[serde(with = ...)]` で使う）
+pub(crate) mod display_from_str {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn parse(args: &[&str]) -> (HostConfig, ArgMatches) {
+        let matches = HostConfig::command()
+            .try_get_matches_from(std::iter::once("hostd").chain(args.iter().copied()))
+            .unwrap();
+        (HostConfig::from_arg_matches(&matches).unwrap(), matches)
+    }
+
+    fn source(sources: &[(String, serde_json::Value, ConfigSource)], key: &str) -> ConfigSource {
+        sources.iter().find(|(k, _, _)| k == key).unwrap().2
+    }
+
+    #[test]
+    fn test_config_file_values_are_applied() {
+        let (cli, matches) = parse(&[]);
+        let (config, sources) = merge_config_file(
+            cli,
+            &matches,
+            r#"
+                session-id = "living-room"
+                llm-port = 9000
+                mock = true
+                mock-pattern = "solid-palette"
+                replay-secs = 30
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.session_id, "living-room");
+        assert_eq!(config.llm_port, 9000);
+        assert!(config.mock);
+        assert_eq!(
+            config.mock_pattern,
+            video_capture_mock::MockPattern::SolidPalette
+        );
+        assert_eq!(config.replay_secs, Some(30));
+        assert_eq!(source(&sources, "llm-port"), ConfigSource::ConfigFile);
+        assert_eq!(source(&sources, "cloudflare-url"), ConfigSource::Default);
+    }
+
+    #[test]
+    fn test_command_line_overrides_config_file() {
+        let (cli, matches) = parse(&["--llm-port", "9100", "--session-id", "desk"]);
+        let (config, sources) = merge_config_file(
+            cli,
+            &matches,
+            "llm-port = 9000\nsession-id = \"living-room\"\ninput-rate = 30\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.llm_port, 9100);
+        assert_eq!(config.session_id, "desk");
+        assert_eq!(config.input_rate, 30);
+        assert_eq!(source(&sources, "llm-port"), ConfigSource::CommandLine);
+        assert_eq!(source(&sources, "input-rate"), ConfigSource::ConfigFile);
+    }
+
+    #[test]
+    fn test_command_line_can_turn_off_bool_from_config_file() {
+        let (cli, matches) = parse(&["--mock=false", "--keep-awake"]);
+        let (config, sources) = merge_config_file(
+            cli,
+            &matches,
+            "mock = true
+keep-awake = false
+on-demand = true
+",
+        )
+        .unwrap();
+
+        assert!(!config.mock);
+        assert_eq!(source(&sources, "mock"), ConfigSource::CommandLine);
+        assert!(config.keep_awake);
+        assert!(config.on_demand);
+        assert_eq!(source(&sources, "on-demand"), ConfigSource::ConfigFile);
+        assert!(!HostConfig::default().mock);
+    }
+
+    #[test]
+    fn test_pipeline_config_reflects_config_file() {
+        let (cli, matches) = parse(&["--opus-frame-ms", "20"]);
+        let (config, _) = merge_config_file(
+            cli,
+            &matches,
+            "no-audio = true\nencoder-mode = \"quality\"\nkeyframe-coalesce-ms = 0\n",
+        )
+        .unwrap();
+        let pipeline = pipeline_config(
+            &config,
+            CaptureConfig::default(),
+            AudioCaptureConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(pipeline.video_encoder.mode, "quality");
+        assert_eq!(pipeline.audio_encoder.frame_duration_ms, 20);
+        assert!(!pipeline.webrtc.audio);
+        assert_eq!(pipeline.webrtc.keyframe_coalesce_ms, 0);
+        // 指定していない値は PipelineConfig 側の既定値と同じ
+        let defaults = PipelineConfig::default();
+        assert_eq!(
+            pipeline.video_encoder.color_matrix,
+            defaults.video_encoder.color_matrix
+        );
+        assert_eq!(
+            pipeline.audio_encoder.application,
+            defaults.audio_encoder.application
+        );
+    }
+
+    #[test]
+    fn test_invalid_config_file_is_rejected() {
+        let (cli, matches) = parse(&[]);
+        // 未知のキー
+        let error = merge_config_file(cli.clone(), &matches, "llm_prot = 9000").unwrap_err();
+        assert!(format!("{:#}", error).contains("llm_prot"), "{:#}", error);
+        // 型の誤り
+        assert!(merge_config_file(cli.clone(), &matches, "llm-port = \"9000\"").is_err());
+        // 解釈できない値
+        assert!(merge_config_file(cli, &matches, "mock-pattern = \"plaid\"").is_err());
+    }
+
+    #[test]
+    fn test_latency_profile_fills_unset_values() {
+        let (cli, matches) = parse(&["--latency-profile", "ultra-low", "--opus-frame-ms", "20"]);
+        let (config, sources) =
+            merge_config_file(cli, &matches, "frame-queue-depth = 4\n").unwrap();
+
+        let profile = ConfigSource::LatencyProfile(LatencyProfile::UltraLow);
+        assert_eq!(config.encoder_mode, "low-latency");
+        assert_eq!(config.audio_buffer_ms, 20);
+        assert_eq!(config.opus_application, "lowdelay");
+        assert_eq!(source(&sources, "opus-application"), profile);
+        // フラグと設定ファイルで指定した値はプロファイルより優先する
+        assert_eq!(config.opus_frame_ms, 20);
+        assert_eq!(source(&sources, "opus-frame-ms"), ConfigSource::CommandLine);
+        assert_eq!(config.frame_queue_depth, 4);
+        assert_eq!(
+            source(&sources, "frame-queue-depth"),
+            ConfigSource::ConfigFile
+        );
+
+        // 設定ファイルでプロファイルを指定してもよい
+        let (cli, matches) = parse(&[]);
+        let (config, _) =
+            merge_config_file(cli.clone(), &matches, "latency-profile = \"quality\"\n").unwrap();
+        assert_eq!(config.encoder_mode, "quality");
+        assert_eq!(config.frame_queue_depth, 3);
+        assert!(merge_config_file(cli, &matches, "latency-profile = \"fast\"\n").is_err());
+    }
+}
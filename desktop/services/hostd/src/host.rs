@@ -0,0 +1,1778 @@
+// hostd をライブラリとして起動する API
+//
+// CLI（main.rs）はこの API の薄いラッパー。`Host::start` で各サービスとチャネルを組み立て、
+// 専用スレッドのランタイムで動かす（WebRTC は Send でないため）。返される `HostHandle` から
+// キャプチャ対象の切り替え、映像統計の購読、停止ができる。
+
+use anyhow::{Context, Result};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::pin;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+use audio_capture;
+use audio_capture_mock;
+use audio_encoder::OpusEncoderFactory;
+use audio_stream::{AudioStreamService, GapFill};
+use core_types::{
+    AudioCaptureConfig, AudioCaptureMessage, AudioEncoderConfig, AudioFrame, AudioSource,
+    AudioStreamMessage, CaptureBackend, CaptureConfig, CaptureMessage, CaptureSize, CaptureTarget,
+    CaptureTargetCommand, CaptureTargetPayload, DataChannelMessage, Frame, Metrics,
+    OutgoingDataChannelMessage, ServiceError, SignalingResponse, TaggerCommand, VideoCodec,
+    VideoEncoderConfig, VideoEncoderFactory, VideoStatsPayload, VideoStreamMessage, WebRtcConfig,
+};
+#[cfg(feature = "h264")]
+use encoder::h264::color::{ColorMatrix, ColorRange, ColorSpace};
+#[cfg(feature = "h264")]
+use encoder::h264::mmf::mf::{EncoderDeviceSelector, EncoderLatencyMode};
+#[cfg(feature = "h264")]
+use encoder::h264::mmf::MediaFoundationH264EncoderFactory;
+#[cfg(feature = "h264")]
+use encoder::h264::worker_thread::{WorkerThreadConfig, WorkerThreadPriority};
+use input::InputService;
+use signaling::SignalingClient;
+use tagger::{ImageOptions, TaggerService};
+use tagger_setup::{ModelSpec, ServerOptions, TaggerSetup};
+use video_capture;
+use video_capture_mock;
+use video_stream::VideoStreamService;
+use webrtc::loopback::run_loopback;
+use webrtc::{Dscp, OnDemandCapture, WebRtcService};
+
+use crate::capture_supervisor;
+use crate::capture_target::CaptureTargetSwitcher;
+use crate::keep_awake::KeepAwake;
+use crate::metrics_server;
+use crate::placeholder;
+use crate::shutdown::{join_or_abort, ShutdownSenders, SERVICE_STOP_TIMEOUT};
+use crate::startup_target::{resolve_startup_target, WindowSpec};
+use crate::web_client;
+
+/// 購読者ごとに溜められる映像統計の数（遅れた購読者は古いものから取りこぼす）
+const STATS_SUBSCRIBER_CAPACITY: usize = 16;
+
+/// hostd の設定（CLI の引数と同じ。ライブラリからは `HostConfig::default()` を書き換えて使う）
+/// 設定ファイル（TOML）のキーはフラグ名から `--` を除いたもの
+#[derive(Parser, Debug, Clone, Serialize, Deserialize)]
+#[command(mut_args = crate::config_file::bool_flag_takes_value)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HostConfig {
+    /// Cloudflare WebSocket URL (e.g., wss://example.com/api/signal)
+    #[arg(long, default_value = "ws://localhost:3000/api/signal")]
+    pub cloudflare_url: String,
+
+    /// Session ID
+    #[arg(long, default_value = "fixed")]
+    pub session_id: String,
+
+    /// Capture target window handle (HWND). Without any window option the REMOTERG_HWND,
+    /// REMOTERG_WINDOW_TITLE and REMOTERG_PROCESS_NAME environment variables are used, in that order
+    #[arg(long, default_value_t = 0)]
+    pub hwnd: u64,
+
+    /// Capture the window whose title contains this text (used when --hwnd is not given)
+    #[arg(long)]
+    pub window_title: Option<String>,
+
+    /// Capture a window of this process (e.g. "game.exe"); combined with --window-title if both are given
+    #[arg(long)]
+    pub process_name: Option<String>,
+
+    /// Capture a whole monitor by index (see --list-monitors) instead of a window
+    #[arg(long, conflicts_with = "primary_monitor")]
+    pub monitor: Option<usize>,
+
+    /// Capture the whole primary monitor instead of a window
+    #[arg(long)]
+    pub primary_monitor: bool,
+
+    /// Capture the monitor of a virtual display adapter instead of a window.
+    /// Requires an external IddCx-based virtual display driver to be installed and enabled
+    #[arg(long, conflicts_with_all = ["monitor", "primary_monitor"])]
+    pub virtual_display: bool,
+
+    /// Capture only the child window (control) of the target window whose class name or title
+    /// matches this text, e.g. the game canvas of an app with its own toolbars (see --list-child-windows)
+    #[arg(long, conflicts_with_all = ["monitor", "primary_monitor", "virtual_display"])]
+    pub child_window: Option<String>,
+
+    /// Use mock implementations for video and audio capture
+    #[arg(long)]
+    pub mock: bool,
+
+    /// Pattern of mock video frames (gradient, solid-palette)
+    #[arg(long, default_value = "gradient")]
+    #[serde(with = "crate::config_file::display_from_str")]
+    pub mock_pattern: video_capture_mock::MockPattern,
+
+    /// Number of mock video frames generated up front and looped
+    #[arg(long, default_value_t = video_capture_mock::DEFAULT_PREGENERATED_FRAMES)]
+    pub mock_frames: usize,
+
+    /// Width of the mock "source" screen used when the client does not request a size
+    #[arg(long, default_value_t = video_capture_mock::DEFAULT_SOURCE_SIZE.0)]
+    pub mock_source_width: u32,
+
+    /// Height of the mock "source" screen used when the client does not request a size
+    #[arg(long, default_value_t = video_capture_mock::DEFAULT_SOURCE_SIZE.1)]
+    pub mock_source_height: u32,
+
+    /// Port for local LLM server (llama-server)
+    #[arg(long, default_value_t = 8081)]
+    pub llm_port: u16,
+
+    /// Image format for screenshots sent to the local LLM: "png", "jpeg" or "webp" (lossless)
+    #[arg(long, default_value = "jpeg")]
+    pub tagger_image_format: String,
+
+    /// Longest edge (px) of screenshots sent to the local LLM; larger ones are downscaled (0 keeps the size)
+    #[arg(long, default_value_t = 1280)]
+    pub tagger_max_image_edge: u32,
+
+    /// Seconds to wait for a connection to the local LLM server
+    #[arg(long, env = "REMOTERG_TAGGER_CONNECT_TIMEOUT_SECS", default_value_t = tagger::DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    pub tagger_connect_timeout_secs: u64,
+
+    /// Seconds to wait for the local LLM to respond (while streaming, the limit between chunks)
+    #[arg(long, env = "REMOTERG_TAGGER_TIMEOUT_SECS", default_value_t = tagger::DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    pub tagger_timeout_secs: u64,
+
+    /// Times a request to the local LLM is retried after a connection failure, timeout or 5xx/429
+    #[arg(long, env = "REMOTERG_TAGGER_RETRIES", default_value_t = tagger::DEFAULT_MAX_RETRIES)]
+    pub tagger_retries: u32,
+
+    /// Milliseconds before the first retry of a local LLM request (doubled on each retry)
+    #[arg(long, default_value_t = tagger::DEFAULT_RETRY_BACKOFF.as_millis() as u64)]
+    pub tagger_retry_backoff_ms: u64,
+
+    /// Directory for saving screenshots
+    #[arg(long, env = "REMOTERG_SCREENSHOTS", default_value = "screenshots")]
+    pub screenshots_dir: String,
+
+    /// Rate (Hz) at which queued input is coalesced and injected (0 injects every message immediately)
+    #[arg(long, default_value_t = input::DEFAULT_INPUT_RATE_HZ)]
+    pub input_rate: u32,
+
+    /// What to do with remote input while the capture target is not the foreground window:
+    /// "refuse" (drop it), "activate" (bring the target to the front first) or "off" (inject anyway)
+    #[arg(long, env = "REMOTERG_FOCUS_POLICY", default_value = "refuse")]
+    pub focus_policy: String,
+
+    /// Sync clipboard text with the client: text copied on the host is sent to the client and text
+    /// from the client is set on the host clipboard (up to 64 KiB)
+    #[arg(long, env = "REMOTERG_CLIPBOARD_SYNC")]
+    pub clipboard_sync: bool,
+
+    /// Path to the llama-server executable or directory
+    #[arg(long, env = "REMOTERG_LLAMA_SERVER_PATH")]
+    pub llama_server_path: Option<String>,
+
+    /// Layers of the local LLM to offload to the GPU (999 offloads all); chosen from the free VRAM when unset
+    #[arg(long, env = "REMOTERG_LLM_GPU_LAYERS")]
+    pub llm_gpu_layers: Option<u32>,
+
+    /// Context size (tokens) of the local LLM
+    #[arg(long, env = "REMOTERG_LLM_CTX_SIZE", default_value_t = tagger_setup::DEFAULT_CTX_SIZE)]
+    pub llm_ctx_size: u32,
+
+    /// CPU threads used by the local LLM
+    #[arg(long, env = "REMOTERG_LLM_THREADS", default_value_t = tagger_setup::DEFAULT_THREADS)]
+    pub llm_threads: u32,
+
+    /// Additional local LLM that an analyze request can select by name, as NAME=PORT,MODEL_PATH,MMPROJ_PATH
+    /// (repeatable); its llama-server starts on the first request and stops when unused
+    #[arg(long, value_name = "NAME=PORT,MODEL,MMPROJ")]
+    pub llm_model: Vec<String>,
+
+    /// Seconds an additional local LLM (--llm-model) may stay unused before its llama-server is stopped
+    #[arg(long, default_value_t = tagger_setup::DEFAULT_MODEL_IDLE_TIMEOUT.as_secs())]
+    pub llm_model_idle_secs: u64,
+
+    /// Run a self-contained loopback session instead of connecting to the signaling server
+    #[arg(long)]
+    pub loopback: bool,
+
+    /// Duration of the loopback session in seconds
+    #[arg(long, default_value_t = 10)]
+    pub loopback_secs: u64,
+
+    /// Capture audio from this render endpoint (MMDevice ID) instead of the window's process
+    #[arg(long, env = "REMOTERG_AUDIO_DEVICE")]
+    pub audio_device: Option<String>,
+
+    /// Mix microphone input into the audio stream
+    #[arg(long)]
+    pub mic: bool,
+
+    /// Capture the microphone from this input device (MMDevice ID) instead of the default one
+    #[arg(long, env = "REMOTERG_MIC_DEVICE")]
+    pub mic_device: Option<String>,
+
+    /// Initial gain of the game audio when mixing with the microphone (0.0-4.0)
+    #[arg(long, default_value_t = 1.0)]
+    pub game_gain: f32,
+
+    /// Initial gain of the microphone (0.0-4.0)
+    #[arg(long, default_value_t = 1.0)]
+    pub mic_gain: f32,
+
+    /// WASAPI capture buffer duration in milliseconds (20-100); shorter buffers reduce audio latency
+    #[arg(long, default_value_t = 100)]
+    pub audio_buffer_ms: u32,
+
+    /// Poll the WASAPI capture buffer every 1ms instead of waiting for data-ready events
+    #[arg(long)]
+    pub audio_polling: bool,
+
+    /// Restart the process loopback when the captured window's process has been silent for this many
+    /// seconds and has started child processes since capture began, so audio from an engine launched
+    /// later by a launcher is picked up (0 disables)
+    #[arg(long, default_value_t = AudioCaptureConfig::DEFAULT_LATE_CHILD_SILENCE.as_secs())]
+    pub audio_child_silence_secs: u64,
+
+    /// Fill gaps in audio capture longer than 30ms with "silence" or "comfort-noise" (disabled when unset)
+    #[arg(long)]
+    pub audio_gap_fill: Option<String>,
+
+    /// Also write the captured audio (48kHz stereo f32, before Opus) to this WAV file for debugging
+    #[arg(long)]
+    pub dump_audio: Option<String>,
+
+    /// Maximum size of the audio dump in megabytes (about 4.5 minutes per 100MB)
+    #[arg(long, default_value_t = 100)]
+    pub dump_audio_max_mb: u64,
+
+    /// Also write the raw RGBA frames fed to the encoder to this file for debugging
+    #[arg(long)]
+    pub dump_video: Option<String>,
+
+    /// Maximum size of the video dump in megabytes (a 1080p frame is about 8MB)
+    #[arg(long, default_value_t = 1024)]
+    pub dump_video_max_mb: u64,
+
+    /// Burn frame index, fps, bitrate and capture timestamp into the top-left of the video
+    /// (for screen recordings attached to bug reports)
+    #[arg(long)]
+    pub debug_overlay: bool,
+
+    /// Seconds to wait for a closed capture window to reappear before giving up
+    #[arg(long, default_value_t = 60)]
+    pub window_reappear_timeout_secs: u64,
+
+    /// When a closed capture window reappears with neither its title nor its window class
+    /// matching, resume on any window of the same process (may pick a launcher or dialog)
+    #[arg(long, env = "REMOTERG_REAPPEAR_MATCH_PROCESS")]
+    pub reappear_match_process: bool,
+
+    /// Restart the capture session when frames stop arriving (static windows also stop producing frames)
+    #[arg(long)]
+    pub restart_on_capture_stall: bool,
+
+    /// Only capture and encode video and audio while a viewer is connected: capture starts on the first
+    /// connection and stops when the last viewer disconnects (microphone capture keeps running, but is not encoded)
+    #[arg(long, env = "REMOTERG_ON_DEMAND")]
+    pub on_demand: bool,
+
+    /// Capacity of the frame queue between capture and encoder (older frames are dropped when full)
+    #[arg(long, default_value_t = 3)]
+    pub frame_queue_depth: usize,
+
+    /// Maximum pixels per encoded frame; larger captures are scaled down keeping the aspect ratio (e.g. 2073600 for 1080p)
+    #[arg(long)]
+    pub max_encode_pixels: Option<u32>,
+
+    /// How to fit a requested capture size with a different aspect ratio: "stretch", "letterbox" or "crop"
+    #[arg(long, default_value = "stretch")]
+    pub aspect: String,
+
+    /// Pixel layout to capture in: "rgba" or "bgra" (BGRA skips the channel swap before H.264 encoding)
+    #[arg(long, default_value = "rgba")]
+    pub capture_format: String,
+
+    /// What to send while the captured window is covered by other windows: "capture-anyway" or "freeze-on-occlusion" (repeat the last uncovered frame)
+    #[arg(long, default_value = "capture-anyway")]
+    pub occlusion_policy: String,
+
+    /// What to do when capture stays black, as DRM-protected content does: "notify" (tell the client the
+    /// content cannot be captured) or "ignore". Protected content cannot be captured by any setting
+    #[arg(long, default_value = "notify")]
+    pub protected_content: String,
+
+    /// Track capture dirty regions and don't encode frames where nothing changed, cutting idle
+    /// bandwidth on static screens (keyframe requests are still answered)
+    #[arg(long)]
+    pub skip_unchanged_frames: bool,
+
+    /// Send full-size captured frames and let the hardware encoder's GPU preprocessor downscale
+    /// them, taking the resize off the capture thread (ignored when encoding in software)
+    #[arg(long, env = "REMOTERG_GPU_SCALING")]
+    pub gpu_scaling: bool,
+
+    /// Lower window capture to this fps while the captured window is not in the foreground (the
+    /// host user alt-tabbed away), restoring full fps on refocus (disabled if unset)
+    #[arg(long)]
+    pub background_fps: Option<u32>,
+
+    /// Opus frame duration in milliseconds (10, 20, 40, 60); longer frames save bandwidth at the cost of latency
+    #[arg(long, default_value_t = AudioEncoderConfig::DEFAULT_FRAME_DURATION_MS)]
+    pub opus_frame_ms: u32,
+
+    /// Opus application: "audio" (music and effects) or "lowdelay" (minimum algorithmic delay)
+    #[arg(long, default_value = AudioEncoderConfig::DEFAULT_APPLICATION)]
+    pub opus_application: String,
+
+    /// Preset for the latency-related settings: "ultra-low", "balanced" or "quality". Fills in
+    /// encoder-mode, frame-queue-depth, audio-buffer-ms, opus-frame-ms and opus-application unless
+    /// they are set explicitly (flags, environment or config file); applied when the config is loaded
+    #[arg(long, env = "REMOTERG_LATENCY_PROFILE")]
+    pub latency_profile: Option<String>,
+
+    /// Seconds without encoder output (while frames are queued) before the watchdog steps in
+    #[arg(long, default_value_t = 3)]
+    pub encode_stall_timeout_secs: u64,
+
+    /// Number of encoder recreations the watchdog attempts before giving up
+    #[arg(long, default_value_t = 3)]
+    pub encode_stall_retries: u32,
+
+    /// Treat this many consecutive identical (or tiny) encoded frames while the captured image is
+    /// changing as a frozen stream and try to recover (0 disables freeze detection)
+    #[arg(long, default_value_t = video_stream::DEFAULT_FREEZE_FRAMES)]
+    pub freeze_frames: u32,
+
+    /// Encoded frames smaller than this many bytes count as empty for freeze detection
+    #[arg(long, default_value_t = video_stream::DEFAULT_FREEZE_MIN_BYTES)]
+    pub freeze_min_bytes: usize,
+
+    /// Always encode H.264 in software (OpenH264), bypassing Media Foundation; a workaround for
+    /// hardware encoders that produce artifacts or fail on some drivers
+    #[arg(long, env = "REMOTERG_FORCE_SOFTWARE")]
+    pub force_software: bool,
+
+    /// Hardware H.264 encoder to use, by index or by name substring (e.g. "NVIDIA"); falls back to the first one
+    #[arg(long, env = "REMOTERG_ENCODER_DEVICE")]
+    pub encoder_device: Option<String>,
+
+    /// MF encoder mode: "low-latency" (no B-frames, for interactive streaming) or "quality" (allows B-frames)
+    #[arg(long, env = "REMOTERG_ENCODER_MODE", default_value = VideoEncoderConfig::DEFAULT_MODE)]
+    pub encoder_mode: String,
+
+    /// Pin the encoder worker thread to these CPU cores (e.g. "0-7" for the P-cores); not pinned if unset
+    #[arg(long, env = "REMOTERG_ENCODER_CORES")]
+    pub encoder_cores: Option<String>,
+
+    /// Encoder worker thread priority: "normal", "above-normal" or "time-critical"
+    #[arg(long, env = "REMOTERG_ENCODER_PRIORITY", default_value = "normal")]
+    pub encoder_priority: String,
+
+    /// Number of OpenH264 encoder threads (software or fallback encoding); defaults to the CPU core count (max 16)
+    #[arg(long, env = "REMOTERG_ENCODER_THREADS")]
+    pub encoder_threads: Option<u16>,
+
+    /// YUV matrix for the encoded stream: "bt709" (HD content) or "bt601" (SD content)
+    #[arg(long, env = "REMOTERG_COLOR_MATRIX", default_value = VideoEncoderConfig::DEFAULT_COLOR_MATRIX)]
+    pub color_matrix: String,
+
+    /// YUV range for the encoded stream: "limited" (16-235) or "full" (0-255)
+    #[arg(long, env = "REMOTERG_COLOR_RANGE", default_value = VideoEncoderConfig::DEFAULT_COLOR_RANGE)]
+    pub color_range: String,
+
+    /// Keep the last N seconds of encoded video for instant replay (saved on a SaveReplay request)
+    #[arg(long)]
+    pub replay_secs: Option<u64>,
+
+    /// Directory for saving instant replays
+    #[arg(long, env = "REMOTERG_REPLAYS", default_value = "replays")]
+    pub replay_dir: String,
+
+    /// Pace video sample writes to this bitrate (kbps) to smooth packet bursts (disabled if unset)
+    #[arg(long)]
+    pub pacing_kbps: Option<u32>,
+
+    /// Automatically step capture resolution/fps down on sustained congestion and back up on
+    /// sustained headroom along this ladder, highest quality first (e.g. "1920x1080@45,1280x720@30";
+    /// "default" uses 1920x1080@45,1920x1080@30,1280x720@30,960x540@30). Starts at the highest
+    /// rung that fits the configured capture size and fps; disabled if unset
+    #[arg(long, env = "REMOTERG_QUALITY_LADDER")]
+    pub quality_ladder: Option<String>,
+
+    /// Maximum time a video sample may be held back by pacing (ms)
+    #[arg(long, default_value_t = 50)]
+    pub pacing_max_delay_ms: u64,
+
+    /// Keep up to this much encoded video (ms) while the connection is being established and send it
+    /// as soon as it is ready (0 drops frames until connected)
+    #[arg(long, default_value_t = 2000)]
+    pub connect_buffer_ms: u64,
+
+    /// Drop encoded video frames captured more than this many ms ago instead of sending them late
+    /// (resumes at the next keyframe; disabled if unset)
+    #[arg(long)]
+    pub max_frame_age_ms: Option<u64>,
+
+    /// On a capture resolution/fps change, close the current GOP with a keyframe from the old
+    /// encoder and switch on the following frame, so the new stream starts on a clean IDR
+    #[arg(long)]
+    pub gop_aligned_resize: bool,
+
+    /// Send delta frames to a newly attached track before its first keyframe (by default they are
+    /// held back so late joiners never decode a frame without its reference)
+    #[arg(long)]
+    pub send_deltas_before_keyframe: bool,
+
+    /// Insert a user-data SEI carrying the capture timestamp before each H.264 frame, so clients
+    /// can measure latency from the bitstream (adds about 30 bytes per frame)
+    #[arg(long)]
+    pub capture_time_sei: bool,
+
+    /// Image streamed until a capture target is selected: "text" (a waiting message), "black",
+    /// "#RRGGBB" or "none"
+    #[arg(long, default_value = "text")]
+    pub placeholder: String,
+
+    /// Number of keyframes sent when a client connects (1 disables the extra ones); extra keyframes
+    /// cover a first keyframe lost while ICE/DTLS settles
+    #[arg(long, default_value_t = video_stream::DEFAULT_STARTUP_KEYFRAME_COUNT)]
+    pub startup_keyframes: u32,
+
+    /// Interval (ms) between the extra keyframes sent after the connection is ready
+    #[arg(long, default_value_t = video_stream::DEFAULT_STARTUP_KEYFRAME_SPACING.as_millis() as u64)]
+    pub startup_keyframe_spacing_ms: u64,
+
+    /// Resend the last keyframe at this rate while the stream is paused, so SFUs and browsers do not
+    /// tear down an idle video transport (0 disables)
+    #[arg(long, default_value_t = video_stream::DEFAULT_PAUSE_HEARTBEAT_FPS)]
+    pub pause_heartbeat_fps: u32,
+
+    /// Report the encoder as overloaded when frames wait longer than this (ms, rolling average)
+    /// before the encoder picks them up, and step the quality ladder down (0 disables)
+    #[arg(long, default_value_t = video_stream::DEFAULT_ENCODER_QUEUE_BUDGET.as_millis() as u64)]
+    pub encoder_queue_budget_ms: u64,
+
+    /// Record the session (H.264 + Opus) to this fragmented MP4 file from startup
+    #[arg(long)]
+    pub record: Option<String>,
+
+    /// Directory for recordings started from the client
+    #[arg(long, env = "REMOTERG_RECORDINGS", default_value = "recordings")]
+    pub record_dir: String,
+
+    /// Mark outgoing media packets with this DSCP value: 0-63, "ef", "afXY" or "csN" (unmarked if unset).
+    /// Windows ignores it unless a policy-based QoS rule for hostd.exe is configured (requires admin)
+    #[arg(long, env = "REMOTERG_DSCP")]
+    pub dscp: Option<String>,
+
+    /// Collect keyframe requests from viewers joining within this many ms and answer them with a
+    /// single keyframe, avoiding bitrate spikes when many viewers join at once (0 disables)
+    #[arg(long, default_value_t = WebRtcConfig::DEFAULT_KEYFRAME_COALESCE_MS)]
+    pub keyframe_coalesce_ms: u64,
+
+    /// Serve Prometheus metrics (encode fps, bitrate, drops, sessions, audio silence) at
+    /// http://0.0.0.0:<port>/metrics (disabled if unset)
+    #[arg(long, env = "REMOTERG_METRICS_PORT")]
+    pub metrics_port: Option<u16>,
+
+    /// Serve a minimal test client at http://127.0.0.1:<port>/ (8080 if no port is given) that connects
+    /// to --cloudflare-url as a viewer of --session-id and plays the stream
+    #[arg(long, num_args = 0..=1, default_missing_value = "8080")]
+    pub serve_client: Option<u16>,
+
+    /// Keep the host and its display from sleeping while at least one viewer is connected
+    #[arg(long)]
+    pub keep_awake: bool,
+
+    /// Stream video only, without capturing audio (also used automatically when audio fails)
+    #[arg(long)]
+    pub no_audio: bool,
+}
+
+impl Default for HostConfig {
+    /// CLI で何も指定しなかった場合の既定値（環境変数は読まない）
+    fn default() -> Self {
+        // 環境変数の値が不正でも parse_from のようにプロセスを終了させないよう、env を外して既定値だけで組み立てる
+        let matches = Self::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(["hostd"])
+            .expect("HostConfig default values must parse");
+        Self::from_arg_matches(&matches).expect("HostConfig default values must parse")
+    }
+}
+
+/// 起動中の hostd の操作（drop すると hostd も停止する）
+pub struct HostHandle {
+    shutdown_tx: Option<oneshot::Sender<()>>,
+    capture_target_cmd_tx: mpsc::Sender<CaptureTargetCommand>,
+    tagger_cmd_tx: mpsc::Sender<TaggerCommand>,
+    stats_tx: broadcast::Sender<VideoStatsPayload>,
+    /// 終了結果（受け取り済みなら None）
+    done_rx: Option<oneshot::Receiver<Result<()>>>,
+}
+
+impl HostHandle {
+    /// 現在のキャプチャ対象
+    pub async fn target(&self) -> Result<CaptureTargetPayload> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.capture_target_cmd_tx
+            .send(CaptureTargetCommand::Get { reply_tx })
+            .await
+            .context("Host is not running")?;
+        reply_rx
+            .await
+            .context("Host stopped before reporting target")
+    }
+
+    /// キャプチャ対象を指定したウィンドウに切り替える（切り替え後の対象を返す）
+    pub async fn set_target(&self, hwnd: u64) -> Result<CaptureTargetPayload> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.capture_target_cmd_tx
+            .send(CaptureTargetCommand::Set {
+                hwnd: Some(hwnd),
+                title: None,
+                reply_tx,
+            })
+            .await
+            .context("Host is not running")?;
+        reply_rx
+            .await
+            .context("Host stopped before switching target")
+    }
+
+    /// タグ付け用の llama-server を起動する（起動中なら再起動する）
+    pub async fn start_tagger(&self) -> Result<()> {
+        self.tagger_command(|reply_tx| TaggerCommand::Start { reply_tx })
+            .await
+    }
+
+    /// タグ付け用の llama-server を停止する
+    pub async fn stop_tagger(&self) -> Result<()> {
+        self.tagger_command(|reply_tx| TaggerCommand::Stop { reply_tx })
+            .await
+    }
+
+    async fn tagger_command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<std::result::Result<(), String>>) -> TaggerCommand,
+    ) -> Result<()> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.tagger_cmd_tx
+            .send(command(reply_tx))
+            .await
+            .context("Host is not running")?;
+        reply_rx
+            .await
+            .context("Host stopped before handling the tagger command")?
+            .map_err(anyhow::Error::msg)
+    }
+
+    /// 映像の統計を購読する（クライアントと接続している間だけ届く）
+    pub fn subscribe_stats(&self) -> broadcast::Receiver<VideoStatsPayload> {
+        self.stats_tx.subscribe()
+    }
+
+    /// hostd が終了するまで待つ（サービスのエラーで終了した場合はそのエラーを返す）
+    pub async fn wait(&mut self) -> Result<()> {
+        let Some(done_rx) = self.done_rx.as_mut() else {
+            return Ok(());
+        };
+        let result = done_rx
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Host thread exited unexpectedly")));
+        self.done_rx = None;
+        result
+    }
+
+    /// hostd が終了していればその結果を返す（まだ動いていれば None。結果を受け取り済みなら Ok）
+    pub fn try_wait(&mut self) -> Option<Result<()>> {
+        let Some(done_rx) = self.done_rx.as_mut() else {
+            return Some(Ok(()));
+        };
+        let result = match done_rx.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return None,
+            Err(oneshot::error::TryRecvError::Closed) => {
+                Err(anyhow::anyhow!("Host thread exited unexpectedly"))
+            }
+        };
+        self.done_rx = None;
+        Some(result)
+    }
+
+    /// 各サービスを順に止め、終了するまで待つ
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            let _ = shutdown_tx.send(());
+        }
+        self.wait().await
+    }
+}
+
+/// hostd の起動
+pub struct Host;
+
+impl Host {
+    /// サービスとチャネルを組み立てて hostd を起動する
+    /// 専用スレッドでランタイムを動かすので、呼び出し元は tokio のランタイム上でなくてもよい
+    pub fn start(config: HostConfig) -> Result<HostHandle> {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (capture_target_cmd_tx, capture_target_cmd_rx) =
+            mpsc::channel::<CaptureTargetCommand>(10);
+        let (tagger_cmd_tx, tagger_cmd_rx) = mpsc::channel::<TaggerCommand>(10);
+        let (stats_tx, _) = broadcast::channel(STATS_SUBSCRIBER_CAPACITY);
+        let (done_tx, done_rx) = oneshot::channel();
+
+        let control = HostControl {
+            shutdown_rx,
+            capture_target_cmd_tx: capture_target_cmd_tx.clone(),
+            capture_target_cmd_rx,
+            tagger_cmd_tx: tagger_cmd_tx.clone(),
+            tagger_cmd_rx,
+            stats_tx: stats_tx.clone(),
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .context("Failed to build tokio runtime")?;
+        std::thread::Builder::new()
+            .name("hostd".to_string())
+            .spawn(move || {
+                let result = runtime.block_on(run(config, control));
+                let _ = done_tx.send(result);
+            })
+            .context("Failed to spawn host thread")?;
+
+        Ok(HostHandle {
+            shutdown_tx: Some(shutdown_tx),
+            capture_target_cmd_tx,
+            tagger_cmd_tx,
+            stats_tx,
+            done_rx: Some(done_rx),
+        })
+    }
+}
+
+/// `run` に渡す HostHandle との接続
+struct HostControl {
+    shutdown_rx: oneshot::Receiver<()>,
+    capture_target_cmd_tx: mpsc::Sender<CaptureTargetCommand>,
+    capture_target_cmd_rx: mpsc::Receiver<CaptureTargetCommand>,
+    tagger_cmd_tx: mpsc::Sender<TaggerCommand>,
+    tagger_cmd_rx: mpsc::Receiver<TaggerCommand>,
+    stats_tx: broadcast::Sender<VideoStatsPayload>,
+}
+
+/// VideoStreamService の統計をクライアントへ送りつつ、購読者にも配る
+async fn forward_stats(
+    mut stats_rx: mpsc::Receiver<OutgoingDataChannelMessage>,
+    outgoing_dc_tx: mpsc::Sender<OutgoingDataChannelMessage>,
+    stats_tx: broadcast::Sender<VideoStatsPayload>,
+) {
+    while let Some(message) = stats_rx.recv().await {
+        if let OutgoingDataChannelMessage::Text(DataChannelMessage::VideoStats { payload }) =
+            &message
+        {
+            // 購読者がいなければ送れないだけなので無視する
+            let _ = stats_tx.send(payload.clone());
+        }
+        if outgoing_dc_tx.try_send(message).is_err() {
+            debug!("Outgoing data channel is full, skipping video stats");
+        }
+    }
+}
+
+enum CaptureServiceEnum {
+    Real(video_capture::CaptureService),
+    Mock(video_capture_mock::CaptureService),
+}
+
+impl CaptureServiceEnum {
+    async fn run(self) -> Result<()> {
+        match self {
+            CaptureServiceEnum::Real(service) => service.run().await,
+            CaptureServiceEnum::Mock(service) => service.run().await,
+        }
+    }
+}
+
+enum AudioCaptureServiceEnum {
+    Real(audio_capture::AudioCaptureService),
+    Mock(audio_capture_mock::AudioCaptureService),
+}
+
+impl AudioCaptureServiceEnum {
+    async fn run(self) -> Result<()> {
+        match self {
+            AudioCaptureServiceEnum::Real(service) => service.run().await,
+            AudioCaptureServiceEnum::Mock(service) => service.run().await,
+        }
+    }
+}
+
+/// サービスがエラー終了した際の hostd の対応
+#[derive(Debug, PartialEq, Eq)]
+enum SupervisorAction {
+    /// 他のサービスは動かし続ける
+    Continue,
+    /// hostd を終了する
+    Exit,
+}
+
+/// サービスのエラー種別から対応を決める
+fn supervise_error(service: &str, e: &anyhow::Error) -> SupervisorAction {
+    match ServiceError::from_anyhow(e) {
+        Some(ServiceError::WindowGone) => {
+            tracing::warn!(
+                "{}: capture target window is gone, keeping other services running: {:#}",
+                service,
+                e
+            );
+            SupervisorAction::Continue
+        }
+        Some(ServiceError::EncoderUnavailable(codec)) => {
+            tracing::error!(
+                "{}: encoder for {:?} is unavailable: {:#}",
+                service,
+                codec,
+                e
+            );
+            SupervisorAction::Exit
+        }
+        Some(ServiceError::SignalingRejected) => {
+            tracing::error!(
+                "{}: signaling server rejected the connection (check URL / session id): {:#}",
+                service,
+                e
+            );
+            SupervisorAction::Exit
+        }
+        Some(ServiceError::DeviceError(_)) | None => {
+            tracing::error!("{} error: {:#}", service, e);
+            SupervisorAction::Exit
+        }
+    }
+}
+
+/// 音声のサービスが終了したら、以降の接続は音声なしにする（映像の配信は止めない）
+fn disable_audio(
+    service: &str,
+    result: std::result::Result<Result<()>, tokio::task::JoinError>,
+    audio_available: &AtomicBool,
+) {
+    match result {
+        Ok(Ok(())) => warn!("{} finished, continuing with video only", service),
+        Ok(Err(e)) => warn!("{} failed, continuing with video only: {:#}", service, e),
+        Err(e) => warn!(
+            "{} task panicked, continuing with video only: {}",
+            service, e
+        ),
+    }
+    audio_available.store(false, Ordering::Relaxed);
+}
+
+/// 各サービスを起動し、いずれかが終了するか停止を要求されるまで動かす
+async fn run(mut config: HostConfig, control: HostControl) -> Result<()> {
+    let HostControl {
+        mut shutdown_rx,
+        capture_target_cmd_tx,
+        mut capture_target_cmd_rx,
+        tagger_cmd_tx,
+        mut tagger_cmd_rx,
+        stats_tx: stats_broadcast_tx,
+    } = control;
+
+    // キャプチャ対象（モニター指定がなければウィンドウ）
+    if config.monitor.is_none() && !config.primary_monitor && !config.virtual_display {
+        let cli = WindowSpec {
+            hwnd: config.hwnd,
+            title: config.window_title.clone(),
+            process_name: config.process_name.clone(),
+        };
+        config.hwnd = resolve_startup_target(&cli)?.hwnd();
+    }
+    // 子ウィンドウの指定があれば親ウィンドウの中にあるか確かめる
+    // （キャプチャと入力はそれぞれ使うたびに親から探し直すので、ここで見つけた HWND は使わない）
+    if let Some(class_or_title) = config.child_window.as_deref() {
+        anyhow::ensure!(
+            config.hwnd != 0,
+            "--child-window requires a parent window (--hwnd, --window-title or --process-name)"
+        );
+        let hwnd =
+            video_capture::find_child_window(config.hwnd, class_or_title).with_context(|| {
+                format!(
+                    "Child window {:?} not found under HWND {}",
+                    class_or_title, config.hwnd
+                )
+            })?;
+        info!("Child window {:?} found: HWND {}", class_or_title, hwnd);
+    }
+    let capture_target = match config.monitor {
+        Some(index) => CaptureTarget::Monitor(index),
+        None if config.primary_monitor => CaptureTarget::PrimaryMonitor,
+        None if config.virtual_display => {
+            let monitor = video_capture::find_virtual_monitor()?;
+            info!(
+                "Virtual display found: {} ({}, {}x{})",
+                monitor.name, monitor.adapter, monitor.width, monitor.height
+            );
+            CaptureTarget::Monitor(monitor.index)
+        }
+        None => match &config.child_window {
+            Some(class_or_title) => CaptureTarget::ChildWindow {
+                parent: config.hwnd,
+                class_or_title: class_or_title.clone(),
+            },
+            None => CaptureTarget::Window(config.hwnd),
+        },
+    };
+    // HWND の指定がなければ無効なウィンドウのキャプチャを始めず、クライアントが対象を選ぶのを待つ
+    let wait_for_target = !config.mock && matches!(capture_target, CaptureTarget::Window(0));
+
+    info!("Starting RemoteRG Host Daemon");
+    info!(
+        "Cloudflare URL: {}, Session ID: {}",
+        config.cloudflare_url, config.session_id
+    );
+    info!("Capture target: {:?}", capture_target);
+    info!("LLM Port: {}", config.llm_port);
+    info!("Screenshots Directory: {}", config.screenshots_dir);
+    if let Some(path) = &config.llama_server_path {
+        info!("LLM Server Path: {}", path);
+    }
+
+    // LLM Sidecar Setup
+    let mut tagger_setup = TaggerSetup::new()
+        .with_model_idle_timeout(std::time::Duration::from_secs(config.llm_model_idle_secs));
+    for value in &config.llm_model {
+        let (name, spec) = ModelSpec::parse_named(value)?;
+        info!("Additional LLM model {:?} on port {}", name, spec.port);
+        tagger_setup.add_model(name, spec);
+    }
+    let llama_server_path = config
+        .llama_server_path
+        .as_ref()
+        .map(std::path::PathBuf::from);
+    let llm_options = ServerOptions {
+        n_gpu_layers: config.llm_gpu_layers,
+        ctx_size: Some(config.llm_ctx_size),
+        threads: Some(config.llm_threads),
+    };
+
+    if let Err(e) = tagger_setup
+        .start(
+            config.llm_port,
+            llama_server_path.clone(),
+            None,
+            None,
+            llm_options,
+        )
+        .await
+    {
+        tracing::warn!("Failed to start LLM sidecar: {}", e);
+    }
+    let tagger_service = TaggerService::new(config.llm_port)
+        .with_image_options(ImageOptions {
+            encoding: config
+                .tagger_image_format
+                .parse()
+                .map_err(anyhow::Error::msg)?,
+            max_edge: (config.tagger_max_image_edge > 0).then_some(config.tagger_max_image_edge),
+        })
+        .with_timeouts(
+            std::time::Duration::from_secs(config.tagger_connect_timeout_secs),
+            std::time::Duration::from_secs(config.tagger_timeout_secs),
+        )
+        .with_retry(
+            config.tagger_retries,
+            std::time::Duration::from_millis(config.tagger_retry_backoff_ms),
+        );
+
+    // チャンネル作成
+    let (frame_tx, frame_rx) = mpsc::channel::<Frame>(config.frame_queue_depth.max(1));
+    let (capture_cmd_tx, capture_cmd_rx) = mpsc::channel::<CaptureMessage>(10);
+    let (signaling_response_tx, signaling_response_rx) = mpsc::channel::<SignalingResponse>(100);
+    let (data_channel_tx, data_channel_rx) = mpsc::channel::<DataChannelMessage>(100);
+
+    // 音声チャンネル作成
+    let (audio_capture_cmd_tx, audio_capture_cmd_rx) = mpsc::channel::<AudioCaptureMessage>(10);
+
+    // ビデオストリームメッセージチャネル（キーフレーム要求など）
+    let (video_stream_msg_tx, video_stream_msg_rx) = mpsc::channel::<VideoStreamMessage>(10);
+
+    // オーディオストリームメッセージチャネル（一時停止・再開）
+    let (audio_stream_msg_tx, audio_stream_msg_rx) = mpsc::channel::<AudioStreamMessage>(10);
+
+    // ビデオトラック情報を受け渡すためのチャンネル
+    let (video_track_tx, video_track_rx) = mpsc::channel::<(
+        Arc<webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample>,
+        Arc<webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender>,
+        Arc<std::sync::atomic::AtomicBool>, // connection_ready
+    )>(10);
+
+    // 音声トラック情報を受け渡すためのチャンネル
+    let (audio_track_tx, audio_track_rx) = mpsc::channel::<(
+        Arc<webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample>,
+        Arc<webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender>,
+    )>(10);
+
+    #[cfg(not(feature = "h264"))]
+    compile_error!("h264 feature must be enabled for hostd");
+
+    let mut encoder_factories: HashMap<VideoCodec, Arc<dyn VideoEncoderFactory>> = HashMap::new();
+    // GPU での縮小はハードウェアエンコーダーの前処理でしか行えない
+    let hardware_encoder;
+    #[cfg(feature = "h264")]
+    {
+        let mut mf_factory = MediaFoundationH264EncoderFactory::new(config.force_software);
+        if let Some(device) = &config.encoder_device {
+            let selector: EncoderDeviceSelector = device.parse()?;
+            info!("Encoder device requested: {:?}", selector);
+            mf_factory = mf_factory.with_encoder_device(selector);
+        }
+        let latency_mode: EncoderLatencyMode =
+            config.encoder_mode.parse().map_err(anyhow::Error::msg)?;
+        info!("Encoder mode: {:?}", latency_mode);
+        mf_factory = mf_factory.with_latency_mode(latency_mode);
+        let color = ColorSpace::new(
+            config
+                .color_matrix
+                .parse::<ColorMatrix>()
+                .map_err(anyhow::Error::msg)?,
+            config
+                .color_range
+                .parse::<ColorRange>()
+                .map_err(anyhow::Error::msg)?,
+        );
+        info!("Encoder color space: {:?}", color);
+        mf_factory = mf_factory.with_color_space(color);
+        let worker_thread = WorkerThreadConfig {
+            affinity_mask: config
+                .encoder_cores
+                .as_deref()
+                .map(WorkerThreadConfig::parse_affinity)
+                .transpose()
+                .map_err(anyhow::Error::msg)?,
+            priority: config
+                .encoder_priority
+                .parse::<WorkerThreadPriority>()
+                .map_err(anyhow::Error::msg)?,
+        };
+        if worker_thread != WorkerThreadConfig::default() {
+            info!("Encoder worker thread: {:?}", worker_thread);
+        }
+        mf_factory = mf_factory.with_worker_thread(worker_thread);
+        if let Some(threads) = config.encoder_threads {
+            info!("OpenH264 encoder threads: {}", threads);
+            mf_factory = mf_factory.with_software_threads(threads);
+        }
+        mf_factory = mf_factory.with_capture_time_sei(config.capture_time_sei);
+        hardware_encoder = mf_factory.use_media_foundation();
+        encoder_factories.insert(
+            VideoCodec::H264,
+            // Arc::new(OpenH264EncoderFactory::new()),
+            Arc::new(mf_factory),
+        );
+    }
+
+    // 音声フレーム用のチャンネルを作成
+    let (audio_frame_tx, audio_frame_rx) = mpsc::channel::<AudioFrame>(100);
+
+    // デフォルトのビデオエンコーダーを選択
+    let default_video_encoder = encoder_factories
+        .get(&VideoCodec::H264)
+        .expect("H264 encoder must be available")
+        .clone();
+
+    // 音声エンコーダーファクトリを作成
+    let audio_encoder_factory = Arc::new(
+        OpusEncoderFactory::new()
+            .with_frame_duration_ms(config.opus_frame_ms)?
+            .with_application(
+                config
+                    .opus_application
+                    .parse()
+                    .map_err(anyhow::Error::msg)?,
+            ),
+    );
+
+    // キャプチャ対象 HWND（ウィンドウ再作成時にスーパーバイザーが更新する）
+    // 子ウィンドウをキャプチャするときも親の HWND を持つ（フォーカスの判定と音声は親で行う）
+    let target_hwnd = Arc::new(AtomicU64::new(config.hwnd));
+    // キャプチャ対象が選ばれるまでは真っ黒な画面の代わりにプレースホルダーを送る
+    if wait_for_target && config.placeholder != "none" {
+        let style: video_capture_mock::PlaceholderStyle =
+            config.placeholder.parse().map_err(anyhow::Error::msg)?;
+        let (width, height) = video_capture_mock::DEFAULT_SOURCE_SIZE;
+        tokio::spawn(placeholder::run_placeholder(
+            video_capture_mock::placeholder_frame(style, width, height),
+            frame_tx.clone(),
+            target_hwnd.clone(),
+        ));
+    }
+    // キャプチャセッションのエラー通知（ウィンドウが閉じられた等）
+    let (capture_error_tx, mut capture_error_rx) = mpsc::unbounded_channel::<ServiceError>();
+    // そのうちキャプチャの再開の判断に使うもの（スーパーバイザーに渡す）
+    let (supervisor_error_tx, supervisor_error_rx) = mpsc::unbounded_channel::<ServiceError>();
+    // 音声キャプチャのエラー通知（この Windows で音声キャプチャが使えない等）
+    let (audio_capture_error_tx, mut audio_capture_error_rx) =
+        mpsc::unbounded_channel::<ServiceError>();
+
+    // 対象のウィンドウが前面にない間にキャプチャ側が立てる（品質ラダーが参照する）
+    let capture_throttled = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    // キャプチャの設定（実キャプチャに渡し、Answer の fmtp と PipelineConfig にも使う）
+    let capture_config = CaptureConfig {
+        aspect: config.aspect.parse().map_err(anyhow::Error::msg)?,
+        pixel_format: config.capture_format.parse().map_err(anyhow::Error::msg)?,
+        occlusion: config
+            .occlusion_policy
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        protected_content: config
+            .protected_content
+            .parse()
+            .map_err(anyhow::Error::msg)?,
+        max_encode_pixels: config.max_encode_pixels,
+        skip_unchanged_frames: config.skip_unchanged_frames,
+        gpu_scaling: config.gpu_scaling && hardware_encoder,
+        ..Default::default()
+    };
+    if config.gpu_scaling && !hardware_encoder {
+        warn!("--gpu-scaling is ignored without a hardware encoder; frames are resized on the CPU");
+    }
+
+    // サービス作成
+    let capture_service = if config.mock {
+        CaptureServiceEnum::Mock(
+            video_capture_mock::CaptureService::new(frame_tx, capture_cmd_rx)
+                .with_pattern(config.mock_pattern)
+                .with_frame_count(config.mock_frames)?
+                .with_source_size(config.mock_source_width, config.mock_source_height)?,
+        )
+    } else {
+        let mut service = video_capture::CaptureService::new(frame_tx, capture_cmd_rx)
+            .with_error_tx(capture_error_tx);
+        if let Some(max_encode_pixels) = capture_config.max_encode_pixels {
+            service = service.with_max_encode_pixels(max_encode_pixels);
+        }
+        service = service.with_aspect_mode(capture_config.aspect);
+        service = service.with_pixel_format(capture_config.pixel_format);
+        service = service.with_occlusion_policy(capture_config.occlusion);
+        service = service.with_protected_content_policy(capture_config.protected_content);
+        service = service.with_skip_unchanged_frames(capture_config.skip_unchanged_frames);
+        service = service.with_gpu_scaling(capture_config.gpu_scaling);
+        if let Some(background_fps) = config.background_fps.filter(|fps| *fps > 0) {
+            service = service.with_background_fps(background_fps, capture_throttled.clone());
+        }
+        CaptureServiceEnum::Real(service)
+    };
+    if config.mock && config.dump_audio.is_some() {
+        warn!("--dump-audio is ignored with mock audio capture");
+    }
+    if config.mock && config.mic {
+        warn!("--mic is ignored with mock audio capture");
+    }
+    let audio_capture_config = AudioCaptureConfig {
+        buffer_ms: config.audio_buffer_ms,
+        event_driven: !config.audio_polling,
+        late_child_silence: (config.audio_child_silence_secs > 0)
+            .then(|| std::time::Duration::from_secs(config.audio_child_silence_secs)),
+    };
+    // マイク用の AudioCaptureService（ゲーム音声とは別のスレッドでキャプチャして AudioStreamService でミックスする）
+    let mut mic_capture = None;
+    if config.mic && !config.mock {
+        let (mic_frame_tx, mic_frame_rx) = mpsc::channel::<AudioFrame>(100);
+        let (mic_capture_cmd_tx, mic_capture_cmd_rx) = mpsc::channel::<AudioCaptureMessage>(10);
+        let service = audio_capture::AudioCaptureService::new(mic_frame_tx, mic_capture_cmd_rx)
+            .with_config(audio_capture_config)?
+            .with_error_tx(audio_capture_error_tx.clone());
+        mic_capture = Some((service, mic_frame_rx, mic_capture_cmd_tx));
+    }
+    let audio_capture_service = if config.mock {
+        AudioCaptureServiceEnum::Mock(audio_capture_mock::AudioCaptureService::new(
+            audio_frame_tx,
+            audio_capture_cmd_rx,
+        ))
+    } else {
+        let mut service =
+            audio_capture::AudioCaptureService::new(audio_frame_tx, audio_capture_cmd_rx)
+                .with_config(audio_capture_config)?
+                .with_error_tx(audio_capture_error_tx);
+        if let Some(path) = &config.dump_audio {
+            service = service.with_audio_dump(
+                std::path::Path::new(path),
+                config.dump_audio_max_mb.saturating_mul(1024 * 1024),
+            )?;
+        }
+        AudioCaptureServiceEnum::Real(service)
+    };
+    // 監視用のメトリクス（各サービスで共有し、--metrics-port で公開する）
+    let metrics = Arc::new(Metrics::default());
+    let metrics_handle = match config.metrics_port {
+        Some(port) => {
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+                .await
+                .with_context(|| format!("Failed to bind metrics port {}", port))?;
+            info!("Serving metrics at http://0.0.0.0:{}/metrics", port);
+            Some(tokio::spawn(metrics_server::serve_metrics(
+                listener,
+                metrics.clone(),
+            )))
+        }
+        None => None,
+    };
+    // 動作確認用のクライアントのページ（--serve-client）
+    let web_client_handle = match config.serve_client {
+        Some(port) => {
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+                .await
+                .with_context(|| format!("Failed to bind client port {}", port))?;
+            info!("Serving test client at http://localhost:{}/", port);
+            let page = web_client::render_page(&config.cloudflare_url, &config.session_id);
+            Some(tokio::spawn(web_client::serve_web_client(listener, page)))
+        }
+        None => None,
+    };
+    // 配信中はスリープさせない（drop で解除する）
+    let _keep_awake = if config.keep_awake {
+        match KeepAwake::spawn(metrics.clone()) {
+            Ok(keep_awake) => Some(keep_awake),
+            Err(e) => {
+                tracing::warn!("Failed to start keep-awake thread: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // VideoStreamService を作成
+    let mut video_stream_service =
+        VideoStreamService::new(frame_rx, default_video_encoder, video_stream_msg_rx)
+            .with_metrics(metrics.clone())
+            .with_encode_watchdog(
+                std::time::Duration::from_secs(config.encode_stall_timeout_secs),
+                config.encode_stall_retries,
+            )
+            .with_freeze_detection(config.freeze_frames, config.freeze_min_bytes)
+            .with_connect_buffer(std::time::Duration::from_millis(config.connect_buffer_ms))
+            .with_gop_aligned_resize(config.gop_aligned_resize)
+            .with_debug_overlay(config.debug_overlay)
+            .with_pause_heartbeat(config.pause_heartbeat_fps)
+            .with_encoder_queue_budget(std::time::Duration::from_millis(
+                config.encoder_queue_budget_ms,
+            ))
+            .with_wait_for_first_keyframe(!config.send_deltas_before_keyframe)
+            .with_startup_keyframes(
+                config.startup_keyframes,
+                std::time::Duration::from_millis(config.startup_keyframe_spacing_ms),
+            );
+    if let Some(max_frame_age_ms) = config.max_frame_age_ms.filter(|ms| *ms > 0) {
+        video_stream_service = video_stream_service
+            .with_max_frame_age(std::time::Duration::from_millis(max_frame_age_ms));
+    }
+    // 録画（音声は AudioStreamService のエンコード結果から分岐させる）
+    let (recording_audio_tx, recording_audio_rx) = mpsc::channel(100);
+    video_stream_service = video_stream_service
+        .with_recording(
+            std::path::PathBuf::from(&config.record_dir),
+            config.record.as_ref().map(std::path::PathBuf::from),
+        )
+        .with_recording_audio(recording_audio_rx);
+    if let Some(replay_secs) = config.replay_secs.filter(|secs| *secs > 0) {
+        video_stream_service = video_stream_service.with_replay(
+            std::time::Duration::from_secs(replay_secs),
+            std::path::PathBuf::from(&config.replay_dir),
+        );
+    }
+
+    if let Some(path) = &config.dump_video {
+        video_stream_service = video_stream_service.with_video_dump(
+            std::path::Path::new(path),
+            config.dump_video_max_mb.saturating_mul(1024 * 1024),
+        )?;
+    }
+
+    if let Some(pacing_kbps) = config.pacing_kbps.filter(|kbps| *kbps > 0) {
+        video_stream_service = video_stream_service.with_pacing(
+            pacing_kbps.saturating_mul(1000),
+            std::time::Duration::from_millis(config.pacing_max_delay_ms),
+        );
+    }
+
+    // Answer の fmtp で宣言するフレームレートとサイズの上限（キャプチャと同じ設定から決める）
+    let mut answer_capture_config = capture_config.clone();
+    if let Some(ladder) = &config.quality_ladder {
+        let ladder: video_stream::QualityLadder = ladder.parse().map_err(anyhow::Error::msg)?;
+        // 品質ラダーはキャプチャの設定に収まる段から始まり、最上段より上には上げない
+        if let Some(top) = ladder.rungs().first() {
+            // キャプチャ側と同じく奇数のサイズは偶数に切り下げる
+            answer_capture_config.size = CaptureSize::Custom {
+                width: top.width,
+                height: top.height,
+            }
+            .to_even();
+            answer_capture_config.fps = top.fps;
+        }
+        video_stream_service = video_stream_service
+            .with_quality_ladder(
+                ladder,
+                capture_config.size.clone(),
+                capture_config.fps,
+                capture_cmd_tx.clone(),
+            )
+            .with_capture_throttled(capture_throttled.clone());
+    }
+
+    // Outgoing DataChannelメッセージ用チャネル (InputService / VideoStreamService -> WebRtcService)
+    let (outgoing_dc_tx, outgoing_dc_rx) = mpsc::channel(100);
+    // 統計は HostHandle の購読者にも配る
+    let (stats_tx, stats_rx) = mpsc::channel(10);
+    let video_stream_service = video_stream_service.with_stats_channel(stats_tx);
+    tokio::spawn(forward_stats(
+        stats_rx,
+        outgoing_dc_tx.clone(),
+        stats_broadcast_tx,
+    ));
+    // サービスのエラーをクライアントに通知するためのクローン
+    let outgoing_dc_tx_for_errors = outgoing_dc_tx.clone();
+
+    // WebRTCサービスの起動
+
+    let (webrtc_service, webrtc_msg_tx) = WebRtcService::new(
+        signaling_response_tx,
+        data_channel_tx,
+        Some(outgoing_dc_rx), // Pass outgoing_dc_rx
+        Some(video_track_tx),
+        Some(video_stream_msg_tx.clone()), // Use clone of video_stream_msg_tx
+        Some(audio_track_tx),
+        Some(audio_stream_msg_tx.clone()),
+    );
+    // 音声が使えない間は音声トラックなしで接続させる（--no-audio か、音声のサービスが終了したとき）
+    let audio_available = Arc::new(AtomicBool::new(!config.no_audio));
+    let webrtc_service = webrtc_service
+        .with_audio_available(audio_available.clone())
+        .with_metrics(metrics.clone())
+        .with_keyframe_coalesce(std::time::Duration::from_millis(
+            config.keyframe_coalesce_ms,
+        ))
+        .with_capture_config(&answer_capture_config);
+    let webrtc_service = match config.dscp.as_deref() {
+        Some(dscp) => {
+            let dscp: Dscp = dscp.parse().map_err(anyhow::Error::msg)?;
+            webrtc_service.with_dscp(dscp)
+        }
+        None => webrtc_service,
+    };
+    // 視聴中だけキャプチャする（ウィンドウ対象ならクライアントが切り替えた先から再開する）
+    let webrtc_service = if config.on_demand {
+        let follow_hwnd = !config.mock && matches!(capture_target, CaptureTarget::Window(_));
+        let initial_target = capture_target.clone();
+        let video_target_hwnd = target_hwnd.clone();
+        let on_demand = OnDemandCapture::new(capture_cmd_tx.clone(), move || {
+            if !follow_hwnd {
+                return Some(initial_target.clone());
+            }
+            match video_target_hwnd.load(Ordering::Relaxed) {
+                0 => None,
+                hwnd => Some(CaptureTarget::Window(hwnd)),
+            }
+        });
+        // 音声も視聴中だけキャプチャ・エンコードする（開始する音声は起動時と同じ選び方）
+        let on_demand = if config.no_audio {
+            on_demand
+        } else {
+            let audio_device = config.audio_device.clone();
+            let monitor = matches!(
+                capture_target,
+                CaptureTarget::Monitor(_) | CaptureTarget::PrimaryMonitor
+            );
+            let target_hwnd = target_hwnd.clone();
+            on_demand.with_audio(
+                audio_capture_cmd_tx.clone(),
+                audio_stream_msg_tx.clone(),
+                move || match &audio_device {
+                    Some(device_id) => Some(AudioCaptureMessage::StartEndpoint {
+                        device_id: Some(device_id.clone()),
+                    }),
+                    None if monitor => Some(AudioCaptureMessage::StartEndpoint { device_id: None }),
+                    None => match target_hwnd.load(Ordering::Relaxed) {
+                        0 => None,
+                        hwnd => Some(AudioCaptureMessage::Start { hwnd }),
+                    },
+                },
+            )
+        };
+        webrtc_service.with_on_demand_capture(on_demand)
+    } else {
+        webrtc_service
+    };
+
+    // WebRtcService::run() に渡すために webrtc_msg_tx をクローン
+    let webrtc_msg_tx_for_run = webrtc_msg_tx.clone();
+    // 停止時に PeerConnection を閉じさせるためのクローン
+    let webrtc_msg_tx_for_shutdown = webrtc_msg_tx.clone();
+
+    let mut audio_stream_service =
+        AudioStreamService::new(audio_frame_rx, audio_encoder_factory, audio_stream_msg_rx)
+            .with_encoded_tap(recording_audio_tx)
+            .with_metrics(metrics);
+    let mut mic_capture_service = None;
+    let mut mic_capture_cmd_tx = None;
+    if let Some((service, mic_frame_rx, cmd_tx)) = mic_capture {
+        audio_stream_service = audio_stream_service
+            .with_mixed_source(AudioSource::Mic, mic_frame_rx)
+            .with_source_gain(AudioSource::Game, config.game_gain)?
+            .with_source_gain(AudioSource::Mic, config.mic_gain)?;
+        mic_capture_service = Some(service);
+        mic_capture_cmd_tx = Some(cmd_tx);
+    }
+    if let Some(fill) = config.audio_gap_fill.as_deref() {
+        let fill: GapFill = fill.parse().map_err(anyhow::Error::msg)?;
+        audio_stream_service = audio_stream_service.with_gap_fill(fill);
+    }
+
+    // クライアントの GetPipelineConfig に返す実効設定（各サービスに渡した値から組み立てる）
+    let pipeline_config = crate::config_file::pipeline_config(
+        &config,
+        answer_capture_config.clone(),
+        audio_capture_config,
+    )?;
+
+    // CaptureServiceへのコマンド送信チャネルを複製
+    let capture_cmd_tx_for_input = capture_cmd_tx.clone();
+
+    let input_service = InputService::new(
+        data_channel_rx,
+        capture_cmd_tx_for_input,
+        outgoing_dc_tx, // Pass outgoing_dc_tx
+        tagger_service,
+        tagger_cmd_tx,
+        std::path::PathBuf::from(config.screenshots_dir),
+        target_hwnd.clone(),
+    )
+    .with_capture_target_tx(capture_target_cmd_tx)
+    .with_input_rate(config.input_rate)
+    .with_focus_policy(config.focus_policy.parse().map_err(anyhow::Error::msg)?)
+    .with_clipboard_sync(config.clipboard_sync)
+    .with_pipeline_config(pipeline_config, audio_available.clone());
+    let input_service = match config.child_window.clone() {
+        Some(class_or_title) => input_service.with_child_window(move |parent| {
+            video_capture::find_child_window(parent, &class_or_title)
+        }),
+        None => input_service,
+    };
+    // ループバックモードではシグナリングサーバーの代わりに自前の受信側と接続する
+    let signaling_fut: Pin<Box<dyn Future<Output = Result<()>> + Send>> = if config.loopback {
+        info!("Loopback mode enabled ({}s)", config.loopback_secs);
+        Box::pin(run_loopback(
+            webrtc_msg_tx,
+            signaling_response_rx,
+            std::time::Duration::from_secs(config.loopback_secs),
+        ))
+    } else {
+        let signaling_client = SignalingClient::new(
+            config.cloudflare_url,
+            config.session_id,
+            webrtc_msg_tx,
+            signaling_response_rx,
+        );
+        Box::pin(signaling_client.run())
+    };
+
+    // CaptureServiceを開始
+    if wait_for_target {
+        info!("No capture target; waiting for client to select one.");
+    } else if config.on_demand {
+        info!("On-demand capture enabled; capture starts when a viewer connects");
+    } else {
+        capture_cmd_tx
+            .send(CaptureMessage::Start {
+                target: capture_target.clone(),
+            })
+            .await
+            .context("Failed to start capture service")?;
+        if config.mock {
+            info!("CaptureService started (mock frames)");
+        } else {
+            info!("CaptureService started (real capture)");
+        }
+    }
+
+    // AudioCaptureServiceを開始（エンドポイント指定時はそのデバイスの出力をキャプチャ）
+    // ウィンドウの音声はキャプチャ対象が選ばれた時に切り替えで開始する
+    // モニターにはプロセスがないので、既定の出力デバイスの音声（システム全体）をキャプチャする
+    // 視聴中だけキャプチャする場合は、最初のビューアーが繋がるまで音声も開始しない
+    let audio_start_msg = match config.audio_device.clone() {
+        _ if config.no_audio || config.on_demand => None,
+        Some(device_id) => Some(AudioCaptureMessage::StartEndpoint {
+            device_id: Some(device_id),
+        }),
+        None if wait_for_target => None,
+        None => match &capture_target {
+            CaptureTarget::Monitor(_) | CaptureTarget::PrimaryMonitor => {
+                Some(AudioCaptureMessage::StartEndpoint { device_id: None })
+            }
+            CaptureTarget::Window(_) | CaptureTarget::ChildWindow { .. } => {
+                Some(AudioCaptureMessage::Start { hwnd: config.hwnd })
+            }
+        },
+    };
+    if let Some(audio_start_msg) = audio_start_msg {
+        audio_capture_cmd_tx
+            .send(audio_start_msg)
+            .await
+            .context("Failed to start audio capture service")?;
+        if config.mock {
+            info!("AudioCaptureService started (mock audio)");
+        } else {
+            info!("AudioCaptureService started (real audio)");
+        }
+    }
+    if config.no_audio {
+        info!("Audio is disabled (--no-audio), streaming video only");
+    } else if config.on_demand {
+        // 最初のビューアーが繋がるまで、途切れを埋めるフレームやマイクの音声もエンコードしない
+        audio_stream_msg_tx
+            .send(AudioStreamMessage::Pause)
+            .await
+            .context("Failed to pause audio stream")?;
+    }
+    if let Some(cmd_tx) = mic_capture_cmd_tx.as_ref().filter(|_| !config.no_audio) {
+        cmd_tx
+            .send(AudioCaptureMessage::StartInputDevice {
+                device_id: config.mic_device.clone(),
+            })
+            .await
+            .context("Failed to start microphone capture")?;
+        info!("AudioCaptureService started (microphone)");
+    }
+
+    // 音声をキャプチャ対象のウィンドウに追従させるか（エンドポイント指定時と --no-audio の時は追従させない）
+    let follow_window_audio = config.audio_device.is_none() && !config.no_audio;
+
+    // クライアントからのキャプチャ対象の切り替え
+    let capture_target_switcher = CaptureTargetSwitcher::new(
+        target_hwnd.clone(),
+        capture_cmd_tx.clone(),
+        follow_window_audio.then(|| audio_capture_cmd_tx.clone()),
+        video_stream_msg_tx.clone(),
+    );
+
+    // ウィンドウが閉じられた際の再起動を監視（実キャプチャでウィンドウ対象の時のみ）
+    let capture_supervisor_handle =
+        (!config.mock && matches!(capture_target, CaptureTarget::Window(_))).then(|| {
+            tokio::spawn(capture_supervisor::run_capture_supervisor(
+                target_hwnd,
+                supervisor_error_rx,
+                capture_cmd_tx.clone(),
+                follow_window_audio.then(|| audio_capture_cmd_tx.clone()),
+                std::time::Duration::from_secs(config.window_reappear_timeout_secs),
+                config.restart_on_capture_stall,
+                config.reappear_match_process,
+            ))
+        });
+
+    // サービスを独立タスクとして起動（Send でない WebRTC はこのスレッドで駆動する）
+    let mut capture_handle = tokio::spawn(async move { capture_service.run().await });
+    let mut audio_capture_handle = tokio::spawn(async move { audio_capture_service.run().await });
+    let mut mic_capture_handle =
+        mic_capture_service.map(|service| tokio::spawn(async move { service.run().await }));
+    let mut input_handle = tokio::spawn(async move { input_service.run().await });
+    let mut signaling_handle = tokio::spawn(signaling_fut);
+
+    // VideoStreamService起動タスク
+    let mut video_stream_handle =
+        tokio::spawn(async move { video_stream_service.run(video_track_rx).await });
+
+    // AudioStreamService起動タスク
+    let mut audio_stream_handle =
+        tokio::spawn(async move { audio_stream_service.run(audio_track_rx).await });
+
+    // WebRTC は非 Send 型を含むため spawn せず現在のタスクで実行する
+    let webrtc_fut = webrtc_service.run(webrtc_msg_tx_for_run);
+    pin!(webrtc_fut);
+
+    // 終了済みの JoinHandle を再度 poll しないためのフラグ
+    let mut capture_running = true;
+    let mut audio_capture_running = true;
+    let mut audio_stream_running = true;
+    let mut webrtc_running = true;
+
+    // 使われていない追加モデル（--llm-model）の llama-server を止める
+    let mut idle_model_check = tokio::time::interval(std::time::Duration::from_secs(30));
+    idle_model_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = idle_model_check.tick() => {
+                let stopped = tagger_setup.shutdown_idle_models().await;
+                if stopped > 0 {
+                    info!("Stopped {} idle LLM model(s)", stopped);
+                }
+            }
+            cmd = tagger_cmd_rx.recv() => {
+                match cmd {
+                    Some(TaggerCommand::UpdateConfig { config }) => {
+                        info!("Restarting llama-server with new config: {:?}", config);
+                        let model_path = config.model_path.map(std::path::PathBuf::from);
+                        let mmproj_path = config.mmproj_path.map(std::path::PathBuf::from);
+                        if let Err(e) = tagger_setup.restart(config.port, llama_server_path.clone(), model_path, mmproj_path, llm_options).await {
+                             tracing::error!("Failed to restart llama-server: {}", e);
+                        }
+                    }
+                    Some(TaggerCommand::GetConfig { reply_tx }) => {
+                        let (port, model_path, mmproj_path) = tagger_setup.get_config();
+                        let config = core_types::LlmConfig {
+                            port,
+                            model_path: model_path.map(|p| p.to_string_lossy().to_string()),
+                            mmproj_path: mmproj_path.map(|p| p.to_string_lossy().to_string()),
+                        };
+                        let _ = reply_tx.send(config);
+                    }
+                    Some(TaggerCommand::Start { reply_tx }) => {
+                        let (port, model_path, mmproj_path) = tagger_setup.get_config();
+                        info!("Starting llama-server on port {}", port);
+                        let result = tagger_setup
+                            .restart(port, llama_server_path.clone(), model_path, mmproj_path, llm_options)
+                            .await
+                            .map_err(|e| format!("{:#}", e));
+                        let _ = reply_tx.send(result);
+                    }
+                    Some(TaggerCommand::Stop { reply_tx }) => {
+                        info!("Stopping llama-server");
+                        let _ = reply_tx.send(tagger_setup.shutdown().await.map_err(|e| format!("{:#}", e)));
+                    }
+                    Some(TaggerCommand::SelectModel { name, reply_tx }) => {
+                        match tagger_setup.select_model(&name).await {
+                            // 読み込みを待つ間（最大 2 分）もホストのループを止めない
+                            Ok(startup) => {
+                                tokio::spawn(async move {
+                                    let _ = reply_tx.send(startup.wait_ready().await.map_err(|e| format!("{:#}", e)));
+                                });
+                            }
+                            Err(e) => {
+                                let _ = reply_tx.send(Err(format!("{:#}", e)));
+                            }
+                        }
+                    }
+                    None => {
+                        info!("Tagger command channel closed");
+                        break;
+                    }
+                }
+            }
+            cmd = capture_target_cmd_rx.recv() => {
+                match cmd {
+                    Some(CaptureTargetCommand::Get { reply_tx }) => {
+                        let _ = reply_tx.send(capture_target_switcher.current());
+                    }
+                    Some(CaptureTargetCommand::Set { hwnd, title, reply_tx }) => {
+                        let _ = reply_tx.send(capture_target_switcher.switch(hwnd, title).await);
+                    }
+                    None => {
+                        info!("Capture target command channel closed");
+                        break;
+                    }
+                }
+            }
+            Some(err) = capture_error_rx.recv() => {
+                if err == ServiceError::ProtectedContent {
+                    // 映像は止めずに、画面が黒い理由をクライアントに伝える
+                    let notification = DataChannelMessage::ServiceErrorNotification {
+                        service: "video".to_string(),
+                        message: err.to_string(),
+                    };
+                    if outgoing_dc_tx_for_errors
+                        .send(OutgoingDataChannelMessage::Text(notification))
+                        .await
+                        .is_err()
+                    {
+                        warn!("Failed to notify client of protected content");
+                    }
+                } else {
+                    let _ = supervisor_error_tx.send(err);
+                }
+            }
+            Some(err) = audio_capture_error_rx.recv() => {
+                tracing::error!("AudioCaptureService error: {}", err);
+                // 音声だけが使えない状態なので映像は続け、理由をクライアントに伝える
+                let notification = DataChannelMessage::ServiceErrorNotification {
+                    service: "audio".to_string(),
+                    message: err.to_string(),
+                };
+                if outgoing_dc_tx_for_errors
+                    .send(OutgoingDataChannelMessage::Text(notification))
+                    .await
+                    .is_err()
+                {
+                    warn!("Failed to notify client of audio capture error");
+                }
+            }
+            // 停止要求（HostHandle が drop された場合も止める）
+            _ = &mut shutdown_rx => {
+                info!("Shutdown requested");
+                break;
+            },
+            result = &mut webrtc_fut => {
+                webrtc_running = false;
+                match result {
+                    Ok(()) => { info!("WebRtcService finished"); break; },
+                    Err(e) => { tracing::error!("WebRtcService error: {}", e); break; },
+                }
+            },
+            result = &mut capture_handle, if capture_running => match result {
+                Ok(Ok(())) => { info!("CaptureService finished"); break; },
+                Ok(Err(e)) => {
+                    capture_running = false;
+                    if supervise_error("CaptureService", &e) == SupervisorAction::Exit {
+                        break;
+                    }
+                },
+                Err(e) => { tracing::error!("CaptureService task panicked: {}", e); break; },
+            },
+            // 音声のサービスが終了しても映像の配信は続ける
+            result = &mut audio_capture_handle, if audio_capture_running => {
+                audio_capture_running = false;
+                disable_audio("AudioCaptureService", result, &audio_available);
+            },
+            result = &mut video_stream_handle => match result {
+                Ok(Ok(())) => { info!("VideoStreamService finished"); break; },
+                Ok(Err(e)) => { supervise_error("VideoStreamService", &e); break; },
+                Err(e) => { tracing::error!("VideoStreamService task panicked: {}", e); break; },
+            },
+            result = &mut audio_stream_handle, if audio_stream_running => {
+                audio_stream_running = false;
+                disable_audio("AudioStreamService", result, &audio_available);
+            },
+            result = &mut input_handle => match result {
+                Ok(Ok(())) => { info!("InputService finished"); break; },
+                Ok(Err(e)) => { tracing::error!("InputService error: {}", e); break; },
+                Err(e) => { tracing::error!("InputService task panicked: {}", e); break; },
+            },
+            result = &mut signaling_handle => match result {
+                Ok(Ok(())) => { info!("SignalingService finished"); break; },
+                Ok(Err(e)) => {
+                    supervise_error("SignalingService", &e);
+                    // ループバックでは検証失敗を終了コードに反映する
+                    if config.loopback {
+                        return Err(e);
+                    }
+                    break;
+                },
+                Err(e) => { tracing::error!("SignalingService task panicked: {}", e); break; },
+            },
+        }
+    }
+
+    // 後片付け: キャプチャを止めて PeerConnection を閉じ、チャネルを閉じて各サービスを終了させる
+    info!("Shutting down services");
+    if let Some(handle) = &capture_supervisor_handle {
+        handle.abort();
+    }
+    if let Some(handle) = &metrics_handle {
+        handle.abort();
+    }
+    if let Some(handle) = &web_client_handle {
+        handle.abort();
+    }
+    // キャプチャへのコマンド送信側を手放させる
+    input_handle.abort();
+    signaling_handle.abort();
+    drop(capture_target_switcher);
+    ShutdownSenders {
+        capture_cmd_tx,
+        audio_capture_cmd_tx,
+        mic_capture_cmd_tx,
+        webrtc_msg_tx: webrtc_msg_tx_for_shutdown,
+    }
+    .stop_all()
+    .await;
+
+    if webrtc_running {
+        match tokio::time::timeout(SERVICE_STOP_TIMEOUT, &mut webrtc_fut).await {
+            Ok(_) => info!("WebRtcService stopped (peer connection closed)"),
+            Err(_) => warn!(
+                "WebRtcService did not stop within {:?}",
+                SERVICE_STOP_TIMEOUT
+            ),
+        }
+    }
+    // キャプチャが止まるとフレームチャネルが閉じ、フレームルーターがエンコーダーのジョブスロットを shutdown する
+    join_or_abort("CaptureService", &mut capture_handle).await;
+    join_or_abort("AudioCaptureService", &mut audio_capture_handle).await;
+    if let Some(handle) = &mut mic_capture_handle {
+        join_or_abort("AudioCaptureService (mic)", handle).await;
+    }
+    join_or_abort("VideoStreamService", &mut video_stream_handle).await;
+    join_or_abort("AudioStreamService", &mut audio_stream_handle).await;
+
+    if let Err(e) = tagger_setup.shutdown().await {
+        warn!("Failed to stop llama-server: {}", e);
+    }
+
+    info!("Host daemon stopped");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audio_failure_keeps_streaming_video_only() {
+        // 出力デバイスがなく音声のキャプチャが初期化に失敗した場合
+        let audio_available = AtomicBool::new(true);
+        let handle = tokio::spawn(async { Err::<(), _>(anyhow::anyhow!("no render endpoint")) });
+        disable_audio("AudioCaptureService", handle.await, &audio_available);
+        assert!(!audio_available.load(Ordering::Relaxed));
+
+        // --no-audio なら最初から音声なしで接続させる
+        let config = HostConfig::parse_from(["hostd", "--no-audio"]);
+        assert!(config.no_audio);
+        assert!(!HostConfig::default().no_audio);
+    }
+
+    #[tokio::test]
+    async fn test_forward_stats_sends_to_client_and_subscribers() {
+        let (stats_tx, stats_rx) = mpsc::channel(10);
+        let (outgoing_dc_tx, mut outgoing_dc_rx) = mpsc::channel(10);
+        let (broadcast_tx, mut subscriber_rx) = broadcast::channel(STATS_SUBSCRIBER_CAPACITY);
+        let handle = tokio::spawn(forward_stats(stats_rx, outgoing_dc_tx, broadcast_tx));
+
+        let payload = VideoStatsPayload {
+            frames: 60,
+            encoder_dropped: 1,
+            encoder_drop_rate: 1.0 / 60.0,
+            network_loss_rate: 0.0,
+            capture_fps: 60.0,
+            keyframes_last_minute: Default::default(),
+            average_qp: Some(30.0),
+            max_qp: Some(34),
+            capture_dropped: 0,
+            capture_to_encode: None,
+            encode_to_send: None,
+        };
+        stats_tx
+            .send(OutgoingDataChannelMessage::Text(
+                DataChannelMessage::VideoStats { payload },
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(subscriber_rx.recv().await.unwrap().frames, 60);
+        assert!(matches!(
+            outgoing_dc_rx.recv().await,
+            Some(OutgoingDataChannelMessage::Text(
+                DataChannelMessage::VideoStats { .. }
+            ))
+        ));
+
+        drop(stats_tx);
+        handle.await.unwrap();
+    }
+}
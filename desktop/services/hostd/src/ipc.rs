@@ -0,0 +1,329 @@
+// デスクトップ UI から hostd を操作するためのコマンド
+//
+// UI（Tauri のコマンドやローカルソケットなど）とは JSON にできる HostCommand / HostResponse だけで
+// やり取りし、運び方には依存しない。HostBridge が HostConfig と起動中の HostHandle を持ち、
+// キャプチャの開始・停止に合わせて hostd を起動・停止する。
+// 画質の変更は起動時の設定でしか反映できないため、キャプチャ中なら hostd を起動し直す
+// （接続中のクライアントはいったん切断される）。
+
+use anyhow::{Context, Result};
+use core_types::{CapturableWindow, CaptureTargetPayload, VideoStatsPayload};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+use crate::host::{Host, HostConfig, HostHandle};
+use crate::window_list::{list_capturable_windows, THUMBNAIL_MAX_EDGE, THUMBNAIL_TIMEOUT};
+
+/// UI からの操作
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum HostCommand {
+    /// キャプチャできるウィンドウをサムネイルつきで列挙する
+    ListWindows,
+    /// 指定したウィンドウのキャプチャを始める（キャプチャ中なら対象を切り替える）
+    StartCapture { hwnd: u64 },
+    /// キャプチャを止め、hostd を停止する
+    StopCapture,
+    /// 画質の設定を変える（None の項目は変えない）
+    SetQuality {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        encoder_mode: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_encode_pixels: Option<u32>,
+    },
+    /// 最新の映像の統計
+    GetStats,
+    /// タグ付け用の llama-server を起動する
+    StartTagger,
+    /// タグ付け用の llama-server を停止する
+    StopTagger,
+}
+
+/// 操作の結果
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HostResponse {
+    Ok,
+    Windows {
+        windows: Vec<CapturableWindow>,
+    },
+    CaptureTarget {
+        target: CaptureTargetPayload,
+    },
+    /// まだ統計が届いていない（クライアント未接続など）場合は None
+    Stats {
+        stats: Option<VideoStatsPayload>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// HostCommand を hostd の操作に変換する
+pub struct HostBridge {
+    config: HostConfig,
+    host: Option<HostHandle>,
+    stats_rx: Option<broadcast::Receiver<VideoStatsPayload>>,
+    latest_stats: Option<VideoStatsPayload>,
+}
+
+impl HostBridge {
+    /// `config` はキャプチャを始めるときに使う設定（HWND は StartCapture で上書きする）
+    pub fn new(config: HostConfig) -> Self {
+        Self {
+            config,
+            host: None,
+            stats_rx: None,
+            latest_stats: None,
+        }
+    }
+
+    /// hostd が起動しているか（エラーなどで自分で終了していたら false）
+    pub fn is_running(&mut self) -> bool {
+        self.reap_exited();
+        self.host.is_some()
+    }
+
+    /// 操作を実行する（失敗した場合は HostResponse::Error を返す）
+    pub async fn handle(&mut self, command: HostCommand) -> HostResponse {
+        match self.execute(command).await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Host command failed: {:#}", e);
+                HostResponse::Error {
+                    message: format!("{:#}", e),
+                }
+            }
+        }
+    }
+
+    async fn execute(&mut self, command: HostCommand) -> Result<HostResponse> {
+        // 自分で終了した hostd に操作を送らず、起動していないものとして扱う
+        self.reap_exited();
+        match command {
+            HostCommand::ListWindows => Ok(HostResponse::Windows {
+                windows: list_capturable_windows(THUMBNAIL_MAX_EDGE, THUMBNAIL_TIMEOUT).await?,
+            }),
+            HostCommand::StartCapture { hwnd } => {
+                let target = match &self.host {
+                    Some(host) => host.set_target(hwnd).await?,
+                    None => {
+                        self.config.hwnd = hwnd;
+                        self.start()?.target().await?
+                    }
+                };
+                Ok(HostResponse::CaptureTarget { target })
+            }
+            HostCommand::StopCapture => {
+                self.stop().await?;
+                Ok(HostResponse::Ok)
+            }
+            HostCommand::SetQuality {
+                encoder_mode,
+                max_encode_pixels,
+            } => {
+                if let Some(mode) = encoder_mode {
+                    // 起動し直してから失敗しないよう先に確かめる
+                    #[cfg(feature = "h264")]
+                    mode.parse::<encoder::h264::mmf::mf::EncoderLatencyMode>()
+                        .map_err(anyhow::Error::msg)?;
+                    self.config.encoder_mode = mode;
+                }
+                if let Some(max_encode_pixels) = max_encode_pixels {
+                    self.config.max_encode_pixels = Some(max_encode_pixels);
+                }
+                if self.is_running() {
+                    // 起動し直しても同じウィンドウをキャプチャする
+                    self.config.hwnd = self.running()?.target().await?.hwnd;
+                    info!("Restarting host to apply quality settings");
+                    self.stop().await?;
+                    self.start()?;
+                }
+                Ok(HostResponse::Ok)
+            }
+            HostCommand::GetStats => Ok(HostResponse::Stats {
+                stats: self.poll_stats(),
+            }),
+            HostCommand::StartTagger => {
+                self.running()?.start_tagger().await?;
+                Ok(HostResponse::Ok)
+            }
+            HostCommand::StopTagger => {
+                self.running()?.stop_tagger().await?;
+                Ok(HostResponse::Ok)
+            }
+        }
+    }
+
+    fn start(&mut self) -> Result<&HostHandle> {
+        let host = Host::start(self.config.clone())?;
+        self.stats_rx = Some(host.subscribe_stats());
+        self.latest_stats = None;
+        Ok(self.host.insert(host))
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        self.stats_rx = None;
+        match self.host.take() {
+            Some(host) => host.shutdown().await,
+            None => Ok(()),
+        }
+    }
+
+    /// hostd が自分で終了していたら手放す（次の StartCapture で起動し直せるようにする）
+    fn reap_exited(&mut self) {
+        let Some(result) = self.host.as_mut().and_then(HostHandle::try_wait) else {
+            return;
+        };
+        match result {
+            Ok(()) => info!("Host exited"),
+            Err(e) => warn!("Host exited with error: {:#}", e),
+        }
+        self.host = None;
+        self.stats_rx = None;
+    }
+
+    fn running(&self) -> Result<&HostHandle> {
+        self.host.as_ref().context("Capture is not running")
+    }
+
+    /// 届いている統計を読み進め、最新のものを返す
+    fn poll_stats(&mut self) -> Option<VideoStatsPayload> {
+        if let Some(stats_rx) = self.stats_rx.as_mut() {
+            loop {
+                match stats_rx.try_recv() {
+                    Ok(stats) => self.latest_stats = Some(stats),
+                    // 読み遅れて取りこぼした分は飛ばし、残っている新しいものを読む
+                    Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                    Err(_) => break,
+                }
+            }
+        }
+        self.latest_stats.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_command_json() {
+        let cases = [
+            (HostCommand::ListWindows, json!({"command": "list_windows"})),
+            (
+                HostCommand::StartCapture { hwnd: 1234 },
+                json!({"command": "start_capture", "hwnd": 1234}),
+            ),
+            (HostCommand::StopCapture, json!({"command": "stop_capture"})),
+            (
+                HostCommand::SetQuality {
+                    encoder_mode: Some("quality".to_string()),
+                    max_encode_pixels: None,
+                },
+                json!({"command": "set_quality", "encoder_mode": "quality"}),
+            ),
+            (HostCommand::GetStats, json!({"command": "get_stats"})),
+            (HostCommand::StartTagger, json!({"command": "start_tagger"})),
+            (HostCommand::StopTagger, json!({"command": "stop_tagger"})),
+        ];
+        for (command, value) in cases {
+            assert_eq!(serde_json::to_value(&command).unwrap(), value);
+            assert_eq!(
+                serde_json::from_value::<HostCommand>(value).unwrap(),
+                command
+            );
+        }
+
+        // 省略した画質の項目は変えない
+        assert_eq!(
+            serde_json::from_value::<HostCommand>(
+                json!({"command": "set_quality", "max_encode_pixels": 921600})
+            )
+            .unwrap(),
+            HostCommand::SetQuality {
+                encoder_mode: None,
+                max_encode_pixels: Some(921600),
+            }
+        );
+        assert!(serde_json::from_value::<HostCommand>(json!({"command": "reboot"})).is_err());
+    }
+
+    #[test]
+    fn test_response_json() {
+        let response = HostResponse::CaptureTarget {
+            target: CaptureTargetPayload {
+                hwnd: 42,
+                title: Some("Game".to_string()),
+                error: None,
+            },
+        };
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "type": "capture_target",
+                "target": {"hwnd": 42, "title": "Game", "error": null}
+            })
+        );
+        assert!(matches!(
+            serde_json::from_value(value).unwrap(),
+            HostResponse::CaptureTarget { target } if target.hwnd == 42
+        ));
+
+        assert_eq!(
+            serde_json::to_value(HostResponse::Stats { stats: None }).unwrap(),
+            json!({"type": "stats", "stats": null})
+        );
+        assert_eq!(
+            serde_json::to_value(HostResponse::Error {
+                message: "Capture is not running".to_string()
+            })
+            .unwrap(),
+            json!({"type": "error", "message": "Capture is not running"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tagger_commands_require_running_host() {
+        let mut bridge = HostBridge::new(HostConfig::default());
+        assert!(!bridge.is_running());
+        assert!(matches!(
+            bridge.handle(HostCommand::StartTagger).await,
+            HostResponse::Error { .. }
+        ));
+        assert!(matches!(
+            bridge.handle(HostCommand::GetStats).await,
+            HostResponse::Stats { stats: None }
+        ));
+        assert!(matches!(
+            bridge.handle(HostCommand::StopCapture).await,
+            HostResponse::Ok
+        ));
+        // 起動していなければ設定だけ変える
+        assert!(matches!(
+            bridge
+                .handle(HostCommand::SetQuality {
+                    encoder_mode: Some("quality".to_string()),
+                    max_encode_pixels: Some(1280 * 720),
+                })
+                .await,
+            HostResponse::Ok
+        ));
+        assert_eq!(bridge.config.encoder_mode, "quality");
+        assert_eq!(bridge.config.max_encode_pixels, Some(1280 * 720));
+        #[cfg(feature = "h264")]
+        assert!(matches!(
+            bridge
+                .handle(HostCommand::SetQuality {
+                    encoder_mode: Some("turbo".to_string()),
+                    max_encode_pixels: None,
+                })
+                .await,
+            HostResponse::Error { .. }
+        ));
+        assert_eq!(bridge.config.encoder_mode, "quality");
+    }
+}
@@ -0,0 +1,126 @@
+// 配信中にホストをスリープさせない（`--keep-awake`）
+//
+// 無人のホストは操作がないのでアイドル扱いになり、配信の途中でスリープやディスプレイの消灯が起きて
+// キャプチャと配信が止まる。接続中のセッションがある間だけ SetThreadExecutionState で
+// システムとディスプレイの動作を要求し、最後のセッションが切れたら解除する。
+// ES_CONTINUOUS の状態は呼び出したスレッドに結びつくので、tokio のワーカーではなく専用のスレッドで
+// 設定と解除を行う（スレッドが終われば OS 側でも解除される）。
+
+use core_types::Metrics;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{info, warn};
+use windows::Win32::System::Power::{
+    SetThreadExecutionState, ES_CONTINUOUS, ES_DISPLAY_REQUIRED, ES_SYSTEM_REQUIRED,
+};
+
+/// 接続中のセッションの数を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// セッションの有無からスリープの抑止を切り替える
+struct SessionAwake {
+    engaged: bool,
+    /// 抑止を設定・解除し、成功したら true（テストでは差し替える）
+    apply: fn(bool) -> bool,
+}
+
+impl SessionAwake {
+    fn new(apply: fn(bool) -> bool) -> Self {
+        Self {
+            engaged: false,
+            apply,
+        }
+    }
+
+    /// セッションの有無を反映し、切り替えたら true を返す
+    fn update(&mut self, active: bool) -> bool {
+        if active == self.engaged {
+            return false;
+        }
+        if !(self.apply)(active) {
+            warn!(
+                "SetThreadExecutionState failed, could not {} sleep prevention",
+                if active { "engage" } else { "release" }
+            );
+            return false;
+        }
+        if active {
+            info!("Viewer connected, keeping the host and display awake");
+        } else {
+            info!("No viewers connected, allowing the host to sleep again");
+        }
+        self.engaged = active;
+        true
+    }
+}
+
+/// 接続中のセッションがある間スリープを抑止するスレッド（drop で止めて解除する）
+pub struct KeepAwake {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl KeepAwake {
+    pub fn spawn(metrics: Arc<Metrics>) -> std::io::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_for_thread = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("keep-awake".to_string())
+            .spawn(move || {
+                let mut awake = SessionAwake::new(set_execution_state);
+                while !stop_for_thread.load(Ordering::Relaxed) {
+                    awake.update(metrics.sessions_active() > 0);
+                    std::thread::park_timeout(POLL_INTERVAL);
+                }
+                awake.update(false);
+            })?;
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for KeepAwake {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// 呼び出したスレッドの実行状態を設定する（`keep_awake` が false なら解除）
+fn set_execution_state(keep_awake: bool) -> bool {
+    let flags = if keep_awake {
+        ES_CONTINUOUS | ES_SYSTEM_REQUIRED | ES_DISPLAY_REQUIRED
+    } else {
+        ES_CONTINUOUS
+    };
+    // 失敗すると 0 を返す（成功時は以前の状態）
+    unsafe { SetThreadExecutionState(flags).0 != 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engages_on_first_session_and_releases_on_last() {
+        let mut awake = SessionAwake::new(|_| true);
+        assert!(!awake.update(false));
+        assert!(awake.update(true));
+        // 2 人目が来ても設定し直さない
+        assert!(!awake.update(true));
+        assert!(awake.update(false));
+        assert!(!awake.engaged);
+
+        // 設定に失敗したら状態を変えず、次の確認でやり直す
+        let mut failing = SessionAwake::new(|_| false);
+        assert!(!failing.update(true));
+        assert!(!failing.engaged);
+    }
+}
@@ -0,0 +1,74 @@
+// 遅延プロファイル（`--latency-profile`）
+//
+// 遅延に効く設定はエンコーダー・キャプチャ・音声に散らばっていて、個別に合わせるのは間違えやすい。
+// プロファイルはそれらの組み合わせのプリセットで、CLI・環境変数・設定ファイルのどれでも
+// 指定していない設定だけを埋める（優先順位は CLI > 環境変数 > 設定ファイル > プロファイル > 既定値）。
+//
+// | 設定                | ultra-low   | balanced    | quality |
+// |---------------------|-------------|-------------|---------|
+// | encoder-mode        | low-latency | low-latency | quality |
+// | frame-queue-depth   | 1           | 2           | 3       |
+// | audio-buffer-ms     | 20          | 50          | 100     |
+// | opus-frame-ms       | 10          | 20          | 20      |
+// | opus-application    | lowdelay    | audio       | audio   |
+//
+// encoder-mode の low-latency は B フレームなし・MF_LOW_LATENCY、quality は B フレームあり。
+// 送出ペーシング（pacing-kbps）はどのプロファイルでも有効にしない（溜めて送る分だけ遅延が増える）。
+
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LatencyProfile {
+    /// 操作の応答を最優先する（フレームを溜めず、音声も最短にする）
+    UltraLow,
+    /// 遅延を抑えつつ、詰まったときの取りこぼしや音声の効率とのバランスを取る
+    Balanced,
+    /// 画質と効率を優先する（B フレームを使う）
+    Quality,
+}
+
+impl LatencyProfile {
+    /// プロファイルが決める設定（設定ファイルのキー, 値）
+    pub fn settings(self) -> [(&'static str, Value); 5] {
+        let (encoder_mode, frame_queue_depth, audio_buffer_ms, opus_frame_ms, opus_application) =
+            match self {
+                LatencyProfile::UltraLow => ("low-latency", 1, 20, 10, "lowdelay"),
+                LatencyProfile::Balanced => ("low-latency", 2, 50, 20, "audio"),
+                LatencyProfile::Quality => ("quality", 3, 100, 20, "audio"),
+            };
+        [
+            ("encoder-mode", json!(encoder_mode)),
+            ("frame-queue-depth", json!(frame_queue_depth)),
+            ("audio-buffer-ms", json!(audio_buffer_ms)),
+            ("opus-frame-ms", json!(opus_frame_ms)),
+            ("opus-application", json!(opus_application)),
+        ]
+    }
+}
+
+impl FromStr for LatencyProfile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ultra-low" => Ok(LatencyProfile::UltraLow),
+            "balanced" => Ok(LatencyProfile::Balanced),
+            "quality" => Ok(LatencyProfile::Quality),
+            _ => Err(format!(
+                "Invalid latency profile: {} (expected \"ultra-low\", \"balanced\" or \"quality\")",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for LatencyProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LatencyProfile::UltraLow => write!(f, "ultra-low"),
+            LatencyProfile::Balanced => write!(f, "balanced"),
+            LatencyProfile::Quality => write!(f, "quality"),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+mod capture_supervisor;
+mod capture_target;
+mod config_file;
+mod host;
+mod ipc;
+mod keep_awake;
+mod latency_profile;
+mod metrics_server;
+mod placeholder;
+#[cfg(feature = "h264")]
+mod selftest;
+mod shutdown;
+mod startup_target;
+mod web_client;
+mod window_list;
+
+pub use config_file::{load_config, ConfigSource};
+pub use host::{Host, HostConfig, HostHandle};
+pub use ipc::{HostBridge, HostCommand, HostResponse};
+#[cfg(feature = "h264")]
+pub use selftest::{run_selftest, SelftestConfig, SelftestReport};
+pub use window_list::{list_capturable_windows, THUMBNAIL_MAX_EDGE, THUMBNAIL_TIMEOUT};
@@ -1,349 +1,174 @@
-use anyhow::{Context, Result};
-use clap::Parser;
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::pin;
-use tokio::sync::mpsc;
-use tracing::info;
+use anyhow::Result;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use hostd::{Host, HostConfig};
+use std::path::PathBuf;
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
-use audio_capture;
-use audio_capture_mock;
-use audio_encoder::OpusEncoderFactory;
-use audio_stream::AudioStreamService;
-use core_types::{
-    AudioCaptureMessage, AudioFrame, CaptureBackend, CaptureMessage, DataChannelMessage, Frame,
-    SignalingResponse, TaggerCommand, VideoCodec, VideoEncoderFactory, VideoStreamMessage,
-};
-#[cfg(feature = "h264")]
-use encoder::h264::mmf::MediaFoundationH264EncoderFactory;
-use input::InputService;
-use signaling::SignalingClient;
-use video_capture;
-use video_capture_mock;
-use video_stream::VideoStreamService;
-use webrtc::WebRtcService;
-use tagger::TaggerService;
-use tagger_setup::TaggerSetup;
-
 #[derive(Parser, Debug)]
 #[command(name = "hostd")]
 #[command(about = "RemoteRG Host Daemon")]
 struct Args {
-    /// Cloudflare WebSocket URL (e.g., wss://example.com/api/signal)
-    #[arg(long, default_value = "ws://localhost:3000/api/signal")]
-    cloudflare_url: String,
-
-    /// Session ID
-    #[arg(long, default_value = "fixed")]
-    session_id: String,
-
     /// Log level (trace, debug, info, warn, error)
     #[arg(short, long, env = "RUST_LOG", default_value = "info")]
     log_level: String,
 
-    /// Capture target window handle (HWND)
-    #[arg(long, env = "REMOTERG_HWND", default_value_t = 0)]
-    hwnd: u64,
+    /// Load settings from this TOML file (keys are the flag names without "--");
+    /// flags and environment variables override values from the file
+    #[arg(long, env = "REMOTERG_CONFIG")]
+    config: Option<PathBuf>,
 
-    /// Use mock implementations for video and audio capture
+    /// List available monitors and exit
     #[arg(long)]
-    mock: bool,
+    list_monitors: bool,
 
-    /// Port for local LLM server (llama-server)
-    #[arg(long, default_value_t = 8081)]
-    llm_port: u16,
-
-    /// Directory for saving screenshots
-    #[arg(long, env = "REMOTERG_SCREENSHOTS", default_value = "screenshots")]
-    screenshots_dir: String,
+    /// List available audio render endpoints and input devices and exit
+    #[arg(long)]
+    list_audio_devices: bool,
 
-    /// Path to the llama-server executable or directory
-    #[arg(long, env = "REMOTERG_LLAMA_SERVER_PATH")]
-    llama_server_path: Option<String>,
-}
+    /// Print capturable windows with thumbnails as JSON and exit
+    #[arg(long)]
+    list_windows: bool,
 
-enum CaptureServiceEnum {
-    Real(video_capture::CaptureService),
-    Mock(video_capture_mock::CaptureService),
-}
+    /// List the child windows (HWND, class name, title) of this window handle and exit
+    #[arg(long, value_name = "HWND")]
+    list_child_windows: Option<u64>,
 
-impl CaptureServiceEnum {
-    async fn run(self) -> Result<()> {
-        match self {
-            CaptureServiceEnum::Real(service) => service.run().await,
-            CaptureServiceEnum::Mock(service) => service.run().await,
-        }
-    }
-}
+    #[command(subcommand)]
+    command: Option<Command>,
 
-enum AudioCaptureServiceEnum {
-    Real(audio_capture::AudioCaptureService),
-    Mock(audio_capture_mock::AudioCaptureService),
+    #[command(flatten)]
+    host: HostConfig,
 }
 
-impl AudioCaptureServiceEnum {
-    async fn run(self) -> Result<()> {
-        match self {
-            AudioCaptureServiceEnum::Real(service) => service.run().await,
-            AudioCaptureServiceEnum::Mock(service) => service.run().await,
-        }
-    }
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Encode mock frames with each H.264 encoder, decode them back and report the round-trip PSNR
+    #[cfg(feature = "h264")]
+    Selftest {
+        /// Frame width
+        #[arg(long, default_value_t = 1280)]
+        width: u32,
+
+        /// Frame height
+        #[arg(long, default_value_t = 720)]
+        height: u32,
+
+        /// Frame rate passed to the encoders
+        #[arg(long, default_value_t = 30)]
+        fps: u32,
+
+        /// Number of frames to encode per encoder
+        #[arg(long, default_value_t = 60)]
+        frames: usize,
+
+        /// Fail if the average round-trip PSNR (dB) is below this
+        #[arg(long, default_value_t = 30.0)]
+        min_psnr: f64,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     // ログ設定
     let filter = EnvFilter::new(&args.log_level);
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    info!("Starting RemoteRG Host Daemon");
-    info!(
-        "Cloudflare URL: {}, Session ID: {}",
-        args.cloudflare_url, args.session_id
-    );
-    info!("Log Level: {}", args.log_level);
-    info!("Capture HWND: {}", args.hwnd);
-    info!("LLM Port: {}", args.llm_port);
-    info!("Screenshots Directory: {}", args.screenshots_dir);
-    if let Some(path) = &args.llama_server_path {
-        info!("LLM Server Path: {}", path);
-    }
-
-    // LLM Sidecar Setup
-    let mut tagger_setup = TaggerSetup::new();
-    let llama_server_path = args.llama_server_path.as_ref().map(std::path::PathBuf::from);
-
-    if let Err(e) = tagger_setup
-        .start(args.llm_port, llama_server_path.clone(), None, None)
-        .await
-    {
-        tracing::warn!("Failed to start LLM sidecar: {}", e);
+    match args.command {
+        #[cfg(feature = "h264")]
+        Some(Command::Selftest {
+            width,
+            height,
+            fps,
+            frames,
+            min_psnr,
+        }) => {
+            let config = hostd::SelftestConfig {
+                width,
+                height,
+                fps,
+                frames,
+                min_psnr_db: min_psnr,
+            };
+            for report in hostd::run_selftest(&config).await? {
+                println!(
+                    "{}: OK ({}/{} frames decoded, {} bytes, PSNR avg {:.2} dB / min {:.2} dB)",
+                    report.encoder,
+                    report.frames_decoded,
+                    report.frames_encoded,
+                    report.bytes,
+                    report.average_psnr_db,
+                    report.min_psnr_db
+                );
+            }
+            return Ok(());
+        }
+        None => {}
     }
-    let tagger_service = TaggerService::new(args.llm_port);
-
-    // チャンネル作成
-    let (frame_tx, frame_rx) = mpsc::channel::<Frame>(100);
-    let (capture_cmd_tx, capture_cmd_rx) = mpsc::channel::<CaptureMessage>(10);
-    let (signaling_response_tx, signaling_response_rx) = mpsc::channel::<SignalingResponse>(100);
-    let (data_channel_tx, data_channel_rx) = mpsc::channel::<DataChannelMessage>(100);
-
-    // 音声チャンネル作成
-    let (audio_capture_cmd_tx, audio_capture_cmd_rx) = mpsc::channel::<AudioCaptureMessage>(10);
-
-    // Tagger Config Channel
-    let (tagger_cmd_tx, mut tagger_cmd_rx) = mpsc::channel::<TaggerCommand>(10);
-
-    // ビデオストリームメッセージチャネル（キーフレーム要求など）
-    let (video_stream_msg_tx, video_stream_msg_rx) = mpsc::channel::<VideoStreamMessage>(10);
 
-    // ビデオトラック情報を受け渡すためのチャンネル
-    let (video_track_tx, video_track_rx) = mpsc::channel::<(
-        Arc<webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample>,
-        Arc<webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender>,
-        Arc<std::sync::atomic::AtomicBool>, // connection_ready
-    )>(10);
-
-    // 音声トラック情報を受け渡すためのチャンネル
-    let (audio_track_tx, audio_track_rx) = mpsc::channel::<(
-        Arc<webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample>,
-        Arc<webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender>,
-    )>(10);
-
-    #[cfg(not(feature = "h264"))]
-    compile_error!("h264 feature must be enabled for hostd");
-
-    let mut encoder_factories: HashMap<VideoCodec, Arc<dyn VideoEncoderFactory>> = HashMap::new();
-    #[cfg(feature = "h264")]
-    {
-        encoder_factories.insert(
-            VideoCodec::H264,
-            // Arc::new(OpenH264EncoderFactory::new()),
-            Arc::new(MediaFoundationH264EncoderFactory::new()),
-        );
+    if args.list_audio_devices {
+        for (id, name) in audio_capture::list_audio_endpoints()? {
+            println!("{}\t{}", name, id);
+        }
+        for (id, name) in audio_capture::list_audio_input_devices()? {
+            println!("[input] {}\t{}", name, id);
+        }
+        return Ok(());
     }
 
-    // 音声フレーム用のチャンネルを作成
-    let (audio_frame_tx, audio_frame_rx) = mpsc::channel::<AudioFrame>(100);
-
-    // デフォルトのビデオエンコーダーを選択
-    let default_video_encoder = encoder_factories
-        .get(&VideoCodec::H264)
-        .expect("H264 encoder must be available")
-        .clone();
-
-    // 音声エンコーダーファクトリを作成
-    let audio_encoder_factory = Arc::new(OpusEncoderFactory::new());
-
-    // サービス作成
-    let capture_service = if args.mock {
-        CaptureServiceEnum::Mock(video_capture_mock::CaptureService::new(
-            frame_tx,
-            capture_cmd_rx,
-        ))
-    } else {
-        CaptureServiceEnum::Real(video_capture::CaptureService::new(frame_tx, capture_cmd_rx))
-    };
-    let audio_capture_service = if args.mock {
-        AudioCaptureServiceEnum::Mock(audio_capture_mock::AudioCaptureService::new(
-            audio_frame_tx,
-            audio_capture_cmd_rx,
-        ))
-    } else {
-        AudioCaptureServiceEnum::Real(audio_capture::AudioCaptureService::new(
-            audio_frame_tx,
-            audio_capture_cmd_rx,
-        ))
-    };
-    // VideoStreamService を作成
-    let video_stream_service =
-        VideoStreamService::new(frame_rx, default_video_encoder, video_stream_msg_rx);
-
-    // WebRTCサービスの起動
-    // Outgoing DataChannelメッセージ用チャネル (InputService -> WebRtcService)
-    let (outgoing_dc_tx, outgoing_dc_rx) = mpsc::channel(100);
-
-    let (webrtc_service, webrtc_msg_tx) = WebRtcService::new(
-        signaling_response_tx,
-        data_channel_tx,
-        Some(outgoing_dc_rx), // Pass outgoing_dc_rx
-        Some(video_track_tx),
-        Some(video_stream_msg_tx.clone()), // Use clone of video_stream_msg_tx
-        Some(audio_track_tx),
-    );
-
-    // WebRtcService::run() に渡すために webrtc_msg_tx をクローン
-    let webrtc_msg_tx_for_run = webrtc_msg_tx.clone();
-
-    let audio_stream_service = AudioStreamService::new(audio_frame_rx, audio_encoder_factory);
-
-    // CaptureServiceへのコマンド送信チャネルを複製
-    let capture_cmd_tx_for_input = capture_cmd_tx.clone();
-    
-    let input_service = InputService::new(
-        data_channel_rx, 
-        capture_cmd_tx_for_input, 
-        outgoing_dc_tx, // Pass outgoing_dc_tx
-        tagger_service,
-        tagger_cmd_tx,
-        std::path::PathBuf::from(args.screenshots_dir),
-        args.hwnd,
-    );
-    let signaling_client = SignalingClient::new(
-        args.cloudflare_url,
-        args.session_id,
-        webrtc_msg_tx,
-        signaling_response_rx,
-    );
-
-    // CaptureServiceを開始
-    capture_cmd_tx
-        .send(CaptureMessage::Start { hwnd: args.hwnd })
-        .await
-        .context("Failed to start capture service")?;
-    if args.mock {
-        info!("CaptureService started (mock frames)");
-    } else {
-        info!("CaptureService started (real capture)");
+    if args.list_windows {
+        let windows =
+            hostd::list_capturable_windows(hostd::THUMBNAIL_MAX_EDGE, hostd::THUMBNAIL_TIMEOUT)
+                .await?;
+        println!("{}", serde_json::to_string_pretty(&windows)?);
+        return Ok(());
     }
 
-    // AudioCaptureServiceを開始
-    audio_capture_cmd_tx
-        .send(AudioCaptureMessage::Start { hwnd: args.hwnd })
-        .await
-        .context("Failed to start audio capture service")?;
-    if args.mock {
-        info!("AudioCaptureService started (mock audio)");
-    } else {
-        info!("AudioCaptureService started (real audio)");
+    if let Some(parent) = args.list_child_windows {
+        for child in video_capture::list_child_windows(parent)? {
+            println!("{}\t{}\t{}", child.hwnd, child.class_name, child.title);
+        }
+        return Ok(());
     }
 
-    // サービスを独立タスクとして起動（Send でない WebRTC はこのスレッドで駆動する）
-    let mut capture_handle = tokio::spawn(async move { capture_service.run().await });
-    let mut audio_capture_handle = tokio::spawn(async move { audio_capture_service.run().await });
-    let mut input_handle = tokio::spawn(async move { input_service.run().await });
-    let mut signaling_handle = tokio::spawn(async move { signaling_client.run().await });
-
-    // VideoStreamService起動タスク
-    let mut video_stream_handle = tokio::spawn(async move {
-        video_stream_service.run(video_track_rx).await
-    });
-
-    // AudioStreamService起動タスク
-    let mut audio_stream_handle = tokio::spawn(async move {
-        audio_stream_service.run(audio_track_rx).await
-    });
-
-    // WebRTC は非 Send 型を含むため spawn せず現在のタスクで実行する
-    let webrtc_fut = webrtc_service.run(webrtc_msg_tx_for_run);
-    pin!(webrtc_fut);
-
-    loop {
-        tokio::select! {
-            cmd = tagger_cmd_rx.recv() => {
-                match cmd {
-                    Some(TaggerCommand::UpdateConfig { config }) => {
-                        info!("Restarting llama-server with new config: {:?}", config);
-                        let model_path = config.model_path.map(std::path::PathBuf::from);
-                        let mmproj_path = config.mmproj_path.map(std::path::PathBuf::from);
-                        if let Err(e) = tagger_setup.restart(config.port, llama_server_path.clone(), model_path, mmproj_path).await {
-                             tracing::error!("Failed to restart llama-server: {}", e);
-                        }
-                    }
-                    Some(TaggerCommand::GetConfig { reply_tx }) => {
-                        let (port, model_path, mmproj_path) = tagger_setup.get_config();
-                        let config = core_types::LlmConfig {
-                            port,
-                            model_path: model_path.map(|p| p.to_string_lossy().to_string()),
-                            mmproj_path: mmproj_path.map(|p| p.to_string_lossy().to_string()),
-                        };
-                        let _ = reply_tx.send(config);
-                    }
-                    None => {
-                        info!("Tagger command channel closed");
-                        break;
-                    }
+    if args.list_monitors {
+        for monitor in video_capture::list_monitors()? {
+            println!(
+                "{}\t{}\t{}x{}+{}+{}\t{}{}",
+                monitor.index,
+                monitor.name,
+                monitor.width,
+                monitor.height,
+                monitor.x,
+                monitor.y,
+                monitor.adapter,
+                if monitor.virtual_display {
+                    " (virtual)"
+                } else {
+                    ""
                 }
-            }
-            result = &mut webrtc_fut => match result {
-                Ok(()) => { info!("WebRtcService finished"); break; },
-                Err(e) => { tracing::error!("WebRtcService error: {}", e); break; },
-            },
-            result = &mut capture_handle => match result {
-                Ok(Ok(())) => { info!("CaptureService finished"); break; },
-                Ok(Err(e)) => { tracing::error!("CaptureService error: {}", e); break; },
-                Err(e) => { tracing::error!("CaptureService task panicked: {}", e); break; },
-            },
-            result = &mut audio_capture_handle => match result {
-                Ok(Ok(())) => { info!("AudioCaptureService finished"); break; },
-                Ok(Err(e)) => { tracing::error!("AudioCaptureService error: {}", e); break; },
-                Err(e) => { tracing::error!("AudioCaptureService task panicked: {}", e); break; },
-            },
-            result = &mut video_stream_handle => match result {
-                Ok(Ok(())) => { info!("VideoStreamService finished"); break; },
-                Ok(Err(e)) => { tracing::error!("VideoStreamService error: {}", e); break; },
-                Err(e) => { tracing::error!("VideoStreamService task panicked: {}", e); break; },
-            },
-            result = &mut audio_stream_handle => match result {
-                Ok(Ok(())) => { info!("AudioStreamService finished"); break; },
-                Ok(Err(e)) => { tracing::error!("AudioStreamService error: {}", e); break; },
-                Err(e) => { tracing::error!("AudioStreamService task panicked: {}", e); break; },
-            },
-            result = &mut input_handle => match result {
-                Ok(Ok(())) => { info!("InputService finished"); break; },
-                Ok(Err(e)) => { tracing::error!("InputService error: {}", e); break; },
-                Err(e) => { tracing::error!("InputService task panicked: {}", e); break; },
-            },
-            result = &mut signaling_handle => match result {
-                Ok(Ok(())) => { info!("SignalingService finished"); break; },
-                Ok(Err(e)) => { tracing::error!("SignalingService error: {}", e); break; },
-                Err(e) => { tracing::error!("SignalingService task panicked: {}", e); break; },
-            },
+            );
         }
+        return Ok(());
     }
 
-    info!("Host daemon stopped");
-    Ok(())
+    info!("Log Level: {}", args.log_level);
+    let config = hostd::load_config(args.config.as_deref(), args.host, &matches)?;
+    let mut host = Host::start(config)?;
+
+    // Ctrl-C で各サービスを順に止めてから終了する
+    tokio::select! {
+        result = host.wait() => return result,
+        result = tokio::signal::ctrl_c() => match result {
+            Ok(()) => info!("Ctrl-C received, shutting down"),
+            Err(e) => {
+                warn!("Failed to listen for Ctrl-C: {}", e);
+                return host.wait().await;
+            }
+        },
+    }
+    host.shutdown().await
 }
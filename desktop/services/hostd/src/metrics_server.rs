@@ -0,0 +1,121 @@
+// メトリクスの HTTP エンドポイント（`--metrics-port`）
+//
+// Prometheus がスクレイプするだけなので、HTTP サーバーのクレートは使わずに
+// `GET /metrics` だけに応答する最小限の実装にしている（それ以外は 404）。
+
+use anyhow::Result;
+use core_types::Metrics;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+/// リクエストヘッダーを読み終えるまでの上限
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// リクエストヘッダーの最大サイズ
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+/// Prometheus のテキスト形式
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// `listener` で受けた接続に `metrics` を返し続ける
+pub async fn serve_metrics(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &metrics).await {
+                debug!("Metrics request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await??;
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some(_), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+        _ => {
+            warn!("Malformed metrics request: {:?}", request_line);
+            ("400 Bad Request", "bad request\n".to_string())
+        }
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// 空行までのリクエストヘッダーを読む（本文は読まない）
+pub(crate) async fn read_request_head(stream: &mut TcpStream) -> Result<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        anyhow::ensure!(buf.len() <= MAX_REQUEST_BYTES, "request header too large");
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_scrape_metrics_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.video_frames_dropped.fetch_add(3, Ordering::Relaxed);
+        metrics.session_started();
+        let server = tokio::spawn(serve_metrics(listener, metrics));
+
+        let response = get(addr, "/metrics").await;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"));
+        for name in [
+            "remoterg_video_encode_fps",
+            "remoterg_video_bitrate_bps",
+            "remoterg_video_frames_dropped_total",
+            "remoterg_sessions_active",
+            "remoterg_audio_silence_ratio",
+        ] {
+            assert!(body.contains(&format!("\n{} ", name)), "{} missing", name);
+        }
+        assert!(body.contains("\nremoterg_video_frames_dropped_total 3\n"));
+        assert!(body.contains("\nremoterg_sessions_active 1\n"));
+
+        let response = get(addr, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        server.abort();
+    }
+}
@@ -0,0 +1,73 @@
+// キャプチャ対象が決まるまでプレースホルダーのフレームを送る
+//
+// 対象が選ばれると CaptureTargetSwitcher が target_hwnd を更新してキーフレームを要求するので、
+// ここでは target_hwnd が 0 でなくなったら送るのをやめるだけでよい。実際のキャプチャと
+// サイズ・フレームレートが違えば、フレームルーターがエンコーダーを作り直して IDR から送り直す。
+
+use core_types::Frame;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::info;
+
+/// `target_hwnd` が設定されるか受信側が閉じるまで、`frame` をそのフレームレートで送り続ける
+pub async fn run_placeholder(
+    frame: Frame,
+    frame_tx: mpsc::Sender<Frame>,
+    target_hwnd: Arc<AtomicU64>,
+) {
+    let period = Duration::from_secs_f64(1.0 / frame.fps.max(1) as f64);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    info!(
+        "Streaming placeholder ({}x{} @{}fps) until a capture target is selected",
+        frame.width, frame.height, frame.fps
+    );
+
+    loop {
+        interval.tick().await;
+        if target_hwnd.load(Ordering::Relaxed) != 0 {
+            info!("Capture target selected, stopping placeholder");
+            return;
+        }
+        let frame = Frame {
+            windows_timespan: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+                / 100,
+            ..frame.clone()
+        };
+        match frame_tx.try_send(frame) {
+            // エンコーダーが詰まっている間は同じ絵なので捨ててよい
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use video_capture_mock::{placeholder_frame, PlaceholderStyle};
+
+    #[tokio::test]
+    async fn test_stops_when_capture_target_is_selected() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(4);
+        let target_hwnd = Arc::new(AtomicU64::new(0));
+        let frame = placeholder_frame(PlaceholderStyle::Solid { r: 0, g: 0, b: 0 }, 4, 4);
+        let handle = tokio::spawn(run_placeholder(frame, frame_tx, target_hwnd.clone()));
+
+        let first = frame_rx.recv().await.unwrap();
+        let second = frame_rx.recv().await.unwrap();
+        assert_eq!((first.width, first.height), (4, 4));
+        assert!(second.windows_timespan >= first.windows_timespan);
+
+        target_hwnd.store(42, Ordering::Relaxed);
+        handle.await.unwrap();
+        // 止まった後はチャネルが閉じる（残っていたフレームを読み切ると None）
+        while frame_rx.recv().await.is_some() {}
+    }
+}
@@ -0,0 +1,308 @@
+// エンコード → デコードの自己診断（`hostd selftest`）
+//
+// ブラウザなしで「このマシンのエンコーダーがデコードできる H.264 を出しているか」を確かめる。
+// モックキャプチャのフレームを実際のエンコーダーファクトリ（Media Foundation と OpenH264）で
+// エンコードし、OpenH264 のデコーダーで戻して元フレームとの PSNR を測る。
+
+use anyhow::{Context, Result};
+use core_types::{
+    CaptureBackend, CaptureMessage, CaptureSize, CaptureTarget, EncodeJob, Frame, PixelFormat,
+    VideoEncoderFactory,
+};
+use encoder::h264::mmf::MediaFoundationH264EncoderFactory;
+use encoder::h264::openh264::OpenH264EncoderFactory;
+use openh264::decoder::Decoder;
+use openh264::formats::YUVSource;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// 1 フレームのエンコード結果を待つ上限（初回はエンコーダーの起動を含む）
+const ENCODE_TIMEOUT: Duration = Duration::from_secs(10);
+/// モックキャプチャからフレームが届くのを待つ上限（初回はフレームの事前生成を含む）
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(30);
+/// 完全に一致した場合の PSNR（MSE が 0 だと無限大になるため）
+const LOSSLESS_PSNR_DB: f64 = 100.0;
+
+/// 自己診断の設定
+#[derive(Debug, Clone)]
+pub struct SelftestConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub frames: usize,
+    /// これを下回る平均 PSNR（dB）は失敗とする
+    pub min_psnr_db: f64,
+}
+
+/// エンコーダー 1 つ分の結果
+#[derive(Debug, Clone)]
+pub struct SelftestReport {
+    pub encoder: &'static str,
+    pub frames_encoded: usize,
+    pub frames_decoded: usize,
+    pub bytes: usize,
+    /// デコードできたフレームの平均 PSNR（dB）
+    pub average_psnr_db: f64,
+    pub min_psnr_db: f64,
+}
+
+/// モックキャプチャのフレームを各エンコーダーでエンコードし、デコードして品質を確かめる
+/// 全エンコーダーの結果を返す。1 つでも失敗した場合はその理由をまとめたエラーを返す
+pub async fn run_selftest(config: &SelftestConfig) -> Result<Vec<SelftestReport>> {
+    anyhow::ensure!(config.frames > 0, "selftest needs at least one frame");
+    let frames = capture_mock_frames(config)
+        .await
+        .context("mock capture failed")?;
+    info!(
+        "Captured {} mock frames ({}x{})",
+        frames.len(),
+        config.width,
+        config.height
+    );
+
+    let mf_factory = MediaFoundationH264EncoderFactory::new(false);
+    let mf_factory: Option<Arc<dyn VideoEncoderFactory>> = if mf_factory.use_media_foundation() {
+        Some(Arc::new(mf_factory))
+    } else {
+        None
+    };
+    let factories: [(&'static str, Option<Arc<dyn VideoEncoderFactory>>); 2] = [
+        ("Media Foundation", mf_factory),
+        ("OpenH264", Some(Arc::new(OpenH264EncoderFactory::new()))),
+    ];
+
+    let mut reports = Vec::new();
+    let mut failures = Vec::new();
+    for (name, factory) in factories {
+        let Some(factory) = factory else {
+            failures.push(format!(
+                "{}: encoder is not available on this machine",
+                name
+            ));
+            continue;
+        };
+        match round_trip(name, factory.as_ref(), &frames, config).await {
+            Ok(report) => reports.push(report),
+            Err(e) => failures.push(format!("{}: {:#}", name, e)),
+        }
+    }
+
+    if !failures.is_empty() {
+        for report in &reports {
+            info!("{} passed: {:?}", report.encoder, report);
+        }
+        anyhow::bail!("selftest failed:\n  {}", failures.join("\n  "));
+    }
+    Ok(reports)
+}
+
+/// モックキャプチャを起動して `config.frames` 枚のフレームを受け取る
+async fn capture_mock_frames(config: &SelftestConfig) -> Result<Vec<Frame>> {
+    let (frame_tx, mut frame_rx) = mpsc::channel::<Frame>(config.frames);
+    let (cmd_tx, cmd_rx) = mpsc::channel::<CaptureMessage>(10);
+    let capture = video_capture_mock::CaptureService::new(frame_tx, cmd_rx);
+    let capture_task = tokio::spawn(capture.run());
+
+    cmd_tx
+        .send(CaptureMessage::UpdateConfig {
+            size: CaptureSize::Custom {
+                width: config.width,
+                height: config.height,
+            },
+            fps: config.fps,
+        })
+        .await?;
+    cmd_tx
+        .send(CaptureMessage::Start {
+            target: CaptureTarget::PrimaryMonitor,
+        })
+        .await?;
+
+    let mut frames = Vec::with_capacity(config.frames);
+    while frames.len() < config.frames {
+        let frame = tokio::time::timeout(CAPTURE_TIMEOUT, frame_rx.recv())
+            .await
+            .context("timed out waiting for a mock frame")?
+            .context("mock capture stopped")?;
+        // 設定変更前の既定サイズのフレームは捨てる
+        if (frame.width, frame.height) == (config.width, config.height) {
+            frames.push(frame);
+        }
+    }
+
+    drop(cmd_tx);
+    capture_task.abort();
+    Ok(frames)
+}
+
+/// `frames` をエンコードし、出力をデコードして元フレームと比べる
+async fn round_trip(
+    name: &'static str,
+    factory: &dyn VideoEncoderFactory,
+    frames: &[Frame],
+    config: &SelftestConfig,
+) -> Result<SelftestReport> {
+    info!("Testing {} encoder", name);
+    let (job_slot, mut result_rx) = factory.setup();
+    let mut decoder = Decoder::new().context("failed to create OpenH264 decoder")?;
+
+    // デコーダーは入力順にフレームを出すので、まだ出ていない元フレームを順に並べておく
+    let mut pending: VecDeque<&Frame> = VecDeque::new();
+    let mut report = SelftestReport {
+        encoder: name,
+        frames_encoded: 0,
+        frames_decoded: 0,
+        bytes: 0,
+        average_psnr_db: 0.0,
+        min_psnr_db: f64::INFINITY,
+    };
+    let mut psnr_sum = 0.0;
+
+    for (index, frame) in frames.iter().enumerate() {
+        // スロットは最新の 1 件しか保持しないので、結果を受け取ってから次を入れる
+        job_slot.set(EncodeJob {
+            width: frame.width,
+            height: frame.height,
+            rgba: frame.data.clone(),
+            timestamp: frame.windows_timespan,
+            enqueue_at: Instant::now(),
+            request_keyframe: None,
+            fps: frame.fps,
+            format: frame.format,
+            sequence: frame.sequence,
+            scale_to: frame.scale_to,
+            max_bitrate_bps: None,
+        });
+        pending.push_back(frame);
+
+        let result = tokio::time::timeout(ENCODE_TIMEOUT, result_rx.recv())
+            .await
+            .with_context(|| {
+                format!(
+                    "no encoder output for frame {} within {:?}",
+                    index, ENCODE_TIMEOUT
+                )
+            })?
+            .context("encoder worker stopped")?;
+        anyhow::ensure!(
+            !result.sample_data.is_empty(),
+            "encoder produced an empty sample for frame {}",
+            index
+        );
+        if index == 0 {
+            anyhow::ensure!(result.is_keyframe, "first encoded frame is not a keyframe");
+        }
+        report.frames_encoded += 1;
+        report.bytes += result.sample_data.len();
+
+        let decoded = decoder
+            .decode(&result.sample_data)
+            .with_context(|| format!("encoded frame {} is not decodable", index))?;
+        if let Some(yuv) = decoded {
+            let (width, height) = yuv.dimensions();
+            let mut rgba = vec![0u8; width * height * 4];
+            yuv.write_rgba8(&mut rgba);
+            let source = pending
+                .pop_front()
+                .context("decoder returned more frames than were encoded")?;
+            let psnr = psnr_db(source, &rgba, width as u32, height as u32);
+            report.frames_decoded += 1;
+            report.min_psnr_db = report.min_psnr_db.min(psnr);
+            psnr_sum += psnr;
+        }
+    }
+    job_slot.shutdown();
+
+    anyhow::ensure!(
+        report.frames_decoded > 0,
+        "none of the {} encoded frames could be decoded",
+        report.frames_encoded
+    );
+    if report.frames_decoded < report.frames_encoded {
+        warn!(
+            "{}: decoded {} of {} frames (the decoder may still buffer the rest)",
+            name, report.frames_decoded, report.frames_encoded
+        );
+    }
+    report.average_psnr_db = psnr_sum / report.frames_decoded as f64;
+    anyhow::ensure!(
+        report.average_psnr_db >= config.min_psnr_db,
+        "round-trip PSNR {:.1} dB is below {:.1} dB (the encoder output does not match the input)",
+        report.average_psnr_db,
+        config.min_psnr_db
+    );
+    Ok(report)
+}
+
+/// 元フレームとデコード結果（RGBA）の RGB の PSNR（dB）
+/// サイズが違う場合（エンコーダーが偶数に丸めたなど）は重なる範囲だけを比べる
+fn psnr_db(source: &Frame, decoded_rgba: &[u8], width: u32, height: u32) -> f64 {
+    let (r, b) = match source.format {
+        PixelFormat::Rgba8 => (0, 2),
+        PixelFormat::Bgra8 => (2, 0),
+    };
+    let w = source.width.min(width) as usize;
+    let h = source.height.min(height) as usize;
+    let mut squared_error = 0u64;
+    for y in 0..h {
+        for x in 0..w {
+            let s = (y * source.width as usize + x) * 4;
+            let d = (y * width as usize + x) * 4;
+            for (sc, dc) in [(r, 0), (1, 1), (b, 2)] {
+                let diff = source.data[s + sc] as i64 - decoded_rgba[d + dc] as i64;
+                squared_error += (diff * diff) as u64;
+            }
+        }
+    }
+    let samples = (w * h * 3) as f64;
+    if squared_error == 0 || samples == 0.0 {
+        return LOSSLESS_PSNR_DB;
+    }
+    let mse = squared_error as f64 / samples;
+    (10.0 * (255.0 * 255.0 / mse).log10()).min(LOSSLESS_PSNR_DB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(format: PixelFormat, pixel: [u8; 4]) -> Frame {
+        Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(pixel.repeat(4)),
+            windows_timespan: 0,
+            fps: 30,
+            format,
+            dirty_fraction: None,
+            sequence: 0,
+            scale_to: None,
+        }
+    }
+
+    #[test]
+    fn test_psnr_of_identical_frames_is_lossless() {
+        let source = frame(PixelFormat::Rgba8, [10, 20, 30, 255]);
+        let decoded = [10, 20, 30, 255].repeat(4);
+        assert_eq!(psnr_db(&source, &decoded, 2, 2), LOSSLESS_PSNR_DB);
+    }
+
+    #[test]
+    fn test_psnr_compares_bgra_in_rgb_order() {
+        let source = frame(PixelFormat::Bgra8, [30, 20, 10, 255]);
+        let decoded = [10, 20, 30, 255].repeat(4);
+        assert_eq!(psnr_db(&source, &decoded, 2, 2), LOSSLESS_PSNR_DB);
+    }
+
+    #[test]
+    fn test_psnr_of_uniform_error() {
+        let source = frame(PixelFormat::Rgba8, [100, 100, 100, 255]);
+        // 全サンプルが 1 ずれると MSE = 1 で 20*log10(255) ≈ 48.13 dB
+        let decoded = [101, 99, 101, 0].repeat(4);
+        let psnr = psnr_db(&source, &decoded, 2, 2);
+        assert!((psnr - 48.13).abs() < 0.01, "psnr = {}", psnr);
+    }
+}
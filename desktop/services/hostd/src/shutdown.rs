@@ -0,0 +1,148 @@
+use core_types::{AudioCaptureMessage, CaptureMessage, WebRtcMessage};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// 停止通知を送るときの待ち時間の上限（サービスが詰まっていても終了処理を止めない）
+const STOP_SEND_TIMEOUT: Duration = Duration::from_secs(1);
+/// 各サービスの終了を待つ時間の上限
+pub const SERVICE_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// hostd の停止時に各サービスへ停止を通知するための送信側
+pub struct ShutdownSenders {
+    pub capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+    pub audio_capture_cmd_tx: mpsc::Sender<AudioCaptureMessage>,
+    /// マイク用の AudioCaptureService（--mic 指定時のみ）
+    pub mic_capture_cmd_tx: Option<mpsc::Sender<AudioCaptureMessage>>,
+    pub webrtc_msg_tx: mpsc::Sender<WebRtcMessage>,
+}
+
+impl ShutdownSenders {
+    /// キャプチャサービスを終了させて PeerConnection を閉じるよう通知し、送信側を手放してチャネルを閉じる
+    /// （キャプチャのコマンド送信側は他のサービスも持っているため、チャネルが閉じるのを待たずに終了させる）
+    pub async fn stop_all(self) {
+        if self
+            .capture_cmd_tx
+            .send_timeout(CaptureMessage::Shutdown, STOP_SEND_TIMEOUT)
+            .await
+            .is_err()
+        {
+            debug!("CaptureService is not accepting commands");
+        }
+        if self
+            .audio_capture_cmd_tx
+            .send_timeout(AudioCaptureMessage::Stop, STOP_SEND_TIMEOUT)
+            .await
+            .is_err()
+        {
+            debug!("AudioCaptureService is not accepting commands");
+        }
+        if let Some(mic_capture_cmd_tx) = &self.mic_capture_cmd_tx {
+            if mic_capture_cmd_tx
+                .send_timeout(AudioCaptureMessage::Stop, STOP_SEND_TIMEOUT)
+                .await
+                .is_err()
+            {
+                debug!("AudioCaptureService (mic) is not accepting commands");
+            }
+        }
+        if self
+            .webrtc_msg_tx
+            .send_timeout(WebRtcMessage::Shutdown, STOP_SEND_TIMEOUT)
+            .await
+            .is_err()
+        {
+            debug!("WebRtcService is not accepting messages");
+        }
+        info!("Stop requested for all services");
+    }
+}
+
+/// サービスのタスクが終わるのを待つ（終了済みなら何もせず、時間内に終わらなければ中断する）
+pub async fn join_or_abort<T>(name: &str, handle: &mut JoinHandle<T>) {
+    if handle.is_finished() {
+        return;
+    }
+    match tokio::time::timeout(SERVICE_STOP_TIMEOUT, &mut *handle).await {
+        Ok(_) => debug!("{} stopped", name),
+        Err(_) => {
+            warn!(
+                "{} did not stop within {:?}, aborting",
+                name, SERVICE_STOP_TIMEOUT
+            );
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_signal_stops_services_and_closes_channels() {
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let (audio_capture_cmd_tx, mut audio_capture_cmd_rx) = mpsc::channel(10);
+        let (mic_capture_cmd_tx, mut mic_capture_cmd_rx) = mpsc::channel(10);
+        let (webrtc_msg_tx, mut webrtc_msg_rx) = mpsc::channel(10);
+        let senders = ShutdownSenders {
+            capture_cmd_tx,
+            audio_capture_cmd_tx,
+            mic_capture_cmd_tx: Some(mic_capture_cmd_tx),
+            webrtc_msg_tx,
+        };
+
+        // Ctrl-C の代わりに oneshot で停止を要求する
+        let (signal_tx, signal_rx) = oneshot::channel::<()>();
+        let shutdown = tokio::spawn(async move {
+            let _ = signal_rx.await;
+            senders.stop_all().await;
+        });
+
+        tokio::task::yield_now().await;
+        assert!(capture_cmd_rx.try_recv().is_err());
+
+        signal_tx.send(()).unwrap();
+        shutdown.await.unwrap();
+
+        assert!(matches!(
+            capture_cmd_rx.recv().await,
+            Some(CaptureMessage::Shutdown)
+        ));
+        assert!(capture_cmd_rx.recv().await.is_none());
+        assert!(matches!(
+            audio_capture_cmd_rx.recv().await,
+            Some(AudioCaptureMessage::Stop)
+        ));
+        assert!(audio_capture_cmd_rx.recv().await.is_none());
+        assert!(matches!(
+            mic_capture_cmd_rx.recv().await,
+            Some(AudioCaptureMessage::Stop)
+        ));
+        assert!(mic_capture_cmd_rx.recv().await.is_none());
+        assert!(matches!(
+            webrtc_msg_rx.recv().await,
+            Some(WebRtcMessage::Shutdown)
+        ));
+        assert!(webrtc_msg_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stop_all_tolerates_stopped_services() {
+        let (capture_cmd_tx, capture_cmd_rx) = mpsc::channel(10);
+        let (audio_capture_cmd_tx, audio_capture_cmd_rx) = mpsc::channel(10);
+        let (webrtc_msg_tx, webrtc_msg_rx) = mpsc::channel(10);
+        drop((capture_cmd_rx, audio_capture_cmd_rx, webrtc_msg_rx));
+
+        ShutdownSenders {
+            capture_cmd_tx,
+            audio_capture_cmd_tx,
+            mic_capture_cmd_tx: None,
+            webrtc_msg_tx,
+        }
+        .stop_all()
+        .await;
+    }
+}
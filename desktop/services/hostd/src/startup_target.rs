@@ -0,0 +1,250 @@
+// 起動時のキャプチャ対象ウィンドウの決定
+//
+// ランチャーからゲームのタイトルを環境変数で渡して自動でキャプチャを始められるよう、
+// HWND・タイトル・プロセス名を CLI と環境変数の両方で受け付ける。優先順位は
+// CLI の HWND > CLI のタイトル/プロセス名 > 環境変数の HWND > 環境変数のタイトル/プロセス名 > クライアントの選択待ち。
+// タイトル/プロセス名に一致するウィンドウがなければ警告して次の候補に進む。
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+/// キャプチャ対象の HWND（u64）
+pub const ENV_HWND: &str = "REMOTERG_HWND";
+/// キャプチャ対象のウィンドウタイトル（部分一致）
+pub const ENV_WINDOW_TITLE: &str = "REMOTERG_WINDOW_TITLE";
+/// キャプチャ対象のプロセス名（例: "game.exe"）
+pub const ENV_PROCESS_NAME: &str = "REMOTERG_PROCESS_NAME";
+
+/// 1 つの指定元（CLI か環境変数）から受け取ったウィンドウの指定
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowSpec {
+    /// 0 は指定なし
+    pub hwnd: u64,
+    pub title: Option<String>,
+    pub process_name: Option<String>,
+}
+
+impl WindowSpec {
+    /// 環境変数から読む（HWND が数値でなければエラー）
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let hwnd = match var(ENV_HWND) {
+            Some(hwnd) => hwnd
+                .parse()
+                .with_context(|| format!("{} is not a window handle: {:?}", ENV_HWND, hwnd))?,
+            None => 0,
+        };
+        Ok(Self {
+            hwnd,
+            title: var(ENV_WINDOW_TITLE),
+            process_name: var(ENV_PROCESS_NAME),
+        })
+    }
+
+    fn has_query(&self) -> bool {
+        self.title.is_some() || self.process_name.is_some()
+    }
+}
+
+/// 決まったキャプチャ対象と、その決め方
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartupTarget {
+    Hwnd {
+        hwnd: u64,
+        reason: &'static str,
+    },
+    /// 指定がない（か、見つからなかった）のでクライアントが選ぶのを待つ
+    WaitForClient,
+}
+
+impl StartupTarget {
+    /// キャプチャを始める HWND（0 はクライアントの選択待ち）
+    pub fn hwnd(&self) -> u64 {
+        match self {
+            StartupTarget::Hwnd { hwnd, .. } => *hwnd,
+            StartupTarget::WaitForClient => 0,
+        }
+    }
+}
+
+/// 優先順位に従ってキャプチャ対象を決める
+/// `find` はタイトル/プロセス名に一致するウィンドウを探す（テストでは差し替える）
+pub fn choose_startup_target(
+    cli: &WindowSpec,
+    env: &WindowSpec,
+    find: impl Fn(Option<&str>, Option<&str>) -> Option<u64>,
+) -> StartupTarget {
+    let candidates = [
+        (cli, "command line hwnd", "command line title/process"),
+        (env, ENV_HWND, "REMOTERG_WINDOW_TITLE/REMOTERG_PROCESS_NAME"),
+    ];
+    for (spec, hwnd_reason, query_reason) in candidates {
+        if spec.hwnd != 0 {
+            return StartupTarget::Hwnd {
+                hwnd: spec.hwnd,
+                reason: hwnd_reason,
+            };
+        }
+        if !spec.has_query() {
+            continue;
+        }
+        match find(spec.title.as_deref(), spec.process_name.as_deref()) {
+            Some(hwnd) => {
+                return StartupTarget::Hwnd {
+                    hwnd,
+                    reason: query_reason,
+                }
+            }
+            None => warn!(
+                "No window matches {} (title: {:?}, process: {:?})",
+                query_reason, spec.title, spec.process_name
+            ),
+        }
+    }
+    StartupTarget::WaitForClient
+}
+
+/// タイトル（部分一致）とプロセス名でウィンドウを探す
+pub fn find_window(title: Option<&str>, process_name: Option<&str>) -> Option<u64> {
+    match (title, process_name) {
+        // 両方一致するものを優先し、なければプロセス名だけで一致するもの（プロセス名の指定を明示とみなす）
+        (title, Some(process_name)) => video_capture::find_window(
+            &video_capture::WindowIdentity {
+                title: title.unwrap_or_default().to_string(),
+                class_name: String::new(),
+                process_name: process_name.to_string(),
+            },
+            true,
+        ),
+        (Some(title), None) => video_capture::find_window_by_title(title),
+        (None, None) => None,
+    }
+}
+
+/// CLI と環境変数の指定から起動時のキャプチャ対象を決めてログに出す
+pub fn resolve_startup_target(cli: &WindowSpec) -> Result<StartupTarget> {
+    let env = WindowSpec::from_env()?;
+    let target = choose_startup_target(cli, &env, find_window);
+    match &target {
+        StartupTarget::Hwnd { hwnd, reason } => {
+            let title = video_capture::window_identity(*hwnd).map(|identity| identity.title);
+            info!(
+                "Capture target HWND {} ({:?}) chosen from {}",
+                hwnd, title, reason
+            );
+        }
+        StartupTarget::WaitForClient => {
+            info!("No capture target window specified or found");
+        }
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(hwnd: u64, title: Option<&str>, process_name: Option<&str>) -> WindowSpec {
+        WindowSpec {
+            hwnd,
+            title: title.map(str::to_string),
+            process_name: process_name.map(str::to_string),
+        }
+    }
+
+    /// "Game" というタイトルの game.exe（HWND 100）と、"Editor" の editor.exe（HWND 200）がある
+    fn find(title: Option<&str>, process_name: Option<&str>) -> Option<u64> {
+        let windows = [(100, "Game", "game.exe"), (200, "Editor", "editor.exe")];
+        windows
+            .iter()
+            .find(|(_, t, p)| {
+                title.is_none_or(|title| t.contains(title))
+                    && process_name.is_none_or(|process_name| *p == process_name)
+            })
+            .map(|(hwnd, _, _)| *hwnd)
+    }
+
+    fn chosen(cli: WindowSpec, env: WindowSpec) -> StartupTarget {
+        choose_startup_target(&cli, &env, find)
+    }
+
+    #[test]
+    fn test_cli_hwnd_wins() {
+        assert_eq!(
+            chosen(spec(1, Some("Game"), None), spec(2, Some("Editor"), None)),
+            StartupTarget::Hwnd {
+                hwnd: 1,
+                reason: "command line hwnd"
+            }
+        );
+    }
+
+    #[test]
+    fn test_cli_title_beats_env_hwnd() {
+        assert_eq!(
+            chosen(spec(0, Some("Game"), None), spec(2, None, None)).hwnd(),
+            100
+        );
+        assert_eq!(
+            chosen(
+                spec(0, None, Some("editor.exe")),
+                spec(2, Some("Game"), None)
+            )
+            .hwnd(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_env_hwnd_beats_env_title() {
+        assert_eq!(
+            chosen(WindowSpec::default(), spec(2, Some("Game"), None)),
+            StartupTarget::Hwnd {
+                hwnd: 2,
+                reason: ENV_HWND
+            }
+        );
+    }
+
+    #[test]
+    fn test_env_title_and_process() {
+        assert_eq!(
+            chosen(WindowSpec::default(), spec(0, Some("Game"), None)).hwnd(),
+            100
+        );
+        assert_eq!(
+            chosen(WindowSpec::default(), spec(0, None, Some("editor.exe"))).hwnd(),
+            200
+        );
+    }
+
+    #[test]
+    fn test_unmatched_title_falls_through() {
+        // CLI のタイトルが見つからなければ環境変数の指定に進む
+        assert_eq!(
+            chosen(
+                spec(0, Some("Missing"), None),
+                spec(0, Some("Editor"), None)
+            )
+            .hwnd(),
+            200
+        );
+        assert_eq!(
+            chosen(spec(0, Some("Missing"), None), WindowSpec::default()),
+            StartupTarget::WaitForClient
+        );
+    }
+
+    #[test]
+    fn test_nothing_specified_waits_for_client() {
+        assert_eq!(
+            chosen(WindowSpec::default(), WindowSpec::default()),
+            StartupTarget::WaitForClient
+        );
+    }
+}
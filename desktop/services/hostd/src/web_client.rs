@@ -0,0 +1,212 @@
+// 動作確認用の組み込みクライアント（`--serve-client`）
+//
+// 別途クライアントをデプロイしなくても「ホストが動いているか」をブラウザで確かめられるよう、
+// シグナリングサーバーに viewer として接続して Offer を送り、届いた映像を表示するだけの
+// 最小限のページを `GET /` で返す。ページは文字列として埋め込み、接続先のシグナリング URL と
+// セッション ID はホストの設定を埋め込む。HTTP の扱いはメトリクスのエンドポイントと同じ最小限の実装。
+
+use anyhow::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, warn};
+
+use crate::metrics_server::{read_request_head, REQUEST_TIMEOUT};
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html lang="ja">
+<head>
+<meta charset="utf-8">
+<title>RemoteRG test client</title>
+<style>
+  body { margin: 0; background: #111; color: #ddd; font: 14px sans-serif; }
+  header { display: flex; gap: 8px; align-items: center; padding: 8px; }
+  video { display: block; width: 100vw; max-height: calc(100vh - 48px); background: #000; }
+  #log { flex: 1; white-space: nowrap; overflow: hidden; text-overflow: ellipsis; }
+</style>
+</head>
+<body>
+<header>
+  <button id="connect">Connect</button>
+  <span id="log">Session: <span id="session"></span></span>
+</header>
+<video id="video" autoplay playsinline muted controls></video>
+<script>
+const SIGNAL_URL = {{SIGNAL_URL}};
+const SESSION_ID = {{SESSION_ID}};
+const log = (message) => {
+  console.log(message);
+  document.getElementById("log").textContent = message;
+};
+document.getElementById("session").textContent = SESSION_ID;
+// このページのビューアーの ID（繋ぎ直しても同じ ID を送り、ホストにセッションを引き継がせる）
+const NEGOTIATION_ID = Math.random().toString(36).slice(2) + Date.now().toString(36);
+
+let pc = null;
+let ws = null;
+
+function disconnect() {
+  if (ws) ws.close();
+  if (pc) pc.close();
+  ws = null;
+  pc = null;
+}
+
+function connect() {
+  disconnect();
+  const url = new URL(SIGNAL_URL);
+  url.searchParams.set("session_id", SESSION_ID);
+  url.searchParams.set("role", "viewer");
+  ws = new WebSocket(url);
+  pc = new RTCPeerConnection({ iceServers: [{ urls: ["stun:stun.l.google.com:19302"] }] });
+  const send = (message) => ws.readyState === WebSocket.OPEN && ws.send(JSON.stringify(message));
+
+  pc.addTransceiver("video", { direction: "recvonly" });
+  pc.addTransceiver("audio", { direction: "recvonly" });
+  pc.createDataChannel("input", { ordered: true });
+
+  const stream = new MediaStream();
+  document.getElementById("video").srcObject = stream;
+  pc.ontrack = (event) => stream.addTrack(event.track);
+  pc.onicecandidate = (event) => {
+    if (event.candidate) {
+      send({
+        type: "ice_candidate",
+        candidate: event.candidate.candidate,
+        sdp_mid: event.candidate.sdpMid,
+        sdp_mline_index: event.candidate.sdpMLineIndex,
+      });
+    }
+  };
+  pc.onconnectionstatechange = () => log(`PeerConnection: ${pc.connectionState}`);
+
+  ws.onopen = async () => {
+    log("Signaling connected, sending offer");
+    const offer = await pc.createOffer();
+    await pc.setLocalDescription(offer);
+    send({ type: "offer", sdp: offer.sdp, negotiation_id: NEGOTIATION_ID });
+  };
+  // Answer を設定し終えるまでに届いた ICE candidate は溜めておき、設定後にまとめて追加する
+  // （onmessage は await の途中でも次のメッセージで呼ばれるので、Answer の直後の candidate が先に着く）
+  let pendingCandidates = [];
+  const addCandidate = (candidate) =>
+    pc.addIceCandidate(candidate).catch((e) => console.warn("Failed to add ICE candidate", e));
+  ws.onmessage = async (event) => {
+    const message = JSON.parse(event.data);
+    if (message.type === "answer") {
+      await pc.setRemoteDescription({ type: "answer", sdp: message.sdp });
+      log("Answer received");
+      const candidates = pendingCandidates;
+      pendingCandidates = null;
+      for (const candidate of candidates) await addCandidate(candidate);
+    } else if (message.type === "ice_candidate") {
+      const candidate = {
+        candidate: message.candidate,
+        sdpMid: message.sdp_mid,
+        sdpMLineIndex: message.sdp_mline_index,
+      };
+      if (pendingCandidates) {
+        pendingCandidates.push(candidate);
+      } else {
+        await addCandidate(candidate);
+      }
+    } else if (message.type === "error") {
+      log(`Signaling error: ${message.message}`);
+    }
+  };
+  ws.onerror = () => log(`Cannot connect to ${SIGNAL_URL}`);
+  ws.onclose = () => console.log("Signaling closed");
+}
+
+document.getElementById("connect").onclick = connect;
+connect();
+</script>
+</body>
+</html>
+"#;
+
+/// シグナリング URL とセッション ID を埋め込んだページ
+pub fn render_page(signal_url: &str, session_id: &str) -> String {
+    PAGE.replace("{{SIGNAL_URL}}", &js_string(signal_url))
+        .replace("{{SESSION_ID}}", &js_string(session_id))
+}
+
+/// <script> の中に置ける JS の文字列リテラル（`</script>` で閉じられないようにする）
+fn js_string(value: &str) -> String {
+    serde_json::Value::from(value)
+        .to_string()
+        .replace("</", "<\\/")
+}
+
+/// `listener` で受けた接続にテスト用クライアントのページを返し続ける
+pub async fn serve_web_client(listener: TcpListener, page: String) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let page = page.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &page).await {
+                debug!("Client page request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(mut stream: TcpStream, page: &str) -> Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request_head(&mut stream)).await??;
+    let request_line = request.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/" | "/index.html")) => ("200 OK", "text/html; charset=utf-8", page),
+        (Some(_), Some(_)) => ("404 Not Found", "text/plain; charset=utf-8", "not found\n"),
+        _ => {
+            warn!("Malformed client page request: {:?}", request_line);
+            (
+                "400 Bad Request",
+                "text/plain; charset=utf-8",
+                "bad request\n",
+            )
+        }
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_serves_page_with_signaling_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let page = render_page("ws://localhost:3000/api/signal", "</script>\"x");
+        let server = tokio::spawn(serve_web_client(listener, page));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/html"));
+        assert!(body.contains(r#"const SIGNAL_URL = "ws://localhost:3000/api/signal";"#));
+        // セッション ID でスクリプトが閉じられない
+        assert!(body.contains(r#"const SESSION_ID = "<\/script>\"x";"#));
+        assert_eq!(body.matches("</script>").count(), 1);
+
+        server.abort();
+    }
+}
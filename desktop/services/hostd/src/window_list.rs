@@ -0,0 +1,118 @@
+// キャプチャ可能なウィンドウの一覧（共有するウィンドウを選ぶ UI 向け）
+//
+// 表示中のトップレベルウィンドウを列挙し、それぞれ 1 フレームだけキャプチャして縮小したサムネイルを付ける。
+// 最小化されているなどでキャプチャできないウィンドウは短いタイムアウトで諦め、サムネイルなしで返す。
+
+use anyhow::Result;
+use base64::prelude::*;
+use core_types::{CapturableWindow, Frame, PixelFormat};
+use image::ImageEncoder;
+use std::time::Duration;
+use tracing::debug;
+
+/// サムネイルの長辺
+pub const THUMBNAIL_MAX_EDGE: u32 = 160;
+/// 1 ウィンドウのキャプチャを待つ時間
+pub const THUMBNAIL_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// キャプチャ可能なウィンドウをサムネイルつきで列挙する
+pub async fn list_capturable_windows(
+    max_edge: u32,
+    timeout: Duration,
+) -> Result<Vec<CapturableWindow>> {
+    let windows = video_capture::list_windows()?;
+    let mut result = Vec::with_capacity(windows.len());
+    for window in windows {
+        let thumbnail = match video_capture::capture_window_frame(window.hwnd, timeout).await {
+            Ok(frame) => match encode_thumbnail(&frame, max_edge) {
+                Ok(thumbnail) => Some(thumbnail),
+                Err(e) => {
+                    debug!("Failed to encode thumbnail for {}: {:#}", window.title, e);
+                    None
+                }
+            },
+            Err(e) => {
+                debug!("Skipping thumbnail for {}: {:#}", window.title, e);
+                None
+            }
+        };
+        result.push(CapturableWindow {
+            hwnd: window.hwnd,
+            title: window.title,
+            process_name: window.process_name,
+            thumbnail,
+        });
+    }
+    Ok(result)
+}
+
+/// 長辺を `max_edge` 以下に縮めたサイズ（アスペクト比を保ち、拡大はしない）
+fn thumbnail_size(width: u32, height: u32, max_edge: u32) -> (u32, u32) {
+    let edge = width.max(height);
+    if edge <= max_edge {
+        return (width.max(1), height.max(1));
+    }
+    let scale = |v: u32| ((v as u64 * max_edge as u64 / edge as u64) as u32).max(1);
+    (scale(width), scale(height))
+}
+
+/// フレームを縮小して PNG の data URL にする
+fn encode_thumbnail(frame: &Frame, max_edge: u32) -> Result<String> {
+    let (width, height) = thumbnail_size(frame.width, frame.height, max_edge);
+    let mut rgba =
+        video_capture::resize_image_impl(&frame.data, frame.width, frame.height, width, height)?;
+    if frame.format == PixelFormat::Bgra8 {
+        for pixel in rgba.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+
+    let mut png = Vec::new();
+    image::codecs::png::PngEncoder::new(&mut png).write_image(
+        &rgba,
+        width,
+        height,
+        image::ColorType::Rgba8,
+    )?;
+    Ok(format!(
+        "data:image/png;base64,{}",
+        BASE64_STANDARD.encode(png)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_thumbnail_size_keeps_aspect() {
+        assert_eq!(thumbnail_size(1920, 1080, 160), (160, 90));
+        assert_eq!(thumbnail_size(600, 800, 160), (120, 160));
+        // 小さいウィンドウは拡大しない
+        assert_eq!(thumbnail_size(100, 50, 160), (100, 50));
+        assert_eq!(thumbnail_size(4000, 1, 160), (160, 1));
+    }
+
+    #[test]
+    fn test_encode_thumbnail_as_png_data_url() {
+        let frame = Frame {
+            width: 320,
+            height: 180,
+            data: Arc::new([0u8, 0, 255, 255].repeat(320 * 180)),
+            windows_timespan: 0,
+            fps: 60,
+            format: PixelFormat::Bgra8,
+            dirty_fraction: None,
+            sequence: 0,
+            scale_to: None,
+        };
+        let thumbnail = encode_thumbnail(&frame, THUMBNAIL_MAX_EDGE).unwrap();
+        let encoded = thumbnail.strip_prefix("data:image/png;base64,").unwrap();
+        let png = BASE64_STANDARD.decode(encoded).unwrap();
+        let image = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(image.dimensions(), (160, 90));
+        // BGRA の赤が RGBA の赤になっている
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,405 @@
+// クリップボードの同期（ホスト ⇄ クライアント）
+//
+// ホストのクリップボードの変更は AddClipboardFormatListener で登録したウィンドウに WM_CLIPBOARDUPDATE で
+// 届く。InputService は tokio のワーカースレッドで動いていてメッセージループを回さないので、
+// 専用のスレッドにメッセージ専用ウィンドウ（HWND_MESSAGE）を作り、そこで受け取ったテキストを
+// チャネルで InputService に渡す。
+//
+// クライアントから受け取ったテキストをホストのクリップボードに設定すると、それ自体が変更として
+// 通知されてクライアントに送り返してしまう。設定したときのクリップボードのシーケンス番号を覚えておき、
+// 同じ番号の変更は送らない。送るのはテキストだけで、大きすぎるものは送らない（DataChannel の
+// メッセージ 1 件に収まる大きさにする）。
+//
+// クリップボードを開くときはほかのアプリが閉じるまで待って開き直すので、設定は spawn_blocking で行い
+// tokio のワーカースレッドを止めない。
+
+use anyhow::{Context, Result};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use windows::core::w;
+use windows::Win32::Foundation::{GlobalFree, HANDLE, HGLOBAL, HWND, LPARAM, LRESULT, WPARAM};
+use windows::Win32::System::DataExchange::{
+    AddClipboardFormatListener, CloseClipboard, EmptyClipboard, GetClipboardData,
+    GetClipboardSequenceNumber, IsClipboardFormatAvailable, OpenClipboard,
+    RemoveClipboardFormatListener, SetClipboardData,
+};
+use windows::Win32::System::LibraryLoader::GetModuleHandleW;
+use windows::Win32::System::Memory::{
+    GlobalAlloc, GlobalLock, GlobalSize, GlobalUnlock, GMEM_MOVEABLE,
+};
+use windows::Win32::System::Ole::CF_UNICODETEXT;
+use windows::Win32::UI::WindowsAndMessaging::{
+    CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetMessageW, PostMessageW,
+    PostQuitMessage, RegisterClassW, HWND_MESSAGE, MSG, WINDOW_EX_STYLE, WINDOW_STYLE,
+    WM_CLIPBOARDUPDATE, WM_CLOSE, WM_DESTROY, WNDCLASSW,
+};
+
+/// 同期するテキストの最大サイズ（UTF-8 のバイト数）
+const MAX_CLIPBOARD_TEXT_BYTES: usize = 64 * 1024;
+/// ほかのアプリがクリップボードを開いている場合に開き直す回数と間隔
+const OPEN_RETRIES: u32 = 5;
+const OPEN_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+/// 1 回のコピーで続けて届く通知をまとめる時間（これより後に同じテキストをコピーし直したら送る）
+const DUPLICATE_UPDATE_WINDOW: Duration = Duration::from_millis(500);
+
+thread_local! {
+    /// 監視スレッドのウィンドウプロシージャから使う状態
+    static WATCHER: RefCell<Option<Watcher>> = const { RefCell::new(None) };
+}
+
+/// ホストのクリップボードの監視と設定
+pub struct ClipboardSync {
+    /// ホストでコピーされたテキスト
+    text_rx: mpsc::Receiver<String>,
+    /// 監視用のメッセージ専用ウィンドウ（HWND はスレッドをまたいで持てないので整数で持つ）
+    hwnd: usize,
+    /// 自分で設定したときのシーケンス番号（この変更は送り返さない）
+    own_sequence: Arc<AtomicU32>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl ClipboardSync {
+    /// 監視スレッドを起動し、メッセージ専用ウィンドウを作るまで待つ
+    pub fn start() -> Result<Self> {
+        let (text_tx, text_rx) = mpsc::channel(8);
+        let own_sequence = Arc::new(AtomicU32::new(0));
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        let watcher = Watcher {
+            text_tx,
+            own_sequence: own_sequence.clone(),
+            last_sent: None,
+        };
+        let thread = std::thread::Builder::new()
+            .name("clipboard".to_string())
+            .spawn(move || run_watcher(watcher, ready_tx))
+            .context("Failed to spawn clipboard thread")?;
+        let hwnd = ready_rx
+            .recv()
+            .context("Clipboard thread exited before creating its window")??;
+        info!("Clipboard sync started");
+        Ok(Self {
+            text_rx,
+            hwnd,
+            own_sequence,
+            thread: Some(thread),
+        })
+    }
+
+    /// ホストでコピーされたテキストを待つ
+    pub async fn recv(&mut self) -> Option<String> {
+        self.text_rx.recv().await
+    }
+
+    /// クライアントから受け取ったテキストをホストのクリップボードに設定する
+    pub async fn set_text(&self, text: String) -> Result<()> {
+        let (hwnd, own_sequence) = (self.hwnd, self.own_sequence.clone());
+        tokio::task::spawn_blocking(move || set_host_text(hwnd, &own_sequence, &text))
+            .await
+            .context("Clipboard task panicked")?
+    }
+}
+
+/// ホストのクリップボードにテキストを設定し、そのときのシーケンス番号を `own_sequence` に覚える
+/// （ほかのアプリが開いている間は待つのでブロックする）
+fn set_host_text(hwnd: usize, own_sequence: &AtomicU32, text: &str) -> Result<()> {
+    anyhow::ensure!(
+        text.len() <= MAX_CLIPBOARD_TEXT_BYTES,
+        "clipboard text too large ({} bytes)",
+        text.len()
+    );
+    let units = host_text(text);
+    let bytes = units.len() * std::mem::size_of::<u16>();
+    let hwnd = HWND(hwnd as *mut _);
+    unsafe {
+        let memory = GlobalAlloc(GMEM_MOVEABLE, bytes)?;
+        let ptr = GlobalLock(memory) as *mut u16;
+        if ptr.is_null() {
+            let _ = GlobalFree(Some(memory));
+            anyhow::bail!("GlobalLock failed");
+        }
+        std::ptr::copy_nonoverlapping(units.as_ptr(), ptr, units.len());
+        let _ = GlobalUnlock(memory);
+
+        if let Err(e) = open_clipboard(hwnd) {
+            let _ = GlobalFree(Some(memory));
+            return Err(e);
+        }
+        let result = EmptyClipboard()
+            .and_then(|_| SetClipboardData(CF_UNICODETEXT.0 as u32, Some(HANDLE(memory.0))));
+        if result.is_ok() {
+            // 閉じると監視スレッドに通知されるので、その前に番号を覚える
+            own_sequence.store(GetClipboardSequenceNumber(), Ordering::Relaxed);
+        } else {
+            // 設定できなかった場合だけメモリの所有権がこちらに残る
+            let _ = GlobalFree(Some(memory));
+        }
+        let _ = CloseClipboard();
+        result.context("SetClipboardData failed")?;
+    }
+    debug!("Set host clipboard text ({} bytes)", text.len());
+    Ok(())
+}
+
+impl Drop for ClipboardSync {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = PostMessageW(
+                Some(HWND(self.hwnd as *mut _)),
+                WM_CLOSE,
+                WPARAM(0),
+                LPARAM(0),
+            );
+        }
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Clipboard thread panicked");
+            }
+        }
+    }
+}
+
+/// 監視スレッドの状態
+struct Watcher {
+    text_tx: mpsc::Sender<String>,
+    own_sequence: Arc<AtomicU32>,
+    /// 最後に送ったテキストと送った時刻（1 回のコピーで続けて通知されることがあるので、
+    /// `DUPLICATE_UPDATE_WINDOW` 以内の同じテキストは送らない）
+    last_sent: Option<(String, Instant)>,
+}
+
+impl Watcher {
+    fn on_update(&mut self, hwnd: HWND) {
+        let sequence = unsafe { GetClipboardSequenceNumber() };
+        if sequence == self.own_sequence.load(Ordering::Relaxed) {
+            // クライアントから受け取って設定したもの
+            return;
+        }
+        let text = match read_clipboard_text(hwnd) {
+            Ok(Some(text)) => text,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to read host clipboard: {:#}", e);
+                return;
+            }
+        };
+        let Some(text) = client_text(&text) else {
+            return;
+        };
+        let now = Instant::now();
+        if is_duplicate_update(self.last_sent.as_ref(), &text, now) {
+            return;
+        }
+        debug!("Host clipboard changed ({} bytes)", text.len());
+        if self.text_tx.try_send(text.clone()).is_ok() {
+            self.last_sent = Some((text, now));
+        }
+    }
+}
+
+fn run_watcher(watcher: Watcher, ready_tx: std::sync::mpsc::Sender<Result<usize>>) {
+    let hwnd = match create_listener_window() {
+        Ok(hwnd) => hwnd,
+        Err(e) => {
+            let _ = ready_tx.send(Err(e));
+            return;
+        }
+    };
+    WATCHER.with(|slot| *slot.borrow_mut() = Some(watcher));
+    let _ = ready_tx.send(Ok(hwnd.0 as usize));
+
+    let mut msg = MSG::default();
+    // 0 は WM_QUIT、-1 はエラー
+    while unsafe { GetMessageW(&mut msg, None, 0, 0) }.0 > 0 {
+        unsafe {
+            DispatchMessageW(&msg);
+        }
+    }
+    WATCHER.with(|slot| slot.borrow_mut().take());
+    debug!("Clipboard thread stopped");
+}
+
+/// クリップボードの変更を受け取るメッセージ専用ウィンドウを作る
+fn create_listener_window() -> Result<HWND> {
+    unsafe {
+        let instance = GetModuleHandleW(None)?;
+        let class_name = w!("RemoteRgClipboardListener");
+        let class = WNDCLASSW {
+            lpfnWndProc: Some(window_proc),
+            hInstance: instance.into(),
+            lpszClassName: class_name,
+            ..Default::default()
+        };
+        // 2 回目以降の起動では登録済みなので失敗してもよい
+        RegisterClassW(&class);
+        let hwnd = CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            class_name,
+            None,
+            WINDOW_STYLE::default(),
+            0,
+            0,
+            0,
+            0,
+            Some(HWND_MESSAGE),
+            None,
+            Some(instance.into()),
+            None,
+        )
+        .context("Failed to create clipboard listener window")?;
+        if let Err(e) = AddClipboardFormatListener(hwnd) {
+            let _ = DestroyWindow(hwnd);
+            return Err(e).context("AddClipboardFormatListener failed");
+        }
+        Ok(hwnd)
+    }
+}
+
+extern "system" fn window_proc(hwnd: HWND, msg: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+    match msg {
+        WM_CLIPBOARDUPDATE => {
+            WATCHER.with(|slot| {
+                if let Some(watcher) = slot.borrow_mut().as_mut() {
+                    watcher.on_update(hwnd);
+                }
+            });
+            LRESULT(0)
+        }
+        WM_CLOSE => {
+            unsafe {
+                let _ = RemoveClipboardFormatListener(hwnd);
+                let _ = DestroyWindow(hwnd);
+            }
+            LRESULT(0)
+        }
+        WM_DESTROY => {
+            unsafe { PostQuitMessage(0) };
+            LRESULT(0)
+        }
+        _ => unsafe { DefWindowProcW(hwnd, msg, wparam, lparam) },
+    }
+}
+
+/// ほかのアプリが開いている間は少し待って開き直す
+fn open_clipboard(hwnd: HWND) -> Result<()> {
+    let mut attempt = 0;
+    loop {
+        match unsafe { OpenClipboard(Some(hwnd)) } {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= OPEN_RETRIES => {
+                return Err(e).context("OpenClipboard failed");
+            }
+            Err(_) => {
+                attempt += 1;
+                std::thread::sleep(OPEN_RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+/// クリップボードのテキストを読む（テキストがなければ None）
+fn read_clipboard_text(hwnd: HWND) -> Result<Option<String>> {
+    if unsafe { IsClipboardFormatAvailable(CF_UNICODETEXT.0 as u32) }.is_err() {
+        return Ok(None);
+    }
+    open_clipboard(hwnd)?;
+    let text = unsafe {
+        GetClipboardData(CF_UNICODETEXT.0 as u32).map(|handle| {
+            let memory = HGLOBAL(handle.0);
+            let ptr = GlobalLock(memory) as *const u16;
+            if ptr.is_null() {
+                return None;
+            }
+            let capacity = GlobalSize(memory) / std::mem::size_of::<u16>();
+            let units = std::slice::from_raw_parts(ptr, capacity);
+            let len = units.iter().position(|&unit| unit == 0).unwrap_or(capacity);
+            let text = String::from_utf16_lossy(&units[..len]);
+            let _ = GlobalUnlock(memory);
+            Some(text)
+        })
+    };
+    unsafe {
+        let _ = CloseClipboard();
+    }
+    text.context("GetClipboardData failed")
+}
+
+/// 直前に送ったものと同じコピーの続きの通知か（時間をおいてコピーし直した同じテキストは送る）
+fn is_duplicate_update(last_sent: Option<&(String, Instant)>, text: &str, now: Instant) -> bool {
+    last_sent
+        .is_some_and(|(last, at)| last == text && now.duration_since(*at) < DUPLICATE_UPDATE_WINDOW)
+}
+
+/// クライアントに送るテキスト（改行は LF にそろえる。空か大きすぎる場合は送らない）
+fn client_text(text: &str) -> Option<String> {
+    let text = text.replace("\r\n", "\n");
+    if text.is_empty() {
+        return None;
+    }
+    if text.len() > MAX_CLIPBOARD_TEXT_BYTES {
+        warn!(
+            "Host clipboard text is {} bytes (limit {}), not sending it to the client",
+            text.len(),
+            MAX_CLIPBOARD_TEXT_BYTES
+        );
+        return None;
+    }
+    Some(text)
+}
+
+/// ホストのクリップボードに設定する UTF-16 のテキスト（改行は CRLF、NUL 終端）
+fn host_text(text: &str) -> Vec<u16> {
+    let text = text.replace("\r\n", "\n").replace('\n', "\r\n");
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_text() {
+        assert_eq!(
+            client_text("https://example.com\r\nline2").as_deref(),
+            Some("https://example.com\nline2")
+        );
+        assert_eq!(client_text(""), None);
+        assert_eq!(
+            client_text(&"a".repeat(MAX_CLIPBOARD_TEXT_BYTES)).map(|t| t.len()),
+            Some(MAX_CLIPBOARD_TEXT_BYTES)
+        );
+        assert_eq!(client_text(&"a".repeat(MAX_CLIPBOARD_TEXT_BYTES + 1)), None);
+    }
+
+    #[test]
+    fn test_recopied_text_is_not_a_duplicate() {
+        let t0 = Instant::now();
+        let last = ("copied".to_string(), t0);
+        assert!(is_duplicate_update(
+            Some(&last),
+            "copied",
+            t0 + Duration::from_millis(50)
+        ));
+        assert!(!is_duplicate_update(
+            Some(&last),
+            "other",
+            t0 + Duration::from_millis(50)
+        ));
+        assert!(!is_duplicate_update(
+            Some(&last),
+            "copied",
+            t0 + DUPLICATE_UPDATE_WINDOW
+        ));
+        assert!(!is_duplicate_update(None, "copied", t0));
+    }
+
+    #[test]
+    fn test_host_text() {
+        let expected: Vec<u16> = "a\r\nb\r\nあ".encode_utf16().chain([0]).collect();
+        assert_eq!(host_text("a\nb\r\nあ"), expected);
+        assert_eq!(host_text(""), vec![0]);
+    }
+}
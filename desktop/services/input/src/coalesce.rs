@@ -0,0 +1,185 @@
+// 入力メッセージのまとめ処理
+//
+// クライアントが 1000Hz でマウス移動を送ってくると、1 件ずつ SendInput していては CPU を使い、
+// 処理が追いつかずに遅延が溜まる。入力はいったんためて一定間隔ごとにまとめて注入する。
+// 連続するマウス移動は最後の位置だけを残し、キーのオートリピートは間隔を制限する。
+// クリックやキーの押下・解放などは順序を保ってすべて注入する。
+
+use core_types::DataChannelMessage;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// 入力をまとめる間隔（Hz）のデフォルト
+pub const DEFAULT_INPUT_RATE_HZ: u32 = 120;
+/// 同じキーのオートリピートを通す最短間隔（Windows のリピート速度の上限は約 30 回/秒）
+pub const KEY_REPEAT_MIN_INTERVAL: Duration = Duration::from_millis(33);
+
+#[derive(Debug)]
+pub struct InputCoalescer {
+    pending: Vec<DataChannelMessage>,
+    key_repeat_interval: Duration,
+    /// 押下中のキーと、最後に通した keydown の時刻
+    key_downs: HashMap<String, Instant>,
+    /// まとめた（捨てた）メッセージ数
+    coalesced: u64,
+}
+
+impl InputCoalescer {
+    pub fn new(key_repeat_interval: Duration) -> Self {
+        Self {
+            pending: Vec::new(),
+            key_repeat_interval,
+            key_downs: HashMap::new(),
+            coalesced: 0,
+        }
+    }
+
+    /// まとめて注入する入力メッセージか（それ以外は受け取ったらすぐ処理する）
+    pub fn accepts(msg: &DataChannelMessage) -> bool {
+        matches!(
+            msg,
+            DataChannelMessage::Key { .. }
+                | DataChannelMessage::RawScanCode { .. }
+                | DataChannelMessage::MouseMove { .. }
+                | DataChannelMessage::MouseClick { .. }
+                | DataChannelMessage::MouseWheel { .. }
+                | DataChannelMessage::TextInput { .. }
+                | DataChannelMessage::ReleaseAllKeys
+                | DataChannelMessage::HeldKeys { .. }
+        )
+    }
+
+    pub fn push(&mut self, msg: DataChannelMessage, now: Instant) {
+        match &msg {
+            DataChannelMessage::MouseMove { .. } => {
+                // 直前も移動なら最後の位置だけ残す（間にクリックなどがあれば順序を保つ）
+                if let Some(last @ DataChannelMessage::MouseMove { .. }) = self.pending.last_mut() {
+                    *last = msg;
+                    self.coalesced += 1;
+                    return;
+                }
+            }
+            DataChannelMessage::Key { key, down: true } => {
+                if let Some(last) = self.key_downs.get_mut(key) {
+                    // 押下中のキーの keydown はオートリピート
+                    if now.duration_since(*last) < self.key_repeat_interval {
+                        self.coalesced += 1;
+                        return;
+                    }
+                    *last = now;
+                } else {
+                    self.key_downs.insert(key.clone(), now);
+                }
+            }
+            DataChannelMessage::Key { key, down: false } => {
+                self.key_downs.remove(key);
+            }
+            DataChannelMessage::ReleaseAllKeys => self.key_downs.clear(),
+            DataChannelMessage::HeldKeys { keys } => {
+                self.key_downs.retain(|key, _| keys.contains(key));
+            }
+            _ => {}
+        }
+        self.pending.push(msg);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// ためた入力を受け取った順に取り出す
+    pub fn drain(&mut self) -> Vec<DataChannelMessage> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// 前回取り出してからまとめたメッセージ数
+    pub fn take_coalesced(&mut self) -> u64 {
+        std::mem::take(&mut self.coalesced)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mouse_move(x: f64, y: f64) -> DataChannelMessage {
+        DataChannelMessage::MouseMove { x, y }
+    }
+
+    fn key(key: &str, down: bool) -> DataChannelMessage {
+        DataChannelMessage::Key {
+            key: key.to_string(),
+            down,
+        }
+    }
+
+    #[test]
+    fn test_collapses_consecutive_mouse_moves() {
+        let mut coalescer = InputCoalescer::new(KEY_REPEAT_MIN_INTERVAL);
+        let now = Instant::now();
+        for i in 1..=10 {
+            coalescer.push(mouse_move(i as f64 / 10.0, 0.5), now);
+        }
+        coalescer.push(
+            DataChannelMessage::MouseClick {
+                x: 1.0,
+                y: 0.5,
+                button: "left".to_string(),
+            },
+            now,
+        );
+        coalescer.push(mouse_move(0.2, 0.2), now);
+        coalescer.push(mouse_move(0.3, 0.3), now);
+
+        // クリックの前後の移動はそれぞれ最後の位置だけ残り、順序は変わらない
+        let drained = coalescer.drain();
+        assert_eq!(drained.len(), 3);
+        assert!(
+            matches!(drained[0], DataChannelMessage::MouseMove { x, y } if x == 1.0 && y == 0.5)
+        );
+        assert!(matches!(drained[1], DataChannelMessage::MouseClick { .. }));
+        assert!(
+            matches!(drained[2], DataChannelMessage::MouseMove { x, y } if x == 0.3 && y == 0.3)
+        );
+        assert_eq!(coalescer.take_coalesced(), 10);
+        assert!(coalescer.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limits_key_repeats() {
+        let mut coalescer = InputCoalescer::new(Duration::from_millis(30));
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+
+        coalescer.push(key("KeyW", true), at(0));
+        // 間隔より短いリピートは捨てる
+        coalescer.push(key("KeyW", true), at(10));
+        coalescer.push(key("KeyW", true), at(20));
+        coalescer.push(key("KeyW", true), at(30));
+        // 他のキーは別に数える
+        coalescer.push(key("KeyD", true), at(31));
+        coalescer.push(key("KeyW", false), at(35));
+        // 離した後の押下はリピートではない
+        coalescer.push(key("KeyW", true), at(36));
+
+        let drained: Vec<_> = coalescer
+            .drain()
+            .into_iter()
+            .map(|msg| match msg {
+                DataChannelMessage::Key { key, down } => (key, down),
+                other => panic!("unexpected message: {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            drained,
+            vec![
+                ("KeyW".to_string(), true),
+                ("KeyW".to_string(), true),
+                ("KeyD".to_string(), true),
+                ("KeyW".to_string(), false),
+                ("KeyW".to_string(), true),
+            ]
+        );
+        assert_eq!(coalescer.take_coalesced(), 2);
+    }
+}
@@ -0,0 +1,236 @@
+// 入力注入のフォーカスガード
+//
+// SendInput はウィンドウを指定できず、注入した入力はその時点のフォアグラウンドウィンドウに届く。
+// ホストの利用者が別のアプリに切り替えていると、リモートの入力がそのアプリに漏れてしまうため、
+// キャプチャ対象がフォアグラウンドでないときは注入を止める（または対象を前面に出してから注入する）。
+//
+// PostMessage/SendMessage で WM_KEYDOWN や WM_LBUTTONDOWN を対象ウィンドウに直接送る方法は、
+// バックグラウンドのまま入力できるように見えるが採らない:
+// - DirectInput / Raw Input / GetAsyncKeyState でキーを読むゲームには届かない
+// - キーボード状態（GetKeyState）や IME が更新されず、修飾キーや文字入力が正しく扱われない
+// - マウスの座標はクライアント座標で送る必要があり、ホバーやドラッグはカーソル位置と食い違う
+// 多くのノベルゲームエンジンは上記のいずれかに該当するため、SendInput を使い続け、
+// 届け先がキャプチャ対象になることだけを保証する。
+//
+// 前面化（SetForegroundWindow）は、呼び出し元が直前の入力イベントを受けたプロセスでないと
+// 拒否されることがある（フォーカスの横取り防止）。SendInput で注入した直後は通りやすいが、
+// 失敗した場合は注入しない。
+//
+// keyup は押下中のキーを残さないようフォアグラウンドに関係なく注入するが、keydown を断ったキーの keyup は
+// 捨てる（前面の別のアプリに、押されていないキーの keyup だけが届かないようにする）。
+
+use core_types::LogThrottle;
+use std::collections::HashSet;
+use std::str::FromStr;
+use tracing::{info, warn};
+use windows::Win32::Foundation::HWND;
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetAncestor, GetForegroundWindow, GetWindowThreadProcessId, IsIconic, SetForegroundWindow,
+    ShowWindow, GA_ROOT, SW_RESTORE,
+};
+
+/// キャプチャ対象がフォアグラウンドでないときの入力の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FocusPolicy {
+    /// 確認せずに注入する（フォアグラウンドのウィンドウに届く）
+    Off,
+    /// 注入せずに捨てる
+    #[default]
+    Refuse,
+    /// キャプチャ対象を前面に出してから注入する（前面に出せなければ捨てる）
+    Activate,
+}
+
+impl FromStr for FocusPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "refuse" => Ok(Self::Refuse),
+            "activate" => Ok(Self::Activate),
+            _ => Err(format!(
+                "Invalid focus policy: {} (expected \"off\", \"refuse\" or \"activate\")",
+                s
+            )),
+        }
+    }
+}
+
+/// keydown / keyup の対応を取るためのキー
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KeyId {
+    /// KeyboardEvent.code
+    Code(String),
+    /// (スキャンコード, 拡張キーか)
+    ScanCode(u16, bool),
+}
+
+/// 入力を注入する前にキャプチャ対象がフォアグラウンドかを確認する
+pub struct FocusGuard {
+    policy: FocusPolicy,
+    /// 捨てた入力の警告ログの間引き
+    refused_log: LogThrottle,
+    /// keydown を捨てたキー（keyup も捨てる）
+    refused_keys: HashSet<KeyId>,
+    /// 対象がフォアグラウンドか（テストでは差し替える）
+    is_foreground: fn(u64) -> bool,
+    /// 対象を前面に出す（テストでは差し替える）
+    activate: fn(u64) -> bool,
+}
+
+impl FocusGuard {
+    pub fn new(policy: FocusPolicy) -> Self {
+        Self {
+            policy,
+            refused_log: LogThrottle::default(),
+            refused_keys: HashSet::new(),
+            is_foreground,
+            activate,
+        }
+    }
+
+    pub fn policy(&self) -> FocusPolicy {
+        self.policy
+    }
+
+    /// `target_hwnd` に入力を注入してよいか（0 はモニター全体のキャプチャで、常に注入する）
+    pub fn allows(&mut self, target_hwnd: u64) -> bool {
+        if self.policy == FocusPolicy::Off || target_hwnd == 0 {
+            return true;
+        }
+        if (self.is_foreground)(target_hwnd) {
+            return true;
+        }
+        if self.policy == FocusPolicy::Activate && (self.activate)(target_hwnd) {
+            info!("Brought capture target {} to the foreground", target_hwnd);
+            return true;
+        }
+        if let Some(suppressed) = self.refused_log.check() {
+            warn!(
+                "Capture target {} is not in the foreground, dropping input (policy: {:?}, {} more suppressed)",
+                target_hwnd, self.policy, suppressed
+            );
+        }
+        false
+    }
+
+    /// keydown を注入してよいか（捨てた場合はそのキーの keyup も `allows_key_up` で捨てる）
+    pub fn allows_key_down(&mut self, target_hwnd: u64, key: KeyId) -> bool {
+        if self.allows(target_hwnd) {
+            self.refused_keys.remove(&key);
+            true
+        } else {
+            self.refused_keys.insert(key);
+            false
+        }
+    }
+
+    /// keyup を注入してよいか（keydown を捨てたキー以外はフォアグラウンドに関係なく注入する）
+    pub fn allows_key_up(&mut self, key: &KeyId) -> bool {
+        !self.refused_keys.remove(key)
+    }
+}
+
+/// フォアグラウンドウィンドウが対象と同じプロセスか
+/// （ゲームが開いたダイアログなど、対象が所有するウィンドウが前面にある場合も含める）
+fn is_foreground(target_hwnd: u64) -> bool {
+    let target = HWND(target_hwnd as *mut _);
+    unsafe {
+        let foreground = GetForegroundWindow();
+        if foreground.is_invalid() {
+            return false;
+        }
+        if foreground == target {
+            return true;
+        }
+        let (mut target_pid, mut foreground_pid) = (0u32, 0u32);
+        GetWindowThreadProcessId(target, Some(&mut target_pid));
+        GetWindowThreadProcessId(foreground, Some(&mut foreground_pid));
+        target_pid != 0 && target_pid == foreground_pid
+    }
+}
+
+/// 対象を（最小化されていれば元に戻して）前面に出す
+/// 子ウィンドウは前面に出せないので、そのトップレベルのウィンドウを出す
+fn activate(target_hwnd: u64) -> bool {
+    let target = HWND(target_hwnd as *mut _);
+    unsafe {
+        let root = GetAncestor(target, GA_ROOT);
+        let target = if root.is_invalid() { target } else { root };
+        if IsIconic(target).as_bool() {
+            let _ = ShowWindow(target, SW_RESTORE);
+        }
+        SetForegroundWindow(target).as_bool() && is_foreground(target_hwnd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(
+        policy: FocusPolicy,
+        is_foreground: fn(u64) -> bool,
+        activate: fn(u64) -> bool,
+    ) -> FocusGuard {
+        FocusGuard {
+            policy,
+            refused_log: LogThrottle::default(),
+            refused_keys: HashSet::new(),
+            is_foreground,
+            activate,
+        }
+    }
+
+    #[test]
+    fn test_focus_policy_from_str() {
+        assert_eq!("off".parse(), Ok(FocusPolicy::Off));
+        assert_eq!("refuse".parse(), Ok(FocusPolicy::Refuse));
+        assert_eq!("activate".parse(), Ok(FocusPolicy::Activate));
+        assert!("background".parse::<FocusPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_refuse_drops_input_when_target_is_in_background() {
+        let mut background = guard(FocusPolicy::Refuse, |_| false, |_| true);
+        assert!(!background.allows(42));
+        // モニター全体のキャプチャは対象ウィンドウがないので確認しない
+        assert!(background.allows(0));
+
+        let mut foreground = guard(FocusPolicy::Refuse, |_| true, |_| false);
+        assert!(foreground.allows(42));
+    }
+
+    #[test]
+    fn test_activate_brings_target_forward() {
+        let mut activated = guard(FocusPolicy::Activate, |_| false, |_| true);
+        assert!(activated.allows(42));
+
+        let mut denied = guard(FocusPolicy::Activate, |_| false, |_| false);
+        assert!(!denied.allows(42));
+    }
+
+    #[test]
+    fn test_keyup_of_refused_keydown_is_dropped() {
+        let mut background = guard(FocusPolicy::Refuse, |_| false, |_| false);
+        let key = KeyId::Code("KeyW".to_string());
+        assert!(!background.allows_key_down(42, key.clone()));
+        assert!(!background.allows_key_up(&key));
+        // keydown を捨てていないキーの keyup は前面に関係なく注入する
+        assert!(background.allows_key_up(&KeyId::ScanCode(0x1E, false)));
+
+        // 前面に戻ってから押し直したキーの keyup は捨てない
+        let mut guard = guard(FocusPolicy::Refuse, |_| false, |_| false);
+        assert!(!guard.allows_key_down(42, key.clone()));
+        guard.is_foreground = |_| true;
+        assert!(guard.allows_key_down(42, key.clone()));
+        assert!(guard.allows_key_up(&key));
+    }
+
+    #[test]
+    fn test_off_always_injects() {
+        let mut off = guard(FocusPolicy::Off, |_| false, |_| false);
+        assert!(off.allows(42));
+    }
+}
@@ -0,0 +1,195 @@
+// キー入力の変換と押下状態の管理
+//
+// クライアントは KeyboardEvent.code（"KeyA", "ShiftLeft" などの物理キー名）を送る。
+// ゲーム操作向けにスキャンコードで注入し、押しっぱなしのキーを追跡して
+// 取りこぼした keyup（タブ切り替え・切断など）を補えるようにする。
+
+use std::collections::HashSet;
+
+/// KeyboardEvent.code をスキャンコード (Set 1) に変換する。拡張キーなら true
+pub fn scan_code(code: &str) -> Option<(u16, bool)> {
+    let scan = match code {
+        "Escape" => 0x01,
+        "Digit1" => 0x02,
+        "Digit2" => 0x03,
+        "Digit3" => 0x04,
+        "Digit4" => 0x05,
+        "Digit5" => 0x06,
+        "Digit6" => 0x07,
+        "Digit7" => 0x08,
+        "Digit8" => 0x09,
+        "Digit9" => 0x0A,
+        "Digit0" => 0x0B,
+        "Minus" => 0x0C,
+        "Equal" => 0x0D,
+        "Backspace" => 0x0E,
+        "Tab" => 0x0F,
+        "KeyQ" => 0x10,
+        "KeyW" => 0x11,
+        "KeyE" => 0x12,
+        "KeyR" => 0x13,
+        "KeyT" => 0x14,
+        "KeyY" => 0x15,
+        "KeyU" => 0x16,
+        "KeyI" => 0x17,
+        "KeyO" => 0x18,
+        "KeyP" => 0x19,
+        "BracketLeft" => 0x1A,
+        "BracketRight" => 0x1B,
+        "Enter" => 0x1C,
+        "ControlLeft" => 0x1D,
+        "KeyA" => 0x1E,
+        "KeyS" => 0x1F,
+        "KeyD" => 0x20,
+        "KeyF" => 0x21,
+        "KeyG" => 0x22,
+        "KeyH" => 0x23,
+        "KeyJ" => 0x24,
+        "KeyK" => 0x25,
+        "KeyL" => 0x26,
+        "Semicolon" => 0x27,
+        "Quote" => 0x28,
+        "Backquote" => 0x29,
+        "ShiftLeft" => 0x2A,
+        "Backslash" => 0x2B,
+        "KeyZ" => 0x2C,
+        "KeyX" => 0x2D,
+        "KeyC" => 0x2E,
+        "KeyV" => 0x2F,
+        "KeyB" => 0x30,
+        "KeyN" => 0x31,
+        "KeyM" => 0x32,
+        "Comma" => 0x33,
+        "Period" => 0x34,
+        "Slash" => 0x35,
+        "ShiftRight" => 0x36,
+        "AltLeft" => 0x38,
+        "Space" => 0x39,
+        "CapsLock" => 0x3A,
+        "F1" => 0x3B,
+        "F2" => 0x3C,
+        "F3" => 0x3D,
+        "F4" => 0x3E,
+        "F5" => 0x3F,
+        "F6" => 0x40,
+        "F7" => 0x41,
+        "F8" => 0x42,
+        "F9" => 0x43,
+        "F10" => 0x44,
+        "F11" => 0x57,
+        "F12" => 0x58,
+        _ => return extended_scan_code(code).map(|scan| (scan, true)),
+    };
+    Some((scan, false))
+}
+
+/// E0 プレフィックス付きのキー
+fn extended_scan_code(code: &str) -> Option<u16> {
+    let scan = match code {
+        "NumpadEnter" => 0x1C,
+        "ControlRight" => 0x1D,
+        "AltRight" => 0x38,
+        "Home" => 0x47,
+        "ArrowUp" => 0x48,
+        "PageUp" => 0x49,
+        "ArrowLeft" => 0x4B,
+        "ArrowRight" => 0x4D,
+        "End" => 0x4F,
+        "ArrowDown" => 0x50,
+        "PageDown" => 0x51,
+        "Insert" => 0x52,
+        "Delete" => 0x53,
+        "MetaLeft" => 0x5B,
+        "MetaRight" => 0x5C,
+        _ => return None,
+    };
+    Some(scan)
+}
+
+/// ホスト側で押下中になっているキーの集合
+#[derive(Debug, Default)]
+pub struct HeldKeys {
+    keys: HashSet<String>,
+}
+
+impl HeldKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 押下を記録する（新たに押された場合は true、オートリピートなら false）
+    pub fn press(&mut self, code: &str) -> bool {
+        self.keys.insert(code.to_string())
+    }
+
+    /// 離したことを記録する（押されていた場合は true）
+    pub fn release(&mut self, code: &str) -> bool {
+        self.keys.remove(code)
+    }
+
+    /// すべてのキーを離したことにして、離すべきキーを返す
+    pub fn release_all(&mut self) -> Vec<String> {
+        let mut keys: Vec<String> = self.keys.drain().collect();
+        keys.sort();
+        keys
+    }
+
+    /// クライアントが押していると報告したキー以外を離したことにして、離すべきキーを返す
+    /// （クライアントだけが押しているキーはここでは押さない）
+    pub fn reconcile(&mut self, client_held: &[String]) -> Vec<String> {
+        let mut stale: Vec<String> = self
+            .keys
+            .iter()
+            .filter(|key| !client_held.contains(key))
+            .cloned()
+            .collect();
+        stale.sort();
+        for key in &stale {
+            self.keys.remove(key);
+        }
+        stale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_code() {
+        assert_eq!(scan_code("KeyW"), Some((0x11, false)));
+        assert_eq!(scan_code("ShiftLeft"), Some((0x2A, false)));
+        assert_eq!(scan_code("ControlRight"), Some((0x1D, true)));
+        assert_eq!(scan_code("ArrowUp"), Some((0x48, true)));
+        assert_eq!(scan_code("Unidentified"), None);
+    }
+
+    #[test]
+    fn test_held_keys_bookkeeping() {
+        let mut held = HeldKeys::new();
+        assert!(held.press("ShiftLeft"));
+        assert!(held.press("KeyW"));
+        // オートリピート
+        assert!(!held.press("KeyW"));
+
+        assert!(held.release("KeyW"));
+        assert!(!held.release("KeyW"));
+
+        held.press("ControlLeft");
+        assert_eq!(held.release_all(), vec!["ControlLeft", "ShiftLeft"]);
+        assert!(held.release_all().is_empty());
+    }
+
+    #[test]
+    fn test_held_keys_reconcile() {
+        let mut held = HeldKeys::new();
+        held.press("ShiftLeft");
+        held.press("KeyW");
+        held.press("ControlLeft");
+
+        // クライアントは W だけ押している → Shift/Ctrl は keyup を取りこぼしている
+        let stale = held.reconcile(&["KeyW".to_string(), "KeyD".to_string()]);
+        assert_eq!(stale, vec!["ControlLeft", "ShiftLeft"]);
+        assert_eq!(held.release_all(), vec!["KeyW"]);
+    }
+}
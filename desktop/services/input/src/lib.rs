@@ -1,23 +1,55 @@
+mod clipboard;
+mod coalesce;
+mod focus;
+mod keys;
+
+pub use coalesce::DEFAULT_INPUT_RATE_HZ;
+pub use focus::FocusPolicy;
+
 use anyhow::Result;
 use image::ColorType;
 use image::ImageEncoder;
 use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
 use tagger::TaggerService;
+use tokio_util::sync::CancellationToken;
 
 use core_types::{
-    CaptureMessage, DataChannelMessage, Frame, OutgoingDataChannelMessage, ScreenshotMetadataPayload,
+    CaptureMessage, DataChannelMessage, Frame, OutgoingDataChannelMessage, PipelineConfig,
+    PixelFormat, ScreenshotMetadataPayload,
 };
 
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use windows::Win32::Foundation::HWND;
 use windows::Win32::UI::Input::KeyboardAndMouse::{
-    SendInput, INPUT, INPUT_MOUSE, MOUSEEVENTF_ABSOLUTE, MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP,
-    MOUSEEVENTF_MOVE, MOUSEEVENTF_VIRTUALDESK, MOUSEINPUT,
+    SendInput, INPUT, INPUT_KEYBOARD, INPUT_MOUSE, KEYBDINPUT, KEYEVENTF_EXTENDEDKEY,
+    KEYEVENTF_KEYUP, KEYEVENTF_SCANCODE, KEYEVENTF_UNICODE, MOUSEEVENTF_ABSOLUTE,
+    MOUSEEVENTF_LEFTDOWN, MOUSEEVENTF_LEFTUP, MOUSEEVENTF_MOVE, MOUSEEVENTF_VIRTUALDESK,
+    MOUSEINPUT, VIRTUAL_KEY,
+};
+
+use crate::clipboard::ClipboardSync;
+use crate::coalesce::{InputCoalescer, KEY_REPEAT_MIN_INTERVAL};
+use crate::focus::{FocusGuard, KeyId};
+use crate::keys::HeldKeys;
+use windows::Win32::Foundation::{POINT, RECT};
+use windows::Win32::Graphics::Gdi::ClientToScreen;
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetAncestor, GetClientRect, GetSystemMetrics, GetWindowRect, IsWindow, GA_ROOT,
+    SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN,
 };
-use windows::Win32::UI::WindowsAndMessaging::{GetSystemMetrics, GetWindowRect, SM_CXVIRTUALSCREEN, SM_CYVIRTUALSCREEN, SM_XVIRTUALSCREEN, SM_YVIRTUALSCREEN};
+
+/// 親ウィンドウの HWND から子ウィンドウの HWND を探す
+type ChildWindowFn = dyn Fn(u64) -> Option<u64> + Send + Sync;
+
+/// TextInput 1 メッセージで注入する最大 UTF-16 コードユニット数
+const MAX_TEXT_INPUT_UNITS: usize = 1024;
 
 /// 入力サービス
 pub struct InputService {
@@ -26,8 +58,35 @@ pub struct InputService {
     outgoing_dc_tx: mpsc::Sender<OutgoingDataChannelMessage>,
     tagger_service: TaggerService,
     tagger_cmd_tx: mpsc::Sender<core_types::TaggerCommand>,
+    /// 進行中の解析（ID ごとの中止トークン、終わったものはトークンがキャンセル済みになる）
+    analyses: HashMap<String, CancellationToken>,
     screenshot_dir: PathBuf,
-    target_hwnd: u64,
+    /// キャプチャ対象の HWND（ウィンドウ再作成時に hostd から更新される）
+    target_hwnd: Arc<AtomicU64>,
+    /// 子ウィンドウをキャプチャしているとき、親の HWND から子を探す（座標変換は子の矩形で行う）
+    child_window: Option<Arc<ChildWindowFn>>,
+    /// 最後に見つけた (親, 子) の HWND（子が閉じられたり親が変わったら探し直す）
+    child_hwnd: Option<(u64, u64)>,
+    /// 注入済みで keyup をまだ送っていないキー
+    held_keys: HeldKeys,
+    /// RawScanCode で注入済みで keyup をまだ送っていない (スキャンコード, 拡張キー)
+    /// （クライアントの HeldKeys はキー名しか持たないので、解放は ReleaseAllKeys のときだけ）
+    held_scan_codes: HashSet<(u16, bool)>,
+    /// キャプチャ対象の問い合わせ・切り替えを hostd に依頼する送信側
+    capture_target_tx: Option<mpsc::Sender<core_types::CaptureTargetCommand>>,
+    /// 入力をまとめて注入する間隔（None なら受け取るたびに注入する）
+    input_interval: Option<Duration>,
+    coalescer: InputCoalescer,
+    /// キャプチャ対象がフォアグラウンドでないときに入力を止める
+    focus_guard: FocusGuard,
+    /// マウス入力の注入（テストでは差し替える）
+    send_input: fn(&[INPUT]) -> u32,
+    /// クリップボードをクライアントと同期する
+    clipboard_sync: bool,
+    /// 同期中のクリップボード（run で起動する）
+    clipboard: Option<ClipboardSync>,
+    /// GetPipelineConfig に返す実効設定と音声を使えるか（None なら既定値を返す）
+    pipeline_config: Option<(PipelineConfig, Arc<AtomicBool>)>,
 }
 
 const PROMPT: &str = r#"以下のJSONスキーマに従って、スクリーンショットの解析結果を出力してください。
@@ -66,7 +125,7 @@ impl InputService {
         tagger_service: TaggerService,
         tagger_cmd_tx: mpsc::Sender<core_types::TaggerCommand>,
         screenshot_dir: PathBuf,
-        target_hwnd: u64,
+        target_hwnd: Arc<AtomicU64>,
     ) -> Self {
         Self {
             message_rx,
@@ -74,58 +133,240 @@ impl InputService {
             outgoing_dc_tx,
             tagger_service,
             tagger_cmd_tx,
+            analyses: HashMap::new(),
             screenshot_dir,
             target_hwnd,
+            held_keys: HeldKeys::new(),
+            held_scan_codes: HashSet::new(),
+            capture_target_tx: None,
+            input_interval: Some(Duration::from_secs(1) / DEFAULT_INPUT_RATE_HZ),
+            coalescer: InputCoalescer::new(KEY_REPEAT_MIN_INTERVAL),
+            focus_guard: FocusGuard::new(FocusPolicy::default()),
+            send_input,
+            clipboard_sync: false,
+            clipboard: None,
+            pipeline_config: None,
+            child_window: None,
+            child_hwnd: None,
+        }
+    }
+
+    /// 子ウィンドウをキャプチャしている（`target_hwnd` は親のまま）
+    /// `find` は親の HWND から子の HWND を探す。子はアプリが作り直すと変わるので、見失うたびに呼ぶ
+    pub fn with_child_window(
+        mut self,
+        find: impl Fn(u64) -> Option<u64> + Send + Sync + 'static,
+    ) -> Self {
+        self.child_window = Some(Arc::new(find));
+        self
+    }
+
+    /// クライアントからのキャプチャ対象の切り替えを有効にする
+    pub fn with_capture_target_tx(
+        mut self,
+        capture_target_tx: mpsc::Sender<core_types::CaptureTargetCommand>,
+    ) -> Self {
+        self.capture_target_tx = Some(capture_target_tx);
+        self
+    }
+
+    /// 入力をまとめて注入する頻度（Hz）。0 ならまとめずに受け取るたびに注入する
+    pub fn with_input_rate(mut self, hz: u32) -> Self {
+        self.input_interval = (hz > 0).then(|| Duration::from_secs(1) / hz);
+        self
+    }
+
+    /// キャプチャ対象がフォアグラウンドでないときの入力の扱い（キーの解放は常に注入する）
+    pub fn with_focus_policy(mut self, policy: FocusPolicy) -> Self {
+        self.focus_guard = FocusGuard::new(policy);
+        self
+    }
+
+    /// ホストでコピーしたテキストをクライアントに送り、クライアントから受け取ったテキストを
+    /// ホストのクリップボードに設定する
+    pub fn with_clipboard_sync(mut self, enabled: bool) -> Self {
+        self.clipboard_sync = enabled;
+        self
+    }
+
+    /// クライアントの GetPipelineConfig に返す実効設定
+    /// `audio_available` は返すときに読み、起動後に音声が使えなくなったことを `webrtc.audio` に反映する
+    pub fn with_pipeline_config(
+        mut self,
+        config: PipelineConfig,
+        audio_available: Arc<AtomicBool>,
+    ) -> Self {
+        self.pipeline_config = Some((config, audio_available));
+        self
+    }
+
+    /// キャプチャ対象に入力を注入してよいか
+    fn target_has_focus(&mut self) -> bool {
+        let target_hwnd = self.target_hwnd.load(Ordering::Relaxed);
+        self.focus_guard.allows(target_hwnd)
+    }
+
+    /// キーの keydown / keyup を注入してよいか
+    /// keyup は押下中のキーを残さないようフォアグラウンドに関係なく注入するが、keydown を捨てたキーの keyup は捨てる
+    fn key_allowed(&mut self, key: KeyId, down: bool) -> bool {
+        if down {
+            let target_hwnd = self.target_hwnd.load(Ordering::Relaxed);
+            self.focus_guard.allows_key_down(target_hwnd, key)
+        } else {
+            self.focus_guard.allows_key_up(&key)
         }
     }
 
     pub async fn run(mut self) -> Result<()> {
-        info!("InputService started");
+        info!(
+            "InputService started (focus policy: {:?})",
+            self.focus_guard.policy()
+        );
+
+        // クリップボードの同期は起動できなくても入力は続ける
+        if self.clipboard_sync {
+            self.clipboard = ClipboardSync::start()
+                .inspect_err(|e| warn!("Clipboard sync disabled: {:#}", e))
+                .ok();
+        }
+
+        let mut tick = self.input_interval.map(|interval| {
+            let mut tick = tokio::time::interval(interval);
+            tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            tick
+        });
 
         loop {
-            match self.message_rx.recv().await {
-                Some(msg) => {
-                    debug!("Received input message: {:?}", msg);
-                    self.handle_message(msg).await?;
+            tokio::select! {
+                // 届いているメッセージを先に受け取り、まとめられるだけまとめる
+                biased;
+                msg = self.message_rx.recv() => match msg {
+                    Some(msg) => {
+                        debug!("Received input message: {:?}", msg);
+                        if tick.is_some() && InputCoalescer::accepts(&msg) {
+                            self.coalescer.push(msg, Instant::now());
+                        } else {
+                            self.handle_message(msg).await?;
+                        }
+                    }
+                    None => {
+                        debug!("Input message channel closed");
+                        break;
+                    }
+                },
+                _ = next_tick(&mut tick), if !self.coalescer.is_empty() => {
+                    self.flush_input().await?;
                 }
-                None => {
-                    debug!("Input message channel closed");
-                    break;
+                Some(text) = next_clipboard_text(&mut self.clipboard) => {
+                    self.outgoing_dc_tx
+                        .send(OutgoingDataChannelMessage::Text(
+                            DataChannelMessage::ClipboardText { text },
+                        ))
+                        .await?;
                 }
             }
         }
 
+        // ためていた入力を注入してから、押しっぱなしのキーを残さないよう離す
+        self.flush_input().await?;
+        self.release_all_keys();
+        for cancel in self.analyses.values() {
+            cancel.cancel();
+        }
         info!("InputService stopped");
         Ok(())
     }
 
-    async fn handle_message(&self, msg: DataChannelMessage) -> Result<()> {
+    /// ためた入力を受け取った順に注入する
+    async fn flush_input(&mut self) -> Result<()> {
+        for msg in self.coalescer.drain() {
+            self.handle_message(msg).await?;
+        }
+        let coalesced = self.coalescer.take_coalesced();
+        if coalesced > 0 {
+            debug!("Coalesced {} input messages", coalesced);
+        }
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, msg: DataChannelMessage) -> Result<()> {
         match msg {
             DataChannelMessage::Key { key, down } => {
-                info!("Key input: {} (down: {})", key, down);
-                // 後でWin32 SendInputを実装
+                debug!("Key input: {} (down: {})", key, down);
+                self.handle_key(&key, down);
+            }
+            DataChannelMessage::RawScanCode {
+                scancode,
+                down,
+                extended,
+            } => {
+                debug!(
+                    "Raw scan code input: {:#06x} (down: {}, extended: {})",
+                    scancode, down, extended
+                );
+                self.handle_raw_scan_code(scancode, down, extended);
+            }
+            DataChannelMessage::ReleaseAllKeys => {
+                self.release_all_keys();
+            }
+            DataChannelMessage::ClipboardText { text } => match self.clipboard.as_ref() {
+                Some(clipboard) => {
+                    if let Err(e) = clipboard.set_text(text).await {
+                        warn!("Failed to set host clipboard: {:#}", e);
+                    }
+                }
+                None => debug!("Ignoring clipboard text (clipboard sync disabled)"),
+            },
+            DataChannelMessage::HeldKeys { keys } => {
+                let stale = self.held_keys.reconcile(&keys);
+                if !stale.is_empty() {
+                    warn!("Releasing keys the client no longer holds: {:?}", stale);
+                    for key in &stale {
+                        inject_key(key, false);
+                    }
+                }
             }
             DataChannelMessage::MouseWheel { delta } => {
                 info!("Mouse wheel: {}", delta);
                 // 後でWin32 SendInputを実装
             }
+            DataChannelMessage::MouseMove { x, y } => {
+                self.handle_mouse_move(x, y);
+            }
             DataChannelMessage::MouseClick { x, y, button } => {
                 // info!("Mouse click: ({}, {}) button={}", x, y, button);
                 self.handle_mouse_click(x, y, &button).await?;
             }
+            DataChannelMessage::TextInput { text } => {
+                debug!("Text input: {} chars", text.chars().count());
+                self.handle_text_input(&text);
+            }
             DataChannelMessage::ScreenshotRequest => {
                 info!("Screenshot requested");
                 self.handle_screenshot_request().await?;
             }
-            DataChannelMessage::AnalyzeRequest { id, max_edge } => {
-                info!("Analysis requested for screenshot: {} (max_edge: {})", id, max_edge);
-                self.handle_analyze_request(id, max_edge).await?;
+            DataChannelMessage::SetCursorVisible { visible } => {
+                info!("Cursor visibility change requested: {}", visible);
+                self.capture_cmd_tx
+                    .send(CaptureMessage::SetCursorVisible { visible })
+                    .await?;
+            }
+            DataChannelMessage::AnalyzeRequest {
+                id,
+                max_edge,
+                model,
+            } => {
+                info!(
+                    "Analysis requested for screenshot: {} (max_edge: {}, model: {:?})",
+                    id, max_edge, model
+                );
+                self.handle_analyze_request(id, max_edge, model).await?;
             }
-            DataChannelMessage::Ping { timestamp } => {
-                debug!("Ping received: timestamp={}", timestamp);
-                // Pingメッセージは接続の生存確認用なので、特に処理は不要
+            DataChannelMessage::Ping { id, client_ts } => {
+                // Pong は受け取ったチャネルで WebRtcService が返しているので、ここには通常届かない
+                debug!("Ping received: id={}, client_ts={}", id, client_ts);
             }
-            DataChannelMessage::Pong { timestamp: _ } => {
+            DataChannelMessage::Pong { .. } => {
                 // Pong receives are ignored
             }
 
@@ -137,6 +378,31 @@ impl InputService {
                 info!("UpdateLlmConfig: {:?}", config);
                 self.handle_update_llm_config(config).await?;
             }
+            DataChannelMessage::GetPipelineConfig => {
+                info!("GetPipelineConfig");
+                let config = match &self.pipeline_config {
+                    Some((config, audio_available)) => {
+                        let mut config = config.clone();
+                        config.webrtc.audio &= audio_available.load(Ordering::Relaxed);
+                        config
+                    }
+                    None => PipelineConfig::default(),
+                };
+                let response = DataChannelMessage::PipelineConfigResponse {
+                    config: Box::new(config),
+                };
+                self.outgoing_dc_tx
+                    .send(OutgoingDataChannelMessage::Text(response))
+                    .await?;
+            }
+            DataChannelMessage::GetCaptureTarget => {
+                info!("GetCaptureTarget");
+                self.handle_capture_target(None).await?;
+            }
+            DataChannelMessage::SetCaptureTarget { hwnd, title } => {
+                info!("SetCaptureTarget: hwnd={:?}, title={:?}", hwnd, title);
+                self.handle_capture_target(Some((hwnd, title))).await?;
+            }
             _ => {
                 debug!("Unhandled message: {:?}", msg);
             }
@@ -165,19 +431,23 @@ impl InputService {
         };
 
         // 2. Encode to PNG
-        // The frame data is BGRA (Windows Capture default)
-        // Convert BGRA to RGBA if needed, or just tell the encoder strictly.
-        // image crate supports Bgra8 so we can use that if available, or just swap.
-        // But let's check `image` crate features. Usually `ColorType::Rgba8` expects R,G,B,A.
-        // Windows Desktop Duplication usually returns BGRA.
-        // `Frame` struct in `core` has raw bytes.
-        // Let's assume we need to swap B and R.
+        // PNG is written as RGBA, so swap B and R when the capture delivers BGRA.
         let width = frame.width;
         let height = frame.height;
+        let rgba = match frame.format {
+            PixelFormat::Rgba8 => std::borrow::Cow::Borrowed(frame.data.as_slice()),
+            PixelFormat::Bgra8 => {
+                let mut data = frame.data.to_vec();
+                for pixel in data.chunks_exact_mut(4) {
+                    pixel.swap(0, 2);
+                }
+                std::borrow::Cow::Owned(data)
+            }
+        };
 
         let mut png_data = Vec::new();
         let encoder = image::codecs::png::PngEncoder::new(&mut png_data);
-        encoder.write_image(&frame.data, width, height, ColorType::Rgba8.into())?;
+        encoder.write_image(&rgba, width, height, ColorType::Rgba8.into())?;
 
         // 3. Create Metadata
         let id = Uuid::new_v4().to_string();
@@ -241,7 +511,12 @@ impl InputService {
         Ok(())
     }
 
-    async fn handle_analyze_request(&self, id: String, max_edge: u32) -> Result<()> {
+    async fn handle_analyze_request(
+        &mut self,
+        id: String,
+        max_edge: u32,
+        model: Option<String>,
+    ) -> Result<()> {
         let file_path = self.screenshot_dir.join(format!("{}.png", id));
         if !file_path.exists() {
             error!("Requested analysis for missing screenshot: {}", id);
@@ -296,52 +571,82 @@ impl InputService {
         };
 
         // 3. Call Tagger
-        let mut rx = match self
-            .tagger_service
-            .analyze_screenshot_stream(&image_data_for_analysis, PROMPT)
-            .await
-        {
-            Ok(rx) => rx,
-            Err(e) => {
-                error!("Tagger analysis failed: {}", e);
-                let response = DataChannelMessage::AnalyzeResponse {
-                    id: id.clone(),
-                    text: format!("Error: {}", e),
+        // モデルの選択（読み込み待ち）と応答の転送は別タスクで行い、解析中も入力を受け付けられるようにする
+        self.analyses.retain(|_, cancel| !cancel.is_cancelled());
+        let cancel = CancellationToken::new();
+        if let Some(previous) = self.analyses.insert(id.clone(), cancel.clone()) {
+            previous.cancel();
+        }
+        let tagger_service = self.tagger_service.clone();
+        let tagger_cmd_tx = self.tagger_cmd_tx.clone();
+        let outgoing_dc_tx = self.outgoing_dc_tx.clone();
+        tokio::spawn(async move {
+            // 終わったら（中止された場合も）トークンをキャンセル済みにして一覧から外せるようにする
+            let _finished = cancel.clone().drop_guard();
+            let stream = async {
+                let service = match model {
+                    Some(name) => {
+                        let port = select_model(&tagger_cmd_tx, name).await?;
+                        tagger_service.with_port(port)
+                    }
+                    None => tagger_service,
                 };
-                self.outgoing_dc_tx
-                    .send(OutgoingDataChannelMessage::Text(response))
-                    .await?;
-                return Ok(());
-            }
-        };
-
-        info!("Analysis stream started for {}", id);
+                service
+                    .analyze_screenshot_stream(&image_data_for_analysis, PROMPT, cancel.clone())
+                    .await
+            };
+            let mut rx = tokio::select! {
+                _ = cancel.cancelled() => return,
+                result = stream => match result {
+                    Ok(rx) => rx,
+                    Err(e) => {
+                        error!("Tagger analysis failed: {:#}", e);
+                        let response = DataChannelMessage::AnalyzeResponse {
+                            id,
+                            text: format!("Error: {:#}", e),
+                        };
+                        let _ = outgoing_dc_tx
+                            .send(OutgoingDataChannelMessage::Text(response))
+                            .await;
+                        return;
+                    }
+                },
+            };
 
-        while let Some(result) = rx.recv().await {
-            match result {
-                Ok(delta) => {
-                    let response = DataChannelMessage::AnalyzeResponseChunk {
-                        id: id.clone(),
-                        delta,
-                    };
-                    self.outgoing_dc_tx
-                        .send(OutgoingDataChannelMessage::Text(response))
-                        .await?;
-                }
-                Err(e) => {
-                    error!("Stream error during analysis: {}", e);
-                    break;
+            info!("Analysis stream started for {}", id);
+            while let Some(result) = rx.recv().await {
+                match result {
+                    Ok(delta) => {
+                        let response = DataChannelMessage::AnalyzeResponseChunk {
+                            id: id.clone(),
+                            delta,
+                        };
+                        if outgoing_dc_tx
+                            .send(OutgoingDataChannelMessage::Text(response))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Stream error during analysis: {:#}", e);
+                        break;
+                    }
                 }
             }
-        }
 
-        // 4. Send Done
-        let response = DataChannelMessage::AnalyzeResponseDone { id };
-        self.outgoing_dc_tx
-            .send(OutgoingDataChannelMessage::Text(response))
-            .await?;
+            // 4. Send Done
+            let response = DataChannelMessage::AnalyzeResponseDone { id };
+            if outgoing_dc_tx
+                .send(OutgoingDataChannelMessage::Text(response))
+                .await
+                .is_ok()
+            {
+                info!("Sent analysis completion");
+            }
+        });
 
-        info!("Sent analysis completion");
         Ok(())
     }
 
@@ -370,6 +675,44 @@ impl InputService {
         Ok(())
     }
 
+    /// キャプチャ対象を問い合わせる（`target` が Some なら切り替える）。結果はクライアントに返す
+    async fn handle_capture_target(
+        &self,
+        target: Option<(Option<u64>, Option<String>)>,
+    ) -> Result<()> {
+        let Some(capture_target_tx) = &self.capture_target_tx else {
+            warn!("Capture target control is not available");
+            return Ok(());
+        };
+
+        let (tx, rx) = oneshot::channel();
+        let command = match target {
+            Some((hwnd, title)) => core_types::CaptureTargetCommand::Set {
+                hwnd,
+                title,
+                reply_tx: tx,
+            },
+            None => core_types::CaptureTargetCommand::Get { reply_tx: tx },
+        };
+        if let Err(e) = capture_target_tx.send(command).await {
+            error!("Failed to send capture target command to hostd: {}", e);
+            return Ok(());
+        }
+
+        match rx.await {
+            Ok(payload) => {
+                let response = DataChannelMessage::CaptureTargetResponse { payload };
+                self.outgoing_dc_tx
+                    .send(OutgoingDataChannelMessage::Text(response))
+                    .await?;
+            }
+            Err(e) => {
+                error!("Failed to receive capture target response: {}", e);
+            }
+        }
+        Ok(())
+    }
+
     async fn handle_update_llm_config(&self, config: core_types::LlmConfig) -> Result<()> {
         if let Err(e) = self
             .tagger_cmd_tx
@@ -385,27 +728,78 @@ impl InputService {
         Ok(())
     }
 
-    async fn handle_mouse_click(&self, x: f64, y: f64, button: &str) -> Result<()> {
-        let (abs_x, abs_y) = if self.target_hwnd != 0 {
-            let hwnd = HWND(self.target_hwnd as *mut _);
-            let mut rect = windows::Win32::Foundation::RECT::default();
-            unsafe {
-                if GetWindowRect(hwnd, &mut rect).is_err() {
-                    error!("Failed to get window rect for hwnd {}", self.target_hwnd);
-                    return Ok(());
-                }
+    /// 座標変換に使うウィンドウ（子ウィンドウをキャプチャしていれば親の中から探した子）
+    fn input_hwnd(&mut self) -> Option<u64> {
+        let parent = self.target_hwnd.load(Ordering::Relaxed);
+        let Some(find) = self.child_window.as_ref().filter(|_| parent != 0) else {
+            return Some(parent);
+        };
+        if let Some((cached_parent, child)) = self.child_hwnd {
+            if cached_parent == parent && is_child_of(child, parent) {
+                return Some(child);
             }
-            let width = rect.right - rect.left;
-            let height = rect.bottom - rect.top;
-
-            let target_x = rect.left + (x * width as f64) as i32;
-            let target_y = rect.top + (y * height as f64) as i32;
+        }
+        let child = find(parent);
+        match child {
+            Some(child) => info!("Input child window resolved to HWND {}", child),
+            None => warn!("Child window not found under HWND {}", parent),
+        }
+        self.child_hwnd = child.map(|child| (parent, child));
+        child
+    }
 
-            self.map_to_virtual_screen(target_x, target_y)
-        } else {
+    /// 0.0-1.0 の位置を SendInput の絶対座標（仮想デスクトップ全体で 0-65535）に変換する
+    fn absolute_position(&mut self, x: f64, y: f64) -> Option<(i32, i32)> {
+        let target_hwnd = self.input_hwnd()?;
+        if target_hwnd == 0 {
             // Full screen mapping (assuming primary monitor or simple scaling)
             // x, y are 0.0-1.0
-            ((x * 65535.0) as i32, (y * 65535.0) as i32)
+            return Some(((x * 65535.0) as i32, (y * 65535.0) as i32));
+        }
+        let Some(rect) = target_rect(HWND(target_hwnd as *mut _)) else {
+            error!("Failed to get window rect for hwnd {}", target_hwnd);
+            return None;
+        };
+        let width = rect.right - rect.left;
+        let height = rect.bottom - rect.top;
+
+        let target_x = rect.left + (x * width as f64) as i32;
+        let target_y = rect.top + (y * height as f64) as i32;
+
+        Some(self.map_to_virtual_screen(target_x, target_y))
+    }
+
+    fn handle_mouse_move(&mut self, x: f64, y: f64) {
+        if !self.target_has_focus() {
+            return;
+        }
+        let Some((abs_x, abs_y)) = self.absolute_position(x, y) else {
+            return;
+        };
+        let input = INPUT {
+            r#type: INPUT_MOUSE,
+            Anonymous: windows::Win32::UI::Input::KeyboardAndMouse::INPUT_0 {
+                mi: MOUSEINPUT {
+                    dx: abs_x,
+                    dy: abs_y,
+                    mouseData: 0,
+                    dwFlags: MOUSEEVENTF_ABSOLUTE | MOUSEEVENTF_MOVE | MOUSEEVENTF_VIRTUALDESK,
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        };
+        if (self.send_input)(&[input]) != 1 {
+            warn!("SendInput failed for mouse move");
+        }
+    }
+
+    async fn handle_mouse_click(&mut self, x: f64, y: f64, button: &str) -> Result<()> {
+        if !self.target_has_focus() {
+            return Ok(());
+        }
+        let Some((abs_x, abs_y)) = self.absolute_position(x, y) else {
+            return Ok(());
         };
 
         // Click sequence: Move -> Down -> Up
@@ -454,13 +848,108 @@ impl InputService {
             },
         ];
 
-        unsafe {
-            SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
-        }
+        (self.send_input)(&inputs);
 
         Ok(())
     }
 
+    fn handle_key(&mut self, key: &str, down: bool) {
+        if keys::scan_code(key).is_none() {
+            debug!("Unsupported key code: {}", key);
+            return;
+        }
+        if !self.key_allowed(KeyId::Code(key.to_string()), down) {
+            return;
+        }
+        if down {
+            self.held_keys.press(key);
+        } else {
+            self.held_keys.release(key);
+        }
+        inject_key(key, down);
+    }
+
+    /// キー名の対応表を通さずにスキャンコードを注入する
+    fn handle_raw_scan_code(&mut self, scancode: u16, down: bool, extended: bool) {
+        if scancode == 0 {
+            debug!("Ignoring raw scan code 0");
+            return;
+        }
+        if !self.key_allowed(KeyId::ScanCode(scancode, extended), down) {
+            return;
+        }
+        if down {
+            self.held_scan_codes.insert((scancode, extended));
+        } else {
+            self.held_scan_codes.remove(&(scancode, extended));
+        }
+        if !inject_scan_code(scancode, extended, down) {
+            warn!(
+                "SendInput failed for raw scan code {:#06x} (down: {})",
+                scancode, down
+            );
+        }
+    }
+
+    /// 押下中のキーすべてに keyup を送る
+    fn release_all_keys(&mut self) {
+        let keys = self.held_keys.release_all();
+        if !keys.is_empty() {
+            info!("Releasing held keys: {:?}", keys);
+            for key in &keys {
+                inject_key(key, false);
+            }
+        }
+        if !self.held_scan_codes.is_empty() {
+            info!("Releasing held raw scan codes: {:?}", self.held_scan_codes);
+            for (scancode, extended) in self.held_scan_codes.drain() {
+                inject_scan_code(scancode, extended, false);
+            }
+        }
+    }
+
+    /// 文字列を KEYEVENTF_UNICODE で注入する（フォアグラウンドウィンドウに届く）
+    /// サロゲートペアは上位・下位のコードユニットをそれぞれ down/up で送る
+    fn handle_text_input(&mut self, text: &str) {
+        if !self.target_has_focus() {
+            return;
+        }
+        let units = text_input_units(text);
+        if units.is_empty() {
+            return;
+        }
+
+        let key_input = |unit: u16, key_up: bool| INPUT {
+            r#type: INPUT_KEYBOARD,
+            Anonymous: windows::Win32::UI::Input::KeyboardAndMouse::INPUT_0 {
+                ki: KEYBDINPUT {
+                    wVk: VIRTUAL_KEY(0),
+                    wScan: unit,
+                    dwFlags: if key_up {
+                        KEYEVENTF_UNICODE | KEYEVENTF_KEYUP
+                    } else {
+                        KEYEVENTF_UNICODE
+                    },
+                    time: 0,
+                    dwExtraInfo: 0,
+                },
+            },
+        };
+        let inputs: Vec<INPUT> = units
+            .iter()
+            .flat_map(|&unit| [key_input(unit, false), key_input(unit, true)])
+            .collect();
+
+        let sent = unsafe { SendInput(&inputs, std::mem::size_of::<INPUT>() as i32) };
+        if sent as usize != inputs.len() {
+            warn!(
+                "SendInput injected {} of {} text input events",
+                sent,
+                inputs.len()
+            );
+        }
+    }
+
     fn map_to_virtual_screen(&self, x: i32, y: i32) -> (i32, i32) {
         unsafe {
             let v_left = GetSystemMetrics(SM_XVIRTUALSCREEN);
@@ -475,3 +964,212 @@ impl InputService {
         }
     }
 }
+
+/// hostd に追加のモデルを選ばせ、読み込みを終えたらリクエストを送るポートを返す
+async fn select_model(
+    tagger_cmd_tx: &mpsc::Sender<core_types::TaggerCommand>,
+    name: String,
+) -> Result<u16> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tagger_cmd_tx
+        .send(core_types::TaggerCommand::SelectModel { name, reply_tx })
+        .await
+        .map_err(|_| anyhow::anyhow!("Host stopped before selecting the model"))?;
+    reply_rx
+        .await
+        .map_err(|_| anyhow::anyhow!("Host stopped before selecting the model"))?
+        .map_err(anyhow::Error::msg)
+}
+
+/// ホストでコピーされたテキストを待つ（同期していなければ進まない）
+async fn next_clipboard_text(clipboard: &mut Option<ClipboardSync>) -> Option<String> {
+    match clipboard {
+        Some(clipboard) => clipboard.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// ためた入力がある間だけ待つ tick（まとめない設定なら進まない）
+async fn next_tick(tick: &mut Option<tokio::time::Interval>) {
+    match tick {
+        Some(tick) => {
+            tick.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// `child` がまだあり、`parent` のトップレベルウィンドウの中にあるか
+fn is_child_of(child: u64, parent: u64) -> bool {
+    let child = HWND(child as *mut _);
+    unsafe { IsWindow(Some(child)).as_bool() && GetAncestor(child, GA_ROOT).0 as u64 == parent }
+}
+
+/// 入力の座標を合わせる対象の矩形（スクリーン座標）
+/// 子ウィンドウはキャプチャされるクライアント領域、トップレベルのウィンドウは外枠を含めた全体
+fn target_rect(hwnd: HWND) -> Option<RECT> {
+    let mut rect = RECT::default();
+    unsafe {
+        if GetAncestor(hwnd, GA_ROOT) == hwnd {
+            GetWindowRect(hwnd, &mut rect).ok()?;
+            return Some(rect);
+        }
+        GetClientRect(hwnd, &mut rect).ok()?;
+        let mut origin = POINT::default();
+        if !ClientToScreen(hwnd, &mut origin).as_bool() {
+            return None;
+        }
+        Some(RECT {
+            left: origin.x,
+            top: origin.y,
+            right: origin.x + rect.right,
+            bottom: origin.y + rect.bottom,
+        })
+    }
+}
+
+fn send_input(inputs: &[INPUT]) -> u32 {
+    unsafe { SendInput(inputs, std::mem::size_of::<INPUT>() as i32) }
+}
+
+/// KeyboardEvent.code のキーをスキャンコードで注入する
+fn inject_key(code: &str, down: bool) {
+    let Some((scan, extended)) = keys::scan_code(code) else {
+        return;
+    };
+    if !inject_scan_code(scan, extended, down) {
+        warn!("SendInput failed for key {} (down: {})", code, down);
+    }
+}
+
+/// スキャンコード (Set 1) を KEYEVENTF_SCANCODE で注入する（注入できたら true）
+fn inject_scan_code(scan: u16, extended: bool, down: bool) -> bool {
+    let mut flags = KEYEVENTF_SCANCODE;
+    if extended {
+        flags |= KEYEVENTF_EXTENDEDKEY;
+    }
+    if !down {
+        flags |= KEYEVENTF_KEYUP;
+    }
+    let input = INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: windows::Win32::UI::Input::KeyboardAndMouse::INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: VIRTUAL_KEY(0),
+                wScan: scan,
+                dwFlags: flags,
+                time: 0,
+                dwExtraInfo: 0,
+            },
+        },
+    };
+    let sent = unsafe { SendInput(&[input], std::mem::size_of::<INPUT>() as i32) };
+    sent == 1
+}
+
+/// TextInput の文字列を注入する UTF-16 コードユニットにする（上限を超えた分は切り捨てる）
+fn text_input_units(text: &str) -> Vec<u16> {
+    // 改行は CR として送る（多くのエディットコントロールは LF を無視する）
+    let text = text.replace("\r\n", "\r").replace('\n', "\r");
+
+    // 上限で切る場合もサロゲートペアの途中で切らないよう文字単位で数える
+    let mut units = Vec::new();
+    let mut buf = [0u16; 2];
+    for c in text.chars() {
+        let encoded = c.encode_utf16(&mut buf);
+        if units.len() + encoded.len() > MAX_TEXT_INPUT_UNITS {
+            warn!(
+                "Text input exceeds {} UTF-16 units, truncating",
+                MAX_TEXT_INPUT_UNITS
+            );
+            break;
+        }
+        units.extend_from_slice(encoded);
+    }
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_text_input_is_truncated_at_the_unit_limit() {
+        assert_eq!(
+            text_input_units("a\r\nb\nc"),
+            vec![0x61, 0x0d, 0x62, 0x0d, 0x63]
+        );
+
+        // ちょうど上限までは切らない
+        let full = "a".repeat(MAX_TEXT_INPUT_UNITS);
+        assert_eq!(text_input_units(&full).len(), MAX_TEXT_INPUT_UNITS);
+        let over = "a".repeat(MAX_TEXT_INPUT_UNITS + 1);
+        assert_eq!(text_input_units(&over).len(), MAX_TEXT_INPUT_UNITS);
+
+        // 上限をまたぐサロゲートペアは丸ごと落とす（上位だけを送らない）
+        let boundary = format!("{}\u{1F600}", "a".repeat(MAX_TEXT_INPUT_UNITS - 1));
+        let units = text_input_units(&boundary);
+        assert_eq!(units.len(), MAX_TEXT_INPUT_UNITS - 1);
+        assert!(units.iter().all(|&unit| unit == 0x61));
+
+        // 収まるサロゲートペアは上位・下位の両方を送る
+        let fits = format!("{}\u{1F600}", "a".repeat(MAX_TEXT_INPUT_UNITS - 2));
+        let units = text_input_units(&fits);
+        assert_eq!(units.len(), MAX_TEXT_INPUT_UNITS);
+        assert_eq!(units[MAX_TEXT_INPUT_UNITS - 2..], [0xD83D, 0xDE00]);
+    }
+
+    thread_local! {
+        static SENT: RefCell<Vec<Vec<INPUT>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_input(inputs: &[INPUT]) -> u32 {
+        SENT.with(|sent| sent.borrow_mut().push(inputs.to_vec()));
+        inputs.len() as u32
+    }
+
+    #[tokio::test]
+    async fn test_mouse_move_burst_is_single_absolute_move() {
+        let (message_tx, message_rx) = mpsc::channel(128);
+        let (capture_cmd_tx, _capture_cmd_rx) = mpsc::channel(1);
+        let (outgoing_dc_tx, _outgoing_dc_rx) = mpsc::channel(1);
+        let (tagger_cmd_tx, _tagger_cmd_rx) = mpsc::channel(1);
+        let mut service = InputService::new(
+            message_rx,
+            capture_cmd_tx,
+            outgoing_dc_tx,
+            TaggerService::new(0),
+            tagger_cmd_tx,
+            PathBuf::from("screenshots"),
+            Arc::new(AtomicU64::new(0)),
+        )
+        .with_input_rate(60);
+        service.send_input = record_input;
+
+        for i in 1..=100 {
+            message_tx
+                .send(DataChannelMessage::MouseMove {
+                    x: i as f64 / 200.0,
+                    y: 0.25,
+                })
+                .await
+                .unwrap();
+        }
+        drop(message_tx);
+        service.run().await.unwrap();
+
+        let sent = SENT.with(|sent| sent.take());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].len(), 1);
+        let mi = unsafe { sent[0][0].Anonymous.mi };
+        assert_eq!(
+            (mi.dx, mi.dy),
+            ((0.5 * 65535.0) as i32, (0.25 * 65535.0) as i32)
+        );
+        assert_eq!(
+            mi.dwFlags,
+            MOUSEEVENTF_ABSOLUTE | MOUSEEVENTF_MOVE | MOUSEEVENTF_VIRTUALDESK
+        );
+    }
+}
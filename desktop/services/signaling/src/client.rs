@@ -1,15 +1,21 @@
 use anyhow::{Context, Result};
-use core_types::{SignalingResponse, VideoCodec, WebRtcMessage};
+use core_types::{PeerConnectionState, ServiceError, SignalingResponse, VideoCodec, WebRtcMessage};
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
-use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{Error as WsError, Message as WsMessage},
+};
 use tracing::{debug, error, info, warn};
 use url::Url;
 
+/// ビューアーが negotiation_id を付けなかった場合にシグナリングサーバーが入れる値
+const DEFAULT_NEGOTIATION_ID: &str = "default";
+
 /// シグナリングメッセージ（Cloudflare経由で送受信）
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
@@ -28,6 +34,8 @@ pub enum SignalingMessage {
     Answer {
         sdp: String,
         #[serde(default, skip_serializing_if = "Option::is_none")]
+        codec: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         session_id: Option<String>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         negotiation_id: Option<String>,
@@ -62,6 +70,13 @@ pub enum SignalingMessage {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         negotiation_id: Option<String>,
     },
+    /// ホストの PeerConnection の接続状態（new/connecting/connected/disconnected/failed/closed）
+    #[serde(rename = "connection_state")]
+    ConnectionState {
+        state: PeerConnectionState,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+    },
 }
 
 /// シグナリングクライアント（WebSocketクライアント）
@@ -118,7 +133,13 @@ impl SignalingClient {
                     break;
                 }
                 Err(e) => {
-                    error!("SignalingClient error: {}", e);
+                    error!("SignalingClient error: {:#}", e);
+
+                    // 接続拒否はリトライしても回復しないため即座に返す
+                    if ServiceError::from_anyhow(&e) == Some(&ServiceError::SignalingRejected) {
+                        return Err(e);
+                    }
+
                     retry_count += 1;
 
                     if retry_count >= MAX_RETRIES {
@@ -157,9 +178,15 @@ impl SignalingClient {
         info!("Connecting to WebSocket: {}", url);
 
         // WebSocket接続
-        let (ws_stream, _) = connect_async(url.as_str())
-            .await
-            .context("Failed to connect to WebSocket")?;
+        let (ws_stream, _) = match connect_async(url.as_str()).await {
+            Ok(result) => result,
+            Err(WsError::Http(response)) if response.status().is_client_error() => {
+                return Err(ServiceError::SignalingRejected).with_context(|| {
+                    format!("WebSocket handshake rejected: {}", response.status())
+                });
+            }
+            Err(e) => return Err(e).context("Failed to connect to WebSocket"),
+        };
 
         info!("WebSocket connected");
 
@@ -179,10 +206,11 @@ impl SignalingClient {
                     break;
                 };
                 let message = match response {
-                    SignalingResponse::Answer { sdp } => SignalingMessage::Answer {
+                    SignalingResponse::Answer { sdp, codec } => SignalingMessage::Answer {
                         sdp,
+                        codec: Some(codec.to_string()),
                         session_id: Some(session_id_clone.clone()),
-                        negotiation_id: Some("default".to_string()),
+                        negotiation_id: Some(DEFAULT_NEGOTIATION_ID.to_string()),
                     },
                     SignalingResponse::IceCandidate {
                         candidate,
@@ -195,7 +223,7 @@ impl SignalingClient {
                         sdp_mline_index,
                         username_fragment,
                         session_id: Some(session_id_clone.clone()),
-                        negotiation_id: Some("default".to_string()),
+                        negotiation_id: Some(DEFAULT_NEGOTIATION_ID.to_string()),
                     },
                     SignalingResponse::IceCandidateComplete => {
                         // ICE gathering完了通知はクライアント側で処理する必要がある場合に送信
@@ -209,7 +237,13 @@ impl SignalingClient {
                         SignalingMessage::OfferForRestart {
                             sdp,
                             session_id: Some(session_id_clone.clone()),
-                            negotiation_id: Some("default".to_string()),
+                            negotiation_id: Some(DEFAULT_NEGOTIATION_ID.to_string()),
+                        }
+                    }
+                    SignalingResponse::ConnectionState { state } => {
+                        SignalingMessage::ConnectionState {
+                            state,
+                            session_id: Some(session_id_clone.clone()),
                         }
                     }
                 };
@@ -232,13 +266,20 @@ impl SignalingClient {
                     Ok(WsMessage::Text(text)) => {
                         debug!("Received message: {}", text);
                         match serde_json::from_str::<SignalingMessage>(&text) {
-                            Ok(SignalingMessage::Offer { sdp, codec, .. }) => {
+                            Ok(SignalingMessage::Offer {
+                                sdp,
+                                codec,
+                                negotiation_id,
+                                ..
+                            }) => {
                                 let parsed_codec = parse_codec_param(codec);
                                 info!("Offer received from signaling server, forwarding to WebRTC service (codec: {:?})", parsed_codec);
                                 if let Err(e) = webrtc_tx_recv
                                     .send(WebRtcMessage::SetOffer {
                                         sdp,
                                         codec: parsed_codec,
+                                        negotiation_id: negotiation_id
+                                            .filter(|id| id != DEFAULT_NEGOTIATION_ID),
                                     })
                                     .await
                                 {
@@ -285,6 +326,9 @@ impl SignalingClient {
                             Ok(SignalingMessage::OfferForRestart { .. }) => {
                                 warn!("Received OfferForRestart message as host (unexpected)");
                             }
+                            Ok(SignalingMessage::ConnectionState { .. }) => {
+                                warn!("Received ConnectionState message as host (unexpected)");
+                            }
                             Err(e) => {
                                 error!("Failed to parse message: {}", e);
                             }
@@ -329,7 +373,17 @@ fn parse_codec_param(codec: Option<String>) -> Option<VideoCodec> {
         if codec_str.eq_ignore_ascii_case("any") || codec_str.is_empty() {
             return None;
         }
-        codec_str.parse::<VideoCodec>().ok()
+        match codec_str.parse::<VideoCodec>() {
+            Ok(codec) => Some(codec),
+            Err(e) => {
+                // 未対応のコーデックはホストの既定コーデックにフォールバック（WebRTC 側で再判定）
+                warn!(
+                    "Requested codec is not available on this host ({}), using host default",
+                    e
+                );
+                None
+            }
+        }
     } else {
         None
     }
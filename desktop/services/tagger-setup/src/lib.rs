@@ -1,6 +1,11 @@
 use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::io::{self, BufReader, Read};
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
 use tokio::process::{Child, Command};
 use tracing::{debug, info, warn};
 use windows::Win32::Foundation::{CloseHandle, HANDLE};
@@ -11,6 +16,137 @@ use windows::Win32::System::JobObjects::{
 };
 use windows::Win32::System::Threading::{OpenProcess, PROCESS_SET_QUOTA, PROCESS_TERMINATE};
 
+/// 使われなくなった追加のモデルの llama-server を止めるまでの時間のデフォルト
+pub const DEFAULT_MODEL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// 追加のモデルの llama-server がモデルを読み込み終えるのを待つ上限
+const MODEL_STARTUP_TIMEOUT: Duration = Duration::from_secs(120);
+/// 読み込み中の llama-server の /health を確認する間隔
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// llama-server のコンテキスト長のデフォルト
+pub const DEFAULT_CTX_SIZE: u32 = 8192;
+/// llama-server のスレッド数のデフォルト
+pub const DEFAULT_THREADS: u32 = 8;
+/// `--n-gpu-layers` に渡す「すべてのレイヤーを GPU に載せる」値
+pub const ALL_GPU_LAYERS: u32 = 999;
+/// 載せるレイヤー数を空き VRAM から決めるとき、計算用のバッファなどに残しておく分
+const VRAM_RESERVE_BYTES: u64 = 1024 * 1024 * 1024;
+/// 1 トークン・1 レイヤーあたりの KV キャッシュの見積もり（8B クラスのモデルを f16 で持つ場合）
+const KV_BYTES_PER_TOKEN_PER_LAYER: u64 = 4 * 1024;
+/// GGUF からレイヤー数を読めなかったときに仮定するレイヤー数
+const FALLBACK_BLOCK_COUNT: u32 = 32;
+
+/// llama-server の起動オプション（`None` は自動またはデフォルト）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerOptions {
+    /// GPU に載せるレイヤー数（`None` なら空き VRAM から決める。`ALL_GPU_LAYERS` ですべて）
+    pub n_gpu_layers: Option<u32>,
+    /// コンテキスト長（`None` なら `DEFAULT_CTX_SIZE`）
+    pub ctx_size: Option<u32>,
+    /// スレッド数（`None` なら `DEFAULT_THREADS`）
+    pub threads: Option<u32>,
+}
+
+/// 名前で選べる追加のモデル（タグ付けには小さく速いモデル、詳しい説明には大きいモデル、など）
+///
+/// それぞれ別のポートで別の llama-server として動かす。
+/// `start` で起動するメインのモデルとは別に、選ばれたときに初めて起動する
+#[derive(Debug, Clone)]
+pub struct ModelSpec {
+    pub port: u16,
+    pub model_path: PathBuf,
+    pub mmproj_path: PathBuf,
+}
+
+impl ModelSpec {
+    /// `NAME=PORT,MODEL_PATH,MMPROJ_PATH` の形の指定（hostd の `--llm-model`）を名前とモデルに分ける
+    pub fn parse_named(value: &str) -> Result<(String, Self)> {
+        let parse = || {
+            let (name, rest) = value.split_once('=')?;
+            let mut parts = rest.splitn(3, ',');
+            let port = parts.next()?.trim().parse().ok()?;
+            let model_path = PathBuf::from(parts.next()?.trim());
+            let mmproj_path = PathBuf::from(parts.next()?.trim());
+            let name = name.trim();
+            (!name.is_empty()).then(|| {
+                (
+                    name.to_string(),
+                    Self {
+                        port,
+                        model_path,
+                        mmproj_path,
+                    },
+                )
+            })
+        };
+        parse().with_context(|| {
+            format!(
+                "Invalid model {:?} (expected NAME=PORT,MODEL_PATH,MMPROJ_PATH)",
+                value
+            )
+        })
+    }
+}
+
+/// `select_model` で選んだモデルの llama-server（起動したばかりなら読み込みを終えるまで待つ）
+///
+/// 待つ間に `TaggerSetup` を借りたままにしないよう、`select_model` とは分けて待つ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelStartup {
+    pub name: String,
+    pub port: u16,
+}
+
+impl ModelStartup {
+    /// llama-server がモデルを読み込み終えるまで待ち、リクエストを送るポートを返す
+    /// （動いている llama-server ならすぐに返る）
+    pub async fn wait_ready(&self) -> Result<u16> {
+        wait_until_healthy(self.port, MODEL_STARTUP_TIMEOUT)
+            .await
+            .with_context(|| format!("Model {:?} is not ready", self.name))?;
+        Ok(self.port)
+    }
+}
+
+/// `list_models` で返す追加のモデルの状態
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelStatus {
+    pub name: String,
+    pub port: u16,
+    pub running: bool,
+}
+
+/// Job Object に入れた llama-server のプロセス
+///
+/// Job Object は子プロセスごとに作るので、ハンドルを閉じればそのプロセスだけが止まる
+struct ServerProcess {
+    child: Child,
+    job: Option<HANDLE>,
+}
+
+impl ServerProcess {
+    async fn stop(mut self) -> Result<()> {
+        self.child.kill().await?;
+        self.child.wait().await?;
+        Ok(())
+    }
+}
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        if let Some(job) = self.job.take() {
+            unsafe {
+                let _ = CloseHandle(job);
+            }
+        }
+    }
+}
+
+struct ModelServer {
+    spec: ModelSpec,
+    process: Option<ServerProcess>,
+    last_used: Instant,
+}
+
 pub struct TaggerSetup {
     child: Option<Child>,
     job_handle: Option<HANDLE>,
@@ -18,6 +154,13 @@ pub struct TaggerSetup {
     current_server_path: Option<PathBuf>,
     current_model_path: Option<PathBuf>,
     current_mmproj_path: Option<PathBuf>,
+    current_options: ServerOptions,
+    /// 名前で選べる追加のモデル
+    models: BTreeMap<String, ModelServer>,
+    /// 追加のモデルがこれだけ選ばれなければ llama-server を止める
+    model_idle_timeout: Duration,
+    /// nvidia-smi の結果（毎回確かめない）
+    use_gpu: Option<bool>,
 }
 
 
@@ -30,7 +173,165 @@ impl TaggerSetup {
             current_server_path: None,
             current_model_path: None,
             current_mmproj_path: None,
+            current_options: ServerOptions::default(),
+            models: BTreeMap::new(),
+            model_idle_timeout: DEFAULT_MODEL_IDLE_TIMEOUT,
+            use_gpu: None,
+        }
+    }
+
+    /// 名前で選べる追加のモデルを登録する（`select_model` で選ばれるまで起動しない）
+    pub fn with_model(mut self, name: impl Into<String>, spec: ModelSpec) -> Self {
+        self.add_model(name, spec);
+        self
+    }
+
+    /// 追加のモデルがこれだけ選ばれなければ llama-server を止める
+    pub fn with_model_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.model_idle_timeout = timeout;
+        self
+    }
+
+    /// 名前で選べる追加のモデルを登録する（同じ名前があれば置き換え、動いていれば止める）
+    pub fn add_model(&mut self, name: impl Into<String>, spec: ModelSpec) {
+        let name = name.into();
+        let previous = self.models.insert(
+            name.clone(),
+            ModelServer {
+                spec,
+                process: None,
+                last_used: Instant::now(),
+            },
+        );
+        if previous.is_some_and(|server| server.process.is_some()) {
+            // ServerProcess の drop で Job Object ごと止まる
+            info!("Replaced model {:?}, its llama-server was stopped", name);
+        }
+    }
+
+    /// 登録されている追加のモデル（名前順）
+    pub fn list_models(&self) -> Vec<ModelStatus> {
+        self.models
+            .iter()
+            .map(|(name, server)| ModelStatus {
+                name: name.clone(),
+                port: server.spec.port,
+                running: server.process.is_some(),
+            })
+            .collect()
+    }
+
+    /// 追加のモデルを選ぶ（llama-server が動いていなければ起動する）
+    ///
+    /// 読み込みを終えるまでは待たないので、返った `ModelStartup::wait_ready` で待ってからリクエストを送る。
+    /// 選ぶたびに使われた時刻を更新するので、リクエストごとに呼ぶ
+    pub async fn select_model(&mut self, name: &str) -> Result<ModelStartup> {
+        let Some(server) = self.models.get_mut(name) else {
+            anyhow::bail!("Unknown model: {}", name);
+        };
+        server.last_used = Instant::now();
+        // 落ちていたら起動し直す
+        let exited = server
+            .process
+            .as_mut()
+            .is_some_and(|process| !matches!(process.child.try_wait(), Ok(None)));
+        if exited {
+            warn!("llama-server for model {:?} exited, restarting it", name);
+            server.process = None;
+        }
+        let spec = server.spec.clone();
+        if server.process.is_none() {
+            let process = self.spawn_model_server(name, &spec).await?;
+            if let Some(server) = self.models.get_mut(name) {
+                server.process = Some(process);
+            }
+        }
+        Ok(ModelStartup {
+            name: name.to_string(),
+            port: spec.port,
+        })
+    }
+
+    /// `model_idle_timeout` の間選ばれなかった追加のモデルの llama-server を止め、止めた数を返す
+    /// （定期的に呼ぶ）
+    pub async fn shutdown_idle_models(&mut self) -> usize {
+        let now = Instant::now();
+        let idle: Vec<String> = self
+            .models
+            .iter()
+            .filter(|(_, server)| {
+                server.process.is_some()
+                    && now.duration_since(server.last_used) >= self.model_idle_timeout
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &idle {
+            info!(
+                "Model {:?} has been idle for {:?}, stopping its llama-server",
+                name, self.model_idle_timeout
+            );
+            self.stop_model(name).await;
+        }
+        idle.len()
+    }
+
+    async fn stop_model(&mut self, name: &str) {
+        let Some(process) = self
+            .models
+            .get_mut(name)
+            .and_then(|server| server.process.take())
+        else {
+            return;
+        };
+        if let Err(e) = process.stop().await {
+            warn!("Failed to stop llama-server for model {:?}: {}", name, e);
+        }
+    }
+
+    async fn spawn_model_server(&mut self, name: &str, spec: &ModelSpec) -> Result<ServerProcess> {
+        let server_path = self.resolve_server_path(self.current_server_path.clone())?;
+        let exe_path = server_path.join("llama-server.exe");
+        anyhow::ensure!(
+            exe_path.exists(),
+            "llama-server.exe not found at {:?}",
+            exe_path
+        );
+        for path in [&spec.model_path, &spec.mmproj_path] {
+            if !path.exists() {
+                warn!(
+                    "File for model {:?} not found at {:?}. llama-server might fail.",
+                    name, path
+                );
+            }
         }
+
+        let options = self.current_options;
+        let args = self
+            .launch_args(&spec.model_path, &spec.mmproj_path, spec.port, options)
+            .await;
+        info!(
+            "Starting llama-server for model {:?}: {:?} {:?}",
+            name, exe_path, args
+        );
+
+        let child = Command::new(exe_path)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to spawn llama-server for model {:?}", name))?;
+        let job = match create_kill_on_close_job() {
+            Ok(job) => {
+                assign_to_job(job, &child);
+                Some(job)
+            }
+            Err(e) => {
+                warn!("Failed to create Job Object for model {:?}: {}", name, e);
+                None
+            }
+        };
+        Ok(ServerProcess { child, job })
     }
 
     pub async fn start(
@@ -39,11 +340,13 @@ impl TaggerSetup {
         server_path: Option<PathBuf>,
         custom_model_path: Option<PathBuf>,
         custom_mmproj_path: Option<PathBuf>,
+        options: ServerOptions,
     ) -> Result<()> {
         self.current_port = port;
         self.current_server_path = server_path.clone();
         self.current_model_path = custom_model_path.clone();
         self.current_mmproj_path = custom_mmproj_path.clone();
+        self.current_options = options;
 
         let server_path = self.resolve_server_path(server_path)?;
         let model_path = if let Some(p) = custom_model_path {
@@ -68,51 +371,7 @@ impl TaggerSetup {
 
         // Ensure Job Object is created
         if self.job_handle.is_none() {
-            unsafe {
-                let job = CreateJobObjectW(None, None)?;
-                let mut info = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
-                info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
-                SetInformationJobObject(
-                    job,
-                    JobObjectExtendedLimitInformation,
-                    &info as *const _ as *const _,
-                    std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
-                )?;
-                self.job_handle = Some(job);
-            }
-        }
-
-        let use_gpu = self.check_gpu_availability().await;
-
-        let mut args = vec![
-            "-m".to_string(),
-            model_path.to_string_lossy().to_string(),
-            "--mmproj".to_string(),
-            mmproj_path.to_string_lossy().to_string(),
-            "--port".to_string(),
-            port.to_string(),
-            "-fa".to_string(), // Flash Attention
-            "on".to_string(),
-            "-t".to_string(),
-            "8".to_string(),
-            "-tb".to_string(),
-            "8".to_string(),
-            "-c".to_string(),
-            "8192".to_string(),
-            "-b".to_string(),
-            "2048".to_string(),
-            "-ub".to_string(),
-            "1024".to_string(),
-            "--image-min-tokens".to_string(),
-            "1024".to_string(),
-        ];
-
-        if use_gpu {
-            info!("NVIDIA GPU detected, enabling GPU offload");
-            args.push("--n-gpu-layers".to_string());
-            args.push("999".to_string());
-        } else {
-            warn!("NVIDIA GPU not detected, falling back to CPU/Software");
+            self.job_handle = Some(create_kill_on_close_job()?);
         }
 
         let exe_path = server_path.join("llama-server.exe");
@@ -121,6 +380,10 @@ impl TaggerSetup {
             return Ok(());
         }
 
+        let args = self
+            .launch_args(&model_path, &mmproj_path, port, options)
+            .await;
+
         info!("Starting llama-server: {:?} {:?}", exe_path, args);
 
         let child = Command::new(exe_path)
@@ -132,24 +395,7 @@ impl TaggerSetup {
             .context("Failed to spawn llama-server")?;
 
         if let Some(job) = self.job_handle {
-            if let Some(pid) = child.id() {
-                let process_handle_res = unsafe {
-                    OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, false, pid)
-                };
-                match process_handle_res {
-                    Ok(process_handle) => {
-                        unsafe {
-                            if let Err(e) = AssignProcessToJobObject(job, process_handle) {
-                                warn!("Failed to assign llama-server to Job Object: {}", e);
-                            }
-                            let _ = CloseHandle(process_handle);
-                        }
-                    }
-                    Err(e) => {
-                         warn!("Failed to open process handle for llama-server (PID: {}): {}", pid, e);
-                    }
-                }
-            }
+            assign_to_job(job, &child);
         }
 
         self.child = Some(child);
@@ -164,9 +410,17 @@ impl TaggerSetup {
         server_path: Option<PathBuf>,
         custom_model_path: Option<PathBuf>,
         custom_mmproj_path: Option<PathBuf>,
+        options: ServerOptions,
     ) -> Result<()> {
         self.shutdown().await?;
-        self.start(port, server_path, custom_model_path, custom_mmproj_path).await
+        self.start(
+            port,
+            server_path,
+            custom_model_path,
+            custom_mmproj_path,
+            options,
+        )
+        .await
     }
 
     pub fn get_config(&self) -> (u16, Option<PathBuf>, Option<PathBuf>) {
@@ -209,9 +463,100 @@ impl TaggerSetup {
         Ok(model_path)
     }
 
-    async fn check_gpu_availability(&self) -> bool {
+    /// llama-server の引数を決めてログに出す
+    async fn launch_args(
+        &mut self,
+        model_path: &Path,
+        mmproj_path: &Path,
+        port: u16,
+        options: ServerOptions,
+    ) -> Vec<String> {
+        let ctx_size = options.ctx_size.unwrap_or(DEFAULT_CTX_SIZE);
+        let threads = options.threads.unwrap_or(DEFAULT_THREADS);
+        let n_gpu_layers = match options.n_gpu_layers {
+            Some(layers) => Some(layers),
+            None => {
+                self.auto_gpu_layers(model_path, mmproj_path, ctx_size)
+                    .await
+            }
+        };
+        info!(
+            "llama-server parameters: n_gpu_layers={} ({}), ctx_size={}, threads={}",
+            n_gpu_layers.map_or("none".to_string(), |layers| layers.to_string()),
+            if options.n_gpu_layers.is_some() {
+                "configured"
+            } else {
+                "auto"
+            },
+            ctx_size,
+            threads
+        );
+        server_args(
+            model_path,
+            mmproj_path,
+            port,
+            n_gpu_layers,
+            ctx_size,
+            threads,
+        )
+    }
+
+    /// 空き VRAM に収まる GPU レイヤー数を決める（GPU がなければ `None`）
+    async fn auto_gpu_layers(
+        &mut self,
+        model_path: &Path,
+        mmproj_path: &Path,
+        ctx_size: u32,
+    ) -> Option<u32> {
+        if !self.check_gpu_availability().await {
+            return None;
+        }
+        let Some(free_vram) = query_free_vram().await else {
+            warn!("Failed to read free VRAM, offloading all layers to the GPU");
+            return Some(ALL_GPU_LAYERS);
+        };
+        // GGUF のメタデータ（トークナイザーの語彙を含む）の読み込みはブロッキングなので別スレッドで行う
+        let (model_path, mmproj_path) = (model_path.to_path_buf(), mmproj_path.to_path_buf());
+        let read = tokio::task::spawn_blocking(move || {
+            let file_len = |path: &Path| std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            (
+                gguf_block_count(&model_path),
+                file_len(&model_path),
+                file_len(&mmproj_path),
+                model_path,
+            )
+        })
+        .await;
+        let (block_count, model_len, mmproj_len, model_path) = match read {
+            Ok(read) => read,
+            Err(e) => {
+                warn!("Failed to read the model files: {}", e);
+                return Some(ALL_GPU_LAYERS);
+            }
+        };
+        let block_count = block_count.unwrap_or_else(|| {
+            warn!(
+                "Failed to read the layer count from {:?}, assuming {}",
+                model_path, FALLBACK_BLOCK_COUNT
+            );
+            FALLBACK_BLOCK_COUNT
+        });
+        let layers = safe_gpu_layers(free_vram, model_len, mmproj_len, block_count, ctx_size);
+        info!(
+            "{} MiB of VRAM free, offloading {} of {} layers",
+            free_vram / (1024 * 1024),
+            layers.min(block_count),
+            block_count
+        );
+        Some(layers)
+    }
+
+    async fn check_gpu_availability(&mut self) -> bool {
+        if let Some(use_gpu) = self.use_gpu {
+            return use_gpu;
+        }
         // Simple check using nvidia-smi
-        match Command::new("nvidia-smi")
+        let use_gpu = match Command::new("nvidia-smi")
             .arg("--query-gpu=name")
             .arg("--format=csv,noheader")
             .output()
@@ -231,7 +576,14 @@ impl TaggerSetup {
                 debug!("Failed to execute nvidia-smi: {}", e);
                 false
             }
+        };
+        if use_gpu {
+            info!("NVIDIA GPU detected, enabling GPU offload");
+        } else {
+            warn!("NVIDIA GPU not detected, falling back to CPU/Software");
         }
+        self.use_gpu = Some(use_gpu);
+        use_gpu
     }
 
     pub async fn shutdown(&mut self) -> Result<()> {
@@ -244,6 +596,466 @@ impl TaggerSetup {
         if let Some(job) = self.job_handle.take() {
             unsafe { let _ = CloseHandle(job); }
         }
+        let names: Vec<String> = self.models.keys().cloned().collect();
+        for name in names {
+            self.stop_model(&name).await;
+        }
         Ok(())
     }
 }
+
+/// llama-server の引数（`n_gpu_layers` が `None` なら GPU に載せない）
+fn server_args(
+    model_path: &Path,
+    mmproj_path: &Path,
+    port: u16,
+    n_gpu_layers: Option<u32>,
+    ctx_size: u32,
+    threads: u32,
+) -> Vec<String> {
+    let mut args = vec![
+        "-m".to_string(),
+        model_path.to_string_lossy().to_string(),
+        "--mmproj".to_string(),
+        mmproj_path.to_string_lossy().to_string(),
+        "--port".to_string(),
+        port.to_string(),
+        "-fa".to_string(), // Flash Attention
+        "on".to_string(),
+        "-t".to_string(),
+        threads.to_string(),
+        "-tb".to_string(),
+        threads.to_string(),
+        "-c".to_string(),
+        ctx_size.to_string(),
+        "-b".to_string(),
+        "2048".to_string(),
+        "-ub".to_string(),
+        "1024".to_string(),
+        "--image-min-tokens".to_string(),
+        "1024".to_string(),
+    ];
+    if let Some(layers) = n_gpu_layers {
+        args.push("--n-gpu-layers".to_string());
+        args.push(layers.to_string());
+    }
+    args
+}
+
+/// nvidia-smi で空き VRAM（バイト）を読む（複数の GPU があれば合計。llama-server はレイヤーを分けて載せる）
+async fn query_free_vram() -> Option<u64> {
+    let output = Command::new("nvidia-smi")
+        .arg("--query-gpu=memory.free")
+        .arg("--format=csv,noheader,nounits")
+        .output()
+        .await
+        .ok()?;
+    if !output.status.success() {
+        debug!("nvidia-smi failed with status: {}", output.status);
+        return None;
+    }
+    parse_free_vram_mib(&String::from_utf8_lossy(&output.stdout)).map(|mib| mib * 1024 * 1024)
+}
+
+/// `nvidia-smi --query-gpu=memory.free --format=csv,noheader,nounits` の出力（GPU ごとに MiB）の合計
+fn parse_free_vram_mib(output: &str) -> Option<u64> {
+    let values: Vec<u64> = output
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    (!values.is_empty()).then(|| values.iter().sum())
+}
+
+/// 空き VRAM に収まる GPU レイヤー数を見積もる（すべて収まれば `ALL_GPU_LAYERS`）
+///
+/// mmproj と計算用のバッファの分を先に差し引き、残りをレイヤーごとの重みと KV キャッシュで割る
+fn safe_gpu_layers(
+    free_vram: u64,
+    model_bytes: u64,
+    mmproj_bytes: u64,
+    block_count: u32,
+    ctx_size: u32,
+) -> u32 {
+    let block_count = block_count.max(1);
+    let per_layer =
+        model_bytes / block_count as u64 + ctx_size as u64 * KV_BYTES_PER_TOKEN_PER_LAYER;
+    let budget = free_vram.saturating_sub(mmproj_bytes + VRAM_RESERVE_BYTES);
+    let layers = budget / per_layer.max(1);
+    if layers >= block_count as u64 {
+        ALL_GPU_LAYERS
+    } else {
+        layers as u32
+    }
+}
+
+/// GGUF のメタデータからモデルのレイヤー数（`<architecture>.block_count`）を読む
+fn gguf_block_count(path: &Path) -> Option<u32> {
+    let file = std::fs::File::open(path).ok()?;
+    read_gguf_block_count(&mut BufReader::new(file))
+        .ok()
+        .flatten()
+}
+
+const GGUF_TYPE_STRING: u32 = 8;
+const GGUF_TYPE_ARRAY: u32 = 9;
+/// 異常なファイルで巨大な確保をしないための文字列長の上限
+const GGUF_MAX_STRING_LEN: u64 = 1 << 20;
+
+fn read_gguf_block_count<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    // バージョン 1 はカウントが u32 で形式が違う（今は使われていない）
+    if &magic != b"GGUF" || read_u32(reader)? < 2 {
+        return Ok(None);
+    }
+    let _tensor_count = read_u64(reader)?;
+    let kv_count = read_u64(reader)?;
+
+    let mut architecture: Option<String> = None;
+    let mut block_counts: Vec<(String, u64)> = Vec::new();
+    for _ in 0..kv_count {
+        let key = read_gguf_string(reader)?;
+        let value_type = read_u32(reader)?;
+        if key == "general.architecture" && value_type == GGUF_TYPE_STRING {
+            architecture = Some(read_gguf_string(reader)?);
+        } else if key.ends_with(".block_count") {
+            match read_gguf_uint(reader, value_type)? {
+                Some(count) => block_counts.push((key, count)),
+                None => continue,
+            }
+        } else {
+            skip_gguf_value(reader, value_type)?;
+        }
+        if let Some(arch) = &architecture {
+            let wanted = format!("{}.block_count", arch);
+            if let Some((_, count)) = block_counts.iter().find(|(key, _)| *key == wanted) {
+                return Ok(u32::try_from(*count).ok());
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_gguf_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = read_u64(reader)?;
+    if len > GGUF_MAX_STRING_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "GGUF string is too long",
+        ));
+    }
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// 符号なし整数の値を読む（他の型なら読み飛ばして `None`）
+fn read_gguf_uint<R: Read>(reader: &mut R, value_type: u32) -> io::Result<Option<u64>> {
+    let mut buf = [0u8; 8];
+    let size = match value_type {
+        0 => 1,
+        2 => 2,
+        4 => 4,
+        10 => 8,
+        _ => {
+            skip_gguf_value(reader, value_type)?;
+            return Ok(None);
+        }
+    };
+    reader.read_exact(&mut buf[..size])?;
+    Ok(Some(u64::from_le_bytes(buf)))
+}
+
+/// 固定長の型の大きさ（バイト）
+fn gguf_fixed_size(value_type: u32) -> Option<u64> {
+    match value_type {
+        0 | 1 | 7 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+fn skip_gguf_value<R: Read>(reader: &mut R, value_type: u32) -> io::Result<()> {
+    let size = match value_type {
+        GGUF_TYPE_STRING => read_u64(reader)?,
+        GGUF_TYPE_ARRAY => {
+            let item_type = read_u32(reader)?;
+            let count = read_u64(reader)?;
+            match gguf_fixed_size(item_type) {
+                Some(item_size) => count.saturating_mul(item_size),
+                None => {
+                    for _ in 0..count {
+                        skip_gguf_value(reader, item_type)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        _ => gguf_fixed_size(value_type)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown GGUF value type"))?,
+    };
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped < size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// ハンドルを閉じると中のプロセスを止める Job Object を作る
+fn create_kill_on_close_job() -> Result<HANDLE> {
+    unsafe {
+        let job = CreateJobObjectW(None, None)?;
+        let mut info = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        if let Err(e) = SetInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            &info as *const _ as *const _,
+            std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+        ) {
+            let _ = CloseHandle(job);
+            return Err(e.into());
+        }
+        Ok(job)
+    }
+}
+
+/// llama-server を Job Object に入れる（hostd が落ちても残らないように）
+fn assign_to_job(job: HANDLE, child: &Child) {
+    let Some(pid) = child.id() else {
+        return;
+    };
+    match unsafe { OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, false, pid) } {
+        Ok(process_handle) => unsafe {
+            if let Err(e) = AssignProcessToJobObject(job, process_handle) {
+                warn!("Failed to assign llama-server to Job Object: {}", e);
+            }
+            let _ = CloseHandle(process_handle);
+        },
+        Err(e) => {
+            warn!(
+                "Failed to open process handle for llama-server (PID: {}): {}",
+                pid, e
+            );
+        }
+    }
+}
+
+/// llama-server がモデルを読み込み終える（/health が 200 を返す）まで待つ
+///
+/// 読み込み中の llama-server は接続を受け付けても 503 を返す
+async fn wait_until_healthy(port: u16, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if health_ok(port).await {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            Instant::now() < deadline,
+            "llama-server on port {} did not become ready within {:?}",
+            port,
+            timeout
+        );
+        tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+    }
+}
+
+async fn health_ok(port: u16) -> bool {
+    let check = async {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        stream
+            .write_all(b"GET /health HTTP/1.0\r\nHost: 127.0.0.1\r\n\r\n")
+            .await?;
+        let mut status_line = [0u8; 12];
+        stream.read_exact(&mut status_line).await?;
+        std::io::Result::Ok(status_line.ends_with(b" 200"))
+    };
+    matches!(
+        tokio::time::timeout(HEALTH_POLL_INTERVAL, check).await,
+        Ok(Ok(true))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1024 * 1024 * 1024;
+
+    fn gguf_string(buf: &mut Vec<u8>, value: &str) {
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    #[test]
+    fn test_parse_free_vram_sums_all_gpus() {
+        assert_eq!(parse_free_vram_mib("11000\n"), Some(11000));
+        assert_eq!(parse_free_vram_mib("8000\r\n4000\r\n"), Some(12000));
+        assert_eq!(parse_free_vram_mib(""), None);
+        assert_eq!(parse_free_vram_mib("[N/A]\n"), None);
+    }
+
+    #[test]
+    fn test_safe_gpu_layers() {
+        // 5 GiB のモデル（32 レイヤー）と 1 GiB の mmproj
+        let model = 5 * GIB;
+        let mmproj = GIB;
+        assert_eq!(
+            safe_gpu_layers(24 * GIB, model, mmproj, 32, 8192),
+            ALL_GPU_LAYERS
+        );
+        // 4 GiB から mmproj と予備を引いた 2 GiB に、1 レイヤー 192 MiB（重み 160 MiB + KV 32 MiB）
+        assert_eq!(safe_gpu_layers(4 * GIB, model, mmproj, 32, 8192), 10);
+        assert_eq!(safe_gpu_layers(GIB, model, mmproj, 32, 8192), 0);
+    }
+
+    #[test]
+    fn test_read_gguf_block_count() {
+        let mut buf = b"GGUF".to_vec();
+        buf.extend_from_slice(&3u32.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.extend_from_slice(&4u64.to_le_bytes());
+        // 文字列の配列は読み飛ばす
+        gguf_string(&mut buf, "tokenizer.ggml.tokens");
+        buf.extend_from_slice(&GGUF_TYPE_ARRAY.to_le_bytes());
+        buf.extend_from_slice(&GGUF_TYPE_STRING.to_le_bytes());
+        buf.extend_from_slice(&2u64.to_le_bytes());
+        gguf_string(&mut buf, "a");
+        gguf_string(&mut buf, "b");
+        // アーキテクチャより前にあるレイヤー数も拾う
+        gguf_string(&mut buf, "qwen3vl.block_count");
+        buf.extend_from_slice(&4u32.to_le_bytes());
+        buf.extend_from_slice(&36u32.to_le_bytes());
+        gguf_string(&mut buf, "general.architecture");
+        buf.extend_from_slice(&GGUF_TYPE_STRING.to_le_bytes());
+        gguf_string(&mut buf, "qwen3vl");
+        gguf_string(&mut buf, "general.name");
+        buf.extend_from_slice(&GGUF_TYPE_STRING.to_le_bytes());
+        gguf_string(&mut buf, "Qwen3 VL");
+
+        assert_eq!(
+            read_gguf_block_count(&mut buf.as_slice()).unwrap(),
+            Some(36)
+        );
+        assert_eq!(read_gguf_block_count(&mut &b"GGML0000"[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_server_args_pass_the_options() {
+        let args = server_args(
+            Path::new("m.gguf"),
+            Path::new("p.gguf"),
+            8081,
+            Some(20),
+            4096,
+            4,
+        );
+        let value = |flag: &str| {
+            let index = args.iter().position(|arg| arg == flag).unwrap();
+            args[index + 1].as_str()
+        };
+        assert_eq!(value("--n-gpu-layers"), "20");
+        assert_eq!(value("-c"), "4096");
+        assert_eq!(value("-t"), "4");
+
+        let args = server_args(
+            Path::new("m.gguf"),
+            Path::new("p.gguf"),
+            8081,
+            None,
+            4096,
+            4,
+        );
+        assert!(!args.iter().any(|arg| arg == "--n-gpu-layers"));
+    }
+
+    fn spec(port: u16) -> ModelSpec {
+        ModelSpec {
+            port,
+            model_path: PathBuf::from("small.gguf"),
+            mmproj_path: PathBuf::from("mmproj.gguf"),
+        }
+    }
+
+    #[test]
+    fn test_parse_named_model() {
+        let (name, spec) =
+            ModelSpec::parse_named(r"tags=8082,C:\models\small.gguf,C:\models\mmproj.gguf")
+                .unwrap();
+        assert_eq!(name, "tags");
+        assert_eq!(spec.port, 8082);
+        assert_eq!(spec.model_path, PathBuf::from(r"C:\models\small.gguf"));
+        assert_eq!(spec.mmproj_path, PathBuf::from(r"C:\models\mmproj.gguf"));
+        assert!(ModelSpec::parse_named("tags=8082,small.gguf").is_err());
+        assert!(ModelSpec::parse_named("=8082,small.gguf,mmproj.gguf").is_err());
+        assert!(ModelSpec::parse_named("tags=port,small.gguf,mmproj.gguf").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_models_are_listed_and_unknown_names_rejected() {
+        let mut setup = TaggerSetup::new()
+            .with_model("tags", spec(8082))
+            .with_model("describe", spec(8083))
+            .with_model_idle_timeout(Duration::ZERO);
+        assert_eq!(
+            setup.list_models(),
+            [
+                ModelStatus {
+                    name: "describe".to_string(),
+                    port: 8083,
+                    running: false
+                },
+                ModelStatus {
+                    name: "tags".to_string(),
+                    port: 8082,
+                    running: false
+                },
+            ]
+        );
+        assert!(setup.select_model("caption").await.is_err());
+        // 動いていないモデルは止めない
+        assert_eq!(setup.shutdown_idle_models().await, 0);
+    }
+
+    /// /health に `statuses` の順で応答する llama-server の代わり
+    async fn fake_health_server(statuses: &'static [u16]) -> u16 {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for status in statuses.iter().cycle() {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 64];
+                let _ = stream.read(&mut request).await;
+                let response = format!("HTTP/1.1 {} OK\r\nContent-Length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_wait_ready_waits_for_the_model_to_load() {
+        // 読み込み中は 503 を返す
+        let port = fake_health_server(&[503, 200]).await;
+        let startup = ModelStartup {
+            name: "tags".to_string(),
+            port,
+        };
+        assert_eq!(startup.wait_ready().await.unwrap(), port);
+    }
+}
@@ -0,0 +1,193 @@
+// ビジョンモデルに送るスクリーンショットの再エンコード
+//
+// フル HD の PNG を base64 にすると数 MB になり、送信と llama-server 側のデコードに時間がかかる。
+// ビジョンモデルは画像を内部で縮小してから使うのでフル解像度は要らず、長辺を抑えて
+// JPEG にすれば大きさは 1/5〜1/10 になる。WebP は純 Rust のエンコーダーが可逆圧縮しか持たないので
+// PNG より少し小さくなる程度で、大きさを抑えたい場合は JPEG を使う。
+// PNG のままで縮小もしない場合（既定）は受け取ったバイト列をそのまま送る。
+
+use anyhow::{Context, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::ImageEncoder;
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+/// JPEG の品質（文字を読める程度を保つ）
+pub const JPEG_QUALITY: u8 = 80;
+
+/// 送るときの画像形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ImageEncoding {
+    /// 受け取った PNG をそのまま送る（縮小した場合は PNG で作り直す）
+    #[default]
+    Png,
+    Jpeg,
+    /// 可逆圧縮の WebP
+    WebP,
+}
+
+impl ImageEncoding {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageEncoding::Png => "image/png",
+            ImageEncoding::Jpeg => "image/jpeg",
+            ImageEncoding::WebP => "image/webp",
+        }
+    }
+}
+
+impl FromStr for ImageEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(ImageEncoding::Png),
+            "jpeg" | "jpg" => Ok(ImageEncoding::Jpeg),
+            "webp" => Ok(ImageEncoding::WebP),
+            _ => Err(format!(
+                "Invalid image encoding: {} (expected \"png\", \"jpeg\" or \"webp\")",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ImageEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageEncoding::Png => write!(f, "png"),
+            ImageEncoding::Jpeg => write!(f, "jpeg"),
+            ImageEncoding::WebP => write!(f, "webp"),
+        }
+    }
+}
+
+/// 送る前の画像の形式と大きさの上限
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImageOptions {
+    pub encoding: ImageEncoding,
+    /// 長辺の上限（超える場合は縦横比を保って縮小する。None なら縮小しない）
+    pub max_edge: Option<u32>,
+}
+
+/// 送る形式にした画像
+pub(crate) struct EncodedImage<'a> {
+    pub mime_type: &'static str,
+    pub data: Cow<'a, [u8]>,
+}
+
+/// 画像を `options` の形式と大きさにする
+pub(crate) fn encode_image<'a>(data: &'a [u8], options: &ImageOptions) -> Result<EncodedImage<'a>> {
+    let mime_type = options.encoding.mime_type();
+    if options.encoding == ImageEncoding::Png && options.max_edge.is_none() {
+        return Ok(EncodedImage {
+            mime_type,
+            data: Cow::Borrowed(data),
+        });
+    }
+
+    let mut image = image::load_from_memory(data).context("Failed to decode screenshot")?;
+    if let Some(max_edge) = options.max_edge {
+        if image.width() > max_edge || image.height() > max_edge {
+            image = image.resize(max_edge, max_edge, FilterType::Triangle);
+        } else if options.encoding == ImageEncoding::Png {
+            return Ok(EncodedImage {
+                mime_type,
+                data: Cow::Borrowed(data),
+            });
+        }
+    }
+
+    let (width, height) = (image.width(), image.height());
+    let mut encoded = Vec::new();
+    match options.encoding {
+        ImageEncoding::Png => {
+            let rgba = image.to_rgba8();
+            PngEncoder::new(&mut encoded).write_image(&rgba, width, height, image::ColorType::Rgba8)
+        }
+        // JPEG はアルファを持てない
+        ImageEncoding::Jpeg => {
+            let rgb = image.to_rgb8();
+            JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY).write_image(
+                &rgb,
+                width,
+                height,
+                image::ColorType::Rgb8,
+            )
+        }
+        ImageEncoding::WebP => {
+            let rgba = image.to_rgba8();
+            WebPEncoder::new_lossless(&mut encoded).write_image(
+                &rgba,
+                width,
+                height,
+                image::ColorType::Rgba8,
+            )
+        }
+    }
+    .with_context(|| format!("Failed to encode screenshot as {}", options.encoding))?;
+
+    Ok(EncodedImage {
+        mime_type,
+        data: Cow::Owned(encoded),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// `width` x `height` のグラデーションの PNG
+    pub(crate) fn test_png(width: u32, height: u32) -> Vec<u8> {
+        let image = image::RgbaImage::from_fn(width, height, |x, y| {
+            image::Rgba([(x % 256) as u8, (y % 256) as u8, 128, 255])
+        });
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .write_image(&image, width, height, image::ColorType::Rgba8)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn test_encodes_and_caps_the_longest_edge() {
+        let png = test_png(640, 360);
+        for (encoding, format) in [
+            (ImageEncoding::Png, image::ImageFormat::Png),
+            (ImageEncoding::Jpeg, image::ImageFormat::Jpeg),
+            (ImageEncoding::WebP, image::ImageFormat::WebP),
+        ] {
+            let options = ImageOptions {
+                encoding,
+                max_edge: Some(320),
+            };
+            let encoded = encode_image(&png, &options).unwrap();
+            assert_eq!(encoded.mime_type, encoding.mime_type());
+            assert_eq!(image::guess_format(&encoded.data).unwrap(), format);
+            let decoded = image::load_from_memory(&encoded.data).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (320, 180));
+        }
+    }
+
+    #[test]
+    fn test_png_within_the_cap_is_sent_as_is() {
+        let png = test_png(320, 240);
+        let options = ImageOptions {
+            encoding: ImageEncoding::Png,
+            max_edge: Some(320),
+        };
+        assert!(matches!(
+            encode_image(&png, &options).unwrap().data,
+            Cow::Borrowed(_)
+        ));
+        // 既定では画像として読めなくてもそのまま送る
+        let encoded = encode_image(b"png", &ImageOptions::default()).unwrap();
+        assert_eq!(&*encoded.data, b"png");
+        assert!("gif".parse::<ImageEncoding>().is_err());
+        assert_eq!("jpg".parse::<ImageEncoding>(), Ok(ImageEncoding::Jpeg));
+    }
+}
@@ -1,12 +1,29 @@
+mod image_encoding;
+mod sse;
+
 use anyhow::{Context, Result};
 use base64::prelude::*;
-use reqwest::Client;
+use reqwest::{Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::image_encoding::{encode_image, EncodedImage};
+use crate::sse::SseParser;
+
+pub use crate::image_encoding::{ImageEncoding, ImageOptions};
 
 #[derive(Clone)]
 pub struct TaggerService {
     client: Client,
     base_url: String,
+    request_timeout: Duration,
+    retry: RetryPolicy,
+    /// ストリーミングの差分を溜めておけるチャネルの容量
+    stream_capacity: usize,
+    /// 送る前の画像の形式と大きさの上限
+    image_options: ImageOptions,
 }
 
 #[derive(Serialize)]
@@ -67,52 +84,264 @@ struct ChunkDelta {
     content: Option<String>,
 }
 
+/// llama-server への接続を待つ上限のデフォルト
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// 応答（ストリーミングでは次のチャンク）を待つ上限のデフォルト
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+/// ストリーミングの差分を溜めておけるチャネルの容量のデフォルト
+pub const DEFAULT_STREAM_CAPACITY: usize = 100;
+/// 一時的な失敗を再試行する回数のデフォルト
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+/// 最初の再試行までの待ち時間のデフォルト（再試行ごとに倍にする）
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// 1 回のリクエストに含められる画像の枚数
+///
+/// llama-server は tagger-setup で `-c 8192 --image-min-tokens 1024` として起動しており、
+/// 画像 1 枚が少なくとも 1024 トークン（解像度によってはそれ以上）のコンテキストを使う。
+/// プロンプトと応答（max_tokens 512）の分を残すと、4 枚程度が上限になる。
+/// 超えた場合 llama-server はコンテキスト不足のエラー（400）を返す
+pub const MAX_BATCH_IMAGES: usize = 4;
+/// 1 回のリクエストに含められる画像の合計サイズ（base64 にした後、約 4/3 倍になる）
+///
+/// llama-server の JSON 本文の上限より十分小さく、数枚のフル HD PNG が収まる大きさにしている
+pub const MAX_BATCH_PAYLOAD_BYTES: usize = 32 * 1024 * 1024;
+
+/// base64 にしたときの長さ
+fn base64_len(bytes: usize) -> usize {
+    bytes.div_ceil(3) * 4
+}
+
+/// 1 回のリクエストで送れる画像か確かめる
+fn validate_batch(images: &[&[u8]]) -> Result<()> {
+    anyhow::ensure!(!images.is_empty(), "No images to analyze");
+    anyhow::ensure!(
+        images.len() <= MAX_BATCH_IMAGES,
+        "Too many images in one request: {} (max {})",
+        images.len(),
+        MAX_BATCH_IMAGES
+    );
+    if let Some(index) = images.iter().position(|image| image.is_empty()) {
+        anyhow::bail!("Image {} is empty", index + 1);
+    }
+    let payload: usize = images.iter().map(|image| base64_len(image.len())).sum();
+    anyhow::ensure!(
+        payload <= MAX_BATCH_PAYLOAD_BYTES,
+        "Images are too large for one request: {} bytes after base64 (max {})",
+        payload,
+        MAX_BATCH_PAYLOAD_BYTES
+    );
+    Ok(())
+}
+
+/// 再試行の方針
+///
+/// 応答を受け取る前の失敗（接続できない・応答待ちのタイムアウト・5xx/429）だけを再試行する。
+/// ストリーミングで一部を返し始めた後の失敗は、同じ内容を重ねて返さないよう再試行しない。
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    max_retries: u32,
+    backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// `attempt` 回目（0 始まり）の失敗の後に待つ時間
+    fn delay(&self, attempt: u32) -> Duration {
+        self.backoff.saturating_mul(1u32 << attempt.min(16))
+    }
+}
+
+/// 再試行してよい失敗か
+fn is_transient(error: &reqwest::Error) -> bool {
+    if error.is_connect() || error.is_timeout() {
+        return true;
+    }
+    error
+        .status()
+        .is_some_and(|status| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS)
+}
+
 impl TaggerService {
     pub fn new(port: u16) -> Self {
         Self {
-            client: Client::builder()
-                .timeout(std::time::Duration::from_secs(300))
-                .build()
-                .unwrap_or_else(|_| Client::new()),
+            client: build_client(DEFAULT_CONNECT_TIMEOUT),
             base_url: format!("http://127.0.0.1:{}", port),
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            retry: RetryPolicy {
+                max_retries: DEFAULT_MAX_RETRIES,
+                backoff: DEFAULT_RETRY_BACKOFF,
+            },
+            stream_capacity: DEFAULT_STREAM_CAPACITY,
+            image_options: ImageOptions::default(),
         }
     }
 
-    pub async fn analyze_screenshot(&self, image_data: &[u8], prompt: &str) -> Result<String> {
-        let base64_image = BASE64_STANDARD.encode(image_data);
-        let data_url = format!("data:image/png;base64,{}", base64_image); 
+    /// リクエストを送る llama-server のポートを変える
+    ///
+    /// tagger-setup の `select_model` で選んだモデルに送る場合は、返ったポートで
+    /// `service.clone().with_port(port)` としてリクエストごとに使い分ける
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.base_url = format!("http://127.0.0.1:{}", port);
+        self
+    }
 
-        let request = ChatCompletionRequest {
+    /// 接続と応答待ちのタイムアウトを指定（ストリーミングでは `request` がチャンク間の上限になる）
+    pub fn with_timeouts(mut self, connect: Duration, request: Duration) -> Self {
+        self.client = build_client(connect);
+        self.request_timeout = request;
+        self
+    }
+
+    /// 一時的な失敗の再試行回数と、最初の再試行までの待ち時間（以降は倍々）を指定
+    pub fn with_retry(mut self, max_retries: u32, backoff: Duration) -> Self {
+        self.retry = RetryPolicy {
+            max_retries,
+            backoff,
+        };
+        self
+    }
+
+    /// ストリーミングの差分を溜めておけるチャネルの容量を指定
+    /// 受信側が追いつかずに埋まった場合は、待たずに以降の差分をつなげて 1 つにまとめる
+    pub fn with_stream_capacity(mut self, capacity: usize) -> Self {
+        self.stream_capacity = capacity.max(1);
+        self
+    }
+
+    /// 送る前に画像を再エンコード・縮小する（既定では受け取った PNG をそのまま送る）
+    pub fn with_image_options(mut self, options: ImageOptions) -> Self {
+        self.image_options = options;
+        self
+    }
+
+    /// 画像を送る形式にし、1 回のリクエストで送れるか確かめる
+    fn encode_images<'a>(&self, images: &[&'a [u8]]) -> Result<Vec<EncodedImage<'a>>> {
+        let encoded = images
+            .iter()
+            .map(|image| encode_image(image, &self.image_options))
+            .collect::<Result<Vec<_>>>()?;
+        let data: Vec<&[u8]> = encoded.iter().map(|image| &*image.data).collect();
+        validate_batch(&data)?;
+        Ok(encoded)
+    }
+
+    /// プロンプトの後に画像を並べたリクエスト（複数枚のときは各画像の前に番号を付ける）
+    fn batch_request(images: &[EncodedImage], prompt: &str, stream: bool) -> ChatCompletionRequest {
+        let mut content = vec![ContentPart::Text {
+            text: prompt.to_string(),
+        }];
+        for (index, image) in images.iter().enumerate() {
+            if images.len() > 1 {
+                content.push(ContentPart::Text {
+                    text: format!("Image {}:", index + 1),
+                });
+            }
+            let base64_image = BASE64_STANDARD.encode(&image.data);
+            content.push(ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: format!("data:{};base64,{}", image.mime_type, base64_image),
+                },
+            });
+        }
+
+        ChatCompletionRequest {
             messages: vec![Message {
                 role: "user".to_string(),
-                content: vec![
-                    ContentPart::Text {
-                        text: prompt.to_string(),
-                    },
-                    ContentPart::ImageUrl {
-                        image_url: ImageUrl {
-                            url: data_url,
-                        },
-                    },
-                ],
+                content,
             }],
             max_tokens: Some(512),
             temperature: Some(0.7),
-            stream: None,
-        };
+            stream: stream.then_some(true),
+        }
+    }
 
-        let response = self
-            .client
-            .post(format!("{}/v1/chat/completions", self.base_url))
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send request to llama-server")?
-            .error_for_status()
-            .context("llama-server returned error status")?
-            .json::<ChatCompletionResponse>()
-            .await
-            .context("Failed to parse response from llama-server")?;
+    /// リクエストを送り、応答ヘッダーを受け取るまで待つ（一時的な失敗はバックオフして再試行する）
+    /// `cancel` されたら待たずにエラーを返す
+    async fn send_with_retry(
+        client: &Client,
+        url: &str,
+        request: &ChatCompletionRequest,
+        request_timeout: Duration,
+        retry: RetryPolicy,
+        cancel: &CancellationToken,
+    ) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let send = async {
+                tokio::time::timeout(request_timeout, client.post(url).json(request).send())
+                    .await
+                    .map_err(|_| None)?
+                    .and_then(Response::error_for_status)
+                    .map_err(Some)
+            };
+            let result = tokio::select! {
+                _ = cancel.cancelled() => anyhow::bail!("Analysis cancelled"),
+                result = send => result,
+            };
+            let error = match result {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            let transient = error.as_ref().is_none_or(is_transient);
+            let error = match error {
+                Some(e) => anyhow::Error::new(e).context("Request to llama-server failed"),
+                None => {
+                    anyhow::anyhow!("llama-server did not respond within {:?}", request_timeout)
+                }
+            };
+            if !transient || attempt >= retry.max_retries {
+                return Err(error);
+            }
+
+            let delay = retry.delay(attempt);
+            attempt += 1;
+            warn!(
+                "{:#}, retrying in {:?} ({}/{})",
+                error, delay, attempt, retry.max_retries
+            );
+            tokio::select! {
+                _ = cancel.cancelled() => anyhow::bail!("Analysis cancelled"),
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+
+    /// スクリーンショットを解析して応答全体を返す（`cancel` で中断できる）
+    pub async fn analyze_screenshot(
+        &self,
+        image_data: &[u8],
+        prompt: &str,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let images = self.encode_images(&[image_data])?;
+        let request = Self::batch_request(&images, prompt, false);
+        self.complete(&request, cancel).await
+    }
+
+    /// ストリーミングせずにリクエストを送り、最初の候補の本文を返す
+    async fn complete(
+        &self,
+        request: &ChatCompletionRequest,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let url = format!("{}/v1/chat/completions", self.base_url);
+
+        let response = Self::send_with_retry(
+            &self.client,
+            &url,
+            request,
+            self.request_timeout,
+            self.retry,
+            cancel,
+        )
+        .await?;
+        let response = tokio::select! {
+            _ = cancel.cancelled() => anyhow::bail!("Analysis cancelled"),
+            body = tokio::time::timeout(self.request_timeout, response.json::<ChatCompletionResponse>()) => body
+                .context("Timed out reading response from llama-server")?
+                .context("Failed to parse response from llama-server")?,
+        };
 
         let content = response
             .choices
@@ -126,95 +355,394 @@ impl TaggerService {
         Ok(content)
     }
 
+    /// 複数のスクリーンショットを 1 回のリクエストで解析する（変化の説明など、画像を比べさせたい場合）
+    ///
+    /// 画像は渡した順に "Image 1:", "Image 2:" ... の見出しを付けて並べるので、
+    /// プロンプトではその番号で画像を参照できる。枚数と base64 後の合計サイズは
+    /// [`MAX_BATCH_IMAGES`] と [`MAX_BATCH_PAYLOAD_BYTES`] を超えられない
+    pub async fn analyze_screenshots(
+        &self,
+        images: &[&[u8]],
+        prompt: &str,
+        cancel: &CancellationToken,
+    ) -> Result<String> {
+        let images = self.encode_images(images)?;
+        let request = Self::batch_request(&images, prompt, false);
+        self.complete(&request, cancel).await
+    }
+
+    /// スクリーンショットを解析し、応答の差分を順に受け取るチャネルを返す
+    ///
+    /// `cancel` されると受信中の HTTP リクエストごと送信タスクを止め、チャネルを閉じる。
+    /// 受信側を drop した場合も、次の差分を待たずに同様に止まる。
+    /// 受信側が追いつかずにチャネルが埋まった場合は、送信タスクは待たずに差分をつなげて溜め、
+    /// 空きができたときにまとめて送る（差分は欠けないが、1 回に届く量が増える）
     pub async fn analyze_screenshot_stream(
         &self,
         image_data: &[u8],
         prompt: &str,
+        cancel: CancellationToken,
     ) -> Result<tokio::sync::mpsc::Receiver<Result<String>>> {
-        let base64_image = BASE64_STANDARD.encode(image_data);
-        let data_url = format!("data:image/png;base64,{}", base64_image);
-
-        let request = ChatCompletionRequest {
-            messages: vec![Message {
-                role: "user".to_string(),
-                content: vec![
-                    ContentPart::Text {
-                        text: prompt.to_string(),
-                    },
-                    ContentPart::ImageUrl {
-                        image_url: ImageUrl { url: data_url },
-                    },
-                ],
-            }],
-            max_tokens: Some(512),
-            temperature: Some(0.7),
-            stream: Some(true),
-        };
-
+        let images = self.encode_images(&[image_data])?;
+        let request = Self::batch_request(&images, prompt, true);
         let client = self.client.clone();
         let url = format!("{}/v1/chat/completions", self.base_url);
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        let (request_timeout, retry) = (self.request_timeout, self.retry);
+        let (tx, rx) = tokio::sync::mpsc::channel(self.stream_capacity);
+        // 受信側が drop されたことを知るための送信側の複製（`tx` は stream が持っていく）
+        let closed_tx = tx.clone();
 
         tokio::spawn(async move {
-            let res = match client
-                .post(url)
-                .json(&request)
-                .send()
+            let stream = async {
+                let mut sender = DeltaSender::new(tx);
+                let res = match Self::send_with_retry(
+                    &client,
+                    &url,
+                    &request,
+                    request_timeout,
+                    retry,
+                    &cancel,
+                )
                 .await
-                .context("Failed to send request")
-            {
-                Ok(res) => res,
-                Err(e) => {
-                    let _ = tx.send(Err(e)).await;
-                    return;
+                {
+                    Ok(res) => res,
+                    Err(e) => {
+                        sender.send_error(e).await;
+                        return;
+                    }
+                };
+
+                use futures::StreamExt;
+                let mut stream = res.bytes_stream();
+                let mut parser = SseParser::default();
+
+                'stream: loop {
+                    let item = match tokio::time::timeout(request_timeout, stream.next()).await {
+                        Ok(Some(item)) => item,
+                        Ok(None) => {
+                            if let Some(data) = parser.finish() {
+                                forward_stream_event(&data, &mut sender);
+                            }
+                            break;
+                        }
+                        Err(_) => {
+                            sender
+                                .send_error(anyhow::anyhow!(
+                                    "llama-server stopped streaming for {:?}",
+                                    request_timeout
+                                ))
+                                .await;
+                            return;
+                        }
+                    };
+                    match item {
+                        Ok(bytes) => {
+                            for data in parser.push(&bytes) {
+                                if !forward_stream_event(&data, &mut sender) {
+                                    break 'stream;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            sender
+                                .send_error(anyhow::anyhow!("Stream error: {}", e))
+                                .await;
+                            return;
+                        }
+                    }
                 }
+                sender.flush().await;
             };
 
-            if let Err(e) = res.error_for_status_ref() {
-                let _ = tx.send(Err(anyhow::anyhow!("Server error: {}", e))).await;
-                return;
+            // キャンセルされたら受信中のレスポンスごと drop する（エラーは送らずにチャネルを閉じる）
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => debug!("Streaming analysis cancelled"),
+                _ = closed_tx.closed() => debug!("Streaming analysis receiver dropped"),
+                _ = stream => {}
             }
+        });
 
-            use futures::StreamExt;
-            let mut stream = res.bytes_stream();
-            let mut buffer = String::new();
+        Ok(rx)
+    }
+}
 
-            while let Some(item) = stream.next().await {
-                match item {
-                    Ok(bytes) => {
-                        let chunk_str = String::from_utf8_lossy(&bytes);
-                        buffer.push_str(&chunk_str);
+/// ストリーミング応答のイベント 1 つ分のデータから本文の差分を送る
+/// 続きを受け取る必要がなければ（[DONE] か受信側が閉じた）false
+fn forward_stream_event(data: &str, sender: &mut DeltaSender) -> bool {
+    if data == "[DONE]" {
+        return false;
+    }
+    let content = serde_json::from_str::<ChatCompletionChunk>(data)
+        .ok()
+        .and_then(|chunk| chunk.choices.into_iter().next())
+        .and_then(|choice| choice.delta.content);
+    match content {
+        Some(content) => sender.push(&content),
+        None => true,
+    }
+}
 
-                        while let Some(idx) = buffer.find('\n') {
-                            let line = buffer[..idx].trim().to_string();
-                            buffer = buffer[idx + 1..].to_string();
+/// 応答の差分を受信側に渡す
+/// チャネルが埋まっている間は待たずに差分をつなげて溜め、空きができたときにまとめて送る
+struct DeltaSender {
+    tx: tokio::sync::mpsc::Sender<Result<String>>,
+    pending: String,
+}
 
-                            if line.starts_with("data: ") {
-                                let data = &line[6..];
-                                if data == "[DONE]" {
-                                    return;
-                                }
+impl DeltaSender {
+    fn new(tx: tokio::sync::mpsc::Sender<Result<String>>) -> Self {
+        Self {
+            tx,
+            pending: String::new(),
+        }
+    }
 
-                                if let Ok(chunk) = serde_json::from_str::<ChatCompletionChunk>(data) {
-                                    if let Some(choice) = chunk.choices.first() {
-                                        if let Some(content) = &choice.delta.content {
-                                            if tx.send(Ok(content.clone())).await.is_err() {
-                                                return; // Receiver dropped
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        let _ = tx.send(Err(anyhow::anyhow!("Stream error: {}", e))).await;
-                        break;
+    /// 差分を送る（受信側が閉じていれば false）
+    fn push(&mut self, delta: &str) -> bool {
+        use tokio::sync::mpsc::error::TrySendError;
+
+        self.pending.push_str(delta);
+        match self.tx.try_send(Ok(std::mem::take(&mut self.pending))) {
+            Ok(()) => true,
+            Err(TrySendError::Full(pending)) => {
+                self.pending = pending.unwrap_or_default();
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// 溜めている差分を送り切る（ストリームの終わりなので空きを待つ）
+    async fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            let _ = self.tx.send(Ok(std::mem::take(&mut self.pending))).await;
+        }
+    }
+
+    /// 溜めている差分の後にエラーを送る
+    async fn send_error(&mut self, error: anyhow::Error) {
+        self.flush().await;
+        let _ = self.tx.send(Err(error)).await;
+    }
+}
+
+fn build_client(connect_timeout: Duration) -> Client {
+    Client::builder()
+        .connect_timeout(connect_timeout)
+        .build()
+        .unwrap_or_else(|_| Client::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// 最初の `hang` 回の接続には応答せず、それ以降は `body` を返すモックサーバー
+    /// 受け付けた接続数を返す
+    async fn mock_server(
+        hang: usize,
+        content_type: &'static str,
+        body: &'static str,
+    ) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let index = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    // リクエストを読み捨てる（ヘッダーと本文は 1 回の read に収まらないこともあるが、応答には不要）
+                    let mut buf = vec![0u8; 64 * 1024];
+                    let _ = socket.read(&mut buf).await;
+                    if index < hang {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        return;
                     }
-                }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        content_type,
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
             }
         });
+        (port, connections)
+    }
 
-        Ok(rx)
+    fn service(port: u16) -> TaggerService {
+        TaggerService::new(port)
+            .with_timeouts(Duration::from_secs(1), Duration::from_millis(200))
+            .with_retry(2, Duration::from_millis(10))
+    }
+
+    #[test]
+    fn test_batch_request_interleaves_labels_and_images() {
+        let images = TaggerService::new(0)
+            .encode_images(&[b"before", b"after"])
+            .unwrap();
+        let request = TaggerService::batch_request(&images, "What changed?", false);
+        let json = serde_json::to_value(&request).unwrap();
+        let content = json["messages"][0]["content"].as_array().unwrap();
+        let kinds: Vec<&str> = content
+            .iter()
+            .map(|p| p["type"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, ["text", "text", "image_url", "text", "image_url"]);
+        assert_eq!(content[0]["text"], "What changed?");
+        assert_eq!(content[3]["text"], "Image 2:");
+        assert_eq!(
+            content[4]["image_url"]["url"],
+            format!("data:image/png;base64,{}", BASE64_STANDARD.encode(b"after"))
+        );
+        assert!(json.get("stream").is_none());
+    }
+
+    #[test]
+    fn test_image_options_set_data_url_type_and_size() {
+        let png = crate::image_encoding::tests::test_png(640, 360);
+        let service = TaggerService::new(0).with_image_options(ImageOptions {
+            encoding: ImageEncoding::Jpeg,
+            max_edge: Some(320),
+        });
+        let images = service.encode_images(&[&png]).unwrap();
+        let request = TaggerService::batch_request(&images, "describe", false);
+        let json = serde_json::to_value(&request).unwrap();
+        let url = json["messages"][0]["content"][1]["image_url"]["url"]
+            .as_str()
+            .unwrap();
+
+        let encoded = url.strip_prefix("data:image/jpeg;base64,").unwrap();
+        let jpeg = BASE64_STANDARD.decode(encoded).unwrap();
+        let image = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((image.width(), image.height()), (320, 180));
+    }
+
+    #[test]
+    fn test_validate_batch() {
+        assert!(validate_batch(&[b"a", b"b"]).is_ok());
+        assert!(validate_batch(&[]).is_err());
+        assert!(validate_batch(&[b"a", b""]).is_err());
+        assert!(validate_batch(&[b"a".as_slice(); MAX_BATCH_IMAGES + 1]).is_err());
+
+        // base64 で 4/3 倍になるので、元のサイズが上限の 3/4 を超えると送れない
+        let image = vec![0u8; MAX_BATCH_PAYLOAD_BYTES / 4 * 3 + 3];
+        assert!(validate_batch(&[&image]).is_err());
+        assert_eq!(base64_len(3), 4);
+        assert_eq!(base64_len(4), 8);
+    }
+
+    #[tokio::test]
+    async fn test_retries_after_timeout() {
+        let (port, connections) = mock_server(
+            1,
+            "application/json",
+            r#"{"choices":[{"message":{"content":"a cat"}}]}"#,
+        )
+        .await;
+
+        let content = service(port)
+            .analyze_screenshot(b"png", "describe", &CancellationToken::new())
+            .await
+            .expect("second attempt should succeed");
+        assert_eq!(content, "a cat");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_with_port_targets_selected_model() {
+        let (port, connections) = mock_server(
+            0,
+            "application/json",
+            r#"{"choices":[{"message":{"content":"tags"}}]}"#,
+        )
+        .await;
+
+        let content = service(0)
+            .with_port(port)
+            .analyze_screenshot(b"png", "tag", &CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(content, "tags");
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_retries() {
+        let (port, connections) = mock_server(usize::MAX, "application/json", "").await;
+
+        let result = service(port)
+            .analyze_screenshot(b"png", "describe", &CancellationToken::new())
+            .await;
+        assert!(result.is_err());
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stream_retries_after_timeout() {
+        let (port, connections) = mock_server(
+            1,
+            "text/event-stream",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"a \"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"cat\"}}]}\n\ndata: [DONE]\n\n",
+        )
+        .await;
+
+        let mut rx = service(port)
+            .analyze_screenshot_stream(b"png", "describe", CancellationToken::new())
+            .await
+            .unwrap();
+        let mut content = String::new();
+        while let Some(delta) = rx.recv().await {
+            content.push_str(&delta.expect("stream should succeed"));
+        }
+        assert_eq!(content, "a cat");
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream_cancel_closes_channel() {
+        let (port, _) = mock_server(usize::MAX, "text/event-stream", "").await;
+        let cancel = CancellationToken::new();
+        let mut rx = TaggerService::new(port)
+            .analyze_screenshot_stream(b"png", "describe", cancel.clone())
+            .await
+            .unwrap();
+
+        cancel.cancel();
+        let closed = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .expect("cancelled stream should close promptly");
+        assert!(closed.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_coalesces_deltas_for_slow_receiver() {
+        let (port, _) = mock_server(
+            0,
+            "text/event-stream",
+            "data: {\"choices\":[{\"delta\":{\"content\":\"a\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"b\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"c\"}}]}\n\ndata: [DONE]\n\n",
+        )
+        .await;
+
+        let mut rx = service(port)
+            .with_stream_capacity(1)
+            .analyze_screenshot_stream(b"png", "describe", CancellationToken::new())
+            .await
+            .unwrap();
+        // 受信側が読まない間も送信タスクは止まらず、残りの差分をまとめておく
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let mut deltas = Vec::new();
+        while let Some(delta) = rx.recv().await {
+            deltas.push(delta.expect("stream should succeed"));
+        }
+        assert_eq!(deltas, ["a", "bc"]);
     }
 }
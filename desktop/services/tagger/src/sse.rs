@@ -0,0 +1,99 @@
+// Server-Sent Events のパーサー（llama-server のストリーミング応答用）
+//
+// ネットワークのチャンクは行や UTF-8 の文字の途中で切れることがあるので、バイト列のまま溜めて
+// 行が揃ってからデコードする。イベントは空行で区切られ、複数の `data:` 行は改行でつないで 1 つのデータにする。
+// 行末は LF と CRLF のどちらも受け付け、コメント行（`:` で始まる）と `data` 以外のフィールドは無視する。
+
+/// SSE のバイト列をイベントのデータに組み立てる
+#[derive(Debug, Default)]
+pub struct SseParser {
+    /// まだ行末が来ていないバイト列
+    buffer: Vec<u8>,
+    /// 組み立て中のイベントのデータ
+    data: Option<String>,
+}
+
+impl SseParser {
+    /// 受け取ったバイト列を加え、揃ったイベントのデータを返す
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            self.process_line(&String::from_utf8_lossy(&line), &mut events);
+        }
+        events
+    }
+
+    /// ストリームの終わりで、空行で閉じられていない最後のイベントを返す
+    pub fn finish(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        let mut events = Vec::new();
+        if !rest.is_empty() {
+            let rest = String::from_utf8_lossy(&rest);
+            self.process_line(rest.trim_end_matches('\r'), &mut events);
+        }
+        self.process_line("", &mut events);
+        events.pop()
+    }
+
+    fn process_line(&mut self, line: &str, events: &mut Vec<String>) {
+        if line.is_empty() {
+            events.extend(self.data.take());
+            return;
+        }
+        if line.starts_with(':') {
+            return;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        if field != "data" {
+            return;
+        }
+        // コロンの後の空白は 1 つだけ取り除く
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match self.data.as_mut() {
+            Some(data) => {
+                data.push('\n');
+                data.push_str(value);
+            }
+            None => self.data = Some(value.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multibyte_character_split_across_chunks() {
+        let event = "data: {\"choices\":[{\"delta\":{\"content\":\"猫😀\"}}]}\n\n".as_bytes();
+        // 絵文字（4 バイト）の途中で切る
+        let split = event.iter().position(|&b| b == 0xF0).unwrap() + 2;
+        let mut parser = SseParser::default();
+        assert!(parser.push(&event[..split]).is_empty());
+        let events = parser.push(&event[split..]);
+        assert_eq!(events.len(), 1);
+
+        let chunk: serde_json::Value = serde_json::from_str(&events[0]).unwrap();
+        assert_eq!(chunk["choices"][0]["delta"]["content"], "猫😀");
+    }
+
+    #[test]
+    fn test_crlf_comments_and_multiple_events() {
+        let mut parser = SseParser::default();
+        let events =
+            parser.push(b": keep-alive\r\ndata: a\r\n\r\ndata:b\ndata: c\n\nevent: x\ndata: [DO");
+        assert_eq!(events, ["a", "b\nc"]);
+        assert_eq!(parser.push(b"NE]\n\n"), ["[DONE]"]);
+
+        // 空行で閉じずに終わったイベントも返す
+        assert!(parser.push(b"data: tail").is_empty());
+        assert_eq!(parser.finish().as_deref(), Some("tail"));
+        assert_eq!(parser.finish(), None);
+    }
+}
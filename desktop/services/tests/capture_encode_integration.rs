@@ -2,7 +2,9 @@
 #[cfg(windows)]
 mod tests {
     use anyhow::{Context, Result};
-    use core_types::{CaptureBackend, CaptureMessage, EncodeJob, Frame, VideoEncoderFactory};
+    use core_types::{
+        CaptureBackend, CaptureMessage, CaptureTarget, EncodeJob, Frame, VideoEncoderFactory,
+    };
     use encoder::h264::mmf::MediaFoundationH264EncoderFactory;
     use std::path::PathBuf;
     use std::sync::Once;
@@ -158,7 +160,9 @@ mod tests {
 
         // キャプチャを開始
         command_tx
-            .send(CaptureMessage::Start { hwnd: hwnd_raw })
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(hwnd_raw),
+            })
             .await
             .context("キャプチャ開始に失敗")?;
 
@@ -338,7 +342,9 @@ mod tests {
 
         // キャプチャを開始
         command_tx
-            .send(CaptureMessage::Start { hwnd: hwnd_raw })
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(hwnd_raw),
+            })
             .await
             .context("キャプチャ開始に失敗")?;
 
@@ -379,7 +385,12 @@ mod tests {
                         rgba: frame.data,
                         timestamp: frame.windows_timespan,
                         enqueue_at: Instant::now(),
-                        request_keyframe: false,
+                        request_keyframe: None,
+                        fps: frame.fps,
+                        format: frame.format,
+                        sequence: frame.sequence,
+                        scale_to: frame.scale_to,
+                        max_bitrate_bps: None,
                     };
 
                     job_slot.set(job);
@@ -568,7 +579,12 @@ mod tests {
                 rgba: frame.data, // clone()を削除してmove
                 timestamp: frame.windows_timespan,
                 enqueue_at: Instant::now(),
-                request_keyframe: false,
+                request_keyframe: None,
+                fps: frame.fps,
+                format: frame.format,
+                sequence: frame.sequence,
+                scale_to: frame.scale_to,
+                max_bitrate_bps: None,
             };
 
             job_slot.set(job);
@@ -629,7 +645,7 @@ mod tests {
     async fn test_capture_encode_integration_h264() -> Result<()> {
         init_tracing();
         // エンコーダーファクトリを作成（Media Foundation H.264エンコーダーを使用）
-        let encoder_factory = MediaFoundationH264EncoderFactory::new();
+        let encoder_factory = MediaFoundationH264EncoderFactory::new(false);
 
         // パイプライン化: キャプチャしながら逐次エンコード
         let capture_duration = Duration::from_secs(8);
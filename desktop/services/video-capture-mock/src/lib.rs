@@ -1,15 +1,43 @@
-use anyhow::Result;
+mod placeholder;
+
+use anyhow::{Context, Result};
 use core_types::{
     CaptureBackend, CaptureCommandReceiver, CaptureConfig, CaptureFrameSender, CaptureFuture,
-    CaptureMessage, Frame,
+    CaptureMessage, Frame, PixelFormat,
 };
 use std::time::Instant;
 #[cfg(test)]
 use tokio::sync::mpsc;
-use tracing::{debug, info};
-
-// グラデーションアニメーション設定
-const PREGENERATED_FRAMES: usize = 90; // 45fps × 2秒 (起動高速化のため削減)
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::{debug, info, warn};
+
+pub use placeholder::{placeholder_frame, PlaceholderStyle, PLACEHOLDER_FPS, PLACEHOLDER_MESSAGE};
+
+/// 事前生成するフレーム数のデフォルト（45fps × 2秒、起動高速化のため削減）
+pub const DEFAULT_PREGENERATED_FRAMES: usize = 90;
+/// UseSourceSize のときに「元画面」として使うサイズのデフォルト
+pub const DEFAULT_SOURCE_SIZE: (u32, u32) = (1280, 720);
+
+/// フレームサイズをエンコーダーが扱えるように検証する
+///
+/// エンコーダーは幅・高さを偶数に切り下げて黙って端を削るため、奇数は偶数に切り下げて警告する。
+/// 0（切り下げると 0 になる 1 も含む）はエラー。
+pub fn validate_frame_size(width: u32, height: u32) -> Result<(u32, u32)> {
+    let even = ((width / 2) * 2, (height / 2) * 2);
+    anyhow::ensure!(
+        even.0 > 0 && even.1 > 0,
+        "Mock frame size must be at least 2x2, got {}x{}",
+        width,
+        height
+    );
+    if even != (width, height) {
+        warn!(
+            "Mock frame size {}x{} has odd dimensions, rounding down to {}x{}",
+            width, height, even.0, even.1
+        );
+    }
+    Ok(even)
+}
 
 /// HSVからRGBに変換
 /// h: 色相 (0.0-360.0)
@@ -37,11 +65,101 @@ fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (u8, u8, u8) {
     )
 }
 
+/// 単色パレットパターンで順に表示する色
+const SOLID_PALETTE: [(u8, u8, u8); 6] = [
+    (200, 40, 40),
+    (40, 200, 40),
+    (40, 40, 200),
+    (200, 200, 40),
+    (40, 200, 200),
+    (200, 40, 200),
+];
+
+/// モックフレームの絵柄
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MockPattern {
+    /// 横方向の色相グラデーションがフレームごとに流れる
+    #[default]
+    Gradient,
+    /// フレーム全体を単色で塗り、一定フレームごとにパレットの次の色へ切り替える
+    SolidPalette,
+}
+
+impl std::str::FromStr for MockPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gradient" => Ok(MockPattern::Gradient),
+            "solid-palette" | "solid_palette" | "solidpalette" => Ok(MockPattern::SolidPalette),
+            other => Err(format!("unsupported mock pattern: {}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for MockPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MockPattern::Gradient => write!(f, "gradient"),
+            MockPattern::SolidPalette => write!(f, "solid-palette"),
+        }
+    }
+}
+
 /// ダミーキャプチャサービス
 pub struct CaptureService {
     frame_tx: CaptureFrameSender,
     command_rx: CaptureCommandReceiver,
     precomputed_frames: Vec<Frame>,
+    pattern: MockPattern,
+    frame_count: usize,
+    source_size: (u32, u32),
+}
+
+impl CaptureService {
+    /// フレームの絵柄を設定
+    pub fn with_pattern(mut self, pattern: MockPattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    /// 事前生成して繰り返し送るフレーム数を設定（多いほどアニメーションの周期が長いが、生成に時間とメモリを使う）
+    pub fn with_frame_count(mut self, frame_count: usize) -> Result<Self> {
+        anyhow::ensure!(frame_count > 0, "Mock frame count must be at least 1");
+        self.frame_count = frame_count;
+        Ok(self)
+    }
+
+    /// UseSourceSize のときに「元画面」として使うサイズを設定
+    pub fn with_source_size(mut self, width: u32, height: u32) -> Result<Self> {
+        self.source_size =
+            validate_frame_size(width, height).context("Invalid mock source size")?;
+        Ok(self)
+    }
+}
+
+/// 事前生成するフレームの仕様（CaptureConfig とモック固有の設定から決まる）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameSetSpec {
+    width: u32,
+    height: u32,
+    fps: u32,
+    count: usize,
+}
+
+impl FrameSetSpec {
+    fn new(config: &CaptureConfig, source_size: (u32, u32), count: usize) -> Self {
+        let (width, height) = match &config.size {
+            core_types::CaptureSize::UseSourceSize => source_size,
+            core_types::CaptureSize::Custom { width, height } => (*width, *height),
+        };
+        Self {
+            width,
+            height,
+            fps: config.fps,
+            count,
+        }
+    }
 }
 
 impl CaptureBackend for CaptureService {
@@ -51,6 +169,9 @@ impl CaptureBackend for CaptureService {
             frame_tx,
             command_rx,
             precomputed_frames: Vec::new(),
+            pattern: MockPattern::default(),
+            frame_count: DEFAULT_PREGENERATED_FRAMES,
+            source_size: DEFAULT_SOURCE_SIZE,
         }
     }
 
@@ -65,15 +186,16 @@ impl CaptureService {
 
         let mut is_capturing = false;
         let mut config = CaptureConfig::default();
+        let pattern = self.pattern;
+        let (source_size, frame_count) = (self.source_size, self.frame_count);
 
         // 初回フレーム生成（バックグラウンドで実行）
         if self.precomputed_frames.is_empty() {
             info!("Generating initial mock frames in background...");
-            let config_clone = config.clone();
-            let frames = tokio::task::spawn_blocking(move || {
-                Self::generate_frame_set(&config_clone, PREGENERATED_FRAMES)
-            })
-            .await?;
+            let spec = FrameSetSpec::new(&config, source_size, frame_count);
+            let frames =
+                tokio::task::spawn_blocking(move || Self::generate_frame_set(&spec, pattern))
+                    .await?;
             self.precomputed_frames = frames;
             info!(
                 "Initial mock frames generated ({} frames)",
@@ -84,18 +206,44 @@ impl CaptureService {
         // 事前生成済みフレームを使用
         let mut precomputed_frames = self.precomputed_frames;
         let mut frame_index: u64 = 0;
+        let mut frames_dropped: u64 = 0;
+        // 送出したフレームの通し番号（設定変更で frame_index を戻しても続けて数える）
+        let mut sequence: u64 = 0;
+        // 1tick = 1フレーム。処理が遅れた分は詰めて送らずスキップする
+        let mut frame_interval = Self::frame_interval(config.fps);
+        // 受信側が詰まっていて送れなかった最新のフレーム（空きができたら送り、より新しいフレームで置き換える）
+        let mut pending_frame: Option<Frame> = None;
         loop {
             tokio::select! {
+                // 詰まっていた間の最新のフレームを送る
+                permit = self.frame_tx.reserve(), if pending_frame.is_some() => {
+                    match permit {
+                        Ok(permit) => {
+                            if let Some(frame) = pending_frame.take() {
+                                permit.send(frame);
+                            }
+                        }
+                        Err(_) => {
+                            tracing::error!("Failed to send frame: channel closed");
+                            break;
+                        }
+                    }
+                }
                 // コマンド受信
                 msg = self.command_rx.recv() => {
                     match msg {
-                        Some(CaptureMessage::Start { hwnd }) => {
-                            info!("Start capture (mock) for HWND: {}", hwnd);
+                        Some(CaptureMessage::Start { target }) => {
+                            info!("Start capture (mock) for {:?}", target);
                             is_capturing = true;
                         }
                         Some(CaptureMessage::Stop) => {
                             info!("Stop capture (mock)");
                             is_capturing = false;
+                            pending_frame = None;
+                        }
+                        Some(CaptureMessage::Shutdown) => {
+                            info!("Shutdown requested (mock)");
+                            break;
                         }
                         Some(CaptureMessage::UpdateConfig { size, fps }) => {
                             match &size {
@@ -106,15 +254,29 @@ impl CaptureService {
                                     info!("Update config (mock): {}x{} @ {}fps", width, height, fps);
                                 }
                             }
-                            config.size = size;
+                            config.size = match size {
+                                core_types::CaptureSize::Custom { width, height } => {
+                                    match validate_frame_size(width, height) {
+                                        Ok((width, height)) => {
+                                            core_types::CaptureSize::Custom { width, height }
+                                        }
+                                        Err(e) => {
+                                            warn!("Ignoring size change (mock): {:#}", e);
+                                            config.size
+                                        }
+                                    }
+                                }
+                                size => size,
+                            };
                             config.fps = fps;
                             frame_index = 0;
+                            frame_interval = Self::frame_interval(config.fps);
                             let regen_start = Instant::now();
                             
                             // 設定変更時もバックグラウンドで再生成
-                            let config_clone = config.clone();
+                            let spec = FrameSetSpec::new(&config, source_size, frame_count);
                             let new_frames = tokio::task::spawn_blocking(move || {
-                                Self::generate_frame_set(&config_clone, PREGENERATED_FRAMES)
+                                Self::generate_frame_set(&spec, pattern)
                             }).await?;
                             precomputed_frames = new_frames;
 
@@ -124,6 +286,11 @@ impl CaptureService {
                                 regen_start.elapsed().as_millis()
                             );
                         }
+                        Some(CaptureMessage::SetCursorVisible { visible }) => {
+                            // モックはカーソルを描画しないので設定のみ保持
+                            info!("Set cursor visible (mock): {}", visible);
+                            config.show_cursor = visible;
+                        }
                         Some(CaptureMessage::RequestFrame { tx }) => {
                             info!("RequestFrame (mock)");
                              if !precomputed_frames.is_empty() {
@@ -146,7 +313,7 @@ impl CaptureService {
                     }
                 }
                 // ダミーフレーム生成
-                _ = tokio::time::sleep(tokio::time::Duration::from_millis(1000 / config.fps.max(1) as u64)) => {
+                _ = frame_interval.tick() => {
                     if is_capturing {
                         let frame_start = Instant::now();
                         if precomputed_frames.is_empty() {
@@ -160,10 +327,28 @@ impl CaptureService {
                             .unwrap();
                         frame.windows_timespan = now.as_nanos() as u64 / 100;
                         frame_index = frame_index.wrapping_add(1);
+                        sequence += 1;
+                        frame.sequence = sequence;
                         let send_start = Instant::now();
-                        if let Err(e) = self.frame_tx.send(frame).await {
-                            tracing::error!("Failed to send frame: {}", e);
-                            break;
+                        // 受信側が詰まっている場合は待たずに取っておき、空いたら送る。
+                        // 取っておいたフレームより新しいものが来たら古い方を捨てる（最新のフレームを優先）
+                        match self.frame_tx.try_send(frame) {
+                            Ok(_) => {}
+                            Err(TrySendError::Full(frame)) => {
+                                if pending_frame.replace(frame).is_some() {
+                                    frames_dropped += 1;
+                                    if frames_dropped % 100 == 1 {
+                                        tracing::warn!(
+                                            "Frame dropped (channel full), total dropped: {}",
+                                            frames_dropped
+                                        );
+                                    }
+                                }
+                            }
+                            Err(TrySendError::Closed(_)) => {
+                                tracing::error!("Failed to send frame: channel closed");
+                                break;
+                            }
                         }
                         let send_dur = send_start.elapsed();
                         let total_dur = frame_start.elapsed();
@@ -184,41 +369,41 @@ impl CaptureService {
         Ok(())
     }
 
-    fn generate_frame_set(config: &CaptureConfig, count: usize) -> Vec<Frame> {
+    fn frame_interval(fps: u32) -> tokio::time::Interval {
+        let period = tokio::time::Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        interval
+    }
+
+    fn generate_frame_set(spec: &FrameSetSpec, pattern: MockPattern) -> Vec<Frame> {
         let start = Instant::now();
-        let frames: Vec<Frame> = (0..count as u64)
-            .map(|i| Self::generate_gradient_frame(config, i))
+        let frames: Vec<Frame> = (0..spec.count as u64)
+            .map(|i| match pattern {
+                MockPattern::Gradient => Self::generate_gradient_frame(spec, i),
+                MockPattern::SolidPalette => Self::generate_solid_palette_frame(spec, i),
+            })
             .collect();
-        let (width, height) = match &config.size {
-            core_types::CaptureSize::UseSourceSize => (0, 0),
-            core_types::CaptureSize::Custom { width, height } => (*width, *height),
-        };
         info!(
-            "Pre-generated {} frames for {}x{} @{}fps in {}ms",
+            "Pre-generated {} {:?} frames for {}x{} @{}fps in {}ms",
             frames.len(),
-            width,
-            height,
-            config.fps,
+            pattern,
+            spec.width,
+            spec.height,
+            spec.fps,
             start.elapsed().as_millis()
         );
         frames
     }
 
-    fn generate_gradient_frame(config: &CaptureConfig, frame_index: u64) -> Frame {
-        let (width, height) = match &config.size {
-            core_types::CaptureSize::UseSourceSize => {
-                // mock では UseSourceSize の場合はデフォルトサイズを使用
-                (1280, 720)
-            }
-            core_types::CaptureSize::Custom { width, height } => (*width, *height),
-        };
+    fn generate_gradient_frame(spec: &FrameSetSpec, frame_index: u64) -> Frame {
+        let (width, height) = (spec.width, spec.height);
 
         let size = (width * height * 4) as usize;
         let mut data = vec![0u8; size];
 
-        // フレームごとの色相オフセット (360度 / 90フレーム = 4度/フレーム)
-        // 元は 450フレームで360度だったので 0.8度/フレーム
-        let frame_hue_offset = (frame_index as f32 / PREGENERATED_FRAMES as f32) * 360.0;
+        // フレームごとの色相オフセット（事前生成した全フレームで 360 度回る）
+        let frame_hue_offset = (frame_index as f32 / spec.count.max(1) as f32) * 360.0;
 
         for y in 0..height {
             for x in 0..width {
@@ -245,6 +430,40 @@ impl CaptureService {
                 .unwrap()
                 .as_nanos() as u64
                 / 100,
+            fps: spec.fps,
+            format: PixelFormat::Rgba8,
+            dirty_fraction: None,
+            // 通し番号は送出時に付ける
+            sequence: 0,
+            scale_to: None,
+        }
+    }
+
+    fn generate_solid_palette_frame(spec: &FrameSetSpec, frame_index: u64) -> Frame {
+        let (width, height) = (spec.width, spec.height);
+
+        // 約0.5秒ごとに次の色へ切り替える
+        let frames_per_color = (spec.fps / 2).max(1) as u64;
+        let color_index = (frame_index / frames_per_color) as usize % SOLID_PALETTE.len();
+        let (r, g, b) = SOLID_PALETTE[color_index];
+
+        let data = [r, g, b, 255].repeat((width * height) as usize);
+
+        Frame {
+            width,
+            height,
+            data: std::sync::Arc::new(data),
+            windows_timespan: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+                / 100,
+            fps: spec.fps,
+            format: PixelFormat::Rgba8,
+            dirty_fraction: None,
+            // 通し番号は送出時に付ける
+            sequence: 0,
+            scale_to: None,
         }
     }
 }
@@ -252,6 +471,7 @@ impl CaptureService {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use core_types::CaptureTarget;
 
     #[tokio::test]
     async fn test_capture_service_start_stop() {
@@ -263,7 +483,9 @@ mod tests {
 
         // キャプチャ開始
         cmd_tx
-            .send(CaptureMessage::Start { hwnd: 12345 })
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(12345),
+            })
             .await
             .unwrap();
 
@@ -285,6 +507,122 @@ mod tests {
         handle.await.unwrap().unwrap();
     }
 
+    #[tokio::test]
+    async fn test_shutdown_ends_service_while_senders_remain() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(10);
+        let (cmd_tx, cmd_rx) = mpsc::channel(10);
+
+        let service = CaptureService::new(frame_tx, cmd_rx)
+            .with_frame_count(1)
+            .unwrap()
+            .with_source_size(64, 64)
+            .unwrap();
+        let handle = tokio::spawn(async move { service.run().await });
+
+        // 他のサービスがコマンドの送信側を持ったままでも終了する
+        cmd_tx.send(CaptureMessage::Shutdown).await.unwrap();
+        tokio::time::timeout(tokio::time::Duration::from_secs(5), handle)
+            .await
+            .expect("Service should stop on Shutdown")
+            .unwrap()
+            .unwrap();
+        assert!(frame_rx.recv().await.is_none());
+        drop(cmd_tx);
+    }
+
+    #[tokio::test]
+    async fn test_capture_service_frame_rate() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(10);
+        let (cmd_tx, cmd_rx) = mpsc::channel(10);
+
+        let service = CaptureService::new(frame_tx, cmd_rx);
+        let handle = tokio::spawn(async move { service.run().await });
+
+        // 生成を軽くするため小さいサイズで 30fps に設定
+        cmd_tx
+            .send(CaptureMessage::UpdateConfig {
+                size: core_types::CaptureSize::Custom {
+                    width: 64,
+                    height: 64,
+                },
+                fps: 30,
+            })
+            .await
+            .unwrap();
+        cmd_tx
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(12345),
+            })
+            .await
+            .unwrap();
+
+        // 最初のフレームが届いてから 2 秒間のフレーム数を数える
+        let first =
+            tokio::time::timeout(tokio::time::Duration::from_secs(10), frame_rx.recv()).await;
+        assert!(matches!(first, Ok(Some(_))), "Frame should be generated");
+
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(2);
+        let mut count = 0;
+        while let Ok(Some(_)) = tokio::time::timeout_at(deadline, frame_rx.recv()).await {
+            count += 1;
+        }
+        assert!(
+            (57..=63).contains(&count),
+            "Expected about 60 frames in 2s at 30fps, got {}",
+            count
+        );
+
+        drop(cmd_tx);
+        drop(frame_rx);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_full_channel_keeps_the_newest_frame() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(1);
+        let (cmd_tx, cmd_rx) = mpsc::channel(10);
+
+        let service = CaptureService::new(frame_tx, cmd_rx);
+        let handle = tokio::spawn(async move { service.run().await });
+
+        cmd_tx
+            .send(CaptureMessage::UpdateConfig {
+                size: core_types::CaptureSize::Custom {
+                    width: 64,
+                    height: 64,
+                },
+                fps: 30,
+            })
+            .await
+            .unwrap();
+        cmd_tx
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(12345),
+            })
+            .await
+            .unwrap();
+        let first = tokio::time::timeout(tokio::time::Duration::from_secs(10), frame_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 受け取らずにいる間にチャネルが埋まる。空けると、詰まっていた間の最新のフレームが届く
+        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        let queued = frame_rx.recv().await.unwrap();
+        let newest = frame_rx.recv().await.unwrap();
+        assert_eq!(queued.sequence, first.sequence + 1);
+        assert!(
+            newest.sequence >= queued.sequence + 5,
+            "Expected the newest frame after the backlog, got #{} after #{}",
+            newest.sequence,
+            queued.sequence
+        );
+
+        drop(cmd_tx);
+        drop(frame_rx);
+        handle.await.unwrap().unwrap();
+    }
+
     #[test]
     fn test_gradient_frame_generation() {
         let config = CaptureConfig {
@@ -292,18 +630,131 @@ mod tests {
                 width: 640,
                 height: 480,
             },
+            aspect: core_types::AspectMode::Stretch,
             fps: 30,
+            max_encode_pixels: None,
+            show_cursor: true,
+            pixel_format: core_types::PixelFormat::Rgba8,
+            occlusion: core_types::OcclusionPolicy::CaptureAnyway,
+            protected_content: core_types::ProtectedContentPolicy::Notify,
+            skip_unchanged_frames: false,
+            gpu_scaling: false,
         };
 
-        let frame = CaptureService::generate_gradient_frame(&config, 0);
+        let spec = FrameSetSpec::new(&config, DEFAULT_SOURCE_SIZE, DEFAULT_PREGENERATED_FRAMES);
+        let frame = CaptureService::generate_gradient_frame(&spec, 0);
 
         assert_eq!(frame.width, 640);
         assert_eq!(frame.height, 480);
         assert_eq!(frame.data.len(), 640 * 480 * 4);
 
         // フレーム0と中間フレームで異なることを確認
-        let mid_frame = PREGENERATED_FRAMES as u64 / 2;
-        let frame2 = CaptureService::generate_gradient_frame(&config, mid_frame);
+        let mid_frame = DEFAULT_PREGENERATED_FRAMES as u64 / 2;
+        let frame2 = CaptureService::generate_gradient_frame(&spec, mid_frame);
         assert_ne!(frame.data, frame2.data);
     }
+
+    #[test]
+    fn test_solid_palette_frame_generation() {
+        let config = CaptureConfig {
+            size: core_types::CaptureSize::Custom {
+                width: 64,
+                height: 32,
+            },
+            aspect: core_types::AspectMode::Stretch,
+            fps: 30,
+            max_encode_pixels: None,
+            show_cursor: true,
+            pixel_format: core_types::PixelFormat::Rgba8,
+            occlusion: core_types::OcclusionPolicy::CaptureAnyway,
+            protected_content: core_types::ProtectedContentPolicy::Notify,
+            skip_unchanged_frames: false,
+            gpu_scaling: false,
+        };
+
+        let spec = FrameSetSpec::new(&config, DEFAULT_SOURCE_SIZE, DEFAULT_PREGENERATED_FRAMES);
+        let frame = CaptureService::generate_solid_palette_frame(&spec, 0);
+        assert_eq!(frame.width, 64);
+        assert_eq!(frame.height, 32);
+        assert_eq!(frame.data.len(), 64 * 32 * 4);
+        // 全ピクセルが同じ色
+        assert!(frame.data.chunks_exact(4).all(|px| px == &frame.data[..4]));
+
+        // 0.5秒（15フレーム）後には別の色になる
+        let next = CaptureService::generate_solid_palette_frame(&spec, 15);
+        assert_ne!(frame.data[..4], next.data[..4]);
+
+        assert_eq!(
+            "solid-palette".parse::<MockPattern>(),
+            Ok(MockPattern::SolidPalette)
+        );
+    }
+
+    #[test]
+    fn test_validate_frame_size() {
+        assert_eq!(validate_frame_size(640, 480).unwrap(), (640, 480));
+        // 奇数は偶数に切り下げる
+        assert_eq!(validate_frame_size(641, 481).unwrap(), (640, 480));
+        // 0 や切り下げて 0 になるサイズはエラー
+        assert!(validate_frame_size(0, 480).is_err());
+        assert!(validate_frame_size(640, 1).is_err());
+
+        let (frame_tx, _frame_rx) = mpsc::channel(1);
+        let (_cmd_tx, cmd_rx) = mpsc::channel(1);
+        let service = CaptureService::new(frame_tx, cmd_rx);
+        assert!(service.with_frame_count(0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_configured_source_size_and_odd_custom_size() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(10);
+        let (cmd_tx, cmd_rx) = mpsc::channel(10);
+
+        let service = CaptureService::new(frame_tx, cmd_rx)
+            .with_pattern(MockPattern::SolidPalette)
+            .with_frame_count(4)
+            .unwrap()
+            .with_source_size(65, 33)
+            .unwrap();
+        let handle = tokio::spawn(async move { service.run().await });
+
+        cmd_tx
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(12345),
+            })
+            .await
+            .unwrap();
+        let frame = tokio::time::timeout(tokio::time::Duration::from_secs(10), frame_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((frame.width, frame.height), (64, 32));
+
+        // 奇数の要求サイズも偶数に揃えてから生成する
+        cmd_tx
+            .send(CaptureMessage::UpdateConfig {
+                size: core_types::CaptureSize::Custom {
+                    width: 31,
+                    height: 17,
+                },
+                fps: 30,
+            })
+            .await
+            .unwrap();
+        let frame = loop {
+            let frame = tokio::time::timeout(tokio::time::Duration::from_secs(10), frame_rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if frame.width != 64 {
+                break frame;
+            }
+        };
+        assert_eq!((frame.width, frame.height), (30, 16));
+        assert_eq!(frame.data.len(), 30 * 16 * 4);
+
+        drop(cmd_tx);
+        drop(frame_rx);
+        handle.await.unwrap().unwrap();
+    }
 }
@@ -0,0 +1,208 @@
+// キャプチャ対象が決まるまで送るプレースホルダーのフレーム
+//
+// 起動時に HWND の指定がないと、クライアントが対象を選ぶまで映像トラックには何も流れず、
+// 視聴側は理由の分からない黒画面になる。その間はモックと同じ要領で合成したフレームを送る。
+// 文字は外部のフォントに頼らず、メッセージに使う文字だけの 5x7 ドットのビットマップを拡大して描く。
+
+use core_types::{Frame, PixelFormat};
+use std::sync::Arc;
+
+/// Text のときに表示するメッセージ
+pub const PLACEHOLDER_MESSAGE: &str = "WAITING FOR CAPTURE TARGET...";
+/// プレースホルダーのフレームレート（静止画なので低くてよい）
+pub const PLACEHOLDER_FPS: u32 = 5;
+
+/// Text の背景と文字の色
+const TEXT_BACKGROUND: (u8, u8, u8) = (24, 24, 28);
+const TEXT_FOREGROUND: (u8, u8, u8) = (220, 220, 220);
+/// 1 文字のドット数（字間の 1 ドットを含む幅）
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const GLYPH_ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+/// プレースホルダーの絵柄
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlaceholderStyle {
+    /// 暗い背景の中央に [`PLACEHOLDER_MESSAGE`] を表示する
+    #[default]
+    Text,
+    /// 単色で塗りつぶす
+    Solid { r: u8, g: u8, b: u8 },
+}
+
+impl std::str::FromStr for PlaceholderStyle {
+    type Err = String;
+
+    /// "text"、"black"、または "#RRGGBB"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "unsupported placeholder: {} (expected \"text\", \"black\" or \"#RRGGBB\")",
+                s
+            )
+        };
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(PlaceholderStyle::Text),
+            "black" => Ok(PlaceholderStyle::Solid { r: 0, g: 0, b: 0 }),
+            color => {
+                let hex = color.strip_prefix('#').ok_or_else(invalid)?;
+                if hex.len() != 6 {
+                    return Err(invalid());
+                }
+                let channel =
+                    |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+                Ok(PlaceholderStyle::Solid {
+                    r: channel(0)?,
+                    g: channel(2)?,
+                    b: channel(4)?,
+                })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for PlaceholderStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaceholderStyle::Text => write!(f, "text"),
+            PlaceholderStyle::Solid { r, g, b } => write!(f, "#{:02x}{:02x}{:02x}", r, g, b),
+        }
+    }
+}
+
+/// プレースホルダーのフレームを作る（タイムスタンプは送るときに付け直す）
+pub fn placeholder_frame(style: PlaceholderStyle, width: u32, height: u32) -> Frame {
+    let data = match style {
+        PlaceholderStyle::Solid { r, g, b } => [r, g, b, 255].repeat((width * height) as usize),
+        PlaceholderStyle::Text => text_frame(PLACEHOLDER_MESSAGE, width, height),
+    };
+    Frame {
+        width,
+        height,
+        data: Arc::new(data),
+        windows_timespan: 0,
+        fps: PLACEHOLDER_FPS,
+        format: PixelFormat::Rgba8,
+        dirty_fraction: None,
+        sequence: 0,
+        scale_to: None,
+    }
+}
+
+/// 背景の中央に `message` を描いた RGBA
+fn text_frame(message: &str, width: u32, height: u32) -> Vec<u8> {
+    let (br, bg, bb) = TEXT_BACKGROUND;
+    let mut data = [br, bg, bb, 255].repeat((width * height) as usize);
+
+    // 幅の 3/4 に収まる整数倍に拡大する
+    let chars = message.chars().count() as u32;
+    let text_width = (chars * GLYPH_ADVANCE).saturating_sub(1).max(1);
+    let scale = (width * 3 / 4 / text_width).min(height / 4 / GLYPH_HEIGHT);
+    if scale == 0 {
+        return data;
+    }
+    let left = (width - text_width * scale) / 2;
+    let top = (height - GLYPH_HEIGHT * scale) / 2;
+
+    let (fr, fg, fb) = TEXT_FOREGROUND;
+    for (index, c) in message.chars().enumerate() {
+        let glyph_left = left + index as u32 * GLYPH_ADVANCE * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    let y = top + row as u32 * scale + dy;
+                    let x = glyph_left + col * scale;
+                    let start = ((y * width + x) * 4) as usize;
+                    for pixel in data[start..start + (scale * 4) as usize].chunks_exact_mut(4) {
+                        pixel.copy_from_slice(&[fr, fg, fb, 255]);
+                    }
+                }
+            }
+        }
+    }
+    data
+}
+
+/// 5x7 ドットの字形（各行の下位 5 ビット、左端が最上位）。メッセージに使う文字だけ持つ
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'N' => [0x11, 0x19, 0x15, 0x13, 0x11, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        _ => [0; 7],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel(frame: &Frame, x: u32, y: u32) -> &[u8] {
+        let offset = ((y * frame.width + x) * 4) as usize;
+        &frame.data[offset..offset + 4]
+    }
+
+    #[test]
+    fn test_parse_placeholder_style() {
+        assert_eq!("text".parse(), Ok(PlaceholderStyle::Text));
+        assert_eq!(
+            "#1E90ff".parse(),
+            Ok(PlaceholderStyle::Solid {
+                r: 0x1E,
+                g: 0x90,
+                b: 0xFF
+            })
+        );
+        assert_eq!(
+            "black".parse::<PlaceholderStyle>().unwrap().to_string(),
+            "#000000"
+        );
+        assert!("#12345".parse::<PlaceholderStyle>().is_err());
+        assert!("#12345g".parse::<PlaceholderStyle>().is_err());
+        assert!("red".parse::<PlaceholderStyle>().is_err());
+    }
+
+    #[test]
+    fn test_text_placeholder_is_centered() {
+        let frame = placeholder_frame(PlaceholderStyle::Text, 1280, 720);
+        assert_eq!(frame.data.len(), 1280 * 720 * 4);
+        assert_eq!(frame.fps, PLACEHOLDER_FPS);
+
+        let foreground = [TEXT_FOREGROUND.0, TEXT_FOREGROUND.1, TEXT_FOREGROUND.2, 255];
+        let lit: Vec<(u32, u32)> = (0..720)
+            .flat_map(|y| (0..1280).map(move |x| (x, y)))
+            .filter(|&(x, y)| pixel(&frame, x, y) == foreground)
+            .collect();
+        assert!(!lit.is_empty());
+        // 文字は中央の帯に収まり、端は背景のまま
+        let (min_x, max_x) = (lit.iter().map(|p| p.0).min(), lit.iter().map(|p| p.0).max());
+        let (min_y, max_y) = (lit.iter().map(|p| p.1).min(), lit.iter().map(|p| p.1).max());
+        assert!(min_x.unwrap() > 100 && max_x.unwrap() < 1180);
+        assert!(min_y.unwrap() > 300 && max_y.unwrap() < 420);
+        assert_eq!(pixel(&frame, 0, 0), [24, 24, 28, 255]);
+
+        // 小さすぎて文字が入らなければ背景だけ
+        let tiny = placeholder_frame(PlaceholderStyle::Text, 16, 16);
+        assert!(tiny.data.chunks_exact(4).all(|p| p == [24, 24, 28, 255]));
+    }
+
+    #[test]
+    fn test_solid_placeholder() {
+        let frame = placeholder_frame(PlaceholderStyle::Solid { r: 1, g: 2, b: 3 }, 4, 2);
+        assert!(frame.data.chunks_exact(4).all(|p| p == [1, 2, 3, 255]));
+    }
+}
@@ -1,4 +1,4 @@
-use core_types::Frame;
+use core_types::{Frame, PixelFormat};
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use std::sync::{mpsc, Arc};
 use video_capture::resize_image_impl;
@@ -108,6 +108,11 @@ fn bench_frame_processing(c: &mut Criterion) {
                         .as_nanos() as u64
                         / 100,
                 ),
+                fps: 60,
+                format: PixelFormat::Rgba8,
+                dirty_fraction: None,
+                sequence: 0,
+                scale_to: None,
             };
             // チャンネル送信（実際には送信しないが、構造体の作成を測定）
             let _ = tx.send(black_box(frame));
@@ -130,6 +135,11 @@ fn bench_frame_processing(c: &mut Criterion) {
                         .as_nanos() as u64
                         / 100,
                 ),
+                fps: 60,
+                format: PixelFormat::Rgba8,
+                dirty_fraction: None,
+                sequence: 0,
+                scale_to: None,
             };
             let _ = tx.send(black_box(frame));
         });
@@ -151,6 +161,11 @@ fn bench_frame_processing(c: &mut Criterion) {
                         .as_nanos() as u64
                         / 100,
                 ),
+                fps: 60,
+                format: PixelFormat::Rgba8,
+                dirty_fraction: None,
+                sequence: 0,
+                scale_to: None,
             };
             let _ = tx.send(black_box(frame));
         });
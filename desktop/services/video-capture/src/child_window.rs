@@ -0,0 +1,146 @@
+// 子ウィンドウ（コントロール）のキャプチャ対象（CaptureTarget::ChildWindow）
+//
+// ゲームの描画領域をトップレベルウィンドウの中の子 HWND に置くアプリがあり、トップレベルを
+// キャプチャするとメニューやツールバーなどの外枠まで映ってしまう。親ウィンドウの子を
+// EnumChildWindows で列挙し、クラス名かタイトルで目的の子ウィンドウを探す。
+// Windows.Graphics.Capture はトップレベルウィンドウしかキャプチャできないので、子のトップレベル
+// （GetAncestor(GA_ROOT)）をキャプチャし、フレームごとに子のクライアント領域の部分を切り出す。
+// 子ウィンドウの HWND はアプリを起動し直すと変わるので、キャプチャを開始するたびに探し直す。
+
+use anyhow::Result;
+use core_types::Rect;
+use windows::core::BOOL;
+use windows::Win32::Foundation::{HWND, LPARAM, POINT, RECT};
+use windows::Win32::Graphics::Gdi::ClientToScreen;
+use windows::Win32::UI::WindowsAndMessaging::{
+    EnumChildWindows, GetAncestor, GetClassNameW, GetClientRect, GetWindowTextW, IsWindow, GA_ROOT,
+};
+
+use crate::occlusion::visible_rect;
+
+/// 子ウィンドウの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChildWindowInfo {
+    pub hwnd: u64,
+    pub class_name: String,
+    pub title: String,
+}
+
+/// 親ウィンドウの子ウィンドウを（孫以下も含めて）列挙する
+pub fn list_child_windows(parent: u64) -> Result<Vec<ChildWindowInfo>> {
+    let mut hwnds: Vec<HWND> = Vec::new();
+    unsafe {
+        // 子ウィンドウがない場合も FALSE が返るので、戻り値ではなく列挙できた数で判断する
+        let _ = EnumChildWindows(
+            Some(HWND(parent as *mut _)),
+            Some(collect_child),
+            LPARAM(&mut hwnds as *mut Vec<HWND> as isize),
+        );
+    }
+    Ok(hwnds
+        .into_iter()
+        .map(|hwnd| ChildWindowInfo {
+            hwnd: hwnd.0 as u64,
+            class_name: class_name(hwnd.0 as u64),
+            title: window_text(|buf| unsafe { GetWindowTextW(hwnd, buf) }),
+        })
+        .collect())
+}
+
+/// 親ウィンドウの子からクラス名かタイトルに一致するものを探す
+pub fn find_child_window(parent: u64, class_or_title: &str) -> Option<u64> {
+    match_child_window(&list_child_windows(parent).ok()?, class_or_title)
+}
+
+/// クラス名の完全一致、タイトルの完全一致、タイトルの部分一致（大文字小文字を区別しない）の順で探す
+fn match_child_window(children: &[ChildWindowInfo], class_or_title: &str) -> Option<u64> {
+    let needle = class_or_title.trim();
+    if needle.is_empty() {
+        return None;
+    }
+    let lower = needle.to_lowercase();
+    children
+        .iter()
+        .find(|child| child.class_name.eq_ignore_ascii_case(needle))
+        .or_else(|| children.iter().find(|child| child.title == needle))
+        .or_else(|| {
+            children
+                .iter()
+                .find(|child| child.title.to_lowercase().contains(&lower))
+        })
+        .map(|child| child.hwnd)
+}
+
+/// 子ウィンドウのトップレベルウィンドウ（キャプチャする HWND）
+pub(crate) fn root_window(child: u64) -> u64 {
+    unsafe { GetAncestor(HWND(child as *mut _), GA_ROOT).0 as u64 }
+}
+
+/// 子ウィンドウのクライアント領域の、トップレベルウィンドウのフレーム上の位置
+/// （フレームは影などの見えない枠を除いた範囲なので、その左上からの位置にする。子が閉じられたら None）
+pub(crate) fn child_rect_in_root(child: u64) -> Option<Rect> {
+    let hwnd = HWND(child as *mut _);
+    let mut client = RECT::default();
+    let mut origin = POINT::default();
+    unsafe {
+        if !IsWindow(Some(hwnd)).as_bool() {
+            return None;
+        }
+        GetClientRect(hwnd, &mut client).ok()?;
+        if !ClientToScreen(hwnd, &mut origin).as_bool() {
+            return None;
+        }
+    }
+    let root = visible_rect(HWND(root_window(child) as *mut _))?;
+    Some(Rect::new(
+        (origin.x - root.left).max(0) as u32,
+        (origin.y - root.top).max(0) as u32,
+        client.right.max(0) as u32,
+        client.bottom.max(0) as u32,
+    ))
+}
+
+/// ウィンドウのクラス名
+pub(crate) fn class_name(hwnd: u64) -> String {
+    window_text(|buf| unsafe { GetClassNameW(HWND(hwnd as *mut _), buf) })
+}
+
+unsafe extern "system" fn collect_child(hwnd: HWND, lparam: LPARAM) -> BOOL {
+    let hwnds = unsafe { &mut *(lparam.0 as *mut Vec<HWND>) };
+    hwnds.push(hwnd);
+    true.into()
+}
+
+fn window_text(read: impl FnOnce(&mut [u16]) -> i32) -> String {
+    let mut buf = [0u16; 256];
+    let len = read(&mut buf).max(0) as usize;
+    String::from_utf16_lossy(&buf[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn child(hwnd: u64, class_name: &str, title: &str) -> ChildWindowInfo {
+        ChildWindowInfo {
+            hwnd,
+            class_name: class_name.to_string(),
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_prefers_class_name_then_exact_title_then_partial_title() {
+        let children = vec![
+            child(1, "ToolbarWindow32", "Game Canvas Toolbar"),
+            child(2, "Static", "Game Canvas"),
+            child(3, "UnityWndClass", ""),
+        ];
+
+        assert_eq!(match_child_window(&children, "unitywndclass"), Some(3));
+        assert_eq!(match_child_window(&children, "Game Canvas"), Some(2));
+        assert_eq!(match_child_window(&children, "toolbar"), Some(1));
+        assert_eq!(match_child_window(&children, "Missing"), None);
+        assert_eq!(match_child_window(&children, "  "), None);
+    }
+}
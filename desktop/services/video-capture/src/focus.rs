@@ -0,0 +1,153 @@
+// キャプチャ対象のウィンドウが前面にない間のフレームレートの引き下げ（`with_background_fps`）
+//
+// ホスト側で Alt+Tab して別のウィンドウを操作している間は、ゲームの画面はほとんど動かず
+// 視聴側もフルのフレームレートを必要としない。前面のウィンドウ（GetForegroundWindow）を定期的に確認し、
+// 対象でない状態が続いたらキャプチャのフレームレートを下げ、前面に戻ったらすぐに元に戻す。
+// 一瞬だけ切り替えたときにセッションの再起動を繰り返さないよう、下げるのは少し待ってからにする。
+//
+// 品質ラダーが送ってくる UpdateConfig のフレームレートは「要求値」として保持し、下げている間は
+// その要求値を上限で抑えるだけにする（前面に戻ったときにラダーの最新の段に戻る）。
+// 下げている間は共有フラグを立て、ラダーが空いた帯域を余裕と誤解して段を上げないようにする。
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::info;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::UI::WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId};
+
+/// 前面のウィンドウを確認する間隔
+pub const FOREGROUND_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// 前面でなくなってからフレームレートを下げるまでの時間
+const BACKGROUND_GRACE: Duration = Duration::from_secs(2);
+
+/// 対象のウィンドウが前面にない間のフレームレートの上限を決める
+pub struct FocusThrottle {
+    hwnd: u64,
+    background_fps: u32,
+    /// 前面でなくなった時刻（前面にある間は None）
+    background_since: Option<Instant>,
+    /// フレームレートを下げている間は true（品質ラダーと共有する）
+    throttled: Arc<AtomicBool>,
+    /// 対象が前面にあるか（テストでは差し替える）
+    is_foreground: fn(u64) -> bool,
+}
+
+impl FocusThrottle {
+    pub fn new(hwnd: u64, background_fps: u32, throttled: Arc<AtomicBool>) -> Self {
+        throttled.store(false, Ordering::Relaxed);
+        Self {
+            hwnd,
+            background_fps: background_fps.max(1),
+            background_since: None,
+            throttled,
+            is_foreground: window_is_foreground,
+        }
+    }
+
+    /// 前面のウィンドウを確認し、フレームレートを変える必要があれば true を返す
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let foreground = (self.is_foreground)(self.hwnd);
+        self.update(foreground, now)
+    }
+
+    fn update(&mut self, foreground: bool, now: Instant) -> bool {
+        let was_throttled = self.is_throttled();
+        let throttled = if foreground {
+            self.background_since = None;
+            false
+        } else {
+            let since = *self.background_since.get_or_insert(now);
+            now.duration_since(since) >= BACKGROUND_GRACE
+        };
+        if throttled == was_throttled {
+            return false;
+        }
+        if throttled {
+            info!(
+                "Capture target is in the background, lowering capture to {} fps",
+                self.background_fps
+            );
+        } else {
+            info!("Capture target is in the foreground again, restoring capture fps");
+        }
+        self.throttled.store(throttled, Ordering::Relaxed);
+        true
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// 要求されたフレームレート `requested` に対して実際にキャプチャするフレームレート
+    pub fn effective_fps(&self, requested: u32) -> u32 {
+        if self.is_throttled() {
+            requested.min(self.background_fps)
+        } else {
+            requested
+        }
+    }
+}
+
+impl Drop for FocusThrottle {
+    /// 対象が変わったりキャプチャを止めたりしたら、下げていた状態を残さない
+    fn drop(&mut self) {
+        self.throttled.store(false, Ordering::Relaxed);
+    }
+}
+
+/// 前面のウィンドウが対象と同じプロセスか（ゲームが開いたダイアログなどが前面にある場合も含める）
+fn window_is_foreground(hwnd: u64) -> bool {
+    let target = HWND(hwnd as *mut _);
+    unsafe {
+        let foreground = GetForegroundWindow();
+        // ロック画面や UAC のプロンプト中は前面のウィンドウが取れない。対象が裏にあるとは限らないので下げない
+        if foreground.is_invalid() || foreground == target {
+            return true;
+        }
+        let (mut target_pid, mut foreground_pid) = (0u32, 0u32);
+        GetWindowThreadProcessId(target, Some(&mut target_pid));
+        GetWindowThreadProcessId(foreground, Some(&mut foreground_pid));
+        target_pid != 0 && target_pid == foreground_pid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lowers_fps_after_grace_and_restores_on_focus() {
+        let throttled = Arc::new(AtomicBool::new(false));
+        let mut throttle = FocusThrottle::new(1, 10, throttled.clone());
+        let t0 = Instant::now();
+
+        assert!(!throttle.update(true, t0));
+        assert_eq!(throttle.effective_fps(60), 60);
+
+        // 前面でなくなってもすぐには下げない（Alt+Tab で一瞬切り替えただけなら戻る）
+        assert!(!throttle.update(false, t0 + FOREGROUND_POLL_INTERVAL));
+        assert!(!throttle.update(true, t0 + BACKGROUND_GRACE));
+        assert!(!throttle.update(false, t0 + BACKGROUND_GRACE * 2));
+        assert_eq!(throttle.effective_fps(60), 60);
+
+        // 続けて前面になければ下げ、ラダーと共有するフラグを立てる
+        assert!(throttle.update(false, t0 + BACKGROUND_GRACE * 3));
+        assert!(throttled.load(Ordering::Relaxed));
+        assert_eq!(throttle.effective_fps(60), 10);
+        // 要求値が上限より低ければそのまま
+        assert_eq!(throttle.effective_fps(5), 5);
+        assert!(!throttle.update(false, t0 + BACKGROUND_GRACE * 4));
+
+        // 前面に戻ったらすぐに戻す
+        assert!(throttle.update(true, t0 + BACKGROUND_GRACE * 4));
+        assert!(!throttled.load(Ordering::Relaxed));
+        assert_eq!(throttle.effective_fps(30), 30);
+
+        // 下げたまま対象が変わってもフラグを残さない
+        assert!(!throttle.update(false, t0 + BACKGROUND_GRACE * 5));
+        assert!(throttle.update(false, t0 + BACKGROUND_GRACE * 6));
+        drop(throttle);
+        assert!(!throttled.load(Ordering::Relaxed));
+    }
+}
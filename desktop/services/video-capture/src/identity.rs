@@ -0,0 +1,110 @@
+// ウィンドウの識別情報（閉じられたウィンドウが再び現れたときに同じアプリのウィンドウを探す）
+//
+// HWND はウィンドウを作り直すと変わるので、プロセス名・タイトル・クラス名を控えておき、
+// 同じプロセスでタイトルかクラス名が一致するウィンドウを探す。タイトルはゲームの状態で変わることがあるが、
+// クラス名は変わらないことが多い。同じプロセスの別のウィンドウ（ランチャーや設定画面など）を
+// 誤って掴まないよう、プロセス名だけでの一致は呼び出し側が明示したときにだけ使う。
+
+use crate::child_window::class_name;
+use windows_capture::window::Window;
+
+/// ウィンドウの識別情報（ウィンドウ再作成時に同じアプリを探すために使う）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowIdentity {
+    pub title: String,
+    pub class_name: String,
+    pub process_name: String,
+}
+
+/// HWND からウィンドウの識別情報を取得
+pub fn window_identity(hwnd: u64) -> Option<WindowIdentity> {
+    let window = Window::from_raw_hwnd(hwnd as *mut _);
+    if !window.is_valid() {
+        return None;
+    }
+    Some(WindowIdentity {
+        title: window.title().ok()?,
+        class_name: class_name(hwnd),
+        process_name: window.process_name().ok()?,
+    })
+}
+
+/// 識別情報に一致するウィンドウを探す
+/// プロセス名が同じで、タイトルかクラス名が一致するものを返す（タイトルの一致を優先）。
+/// `match_process_only` が true なら、どちらも一致しないときにプロセス名だけで一致するものを返す
+pub fn find_window(identity: &WindowIdentity, match_process_only: bool) -> Option<u64> {
+    let candidates: Vec<(u64, WindowIdentity)> = Window::enumerate()
+        .ok()?
+        .into_iter()
+        .filter_map(|window| {
+            let hwnd = window.as_raw_hwnd() as u64;
+            Some((
+                hwnd,
+                WindowIdentity {
+                    title: window.title().unwrap_or_default(),
+                    class_name: class_name(hwnd),
+                    process_name: window.process_name().ok()?,
+                },
+            ))
+        })
+        .collect();
+    match_window(&candidates, identity, match_process_only)
+}
+
+fn match_window(
+    candidates: &[(u64, WindowIdentity)],
+    identity: &WindowIdentity,
+    match_process_only: bool,
+) -> Option<u64> {
+    let same_process = || {
+        candidates
+            .iter()
+            .filter(|(_, candidate)| candidate.process_name == identity.process_name)
+    };
+    same_process()
+        .find(|(_, candidate)| !identity.title.is_empty() && candidate.title == identity.title)
+        .or_else(|| {
+            same_process().find(|(_, candidate)| {
+                !identity.class_name.is_empty() && candidate.class_name == identity.class_name
+            })
+        })
+        .or_else(|| same_process().next().filter(|_| match_process_only))
+        .map(|(hwnd, _)| *hwnd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(title: &str, class_name: &str, process_name: &str) -> WindowIdentity {
+        WindowIdentity {
+            title: title.to_string(),
+            class_name: class_name.to_string(),
+            process_name: process_name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_requires_title_or_class_unless_process_only_is_allowed() {
+        let candidates = vec![
+            (1, identity("Launcher", "LauncherWnd", "game.exe")),
+            (2, identity("Game - Chapter 2", "GameWnd", "game.exe")),
+            (3, identity("Game - Chapter 1", "GameWnd", "other.exe")),
+        ];
+        let closed = identity("Game - Chapter 1", "GameWnd", "game.exe");
+
+        // タイトルが変わっていてもクラス名で見つける（別プロセスの同じタイトルは対象外）
+        assert_eq!(match_window(&candidates, &closed, false), Some(2));
+        let renamed = identity("Game - Chapter 2", "Other", "game.exe");
+        assert_eq!(match_window(&candidates, &renamed, false), Some(2));
+
+        // タイトルもクラス名も一致しなければ、明示したときだけプロセス名で探す
+        let unknown = identity("Settings", "SettingsWnd", "game.exe");
+        assert_eq!(match_window(&candidates, &unknown, false), None);
+        assert_eq!(match_window(&candidates, &unknown, true), Some(1));
+        assert_eq!(
+            match_window(&candidates, &identity("", "", "missing.exe"), true),
+            None
+        );
+    }
+}
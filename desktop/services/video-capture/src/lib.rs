@@ -1,27 +1,122 @@
 use anyhow::Result;
 use core_types::{
-    CaptureBackend, CaptureCommandReceiver, CaptureConfig, CaptureFrameSender, CaptureFuture,
-    CaptureMessage, Frame,
+    dirty_fraction, merge_dirty_fraction, AspectMode, CaptureBackend, CaptureCommandReceiver,
+    CaptureConfig, CaptureFrameSender, CaptureFuture, CaptureMessage, CaptureTarget, Frame,
+    FrameIntervalMonitor, OcclusionPolicy, PixelFormat, ProtectedContentPolicy, Rect, ScaleTarget,
+    ServiceError,
 };
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::Duration;
-use tracing::{debug, error, info, span, Level};
+use tracing::{debug, error, info, span, warn, Level};
+use windows::core::PCWSTR;
+use windows::Win32::Graphics::Gdi::{
+    EnumDisplayDevicesW, GetMonitorInfoW, DISPLAY_DEVICEW, HMONITOR, MONITORINFO,
+};
 use windows_capture::capture::{
     CaptureControl, Context as CaptureContext, GraphicsCaptureApiHandler,
 };
 use windows_capture::frame::Frame as WindowsFrame;
-use windows_capture::graphics_capture_api::InternalCaptureControl;
+use windows_capture::graphics_capture_api::{GraphicsCaptureApi, InternalCaptureControl};
+use windows_capture::monitor::Monitor;
 use windows_capture::settings::{
     ColorFormat, CursorCaptureSettings, DirtyRegionSettings, DrawBorderSettings,
-    MinimumUpdateIntervalSettings, SecondaryWindowSettings, Settings,
+    GraphicsCaptureItemType, MinimumUpdateIntervalSettings, SecondaryWindowSettings, Settings,
 };
 use windows_capture::window::Window;
 
-/// 実キャプチャサービス（windows-captureクレートによる HWND キャプチャ）
+mod child_window;
+mod focus;
+mod identity;
+mod occlusion;
+mod protected;
+mod timestamp;
+pub use child_window::{find_child_window, list_child_windows, ChildWindowInfo};
+use focus::{FocusThrottle, FOREGROUND_POLL_INTERVAL};
+pub use identity::{find_window, window_identity, WindowIdentity};
+use occlusion::OcclusionFilter;
+use protected::ProtectedContentDetector;
+use timestamp::MonotonicTimestamps;
+
+/// 実キャプチャサービス（windows-captureクレートによるウィンドウ・モニターキャプチャ）
 pub struct CaptureService {
     frame_tx: CaptureFrameSender,
     command_rx: CaptureCommandReceiver,
+    error_tx: Option<mpsc::UnboundedSender<ServiceError>>,
+    max_encode_pixels: Option<u32>,
+    aspect: AspectMode,
+    pixel_format: PixelFormat,
+    occlusion: OcclusionPolicy,
+    protected_content: ProtectedContentPolicy,
+    skip_unchanged_frames: bool,
+    gpu_scaling: bool,
+    /// 対象のウィンドウが前面にない間のフレームレート (上限, 下げている間に立てるフラグ)（None で下げない）
+    background_fps: Option<(u32, Arc<AtomicBool>)>,
+}
+
+impl CaptureService {
+    /// キャプチャセッション中に発生したエラー（ウィンドウが閉じられた等）の通知先を設定
+    pub fn with_error_tx(mut self, error_tx: mpsc::UnboundedSender<ServiceError>) -> Self {
+        self.error_tx = Some(error_tx);
+        self
+    }
+
+    /// エンコードに渡すフレームの最大画素数を設定（超える場合はアスペクト比を保って縮小）
+    pub fn with_max_encode_pixels(mut self, max_encode_pixels: u32) -> Self {
+        self.max_encode_pixels = Some(max_encode_pixels);
+        self
+    }
+
+    /// 要求サイズと元画面のアスペクト比が違う場合の合わせ方を設定
+    pub fn with_aspect_mode(mut self, aspect: AspectMode) -> Self {
+        self.aspect = aspect;
+        self
+    }
+
+    /// キャプチャする画素の並びを設定（BGRA ならエンコーダーの GPU 前処理で並べ替えが要らない）
+    pub fn with_pixel_format(mut self, pixel_format: PixelFormat) -> Self {
+        self.pixel_format = pixel_format;
+        self
+    }
+
+    /// キャプチャ対象のウィンドウが他のウィンドウに覆われたときの扱いを設定
+    pub fn with_occlusion_policy(mut self, occlusion: OcclusionPolicy) -> Self {
+        self.occlusion = occlusion;
+        self
+    }
+
+    /// 保護されたコンテンツで真っ黒なフレームが続いたときの扱いを設定
+    /// （Notify なら `with_error_tx` の通知先に `ServiceError::ProtectedContent` を送る）
+    pub fn with_protected_content_policy(
+        mut self,
+        protected_content: ProtectedContentPolicy,
+    ) -> Self {
+        self.protected_content = protected_content;
+        self
+    }
+
+    /// ダーティ領域を取得し、前のフレームから変化がないフレームをエンコーダーに渡さないようにする
+    /// （静止した画面での帯域を減らす。OS が対応していない場合は無効のまま）
+    pub fn with_skip_unchanged_frames(mut self, skip_unchanged_frames: bool) -> Self {
+        self.skip_unchanged_frames = skip_unchanged_frames;
+        self
+    }
+
+    /// 要求サイズへの縮小をキャプチャスレッドで行わず、元のサイズのフレームに出力サイズを付けて送る
+    /// （ハードウェアエンコーダーの前処理で縮小する場合だけ有効にする）
+    pub fn with_gpu_scaling(mut self, gpu_scaling: bool) -> Self {
+        self.gpu_scaling = gpu_scaling;
+        self
+    }
+
+    /// キャプチャ対象のウィンドウが前面にない間はフレームレートを `fps` 以下に下げ、前面に戻ったら元に戻す
+    /// 下げている間は `throttled` を立てる（品質ラダーがその間に段を動かさないようにする）
+    pub fn with_background_fps(mut self, fps: u32, throttled: Arc<AtomicBool>) -> Self {
+        self.background_fps = Some((fps, throttled));
+        self
+    }
 }
 
 impl CaptureBackend for CaptureService {
@@ -29,6 +124,15 @@ impl CaptureBackend for CaptureService {
         Self {
             frame_tx,
             command_rx,
+            error_tx: None,
+            max_encode_pixels: None,
+            aspect: AspectMode::default(),
+            pixel_format: PixelFormat::default(),
+            occlusion: OcclusionPolicy::default(),
+            protected_content: ProtectedContentPolicy::default(),
+            skip_unchanged_frames: false,
+            gpu_scaling: false,
+            background_fps: None,
         }
     }
 
@@ -37,12 +141,39 @@ impl CaptureBackend for CaptureService {
     }
 }
 
+/// キャプチャ停止を判定する間隔
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// windows-captureのハンドラ実装
 struct CaptureHandler {
     frame_tx: mpsc::Sender<Frame>,
+    /// フレーム間隔の計測（停止検知はサービス側のタイマーで行う）
+    frame_interval: Arc<Mutex<FrameIntervalMonitor>>,
     screenshot_tx: Arc<Mutex<Option<oneshot::Sender<Frame>>>>,
     last_captured_frame: Arc<Mutex<Option<Frame>>>,
+    error_tx: Option<mpsc::UnboundedSender<ServiceError>>,
     config: CaptureConfig,
+    /// キュー溢れでドロップしたフレーム数（定期的にログ出力）
+    frames_sent: u64,
+    frames_dropped: u64,
+    last_drop_log: Instant,
+    /// 最大画素数の上限で縮小したサイズ（変化したときだけログを出す）
+    capped_size: Option<(u32, u32)>,
+    /// 奇数の幅・高さを切り落とした元画面のサイズ（変化したときだけログを出す）
+    odd_source_size: Option<(u32, u32)>,
+    /// 覆われている間は最後のフレームを送る（FreezeOnOcclusion でウィンドウをキャプチャしているときだけ）
+    occlusion: Option<OcclusionFilter>,
+    /// 真っ黒なフレームが続いたら通知する（ProtectedContentPolicy::Notify のときだけ）
+    protected_content: Option<ProtectedContentDetector>,
+    /// キュー溢れで送れなかったフレームの変化（次に送るフレームに持ち越す）
+    unsent_dirty_fraction: Option<f32>,
+    /// 0 や前のフレーム以前のタイムスタンプの置き換え
+    timestamps: MonotonicTimestamps,
+    /// 最後に付けたフレームの通し番号（キュー溢れで送れなかったフレームも数える）
+    sequence: u64,
+    /// 子ウィンドウをキャプチャしているときの子の HWND と、最後に切り出した矩形（変化したときだけログを出す）
+    crop_child: Option<u64>,
+    child_rect: Option<Rect>,
 }
 
 impl GraphicsCaptureApiHandler for CaptureHandler {
@@ -53,9 +184,30 @@ impl GraphicsCaptureApiHandler for CaptureHandler {
         info!("CaptureHandler::new called");
         Ok(Self {
             frame_tx: ctx.flags.frame_tx.clone(),
+            frame_interval: ctx.flags.frame_interval.clone(),
             screenshot_tx: ctx.flags.screenshot_tx.clone(),
             last_captured_frame: ctx.flags.last_captured_frame.clone(),
+            error_tx: ctx.flags.error_tx.clone(),
             config: ctx.flags.config.clone(),
+            frames_sent: 0,
+            frames_dropped: 0,
+            last_drop_log: Instant::now(),
+            capped_size: None,
+            odd_source_size: None,
+            occlusion: match (ctx.flags.config.occlusion, ctx.flags.target_hwnd) {
+                (OcclusionPolicy::FreezeOnOcclusion, Some(hwnd)) => {
+                    Some(OcclusionFilter::new(hwnd))
+                }
+                _ => None,
+            },
+            protected_content: (ctx.flags.config.protected_content
+                == ProtectedContentPolicy::Notify)
+                .then(ProtectedContentDetector::default),
+            unsent_dirty_fraction: Some(0.0),
+            timestamps: MonotonicTimestamps::default(),
+            sequence: 0,
+            crop_child: ctx.flags.crop_child,
+            child_rect: None,
         })
     }
 
@@ -66,22 +218,109 @@ impl GraphicsCaptureApiHandler for CaptureHandler {
     ) -> Result<(), Self::Error> {
         debug!("on_frame_arrived called");
 
-        // FrameBufferを取得してRGBAデータを読み取る
+        if let Ok(mut monitor) = self.frame_interval.lock() {
+            monitor.record_at(Instant::now());
+        }
+
+        // 前のフレームから変化した領域（ダーティ領域を取得していない場合は None）
+        let dirty = if self.config.skip_unchanged_frames {
+            match frame.dirty_regions() {
+                Ok(regions) => {
+                    let regions: Vec<Rect> = regions
+                        .iter()
+                        .map(|r| {
+                            Rect::new(
+                                r.x.max(0) as u32,
+                                r.y.max(0) as u32,
+                                r.width.max(0) as u32,
+                                r.height.max(0) as u32,
+                            )
+                        })
+                        .collect();
+                    Some(dirty_fraction(frame.width(), frame.height(), &regions))
+                }
+                Err(e) => {
+                    debug!("Failed to get dirty regions: {:?}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // FrameBufferを取得して画素データを読み取る（並びは config.pixel_format）
         let frame_buffer = frame.buffer()?;
 
         // パディングなしのバッファを取得
         let mut buffer = Vec::new();
         let _ = frame_buffer.as_nopadding_buffer(&mut buffer);
 
-        let src_width = frame_buffer.width();
-        let src_height = frame_buffer.height();
+        let mut src_width = frame_buffer.width();
+        let mut src_height = frame_buffer.height();
 
-        // リサイズが必要かチェック
+        // 子ウィンドウはトップレベルウィンドウのフレームから子のクライアント領域を切り出す
+        // （子が移動・リサイズしても追従するよう、位置はフレームごとに取り直す）
+        if let Some(child) = self.crop_child {
+            let cropped = child_window::child_rect_in_root(child).and_then(|rect| {
+                if self.child_rect != Some(rect) {
+                    info!("Cropping child window {} at {:?}", child, rect);
+                    self.child_rect = Some(rect);
+                }
+                core_types::crop_rect(&buffer, src_width, src_height, rect)
+            });
+            let Some((cropped, width, height)) = cropped else {
+                debug!(
+                    "Child window {} is not visible in the frame, skipping",
+                    child
+                );
+                return Ok(());
+            };
+            buffer = cropped;
+            (src_width, src_height) = (width, height);
+        }
+
+        // 元のサイズのまま送る場合、幅か高さが奇数なら右端の列・下端の行を落として偶数にそろえる
+        // （エンコーダーに切り下げさせるとキャプチャとエンコードでサイズがずれ、端に緑の線が出ることがある）
+        if self.config.size == core_types::CaptureSize::UseSourceSize {
+            if let Some(cropped) = core_types::crop_to_even(&buffer, src_width, src_height) {
+                let even = core_types::even_size(src_width, src_height);
+                if self.odd_source_size != Some((src_width, src_height)) {
+                    info!(
+                        "Source size {}x{} has odd dimensions, cropping to {}x{}",
+                        src_width, src_height, even.0, even.1
+                    );
+                    self.odd_source_size = Some((src_width, src_height));
+                }
+                buffer = cropped;
+                (src_width, src_height) = even;
+            }
+        }
+
+        // リサイズが必要かチェック（要求サイズは UpdateConfig の受信時に偶数にそろえてある）
         let (dst_width, dst_height) = match &self.config.size {
             core_types::CaptureSize::UseSourceSize => (src_width, src_height),
             core_types::CaptureSize::Custom { width, height } => (*width, *height),
         };
 
+        // 弱いマシンを守るため、要求サイズに関わらず最大画素数で頭打ちにする
+        let (dst_width, dst_height) = match self.config.max_encode_pixels {
+            Some(max_pixels) => {
+                let capped = core_types::fit_to_max_pixels(dst_width, dst_height, max_pixels);
+                let capped_size = (capped != (dst_width, dst_height)).then_some(capped);
+                if capped_size != self.capped_size {
+                    if let Some((width, height)) = capped_size {
+                        info!(
+                            "Frame {}x{} exceeds max encode pixels ({}), scaling down to {}x{}",
+                            dst_width, dst_height, max_pixels, width, height
+                        );
+                    }
+                    self.capped_size = capped_size;
+                }
+                capped
+            }
+            None => (dst_width, dst_height),
+        };
+
         // フレーム処理全体を span で計測
         let frame_span = span!(
             Level::DEBUG,
@@ -94,28 +333,65 @@ impl GraphicsCaptureApiHandler for CaptureHandler {
         let _frame_guard = frame_span.enter();
 
         // リサイズが必要な場合
-        let final_data = if dst_width != src_width || dst_height != src_height {
-            resize_image_impl(&buffer, src_width, src_height, dst_width, dst_height)?
+        // GPU で縮小する設定なら元のサイズのまま送り、出力サイズを付けてエンコーダーの前処理に任せる
+        let needs_resize = dst_width != src_width || dst_height != src_height;
+        let scale_to = (needs_resize && self.config.gpu_scaling).then_some(ScaleTarget {
+            width: dst_width,
+            height: dst_height,
+            aspect: self.config.aspect,
+        });
+        let (final_data, frame_width, frame_height) = if needs_resize && scale_to.is_none() {
+            let resized = resize_image_with_aspect(
+                &buffer,
+                src_width,
+                src_height,
+                dst_width,
+                dst_height,
+                self.config.aspect,
+            )?;
+            (resized, dst_width, dst_height)
         } else {
-            buffer
+            (buffer, src_width, src_height)
         };
 
         // Arc化してコストなしで共有可能にする
         let final_data = Arc::new(final_data);
 
+        // 真っ黒なフレームが続いたら保護されたコンテンツとして hostd に通知する
+        if let Some(detector) = self.protected_content.as_mut() {
+            if detector.observe(&final_data, Instant::now()) {
+                if let Some(error_tx) = &self.error_tx {
+                    let _ = error_tx.send(ServiceError::ProtectedContent);
+                }
+            }
+        }
+
         // core_types::Frameに変換
         // frame.timestamp() は100ナノ秒単位の TimeSpan を返す
         // TimeSpan を Duration に変換してから、100ナノ秒単位の値を取得
         let timespan = frame.timestamp()?;
         let duration: std::time::Duration = timespan.into();
         // Duration から100ナノ秒単位の値を取得（as_nanos() はナノ秒単位なので、100で割る）
-        let windows_timespan = (duration.as_nanos() / 100) as u64;
+        // 0 や前のフレームより戻った値を返すドライバーがあるので、単調増加にそろえる
+        let windows_timespan = self
+            .timestamps
+            .next((duration.as_nanos() / 100) as u64, self.config.fps);
 
+        self.sequence += 1;
         let core_frame = Frame {
-            width: dst_width,
-            height: dst_height,
+            width: frame_width,
+            height: frame_height,
             data: final_data.clone(),
             windows_timespan,
+            fps: self.config.fps,
+            format: self.config.pixel_format,
+            dirty_fraction: merge_dirty_fraction(dirty, self.unsent_dirty_fraction),
+            sequence: self.sequence,
+            scale_to,
+        };
+        let core_frame = match self.occlusion.as_mut() {
+            Some(occlusion) => occlusion.filter(core_frame, Instant::now()),
+            None => core_frame,
         };
 
         // 最新フレームをキャッシュ（スクリーンショット用）
@@ -139,16 +415,36 @@ impl GraphicsCaptureApiHandler for CaptureHandler {
         let _send_guard = send_span.enter();
 
         // tokio::sync::mpscを使って非同期送信（try_sendで詰まってる場合はドロップ）
+        let sent_dirty_fraction = core_frame.dirty_fraction;
         match self.frame_tx.try_send(core_frame) {
-            Ok(_) => {}
+            Ok(_) => {
+                self.frames_sent += 1;
+                self.unsent_dirty_fraction = Some(0.0);
+            }
             Err(mpsc::error::TrySendError::Full(_)) => {
                 debug!("Frame dropped (channel full)");
+                self.frames_dropped += 1;
+                self.unsent_dirty_fraction = sent_dirty_fraction;
             }
             Err(mpsc::error::TrySendError::Closed(_)) => {
                 error!("Failed to send frame: channel closed");
             }
         }
 
+        if self.last_drop_log.elapsed() >= Duration::from_secs(5) {
+            if self.frames_dropped > 0 {
+                let total = self.frames_sent + self.frames_dropped;
+                info!(
+                    "Capture queue drops (last 5s): dropped={} / {} ({:.1}%)",
+                    self.frames_dropped,
+                    total,
+                    self.frames_dropped as f32 / total as f32 * 100.0
+                );
+            }
+            self.frames_sent = 0;
+            self.frames_dropped = 0;
+            self.last_drop_log = Instant::now();
+        }
 
         drop(_send_guard);
         drop(_frame_guard);
@@ -158,10 +454,187 @@ impl GraphicsCaptureApiHandler for CaptureHandler {
 
     fn on_closed(&mut self) -> Result<(), Self::Error> {
         info!("Capture session closed");
+        // 対象ウィンドウが閉じられたことを hostd に通知（再起動の判断は hostd 側で行う）
+        if let Some(error_tx) = &self.error_tx {
+            let _ = error_tx.send(ServiceError::WindowGone);
+        }
         Ok(())
     }
 }
 
+/// タイトルでウィンドウを探す
+/// 完全一致を優先し、なければタイトルに部分一致（大文字小文字を区別しない）するものを返す
+pub fn find_window_by_title(title: &str) -> Option<u64> {
+    let needle = title.to_lowercase();
+    let mut partial_match = None;
+    for window in Window::enumerate().ok()? {
+        let Ok(window_title) = window.title() else {
+            continue;
+        };
+        let hwnd = window.as_raw_hwnd() as u64;
+        if window_title == title {
+            return Some(hwnd);
+        }
+        if window_title.to_lowercase().contains(&needle) {
+            partial_match.get_or_insert(hwnd);
+        }
+    }
+    partial_match
+}
+
+/// キャプチャ可能なウィンドウの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub hwnd: u64,
+    pub title: String,
+    pub process_name: String,
+}
+
+/// 表示中のトップレベルウィンドウを列挙（タイトルのないものは除く）
+pub fn list_windows() -> Result<Vec<WindowInfo>> {
+    let windows =
+        Window::enumerate().map_err(|e| anyhow::anyhow!("Failed to enumerate windows: {:?}", e))?;
+    Ok(windows
+        .into_iter()
+        .filter_map(|window| {
+            let title = window.title().ok().filter(|title| !title.is_empty())?;
+            Some(WindowInfo {
+                hwnd: window.as_raw_hwnd() as u64,
+                title,
+                process_name: window.process_name().unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// ウィンドウを 1 フレームだけキャプチャする（サムネイル用）
+/// キャプチャセッションを一時的に立て、RequestFrame で最初のフレームを受け取ったら止める。
+/// 最小化されているなどで `timeout` までにフレームが届かなければエラーを返す
+pub async fn capture_window_frame(hwnd: u64, timeout: Duration) -> Result<Frame> {
+    let (frame_tx, _frame_rx) = mpsc::channel(1);
+    let (command_tx, command_rx) = mpsc::channel(4);
+    let service = CaptureService::new(frame_tx, command_rx).with_pixel_format(PixelFormat::Rgba8);
+    let handle = tokio::spawn(service.run());
+
+    let result = async {
+        command_tx
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(hwnd),
+            })
+            .await?;
+        let (tx, rx) = oneshot::channel();
+        command_tx.send(CaptureMessage::RequestFrame { tx }).await?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(frame)) => Ok(frame),
+            Ok(Err(_)) => Err(anyhow::anyhow!("Capture of HWND {} stopped", hwnd)),
+            Err(_) => Err(anyhow::anyhow!("Timed out capturing HWND {}", hwnd)),
+        }
+    }
+    .await;
+
+    // セッションを止めてからサービスを終わらせる
+    let _ = command_tx.send(CaptureMessage::Stop).await;
+    drop(command_tx);
+    if let Err(e) = handle.await {
+        warn!("Thumbnail capture task failed: {}", e);
+    }
+    result
+}
+
+/// キャプチャ可能なモニターの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MonitorInfo {
+    /// `CaptureTarget::Monitor` に渡すインデックス（0始まり）
+    pub index: usize,
+    pub name: String,
+    /// 仮想スクリーン座標での位置とサイズ
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+    /// 接続先のディスプレイアダプター名
+    pub adapter: String,
+    /// 仮想ディスプレイドライバー（IddCx など）のモニターか
+    pub virtual_display: bool,
+}
+
+/// モニターが接続されているディスプレイアダプターの名前と、仮想ディスプレイかどうか
+///
+/// IddCx ベースの仮想ディスプレイドライバーは ROOT\ や SWD\ で列挙されるソフトウェアデバイスなので、
+/// PCI バス上にないアダプターを仮想ディスプレイとみなす。
+fn display_adapter(device_name: &str) -> Option<(String, bool)> {
+    let utf16 = |buf: &[u16]| {
+        String::from_utf16_lossy(&buf[..buf.iter().position(|c| *c == 0).unwrap_or(buf.len())])
+    };
+    for index in 0.. {
+        let mut device = DISPLAY_DEVICEW {
+            cb: std::mem::size_of::<DISPLAY_DEVICEW>() as u32,
+            ..Default::default()
+        };
+        if !unsafe { EnumDisplayDevicesW(PCWSTR::null(), index, &mut device, 0) }.as_bool() {
+            break;
+        }
+        if utf16(&device.DeviceName).eq_ignore_ascii_case(device_name) {
+            let device_id = utf16(&device.DeviceID).to_ascii_uppercase();
+            return Some((utf16(&device.DeviceString), !device_id.starts_with("PCI\\")));
+        }
+    }
+    None
+}
+
+/// 接続されているモニターを列挙
+pub fn list_monitors() -> Result<Vec<MonitorInfo>> {
+    let monitors = Monitor::enumerate()
+        .map_err(|e| anyhow::anyhow!("Failed to enumerate monitors: {:?}", e))?;
+    let mut infos = Vec::with_capacity(monitors.len());
+    for (index, monitor) in monitors.into_iter().enumerate() {
+        let mut info = MONITORINFO {
+            cbSize: std::mem::size_of::<MONITORINFO>() as u32,
+            ..Default::default()
+        };
+        let hmonitor = HMONITOR(monitor.as_raw_hmonitor());
+        if !unsafe { GetMonitorInfoW(hmonitor, &mut info) }.as_bool() {
+            warn!("GetMonitorInfoW failed for monitor {}", index);
+            continue;
+        }
+        let rect = info.rcMonitor;
+        let (adapter, virtual_display) = monitor
+            .device_name()
+            .ok()
+            .and_then(|device_name| display_adapter(&device_name))
+            .unwrap_or_default();
+        infos.push(MonitorInfo {
+            index,
+            name: monitor
+                .name()
+                .or_else(|_| monitor.device_name())
+                .unwrap_or_default(),
+            x: rect.left,
+            y: rect.top,
+            width: (rect.right - rect.left) as u32,
+            height: (rect.bottom - rect.top) as u32,
+            adapter,
+            virtual_display,
+        });
+    }
+    Ok(infos)
+}
+
+/// 仮想ディスプレイのモニターを探す（複数あれば最初のもの）
+///
+/// 仮想ディスプレイは外部の IddCx ドライバー（Virtual Display Driver など）を
+/// インストールして有効にしておく必要がある。見つからなければエラーを返す。
+pub fn find_virtual_monitor() -> Result<MonitorInfo> {
+    list_monitors()?
+        .into_iter()
+        .find(|monitor| monitor.virtual_display)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "No virtual display found: install and enable an IddCx-based virtual display driver"
+            )
+        })
+}
+
 /// 画像リサイズ処理の実装（ベンチマーク用に公開）
 pub fn resize_image_impl(
     src_data: &[u8],
@@ -170,27 +643,42 @@ pub fn resize_image_impl(
     dst_width: u32,
     dst_height: u32,
 ) -> Result<Vec<u8>> {
-    let dst_stride = dst_width * 4;
-    let mut dst_data = vec![0u8; (dst_stride * dst_height) as usize];
-
-    for y in 0..dst_height {
-        let src_y = (y * src_height) / dst_height;
-        for x in 0..dst_width {
-            let src_x = (x * src_width) / dst_width;
-
-            let src_offset = (src_y * src_width + src_x) * 4;
-            let dst_offset = (y * dst_width + x) * 4;
-
-            if (src_offset + 4) as usize <= src_data.len()
-                && (dst_offset + 4) as usize <= dst_data.len()
-            {
-                dst_data[dst_offset as usize..(dst_offset + 4) as usize]
-                    .copy_from_slice(&src_data[src_offset as usize..(src_offset + 4) as usize]);
-            }
-        }
-    }
+    resize_image_with_aspect(
+        src_data,
+        src_width,
+        src_height,
+        dst_width,
+        dst_height,
+        AspectMode::Stretch,
+    )
+}
 
-    Ok(dst_data)
+/// アスペクト比の合わせ方を指定してリサイズする（余白は不透明な黒）
+pub fn resize_image_with_aspect(
+    src_data: &[u8],
+    src_width: u32,
+    src_height: u32,
+    dst_width: u32,
+    dst_height: u32,
+    aspect: AspectMode,
+) -> Result<Vec<u8>> {
+    // エンコーダーが GPU で縮小できないときにも同じ縮小を使うため、実装は core_types にある
+    Ok(core_types::resize_with_aspect(
+        src_data, src_width, src_height, dst_width, dst_height, aspect,
+    ))
+}
+
+/// エンコーダーは偶数のサイズしか扱えないので、奇数の要求サイズは切り下げる
+/// （起動時の設定と UpdateConfig の両方に使う）
+fn even_capture_size(size: core_types::CaptureSize) -> core_types::CaptureSize {
+    let even = size.to_even();
+    if even != size {
+        warn!(
+            "Requested capture size {:?} has odd dimensions, rounding down to {:?}",
+            size, even
+        );
+    }
+    even
 }
 
 impl CaptureService {
@@ -198,21 +686,69 @@ impl CaptureService {
         info!("CaptureService (windows-capture) started");
 
         let mut capture_control: Option<CaptureControl<CaptureHandler, anyhow::Error>> = None;
-        let mut target_hwnd: Option<u64> = None;
-        let mut config = CaptureConfig::default();
+        let mut current_target: Option<CaptureTarget> = None;
+        let mut config = CaptureConfig {
+            max_encode_pixels: self.max_encode_pixels,
+            aspect: self.aspect,
+            pixel_format: self.pixel_format,
+            occlusion: self.occlusion,
+            protected_content: self.protected_content,
+            skip_unchanged_frames: self.skip_unchanged_frames,
+            gpu_scaling: self.gpu_scaling,
+            ..Default::default()
+        };
+        config.size = even_capture_size(config.size);
         
         // スクリーンショット要求を保持する共有ステート
         let screenshot_req: Arc<Mutex<Option<oneshot::Sender<Frame>>>> = Arc::new(Mutex::new(None));
         // 最新フレームのキャッシュ（共有）
         let last_captured_frame: Arc<Mutex<Option<Frame>>> = Arc::new(Mutex::new(None));
+        // フレーム間隔の EMA（ハンドラが記録し、ここで定期的に停止を判定する）
+        let frame_interval = Arc::new(Mutex::new(FrameIntervalMonitor::new(config.fps)));
+        let mut stall_check = tokio::time::interval(STALL_CHECK_INTERVAL);
+        let mut stalled = false;
+        // 対象のウィンドウが前面にない間のフレームレートの引き下げ（ウィンドウキャプチャのみ）
+        let mut focus_throttle: Option<FocusThrottle> = None;
+        let mut focus_poll = tokio::time::interval(FOREGROUND_POLL_INTERVAL);
 
         loop {
+            let mut restart_session = false;
             tokio::select! {
+                _ = stall_check.tick() => {
+                    if capture_control.is_none() {
+                        continue;
+                    }
+                    let now = Instant::now();
+                    let (is_stalled, interval) = match frame_interval.lock() {
+                        Ok(monitor) => (monitor.is_stalled_at(now), monitor.interval_at(now)),
+                        Err(_) => continue,
+                    };
+                    if is_stalled && !stalled {
+                        let interval_ms = interval.unwrap_or_default().as_millis() as u64;
+                        warn!(
+                            "Capture stalled: average frame interval {} ms (expected {} fps)",
+                            interval_ms, Self::effective_fps(&config, focus_throttle.as_ref())
+                        );
+                        if let Some(error_tx) = &self.error_tx {
+                            let _ = error_tx.send(ServiceError::CaptureStalled { interval_ms });
+                        }
+                    } else if !is_stalled && stalled {
+                        info!("Capture resumed after stall");
+                    }
+                    stalled = is_stalled;
+                }
+                _ = focus_poll.tick() => {
+                    if capture_control.is_some() {
+                        restart_session = focus_throttle
+                            .as_mut()
+                            .is_some_and(|throttle| throttle.poll(Instant::now()));
+                    }
+                }
                 msg = self.command_rx.recv() => {
                     match msg {
-                        Some(CaptureMessage::Start { hwnd }) => {
-                            info!("Start capture for HWND: {hwnd}");
-                            target_hwnd = Some(hwnd);
+                        Some(CaptureMessage::Start { target }) => {
+                            info!("Start capture for {:?}", target);
+                            current_target = Some(target.clone());
 
                             // 既存のキャプチャを停止
                             if let Some(control) = capture_control.take() {
@@ -220,27 +756,60 @@ impl CaptureService {
                                     error!("Failed to stop previous capture: {:?}", e);
                                 }
                             }
+                            // 別の対象のフレームを返さないようキャッシュを破棄
+                            if let Ok(mut guard) = last_captured_frame.lock() {
+                                *guard = None;
+                            }
+                            focus_throttle = match (&target, &self.background_fps) {
+                                (
+                                    CaptureTarget::Window(hwnd)
+                                    | CaptureTarget::ChildWindow { parent: hwnd, .. },
+                                    Some((fps, throttled)),
+                                ) => Some(FocusThrottle::new(*hwnd, *fps, throttled.clone())),
+                                _ => None,
+                            };
 
                             // 新しいキャプチャセッションを開始
-                            match Self::start_capture(hwnd, &config, self.frame_tx.clone(), screenshot_req.clone(), last_captured_frame.clone()).await {
+                            match Self::start_capture(target, &Self::effective_config(&config, focus_throttle.as_ref()), self.frame_tx.clone(), frame_interval.clone(), screenshot_req.clone(), last_captured_frame.clone(), self.error_tx.clone()).await {
                                 Ok(control) => {
                                     capture_control = Some(control);
+                                    Self::reset_frame_interval(&frame_interval, Self::effective_fps(&config, focus_throttle.as_ref()), &mut stalled);
                                     info!("Capture started successfully");
                                 }
                                 Err(e) => {
                                     error!("Failed to start capture: {:?}", e);
+                                    // 対象ウィンドウが存在しない場合は hostd 側で判断させる
+                                    // （サービスは止めずに、次の Start で別の対象を受け付ける）
+                                    if ServiceError::from_anyhow(&e) == Some(&ServiceError::WindowGone) {
+                                        current_target = None;
+                                        focus_throttle = None;
+                                        if let Some(error_tx) = &self.error_tx {
+                                            let _ = error_tx.send(ServiceError::WindowGone);
+                                        }
+                                    }
                                 }
                             }
                         }
                         Some(CaptureMessage::Stop) => {
                             info!("Stop capture");
+                            focus_throttle = None;
                             if let Some(control) = capture_control.take() {
                                 if let Err(e) = control.stop() {
                                     error!("Failed to stop capture: {:?}", e);
                                 }
                             }
                         }
+                        Some(CaptureMessage::Shutdown) => {
+                            info!("Shutdown requested, stopping capture");
+                            if let Some(control) = capture_control.take() {
+                                if let Err(e) = control.stop() {
+                                    error!("Failed to stop capture: {:?}", e);
+                                }
+                            }
+                            break;
+                        }
                         Some(CaptureMessage::UpdateConfig { size, fps }) => {
+                            let size = even_capture_size(size);
                             match &size {
                                 core_types::CaptureSize::UseSourceSize => {
                                     info!("Update config: UseSourceSize @ {}fps", fps);
@@ -251,29 +820,12 @@ impl CaptureService {
                             }
                             config.size = size;
                             config.fps = fps.max(1);
-
-                            // キャプチャ中ならセッションを再作成
-                            if capture_control.is_some() {
-                                if let Some(hwnd_raw) = target_hwnd {
-                                    // 既存のキャプチャを停止
-                                    if let Some(control) = capture_control.take() {
-                                        if let Err(e) = control.stop() {
-                                            error!("Failed to stop capture session: {:?}", e);
-                                        }
-                                    }
-
-                                    // 新しい設定で再開
-                                    match Self::start_capture(hwnd_raw, &config, self.frame_tx.clone(), screenshot_req.clone(), last_captured_frame.clone()).await {
-                                        Ok(control) => {
-                                            capture_control = Some(control);
-                                            info!("Capture restarted with new config");
-                                        }
-                                        Err(e) => {
-                                            error!("Failed to restart capture session: {:?}", e);
-                                        }
-                                    }
-                                }
-                            }
+                            restart_session = true;
+                        }
+                        Some(CaptureMessage::SetCursorVisible { visible }) => {
+                            info!("Set cursor visible: {}", visible);
+                            restart_session = config.show_cursor != visible;
+                            config.show_cursor = visible;
                         }
                         Some(CaptureMessage::RequestFrame { tx }) => {
                             info!("RequestFrame received");
@@ -287,6 +839,10 @@ impl CaptureService {
                             if let Some(frame) = cached_frame {
                                 info!("Returning cached frame for screenshot");
                                 let _ = tx.send(frame);
+                            } else if capture_control.is_none() {
+                                // キャプチャ停止中は次のフレームが来ないので待たせずに閉じる
+                                warn!("RequestFrame received while capture is stopped");
+                                drop(tx);
                             } else {
                                 info!("No cached frame, queuing for next frame");
                                 if let Ok(mut guard) = screenshot_req.lock() {
@@ -303,6 +859,44 @@ impl CaptureService {
                     }
                 }
             }
+
+            // 設定変更時（前面・背面の切り替えを含む）、キャプチャ中ならセッションを再作成
+            if restart_session && capture_control.is_some() {
+                if let Some(target) = current_target.clone() {
+                    // 既存のキャプチャを停止
+                    if let Some(control) = capture_control.take() {
+                        if let Err(e) = control.stop() {
+                            error!("Failed to stop capture session: {:?}", e);
+                        }
+                    }
+
+                    // 新しい設定で再開
+                    match Self::start_capture(
+                        target,
+                        &Self::effective_config(&config, focus_throttle.as_ref()),
+                        self.frame_tx.clone(),
+                        frame_interval.clone(),
+                        screenshot_req.clone(),
+                        last_captured_frame.clone(),
+                        self.error_tx.clone(),
+                    )
+                    .await
+                    {
+                        Ok(control) => {
+                            capture_control = Some(control);
+                            Self::reset_frame_interval(
+                                &frame_interval,
+                                Self::effective_fps(&config, focus_throttle.as_ref()),
+                                &mut stalled,
+                            );
+                            info!("Capture restarted with new config");
+                        }
+                        Err(e) => {
+                            error!("Failed to restart capture session: {:?}", e);
+                        }
+                    }
+                }
+            }
         }
 
         // クリーンアップ
@@ -314,45 +908,188 @@ impl CaptureService {
         Ok(())
     }
 
+    /// 要求された設定に、前面にない間のフレームレートの上限を反映したもの
+    fn effective_config(
+        config: &CaptureConfig,
+        focus_throttle: Option<&FocusThrottle>,
+    ) -> CaptureConfig {
+        CaptureConfig {
+            fps: Self::effective_fps(config, focus_throttle),
+            ..config.clone()
+        }
+    }
+
+    fn effective_fps(config: &CaptureConfig, focus_throttle: Option<&FocusThrottle>) -> u32 {
+        focus_throttle.map_or(config.fps, |throttle| throttle.effective_fps(config.fps))
+    }
+
+    /// セッション開始時にフレーム間隔の計測をやり直す
+    fn reset_frame_interval(
+        frame_interval: &Mutex<FrameIntervalMonitor>,
+        fps: u32,
+        stalled: &mut bool,
+    ) {
+        if let Ok(mut monitor) = frame_interval.lock() {
+            monitor.reset(fps);
+        }
+        *stalled = false;
+    }
+
     async fn start_capture(
-        hwnd: u64,
+        target: CaptureTarget,
         config: &CaptureConfig,
         frame_tx: mpsc::Sender<Frame>,
+        frame_interval: Arc<Mutex<FrameIntervalMonitor>>,
         screenshot_tx: Arc<Mutex<Option<oneshot::Sender<Frame>>>>,
         last_captured_frame: Arc<Mutex<Option<Frame>>>,
+        error_tx: Option<mpsc::UnboundedSender<ServiceError>>,
     ) -> Result<CaptureControl<CaptureHandler, anyhow::Error>> {
-        info!("start_capture called for HWND: {hwnd}");
+        info!("start_capture called for {:?}", target);
 
+        let flags = CaptureConfigWithSender {
+            config: config.clone(),
+            frame_tx,
+            frame_interval,
+            screenshot_tx,
+            last_captured_frame,
+            error_tx,
+            target_hwnd: None,
+            crop_child: None,
+        };
+
+        let control = match target {
+            CaptureTarget::Window(hwnd) => {
+                let flags = CaptureConfigWithSender {
+                    target_hwnd: Some(hwnd),
+                    ..flags
+                };
+                Self::start_window_capture(hwnd, config, flags).await?
+            }
+            // 子ウィンドウは開始のたびに HWND を探し直し、そのトップレベルウィンドウをキャプチャして切り出す
+            CaptureTarget::ChildWindow {
+                parent,
+                class_or_title,
+            } => {
+                let child = find_child_window(parent, &class_or_title).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Child window {:?} not found under HWND {}",
+                        class_or_title,
+                        parent
+                    )
+                    .context(ServiceError::WindowGone)
+                })?;
+                let root = child_window::root_window(child);
+                info!(
+                    "Child window {:?} resolved to HWND {} (capturing top-level HWND {})",
+                    class_or_title, child, root
+                );
+                // 覆われているかは Z オーダーで判定するので、トップレベルを見る
+                let flags = CaptureConfigWithSender {
+                    target_hwnd: Some(root),
+                    crop_child: Some(child),
+                    ..flags
+                };
+                Self::start_window_capture(root, config, flags).await?
+            }
+            CaptureTarget::Monitor(_) | CaptureTarget::PrimaryMonitor => {
+                let monitor = if let CaptureTarget::Monitor(index) = target {
+                    // windows-capture のインデックスは1始まり
+                    Monitor::from_index(index + 1)
+                } else {
+                    Monitor::primary()
+                }
+                .map_err(|e| {
+                    anyhow::anyhow!("Monitor not found for {:?}: {:?}", target, e)
+                        .context(ServiceError::DeviceError(format!("{:?}", target)))
+                })?;
+                info!("Monitor resolved: {:?}", monitor.name().ok());
+                Self::start_capture_item(monitor, config, flags)
+                    .await
+                    .map_err(|err| {
+                        err.context(ServiceError::DeviceError(
+                            "graphics capture session".to_string(),
+                        ))
+                    })?
+            }
+        };
+        info!("Capture started successfully, CaptureControl returned");
+
+        Ok(control)
+    }
+
+    /// トップレベルウィンドウのキャプチャを開始
+    async fn start_window_capture(
+        hwnd: u64,
+        config: &CaptureConfig,
+        flags: CaptureConfigWithSender,
+    ) -> Result<CaptureControl<CaptureHandler, anyhow::Error>> {
         // HWNDからWindowを作成
         let window = Window::from_raw_hwnd(hwnd as *mut _);
         info!("Window created from HWND");
 
         // Windowが有効かチェック（警告のみ、デスクトップウィンドウなどは無効でも試行）
-        if !window.is_valid() {
+        let window_valid = window.is_valid();
+        if !window_valid {
             info!("Window is not valid for capture according to is_valid(), but will try anyway");
         } else {
             info!("Window is valid for capture");
         }
 
+        Self::start_capture_item(window, config, flags)
+            .await
+            .map_err(|err| {
+                if window_valid {
+                    err.context(ServiceError::DeviceError(
+                        "graphics capture session".to_string(),
+                    ))
+                } else {
+                    err.context(ServiceError::WindowGone)
+                }
+            })
+    }
+
+    /// キャプチャ対象（Window / Monitor）から Settings を作成してキャプチャを開始
+    async fn start_capture_item<T>(
+        item: T,
+        config: &CaptureConfig,
+        mut flags: CaptureConfigWithSender,
+    ) -> Result<CaptureControl<CaptureHandler, anyhow::Error>>
+    where
+        T: TryInto<GraphicsCaptureItemType> + Send + 'static,
+    {
         // FPSからミリ秒への変換
         let fps_ms = Duration::from_millis(1000 / config.fps.max(1) as u64);
         info!("FPS: {}, interval: {:?}", config.fps, fps_ms);
 
-        // Settingsを作成（Windowを直接渡す）
+        // ダーティ領域を報告させる（描画はそのまま）。対応していない OS では取得しない
+        let dirty_region = if config.skip_unchanged_frames {
+            if GraphicsCaptureApi::is_dirty_region_supported().unwrap_or(false) {
+                DirtyRegionSettings::ReportOnly
+            } else {
+                warn!("Dirty regions are not supported on this Windows build, sending every frame");
+                flags.config.skip_unchanged_frames = false;
+                DirtyRegionSettings::Default
+            }
+        } else {
+            DirtyRegionSettings::Default
+        };
+
         let settings = Settings::new(
-            window,
-            CursorCaptureSettings::Default,
+            item,
+            if config.show_cursor {
+                CursorCaptureSettings::WithCursor
+            } else {
+                CursorCaptureSettings::WithoutCursor
+            },
             DrawBorderSettings::Default,
             SecondaryWindowSettings::Default,
             MinimumUpdateIntervalSettings::Custom(fps_ms),
-            DirtyRegionSettings::Default,
-            ColorFormat::Rgba8,
-            CaptureConfigWithSender {
-                config: config.clone(),
-                frame_tx,
-                screenshot_tx,
-                last_captured_frame,
+            dirty_region,
+            match config.pixel_format {
+                PixelFormat::Rgba8 => ColorFormat::Rgba8,
+                PixelFormat::Bgra8 => ColorFormat::Bgra8,
             },
+            flags,
         );
         info!("Settings created");
 
@@ -364,11 +1101,7 @@ impl CaptureService {
                 .await
                 .map_err(|e| anyhow::anyhow!("Failed to spawn capture thread: {:?}", e))?;
 
-        let control =
-            control_result.map_err(|e| anyhow::anyhow!("Failed to start capture: {:?}", e))?;
-        info!("Capture started successfully, CaptureControl returned");
-
-        Ok(control)
+        control_result.map_err(|e| anyhow::anyhow!("Failed to start capture: {:?}", e))
     }
 }
 
@@ -377,7 +1110,13 @@ impl CaptureService {
 struct CaptureConfigWithSender {
     config: CaptureConfig,
     frame_tx: mpsc::Sender<Frame>,
+    frame_interval: Arc<Mutex<FrameIntervalMonitor>>,
     screenshot_tx: Arc<Mutex<Option<oneshot::Sender<Frame>>>>,
     last_captured_frame: Arc<Mutex<Option<Frame>>>,
+    error_tx: Option<mpsc::UnboundedSender<ServiceError>>,
+    /// キャプチャ対象のウィンドウ（モニターのときは None）
+    target_hwnd: Option<u64>,
+    /// 子ウィンドウをキャプチャしているときの子の HWND（トップレベルのフレームから切り出す）
+    crop_child: Option<u64>,
 }
 
@@ -0,0 +1,229 @@
+// キャプチャ対象のウィンドウが覆われたときのフレームの扱い（OcclusionPolicy::FreezeOnOcclusion）
+//
+// ウィンドウキャプチャでも、DWM の合成結果によっては上に重なったウィンドウの内容が映り込むことがある。
+// 覆われているかは Z オーダーで対象より手前にある可視ウィンドウの矩形との重なりで判定し、
+// 覆われている間は覆われる前の最後のフレームを送り続ける。フレームを止めずに同じ内容を送るのは、
+// 覆われている間に接続したクライアントやキーフレーム要求にも絵を返すため（同じ内容のエンコードは軽い）。
+//
+// ダーティ領域（DirtyRegionSettings）は「変わった場所」しか分からず、変化が覆ったウィンドウによるものかは
+// 区別できないので使わない。クリック透過のオーバーレイ（WS_EX_TRANSPARENT）とクローク中のウィンドウは
+// 画面に実体がないので数えない。対象が（オーナーをたどって）所有するダイアログやポップアップは
+// 対象の一部なので、手前にあっても覆っているとはみなさない。
+
+use core_types::{occluded_fraction, Frame, ScreenRect};
+use std::time::{Duration, Instant};
+use tracing::info;
+use windows::Win32::Foundation::{HWND, RECT};
+use windows::Win32::Graphics::Dwm::{
+    DwmGetWindowAttribute, DWMWA_CLOAKED, DWMWA_EXTENDED_FRAME_BOUNDS,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetWindow, GetWindowLongPtrW, GetWindowRect, IsIconic, IsWindowVisible, GWL_EXSTYLE,
+    GW_HWNDPREV, GW_OWNER, WS_EX_TRANSPARENT,
+};
+
+/// 重なりを確認する間隔（ウィンドウの列挙をフレームごとにはしない）
+const OCCLUSION_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+/// これ以上覆われていたら覆われているとみなす（ツールチップ程度の重なりは無視する）
+const OCCLUDED_MIN_FRACTION: f32 = 0.02;
+
+/// 覆われている間のフレームを、覆われる前の最後のフレームに差し替える
+pub struct OcclusionFilter {
+    hwnd: u64,
+    occluded: bool,
+    /// 覆われなくなった直後で、まだフレームを送っていないか
+    resumed: bool,
+    last_check: Option<Instant>,
+    /// 覆われていなかった最後のフレーム
+    last_clear_frame: Option<Frame>,
+    /// 対象が覆われている割合（テストでは差し替える）
+    occlusion: fn(u64) -> f32,
+}
+
+impl OcclusionFilter {
+    pub fn new(hwnd: u64) -> Self {
+        Self {
+            hwnd,
+            occluded: false,
+            resumed: false,
+            last_check: None,
+            last_clear_frame: None,
+            occlusion: window_occlusion,
+        }
+    }
+
+    /// 送るフレームを返す（覆われていれば最後のフレームにタイムスタンプだけ新しくしたもの）
+    pub fn filter(&mut self, mut frame: Frame, now: Instant) -> Frame {
+        if self
+            .last_check
+            .is_none_or(|last| now.duration_since(last) >= OCCLUSION_CHECK_INTERVAL)
+        {
+            self.last_check = Some(now);
+            let fraction = (self.occlusion)(self.hwnd);
+            let occluded = fraction >= OCCLUDED_MIN_FRACTION;
+            if occluded != self.occluded {
+                if occluded {
+                    info!(
+                        "Capture target is {:.0}% covered by other windows, freezing on the last frame",
+                        fraction * 100.0
+                    );
+                } else {
+                    info!("Capture target is no longer covered, resuming capture");
+                    self.resumed = true;
+                }
+                self.occluded = occluded;
+            }
+        }
+
+        if !self.occluded {
+            if std::mem::take(&mut self.resumed) {
+                // 送っていた止めたフレームとの差分はダーティ領域に現れない
+                frame.dirty_fraction = None;
+            }
+            self.last_clear_frame = Some(frame.clone());
+            return frame;
+        }
+        match &self.last_clear_frame {
+            // サイズが変わった場合は古いフレームを送れないので、そのまま送る
+            Some(clear) if (clear.width, clear.height) == (frame.width, frame.height) => Frame {
+                windows_timespan: frame.windows_timespan,
+                sequence: frame.sequence,
+                scale_to: frame.scale_to,
+                // 同じ内容を送り続けるので変化はない
+                dirty_fraction: frame.dirty_fraction.map(|_| 0.0),
+                ..clear.clone()
+            },
+            _ => frame,
+        }
+    }
+}
+
+/// `hwnd` が Z オーダーで手前の可視ウィンドウに覆われている割合
+fn window_occlusion(hwnd: u64) -> f32 {
+    let hwnd = HWND(hwnd as *mut _);
+    let Some(target) = visible_rect(hwnd) else {
+        return 0.0;
+    };
+    let mut occluders = Vec::new();
+    let mut above = hwnd;
+    // GetWindow は手前のウィンドウがなくなるとエラーを返す
+    while let Ok(window) = unsafe { GetWindow(above, GW_HWNDPREV) } {
+        above = window;
+        if is_on_screen(window) && !is_owned_by(window, hwnd) {
+            occluders.extend(visible_rect(window));
+        }
+    }
+    occluded_fraction(target, &occluders)
+}
+
+/// `window` のオーナーをたどると `owner` に行き着くか
+fn is_owned_by(window: HWND, owner: HWND) -> bool {
+    let mut current = window;
+    // GetWindow はオーナーがなければエラーを返す
+    while let Ok(next) = unsafe { GetWindow(current, GW_OWNER) } {
+        if next == owner {
+            return true;
+        }
+        current = next;
+    }
+    false
+}
+
+/// 画面に実際に描かれているウィンドウか
+fn is_on_screen(hwnd: HWND) -> bool {
+    unsafe {
+        if !IsWindowVisible(hwnd).as_bool() || IsIconic(hwnd).as_bool() {
+            return false;
+        }
+        if GetWindowLongPtrW(hwnd, GWL_EXSTYLE) as u32 & WS_EX_TRANSPARENT.0 != 0 {
+            return false;
+        }
+        let mut cloaked = 0u32;
+        let cloaked_ok = DwmGetWindowAttribute(
+            hwnd,
+            DWMWA_CLOAKED,
+            &mut cloaked as *mut u32 as *mut _,
+            std::mem::size_of::<u32>() as u32,
+        )
+        .is_ok();
+        !(cloaked_ok && cloaked != 0)
+    }
+}
+
+/// 影などの見えない枠を除いたウィンドウの矩形（取れなければ GetWindowRect）
+pub(crate) fn visible_rect(hwnd: HWND) -> Option<ScreenRect> {
+    let mut rect = RECT::default();
+    unsafe {
+        let bounds = DwmGetWindowAttribute(
+            hwnd,
+            DWMWA_EXTENDED_FRAME_BOUNDS,
+            &mut rect as *mut RECT as *mut _,
+            std::mem::size_of::<RECT>() as u32,
+        );
+        if bounds.is_err() {
+            GetWindowRect(hwnd, &mut rect).ok()?;
+        }
+    }
+    Some(ScreenRect {
+        left: rect.left,
+        top: rect.top,
+        right: rect.right,
+        bottom: rect.bottom,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_types::PixelFormat;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    static OCCLUSION: AtomicU32 = AtomicU32::new(0);
+
+    fn fake_occlusion(_hwnd: u64) -> f32 {
+        f32::from_bits(OCCLUSION.load(Ordering::Relaxed))
+    }
+
+    fn frame(value: u8, timestamp: u64) -> Frame {
+        Frame {
+            width: 2,
+            height: 2,
+            data: Arc::new(vec![value; 16]),
+            windows_timespan: timestamp,
+            fps: 30,
+            format: PixelFormat::Bgra8,
+            dirty_fraction: None,
+            sequence: 0,
+            scale_to: None,
+        }
+    }
+
+    #[test]
+    fn test_freezes_on_last_clear_frame_while_covered() {
+        let mut filter = OcclusionFilter::new(1);
+        filter.occlusion = fake_occlusion;
+        let t0 = Instant::now();
+
+        OCCLUSION.store(0.0f32.to_bits(), Ordering::Relaxed);
+        assert_eq!(filter.filter(frame(1, 10), t0).data[0], 1);
+
+        // 覆われたら最後のフレームの内容を新しいタイムスタンプで送る
+        OCCLUSION.store(0.5f32.to_bits(), Ordering::Relaxed);
+        let t1 = t0 + OCCLUSION_CHECK_INTERVAL;
+        let sent = filter.filter(frame(2, 20), t1);
+        assert_eq!((sent.data[0], sent.windows_timespan), (1, 20));
+        // 確認の間隔の間は前の判定のまま
+        OCCLUSION.store(0.0f32.to_bits(), Ordering::Relaxed);
+        assert_eq!(filter.filter(frame(3, 30), t1).data[0], 1);
+
+        // 覆われなくなったら戻る
+        let t2 = t1 + OCCLUSION_CHECK_INTERVAL;
+        assert_eq!(filter.filter(frame(4, 40), t2).data[0], 4);
+
+        // ツールチップ程度の重なりは無視する
+        OCCLUSION.store(0.01f32.to_bits(), Ordering::Relaxed);
+        let t3 = t2 + OCCLUSION_CHECK_INTERVAL;
+        assert_eq!(filter.filter(frame(5, 50), t3).data[0], 5);
+    }
+}
@@ -0,0 +1,86 @@
+// 保護されたコンテンツ（DRM）の検出（ProtectedContentPolicy::Notify）
+//
+// 動画配信サービスのプレーヤーや SetWindowDisplayAffinity を設定したアプリは、キャプチャすると
+// 真っ黒なフレームになる。Windows.Graphics.Capture からは保護されているかを問い合わせられないので、
+// 真っ黒なフレームがしばらく続いたことをもって保護されたコンテンツとみなし、一度だけ通知する。
+// ロード画面や暗転でも真っ黒になるため、短い暗転では通知しない。
+
+use core_types::is_black_frame;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// フレームが黒いかを確認する間隔（フレームごとには画素を調べない）
+const BLACK_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// 真っ黒なフレームがこれだけ続いたら保護されたコンテンツとみなす
+const PROTECTED_AFTER: Duration = Duration::from_secs(10);
+
+/// 真っ黒なフレームが続いていることを検出する
+#[derive(Default)]
+pub struct ProtectedContentDetector {
+    /// 真っ黒なフレームが続いている最初の時刻（黒くなければ None）
+    black_since: Option<Instant>,
+    /// 今回の真っ黒な区間をすでに通知したか
+    reported: bool,
+    last_check: Option<Instant>,
+}
+
+impl ProtectedContentDetector {
+    /// フレームを確認し、保護されたコンテンツとみなした時点で一度だけ true を返す
+    pub fn observe(&mut self, data: &[u8], now: Instant) -> bool {
+        if self
+            .last_check
+            .is_some_and(|last| now.duration_since(last) < BLACK_CHECK_INTERVAL)
+        {
+            return false;
+        }
+        self.last_check = Some(now);
+        self.update(is_black_frame(data), now)
+    }
+
+    fn update(&mut self, black: bool, now: Instant) -> bool {
+        if !black {
+            if self.reported {
+                info!("Captured frames are no longer black");
+            }
+            self.black_since = None;
+            self.reported = false;
+            return false;
+        }
+        let since = *self.black_since.get_or_insert(now);
+        if self.reported || now.duration_since(since) < PROTECTED_AFTER {
+            return false;
+        }
+        warn!(
+            "Captured frames have been black for {:?}, the content is likely protected (DRM)",
+            now.duration_since(since)
+        );
+        self.reported = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_once_after_black_persists() {
+        let mut detector = ProtectedContentDetector::default();
+        let t0 = Instant::now();
+
+        // 短い暗転では通知しない
+        assert!(!detector.update(true, t0));
+        assert!(!detector.update(false, t0 + Duration::from_secs(3)));
+        assert!(!detector.update(true, t0 + Duration::from_secs(4)));
+        assert!(!detector.update(true, t0 + Duration::from_secs(13)));
+
+        // 続いたら一度だけ通知する
+        assert!(detector.update(true, t0 + Duration::from_secs(14)));
+        assert!(!detector.update(true, t0 + Duration::from_secs(30)));
+
+        // 描画が戻ってから再び黒くなったら改めて通知する
+        assert!(!detector.update(false, t0 + Duration::from_secs(31)));
+        assert!(!detector.update(true, t0 + Duration::from_secs(32)));
+        assert!(detector.update(true, t0 + Duration::from_secs(42)));
+    }
+}
@@ -0,0 +1,93 @@
+// キャプチャのタイムスタンプを単調増加にそろえる
+//
+// フレームの `windows_timespan` は windows-capture が返す SystemRelativeTime（100ns 単位）だが、
+// ドライバーによっては最初の数フレームが 0 だったり、前のフレームより戻ったりする。
+// そのまま流すと A/V 同期やキャプチャ時刻の SEI の計算が壊れるので、0 や前のフレーム以前の値は
+// 前のフレームから設定のフレームレート 1 フレーム分だけ進めた値に置き換える。
+// 置き換えが続いている間も、実際の値が追い越せばそちらに戻る。
+
+use core_types::LogThrottle;
+use tracing::warn;
+
+/// 100ns 単位の 1 秒
+const TIMESPAN_PER_SECOND: u64 = 10_000_000;
+
+/// 前のフレームのタイムスタンプを覚えておき、戻ったものを置き換える
+#[derive(Debug, Default)]
+pub struct MonotonicTimestamps {
+    last: Option<u64>,
+    anomaly_log: LogThrottle,
+}
+
+impl MonotonicTimestamps {
+    /// キャプチャのタイムスタンプ `raw` を、前のフレームより後ろの値にして返す
+    pub fn next(&mut self, raw: u64, fps: u32) -> u64 {
+        let timestamp = match self.last {
+            Some(last) if raw == 0 || raw <= last => {
+                let synthesized = last + TIMESPAN_PER_SECOND / fps.max(1) as u64;
+                if let Some(suppressed) = self.anomaly_log.check() {
+                    warn!(
+                        "Capture timestamp {} is zero or not after the previous frame ({}), using {} ({} similar suppressed)",
+                        raw, last, synthesized, suppressed
+                    );
+                }
+                synthesized
+            }
+            // 最初のフレームが 0 ならそこをタイムラインの始まりにする
+            None if raw == 0 => {
+                if let Some(suppressed) = self.anomaly_log.check() {
+                    warn!(
+                        "First capture timestamp is zero, starting the timeline at 0 ({} similar suppressed)",
+                        suppressed
+                    );
+                }
+                0
+            }
+            _ => raw,
+        };
+        self.last = Some(timestamp);
+        timestamp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_monotonic_timestamps_become_monotonic() {
+        let mut timestamps = MonotonicTimestamps::default();
+        let frame = TIMESPAN_PER_SECOND / 60;
+        // 最初の 2 フレームが 0、途中で戻ったり 0 になったりし、その後は実際の値に戻る
+        let raw = [
+            0,
+            0,
+            5_000_000,
+            5_000_000 + frame,
+            5_000_000,
+            0,
+            5_000_000 + frame * 2,
+            5_000_000 + frame * 5,
+        ];
+        let output: Vec<u64> = raw.iter().map(|&t| timestamps.next(t, 60)).collect();
+
+        assert!(
+            output.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            output
+        );
+        assert_eq!(output[..3], [0, frame, 5_000_000]);
+        // 戻った分は 1 フレームずつ進めて埋める
+        assert_eq!(
+            output[3..7],
+            [
+                5_000_000 + frame,
+                5_000_000 + frame * 2,
+                5_000_000 + frame * 3,
+                5_000_000 + frame * 4,
+            ]
+        );
+        // 実際の値が追い越したらそちらを使う
+        assert_eq!(output[7], 5_000_000 + frame * 5);
+    }
+}
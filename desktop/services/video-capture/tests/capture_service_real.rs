@@ -3,7 +3,7 @@
 mod tests {
     use anyhow::{Context, Result};
     use video_capture::CaptureService;
-    use core_types::{CaptureBackend, CaptureMessage};
+    use core_types::{CaptureBackend, CaptureMessage, CaptureTarget};
     use std::path::PathBuf;
     use std::time::Duration;
     use tokio::sync::mpsc;
@@ -95,7 +95,9 @@ mod tests {
 
         // キャプチャを開始
         command_tx
-            .send(CaptureMessage::Start { hwnd: hwnd_raw })
+            .send(CaptureMessage::Start {
+                target: CaptureTarget::Window(hwnd_raw),
+            })
             .await
             .unwrap();
 
@@ -0,0 +1,88 @@
+use core_types::AspectMode;
+use video_capture::resize_image_with_aspect;
+
+const RED: [u8; 4] = [255, 0, 0, 255];
+const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+/// 左半分が赤、右半分が青の RGBA 画像
+fn create_split_image(width: u32, height: u32) -> Vec<u8> {
+    let mut image = Vec::with_capacity((width * height * 4) as usize);
+    for _ in 0..height {
+        for x in 0..width {
+            if x < width / 2 {
+                image.extend_from_slice(&RED);
+            } else {
+                image.extend_from_slice(&[0, 0, 255, 255]);
+            }
+        }
+    }
+    image
+}
+
+fn pixel(data: &[u8], width: u32, x: u32, y: u32) -> [u8; 4] {
+    let offset = ((y * width + x) * 4) as usize;
+    data[offset..offset + 4].try_into().unwrap()
+}
+
+#[test]
+fn test_letterbox_pads_with_black() {
+    // 16:9 → 4:3 は上下 60px ずつ余白
+    let src = create_split_image(1920, 1080);
+    let dst = resize_image_with_aspect(&src, 1920, 1080, 640, 480, AspectMode::Letterbox).unwrap();
+    assert_eq!(dst.len(), 640 * 480 * 4);
+
+    for x in [0, 320, 639] {
+        assert_eq!(pixel(&dst, 640, x, 0), BLACK);
+        assert_eq!(pixel(&dst, 640, x, 59), BLACK);
+        assert_eq!(pixel(&dst, 640, x, 420), BLACK);
+        assert_eq!(pixel(&dst, 640, x, 479), BLACK);
+    }
+    assert_eq!(pixel(&dst, 640, 0, 60), RED);
+    assert_eq!(pixel(&dst, 640, 0, 419), RED);
+    assert_eq!(pixel(&dst, 640, 639, 240), [0, 0, 255, 255]);
+}
+
+#[test]
+fn test_pillarbox_pads_with_black() {
+    // 4:3 → 16:9 は左右 160px ずつ余白
+    let src = create_split_image(640, 480);
+    let dst = resize_image_with_aspect(&src, 640, 480, 1280, 720, AspectMode::Letterbox).unwrap();
+    assert_eq!(dst.len(), 1280 * 720 * 4);
+
+    assert_eq!(pixel(&dst, 1280, 0, 360), BLACK);
+    assert_eq!(pixel(&dst, 1280, 159, 360), BLACK);
+    assert_eq!(pixel(&dst, 1280, 160, 360), RED);
+    assert_eq!(pixel(&dst, 1280, 1119, 360), [0, 0, 255, 255]);
+    assert_eq!(pixel(&dst, 1280, 1120, 360), BLACK);
+}
+
+#[test]
+fn test_crop_trims_edges() {
+    // 16:9 → 1:1 は左右を切り落とす。中央 1080px のうち左半分が赤
+    let mut src = create_split_image(1920, 1080);
+    // 切り落とされる左端の列だけ緑にしておく
+    for y in 0..1080 {
+        let offset = (y * 1920 * 4) as usize;
+        src[offset..offset + 4].copy_from_slice(&[0, 255, 0, 255]);
+    }
+    let dst = resize_image_with_aspect(&src, 1920, 1080, 540, 540, AspectMode::Crop).unwrap();
+    assert_eq!(dst.len(), 540 * 540 * 4);
+
+    // 余白も切り落とした左端の列も出てこない
+    for (i, chunk) in dst.chunks_exact(4).enumerate() {
+        assert_ne!(chunk, BLACK, "pixel {} is padding", i);
+        assert_ne!(chunk, [0, 255, 0, 255], "pixel {} should be cropped", i);
+    }
+    assert_eq!(pixel(&dst, 540, 0, 0), RED);
+    assert_eq!(pixel(&dst, 540, 269, 539), RED);
+    assert_eq!(pixel(&dst, 540, 270, 0), [0, 0, 255, 255]);
+}
+
+#[test]
+fn test_stretch_fills_output() {
+    let src = create_split_image(1920, 1080);
+    let dst = resize_image_with_aspect(&src, 1920, 1080, 640, 480, AspectMode::Stretch).unwrap();
+    assert_eq!(dst.len(), 640 * 480 * 4);
+    assert_eq!(pixel(&dst, 640, 0, 0), RED);
+    assert_eq!(pixel(&dst, 640, 639, 479), [0, 0, 255, 255]);
+}
@@ -0,0 +1,181 @@
+// abs-capture-time RTP ヘッダー拡張（キャプチャ時刻）
+//
+// クライアントが glass-to-glass の遅延を測れるよう、各サンプルにキャプチャ時刻を送信側の壁時計
+// （NTP 形式 UQ32.32）で載せる。windows_timespan は QPC 基準の単調時刻なので、フレームルーターが
+// フレームを受け取った時点の壁時計との差をオフセットとして記録して変換する。
+// 受け取りまでの遅延が最も小さいフレームの差が真の値に近いため区間ごとの最小値を使い、
+// 区間を締めるたびに取り直して QPC と壁時計のずれの蓄積にも追従する。
+//
+// 拡張はパケットごとに 8 バイト増える。ネゴシエートされていなければ webrtc-rs が載せない。
+
+use core_types::ABS_CAPTURE_TIME_URI;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use webrtc_rs::rtp::extension::HeaderExtension;
+use webrtc_rs::util::{Marshal, MarshalSize};
+
+/// オフセットの最小値を取り直す間隔
+const OFFSET_WINDOW: Duration = Duration::from_secs(10);
+/// 1900 年（NTP）から 1970 年（UNIX）までの秒数
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+const HNS_PER_SEC: u64 = 10_000_000;
+/// オフセット未確定
+const UNKNOWN_OFFSET: i64 = i64::MIN;
+
+/// windows_timespan（100ナノ秒単位）から壁時計への変換
+/// フレームルーターが `observe` し、トラックへの書き込み時に `to_ntp` で変換する（遅れたフレームの判定にも使う）
+#[derive(Debug)]
+pub struct CaptureClock {
+    /// 壁時計（UNIX 時刻、100ナノ秒単位）- windows_timespan
+    offset_hns: AtomicI64,
+    /// 集計中の区間（最小オフセット, 区間の開始時刻）
+    window: Mutex<Option<(i64, Instant)>>,
+}
+
+impl Default for CaptureClock {
+    fn default() -> Self {
+        Self {
+            offset_hns: AtomicI64::new(UNKNOWN_OFFSET),
+            window: Mutex::new(None),
+        }
+    }
+}
+
+impl CaptureClock {
+    /// キャプチャ時刻 `timestamp` のフレームを壁時計 `now` に受け取った
+    pub fn observe(&self, timestamp: u64, now: SystemTime, at: Instant) {
+        let Ok(since_epoch) = now.duration_since(UNIX_EPOCH) else {
+            return;
+        };
+        let wall_hns = (since_epoch.as_nanos() / 100) as i64;
+        let offset = wall_hns.saturating_sub(timestamp as i64);
+
+        let current = self.offset_hns.load(Ordering::Relaxed);
+        if current == UNKNOWN_OFFSET || offset < current {
+            self.offset_hns.store(offset, Ordering::Relaxed);
+        }
+
+        let mut window = self.window.lock().unwrap();
+        match window.as_mut() {
+            Some((min_offset, started)) if at.duration_since(*started) < OFFSET_WINDOW => {
+                *min_offset = (*min_offset).min(offset);
+            }
+            Some((min_offset, _)) => {
+                // 区間を締めて、この区間の最小値に置き換える（ずれが進んだ場合はここで追従する）
+                self.offset_hns
+                    .store((*min_offset).min(offset), Ordering::Relaxed);
+                *window = Some((offset, at));
+            }
+            None => *window = Some((offset, at)),
+        }
+    }
+
+    /// キャプチャ時刻 `timestamp` から壁時計 `now` までの経過時間（まだフレームを受け取っていなければ None）
+    pub fn age(&self, timestamp: u64, now: SystemTime) -> Option<Duration> {
+        let offset = self.offset_hns.load(Ordering::Relaxed);
+        if offset == UNKNOWN_OFFSET {
+            return None;
+        }
+        let now_hns = (now.duration_since(UNIX_EPOCH).ok()?.as_nanos() / 100) as i64;
+        let captured_hns = (timestamp as i64).saturating_add(offset);
+        let age_hns = now_hns.saturating_sub(captured_hns).max(0) as u64;
+        Some(Duration::from_nanos(age_hns * 100))
+    }
+
+    /// キャプチャ時刻を NTP 形式（UQ32.32）に変換する（まだフレームを受け取っていなければ None）
+    pub fn to_ntp(&self, timestamp: u64) -> Option<u64> {
+        let offset = self.offset_hns.load(Ordering::Relaxed);
+        if offset == UNKNOWN_OFFSET {
+            return None;
+        }
+        let wall_hns = u64::try_from((timestamp as i64).saturating_add(offset)).ok()?;
+        let secs = wall_hns / HNS_PER_SEC + NTP_UNIX_OFFSET_SECS;
+        let frac = ((wall_hns % HNS_PER_SEC) << 32) / HNS_PER_SEC;
+        Some((secs << 32) | frac)
+    }
+}
+
+/// 拡張のペイロード（推定キャプチャクロックオフセットは省略する短い形式）
+#[derive(Debug, Clone, Copy)]
+struct AbsCaptureTime {
+    ntp_timestamp: u64,
+}
+
+impl MarshalSize for AbsCaptureTime {
+    fn marshal_size(&self) -> usize {
+        8
+    }
+}
+
+impl Marshal for AbsCaptureTime {
+    fn marshal_to(&self, buf: &mut [u8]) -> Result<usize, webrtc_rs::util::Error> {
+        if buf.len() < 8 {
+            return Err(webrtc_rs::util::Error::ErrBufferShort);
+        }
+        buf[..8].copy_from_slice(&self.ntp_timestamp.to_be_bytes());
+        Ok(8)
+    }
+}
+
+/// NTP 形式のキャプチャ時刻から RTP ヘッダー拡張を作る
+pub fn header_extension(ntp_timestamp: u64) -> HeaderExtension {
+    HeaderExtension::Custom {
+        uri: ABS_CAPTURE_TIME_URI.into(),
+        extension: Box::new(AbsCaptureTime { ntp_timestamp }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_converts_capture_timestamp_to_ntp() {
+        let clock = CaptureClock::default();
+        assert_eq!(clock.to_ntp(0), None);
+
+        // UNIX 時刻 1_000_000.5 秒に windows_timespan 20_000_000（2 秒）のフレームを受け取った
+        let start = Instant::now();
+        let now = UNIX_EPOCH + Duration::from_millis(1_000_000_500);
+        clock.observe(20_000_000, now, start);
+
+        // 1 秒前にキャプチャしたフレームは UNIX 時刻 999_999.5 秒
+        let ntp = clock.to_ntp(10_000_000).unwrap();
+        assert_eq!(ntp >> 32, 999_999 + NTP_UNIX_OFFSET_SECS);
+        assert_eq!(ntp & 0xFFFF_FFFF, 1 << 31);
+
+        // 0.25 秒後に見ると 1.25 秒経っている
+        let later = now + Duration::from_millis(250);
+        assert_eq!(
+            clock.age(10_000_000, later),
+            Some(Duration::from_millis(1_250))
+        );
+
+        let mut buf = [0u8; 8];
+        let extension = AbsCaptureTime { ntp_timestamp: ntp };
+        assert_eq!(extension.marshal_to(&mut buf).unwrap(), 8);
+        assert_eq!(u64::from_be_bytes(buf), ntp);
+    }
+
+    #[test]
+    fn test_offset_uses_smallest_delay_and_follows_drift() {
+        let clock = CaptureClock::default();
+        let start = Instant::now();
+        let wall = |ms: u64| UNIX_EPOCH + Duration::from_millis(ms);
+
+        // 受け取りが遅れたフレームより、遅延の小さいフレームのオフセットを使う
+        clock.observe(0, wall(1_005), start);
+        clock.observe(10_000_000, wall(2_001), start + Duration::from_secs(1));
+        clock.observe(20_000_000, wall(3_004), start + Duration::from_secs(2));
+        let offset = clock.offset_hns.load(Ordering::Relaxed);
+        assert_eq!(offset, 1_001 * 10_000);
+
+        // 壁時計が 50ms 進んだ後は、区間を締めた時点でその区間の最小値に追従する
+        clock.observe(100_000_000, wall(11_052), start + Duration::from_secs(10));
+        clock.observe(110_000_000, wall(12_051), start + Duration::from_secs(11));
+        clock.observe(200_000_000, wall(21_053), start + Duration::from_secs(20));
+        let offset = clock.offset_hns.load(Ordering::Relaxed);
+        assert_eq!(offset, 1_051 * 10_000);
+    }
+}
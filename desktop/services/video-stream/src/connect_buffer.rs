@@ -0,0 +1,152 @@
+// 接続確立中（ICE/DTLS 完了前）のエンコード結果の保持
+//
+// connection_ready が true になるまでのエンコード結果を捨てると、最初に映るのが次のキーフレームまで遅れる。
+// 直近のキーフレームとそれ以降の差分フレームを保持しておき、接続が完了したらまとめて書き込む。
+// 接続が完了しないまま溜まり続けないよう、保持する長さとバイト数に上限を設ける。
+
+use crate::track_writer::KeyframeGate;
+use core_types::EncodeResult;
+use std::time::Duration;
+
+/// 保持する長さ（デフォルト）
+pub const DEFAULT_CONNECT_BUFFER_DURATION: Duration = Duration::from_secs(2);
+/// 保持するバイト数の上限
+const MAX_BUFFERED_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct ConnectBuffer {
+    max_duration: Duration,
+    samples: Vec<EncodeResult>,
+    duration: Duration,
+    bytes: usize,
+}
+
+impl ConnectBuffer {
+    pub fn new(max_duration: Duration) -> Self {
+        Self {
+            max_duration,
+            samples: Vec::new(),
+            duration: Duration::ZERO,
+            bytes: 0,
+        }
+    }
+
+    /// エンコード結果を保持する
+    /// キーフレームが来たらそれ以前は捨て、キーフレームの無い差分フレームは保持しない
+    /// 上限を超えたら次のキーフレームまで何も保持しない
+    pub fn push(&mut self, result: EncodeResult) {
+        if result.is_keyframe {
+            self.clear();
+        } else if self.samples.is_empty() {
+            return;
+        }
+        if self.duration + result.duration > self.max_duration
+            || self.bytes + result.sample_data.len() > MAX_BUFFERED_BYTES
+        {
+            self.clear();
+            return;
+        }
+        self.duration += result.duration;
+        self.bytes += result.sample_data.len();
+        self.samples.push(result);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// 保持しているエンコード結果を取り出す（先頭はキーフレーム）
+    pub fn take(&mut self) -> Vec<EncodeResult> {
+        self.duration = Duration::ZERO;
+        self.bytes = 0;
+        std::mem::take(&mut self.samples)
+    }
+
+    /// 保持しているエンコード結果のうち、`gate` を通るものを取り出す
+    /// （先頭のキーフレームで新しいトラックの待ちが終わり、以降の差分フレームも書き込めるようになる）
+    pub fn take_admitted(&mut self, gate: &mut KeyframeGate) -> Vec<EncodeResult> {
+        self.take()
+            .into_iter()
+            .filter(|sample| gate.admit(sample.is_keyframe))
+            .collect()
+    }
+
+    fn clear(&mut self) {
+        self.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(is_keyframe: bool, bytes: usize) -> EncodeResult {
+        EncodeResult {
+            sample_data: vec![0; bytes],
+            is_keyframe,
+            keyframe_reason: None,
+            duration: Duration::from_millis(100),
+            width: 2,
+            height: 2,
+            capture_timestamp: 0,
+            average_qp: None,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_keeps_latest_keyframe_and_following_deltas() {
+        let mut buffer = ConnectBuffer::new(Duration::from_secs(1));
+        // キーフレームより前の差分フレームは復号できないので保持しない
+        buffer.push(result(false, 10));
+        assert!(buffer.is_empty());
+
+        buffer.push(result(true, 100));
+        buffer.push(result(false, 10));
+        buffer.push(result(true, 200));
+        buffer.push(result(false, 20));
+        buffer.push(result(false, 30));
+
+        let samples = buffer.take();
+        let sizes: Vec<usize> = samples.iter().map(|s| s.sample_data.len()).collect();
+        assert_eq!(sizes, vec![200, 20, 30]);
+        assert!(samples[0].is_keyframe);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_flush_ends_the_new_tracks_keyframe_wait() {
+        // トラックが付いた直後（最初のキーフレームを待っている）に、接続完了で保持分を書き込む
+        let mut gate = KeyframeGate::new(true);
+        let mut buffer = ConnectBuffer::new(Duration::from_secs(1));
+        buffer.push(result(true, 100));
+        buffer.push(result(false, 10));
+        let flushed = buffer.take_admitted(&mut gate);
+        assert_eq!(flushed.len(), 2);
+        assert!(buffer.is_empty());
+
+        // 書き込んだキーフレームに続く差分フレームは止めない
+        assert!(gate.admit(false));
+        assert_eq!(gate.take_suppressed(), None);
+    }
+
+    #[test]
+    fn test_drops_everything_until_next_keyframe_when_over_limit() {
+        let mut buffer = ConnectBuffer::new(Duration::from_millis(300));
+        buffer.push(result(true, 100));
+        buffer.push(result(false, 10));
+        buffer.push(result(false, 10));
+        // 4 フレーム目（計 400ms）で上限を超える
+        buffer.push(result(false, 10));
+        assert!(buffer.is_empty());
+        buffer.push(result(false, 10));
+        assert!(buffer.is_empty());
+
+        buffer.push(result(true, 100));
+        assert_eq!(buffer.take().len(), 1);
+
+        // 上限を超える大きさのキーフレームは保持しない
+        buffer.push(result(true, MAX_BUFFERED_BYTES + 1));
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+// 映像に焼き込むデバッグ用の統計表示（`with_debug_overlay`）
+//
+// 不具合の画面録画を送ってもらったときに、どのフレームで何が起きたかを映像だけから追えるよう、
+// エンコーダーに渡す直前のフレームの左上にフレーム番号・エンコードのフレームレート・ビットレート・
+// キャプチャのタイムスタンプを描く。録画にも配信にもそのまま残る。
+// 文字は外部のフォントに頼らず、表示に使う文字だけの 5x7 ドットのビットマップを拡大して描く。
+// 白と黒だけで描くので RGBA と BGRA のどちらのフレームにもそのまま使える。
+
+use core_types::{Frame, Metrics};
+use std::sync::Arc;
+
+/// 表示の左上の位置（フレームの端からの距離）
+const MARGIN: u32 = 8;
+/// 文字の拡大率
+const SCALE: u32 = 2;
+/// 文字の周りの余白（拡大前のドット数）
+const PADDING: u32 = 2;
+/// 1 文字のドット数（字間の 1 ドットを含む幅）
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const GLYPH_ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+const BACKGROUND: [u8; 4] = [0, 0, 0, 255];
+const FOREGROUND: [u8; 4] = [255, 255, 255, 255];
+
+/// エンコーダーに渡すフレームに統計を描く
+pub struct DebugOverlay {
+    metrics: Arc<Metrics>,
+    frame_index: u64,
+}
+
+impl DebugOverlay {
+    /// エンコードのフレームレートとビットレートは `metrics` の直近の区間の値を使う
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            metrics,
+            frame_index: 0,
+        }
+    }
+
+    /// 次のフレームの番号を進め、`frame` に統計を描く
+    pub fn apply(&mut self, frame: &mut Frame) {
+        let (fps, bitrate_bps) = self.metrics.video_rates();
+        let text = overlay_text(self.frame_index, fps, bitrate_bps, frame.windows_timespan);
+        self.frame_index += 1;
+        let (width, height) = (frame.width, frame.height);
+        let data: &mut Vec<u8> = Arc::make_mut(&mut frame.data);
+        draw_text(data, width, height, &text);
+    }
+}
+
+/// 表示する文字列（`windows_timespan` は 100ns 単位）
+fn overlay_text(frame_index: u64, fps: f32, bitrate_bps: f32, windows_timespan: u64) -> String {
+    format!(
+        "F{} {:.1}FPS {}KBPS T{:.3}",
+        frame_index,
+        fps,
+        (bitrate_bps / 1000.0).round() as u64,
+        windows_timespan as f64 / 10_000_000.0
+    )
+}
+
+/// 背景の四角を敷いて `text` を描く（フレームからはみ出す部分は描かない）
+fn draw_text(data: &mut [u8], width: u32, height: u32, text: &str) {
+    if (data.len() as u64) < width as u64 * height as u64 * 4 {
+        return;
+    }
+    let chars = text.chars().count() as u32;
+    let box_width = (chars * GLYPH_ADVANCE - 1 + PADDING * 2) * SCALE;
+    let box_height = (GLYPH_HEIGHT + PADDING * 2) * SCALE;
+    let mut fill = |x: u32, y: u32, w: u32, h: u32, color: [u8; 4]| {
+        let (x_end, y_end) = ((x + w).min(width), (y + h).min(height));
+        for y in y.min(y_end)..y_end {
+            let row = (y * width) as usize * 4;
+            for pixel in
+                data[row + x.min(x_end) as usize * 4..row + x_end as usize * 4].chunks_exact_mut(4)
+            {
+                pixel.copy_from_slice(&color);
+            }
+        }
+    };
+
+    fill(MARGIN, MARGIN, box_width, box_height, BACKGROUND);
+    let left = MARGIN + PADDING * SCALE;
+    let top = MARGIN + PADDING * SCALE;
+    for (index, c) in text.chars().enumerate() {
+        let glyph_left = left + index as u32 * GLYPH_ADVANCE * SCALE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+                    fill(
+                        glyph_left + col * SCALE,
+                        top + row as u32 * SCALE,
+                        SCALE,
+                        SCALE,
+                        FOREGROUND,
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// 5x7 ドットの字形（各行の下位 5 ビット、左端が最上位）。表示に使う文字だけ持つ
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        _ => [0; 7],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_types::PixelFormat;
+
+    fn gray_frame(width: u32, height: u32) -> Frame {
+        Frame {
+            width,
+            height,
+            data: Arc::new(vec![128; (width * height * 4) as usize]),
+            windows_timespan: 12_345_670_000,
+            fps: 60,
+            format: PixelFormat::Rgba8,
+            dirty_fraction: None,
+            sequence: 0,
+            scale_to: None,
+        }
+    }
+
+    #[test]
+    fn test_overlay_draws_in_top_left_region() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.set_video_rates(59.9, 8_000_000.0);
+        let mut overlay = DebugOverlay::new(metrics);
+        let mut frame = gray_frame(640, 360);
+        overlay.apply(&mut frame);
+
+        let text = overlay_text(0, 59.9, 8_000_000.0, frame.windows_timespan);
+        assert_eq!(text, "F0 59.9FPS 8000KBPS T1234.567");
+        let box_width = (text.len() as u32 * GLYPH_ADVANCE - 1 + PADDING * 2) * SCALE;
+        let box_height = (GLYPH_HEIGHT + PADDING * 2) * SCALE;
+
+        let pixel = |x: u32, y: u32| {
+            let offset = ((y * frame.width + x) * 4) as usize;
+            <[u8; 4]>::try_from(&frame.data[offset..offset + 4]).unwrap()
+        };
+        let inside = |x: u32, y: u32| {
+            (MARGIN..MARGIN + box_width).contains(&x) && (MARGIN..MARGIN + box_height).contains(&y)
+        };
+        // 四角の中だけが白か黒になり、文字の白い画素もある
+        let mut lit = 0;
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let p = pixel(x, y);
+                if inside(x, y) {
+                    assert!(p == BACKGROUND || p == FOREGROUND, "({}, {})", x, y);
+                    lit += (p == FOREGROUND) as u32;
+                } else {
+                    assert_eq!(p, [128; 4], "({}, {})", x, y);
+                }
+            }
+        }
+        assert!(lit > 0);
+
+        // フレームごとに番号が進む
+        let before = frame.data.clone();
+        overlay.apply(&mut frame);
+        assert_ne!(frame.data, before);
+    }
+
+    #[test]
+    fn test_overlay_is_clipped_to_small_frames() {
+        let mut overlay = DebugOverlay::new(Arc::new(Metrics::default()));
+        let mut frame = gray_frame(16, 12);
+        overlay.apply(&mut frame);
+        assert_eq!(frame.data.len(), 16 * 12 * 4);
+        assert_eq!(&frame.data[..4], [128; 4]);
+        assert_eq!(&frame.data[(10 * 16 + 10) * 4..][..4], BACKGROUND);
+    }
+}
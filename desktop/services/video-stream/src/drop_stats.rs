@@ -0,0 +1,286 @@
+// 映像のドロップ統計
+//
+// エンコーダー側: フレームルーターが捨てたフレーム（チャネルの滞留、エンコーダーが前のジョブを処理中）
+// ネットワーク側: 受信側が RTCP Receiver Report で報告したパケットロス率
+// 両者を分けて集計し、「CPU/GPU が追いつかない」のか「回線が詰まっている」のかを判別できるようにする。
+// あわせてエンコード結果の QP を集計し、ビットレートが内容に対して足りているかを判別できるようにする。
+// キャプチャ側: キャプチャサービスのキューが溢れて送られなかったフレーム。フレームルーターに届いた
+// フレームの通し番号（`Frame::sequence`）の飛びから数える。
+// 遅延: キャプチャからエンコード完了まで、エンコード完了から送出までをヒストグラムで集計し、
+// 平均では埋もれる詰まりが分かるよう区間ごとの p50 / p95 / p99 を出す。
+
+use core_types::{LatencyHistogram, VideoStatsPayload};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use webrtc_rs::rtcp::packet::Packet;
+use webrtc_rs::rtcp::receiver_report::ReceiverReport;
+
+/// エンコーダー側のドロップ率がこれを超えたら警告
+pub const ENCODER_DROP_WARN_RATE: f32 = 0.1;
+/// ネットワーク側のロス率がこれを超えたら警告
+pub const NETWORK_LOSS_WARN_RATE: f32 = 0.05;
+/// 平均 QP がこれを超えたら警告（上限近くに張り付いていて画質が落ちている）
+pub const QP_WARN_LEVEL: f32 = 45.0;
+
+/// フレームルーター・RTCP 受信タスクと共有するカウンタ
+#[derive(Debug, Default)]
+pub struct DropCounters {
+    /// キャプチャから受け取ったフレームの累計
+    pub frames_received: AtomicU64,
+    /// キャプチャ側で捨てられたフレームの累計（通し番号の飛び）
+    pub capture_dropped: AtomicU64,
+    /// エンコーダーが追いつかずに捨てたフレームの累計
+    pub encoder_dropped: AtomicU64,
+    /// 直近の Receiver Report の fraction lost（256 分率）
+    pub network_fraction_lost: AtomicU32,
+    /// エンコーダーがジョブを取り出すまでの待ち時間の移動平均（マイクロ秒）
+    pub encoder_queue_wait_us: AtomicU64,
+}
+
+impl DropCounters {
+    /// RTCP パケットに Receiver Report が含まれていればロス率を更新
+    pub fn record_rtcp(&self, packet: &(dyn Packet + Send + Sync)) {
+        let Some(rr) = packet.as_any().downcast_ref::<ReceiverReport>() else {
+            return;
+        };
+        if let Some(fraction_lost) = rr.reports.iter().map(|r| r.fraction_lost).max() {
+            self.network_fraction_lost
+                .store(fraction_lost as u32, Ordering::Relaxed);
+        }
+    }
+}
+
+/// フレームの通し番号の飛びを数える
+#[derive(Debug, Default)]
+pub struct SequenceGaps {
+    last: Option<u64>,
+}
+
+impl SequenceGaps {
+    /// 届いたフレームの通し番号を記録し、前のフレームとの間で飛んだ枚数を返す
+    ///
+    /// 番号なし（0）のフレームは数えない。番号が戻った場合はキャプチャが始め直したものとして数え直す
+    pub fn observe(&mut self, sequence: u64) -> u64 {
+        if sequence == 0 {
+            return 0;
+        }
+        let gap = match self.last {
+            Some(last) if sequence > last => sequence - last - 1,
+            _ => 0,
+        };
+        self.last = Some(sequence);
+        gap
+    }
+}
+
+/// 前回の値との差分から区間ごとの統計を作る
+#[derive(Debug)]
+pub struct DropWindow {
+    last_received: u64,
+    last_dropped: u64,
+    last_capture_dropped: u64,
+    last_report: Instant,
+    /// 区間内に QP を報告したフレームの合計・数・最大
+    qp_sum: u64,
+    qp_frames: u64,
+    max_qp: Option<u8>,
+    /// 区間内のキャプチャからエンコード完了まで、エンコード完了から送出までの遅延
+    capture_to_encode: LatencyHistogram,
+    encode_to_send: LatencyHistogram,
+}
+
+impl DropWindow {
+    pub fn new() -> Self {
+        Self {
+            last_received: 0,
+            last_dropped: 0,
+            last_capture_dropped: 0,
+            last_report: Instant::now(),
+            qp_sum: 0,
+            qp_frames: 0,
+            max_qp: None,
+            capture_to_encode: LatencyHistogram::default(),
+            encode_to_send: LatencyHistogram::default(),
+        }
+    }
+
+    /// エンコード結果の QP を区間の集計に加える
+    pub fn record_qp(&mut self, qp: u8) {
+        self.qp_sum += qp as u64;
+        self.qp_frames += 1;
+        self.max_qp = self.max_qp.max(Some(qp));
+    }
+
+    /// キャプチャからエンコード完了までの遅延を区間の集計に加える
+    pub fn record_capture_to_encode(&mut self, latency: Duration) {
+        self.capture_to_encode.record(latency);
+    }
+
+    /// エンコード完了からトラックに書き込むまでの遅延を区間の集計に加える
+    pub fn record_encode_to_send(&mut self, latency: Duration) {
+        self.encode_to_send.record(latency);
+    }
+
+    /// 前回呼び出しからの区間の統計
+    pub fn report(&mut self, counters: &DropCounters) -> VideoStatsPayload {
+        self.report_at(counters, Instant::now())
+    }
+
+    pub fn report_at(&mut self, counters: &DropCounters, now: Instant) -> VideoStatsPayload {
+        let received = counters.frames_received.load(Ordering::Relaxed);
+        let dropped = counters.encoder_dropped.load(Ordering::Relaxed);
+        let frames = received.saturating_sub(self.last_received);
+        let encoder_dropped = dropped.saturating_sub(self.last_dropped);
+        self.last_received = received;
+        self.last_dropped = dropped;
+        let capture_dropped = counters.capture_dropped.load(Ordering::Relaxed);
+        let capture_dropped_delta = capture_dropped.saturating_sub(self.last_capture_dropped);
+        self.last_capture_dropped = capture_dropped;
+        let elapsed = now
+            .saturating_duration_since(self.last_report)
+            .as_secs_f32();
+        self.last_report = now;
+
+        let encoder_drop_rate = if frames > 0 {
+            (encoder_dropped as f32 / frames as f32).min(1.0)
+        } else {
+            0.0
+        };
+        let network_loss_rate =
+            counters.network_fraction_lost.load(Ordering::Relaxed) as f32 / 256.0;
+        // QP を報告しないエンコーダーでは None のまま
+        let average_qp = (self.qp_frames > 0).then(|| self.qp_sum as f32 / self.qp_frames as f32);
+        let max_qp = self.max_qp.take();
+        self.qp_sum = 0;
+        self.qp_frames = 0;
+        let capture_to_encode = self.capture_to_encode.percentiles();
+        let encode_to_send = self.encode_to_send.percentiles();
+        self.capture_to_encode.reset();
+        self.encode_to_send.reset();
+
+        VideoStatsPayload {
+            frames,
+            encoder_dropped,
+            encoder_drop_rate,
+            network_loss_rate,
+            capture_fps: if elapsed > 0.0 {
+                frames as f32 / elapsed
+            } else {
+                0.0
+            },
+            // キーフレームの集計は VideoStreamService が 1 分ごとに埋める
+            keyframes_last_minute: Default::default(),
+            average_qp,
+            max_qp,
+            capture_dropped: capture_dropped_delta,
+            capture_to_encode,
+            encode_to_send,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use webrtc_rs::rtcp::reception_report::ReceptionReport;
+
+    #[test]
+    fn test_window_reports_deltas() {
+        let counters = DropCounters::default();
+        let mut window = DropWindow::new();
+        let start = window.last_report;
+
+        counters.frames_received.store(100, Ordering::Relaxed);
+        counters.encoder_dropped.store(25, Ordering::Relaxed);
+        counters.capture_dropped.store(4, Ordering::Relaxed);
+        let report = window.report_at(&counters, start + Duration::from_secs(2));
+        assert_eq!((report.frames, report.encoder_dropped), (100, 25));
+        assert_eq!(report.capture_dropped, 4);
+        assert!((report.encoder_drop_rate - 0.25).abs() < f32::EPSILON);
+        assert!((report.capture_fps - 50.0).abs() < 0.01);
+
+        // 次の区間は差分のみ
+        counters.frames_received.store(160, Ordering::Relaxed);
+        counters.encoder_dropped.store(31, Ordering::Relaxed);
+        let report = window.report(&counters);
+        assert_eq!((report.frames, report.encoder_dropped), (60, 6));
+        assert_eq!(report.capture_dropped, 0);
+        assert!((report.encoder_drop_rate - 0.1).abs() < f32::EPSILON);
+
+        // フレームが来ていない区間
+        let report = window.report(&counters);
+        assert_eq!(report.frames, 0);
+        assert_eq!(report.encoder_drop_rate, 0.0);
+    }
+
+    #[test]
+    fn test_sequence_gaps() {
+        let mut gaps = SequenceGaps::default();
+        assert_eq!(gaps.observe(1), 0);
+        assert_eq!(gaps.observe(2), 0);
+        // 3, 4 が届かなかった
+        assert_eq!(gaps.observe(5), 2);
+        // 番号なしのフレームは数えない
+        assert_eq!(gaps.observe(0), 0);
+        assert_eq!(gaps.observe(6), 0);
+        // キャプチャを始め直した
+        assert_eq!(gaps.observe(1), 0);
+        assert_eq!(gaps.observe(3), 1);
+    }
+
+    #[test]
+    fn test_window_reports_qp() {
+        let counters = DropCounters::default();
+        let mut window = DropWindow::new();
+        for qp in [30, 40, 50] {
+            window.record_qp(qp);
+        }
+        let report = window.report(&counters);
+        assert_eq!(report.average_qp, Some(40.0));
+        assert_eq!(report.max_qp, Some(50));
+
+        // QP の報告がない区間は不明
+        let report = window.report(&counters);
+        assert_eq!(report.average_qp, None);
+        assert_eq!(report.max_qp, None);
+    }
+
+    #[test]
+    fn test_window_reports_latency_percentiles() {
+        let counters = DropCounters::default();
+        let mut window = DropWindow::new();
+        for ms in [4, 5, 6, 40] {
+            window.record_capture_to_encode(Duration::from_millis(ms));
+        }
+        let report = window.report(&counters);
+        let latency = report.capture_to_encode.unwrap();
+        assert_eq!((latency.p50_ms, latency.p99_ms), (5.0, 40.0));
+        assert_eq!(report.encode_to_send, None);
+
+        // 区間ごとに集計し直す
+        let report = window.report(&counters);
+        assert_eq!(report.capture_to_encode, None);
+    }
+
+    #[test]
+    fn test_record_receiver_report() {
+        let counters = DropCounters::default();
+        let rr = ReceiverReport {
+            reports: vec![
+                ReceptionReport {
+                    fraction_lost: 13,
+                    ..Default::default()
+                },
+                ReceptionReport {
+                    fraction_lost: 64,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        counters.record_rtcp(&rr);
+
+        let report = DropWindow::new().report(&counters);
+        assert!((report.network_loss_rate - 0.25).abs() < f32::EPSILON);
+    }
+}
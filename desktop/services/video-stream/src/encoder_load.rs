@@ -0,0 +1,84 @@
+// エンコーダーの入力の待ち時間による過負荷の検出（`with_encoder_queue_budget`）
+//
+// エンコーダーが追いつかなくなると、フレームはジョブスロットで置き換えられて捨てられる前に、
+// 取り出されるまでの待ち時間がまず伸びる。ドロップ率が警告の閾値に届く前でも遅延は増えているので、
+// ジョブスロットが計測した待ち時間の移動平均をドロップ統計の区間ごとに予算と比べ、続けて超えていたら
+// 「エンコーダーの過負荷」としてログに出し、品質ラダーにフレームレート・解像度を下げさせる。
+// 予算を下回る区間が来たら解除する。
+
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// エンコーダーの入力の待ち時間の予算（デフォルト）
+pub const DEFAULT_ENCODER_QUEUE_BUDGET: Duration = Duration::from_millis(25);
+/// 続けてこの区間数だけ予算を超えていたら過負荷とみなす
+const OVERLOAD_INTERVALS: u32 = 2;
+
+/// 区間ごとの平均の待ち時間から過負荷かどうかを決める
+#[derive(Debug)]
+pub struct QueueWaitMonitor {
+    budget: Duration,
+    over_budget_intervals: u32,
+    overloaded: bool,
+}
+
+impl QueueWaitMonitor {
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            budget,
+            over_budget_intervals: 0,
+            overloaded: false,
+        }
+    }
+
+    /// 区間の平均の待ち時間を記録し、過負荷かどうかを返す
+    pub fn observe(&mut self, average_wait: Duration) -> bool {
+        if average_wait <= self.budget {
+            if self.overloaded {
+                info!(
+                    "Encoder caught up: frames wait {:?} on average before encoding (budget {:?})",
+                    average_wait, self.budget
+                );
+            }
+            self.over_budget_intervals = 0;
+            self.overloaded = false;
+            return false;
+        }
+
+        self.over_budget_intervals += 1;
+        if !self.overloaded && self.over_budget_intervals >= OVERLOAD_INTERVALS {
+            warn!(
+                "Encoder overloaded: frames wait {:?} on average before encoding (budget {:?}), reducing quality",
+                average_wait, self.budget
+            );
+            self.overloaded = true;
+        }
+        self.overloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sustained_queue_wait_over_budget_is_overload() {
+        let ms = Duration::from_millis;
+        let mut monitor = QueueWaitMonitor::new(ms(25));
+
+        // 予算内、または一度だけ超えた区間では過負荷としない
+        assert!(!monitor.observe(ms(5)));
+        assert!(!monitor.observe(ms(40)));
+        assert!(!monitor.observe(ms(25)));
+        assert!(!monitor.observe(ms(40)));
+
+        // 続けて超えたら過負荷とし、超えている間は続ける
+        assert!(monitor.observe(ms(30)));
+        assert!(monitor.observe(ms(60)));
+
+        // 予算を下回ったら解除し、また続けて超えるまで過負荷としない
+        assert!(!monitor.observe(ms(10)));
+        assert!(!monitor.observe(ms(30)));
+        assert!(monitor.observe(ms(30)));
+    }
+}
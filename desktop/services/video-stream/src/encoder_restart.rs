@@ -0,0 +1,153 @@
+// 要求によるエンコーダーの作り直しの間引き
+//
+// RestartEncoder を受けるたびにエンコーダーのワーカーを作ると、一時停止中などフレームが届かない間は
+// ルーターが差し替えないので、作ったワーカーが replace_slot のチャネルに溜まり続ける。
+// 前回作ったワーカーにまだフレームが届いていなければ（ルーターが差し替えていなければ）新しく作らず、
+// 差し替えた後も一定時間は作り直さない。まとめた要求は前回のワーカーの最初のキーフレームで満たされる。
+// フリーズ検出とウォッチドッグによる作り直しも同じ経路（EncoderRestarter）を通す。
+
+use core_types::{EncodeJobSlot, EncodeResult, VideoEncoderFactory};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// 作り直しの最短の間隔
+pub const ENCODER_RESTART_MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct EncoderRestartLimiter {
+    min_interval: Duration,
+    /// 前回作り直した時刻と、その時点でルーターがキューに入れたジョブの累計
+    last: Option<(Instant, u64)>,
+}
+
+impl EncoderRestartLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last: None,
+        }
+    }
+
+    /// 作り直してよいか（よければ作り直したものとして記録する）
+    /// `jobs_queued` はルーターがキューに入れたジョブの累計（前回から増えていなければ、まだ差し替えていない）
+    pub fn try_restart(&mut self, now: Instant, jobs_queued: u64) -> bool {
+        if let Some((at, jobs_at_restart)) = self.last {
+            if jobs_queued == jobs_at_restart || now.duration_since(at) < self.min_interval {
+                return false;
+            }
+        }
+        self.last = Some((now, jobs_queued));
+        true
+    }
+}
+
+/// エンコーダーのワーカーを作り、ルーターに差し替えさせる
+pub struct EncoderRestarter {
+    factory: Arc<dyn VideoEncoderFactory>,
+    replace_slot_tx: mpsc::UnboundedSender<Arc<EncodeJobSlot>>,
+    /// ルーターがキューに入れたジョブの累計
+    jobs_queued: Arc<AtomicU64>,
+    limiter: EncoderRestartLimiter,
+}
+
+impl EncoderRestarter {
+    pub fn new(
+        factory: Arc<dyn VideoEncoderFactory>,
+        replace_slot_tx: mpsc::UnboundedSender<Arc<EncodeJobSlot>>,
+        jobs_queued: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            factory,
+            replace_slot_tx,
+            jobs_queued,
+            limiter: EncoderRestartLimiter::new(ENCODER_RESTART_MIN_INTERVAL),
+        }
+    }
+
+    /// 作り直して新しいワーカーの結果チャネルを返す（間引いた場合は None）
+    pub fn restart(&mut self, now: Instant) -> Option<mpsc::UnboundedReceiver<EncodeResult>> {
+        if !self.limiter.try_restart(now, self.jobs_queued()) {
+            return None;
+        }
+        let (new_slot, new_result_rx) = self.factory.setup();
+        if self.replace_slot_tx.send(new_slot).is_err() {
+            warn!("Frame router is gone, cannot replace encoder worker");
+        }
+        Some(new_result_rx)
+    }
+
+    pub fn jobs_queued(&self) -> u64 {
+        self.jobs_queued.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_types::VideoCodec;
+    use std::sync::atomic::AtomicUsize;
+
+    #[derive(Default)]
+    struct CountingFactory {
+        setups: AtomicUsize,
+    }
+
+    impl VideoEncoderFactory for CountingFactory {
+        fn setup(&self) -> (Arc<EncodeJobSlot>, mpsc::UnboundedReceiver<EncodeResult>) {
+            self.setups.fetch_add(1, Ordering::Relaxed);
+            let (_result_tx, result_rx) = mpsc::unbounded_channel();
+            (EncodeJobSlot::new(), result_rx)
+        }
+
+        fn codec(&self) -> VideoCodec {
+            VideoCodec::H264
+        }
+    }
+
+    #[test]
+    fn test_restarts_are_coalesced_until_the_router_swaps_in_the_worker() {
+        let interval = Duration::from_secs(1);
+        let mut limiter = EncoderRestartLimiter::new(interval);
+        let t0 = Instant::now();
+
+        assert!(limiter.try_restart(t0, 10));
+        // 一時停止中（ジョブが増えない）は何度要求されても作らない
+        assert!(!limiter.try_restart(t0 + interval * 5, 10));
+        assert!(!limiter.try_restart(t0 + interval * 60, 10));
+
+        // 差し替えた後も間隔が空くまでは作らない
+        let swapped = t0 + interval * 61;
+        assert!(limiter.try_restart(swapped, 11));
+        assert!(!limiter.try_restart(swapped + interval / 2, 20));
+        assert!(limiter.try_restart(swapped + interval, 20));
+    }
+
+    #[test]
+    fn test_restarter_hands_new_workers_to_the_router_through_the_limiter() {
+        let factory = Arc::new(CountingFactory::default());
+        let (replace_slot_tx, mut replace_slot_rx) = mpsc::unbounded_channel();
+        let jobs_queued = Arc::new(AtomicU64::new(0));
+        let mut restarter =
+            EncoderRestarter::new(factory.clone(), replace_slot_tx, jobs_queued.clone());
+        let t0 = Instant::now();
+
+        assert!(restarter.restart(t0).is_some());
+        assert!(replace_slot_rx.try_recv().is_ok());
+        // ルーターが差し替える前（ウォッチドッグ・フリーズ検出からでも）は作らない
+        assert!(restarter
+            .restart(t0 + ENCODER_RESTART_MIN_INTERVAL * 5)
+            .is_none());
+        assert_eq!(factory.setups.load(Ordering::Relaxed), 1);
+        assert!(replace_slot_rx.try_recv().is_err());
+
+        jobs_queued.store(3, Ordering::Relaxed);
+        assert!(restarter
+            .restart(t0 + ENCODER_RESTART_MIN_INTERVAL * 6)
+            .is_some());
+        assert_eq!(factory.setups.load(Ordering::Relaxed), 2);
+        assert_eq!(restarter.jobs_queued(), 3);
+    }
+}
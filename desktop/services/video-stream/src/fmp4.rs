@@ -0,0 +1,319 @@
+// 録画用の fragmented MP4 (ISO BMFF) マルチプレクサ
+//
+// 先頭に初期化セグメント（ftyp + moov）を書き、以降は moof + mdat のフラグメントを追記していく。
+// moov にサンプル表を持たないので、録画中にプロセスが落ちてもそれまでのフラグメントは再生できる。
+// 映像は H.264（avc1）、音声は Opus（ISO/IEC 14496-12 への Opus のマッピング）。
+
+use crate::mp4::{avc1, dinf, full_box, hdlr, mdhd, mp4_box, mvhd, tkhd};
+
+pub const VIDEO_TRACK_ID: u32 = 1;
+pub const AUDIO_TRACK_ID: u32 = 2;
+/// 映像トラックのタイムスケール (90kHz)
+pub const VIDEO_TIMESCALE: u32 = 90_000;
+/// 音声トラックのタイムスケール（Opus は常に 48kHz）
+pub const AUDIO_TIMESCALE: u32 = 48_000;
+const MOVIE_TIMESCALE: u32 = 1_000;
+
+const OPUS_CHANNELS: u16 = 2;
+/// エンコーダーの先読み分（OPUS_APPLICATION_AUDIO, 48kHz）。再生時に先頭から捨てられる
+const OPUS_PRE_SKIP: u16 = 312;
+
+/// sample_depends_on = 2（他のサンプルを参照しない）
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+/// sample_depends_on = 1, sample_is_non_sync_sample = 1
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// tfhd: default-base-is-moof（trun のデータオフセットを moof の先頭から数える）
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+/// trun: data-offset, sample-duration, sample-size, sample-flags
+const TRUN_FLAGS: u32 = 0x0001 | 0x0100 | 0x0200 | 0x0400;
+
+/// フラグメントに入れる 1 サンプル
+#[derive(Debug, Clone)]
+pub struct FragmentSample {
+    /// 映像は長さプレフィックス形式の NAL ユニット列、音声は Opus パケット
+    pub data: Vec<u8>,
+    /// トラックのタイムスケールでの長さ
+    pub duration: u32,
+    pub is_sync: bool,
+}
+
+/// 1 フラグメント分の 1 トラックのサンプル
+#[derive(Debug, Clone, Copy)]
+pub struct TrackRun<'a> {
+    pub track_id: u32,
+    /// 先頭サンプルのデコード時刻（トラックのタイムスケール）
+    pub base_decode_time: u64,
+    pub samples: &'a [FragmentSample],
+}
+
+/// 初期化セグメント（ftyp + moov）を作る（`audio` が false なら映像だけ）
+pub fn init_segment(sps: &[u8], pps: &[u8], width: u32, height: u32, audio: bool) -> Vec<u8> {
+    let ftyp = mp4_box(b"ftyp", |b| {
+        b.extend_from_slice(b"isom");
+        b.extend_from_slice(&0x200u32.to_be_bytes());
+        for brand in [b"isom", b"iso6", b"avc1", b"mp41"] {
+            b.extend_from_slice(brand);
+        }
+    });
+
+    let video_trak = trak(
+        tkhd(VIDEO_TRACK_ID, 0, width, height, 0),
+        VIDEO_TIMESCALE,
+        hdlr(b"vide", "VideoHandler"),
+        full_box(b"vmhd", 0, 1, |b| b.extend_from_slice(&[0; 8])),
+        avc1(sps, pps, width, height),
+    );
+    let audio_trak = audio.then(|| {
+        trak(
+            tkhd(AUDIO_TRACK_ID, 0, 0, 0, 0x0100),
+            AUDIO_TIMESCALE,
+            hdlr(b"soun", "SoundHandler"),
+            full_box(b"smhd", 0, 0, |b| b.extend_from_slice(&[0; 4])),
+            opus_sample_entry(),
+        )
+    });
+
+    let mut track_ids = vec![VIDEO_TRACK_ID];
+    if audio {
+        track_ids.push(AUDIO_TRACK_ID);
+    }
+    let mvex = mp4_box(b"mvex", |b| {
+        for track_id in &track_ids {
+            b.extend_from_slice(&full_box(b"trex", 0, 0, |b| {
+                b.extend_from_slice(&track_id.to_be_bytes());
+                b.extend_from_slice(&1u32.to_be_bytes()); // default_sample_description_index
+                b.extend_from_slice(&[0; 12]); // default duration, size, flags
+            }));
+        }
+    });
+    let moov = mp4_box(b"moov", |b| {
+        b.extend_from_slice(&mvhd(MOVIE_TIMESCALE, 0, AUDIO_TRACK_ID + 1));
+        b.extend_from_slice(&video_trak);
+        if let Some(audio_trak) = &audio_trak {
+            b.extend_from_slice(audio_trak);
+        }
+        b.extend_from_slice(&mvex);
+    });
+
+    let mut out = ftyp;
+    out.extend_from_slice(&moov);
+    out
+}
+
+/// サンプル表が空のトラック（サンプルはフラグメント側に書く）
+fn trak(
+    tkhd: Vec<u8>,
+    timescale: u32,
+    hdlr: Vec<u8>,
+    media_header: Vec<u8>,
+    sample_entry: Vec<u8>,
+) -> Vec<u8> {
+    let stsd = full_box(b"stsd", 0, 0, |b| {
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&sample_entry);
+    });
+    let empty_table = |kind: &[u8; 4]| full_box(kind, 0, 0, |b| b.extend_from_slice(&[0; 4]));
+    let stsz = full_box(b"stsz", 0, 0, |b| b.extend_from_slice(&[0; 8]));
+    let stbl = mp4_box(b"stbl", |b| {
+        for child in [
+            &stsd,
+            &empty_table(b"stts"),
+            &empty_table(b"stsc"),
+            &stsz,
+            &empty_table(b"stco"),
+        ] {
+            b.extend_from_slice(child);
+        }
+    });
+    let minf = mp4_box(b"minf", |b| {
+        for child in [&media_header, &dinf(), &stbl] {
+            b.extend_from_slice(child);
+        }
+    });
+    let mdia = mp4_box(b"mdia", |b| {
+        for child in [&mdhd(timescale, 0), &hdlr, &minf] {
+            b.extend_from_slice(child);
+        }
+    });
+    mp4_box(b"trak", |b| {
+        b.extend_from_slice(&tkhd);
+        b.extend_from_slice(&mdia);
+    })
+}
+
+/// Opus のサンプルエントリー（dOps はチャネルマッピングなしのステレオ）
+fn opus_sample_entry() -> Vec<u8> {
+    let dops = mp4_box(b"dOps", |b| {
+        b.push(0); // Version
+        b.push(OPUS_CHANNELS as u8);
+        b.extend_from_slice(&OPUS_PRE_SKIP.to_be_bytes());
+        b.extend_from_slice(&AUDIO_TIMESCALE.to_be_bytes()); // InputSampleRate
+        b.extend_from_slice(&0i16.to_be_bytes()); // OutputGain
+        b.push(0); // ChannelMappingFamily
+    });
+    mp4_box(b"Opus", |b| {
+        b.extend_from_slice(&[0; 6]);
+        b.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+        b.extend_from_slice(&[0; 8]);
+        b.extend_from_slice(&OPUS_CHANNELS.to_be_bytes());
+        b.extend_from_slice(&16u16.to_be_bytes()); // samplesize
+        b.extend_from_slice(&[0; 4]);
+        b.extend_from_slice(&(AUDIO_TIMESCALE << 16).to_be_bytes());
+        b.extend_from_slice(&dops);
+    })
+}
+
+/// フラグメント（moof + mdat）を作る。mdat にはトラックの順にサンプルを並べる
+pub fn fragment(sequence_number: u32, runs: &[TrackRun]) -> Vec<u8> {
+    let runs: Vec<&TrackRun> = runs.iter().filter(|run| !run.samples.is_empty()).collect();
+    let run_bytes: Vec<usize> = runs
+        .iter()
+        .map(|run| run.samples.iter().map(|s| s.data.len()).sum())
+        .collect();
+
+    // trun のデータオフセットは moof の大きさで決まるので、一度仮の値で作って大きさを測る
+    let build_moof = |data_offsets: &[u32]| {
+        mp4_box(b"moof", |b| {
+            b.extend_from_slice(&full_box(b"mfhd", 0, 0, |b| {
+                b.extend_from_slice(&sequence_number.to_be_bytes());
+            }));
+            for (run, data_offset) in runs.iter().zip(data_offsets) {
+                b.extend_from_slice(&traf(run, *data_offset));
+            }
+        })
+    };
+    let moof_len = build_moof(&vec![0; runs.len()]).len();
+    let mut data_offsets = Vec::with_capacity(runs.len());
+    let mut offset = moof_len + 8;
+    for bytes in &run_bytes {
+        data_offsets.push(offset as u32);
+        offset += bytes;
+    }
+    let moof = build_moof(&data_offsets);
+
+    let mdat_size = 8 + run_bytes.iter().sum::<usize>();
+    let mut out = Vec::with_capacity(moof.len() + mdat_size);
+    out.extend_from_slice(&moof);
+    out.extend_from_slice(&(mdat_size as u32).to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    for run in &runs {
+        for sample in run.samples {
+            out.extend_from_slice(&sample.data);
+        }
+    }
+    out
+}
+
+fn traf(run: &TrackRun, data_offset: u32) -> Vec<u8> {
+    mp4_box(b"traf", |b| {
+        b.extend_from_slice(&full_box(b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, |b| {
+            b.extend_from_slice(&run.track_id.to_be_bytes());
+        }));
+        b.extend_from_slice(&full_box(b"tfdt", 1, 0, |b| {
+            b.extend_from_slice(&run.base_decode_time.to_be_bytes());
+        }));
+        b.extend_from_slice(&full_box(b"trun", 0, TRUN_FLAGS, |b| {
+            b.extend_from_slice(&(run.samples.len() as u32).to_be_bytes());
+            b.extend_from_slice(&data_offset.to_be_bytes());
+            for sample in run.samples {
+                let flags = if sample.is_sync {
+                    SAMPLE_FLAGS_SYNC
+                } else {
+                    SAMPLE_FLAGS_NON_SYNC
+                };
+                b.extend_from_slice(&sample.duration.to_be_bytes());
+                b.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+                b.extend_from_slice(&flags.to_be_bytes());
+            }
+        }));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+        data.windows(pattern.len()).position(|w| w == pattern)
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_init_segment_has_both_tracks() {
+        let sps = [0x67, 0x42, 0xC0, 0x1F, 0xDA];
+        let pps = [0x68, 0xCE, 0x3C, 0x80];
+        let init = init_segment(&sps, &pps, 1280, 720, true);
+        assert_eq!(&init[4..8], b"ftyp");
+        assert!(find(&init, b"avcC").is_some());
+        assert!(find(&init, b"dOps").is_some());
+        assert_eq!(init.windows(4).filter(|w| *w == b"trex").count(), 2);
+
+        let video_only = init_segment(&sps, &pps, 1280, 720, false);
+        assert!(find(&video_only, b"Opus").is_none());
+        assert_eq!(video_only.windows(4).filter(|w| *w == b"trex").count(), 1);
+    }
+
+    #[test]
+    fn test_fragment_data_offsets_point_into_mdat() {
+        let video = vec![
+            FragmentSample {
+                data: vec![0, 0, 0, 2, 0x65, 0x88],
+                duration: 3000,
+                is_sync: true,
+            },
+            FragmentSample {
+                data: vec![0, 0, 0, 2, 0x41, 0x9A],
+                duration: 3000,
+                is_sync: false,
+            },
+        ];
+        let audio = vec![FragmentSample {
+            data: vec![0xFC, 0x01, 0x02],
+            duration: 960,
+            is_sync: true,
+        }];
+        let data = fragment(
+            7,
+            &[
+                TrackRun {
+                    track_id: VIDEO_TRACK_ID,
+                    base_decode_time: 6000,
+                    samples: &video,
+                },
+                TrackRun {
+                    track_id: AUDIO_TRACK_ID,
+                    base_decode_time: 1920,
+                    samples: &audio,
+                },
+            ],
+        );
+
+        assert_eq!(&data[4..8], b"moof");
+        let moof_len = read_u32(&data, 0) as usize;
+        assert_eq!(&data[moof_len + 4..moof_len + 8], b"mdat");
+        assert_eq!(read_u32(&data, moof_len) as usize, data.len() - moof_len);
+        // mfhd のシーケンス番号
+        let mfhd = find(&data, b"mfhd").unwrap();
+        assert_eq!(read_u32(&data, mfhd + 8), 7);
+
+        // trun のデータオフセットがそれぞれのトラックの先頭サンプルを指す
+        let truns: Vec<usize> = (0..data.len() - 4)
+            .filter(|&i| &data[i..i + 4] == b"trun")
+            .collect();
+        assert_eq!(truns.len(), 2);
+        let video_offset = read_u32(&data, truns[0] + 12) as usize;
+        assert_eq!(&data[video_offset..video_offset + 5], &[0, 0, 0, 2, 0x65]);
+        let audio_offset = read_u32(&data, truns[1] + 12) as usize;
+        assert_eq!(&data[audio_offset..], &[0xFC, 0x01, 0x02]);
+
+        // 差分フレームは non-sync
+        assert_eq!(
+            read_u32(&data, truns[0] + 16 + 12 + 8),
+            SAMPLE_FLAGS_NON_SYNC
+        );
+    }
+}
@@ -1,14 +1,91 @@
-use core_types::{EncodeJob, EncodeJobSlot, Frame, VideoEncoderFactory};
-use std::sync::atomic::{AtomicBool, Ordering};
+use core_types::{
+    merge_dirty_fraction, EncodeJob, EncodeJobSlot, EncodeResult, Frame, KeyframeReason,
+    VideoEncoderFactory,
+};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
+use tokio::sync::mpsc;
 use tracing::{debug, info, span, warn, Level};
 
+use crate::abs_capture_time::CaptureClock;
+use crate::debug_overlay::DebugOverlay;
+use crate::drop_stats::{DropCounters, SequenceGaps};
+use crate::freeze_detector::frame_fingerprint;
+use crate::keyframe_stats::KeyframeRequest;
+use crate::video_dump::VideoDump;
+
+/// VideoStreamService からフレームルーターのエンコーダーを監視・差し替えるための口
+pub struct EncoderControl {
+    /// ウォッチドッグが再生成したエンコーダーワーカー（受け取ったら古いものと差し替える）
+    pub replace_slot_rx: mpsc::UnboundedReceiver<Arc<EncodeJobSlot>>,
+    /// 解像度・フレームレートの変更でルーターが作り直したエンコーダーの結果チャネル
+    /// （古いワーカーの結果を受け取り終えたら、こちらに切り替える）
+    pub replace_result_tx: mpsc::UnboundedSender<mpsc::UnboundedReceiver<EncodeResult>>,
+    /// エンコーダーに渡したジョブの累計数
+    pub jobs_queued: Arc<AtomicU64>,
+    /// 前のジョブからキャプチャの内容が変わったジョブの累計数（フリーズ検出用）
+    pub source_changes: Arc<AtomicU64>,
+    /// エンコーダー側のドロップ集計（クライアントへの統計送信用）
+    pub drop_counters: Arc<DropCounters>,
+    /// エンコーダーに渡すフレームのダンプ先（デバッグ用）
+    pub video_dump: Option<VideoDump>,
+    /// キャプチャ時刻と壁時計の対応付け（abs-capture-time 用）
+    pub capture_clock: Arc<CaptureClock>,
+    /// 解像度・フレームレートの変更時に、古いエンコーダーでキーフレームを出してから切り替える
+    pub gop_aligned_resize: bool,
+    /// エンコーダーに渡すフレームに焼き込む統計表示（デバッグ用）
+    pub debug_overlay: Option<DebugOverlay>,
+    /// 接続先のブラウザが示したビットレートの上限（bps、0 で制限しない）
+    pub max_bitrate_bps: Arc<AtomicU32>,
+}
+
+/// GOP 境界に合わせた解像度・フレームレートの切り替え
+///
+/// 変更を検出したフレームでは切り替えずに、古いエンコーダーで最後のフレームをキーフレームとして出し直し、
+/// 次のフレームで切り替える。GOP の途中でストリームが切り替わると乱れるデコーダーがあるため。
+#[derive(Debug, Default)]
+struct ResizeDeferral {
+    /// 切り替えを待っている (幅, 高さ, fps)
+    pending: Option<(u32, u32, u32)>,
+}
+
+impl ResizeDeferral {
+    /// `format` への変更を検出したフレームで呼び、切り替えを次のフレームまで待つなら true
+    fn defer(&mut self, format: (u32, u32, u32)) -> bool {
+        if self.pending == Some(format) {
+            self.pending = None;
+            return false;
+        }
+        // 待っている間にさらに変わった場合も、キーフレームを出し直してから切り替える
+        self.pending = Some(format);
+        true
+    }
+
+    /// 待っている間に元の形式に戻ったら切り替えない
+    fn cancel(&mut self) {
+        if let Some((width, height, fps)) = self.pending.take() {
+            debug!(
+                "Frame format reverted before switching to {}x{}@{}, keeping the encoder",
+                width, height, fps
+            );
+        }
+    }
+}
+
 /// フレーム処理の統計情報
 struct FrameStats {
     frames_received: u64,
     frames_dropped_not_ready: u64,
     frames_dropped_no_encoder: u64,
+    /// チャネルに溜まっていた古いフレームを捨てた数
+    frames_dropped_stale: u64,
+    /// エンコーダーが前のジョブを取り出す前に上書きした数
+    frames_dropped_encoder_busy: u64,
+    /// 幅か高さが 0 のフレーム（最小化されたウィンドウ）を捨てた数
+    frames_dropped_empty: u64,
+    /// ダーティ領域がなく、前のフレームと同じ内容なので送らなかった数
+    frames_skipped_unchanged: u64,
     frames_queued: u64,
     last_perf_log: Instant,
 }
@@ -19,6 +96,10 @@ impl FrameStats {
             frames_received: 0,
             frames_dropped_not_ready: 0,
             frames_dropped_no_encoder: 0,
+            frames_dropped_stale: 0,
+            frames_dropped_encoder_busy: 0,
+            frames_dropped_empty: 0,
+            frames_skipped_unchanged: 0,
             frames_queued: 0,
             last_perf_log: Instant::now(),
         }
@@ -29,20 +110,34 @@ impl FrameStats {
             let elapsed_sec = self.last_perf_log.elapsed().as_secs_f32();
             let receive_fps = self.frames_received as f32 / elapsed_sec;
             let queue_fps = self.frames_queued as f32 / elapsed_sec;
+            let stale_drop_rate = if self.frames_received > 0 {
+                self.frames_dropped_stale as f32 / self.frames_received as f32 * 100.0
+            } else {
+                0.0
+            };
             tracing::info!(
-                "Frame processing stats (last {}s): received={} ({:.1} fps), queued={} ({:.1} fps), dropped_not_ready={}, dropped_no_encoder={}",
+                "Frame processing stats (last {}s): received={} ({:.1} fps), queued={} ({:.1} fps), dropped_not_ready={}, dropped_no_encoder={}, dropped_stale={} ({:.1}%), dropped_encoder_busy={}, dropped_empty={}, skipped_unchanged={}",
                 elapsed_sec,
                 self.frames_received,
                 receive_fps,
                 self.frames_queued,
                 queue_fps,
                 self.frames_dropped_not_ready,
-                self.frames_dropped_no_encoder
+                self.frames_dropped_no_encoder,
+                self.frames_dropped_stale,
+                stale_drop_rate,
+                self.frames_dropped_encoder_busy,
+                self.frames_dropped_empty,
+                self.frames_skipped_unchanged
             );
             self.frames_received = 0;
             self.frames_queued = 0;
             self.frames_dropped_not_ready = 0;
             self.frames_dropped_no_encoder = 0;
+            self.frames_dropped_stale = 0;
+            self.frames_dropped_encoder_busy = 0;
+            self.frames_dropped_empty = 0;
+            self.frames_skipped_unchanged = 0;
             self.last_perf_log = Instant::now();
         }
     }
@@ -54,21 +149,103 @@ pub async fn run_frame_router(
     initial_encode_job_slot: Arc<EncodeJobSlot>,
     encoder_factory: Arc<dyn VideoEncoderFactory>,
     connection_ready: Arc<AtomicBool>,
-    keyframe_requested: Arc<AtomicBool>,
+    keyframe_request: Arc<KeyframeRequest>,
+    stream_paused: Arc<AtomicBool>,
+    mut encoder_control: EncoderControl,
 ) {
     info!("Frame router started");
 
     let mut encode_job_slot = Some(initial_encode_job_slot);
     let mut current_width: u32 = 0;
     let mut current_height: u32 = 0;
+    let mut current_fps: u32 = 0;
     let mut last_frame_ts: Option<u64> = None;
     let mut stats = FrameStats::new();
     let mut first_frame_received = false;
     let mut first_job_queued = false;
+    // 一時停止後に黒フレームを送ったか
+    let mut pause_frame_sent = false;
+    // 0x0 のフレームを受け取っていて、まだ通常のフレームに戻っていないか
+    let mut target_minimized = false;
+    // 前のジョブのフレームの指紋（フリーズ検出用）
+    let mut last_fingerprint: Option<u64> = None;
+    let mut resize_deferral = ResizeDeferral::default();
+    // 最後にエンコーダーに渡したフレーム（GOP 境界に合わせて切り替える場合のキーフレーム用）
+    let mut last_queued_frame: Option<Frame> = None;
+    // キャプチャ側で捨てられたフレーム（通し番号の飛び）
+    let mut capture_sequence = SequenceGaps::default();
 
-    while let Some(frame) = frame_rx.recv().await {
+    while let Some(mut frame) = frame_rx.recv().await {
         let pipeline_start = Instant::now();
         stats.frames_received += 1;
+        let drop_counters = &encoder_control.drop_counters;
+        drop_counters
+            .frames_received
+            .fetch_add(1, Ordering::Relaxed);
+        drop_counters
+            .capture_dropped
+            .fetch_add(capture_sequence.observe(frame.sequence), Ordering::Relaxed);
+
+        // チャネルに溜まっている古いフレームは捨てて最新のものだけを処理する（latest frame wins）
+        while let Ok(newer) = frame_rx.try_recv() {
+            stats.frames_received += 1;
+            stats.frames_dropped_stale += 1;
+            drop_counters
+                .frames_received
+                .fetch_add(1, Ordering::Relaxed);
+            drop_counters
+                .encoder_dropped
+                .fetch_add(1, Ordering::Relaxed);
+            drop_counters
+                .capture_dropped
+                .fetch_add(capture_sequence.observe(newer.sequence), Ordering::Relaxed);
+            debug!(
+                "Dropped stale frame #{} (superseded by #{})",
+                frame.sequence, newer.sequence
+            );
+            // 捨てたフレームの変化は次のフレームに含まれているものとして数える
+            frame = Frame {
+                dirty_fraction: merge_dirty_fraction(frame.dirty_fraction, newer.dirty_fraction),
+                ..newer
+            };
+        }
+        encoder_control.capture_clock.observe(
+            frame.windows_timespan,
+            SystemTime::now(),
+            pipeline_start,
+        );
+
+        // 最小化されたウィンドウのキャプチャは 0x0 のフレームになる。エンコーダーに渡すと
+        // テクスチャの作成などに失敗するので捨てる（クライアントには最後のフレームが表示されたまま）
+        if frame.width == 0 || frame.height == 0 || frame.data.is_empty() {
+            stats.frames_dropped_empty += 1;
+            if !target_minimized {
+                info!(
+                    "Received a {}x{} frame (capture target minimized?), holding the last frame until it is restored",
+                    frame.width, frame.height
+                );
+                target_minimized = true;
+            }
+            stats.log_if_needed();
+            continue;
+        }
+        if target_minimized {
+            info!(
+                "Capture target restored ({}x{}), resuming encoding",
+                frame.width, frame.height
+            );
+            target_minimized = false;
+        }
+
+        // ウォッチドッグ（または再起動の要求）でエンコーダーが再生成されていれば差し替える
+        while let Ok(new_slot) = encoder_control.replace_slot_rx.try_recv() {
+            info!("Replacing encoder worker");
+            if let Some(old_slot) = encode_job_slot.take() {
+                old_slot.shutdown();
+            }
+            encode_job_slot = Some(new_slot);
+            keyframe_request.request(KeyframeReason::EncoderRestart);
+        }
 
         let interarrival_ms = last_frame_ts
             .map(|prev| {
@@ -105,6 +282,23 @@ pub async fn run_frame_router(
             continue;
         }
 
+        // 一時停止中はエンコーダーに流さない（停止直後に黒フレームを1枚だけ送る）
+        if stream_paused.load(Ordering::Relaxed) {
+            if pause_frame_sent {
+                continue;
+            }
+            frame = Frame {
+                data: Arc::new(vec![0u8; frame.data.len()]),
+                dirty_fraction: None,
+                ..frame
+            };
+            pause_frame_sent = true;
+        } else if pause_frame_sent {
+            // 再開直後はキーフレームから送り直す
+            keyframe_request.request(KeyframeReason::Resume);
+            pause_frame_sent = false;
+        }
+
         // フレーム処理全体を span で計測
         let process_frame_span = span!(
             Level::DEBUG,
@@ -116,43 +310,100 @@ pub async fn run_frame_router(
 
         // タイムスタンプを更新
         last_frame_ts = Some(frame.windows_timespan);
+        let max_bitrate_bps =
+            Some(encoder_control.max_bitrate_bps.load(Ordering::Relaxed)).filter(|bps| *bps > 0);
 
-        // 解像度変更を検出した場合はencoderを再生成
-        let resolution_changed = current_width != frame.width || current_height != frame.height;
-        if resolution_changed {
+        // 解像度・フレームレート変更を検出した場合はencoderを再生成
+        // エンコーダー側で縮小するフレームは、元のサイズではなく出力サイズで判定する
+        let (output_width, output_height) = frame.output_size();
+        let resolution_changed = current_width != output_width || current_height != output_height;
+        let fps_changed = current_fps != frame.fps;
+        if resolution_changed || fps_changed {
             if current_width == 0 && current_height == 0 {
                 // 最初のフレーム: エンコーダーは既に起動済みで最初のフレームを待機中
                 // shutdownせずに解像度を更新するだけ
                 info!(
-                    "Observed first frame {}x{} (encoder already initialized and waiting)",
-                    frame.width, frame.height
+                    "Observed first frame {}x{} @ {}fps (encoder already initialized and waiting)",
+                    output_width, output_height, frame.fps
                 );
-                current_width = frame.width;
-                current_height = frame.height;
+                current_width = output_width;
+                current_height = output_height;
+                current_fps = frame.fps;
                 // 最初のキーフレームを要求
-                keyframe_requested.store(true, Ordering::Relaxed);
+                keyframe_request.request(KeyframeReason::FirstFrame);
+            } else if let Some(last_frame) = last_queued_frame
+                .as_ref()
+                .filter(|_| resize_deferral.defer((output_width, output_height, frame.fps)))
+            {
+                // 古いエンコーダーで最後のフレームをキーフレームとして出し、次のフレームで切り替える
+                info!(
+                    "Observed frame format change {}x{}@{} -> {}x{}@{} (closing the GOP with a keyframe before recreating encoder)",
+                    current_width,
+                    current_height,
+                    current_fps,
+                    output_width,
+                    output_height,
+                    frame.fps
+                );
+                if let Some(job_slot) = encode_job_slot.as_ref() {
+                    keyframe_request.request(KeyframeReason::ResolutionChange);
+                    job_slot.set(EncodeJob {
+                        width: last_frame.width,
+                        height: last_frame.height,
+                        rgba: last_frame.data.clone(),
+                        timestamp: frame.windows_timespan,
+                        enqueue_at: pipeline_start,
+                        request_keyframe: keyframe_request.take(),
+                        fps: last_frame.fps,
+                        format: last_frame.format,
+                        sequence: last_frame.sequence,
+                        scale_to: last_frame.scale_to,
+                        max_bitrate_bps,
+                    });
+                    stats.frames_queued += 1;
+                    encoder_control.jobs_queued.fetch_add(1, Ordering::Relaxed);
+                }
+                stats.log_if_needed();
+                continue;
             } else {
-                // 実際の解像度変更: エンコーダーを再起動
+                // 実際の解像度・フレームレート変更: エンコーダーを再起動
                 info!(
-                    "Observed frame resize {}x{} -> {}x{} (recreating encoder)",
-                    current_width, current_height, frame.width, frame.height
+                    "Observed frame format change {}x{}@{} -> {}x{}@{} (recreating encoder)",
+                    current_width,
+                    current_height,
+                    current_fps,
+                    output_width,
+                    output_height,
+                    frame.fps
                 );
 
+                // 新しいencoderワーカーを起動し、結果チャネルを VideoStreamService に渡す
+                // （古いワーカーを止める前に渡すので、古いチャネルが閉じたときには届いている）
+                let (new_slot, new_rx) = encoder_factory.setup();
+                if encoder_control.replace_result_tx.send(new_rx).is_err() {
+                    warn!("Video stream service is gone, encode results will not be delivered");
+                }
+
                 // 既存のencoderワーカーを停止
-                if let Some(old_slot) = encode_job_slot.as_ref() {
+                if let Some(old_slot) = encode_job_slot.replace(new_slot) {
                     old_slot.shutdown();
                 }
-                drop(encode_job_slot.take());
 
-                // 新しいencoderワーカーを起動
-                // TODO: 解像度変更時のencode_result_rx破棄問題を修正する必要がある
-                let (new_slot, _new_rx) = encoder_factory.setup();
-                encode_job_slot = Some(new_slot);
-
-                current_width = frame.width;
-                current_height = frame.height;
-                keyframe_requested.store(true, Ordering::Relaxed);
+                current_width = output_width;
+                current_height = output_height;
+                current_fps = frame.fps;
+                keyframe_request.request(KeyframeReason::ResolutionChange);
             }
+        } else {
+            resize_deferral.cancel();
+        }
+
+        // 前のフレームから何も変わっていなければエンコーダーに渡さない
+        // （キーフレーム要求があるときは同じ内容でもこのフレームで応える）
+        if frame.dirty_fraction == Some(0.0) && !keyframe_request.is_pending() {
+            stats.frames_skipped_unchanged += 1;
+            stats.log_if_needed();
+            continue;
         }
 
         // エンコードジョブ送信を span で計測
@@ -162,29 +413,70 @@ pub async fn run_frame_router(
             let job_send_start = Instant::now();
 
             // キーフレーム要求が来ている場合は、フラグをリセットしてジョブに含める
-            let request_keyframe = keyframe_requested.swap(false, Ordering::Relaxed);
+            let request_keyframe = keyframe_request.take();
 
             if !first_job_queued {
                 info!(
-                    "Queueing first encode job: {}x{} (keyframe: {})",
+                    "Queueing first encode job: {}x{} (keyframe: {:?})",
                     frame.width, frame.height, request_keyframe
                 );
                 first_job_queued = true;
             }
 
-            job_slot.set(EncodeJob {
+            // フリーズ検出はキャプチャの内容で判定する（統計表示はフレームごとに変わるので描く前に取る）
+            let fingerprint = frame_fingerprint(&frame.data);
+            if last_fingerprint.replace(fingerprint) != Some(fingerprint) {
+                encoder_control
+                    .source_changes
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            if let Some(overlay) = encoder_control.debug_overlay.as_mut() {
+                overlay.apply(&mut frame);
+            }
+
+            if let Some(video_dump) = encoder_control.video_dump.as_ref() {
+                video_dump.push(&frame);
+            }
+            if encoder_control.gop_aligned_resize {
+                last_queued_frame = Some(frame.clone());
+            }
+
+            let frame_sequence = frame.sequence;
+            let replaced = job_slot.set(EncodeJob {
                 width: frame.width,
                 height: frame.height,
                 rgba: frame.data,
                 timestamp: frame.windows_timespan,
                 enqueue_at: pipeline_start,
                 request_keyframe,
+                fps: frame.fps,
+                format: frame.format,
+                sequence: frame.sequence,
+                scale_to: frame.scale_to,
+                max_bitrate_bps,
             });
 
             let job_send_dur = job_send_start.elapsed();
             drop(_queue_encode_job_guard);
 
             stats.frames_queued += 1;
+            encoder_control.jobs_queued.fetch_add(1, Ordering::Relaxed);
+            if replaced {
+                // エンコーダーが前のフレームをまだ処理中だった
+                debug!(
+                    "Encode job replaced by frame #{} before the encoder took it",
+                    frame_sequence
+                );
+                stats.frames_dropped_encoder_busy += 1;
+                encoder_control
+                    .drop_counters
+                    .encoder_dropped
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            encoder_control.drop_counters.encoder_queue_wait_us.store(
+                job_slot.average_queue_wait().as_micros() as u64,
+                Ordering::Relaxed,
+            );
             if job_send_dur.as_millis() > 10 {
                 warn!("Encode job set took {}ms", job_send_dur.as_millis());
             }
@@ -204,10 +496,433 @@ pub async fn run_frame_router(
         stats.log_if_needed();
     }
 
-    // クリーンアップ: エンコーダーをシャットダウン
-    if let Some(job_slot) = encode_job_slot.as_ref() {
+    // クリーンアップ: エンコーダーをシャットダウンし、ワーカースレッドの終了を待つ
+    if let Some(job_slot) = encode_job_slot.take() {
         job_slot.shutdown();
+        match tokio::task::spawn_blocking(move || job_slot.join_worker()).await {
+            Ok(Ok(())) => debug!("Encoder worker thread stopped"),
+            _ => warn!("Encoder worker thread panicked"),
+        }
     }
 
     info!("Frame router stopped");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_types::{PixelFormat, VideoCodec};
+    use std::sync::Mutex;
+
+    /// 作ったスロットと結果の送信側を記録するだけのファクトリ
+    struct RecordingFactory {
+        slots: Mutex<Vec<Arc<EncodeJobSlot>>>,
+        results: Mutex<Vec<mpsc::UnboundedSender<EncodeResult>>>,
+    }
+
+    impl VideoEncoderFactory for RecordingFactory {
+        fn setup(&self) -> (Arc<EncodeJobSlot>, mpsc::UnboundedReceiver<EncodeResult>) {
+            let slot = EncodeJobSlot::new();
+            let (result_tx, result_rx) = mpsc::unbounded_channel();
+            self.slots.lock().unwrap().push(slot.clone());
+            self.results.lock().unwrap().push(result_tx);
+            (slot, result_rx)
+        }
+
+        fn codec(&self) -> VideoCodec {
+            VideoCodec::H264
+        }
+    }
+
+    fn frame(width: u32, height: u32) -> Frame {
+        Frame {
+            width,
+            height,
+            data: Arc::new(vec![0u8; (width * height * 4) as usize]),
+            windows_timespan: 0,
+            fps: 30,
+            format: PixelFormat::Rgba8,
+            dirty_fraction: None,
+            sequence: 0,
+            scale_to: None,
+        }
+    }
+
+    fn encode_result(width: u32, height: u32) -> EncodeResult {
+        EncodeResult {
+            sample_data: vec![0; 4],
+            is_keyframe: true,
+            keyframe_reason: Some(KeyframeReason::ResolutionChange),
+            duration: std::time::Duration::from_millis(33),
+            width,
+            height,
+            capture_timestamp: 0,
+            average_qp: None,
+            sequence: 0,
+        }
+    }
+
+    /// テスト用に起動したフレームルーター
+    struct TestRouter {
+        frame_tx: mpsc::Sender<Frame>,
+        slot: Arc<EncodeJobSlot>,
+        factory: Arc<RecordingFactory>,
+        jobs_queued: Arc<AtomicU64>,
+        keyframe_request: Arc<KeyframeRequest>,
+        max_bitrate_bps: Arc<AtomicU32>,
+        replace_slot_tx: mpsc::UnboundedSender<Arc<EncodeJobSlot>>,
+        replace_result_rx: mpsc::UnboundedReceiver<mpsc::UnboundedReceiver<EncodeResult>>,
+        router: tokio::task::JoinHandle<()>,
+    }
+
+    impl TestRouter {
+        fn start() -> Self {
+            Self::start_with_gop_aligned_resize(false)
+        }
+
+        fn start_with_gop_aligned_resize(gop_aligned_resize: bool) -> Self {
+            let (frame_tx, frame_rx) = mpsc::channel(4);
+            let (replace_slot_tx, replace_slot_rx) = mpsc::unbounded_channel();
+            let (replace_result_tx, replace_result_rx) = mpsc::unbounded_channel();
+            let jobs_queued = Arc::new(AtomicU64::new(0));
+            let factory = Arc::new(RecordingFactory {
+                slots: Mutex::new(Vec::new()),
+                results: Mutex::new(Vec::new()),
+            });
+            let slot = EncodeJobSlot::new();
+            let keyframe_request = Arc::new(KeyframeRequest::default());
+            let max_bitrate_bps = Arc::new(AtomicU32::new(0));
+            let encoder_control = EncoderControl {
+                replace_slot_rx,
+                replace_result_tx,
+                jobs_queued: jobs_queued.clone(),
+                source_changes: Arc::new(AtomicU64::new(0)),
+                drop_counters: Arc::new(DropCounters::default()),
+                video_dump: None,
+                debug_overlay: None,
+                capture_clock: Arc::new(CaptureClock::default()),
+                gop_aligned_resize,
+                max_bitrate_bps: max_bitrate_bps.clone(),
+            };
+
+            let router = tokio::spawn(run_frame_router(
+                frame_rx,
+                slot.clone(),
+                factory.clone(),
+                Arc::new(AtomicBool::new(true)),
+                keyframe_request.clone(),
+                Arc::new(AtomicBool::new(false)),
+                encoder_control,
+            ));
+            Self {
+                frame_tx,
+                slot,
+                factory,
+                jobs_queued,
+                keyframe_request,
+                max_bitrate_bps,
+                replace_slot_tx,
+                replace_result_rx,
+                router,
+            }
+        }
+
+        /// フレームを 1 枚流し、ルーターが受け取って処理し終えるまで待つ
+        /// （まとめて送ると古いフレームとして捨てられてしまう）
+        async fn send(&self, frame: Frame) {
+            self.frame_tx.send(frame).await.unwrap();
+            while self.frame_tx.capacity() < self.frame_tx.max_capacity() {
+                tokio::task::yield_now().await;
+            }
+        }
+
+        fn jobs_queued(&self) -> u64 {
+            self.jobs_queued.load(Ordering::Relaxed)
+        }
+
+        async fn stop(self) {
+            drop(self.frame_tx);
+            self.router.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zero_size_frame_is_dropped() {
+        let router = TestRouter::start();
+
+        // 最小化されたウィンドウの 0x0 フレームはエンコーダーに渡らない
+        router.send(frame(0, 0)).await;
+        assert_eq!(router.jobs_queued(), 0);
+
+        router.send(frame(4, 2)).await;
+        assert_eq!(router.jobs_queued(), 1);
+        let job = router.slot.try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height), (4, 2));
+
+        // 途中で最小化されても、最後のフレームのまま解像度変更としては扱わない
+        router.send(frame(0, 0)).await;
+        assert_eq!(router.jobs_queued(), 1);
+        assert!(router.slot.try_take().is_none());
+        assert!(router.factory.slots.lock().unwrap().is_empty());
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_frame_is_skipped_unless_keyframe_requested() {
+        let router = TestRouter::start();
+        let dirty = |dirty_fraction| Frame {
+            dirty_fraction,
+            ..frame(4, 2)
+        };
+
+        router.send(dirty(Some(1.0))).await;
+        assert_eq!(router.jobs_queued(), 1);
+        router.slot.try_take().unwrap().unwrap();
+
+        // ダーティ領域がなければ送らない
+        router.send(dirty(Some(0.0))).await;
+        assert_eq!(router.jobs_queued(), 1);
+        // ダーティ領域が小さくても、取得していなくても送る
+        router.send(dirty(Some(0.001))).await;
+        router.send(dirty(None)).await;
+        assert_eq!(router.jobs_queued(), 3);
+        router.slot.try_take().unwrap().unwrap();
+
+        // キーフレーム要求には同じ内容のフレームで応える
+        router.keyframe_request.request(KeyframeReason::Pli);
+        router.send(dirty(Some(0.0))).await;
+        assert_eq!(router.jobs_queued(), 4);
+        let job = router.slot.try_take().unwrap().unwrap();
+        assert_eq!(job.request_keyframe, Some(KeyframeReason::Pli));
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_jobs_carry_the_negotiated_max_bitrate() {
+        let router = TestRouter::start();
+
+        router.send(frame(4, 2)).await;
+        assert_eq!(
+            router.slot.try_take().unwrap().unwrap().max_bitrate_bps,
+            None
+        );
+
+        // 接続先が帯域を示したら、次のジョブから上限を付ける
+        router.max_bitrate_bps.store(1_000_000, Ordering::Relaxed);
+        router.send(frame(4, 2)).await;
+        assert_eq!(
+            router.slot.try_take().unwrap().unwrap().max_bitrate_bps,
+            Some(1_000_000)
+        );
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_restarted_encoder_starts_with_a_keyframe() {
+        let router = TestRouter::start();
+        let unchanged = |width, height| Frame {
+            dirty_fraction: Some(0.0),
+            ..frame(width, height)
+        };
+
+        router.send(frame(4, 2)).await;
+        router.slot.try_take().unwrap().unwrap();
+
+        // 続けて再起動しても最後のワーカーだけが残り、変化のないフレームでもキーフレームを出す
+        let first = EncodeJobSlot::new();
+        let second = EncodeJobSlot::new();
+        router.replace_slot_tx.send(first.clone()).unwrap();
+        router.replace_slot_tx.send(second.clone()).unwrap();
+        router.send(unchanged(4, 2)).await;
+        assert!(matches!(router.slot.try_take(), Some(Err(_))));
+        assert!(matches!(first.try_take(), Some(Err(_))));
+        let job = second.try_take().unwrap().unwrap();
+        assert_eq!(job.request_keyframe, Some(KeyframeReason::EncoderRestart));
+        router.send(unchanged(4, 2)).await;
+        assert!(second.try_take().is_none());
+
+        // 解像度の変更と重なっても、差し替えてから作り直すので新しい解像度のキーフレームになる
+        let third = EncodeJobSlot::new();
+        router.replace_slot_tx.send(third.clone()).unwrap();
+        router.send(frame(8, 4)).await;
+        assert!(matches!(second.try_take(), Some(Err(_))));
+        assert!(matches!(third.try_take(), Some(Err(_))));
+        let slots = router.factory.slots.lock().unwrap().clone();
+        assert_eq!(slots.len(), 1);
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height), (8, 4));
+        assert!(job.request_keyframe.is_some());
+        assert_eq!(router.keyframe_request.take(), None);
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_gop_aligned_resize_closes_gop_before_switching() {
+        let mut router = TestRouter::start_with_gop_aligned_resize(true);
+        let frame_at = |width, height, windows_timespan| Frame {
+            windows_timespan,
+            ..frame(width, height)
+        };
+
+        router.send(frame_at(4, 2, 1)).await;
+        router.slot.try_take().unwrap().unwrap();
+
+        // 変更を検出したフレームでは、古いエンコーダーに最後のフレームをキーフレームとして渡す
+        router.send(frame_at(8, 4, 2)).await;
+        assert!(router.factory.slots.lock().unwrap().is_empty());
+        assert!(router.replace_result_rx.try_recv().is_err());
+        let job = router.slot.try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height, job.timestamp), (4, 2, 2));
+        assert_eq!(job.request_keyframe, Some(KeyframeReason::ResolutionChange));
+
+        // 次のフレームで新しい解像度のエンコーダーに切り替え、キーフレームから始める
+        router.send(frame_at(8, 4, 3)).await;
+        let slots = router.factory.slots.lock().unwrap().clone();
+        assert_eq!(slots.len(), 1);
+        assert!(matches!(router.slot.try_take(), Some(Err(_))));
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height), (8, 4));
+        assert_eq!(job.request_keyframe, Some(KeyframeReason::ResolutionChange));
+
+        // 切り替えた後の結果は新しいワーカーの結果チャネルから届く
+        let mut result_rx = router.replace_result_rx.try_recv().unwrap();
+        let result_tx = router.factory.results.lock().unwrap()[0].clone();
+        result_tx.send(encode_result(8, 4)).unwrap();
+        assert_eq!(result_rx.recv().await.unwrap().width, 8);
+
+        // 切り替えを待っている間に元に戻ったら作り直さない
+        router.send(frame_at(2, 2, 4)).await;
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height), (8, 4));
+        router.send(frame_at(8, 4, 5)).await;
+        router.send(frame_at(2, 2, 6)).await;
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height, job.timestamp), (8, 4, 6));
+        assert_eq!(router.factory.slots.lock().unwrap().len(), 1);
+        assert!(router.replace_result_rx.try_recv().is_err());
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_resize_hands_over_the_new_encode_results() {
+        let mut router = TestRouter::start();
+
+        router.send(frame(4, 2)).await;
+        router.slot.try_take().unwrap().unwrap();
+        assert!(router.replace_result_rx.try_recv().is_err());
+
+        // 作り直したワーカーの結果チャネルが、古いワーカーを止める前に渡される
+        router.send(frame(8, 4)).await;
+        let mut result_rx = router.replace_result_rx.try_recv().unwrap();
+        assert!(matches!(router.slot.try_take(), Some(Err(_))));
+        let slots = router.factory.slots.lock().unwrap().clone();
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height), (8, 4));
+        let result_tx = router.factory.results.lock().unwrap()[0].clone();
+        result_tx.send(encode_result(8, 4)).unwrap();
+        let received = result_rx.recv().await.unwrap();
+        assert_eq!((received.width, received.height), (8, 4));
+
+        // もう一度変わっても、その都度新しいチャネルが届く
+        router.send(frame(16, 8)).await;
+        let mut result_rx = router.replace_result_rx.try_recv().unwrap();
+        let result_tx = router.factory.results.lock().unwrap()[1].clone();
+        result_tx.send(encode_result(16, 8)).unwrap();
+        assert_eq!(result_rx.recv().await.unwrap().width, 16);
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_fps_change_hands_over_the_new_encode_results() {
+        let mut router = TestRouter::start();
+        let frame_at_fps = |fps| Frame { fps, ..frame(4, 2) };
+
+        router.send(frame_at_fps(30)).await;
+        router.slot.try_take().unwrap().unwrap();
+
+        // 同じ解像度でもフレームレートが変われば作り直し、新しいワーカーの結果を受け取れる
+        router.send(frame_at_fps(60)).await;
+        let mut result_rx = router.replace_result_rx.try_recv().unwrap();
+        let slots = router.factory.slots.lock().unwrap().clone();
+        assert_eq!(slots.len(), 1);
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!(job.fps, 60);
+        assert_eq!(job.request_keyframe, Some(KeyframeReason::ResolutionChange));
+        let result_tx = router.factory.results.lock().unwrap()[0].clone();
+        result_tx.send(encode_result(4, 2)).unwrap();
+        assert!(result_rx.recv().await.is_some());
+
+        // 同じフレームレートのままなら作り直さない
+        router.send(frame_at_fps(60)).await;
+        assert!(router.replace_result_rx.try_recv().is_err());
+        assert_eq!(router.factory.slots.lock().unwrap().len(), 1);
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_switch_from_placeholder_to_capture_forces_a_keyframe() {
+        let router = TestRouter::start();
+        // プレースホルダーは低いフレームレートで、対象を選ぶと実際のキャプチャのサイズに変わる
+        let placeholder = Frame {
+            fps: 5,
+            ..frame(8, 4)
+        };
+
+        router.send(placeholder.clone()).await;
+        router.send(placeholder).await;
+        router.slot.try_take().unwrap().unwrap();
+
+        router.send(frame(16, 10)).await;
+        let slots = router.factory.slots.lock().unwrap().clone();
+        let job = slots[0].try_take().unwrap().unwrap();
+        assert_eq!((job.width, job.height, job.fps), (16, 10, 30));
+        assert_eq!(job.request_keyframe, Some(KeyframeReason::ResolutionChange));
+
+        router.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_background_throttle_round_trip_recreates_the_encoder_at_each_fps() {
+        let router = TestRouter::start();
+        let frame_at_fps = |fps| Frame { fps, ..frame(4, 2) };
+
+        router.send(frame_at_fps(60)).await;
+        router.slot.try_take().unwrap().unwrap();
+
+        // 背面に回って下げ、前面に戻って上げるたびに、その fps でエンコーダーを作り直す
+        for (index, fps) in [5, 60].into_iter().enumerate() {
+            router.send(frame_at_fps(fps)).await;
+            let slot = router.factory.slots.lock().unwrap()[index].clone();
+            let job = slot.try_take().unwrap().unwrap();
+            assert_eq!(job.fps, fps);
+            assert_eq!(job.request_keyframe, Some(KeyframeReason::ResolutionChange));
+        }
+        // 前のワーカーは止まっている
+        let slots = router.factory.slots.lock().unwrap().clone();
+        assert!(matches!(slots[0].try_take(), Some(Err(_))));
+
+        router.stop().await;
+    }
+
+    #[test]
+    fn test_resize_deferral() {
+        let mut deferral = ResizeDeferral::default();
+        assert!(deferral.defer((8, 4, 30)));
+        assert!(!deferral.defer((8, 4, 30)));
+        // 待っている間にさらに変わったら、その形式で待ち直す
+        assert!(deferral.defer((8, 4, 30)));
+        assert!(deferral.defer((16, 8, 30)));
+        assert!(!deferral.defer((16, 8, 30)));
+        // 元に戻ったら待っていた変更は忘れる
+        assert!(deferral.defer((8, 4, 60)));
+        deferral.cancel();
+        assert!(deferral.defer((8, 4, 60)));
+    }
+}
@@ -0,0 +1,190 @@
+// 映像のフリーズ検出
+//
+// 障害の後にエンコーダーが同じ（かほぼ空の）出力を出し続け、エラーもなく画面が止まることがある。
+// 出力が途絶えるケースはウォッチドッグが拾うが、出力はあるのに中身が古いケースはここで拾う。
+// 静止画面ではエンコーダーが同じスキップフレームを出すのが正常なので、キャプチャの内容が
+// 変わっているのに出力が変わらないフレームだけを数え、一定数続いたらフリーズとみなす。
+// 対処はキーフレーム要求 → エンコーダーの再生成（上限まで）の順。
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use tracing::{error, info, warn};
+
+/// フリーズとみなす連続フレーム数（デフォルト）
+pub const DEFAULT_FREEZE_FRAMES: u32 = 60;
+/// これより小さい出力は中身がない（全ブロックスキップ）とみなす（デフォルト）
+pub const DEFAULT_FREEZE_MIN_BYTES: usize = 32;
+/// キャプチャ内容の比較に使う画素のサンプル数
+const FINGERPRINT_SAMPLES: usize = 1024;
+
+/// フリーズに対する対処
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreezeAction {
+    RequestKeyframe,
+    /// エンコーダーを作り直す（`attempt` 回目）
+    RecreateEncoder {
+        attempt: u32,
+    },
+}
+
+#[derive(Debug)]
+pub struct FreezeDetector {
+    threshold_frames: u32,
+    min_bytes: usize,
+    max_recreations: u32,
+    last_output: Option<u64>,
+    /// キャプチャ内容が変わったのに出力が変わらなかった連続フレーム数
+    stale_frames: u32,
+    /// 0: 未対応, 1: キーフレーム要求済み, 2以降: エンコーダー再生成済み
+    stage: u32,
+}
+
+impl FreezeDetector {
+    pub fn new(threshold_frames: u32, min_bytes: usize, max_recreations: u32) -> Self {
+        Self {
+            threshold_frames: threshold_frames.max(1),
+            min_bytes,
+            max_recreations,
+            last_output: None,
+            stale_frames: 0,
+            stage: 0,
+        }
+    }
+
+    /// エンコード結果 `sample` を記録し、必要な対処を返す
+    /// `source_changed` は前のエンコード結果以降にキャプチャの内容が変わったか
+    pub fn record(&mut self, sample: &[u8], source_changed: bool) -> Option<FreezeAction> {
+        let output = fingerprint(sample);
+        let stale = sample.len() < self.min_bytes || self.last_output == Some(output);
+        self.last_output = Some(output);
+
+        if !stale {
+            if self.stage > 0 {
+                info!("Video output is changing again after freeze recovery");
+            }
+            self.stale_frames = 0;
+            self.stage = 0;
+            return None;
+        }
+        if !source_changed {
+            // 静止画面では同じ出力が正常
+            return None;
+        }
+        self.stale_frames += 1;
+        if self.stale_frames < self.threshold_frames {
+            return None;
+        }
+        self.stale_frames = 0;
+
+        let action = match self.stage {
+            0 => {
+                warn!(
+                    "Video output unchanged for {} frames while the capture is changing, requesting keyframe",
+                    self.threshold_frames
+                );
+                FreezeAction::RequestKeyframe
+            }
+            stage if stage <= self.max_recreations => {
+                warn!(
+                    "Video output still frozen, recreating encoder worker (retry {}/{})",
+                    stage, self.max_recreations
+                );
+                FreezeAction::RecreateEncoder { attempt: stage }
+            }
+            _ => {
+                if self.stage == self.max_recreations + 1 {
+                    error!(
+                        "Video output still frozen after {} encoder recreations, giving up",
+                        self.max_recreations
+                    );
+                    self.stage += 1;
+                }
+                return None;
+            }
+        };
+        self.stage += 1;
+        Some(action)
+    }
+}
+
+/// キャプチャしたフレームの内容の指紋（全画素ではなく等間隔のサンプルから計算する）
+pub fn frame_fingerprint(data: &[u8]) -> u64 {
+    let pixels = data.len() / 4;
+    let mut hasher = DefaultHasher::new();
+    let step = (pixels / FINGERPRINT_SAMPLES).max(1);
+    for i in (0..pixels).step_by(step) {
+        data[i * 4..i * 4 + 4].hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn fingerprint(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(i: u32) -> Vec<u8> {
+        let mut data = vec![0xab; 256];
+        data[..4].copy_from_slice(&i.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_identical_output_while_capture_changes_escalates() {
+        let mut detector = FreezeDetector::new(3, 32, 1);
+        let stale = frame(0);
+        let mut actions = Vec::new();
+        for _ in 0..12 {
+            actions.extend(detector.record(&stale, true));
+        }
+        // 最初の 1 枚は前の出力と違うので数えない
+        assert_eq!(
+            actions,
+            vec![
+                FreezeAction::RequestKeyframe,
+                FreezeAction::RecreateEncoder { attempt: 1 },
+            ]
+        );
+
+        // 出力が変わったらやり直す
+        assert_eq!(detector.record(&frame(1), true), None);
+        let actions: Vec<_> = (0..4)
+            .filter_map(|_| detector.record(&frame(2), true))
+            .collect();
+        assert_eq!(actions, vec![FreezeAction::RequestKeyframe]);
+    }
+
+    #[test]
+    fn test_tiny_output_counts_as_frozen() {
+        let mut detector = FreezeDetector::new(2, 32, 3);
+        assert_eq!(detector.record(&[0x41, 0x9a], true), None);
+        assert_eq!(
+            detector.record(&[0x41, 0x9b], true),
+            Some(FreezeAction::RequestKeyframe)
+        );
+    }
+
+    #[test]
+    fn test_static_capture_is_not_a_freeze() {
+        let mut detector = FreezeDetector::new(2, 32, 3);
+        let stale = frame(0);
+        for _ in 0..100 {
+            assert_eq!(detector.record(&stale, false), None);
+        }
+    }
+
+    #[test]
+    fn test_frame_fingerprint_detects_changes() {
+        let a = vec![0u8; 1920 * 1080 * 4];
+        let mut b = a.clone();
+        assert_eq!(frame_fingerprint(&a), frame_fingerprint(&b));
+        // サンプルする画素（先頭）の変化
+        b[0] = 1;
+        assert_ne!(frame_fingerprint(&a), frame_fingerprint(&b));
+        assert_eq!(frame_fingerprint(&[]), frame_fingerprint(&[1, 2]));
+    }
+}
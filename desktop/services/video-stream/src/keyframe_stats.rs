@@ -0,0 +1,126 @@
+// キーフレームの要求理由ごとの集計
+//
+// 「ビットレートが跳ねる」という報告の調査用に、送出したキーフレームを要求の理由
+// （PLI・接続・解像度変更など）ごとに数え、1 分ごとにログとクライアントへの統計に出す。
+// 要求はフレームルーターが次のジョブに載せるまでに重なることがあるため、保留中の理由をビットで持つ。
+
+use core_types::{EncodeResult, KeyframeCounts, KeyframeReason};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// キーフレーム数を集計してログに出す間隔
+pub const KEYFRAME_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// VideoStreamService とフレームルーターで共有するキーフレーム要求
+#[derive(Debug, Default)]
+pub struct KeyframeRequest {
+    pending: AtomicU32,
+}
+
+impl KeyframeRequest {
+    pub fn request(&self, reason: KeyframeReason) {
+        self.pending.fetch_or(1 << reason as u32, Ordering::Relaxed);
+    }
+
+    /// 取り出されていない要求があるか
+    pub fn is_pending(&self) -> bool {
+        self.pending.load(Ordering::Relaxed) != 0
+    }
+
+    /// 保留中の要求を取り出す（重なっていた場合は `KeyframeReason::ALL` で先にある理由を返す）
+    pub fn take(&self) -> Option<KeyframeReason> {
+        let pending = self.pending.swap(0, Ordering::Relaxed);
+        KeyframeReason::ALL
+            .into_iter()
+            .find(|reason| pending & (1 << *reason as u32) != 0)
+    }
+}
+
+/// 送出したキーフレームを区間ごとに集計する
+#[derive(Debug, Default)]
+pub struct KeyframeWindow {
+    current: KeyframeCounts,
+    last_minute: KeyframeCounts,
+}
+
+impl KeyframeWindow {
+    pub fn record(&mut self, result: &EncodeResult) {
+        if result.is_keyframe {
+            self.current.record(result.keyframe_reason);
+        }
+    }
+
+    /// 区間を締めて、締めた区間の集計を返す
+    pub fn rotate(&mut self) -> &KeyframeCounts {
+        self.last_minute = std::mem::take(&mut self.current);
+        &self.last_minute
+    }
+
+    /// 直前に締めた区間の集計（クライアントへの統計に載せる）
+    pub fn last_minute(&self) -> &KeyframeCounts {
+        &self.last_minute
+    }
+}
+
+/// ログ用の要約（0 件の理由は省く）
+pub fn summarize(counts: &KeyframeCounts) -> String {
+    let reasons: Vec<String> = KeyframeReason::ALL
+        .into_iter()
+        .map(Some)
+        .chain([None])
+        .filter_map(|reason| {
+            let count = counts.get(reason);
+            let name = reason.map_or_else(|| "periodic".to_string(), |r| r.to_string());
+            (count > 0).then(|| format!("{}={}", name, count))
+        })
+        .collect();
+    format!("{} ({})", counts.total(), reasons.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(is_keyframe: bool, keyframe_reason: Option<KeyframeReason>) -> EncodeResult {
+        EncodeResult {
+            sample_data: vec![0, 0, 0, 1, 0x65],
+            is_keyframe,
+            keyframe_reason,
+            duration: Duration::from_millis(16),
+            width: 2,
+            height: 2,
+            capture_timestamp: 0,
+            average_qp: None,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_request_coalesces_reasons() {
+        let request = KeyframeRequest::default();
+        assert_eq!(request.take(), None);
+
+        request.request(KeyframeReason::Reconnect);
+        request.request(KeyframeReason::FirstFrame);
+        // 重なった要求は 1 回のキーフレームにまとめられる
+        assert_eq!(request.take(), Some(KeyframeReason::FirstFrame));
+        assert_eq!(request.take(), None);
+    }
+
+    #[test]
+    fn test_window_counts_keyframes_by_reason() {
+        let mut window = KeyframeWindow::default();
+        window.record(&result(true, Some(KeyframeReason::Pli)));
+        window.record(&result(true, Some(KeyframeReason::Pli)));
+        window.record(&result(true, None));
+        window.record(&result(false, None));
+
+        let counts = window.rotate();
+        assert_eq!((counts.pli, counts.periodic), (2, 1));
+        assert_eq!(summarize(counts), "3 (pli=2, periodic=1)");
+
+        // 次の区間は 0 から数え直す
+        assert_eq!(window.rotate().total(), 0);
+        assert_eq!(window.last_minute().total(), 0);
+    }
+}
@@ -0,0 +1,104 @@
+// 遅れて届いたエンコード結果の破棄（遅延の上限）
+//
+// GPU が一時的に詰まると、古いフレームのエンコード結果がまとめて届くことがある。
+// そのまま書き込むとブラウザは早送りで追いつこうとするため、キャプチャから一定時間以上経った
+// フレームは捨てて現在の映像に飛ばす。差分フレームを 1 枚でも捨てると以降のフレームが正しく
+// 復号できないので、次のキーフレームまでは続けて捨て、キーフレームを要求する。
+// キーフレームは復号の起点になるので、遅れていても捨てない。
+
+use std::time::Duration;
+
+/// エンコード結果の扱い
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LateFrameAction {
+    Write,
+    /// 捨てる（`request_keyframe` が true なら捨て始めたところなのでキーフレームを要求する）
+    Drop {
+        request_keyframe: bool,
+    },
+}
+
+#[derive(Debug)]
+pub struct LateFrameFilter {
+    max_age: Duration,
+    /// 次のキーフレームまで捨てている
+    dropping: bool,
+    /// 捨て始めてから捨てた数
+    dropped: u64,
+}
+
+impl LateFrameFilter {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            dropping: false,
+            dropped: 0,
+        }
+    }
+
+    /// `age` はキャプチャからの経過時間（分からない場合は None で、遅れていないものとして扱う）
+    pub fn check(&mut self, is_keyframe: bool, age: Option<Duration>) -> LateFrameAction {
+        if is_keyframe {
+            self.dropping = false;
+            return LateFrameAction::Write;
+        }
+        if self.dropping {
+            self.dropped += 1;
+            return LateFrameAction::Drop {
+                request_keyframe: false,
+            };
+        }
+        if age.is_some_and(|age| age > self.max_age) {
+            self.dropping = true;
+            self.dropped = 1;
+            return LateFrameAction::Drop {
+                request_keyframe: true,
+            };
+        }
+        LateFrameAction::Write
+    }
+
+    /// 直前の破棄で捨てた数を取り出す（キーフレームで復帰した後にログに出す）
+    pub fn take_dropped(&mut self) -> Option<u64> {
+        if self.dropping || self.dropped == 0 {
+            return None;
+        }
+        Some(std::mem::take(&mut self.dropped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drops_stale_frames_until_keyframe() {
+        let mut filter = LateFrameFilter::new(Duration::from_millis(100));
+        let fresh = Some(Duration::from_millis(20));
+        let stale = Some(Duration::from_millis(300));
+
+        assert_eq!(filter.check(false, fresh), LateFrameAction::Write);
+        // 遅れた差分フレームは捨ててキーフレームを要求する
+        assert_eq!(
+            filter.check(false, stale),
+            LateFrameAction::Drop {
+                request_keyframe: true
+            }
+        );
+        // 以降の差分フレームは新しくてもキーフレームまで捨てる（参照先が欠けているため）
+        assert_eq!(
+            filter.check(false, fresh),
+            LateFrameAction::Drop {
+                request_keyframe: false
+            }
+        );
+        assert_eq!(filter.take_dropped(), None);
+
+        // キーフレームは遅れていても書き込み、そこから復帰する
+        assert_eq!(filter.check(true, stale), LateFrameAction::Write);
+        assert_eq!(filter.take_dropped(), Some(2));
+        assert_eq!(filter.take_dropped(), None);
+        assert_eq!(filter.check(false, fresh), LateFrameAction::Write);
+        assert_eq!(filter.check(false, None), LateFrameAction::Write);
+    }
+}
@@ -1,22 +1,105 @@
+mod abs_capture_time;
+mod connect_buffer;
+mod debug_overlay;
+mod drop_stats;
+mod encoder_load;
+mod encoder_restart;
+mod fmp4;
 mod frame_processor;
+mod freeze_detector;
+mod keyframe_stats;
+mod late_frames;
+mod mp4;
+mod pacer;
+mod quality_ladder;
+mod recorder;
+mod replay;
+mod startup_keyframes;
 mod track_writer;
+mod video_dump;
+
+pub use encoder_load::DEFAULT_ENCODER_QUEUE_BUDGET;
+pub use freeze_detector::{DEFAULT_FREEZE_FRAMES, DEFAULT_FREEZE_MIN_BYTES};
+pub use quality_ladder::{QualityLadder, QualityRung, DEFAULT_QUALITY_LADDER};
+pub use startup_keyframes::{DEFAULT_STARTUP_KEYFRAME_COUNT, DEFAULT_STARTUP_KEYFRAME_SPACING};
+pub use track_writer::DEFAULT_PAUSE_HEARTBEAT_FPS;
+pub use video_dump::read_video_dump;
 
 use anyhow::Result;
-use core_types::{Frame, VideoEncoderFactory, VideoStreamMessage};
-use std::sync::atomic::{AtomicBool, Ordering};
+use core_types::{
+    AudioEncodeResult, CaptureMessage, CaptureSize, DataChannelMessage, Frame, KeyframeReason,
+    Metrics, OutgoingDataChannelMessage, ServiceError, VideoEncoderFactory, VideoStatsPayload,
+    VideoStreamMessage,
+};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::mpsc;
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
 use webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender;
 use webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 
+/// エンコード結果が途絶えたとみなすまでの時間（デフォルト）
+const DEFAULT_ENCODE_STALL_TIMEOUT: Duration = Duration::from_secs(3);
+/// エンコーダー再生成を試みる回数（デフォルト）
+const DEFAULT_ENCODE_STALL_MAX_RETRIES: u32 = 3;
+/// ドロップ統計の集計・送信間隔
+const DROP_STATS_INTERVAL: Duration = Duration::from_secs(5);
+/// 接続確立中に保持したエンコード結果がある間、接続完了を確認する間隔
+const CONNECT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// 接続直後の追加のキーフレームを待っている間、接続完了と要求時刻を確認する間隔
+const STARTUP_KEYFRAME_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
 /// VideoStreamService
 /// 責務: ビデオフレーム受信 → エンコード → ビデオトラック書き込み
 pub struct VideoStreamService {
     frame_rx: mpsc::Receiver<Frame>,
     video_encoder_factory: Arc<dyn VideoEncoderFactory>,
     video_stream_msg_rx: mpsc::Receiver<VideoStreamMessage>,
+    encode_stall_timeout: Duration,
+    encode_stall_max_retries: u32,
+    /// インスタントリプレイ (保持する秒数, 保存先ディレクトリ)
+    replay: Option<(Duration, PathBuf)>,
+    /// ドロップ統計をクライアントに送る DataChannel
+    stats_tx: Option<mpsc::Sender<OutgoingDataChannelMessage>>,
+    /// 送出ペーシング (目標ビットレート bps, 1 サンプルあたりの最大待ち時間)
+    pacing: Option<(u32, Duration)>,
+    /// エンコーダーに渡したフレームのダンプ（デバッグ用）
+    video_dump: Option<video_dump::VideoDump>,
+    /// 接続確立中に保持するエンコード結果の長さ
+    connect_buffer_duration: Duration,
+    /// キャプチャからこれ以上経ったフレームは捨てる（None なら捨てない）
+    max_frame_age: Option<Duration>,
+    /// 接続時に送るキーフレームの数と、追加分の間隔
+    startup_keyframes: (u32, Duration),
+    /// フリーズ検出 (連続フレーム数, 中身がないとみなすバイト数)（None で無効）
+    freeze_detection: Option<(u32, usize)>,
+    /// 録画 (StartRecording で録画を始めるディレクトリ, 起動時から録画するファイル)
+    recording: Option<(PathBuf, Option<PathBuf>)>,
+    /// 録画に含める音声のエンコード結果（AudioStreamService から分岐したもの）
+    recording_audio_rx: Option<mpsc::Receiver<AudioEncodeResult>>,
+    /// 監視用のメトリクス（hostd の /metrics で公開する）
+    metrics: Arc<Metrics>,
+    /// 解像度・フレームレートの変更を次のフレームまで待ち、古いエンコーダーでキーフレームを出してから切り替える
+    gop_aligned_resize: bool,
+    /// 品質ラダー (ラダー, 段を変えたときに設定を送るキャプチャサービス)（None で無効）
+    quality_ladder: Option<(
+        QualityLadder,
+        CaptureSize,
+        u32,
+        mpsc::Sender<CaptureMessage>,
+    )>,
+    /// キャプチャ側が前面にないウィンドウのフレームレートを下げている間に立つフラグ（品質ラダーが参照する）
+    capture_throttled: Option<Arc<AtomicBool>>,
+    /// 新しいトラックに最初のキーフレームが届くまで差分フレームを書き込まない
+    wait_for_first_keyframe: bool,
+    /// エンコーダーに渡すフレームに統計を焼き込む（デバッグ用）
+    debug_overlay: bool,
+    /// 一時停止中に最後のキーフレームを送り直すフレームレート（0 で無効）
+    pause_heartbeat_fps: u32,
+    /// エンコーダーの入力の待ち時間の予算（None で過負荷を検出しない）
+    encoder_queue_budget: Option<Duration>,
 }
 
 impl VideoStreamService {
@@ -31,9 +114,177 @@ impl VideoStreamService {
             frame_rx,
             video_encoder_factory,
             video_stream_msg_rx,
+            encode_stall_timeout: DEFAULT_ENCODE_STALL_TIMEOUT,
+            encode_stall_max_retries: DEFAULT_ENCODE_STALL_MAX_RETRIES,
+            replay: None,
+            stats_tx: None,
+            pacing: None,
+            video_dump: None,
+            connect_buffer_duration: connect_buffer::DEFAULT_CONNECT_BUFFER_DURATION,
+            max_frame_age: None,
+            startup_keyframes: (
+                startup_keyframes::DEFAULT_STARTUP_KEYFRAME_COUNT,
+                startup_keyframes::DEFAULT_STARTUP_KEYFRAME_SPACING,
+            ),
+            freeze_detection: Some((
+                freeze_detector::DEFAULT_FREEZE_FRAMES,
+                freeze_detector::DEFAULT_FREEZE_MIN_BYTES,
+            )),
+            recording: None,
+            recording_audio_rx: None,
+            metrics: Arc::new(Metrics::default()),
+            gop_aligned_resize: false,
+            quality_ladder: None,
+            capture_throttled: None,
+            wait_for_first_keyframe: true,
+            debug_overlay: false,
+            pause_heartbeat_fps: track_writer::DEFAULT_PAUSE_HEARTBEAT_FPS,
+            encoder_queue_budget: Some(encoder_load::DEFAULT_ENCODER_QUEUE_BUDGET),
         }
     }
 
+    /// エンコーダーのウォッチドッグ設定
+    /// ジョブを渡しているのに `timeout` の間エンコード結果が来なければ、まずキーフレームを要求し、
+    /// それでも来なければエンコーダーを最大 `max_retries` 回再生成する
+    pub fn with_encode_watchdog(mut self, timeout: Duration, max_retries: u32) -> Self {
+        self.encode_stall_timeout = timeout;
+        self.encode_stall_max_retries = max_retries;
+        self
+    }
+
+    /// 直近 `duration` 分のエンコード結果を保持し、SaveReplay で `dir` に MP4 として保存する
+    pub fn with_replay(mut self, duration: Duration, dir: PathBuf) -> Self {
+        self.replay = Some((duration, dir));
+        self
+    }
+
+    /// ドロップ統計を定期的に DataChannel (VIDEO_STATS) でクライアントに送る
+    pub fn with_stats_channel(
+        mut self,
+        stats_tx: mpsc::Sender<OutgoingDataChannelMessage>,
+    ) -> Self {
+        self.stats_tx = Some(stats_tx);
+        self
+    }
+
+    /// エンコード結果を即座に書き込まず、`bitrate_bps` に合わせてならしてから書き込む
+    /// 大きなサンプルの後は最大 `max_delay` だけ次の書き込みを待たせる
+    pub fn with_pacing(mut self, bitrate_bps: u32, max_delay: Duration) -> Self {
+        self.pacing = Some((bitrate_bps, max_delay));
+        self
+    }
+
+    /// エンコーダーに渡したフレーム（RGBA）を `path` に無圧縮で書き出す（デバッグ用）
+    /// 合計が `max_bytes` に達したら書き出しをやめる。読み出しは `read_video_dump`
+    pub fn with_video_dump(mut self, path: &std::path::Path, max_bytes: u64) -> Result<Self> {
+        self.video_dump = Some(video_dump::VideoDump::spawn(path, max_bytes)?);
+        Ok(self)
+    }
+
+    /// 接続確立中（ICE/DTLS 完了前）は直近のキーフレーム以降のエンコード結果を最大 `duration` 分保持し、
+    /// 接続完了と同時に書き込む（ZERO で保持せずに捨てる）
+    pub fn with_connect_buffer(mut self, duration: Duration) -> Self {
+        self.connect_buffer_duration = duration;
+        self
+    }
+
+    /// キャプチャから `max_age` 以上経ったエンコード結果を書き込まずに捨て、次のキーフレームから再開する
+    /// （GPU の一時的な詰まりの後に早送りで追いつくのを避ける。キーフレームは捨てない）
+    pub fn with_max_frame_age(mut self, max_age: Duration) -> Self {
+        self.max_frame_age = Some(max_age);
+        self
+    }
+
+    /// 接続時に `count` 枚のキーフレームを送る（2 枚目以降は接続完了から `spacing` ごと）
+    /// 最初の 1 枚が接続の確立中に失われても、PLI の往復を待たずに映像が出るようにする（1 で追加しない）
+    pub fn with_startup_keyframes(mut self, count: u32, spacing: Duration) -> Self {
+        self.startup_keyframes = (count.max(1), spacing);
+        self
+    }
+
+    /// キャプチャの内容が変わっているのに、同じ出力か `min_bytes` 未満の出力が `frames` 枚続いたら
+    /// フリーズとみなし、キーフレーム要求 → エンコーダーの再生成で復旧を試みる（`frames` が 0 で無効）
+    /// 再生成の回数の上限はウォッチドッグと共通（with_encode_watchdog の `max_retries`）
+    pub fn with_freeze_detection(mut self, frames: u32, min_bytes: usize) -> Self {
+        self.freeze_detection = (frames > 0).then_some((frames, min_bytes));
+        self
+    }
+
+    /// StartRecording で `dir` にセッションを fMP4 で録画できるようにする
+    /// `start_path` を指定すると起動時からそのファイルに録画する
+    pub fn with_recording(mut self, dir: PathBuf, start_path: Option<PathBuf>) -> Self {
+        self.recording = Some((dir, start_path));
+        self
+    }
+
+    /// 録画に音声を含める（`audio_rx` は録画していない間も読み捨てる）
+    pub fn with_recording_audio(mut self, audio_rx: mpsc::Receiver<AudioEncodeResult>) -> Self {
+        self.recording_audio_rx = Some(audio_rx);
+        self
+    }
+
+    /// エンコードとドロップの統計を `metrics` に書き込む
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 解像度・フレームレートの変更時に、古いエンコーダーで最後のフレームをキーフレームとして出してから
+    /// 次のフレームで切り替える（GOP の途中で切り替わると乱れるデコーダー向け。切り替えが 1 フレーム遅れる）
+    pub fn with_gop_aligned_resize(mut self, enabled: bool) -> Self {
+        self.gop_aligned_resize = enabled;
+        self
+    }
+
+    /// エンコーダーに渡すフレームの左上にフレーム番号・フレームレート・ビットレート・タイムスタンプを描く
+    /// （不具合の画面録画から該当のフレームを特定するためのデバッグ用）
+    pub fn with_debug_overlay(mut self, enabled: bool) -> Self {
+        self.debug_overlay = enabled;
+        self
+    }
+
+    /// 一時停止中も `fps` のレートで最後のキーフレームを送り直し、映像のトランスポートが閉じられないようにする
+    /// （一時停止の直後にキーフレームを要求する。0 で無効）
+    pub fn with_pause_heartbeat(mut self, fps: u32) -> Self {
+        self.pause_heartbeat_fps = fps;
+        self
+    }
+
+    /// エンコーダーがジョブを取り出すまでの平均の待ち時間が続けて `budget` を超えたら過負荷としてログに出し、
+    /// 品質ラダーが有効ならフレームレート・解像度を下げる（`budget` が 0 で無効）
+    pub fn with_encoder_queue_budget(mut self, budget: Duration) -> Self {
+        self.encoder_queue_budget = (!budget.is_zero()).then_some(budget);
+        self
+    }
+
+    /// `throttled` が立っている間（キャプチャ側が前面にないウィンドウのフレームレートを下げている間）は
+    /// 品質ラダーの段を上げない（下げたフレームレートで空いた帯域を余裕と誤解しないようにする）
+    pub fn with_capture_throttled(mut self, throttled: Arc<AtomicBool>) -> Self {
+        self.capture_throttled = Some(throttled);
+        self
+    }
+
+    /// 新しいトラックが接続されたら、最初のキーフレームが届くまでそのトラックに差分フレームを書き込まない
+    /// （途中から接続したビューアーが PLI の往復まで崩れた絵を出すのを避ける。デフォルトで有効）
+    pub fn with_wait_for_first_keyframe(mut self, enabled: bool) -> Self {
+        self.wait_for_first_keyframe = enabled;
+        self
+    }
+
+    /// ドロップ統計に応じて `ladder` の段を自動で上下させ、`capture_cmd_tx` にキャプチャ設定の更新を送る
+    /// （詰まりが続けば解像度・フレームレートを下げ、余裕が続けば上げる。起動時はキャプチャの設定
+    /// `size` と `fps` を超えない最上段）
+    pub fn with_quality_ladder(
+        mut self,
+        ladder: QualityLadder,
+        size: CaptureSize,
+        fps: u32,
+        capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+    ) -> Self {
+        self.quality_ladder = Some((ladder, size, fps, capture_cmd_tx));
+        self
+    }
+
     /// サービスを実行（ブロッキング）
     /// ビデオトラックとRTPSenderを受け取り、エンコード結果を書き込む
     pub async fn run(
@@ -47,14 +298,17 @@ impl VideoStreamService {
         info!("VideoStreamService started");
 
         // エンコーダーをセットアップ
+        let codec = self.video_encoder_factory.codec();
         let (encode_job_slot, mut encode_result_rx) = self.video_encoder_factory.setup();
 
-        // キーフレーム要求フラグ
-        let keyframe_requested = Arc::new(AtomicBool::new(false));
+        // キーフレーム要求（理由つき）
+        let keyframe_request = Arc::new(keyframe_stats::KeyframeRequest::default());
 
         // 現在のアクティブなトラック情報
         let mut current_video_track: Option<Arc<TrackLocalStaticSample>> = None;
         let mut current_connection_ready: Option<Arc<AtomicBool>> = None;
+        // 現在のトラックが最初のキーフレームを待っているか（トラックごとに作り直す）
+        let mut keyframe_gate = track_writer::KeyframeGate::new(false);
 
         // ビデオフレームをエンコーダーに転送するタスクをスポーン
         // Note: connection_ready はここでは直接渡さず、
@@ -129,30 +383,180 @@ impl VideoStreamService {
         // これなら frame_router の変更は最小限で済む（あるいは変更不要でダミーを渡す）。
         
         let global_encode_enable = Arc::new(AtomicBool::new(false)); // 初期値はfalse
-        let keyframe_requested_clone = keyframe_requested.clone();
+        let keyframe_request_for_router = keyframe_request.clone();
         
         // frame_router 用に clone
         let global_encode_enable_for_router = global_encode_enable.clone();
 
+        // 一時停止フラグ（接続は維持したままエンコーダーへの供給だけ止める）
+        let stream_paused = Arc::new(AtomicBool::new(false));
+        let stream_paused_for_router = stream_paused.clone();
+
+        // ウォッチドッグ用: ルーターが渡したジョブ数と、再生成したエンコーダーの受け渡し
+        let jobs_queued = Arc::new(AtomicU64::new(0));
+        let source_changes = Arc::new(AtomicU64::new(0));
+        let (replace_slot_tx, replace_slot_rx) = mpsc::unbounded_channel();
+        // ルーターが解像度の変更で作り直したエンコーダーの結果チャネル
+        let (replace_result_tx, mut replace_result_rx) = mpsc::unbounded_channel();
+        let drop_counters = Arc::new(drop_stats::DropCounters::default());
+        let capture_clock = Arc::new(abs_capture_time::CaptureClock::default());
+        // 接続ごとのビットレートの上限（SetMaxBitrate で更新し、ルーターがジョブに付ける）
+        let max_bitrate_bps = Arc::new(AtomicU32::new(0));
+        let encoder_control = frame_processor::EncoderControl {
+            replace_slot_rx,
+            replace_result_tx,
+            jobs_queued: jobs_queued.clone(),
+            source_changes: source_changes.clone(),
+            drop_counters: drop_counters.clone(),
+            video_dump: self.video_dump.take(),
+            capture_clock: capture_clock.clone(),
+            gop_aligned_resize: self.gop_aligned_resize,
+            debug_overlay: self
+                .debug_overlay
+                .then(|| debug_overlay::DebugOverlay::new(self.metrics.clone())),
+            max_bitrate_bps: max_bitrate_bps.clone(),
+        };
+
+        // エンコーダーの作り直し（要求・フリーズ検出・ウォッチドッグで共通）
+        let mut encoder_restarter = encoder_restart::EncoderRestarter::new(
+            self.video_encoder_factory.clone(),
+            replace_slot_tx,
+            jobs_queued.clone(),
+        );
         let frame_router_handle = tokio::spawn(async move {
             frame_processor::run_frame_router(
                 self.frame_rx,
                 encode_job_slot,
                 self.video_encoder_factory.clone(),
                 global_encode_enable_for_router, // エンコード可否はここで制御
-                keyframe_requested_clone,
+                keyframe_request_for_router,
+                stream_paused_for_router,
+                encoder_control,
             )
             .await
         });
 
         // 統計情報
         let mut first_encode_result_received = false;
-        let mut last_encode_result_wait_start = Instant::now();
-        let mut encode_result_timeout_warned = false;
+
+        // インスタントリプレイ用バッファ
+        let mut replay_buffer = self.replay.as_ref().map(|(duration, dir)| {
+            info!(
+                "Instant replay enabled: last {:?}, saved to {}",
+                duration,
+                dir.display()
+            );
+            replay::ReplayBuffer::new(*duration)
+        });
+
+        // ウォッチドッグ: 最後に結果を受け取った時点のジョブ数と、結果待ちが始まった時刻
+        let mut jobs_queued_at_last_result: u64 = 0;
+        let mut encode_stall_since: Option<Instant> = None;
+        // 0: 未対応, 1: キーフレーム要求済み, 2以降: エンコーダー再生成済み
+        let mut encode_stall_stage: u32 = 0;
+        let mut watchdog_interval = tokio::time::interval(Duration::from_secs(1));
+        // 出力はあるのに中身が変わらないフリーズの検出
+        let mut freeze_detector = self.freeze_detection.map(|(frames, min_bytes)| {
+            freeze_detector::FreezeDetector::new(frames, min_bytes, self.encode_stall_max_retries)
+        });
+        let mut source_changes_at_last_result: u64 = 0;
+
+        // 送出ペーシング
+        let mut pacer = self.pacing.map(|(bitrate_bps, max_delay)| {
+            info!(
+                "Video pacing enabled: {} kbps (max delay {:?})",
+                bitrate_bps / 1000,
+                max_delay
+            );
+            pacer::Pacer::new(bitrate_bps, max_delay)
+        });
+
+        // ドロップ統計
+        let mut drop_window = drop_stats::DropWindow::new();
+        let mut drop_stats_interval = tokio::time::interval(DROP_STATS_INTERVAL);
+        // メトリクスのレートを計算するための前回の区間の累計 (フレーム数, バイト数)
+        let mut encoded_at_last_stats = (0u64, 0u64);
+
+        // 品質ラダー（キャプチャの設定に収まる段から始め、設定と違う段ならキャプチャ設定を合わせる）
+        let mut quality_ladder =
+            self.quality_ladder
+                .take()
+                .map(|(ladder, size, fps, capture_cmd_tx)| {
+                    let controller =
+                        quality_ladder::LadderController::new(ladder, size.clone(), fps);
+                    let start = controller.current();
+                    info!("Quality ladder enabled, starting at {}", start);
+                    let configured =
+                        size == CaptureSize::Custom {
+                            width: start.width,
+                            height: start.height,
+                        } && fps == start.fps;
+                    if !configured {
+                        update_capture_quality(&capture_cmd_tx, start);
+                    }
+                    (controller, capture_cmd_tx)
+                });
+
+        // エンコーダーの入力の待ち時間による過負荷の検出
+        let mut queue_wait_monitor = self
+            .encoder_queue_budget
+            .map(encoder_load::QueueWaitMonitor::new);
+
+        // キーフレームの理由ごとの集計（最初の tick は 1 区間後）
+        let mut keyframe_window = keyframe_stats::KeyframeWindow::default();
+        let mut keyframe_stats_interval = tokio::time::interval_at(
+            tokio::time::Instant::now() + keyframe_stats::KEYFRAME_STATS_INTERVAL,
+            keyframe_stats::KEYFRAME_STATS_INTERVAL,
+        );
+
+        // 接続確立中のエンコード結果
+        let mut connect_buffer = connect_buffer::ConnectBuffer::new(self.connect_buffer_duration);
+        let mut connect_poll_interval = tokio::time::interval(CONNECT_POLL_INTERVAL);
+        // 遅れたフレームの破棄
+        let mut late_frame_filter = self.max_frame_age.map(|max_age| {
+            info!("Dropping video frames older than {:?}", max_age);
+            late_frames::LateFrameFilter::new(max_age)
+        });
+        connect_poll_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // 接続直後のキーフレームの追い打ち
+        let (startup_keyframe_count, startup_keyframe_spacing) = self.startup_keyframes;
+        let mut startup_keyframes = startup_keyframes::StartupKeyframes::new(
+            startup_keyframe_count,
+            startup_keyframe_spacing,
+        );
+        let mut startup_keyframe_interval = tokio::time::interval(STARTUP_KEYFRAME_POLL_INTERVAL);
+        startup_keyframe_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // 一時停止中のキーフレームの送り直し
+        let mut pause_heartbeat = track_writer::PauseHeartbeat::new(self.pause_heartbeat_fps);
+        let mut pause_heartbeat_interval = tokio::time::interval(
+            pause_heartbeat
+                .as_ref()
+                .map_or(Duration::from_secs(1), |heartbeat| {
+                    heartbeat.poll_interval()
+                }),
+        );
+        pause_heartbeat_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        // 録画（WebRTC の接続とは関係なく書き出す）
+        let mut recording_audio_rx = self.recording_audio_rx.take();
+        let record_audio = recording_audio_rx.is_some();
+        let mut recorder = None;
+        if let Some(path) = self.recording.as_ref().and_then(|(_, path)| path.as_ref()) {
+            match recorder::Recorder::spawn(path, record_audio) {
+                Ok(active) => {
+                    recorder = Some(active);
+                    keyframe_request.request(KeyframeReason::Recording);
+                }
+                Err(e) => warn!("Failed to start recording: {:#}", e),
+            }
+        }
 
         // RTCP読み込みタスクのハンドル（キャンセル用）
         let mut rtcp_drain_handle: Option<tokio::task::JoinHandle<()>> = None;
 
+        // hostd に返すエラー（クリーンアップ後に返す）
+        let mut exit_error: Option<anyhow::Error> = None;
+
         info!("VideoStreamService entered main loop");
 
         loop {
@@ -169,10 +573,16 @@ impl VideoStreamService {
                             }
 
                             // 新しいRTCPタスクを起動
+                            // Receiver Report のロス率はネットワーク側のドロップとして集計
                             let sender_for_rtcp = sender.clone();
+                            let drop_counters_for_rtcp = drop_counters.clone();
                             rtcp_drain_handle = Some(tokio::spawn(async move {
                                 let mut rtcp_buf = vec![0u8; 1500];
-                                while let Ok((_, _)) = sender_for_rtcp.read(&mut rtcp_buf).await {}
+                                while let Ok((packets, _)) = sender_for_rtcp.read(&mut rtcp_buf).await {
+                                    for packet in &packets {
+                                        drop_counters_for_rtcp.record_rtcp(packet.as_ref());
+                                    }
+                                }
                             }));
 
                             // 明示的な送信開始
@@ -187,6 +597,7 @@ impl VideoStreamService {
                             // ステート更新
                             current_video_track = Some(track);
                             current_connection_ready = Some(connection_ready);
+                            keyframe_gate = track_writer::KeyframeGate::new(self.wait_for_first_keyframe);
                             
                             // エンコードを有効化（再接続時は即座に有効化して良いとする）
                             // 本来は connection_ready を監視して true になったら有効化すべきだが、
@@ -196,7 +607,8 @@ impl VideoStreamService {
                             global_encode_enable.store(true, Ordering::Relaxed);
                             
                             // キーフレーム要求を出して、新しい接続に即座に絵が出るようにする
-                            keyframe_requested.store(true, Ordering::Relaxed);
+                            keyframe_request.request(KeyframeReason::Reconnect);
+                            startup_keyframes.start();
                         }
                         None => {
                             info!("Video track channel closed");
@@ -209,6 +621,17 @@ impl VideoStreamService {
                 result = encode_result_rx.recv() => {
                     match result {
                         Some(encode_result) => {
+                            let encoded_at = Instant::now();
+                            if let Some(age) = capture_clock.age(encode_result.capture_timestamp, SystemTime::now()) {
+                                drop_window.record_capture_to_encode(age);
+                            }
+                            jobs_queued_at_last_result = jobs_queued.load(Ordering::Relaxed);
+                            encode_stall_since = None;
+                            if encode_stall_stage > 0 {
+                                info!("Video encoder recovered after watchdog action");
+                                encode_stall_stage = 0;
+                            }
+
                             if !first_encode_result_received {
                                 info!(
                                     "First video encode result received: {} bytes, keyframe: {}",
@@ -216,26 +639,102 @@ impl VideoStreamService {
                                     encode_result.is_keyframe
                                 );
                                 first_encode_result_received = true;
-                                encode_result_timeout_warned = false;
+                            }
+                            keyframe_window.record(&encode_result);
+                            if let Some(heartbeat) = pause_heartbeat.as_mut() {
+                                heartbeat.record(&encode_result, Instant::now());
+                            }
+                            if let Some(detector) = freeze_detector.as_mut() {
+                                let source_changes = source_changes.load(Ordering::Relaxed);
+                                let source_changed = source_changes != source_changes_at_last_result;
+                                source_changes_at_last_result = source_changes;
+                                match detector.record(&encode_result.sample_data, source_changed) {
+                                    Some(freeze_detector::FreezeAction::RequestKeyframe) => {
+                                        keyframe_request.request(KeyframeReason::EncoderRestart);
+                                    }
+                                    Some(freeze_detector::FreezeAction::RecreateEncoder { .. }) => {
+                                        restart_encoder(
+                                            &mut encoder_restarter,
+                                            &mut encode_result_rx,
+                                            &mut pause_heartbeat,
+                                            &mut jobs_queued_at_last_result,
+                                            &mut encode_stall_since,
+                                        );
+                                    }
+                                    None => {}
+                                }
+                            }
+                            self.metrics.video_frames_encoded.fetch_add(1, Ordering::Relaxed);
+                            self.metrics
+                                .video_bytes_encoded
+                                .fetch_add(encode_result.sample_data.len() as u64, Ordering::Relaxed);
+                            if let Some(qp) = encode_result.average_qp {
+                                drop_window.record_qp(qp);
+                            }
+
+                            if recorder.is_some() {
+                                record(&mut recorder, recorder::RecordSample::Video(encode_result.clone()));
+                            }
+
+                            if let Some(replay_buffer) = replay_buffer.as_mut() {
+                                replay_buffer.push(&encode_result);
+                                // 古い GOP を捨てられるよう定期的にキーフレームを挟む
+                                if replay_buffer.take_keyframe_request() {
+                                    keyframe_request.request(KeyframeReason::Replay);
+                                }
                             }
 
                             // 現在アクティブなトラックがあり、かつ接続準備完了していれば送信
                             if let (Some(track), Some(conn_ready)) = (&current_video_track, &current_connection_ready) {
                                 if conn_ready.load(Ordering::Relaxed) {
-                                     track_writer::write_encoded_sample(
-                                        track,
-                                        encode_result,
-                                    ).await?;
-
-                                    last_encode_result_wait_start = Instant::now();
+                                    // 接続確立中に保持していた分を先に書き込む
+                                    for sample in connect_buffer.take_admitted(&mut keyframe_gate) {
+                                        write_paced(track, pacer.as_mut(), &capture_clock, sample).await?;
+                                    }
+                                    let action = late_frame_filter.as_mut().map_or(late_frames::LateFrameAction::Write, |filter| {
+                                        let age = capture_clock.age(encode_result.capture_timestamp, SystemTime::now());
+                                        filter.check(encode_result.is_keyframe, age)
+                                    });
+                                    match action {
+                                        late_frames::LateFrameAction::Write => {
+                                            if let Some(dropped) = late_frame_filter.as_mut().and_then(|filter| filter.take_dropped()) {
+                                                info!("Skipped {} late video frames, resumed at keyframe", dropped);
+                                            }
+                                            if keyframe_gate.admit(encode_result.is_keyframe) {
+                                                if let Some(suppressed) = keyframe_gate.take_suppressed() {
+                                                    info!("Held back {} delta frames until the new track's first keyframe", suppressed);
+                                                }
+                                                write_paced(track, pacer.as_mut(), &capture_clock, encode_result).await?;
+                                                drop_window.record_encode_to_send(encoded_at.elapsed());
+                                            }
+                                        }
+                                        late_frames::LateFrameAction::Drop { request_keyframe } => {
+                                            if request_keyframe {
+                                                warn!("Video frames are arriving late, dropping until the next keyframe");
+                                                keyframe_request.request(KeyframeReason::LateFrames);
+                                            }
+                                        }
+                                    }
                                 } else {
-                                    // 接続準備未完了ならドロップ（ログ出しすぎないよう注意）
-                                    // debug!("Connection not ready, dropping video frame");
+                                    // 接続完了と同時に映るよう、直近のキーフレーム以降を保持しておく
+                                    connect_buffer.push(encode_result);
                                 }
                             }
                         }
                         None => {
-                            info!("Video encode result channel closed");
+                            // ルーターがエンコーダーを作り直していれば、古いワーカーの結果を受け取り終えたので切り替える
+                            if let Ok(new_result_rx) = replace_result_rx.try_recv() {
+                                debug!("Switching to the encode results of the recreated encoder");
+                                encode_result_rx = new_result_rx;
+                                continue;
+                            }
+                            // フレームルーターが動いているのに結果チャネルが閉じた場合はエンコーダーが落ちている
+                            if frame_router_handle.is_finished() {
+                                info!("Video encode result channel closed");
+                            } else {
+                                warn!("Video encode result channel closed while frame router is running");
+                                exit_error = Some(anyhow::Error::new(ServiceError::EncoderUnavailable(codec)));
+                            }
                             break;
                         }
                     }
@@ -244,10 +743,87 @@ impl VideoStreamService {
                 // 3. キーフレーム要求
                 msg = self.video_stream_msg_rx.recv() => {
                     match msg {
-                        Some(VideoStreamMessage::RequestKeyframe) => {
-                            debug!("Received keyframe request");
-                            keyframe_requested.store(true, Ordering::Relaxed);
+                        Some(VideoStreamMessage::RequestKeyframe { reason }) => {
+                            debug!("Received keyframe request ({})", reason);
+                            keyframe_request.request(reason);
+                        }
+                        Some(VideoStreamMessage::Pause) => {
+                            info!("Video stream paused");
+                            stream_paused.store(true, Ordering::Relaxed);
+                            // 一時停止の直後の黒いフレームを、送り直せるようにキーフレームにする
+                            // （一時停止の前の絵を送り直さないよう、それまでのキーフレームは捨てる）
+                            if let Some(heartbeat) = pause_heartbeat.as_mut() {
+                                heartbeat.reset();
+                                keyframe_request.request(KeyframeReason::Pause);
+                            }
+                        }
+                        Some(VideoStreamMessage::Resume) => {
+                            info!("Video stream resumed");
+                            stream_paused.store(false, Ordering::Relaxed);
+                            keyframe_request.request(KeyframeReason::Resume);
+                        }
+                        Some(VideoStreamMessage::SetMaxBitrate { bitrate_bps }) => {
+                            match bitrate_bps {
+                                Some(bps) => info!("Video bitrate limited to {} kbps by the viewer", bps / 1000),
+                                None => debug!("Video bitrate is not limited by the viewer"),
+                            }
+                            max_bitrate_bps.store(bitrate_bps.unwrap_or(0), Ordering::Relaxed);
+                        }
+                        Some(VideoStreamMessage::RestartEncoder) => {
+                            // ルーターは次のフレームの処理の最初に差し替えるので、解像度の変更と同じフレームでも
+                            // 順番に処理される。まだ差し替えていないワーカーがあれば作らずにまとめる
+                            if !restart_encoder(
+                                &mut encoder_restarter,
+                                &mut encode_result_rx,
+                                &mut pause_heartbeat,
+                                &mut jobs_queued_at_last_result,
+                                &mut encode_stall_since,
+                            ) {
+                                continue;
+                            }
+                            info!("Restarted video encoder ({}) on request", codec);
+                            keyframe_request.request(KeyframeReason::EncoderRestart);
+                        }
+                        Some(VideoStreamMessage::SaveReplay) => {
+                            let (Some(replay_buffer), Some((_, dir))) = (&replay_buffer, &self.replay) else {
+                                warn!("Save replay requested, but instant replay is disabled");
+                                continue;
+                            };
+                            let Some(clip) = replay_buffer.snapshot() else {
+                                warn!("Save replay requested, but the replay buffer is empty");
+                                continue;
+                            };
+                            info!("Saving replay ({:?}, {} frames)", clip.duration(), clip.samples.len());
+                            let dir = dir.clone();
+                            // MP4 の書き出しはメインループを止めないよう別スレッドで
+                            tokio::task::spawn_blocking(move || match replay::save_clip(&clip, &dir) {
+                                Ok(path) => info!("Replay saved: {}", path.display()),
+                                Err(e) => warn!("Failed to save replay: {:#}", e),
+                            });
+                        }
+                        Some(VideoStreamMessage::StartRecording) => {
+                            let Some((dir, _)) = &self.recording else {
+                                warn!("Start recording requested, but recording is disabled");
+                                continue;
+                            };
+                            if let Some(active) = &recorder {
+                                info!("Already recording to {}", active.path().display());
+                                continue;
+                            }
+                            match recorder::Recorder::spawn_in(dir, record_audio) {
+                                Ok(active) => {
+                                    recorder = Some(active);
+                                    // ファイルはキーフレームから始まるので、次の GOP を待たずに出させる
+                                    keyframe_request.request(KeyframeReason::Recording);
+                                }
+                                Err(e) => warn!("Failed to start recording: {:#}", e),
+                            }
                         }
+                        Some(VideoStreamMessage::StopRecording) => match recorder.take() {
+                            // 残りの書き出しは書き込みスレッドで行う
+                            Some(active) => info!("Stopping recording: {}", active.path().display()),
+                            None => debug!("Stop recording requested, but not recording"),
+                        },
                         None => {
                             info!("Video stream message channel closed");
                             break;
@@ -255,23 +831,205 @@ impl VideoStreamService {
                     }
                 }
 
-                // 4. タイムアウト監視
-                _ = tokio::time::sleep(tokio::time::Duration::from_secs(3)) => {
-                    if !first_encode_result_received {
-                        // まだ一度も受信していない場合
-                         if let Some(conn_ready) = &current_connection_ready {
-                            if conn_ready.load(Ordering::Relaxed) {
-                                let wait_duration = last_encode_result_wait_start.elapsed();
-                                if wait_duration.as_secs() >= 3 && !encode_result_timeout_warned {
-                                    warn!(
-                                        "No encode result received for {}s (connection_ready: true)",
-                                        wait_duration.as_secs()
-                                    );
-                                    encode_result_timeout_warned = true;
-                                }
-                            }
-                         }
+                // 4. エンコーダーのウォッチドッグ
+                _ = watchdog_interval.tick() => {
+                    // ジョブを渡していなければ（静止画面・一時停止など）待ち状態ではない
+                    if jobs_queued.load(Ordering::Relaxed) == jobs_queued_at_last_result {
+                        encode_stall_since = None;
+                        continue;
                     }
+                    let stall_since = *encode_stall_since.get_or_insert_with(Instant::now);
+                    if stall_since.elapsed() < self.encode_stall_timeout {
+                        continue;
+                    }
+
+                    if encode_stall_stage == 0 {
+                        warn!(
+                            "No encode output for {:?} while frames are queued, requesting keyframe",
+                            self.encode_stall_timeout
+                        );
+                        keyframe_request.request(KeyframeReason::EncoderRestart);
+                    } else if encode_stall_stage <= self.encode_stall_max_retries {
+                        warn!(
+                            "Encoder still stalled, recreating encoder worker (retry {}/{})",
+                            encode_stall_stage, self.encode_stall_max_retries
+                        );
+                        restart_encoder(
+                            &mut encoder_restarter,
+                            &mut encode_result_rx,
+                            &mut pause_heartbeat,
+                            &mut jobs_queued_at_last_result,
+                            &mut encode_stall_since,
+                        );
+                    } else {
+                        error!(
+                            "Encoder produced no output after {} recreation attempts, giving up",
+                            self.encode_stall_max_retries
+                        );
+                        exit_error = Some(anyhow::Error::new(ServiceError::EncoderUnavailable(codec)));
+                        break;
+                    }
+                    encode_stall_stage += 1;
+                    encode_stall_since = Some(Instant::now());
+                }
+
+                // 5. ドロップ統計のログとクライアントへの送信
+                _ = drop_stats_interval.tick() => {
+                    let report = drop_window.report(&drop_counters);
+                    self.metrics.video_frames_captured.fetch_add(report.frames, Ordering::Relaxed);
+                    self.metrics
+                        .video_frames_dropped
+                        .fetch_add(report.encoder_dropped, Ordering::Relaxed);
+                    let encoded = (
+                        self.metrics.video_frames_encoded.load(Ordering::Relaxed),
+                        self.metrics.video_bytes_encoded.load(Ordering::Relaxed),
+                    );
+                    let interval_secs = DROP_STATS_INTERVAL.as_secs_f32();
+                    self.metrics.set_video_rates(
+                        encoded.0.saturating_sub(encoded_at_last_stats.0) as f32 / interval_secs,
+                        encoded.1.saturating_sub(encoded_at_last_stats.1) as f32 * 8.0 / interval_secs,
+                    );
+                    encoded_at_last_stats = encoded;
+                    if report.frames == 0 {
+                        continue;
+                    }
+                    let log_line = format!(
+                        "Video drops (last {:?}): encoder {}/{} frames ({:.1}%), network loss {:.1}%, average QP {}, capture to encode {}, encode to send {}",
+                        DROP_STATS_INTERVAL,
+                        report.encoder_dropped,
+                        report.frames,
+                        report.encoder_drop_rate * 100.0,
+                        report.network_loss_rate * 100.0,
+                        report.average_qp.map_or_else(|| "unavailable".to_string(), |qp| format!("{:.1}", qp)),
+                        report.capture_to_encode.map_or_else(|| "unavailable".to_string(), |latency| latency.to_string()),
+                        report.encode_to_send.map_or_else(|| "unavailable".to_string(), |latency| latency.to_string())
+                    );
+                    let encoder_overloaded = report.encoder_drop_rate >= drop_stats::ENCODER_DROP_WARN_RATE;
+                    let network_congested = report.network_loss_rate >= drop_stats::NETWORK_LOSS_WARN_RATE;
+                    let quality_limited = report.average_qp.is_some_and(|qp| qp >= drop_stats::QP_WARN_LEVEL);
+                    if encoder_overloaded {
+                        warn!("{} - encoder cannot keep up (CPU/GPU overloaded)", log_line);
+                    }
+                    if network_congested {
+                        warn!("{} - network is congested", log_line);
+                    }
+                    if quality_limited {
+                        warn!("{} - bitrate is too low for the content", log_line);
+                    }
+                    if !encoder_overloaded && !network_congested && !quality_limited {
+                        if report.encoder_dropped > 0 {
+                            info!("{}", log_line);
+                        } else {
+                            debug!("{}", log_line);
+                        }
+                    }
+
+                    let queue_overloaded = queue_wait_monitor.as_mut().is_some_and(|monitor| {
+                        monitor.observe(Duration::from_micros(
+                            drop_counters.encoder_queue_wait_us.load(Ordering::Relaxed),
+                        ))
+                    });
+
+                    let connected = current_connection_ready
+                        .as_ref()
+                        .is_some_and(|ready| ready.load(Ordering::Relaxed));
+                    // 受信側がいない間のロス率は古いままなので段を動かさない
+                    if let Some((controller, capture_cmd_tx)) =
+                        quality_ladder.as_mut().filter(|_| connected)
+                    {
+                        controller.set_capture_throttled(
+                            self.capture_throttled
+                                .as_ref()
+                                .is_some_and(|throttled| throttled.load(Ordering::Relaxed)),
+                        );
+                        controller.set_encoder_overloaded(queue_overloaded);
+                        if let Some(rung) = controller.observe(&report) {
+                            update_capture_quality(capture_cmd_tx, rung);
+                        }
+                    }
+                    if let Some(stats_tx) = self.stats_tx.as_ref().filter(|_| connected) {
+                        let message = OutgoingDataChannelMessage::Text(
+                            DataChannelMessage::VideoStats {
+                                payload: VideoStatsPayload {
+                                    keyframes_last_minute: keyframe_window.last_minute().clone(),
+                                    ..report
+                                },
+                            },
+                        );
+                        // 統計は取りこぼしても次の区間で送り直されるので、詰まっていれば捨てる
+                        if stats_tx.try_send(message).is_err() {
+                            debug!("Outgoing data channel is full, skipping video stats");
+                        }
+                    }
+                }
+
+                // 6. キーフレームの理由ごとの集計をログに出す
+                _ = keyframe_stats_interval.tick() => {
+                    let counts = keyframe_window.rotate();
+                    let summary = keyframe_stats::summarize(counts);
+                    if counts.total() > 0 {
+                        info!("Video keyframes (last {:?}): {}", keyframe_stats::KEYFRAME_STATS_INTERVAL, summary);
+                    } else {
+                        debug!("Video keyframes (last {:?}): {}", keyframe_stats::KEYFRAME_STATS_INTERVAL, summary);
+                    }
+                }
+
+                // 7. 接続完了の検知（保持しているエンコード結果を次のフレームを待たずに書き込む）
+                _ = connect_poll_interval.tick(), if !connect_buffer.is_empty() => {
+                    let connected = current_connection_ready
+                        .as_ref()
+                        .is_some_and(|ready| ready.load(Ordering::Relaxed));
+                    let Some(track) = current_video_track.as_ref().filter(|_| connected) else {
+                        continue;
+                    };
+                    // 新しいトラックの最初のキーフレームを待っていれば、保持分の先頭のキーフレームで待ちを終える
+                    let samples = connect_buffer.take_admitted(&mut keyframe_gate);
+                    info!("Connection ready, flushing {} buffered video samples", samples.len());
+                    for sample in samples {
+                        write_paced(track, pacer.as_mut(), &capture_clock, sample).await?;
+                    }
+                }
+
+                // 8. 録画する音声（録画していなければ読み捨てる）
+                audio = async { recording_audio_rx.as_mut()?.recv().await }, if recording_audio_rx.is_some() => {
+                    match audio {
+                        Some(result) => record(&mut recorder, recorder::RecordSample::Audio(result)),
+                        None => {
+                            debug!("Recording audio channel closed");
+                            recording_audio_rx = None;
+                        }
+                    }
+                }
+
+                // 9. 接続直後の追加のキーフレーム（最初の 1 枚が失われた場合に備える）
+                _ = startup_keyframe_interval.tick(), if startup_keyframes.is_pending() => {
+                    let connected = current_connection_ready
+                        .as_ref()
+                        .is_some_and(|ready| ready.load(Ordering::Relaxed));
+                    if startup_keyframes.poll(connected, Instant::now()) {
+                        debug!("Requesting startup keyframe");
+                        keyframe_request.request(KeyframeReason::Reconnect);
+                    }
+                }
+
+                // 10. 一時停止中のキーフレームの送り直し（トラックに何も書かれない状態を続けない）
+                _ = pause_heartbeat_interval.tick(), if pause_heartbeat.is_some() && stream_paused.load(Ordering::Relaxed) => {
+                    let connected = current_connection_ready
+                        .as_ref()
+                        .is_some_and(|ready| ready.load(Ordering::Relaxed));
+                    let Some(track) = current_video_track.as_ref().filter(|_| connected) else {
+                        continue;
+                    };
+                    let Some(resent) = pause_heartbeat
+                        .as_mut()
+                        .and_then(|heartbeat| heartbeat.poll(true, Instant::now()))
+                    else {
+                        continue;
+                    };
+                    // 一時停止中に接続したトラックもこのキーフレームから映る
+                    keyframe_gate.admit(true);
+                    debug!("Resending the last keyframe while paused");
+                    track_writer::write_encoded_sample(track, resent, &[]).await?;
                 }
             }
         }
@@ -281,8 +1039,81 @@ impl VideoStreamService {
             handle.abort();
         }
         let _ = frame_router_handle.await;
+        // 録画の残りを書き出してから終える
+        if let Some(active) = recorder {
+            let _ = tokio::task::spawn_blocking(move || active.finish()).await;
+        }
 
         info!("VideoStreamService stopped");
-        Ok(())
+        match exit_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// エンコーダーを作り直し、古いワーカーの結果を待っていた状態を戻す（間引いた場合は false）
+fn restart_encoder(
+    restarter: &mut encoder_restart::EncoderRestarter,
+    encode_result_rx: &mut mpsc::UnboundedReceiver<core_types::EncodeResult>,
+    pause_heartbeat: &mut Option<track_writer::PauseHeartbeat>,
+    jobs_queued_at_last_result: &mut u64,
+    encode_stall_since: &mut Option<Instant>,
+) -> bool {
+    let Some(new_result_rx) = restarter.restart(Instant::now()) else {
+        info!("Video encoder restart already pending or too soon, skipping");
+        return false;
+    };
+    *encode_result_rx = new_result_rx;
+    // 古いワーカーのキーフレームは新しいワーカーの出力と混ぜない
+    if let Some(heartbeat) = pause_heartbeat.as_mut() {
+        heartbeat.reset();
+    }
+    // 新しいワーカーにジョブが渡るまではウォッチドッグの待ち状態にしない
+    *jobs_queued_at_last_result = restarter.jobs_queued();
+    *encode_stall_since = None;
+    true
+}
+
+/// 品質ラダーの段をキャプチャ設定に反映する
+fn update_capture_quality(capture_cmd_tx: &mpsc::Sender<CaptureMessage>, rung: QualityRung) {
+    let message = CaptureMessage::UpdateConfig {
+        size: CaptureSize::Custom {
+            width: rung.width,
+            height: rung.height,
+        },
+        fps: rung.fps,
+    };
+    if let Err(e) = capture_cmd_tx.try_send(message) {
+        warn!("Failed to update capture quality to {}: {}", rung, e);
+    }
+}
+
+/// 録画中なら書き込みスレッドに渡す（渡せなければ録画を止める）
+fn record(recorder: &mut Option<recorder::Recorder>, sample: recorder::RecordSample) {
+    if recorder.as_ref().is_some_and(|active| !active.push(sample)) {
+        warn!("Recording stopped: the recorder fell behind or failed to write");
+        *recorder = None;
+    }
+}
+
+/// ペーシングが有効なら必要なだけ待ってからトラックに書き込む（キャプチャ時刻の拡張を付ける）
+async fn write_paced(
+    track: &Arc<TrackLocalStaticSample>,
+    pacer: Option<&mut pacer::Pacer>,
+    capture_clock: &abs_capture_time::CaptureClock,
+    encode_result: core_types::EncodeResult,
+) -> Result<()> {
+    if let Some(pacer) = pacer {
+        let wait = pacer.reserve(encode_result.sample_data.len(), Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
     }
+    let extensions: Vec<_> = capture_clock
+        .to_ntp(encode_result.capture_timestamp)
+        .map(abs_capture_time::header_extension)
+        .into_iter()
+        .collect();
+    track_writer::write_encoded_sample(track, encode_result, &extensions).await
 }
@@ -0,0 +1,499 @@
+// H.264 Annex-B サンプル列を MP4 (ISO BMFF) に書き出す最小限のマルチプレクサ
+//
+// ftyp → mdat → moov の順に並べ、チャンクは 1 つにまとめる。
+// SPS/PPS は avcC に格納し、サンプル本体からは取り除く。
+
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+/// トラックのタイムスケール (90kHz)
+const TIMESCALE: u32 = 90_000;
+/// ムービー全体のタイムスケール (ミリ秒)
+const MOVIE_TIMESCALE: u32 = 1_000;
+
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+/// MP4 に書き出す 1 フレーム分のサンプル
+#[derive(Debug, Clone)]
+pub struct Mp4Sample {
+    /// Annex-B 形式の H.264 アクセスユニット
+    pub data: Vec<u8>,
+    pub is_keyframe: bool,
+    pub duration: Duration,
+}
+
+/// Annex-B のバイト列を NAL ユニットごとに分割（3 バイト/4 バイトのスタートコード両対応）
+pub(crate) fn split_annexb(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut nals = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = match starts.get(n + 1) {
+            Some(&next) => next - 3,
+            None => data.len(),
+        };
+        // 4 バイトスタートコードの先頭 0 や trailing zero を落とす
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            nals.push(&data[start..end]);
+        }
+    }
+    nals
+}
+
+/// Annex-B のアクセスユニットを長さプレフィックス形式にして `out` に追加する
+/// SPS/PPS はサンプルから取り除き、まだ見つけていなければ `sps`/`pps` に取り出す（AUD は捨てる）
+pub(crate) fn append_length_prefixed(
+    data: &[u8],
+    out: &mut Vec<u8>,
+    sps: &mut Option<Vec<u8>>,
+    pps: &mut Option<Vec<u8>>,
+) {
+    for nal in split_annexb(data) {
+        match nal[0] & 0x1F {
+            NAL_SPS => {
+                sps.get_or_insert_with(|| nal.to_vec());
+            }
+            NAL_PPS => {
+                pps.get_or_insert_with(|| nal.to_vec());
+            }
+            NAL_AUD => {}
+            _ => {
+                out.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                out.extend_from_slice(nal);
+            }
+        }
+    }
+}
+
+/// サンプル列を MP4 ファイルのバイト列に変換
+pub fn mux_h264(samples: &[Mp4Sample], width: u32, height: u32) -> Result<Vec<u8>> {
+    if samples.is_empty() {
+        bail!("No samples to mux");
+    }
+    if !samples[0].is_keyframe {
+        bail!("First sample must be a keyframe");
+    }
+
+    // SPS/PPS を取り出しつつ、サンプルを長さプレフィックス形式に変換
+    let mut sps: Option<Vec<u8>> = None;
+    let mut pps: Option<Vec<u8>> = None;
+    let mut mdat_payload = Vec::new();
+    let mut sample_sizes = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let before = mdat_payload.len();
+        append_length_prefixed(&sample.data, &mut mdat_payload, &mut sps, &mut pps);
+        sample_sizes.push((mdat_payload.len() - before) as u32);
+    }
+    let (Some(sps), Some(pps)) = (sps, pps) else {
+        bail!("SPS/PPS not found in samples");
+    };
+    if sps.len() < 4 {
+        bail!("SPS is too short ({} bytes)", sps.len());
+    }
+
+    // サンプルごとの長さ (90kHz)。丸め誤差が積み上がらないよう累積時刻から求める
+    let mut deltas = Vec::with_capacity(samples.len());
+    let mut elapsed = Duration::ZERO;
+    let mut prev_ticks = 0u64;
+    for sample in samples {
+        elapsed += sample.duration;
+        let ticks = (elapsed.as_nanos() * TIMESCALE as u128 / 1_000_000_000) as u64;
+        let delta = ticks.saturating_sub(prev_ticks).max(1);
+        prev_ticks += delta;
+        deltas.push(delta as u32);
+    }
+    let media_duration = prev_ticks;
+    let movie_duration = media_duration * MOVIE_TIMESCALE as u64 / TIMESCALE as u64;
+
+    let ftyp = mp4_box(b"ftyp", |b| {
+        b.extend_from_slice(b"isom");
+        b.extend_from_slice(&0x200u32.to_be_bytes());
+        for brand in [b"isom", b"iso2", b"avc1", b"mp41"] {
+            b.extend_from_slice(brand);
+        }
+    });
+
+    let mdat_size = 8 + mdat_payload.len();
+    if mdat_size > u32::MAX as usize {
+        bail!("Replay is too large to mux ({} bytes)", mdat_size);
+    }
+    // mdat は ftyp の直後なので、チャンクの先頭は ftyp + mdat ヘッダーの位置
+    let chunk_offset = (ftyp.len() + 8) as u32;
+
+    let moov = build_moov(
+        &sps,
+        &pps,
+        width,
+        height,
+        &deltas,
+        &sample_sizes,
+        samples,
+        chunk_offset,
+        media_duration,
+        movie_duration,
+    );
+
+    let mut out = Vec::with_capacity(ftyp.len() + mdat_size + moov.len());
+    out.extend_from_slice(&ftyp);
+    out.extend_from_slice(&(mdat_size as u32).to_be_bytes());
+    out.extend_from_slice(b"mdat");
+    out.extend_from_slice(&mdat_payload);
+    out.extend_from_slice(&moov);
+    Ok(out)
+}
+
+/// `[size][type][payload]` のボックスを作る
+pub(crate) fn mp4_box(kind: &[u8; 4], fill: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+    let mut b = vec![0, 0, 0, 0];
+    b.extend_from_slice(kind);
+    fill(&mut b);
+    let size = b.len() as u32;
+    b[..4].copy_from_slice(&size.to_be_bytes());
+    b
+}
+
+/// version/flags 付きのフルボックスを作る
+pub(crate) fn full_box(
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    fill: impl FnOnce(&mut Vec<u8>),
+) -> Vec<u8> {
+    mp4_box(kind, |b| {
+        b.push(version);
+        b.extend_from_slice(&flags.to_be_bytes()[1..]);
+        fill(b);
+    })
+}
+
+/// 単位行列
+fn write_matrix(b: &mut Vec<u8>) {
+    for v in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        b.extend_from_slice(&v.to_be_bytes());
+    }
+}
+
+/// ムービーヘッダー
+pub(crate) fn mvhd(timescale: u32, duration: u32, next_track_id: u32) -> Vec<u8> {
+    full_box(b"mvhd", 0, 0, |b| {
+        b.extend_from_slice(&0u32.to_be_bytes()); // creation_time
+        b.extend_from_slice(&0u32.to_be_bytes()); // modification_time
+        b.extend_from_slice(&timescale.to_be_bytes());
+        b.extend_from_slice(&duration.to_be_bytes());
+        b.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+        b.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+        b.extend_from_slice(&[0; 10]);
+        write_matrix(b);
+        b.extend_from_slice(&[0; 24]); // pre_defined
+        b.extend_from_slice(&next_track_id.to_be_bytes());
+    })
+}
+
+/// トラックヘッダー（`volume` は音声トラックなら 0x0100）
+pub(crate) fn tkhd(track_id: u32, duration: u32, width: u32, height: u32, volume: u16) -> Vec<u8> {
+    full_box(b"tkhd", 0, 0x3, |b| {
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&track_id.to_be_bytes());
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&duration.to_be_bytes());
+        b.extend_from_slice(&[0; 8]);
+        b.extend_from_slice(&0u16.to_be_bytes()); // layer
+        b.extend_from_slice(&0u16.to_be_bytes()); // alternate_group
+        b.extend_from_slice(&volume.to_be_bytes());
+        b.extend_from_slice(&0u16.to_be_bytes());
+        write_matrix(b);
+        b.extend_from_slice(&(width << 16).to_be_bytes());
+        b.extend_from_slice(&(height << 16).to_be_bytes());
+    })
+}
+
+/// メディアヘッダー
+pub(crate) fn mdhd(timescale: u32, duration: u32) -> Vec<u8> {
+    full_box(b"mdhd", 0, 0, |b| {
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&timescale.to_be_bytes());
+        b.extend_from_slice(&duration.to_be_bytes());
+        b.extend_from_slice(&0x55C4u16.to_be_bytes()); // language "und"
+        b.extend_from_slice(&0u16.to_be_bytes());
+    })
+}
+
+/// ハンドラー（映像は "vide"、音声は "soun"）
+pub(crate) fn hdlr(handler: &[u8; 4], name: &str) -> Vec<u8> {
+    full_box(b"hdlr", 0, 0, |b| {
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(handler);
+        b.extend_from_slice(&[0; 12]);
+        b.extend_from_slice(name.as_bytes());
+        b.push(0);
+    })
+}
+
+/// データ参照（サンプルは同じファイル内）
+pub(crate) fn dinf() -> Vec<u8> {
+    mp4_box(b"dinf", |b| {
+        b.extend_from_slice(&full_box(b"dref", 0, 0, |b| {
+            b.extend_from_slice(&1u32.to_be_bytes());
+            b.extend_from_slice(&full_box(b"url ", 0, 1, |_| {}));
+        }));
+    })
+}
+
+/// H.264 のサンプルエントリー（SPS/PPS は avcC に格納する）
+pub(crate) fn avc1(sps: &[u8], pps: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let avcc = mp4_box(b"avcC", |b| {
+        b.push(1); // configurationVersion
+        b.extend_from_slice(&sps[1..4]); // profile, compatibility, level
+        b.push(0xFF); // lengthSizeMinusOne = 3
+        b.push(0xE1); // SPS 1 個
+        b.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+        b.extend_from_slice(sps);
+        b.push(1); // PPS 1 個
+        b.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+        b.extend_from_slice(pps);
+    });
+    mp4_box(b"avc1", |b| {
+        b.extend_from_slice(&[0; 6]);
+        b.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+        b.extend_from_slice(&[0; 16]);
+        b.extend_from_slice(&(width as u16).to_be_bytes());
+        b.extend_from_slice(&(height as u16).to_be_bytes());
+        b.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+        b.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&1u16.to_be_bytes()); // frame_count
+        b.extend_from_slice(&[0; 32]); // compressorname
+        b.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+        b.extend_from_slice(&0xFFFFu16.to_be_bytes()); // pre_defined = -1
+        b.extend_from_slice(&avcc);
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build_moov(
+    sps: &[u8],
+    pps: &[u8],
+    width: u32,
+    height: u32,
+    deltas: &[u32],
+    sample_sizes: &[u32],
+    samples: &[Mp4Sample],
+    chunk_offset: u32,
+    media_duration: u64,
+    movie_duration: u64,
+) -> Vec<u8> {
+    let mvhd = mvhd(MOVIE_TIMESCALE, movie_duration as u32, 2);
+    let tkhd = tkhd(1, movie_duration as u32, width, height, 0);
+    let mdhd = mdhd(TIMESCALE, media_duration as u32);
+    let hdlr = hdlr(b"vide", "VideoHandler");
+    let vmhd = full_box(b"vmhd", 0, 1, |b| b.extend_from_slice(&[0; 8]));
+    let dinf = dinf();
+    let avc1 = avc1(sps, pps, width, height);
+    let stsd = full_box(b"stsd", 0, 0, |b| {
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&avc1);
+    });
+
+    // 同じ長さが続くサンプルはまとめる
+    let mut stts_entries: Vec<(u32, u32)> = Vec::new();
+    for &delta in deltas {
+        match stts_entries.last_mut() {
+            Some((count, last)) if *last == delta => *count += 1,
+            _ => stts_entries.push((1, delta)),
+        }
+    }
+    let stts = full_box(b"stts", 0, 0, |b| {
+        b.extend_from_slice(&(stts_entries.len() as u32).to_be_bytes());
+        for (count, delta) in &stts_entries {
+            b.extend_from_slice(&count.to_be_bytes());
+            b.extend_from_slice(&delta.to_be_bytes());
+        }
+    });
+
+    let keyframes: Vec<u32> = samples
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| s.is_keyframe)
+        .map(|(i, _)| i as u32 + 1)
+        .collect();
+    let stss = full_box(b"stss", 0, 0, |b| {
+        b.extend_from_slice(&(keyframes.len() as u32).to_be_bytes());
+        for n in &keyframes {
+            b.extend_from_slice(&n.to_be_bytes());
+        }
+    });
+
+    let stsz = full_box(b"stsz", 0, 0, |b| {
+        b.extend_from_slice(&0u32.to_be_bytes());
+        b.extend_from_slice(&(sample_sizes.len() as u32).to_be_bytes());
+        for size in sample_sizes {
+            b.extend_from_slice(&size.to_be_bytes());
+        }
+    });
+
+    // 全サンプルを 1 チャンクに入れる
+    let stsc = full_box(b"stsc", 0, 0, |b| {
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&(sample_sizes.len() as u32).to_be_bytes());
+        b.extend_from_slice(&1u32.to_be_bytes());
+    });
+    let stco = full_box(b"stco", 0, 0, |b| {
+        b.extend_from_slice(&1u32.to_be_bytes());
+        b.extend_from_slice(&chunk_offset.to_be_bytes());
+    });
+
+    let stbl = mp4_box(b"stbl", |b| {
+        for child in [&stsd, &stts, &stss, &stsz, &stsc, &stco] {
+            b.extend_from_slice(child);
+        }
+    });
+    let minf = mp4_box(b"minf", |b| {
+        for child in [&vmhd, &dinf, &stbl] {
+            b.extend_from_slice(child);
+        }
+    });
+    let mdia = mp4_box(b"mdia", |b| {
+        for child in [&mdhd, &hdlr, &minf] {
+            b.extend_from_slice(child);
+        }
+    });
+    let trak = mp4_box(b"trak", |b| {
+        b.extend_from_slice(&tkhd);
+        b.extend_from_slice(&mdia);
+    });
+    mp4_box(b"moov", |b| {
+        b.extend_from_slice(&mvhd);
+        b.extend_from_slice(&trak);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x42, 0xC0, 0x1F, 0xDA, 0x01];
+    const PPS: &[u8] = &[0x68, 0xCE, 0x3C, 0x80];
+
+    fn keyframe() -> Mp4Sample {
+        let mut data = vec![0, 0, 0, 1];
+        data.extend_from_slice(SPS);
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(PPS);
+        data.extend_from_slice(&[0, 0, 1, 0x65, 0x88, 0x84]);
+        Mp4Sample {
+            data,
+            is_keyframe: true,
+            duration: Duration::from_millis(33),
+        }
+    }
+
+    fn delta_frame() -> Mp4Sample {
+        Mp4Sample {
+            data: vec![0, 0, 0, 1, 0x09, 0xF0, 0, 0, 0, 1, 0x41, 0x9A, 0x02],
+            is_keyframe: false,
+            duration: Duration::from_millis(33),
+        }
+    }
+
+    /// トップレベルのボックスを (type, 本体) で列挙
+    fn top_level_boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut boxes = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            boxes.push((
+                &data[offset + 4..offset + 8],
+                &data[offset + 8..offset + size],
+            ));
+            offset += size;
+        }
+        assert_eq!(offset, data.len());
+        boxes
+    }
+
+    fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+        data.windows(pattern.len()).position(|w| w == pattern)
+    }
+
+    #[test]
+    fn test_split_annexb() {
+        let data = [
+            0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE, 0, 0, 0, 1, 0x65, 0x88, 0,
+        ];
+        let nals = split_annexb(&data);
+        assert_eq!(nals, vec![&[0x67, 0x42][..], &[0x68, 0xCE], &[0x65, 0x88]]);
+        assert!(split_annexb(&[0, 0]).is_empty());
+    }
+
+    #[test]
+    fn test_mux_h264_layout() {
+        let samples = vec![keyframe(), delta_frame(), delta_frame()];
+        let mp4 = mux_h264(&samples, 1280, 720).unwrap();
+
+        let boxes = top_level_boxes(&mp4);
+        let kinds: Vec<&[u8]> = boxes.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(kinds, vec![&b"ftyp"[..], b"mdat", b"moov"]);
+
+        // mdat は長さプレフィックス形式で、SPS/PPS/AUD は含まない
+        let mdat = boxes[1].1;
+        assert_eq!(
+            mdat,
+            &[
+                0, 0, 0, 3, 0x65, 0x88, 0x84, // IDR
+                0, 0, 0, 3, 0x41, 0x9A, 0x02, // P
+                0, 0, 0, 3, 0x41, 0x9A, 0x02, // P
+            ]
+        );
+
+        // avcC に SPS/PPS が入っている
+        let moov = boxes[2].1;
+        let avcc = find(moov, b"avcC").unwrap() + 4;
+        assert_eq!(&moov[avcc..avcc + 4], &[1, 0x42, 0xC0, 0x1F]);
+        assert!(find(moov, SPS).is_some());
+        assert!(find(moov, PPS).is_some());
+
+        // stco がチャンク先頭（mdat 本体）を指している
+        let stco = find(moov, b"stco").unwrap() + 4;
+        let offset = u32::from_be_bytes(moov[stco + 8..stco + 12].try_into().unwrap()) as usize;
+        assert_eq!(&mp4[offset..offset + 5], &[0, 0, 0, 3, 0x65]);
+
+        // 33ms x 3 = 8910 ticks (90kHz) が 1 エントリにまとまる
+        let stts = find(moov, b"stts").unwrap() + 4;
+        assert_eq!(
+            &moov[stts + 4..stts + 16],
+            &[0, 0, 0, 1, 0, 0, 0, 3, 0, 0, 0x0B, 0x9A]
+        );
+
+        // キーフレームは 1 番目のみ
+        let stss = find(moov, b"stss").unwrap() + 4;
+        assert_eq!(&moov[stss + 4..stss + 12], &[0, 0, 0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_mux_h264_errors() {
+        assert!(mux_h264(&[], 640, 480).is_err());
+        // 先頭がキーフレームでない
+        assert!(mux_h264(&[delta_frame(), keyframe()], 640, 480).is_err());
+        // SPS/PPS がない
+        let mut no_sps = keyframe();
+        no_sps.data = vec![0, 0, 1, 0x65, 0x88];
+        assert!(mux_h264(&[no_sps], 640, 480).is_err());
+    }
+}
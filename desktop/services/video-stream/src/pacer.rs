@@ -0,0 +1,117 @@
+// エンコード済みサンプルの送出ペーシング（リーキーバケット）
+//
+// キーフレームなど大きなサンプルを一気に書き込むとパケットがバーストし、
+// ブラウザ側のジッターバッファが膨らむ。目標ビットレートでトークンを補充し、
+// 使い過ぎた分（負債）を返し終えるまで次のサンプルの書き込みを待たせる。
+
+use std::time::{Duration, Instant};
+
+/// 待たずに送れるバースト量（目標ビットレートでこの時間に送れる分）
+const BURST_WINDOW: Duration = Duration::from_millis(20);
+
+pub struct Pacer {
+    bytes_per_sec: f64,
+    /// バケットの容量（バイト）
+    capacity: f64,
+    /// 負債の上限（バイト）。これを超えた分は切り捨てて遅延が積み上がらないようにする
+    max_debt: f64,
+    tokens: f64,
+    last_refill: Option<Instant>,
+}
+
+impl Pacer {
+    /// `bitrate_bps` で送出をならし、1 サンプルあたりの待ち時間は `max_delay` までに抑える
+    pub fn new(bitrate_bps: u32, max_delay: Duration) -> Self {
+        let bytes_per_sec = bitrate_bps.max(1) as f64 / 8.0;
+        let capacity = bytes_per_sec * BURST_WINDOW.as_secs_f64();
+        Self {
+            bytes_per_sec,
+            capacity,
+            max_debt: bytes_per_sec * max_delay.as_secs_f64(),
+            tokens: capacity,
+            last_refill: None,
+        }
+    }
+
+    /// `bytes` のサンプルを書き込む前に待つべき時間を返し、その分のトークンを消費する
+    pub fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        if let Some(last) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.bytes_per_sec).min(self.capacity);
+        }
+        self.last_refill = Some(now);
+        self.tokens = self.tokens.max(-self.max_debt);
+
+        let wait = if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec)
+        } else {
+            Duration::ZERO
+        };
+        self.tokens -= bytes as f64;
+        wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 8 Mbps = 1,000,000 bytes/s、バースト 20ms = 20,000 bytes
+    fn pacer() -> Pacer {
+        Pacer::new(8_000_000, Duration::from_millis(100))
+    }
+
+    fn assert_close(actual: Duration, expected_ms: f64) {
+        let actual_ms = actual.as_secs_f64() * 1000.0;
+        assert!(
+            (actual_ms - expected_ms).abs() < 0.01,
+            "expected {}ms, got {}ms",
+            expected_ms,
+            actual_ms
+        );
+    }
+
+    #[test]
+    fn test_small_samples_pass_immediately() {
+        let mut pacer = pacer();
+        let start = Instant::now();
+        // 60fps で 10,000 bytes ずつ = 600,000 bytes/s は目標以下
+        for i in 0..10 {
+            let now = start + Duration::from_micros(16_667 * i);
+            assert_eq!(pacer.reserve(10_000, now), Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_large_sample_delays_next() {
+        let mut pacer = pacer();
+        let start = Instant::now();
+        // バースト 20,000 bytes に対して 70,000 bytes のキーフレームは即送るが、50,000 bytes の負債になる
+        assert_eq!(pacer.reserve(70_000, start), Duration::ZERO);
+        // 直後のサンプルは負債 50,000 bytes を返すまで 50ms 待つ
+        assert_close(pacer.reserve(1_000, start), 50.0);
+        // 待った後は負債が返済済みなので待たない（1,000 bytes 分は補充で相殺）
+        let after = start + Duration::from_millis(51);
+        assert_eq!(pacer.reserve(1_000, after), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_refill_is_capped_at_burst() {
+        let mut pacer = pacer();
+        let start = Instant::now();
+        pacer.reserve(0, start);
+        // 長く休んでもバースト量以上は溜まらない
+        let later = start + Duration::from_secs(10);
+        assert_eq!(pacer.reserve(30_000, later), Duration::ZERO);
+        assert_close(pacer.reserve(0, later), 10.0);
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let mut pacer = pacer();
+        let start = Instant::now();
+        // 500,000 bytes の負債でも待ち時間は max_delay (100ms) まで
+        pacer.reserve(520_000, start);
+        assert_close(pacer.reserve(0, start), 100.0);
+    }
+}
@@ -0,0 +1,390 @@
+// 回線・エンコーダーの状態に応じた解像度・フレームレートの自動切り替え（品質ラダー）
+//
+// 詰まった回線でビットレートだけを絞ると高解像度のまま画質が潰れるが、解像度やフレームレートを
+// 落とせば同じビットレートでも見やすい。ドロップ統計（RTCP のロス率とエンコーダー側のドロップ率）が
+// 続けて悪ければラダーを 1 段下げ、続けて余裕があれば 1 段上げて、キャプチャ設定を更新する。
+// 切り替えはキャプチャセッションの再起動とキーフレームを伴うので、上げるときは下げるときより長く待ち、
+// 上げた直後にまた詰まった場合は次に上げるまでの待ちを倍にする（行ったり来たりを避ける）。
+// キャプチャ側が対象のウィンドウが前面にないためにフレームレートを下げている間は、空いた帯域を
+// 余裕とみなさず上げない（前面に戻った途端に詰まるのを避ける）。詰まりによる引き下げはそのまま行う。
+// エンコーダーの入力の待ち時間が予算を超えている間（`encoder_load`）も、ドロップがなくても詰まりとみなす。
+
+use crate::drop_stats::{ENCODER_DROP_WARN_RATE, NETWORK_LOSS_WARN_RATE};
+use core_types::{CaptureSize, VideoStatsPayload};
+use std::fmt;
+use std::str::FromStr;
+use tracing::info;
+
+/// デフォルトのラダー（上から順に試す）
+pub const DEFAULT_QUALITY_LADDER: &str = "1920x1080@45,1920x1080@30,1280x720@30,960x540@30";
+/// 続けてこの区間数だけ詰まっていたら 1 段下げる
+const STEP_DOWN_INTERVALS: u32 = 2;
+/// 続けてこの区間数だけ余裕があれば 1 段上げる（詰まって戻った回数に応じて倍にする）
+const STEP_UP_INTERVALS: u32 = 6;
+/// 上げる前の待ちを倍にする回数の上限
+const MAX_BACKOFF_SHIFT: u32 = 3;
+/// 上げてからこの区間数以内に下げたら、上げたのは失敗だったとみなす
+const FAILED_PROBE_INTERVALS: u32 = STEP_UP_INTERVALS;
+/// 余裕があるとみなすロス率・ドロップ率の上限
+const HEADROOM_NETWORK_LOSS_RATE: f32 = 0.01;
+const HEADROOM_ENCODER_DROP_RATE: f32 = 0.02;
+
+/// ラダーの 1 段（キャプチャの出力サイズとフレームレート）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QualityRung {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+}
+
+impl fmt::Display for QualityRung {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}@{}", self.width, self.height, self.fps)
+    }
+}
+
+impl FromStr for QualityRung {
+    type Err = String;
+
+    /// "1280x720@30" の形式
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid quality rung (expected WIDTHxHEIGHT@FPS): {}", s);
+        let (size, fps) = s.trim().split_once('@').ok_or_else(invalid)?;
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let rung = QualityRung {
+            width: width.parse().map_err(|_| invalid())?,
+            height: height.parse().map_err(|_| invalid())?,
+            fps: fps.parse().map_err(|_| invalid())?,
+        };
+        if rung.width == 0 || rung.height == 0 || rung.fps == 0 {
+            return Err(invalid());
+        }
+        Ok(rung)
+    }
+}
+
+/// 品質の高い順に並んだラダー
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QualityLadder(Vec<QualityRung>);
+
+impl QualityLadder {
+    pub fn rungs(&self) -> &[QualityRung] {
+        &self.0
+    }
+}
+
+impl Default for QualityLadder {
+    fn default() -> Self {
+        DEFAULT_QUALITY_LADDER
+            .parse()
+            .expect("default quality ladder is valid")
+    }
+}
+
+impl FromStr for QualityLadder {
+    type Err = String;
+
+    /// "1920x1080@45,1280x720@30" のようにカンマ区切りで、品質の高い順（"default" でデフォルトのラダー）
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case("default") {
+            return Ok(Self::default());
+        }
+        let rungs = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<QualityRung>, _>>()?;
+        let cost = |r: &QualityRung| r.width as u64 * r.height as u64 * r.fps as u64;
+        if rungs
+            .windows(2)
+            .any(|pair| cost(&pair[1]) >= cost(&pair[0]))
+        {
+            return Err(format!(
+                "quality ladder must go from highest to lowest quality: {}",
+                s
+            ));
+        }
+        Ok(QualityLadder(rungs))
+    }
+}
+
+/// ドロップ統計の区間ごとにラダーの段を決める
+#[derive(Debug)]
+pub struct LadderController {
+    ladder: QualityLadder,
+    current: usize,
+    congested_intervals: u32,
+    headroom_intervals: u32,
+    /// 上げてからの区間数（上げていなければ None）
+    since_step_up: Option<u32>,
+    /// 上げた直後に詰まった回数（上げる前の待ちを倍にする）
+    backoff_shift: u32,
+    /// キャプチャ側がフレームレートを下げている（この間は上げない）
+    capture_throttled: bool,
+    /// エンコーダーの入力の待ち時間が予算を超えている
+    encoder_overloaded: bool,
+}
+
+impl LadderController {
+    /// キャプチャの設定（`size` と `fps`）を超えない最上段から始める（超えない段がなければ最下段）
+    pub fn new(ladder: QualityLadder, size: CaptureSize, fps: u32) -> Self {
+        let fits = |rung: &QualityRung| {
+            rung.fps <= fps
+                && match size {
+                    CaptureSize::UseSourceSize => true,
+                    CaptureSize::Custom { width, height } => {
+                        rung.width <= width && rung.height <= height
+                    }
+                }
+        };
+        let current = ladder
+            .rungs()
+            .iter()
+            .position(fits)
+            .unwrap_or(ladder.rungs().len() - 1);
+        Self {
+            ladder,
+            current,
+            congested_intervals: 0,
+            headroom_intervals: 0,
+            since_step_up: None,
+            backoff_shift: 0,
+            capture_throttled: false,
+            encoder_overloaded: false,
+        }
+    }
+
+    pub fn current(&self) -> QualityRung {
+        self.ladder.rungs()[self.current]
+    }
+
+    /// キャプチャ側が前面にないウィンドウのフレームレートを下げているか
+    pub fn set_capture_throttled(&mut self, throttled: bool) {
+        self.capture_throttled = throttled;
+    }
+
+    /// エンコーダーの入力の待ち時間が予算を超えているか（超えている間は詰まっているとみなす）
+    pub fn set_encoder_overloaded(&mut self, overloaded: bool) {
+        self.encoder_overloaded = overloaded;
+    }
+
+    /// 区間の統計を記録し、段を変える場合は新しい段を返す
+    pub fn observe(&mut self, report: &VideoStatsPayload) -> Option<QualityRung> {
+        let congested = report.network_loss_rate >= NETWORK_LOSS_WARN_RATE
+            || report.encoder_drop_rate >= ENCODER_DROP_WARN_RATE
+            || self.encoder_overloaded;
+        let headroom = report.network_loss_rate < HEADROOM_NETWORK_LOSS_RATE
+            && report.encoder_drop_rate < HEADROOM_ENCODER_DROP_RATE;
+        if let Some(intervals) = self.since_step_up.as_mut() {
+            *intervals += 1;
+        }
+
+        if congested {
+            self.headroom_intervals = 0;
+            self.congested_intervals += 1;
+            if self.congested_intervals < STEP_DOWN_INTERVALS
+                || self.current + 1 >= self.ladder.rungs().len()
+            {
+                return None;
+            }
+            if self
+                .since_step_up
+                .is_some_and(|intervals| intervals <= FAILED_PROBE_INTERVALS)
+            {
+                self.backoff_shift = (self.backoff_shift + 1).min(MAX_BACKOFF_SHIFT);
+            }
+            self.since_step_up = None;
+            self.congested_intervals = 0;
+            self.current += 1;
+            info!(
+                "Video is congested (network loss {:.1}%, encoder drops {:.1}%, encoder overloaded: {}), stepping down to {}",
+                report.network_loss_rate * 100.0,
+                report.encoder_drop_rate * 100.0,
+                self.encoder_overloaded,
+                self.current()
+            );
+            return Some(self.current());
+        }
+
+        self.congested_intervals = 0;
+        if !headroom || self.capture_throttled {
+            self.headroom_intervals = 0;
+            return None;
+        }
+        self.headroom_intervals += 1;
+        if self.headroom_intervals < STEP_UP_INTERVALS << self.backoff_shift || self.current == 0 {
+            return None;
+        }
+        self.headroom_intervals = 0;
+        self.since_step_up = Some(0);
+        self.current -= 1;
+        info!("Video has headroom, stepping up to {}", self.current());
+        Some(self.current())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(network_loss_rate: f32, encoder_drop_rate: f32) -> VideoStatsPayload {
+        VideoStatsPayload {
+            frames: 150,
+            encoder_dropped: 0,
+            encoder_drop_rate,
+            network_loss_rate,
+            capture_fps: 30.0,
+            keyframes_last_minute: Default::default(),
+            average_qp: None,
+            max_qp: None,
+            capture_dropped: 0,
+            capture_to_encode: None,
+            encode_to_send: None,
+        }
+    }
+
+    fn ladder() -> QualityLadder {
+        "1920x1080@45,1280x720@30,960x540@30".parse().unwrap()
+    }
+
+    /// 最上段と同じ設定で起動したコントローラー
+    fn controller() -> LadderController {
+        LadderController::new(ladder(), CaptureSize::UseSourceSize, 45)
+    }
+
+    /// 同じ区間を `n` 回記録し、段が変わったものを返す
+    fn observe_n(
+        controller: &mut LadderController,
+        n: u32,
+        report: VideoStatsPayload,
+    ) -> Vec<QualityRung> {
+        (0..n).filter_map(|_| controller.observe(&report)).collect()
+    }
+
+    #[test]
+    fn test_parse_quality_ladder() {
+        let ladder: QualityLadder = "default".parse().unwrap();
+        assert_eq!(ladder, QualityLadder::default());
+        assert_eq!(ladder.rungs().len(), 4);
+        assert_eq!(
+            ladder.rungs()[2],
+            QualityRung {
+                width: 1280,
+                height: 720,
+                fps: 30
+            }
+        );
+        assert_eq!(ladder.rungs()[0].to_string(), "1920x1080@45");
+        // 低い順・不正な段は受け付けない
+        assert!("1280x720@30,1920x1080@30".parse::<QualityLadder>().is_err());
+        assert!("1280x720".parse::<QualityLadder>().is_err());
+        assert!("1280x720@0".parse::<QualityLadder>().is_err());
+    }
+
+    #[test]
+    fn test_starts_from_the_configured_capture() {
+        let start = |size, fps| {
+            LadderController::new(ladder(), size, fps)
+                .current()
+                .to_string()
+        };
+        let custom = |width, height| CaptureSize::Custom { width, height };
+
+        assert_eq!(start(CaptureSize::UseSourceSize, 60), "1920x1080@45");
+        assert_eq!(start(CaptureSize::UseSourceSize, 30), "1280x720@30");
+        assert_eq!(start(custom(1280, 720), 60), "1280x720@30");
+        assert_eq!(start(custom(1600, 900), 30), "1280x720@30");
+        // どの段にも収まらなければ最下段
+        assert_eq!(start(custom(640, 360), 30), "960x540@30");
+        assert_eq!(start(CaptureSize::UseSourceSize, 15), "960x540@30");
+
+        // 下から始めても、余裕があれば最上段まで上げる
+        let mut controller = LadderController::new(ladder(), custom(960, 540), 30);
+        observe_n(&mut controller, STEP_UP_INTERVALS * 2, report(0.0, 0.0));
+        assert_eq!(controller.current().to_string(), "1920x1080@45");
+    }
+
+    #[test]
+    fn test_steps_down_on_sustained_congestion() {
+        let mut controller = controller();
+        // 一度だけの詰まりでは下げない
+        assert_eq!(controller.observe(&report(0.1, 0.0)), None);
+        assert_eq!(controller.observe(&report(0.0, 0.0)), None);
+        assert_eq!(controller.observe(&report(0.1, 0.0)), None);
+
+        // エンコーダーが追いつかない場合も下げる
+        let stepped = controller.observe(&report(0.0, 0.2)).unwrap();
+        assert_eq!(stepped.to_string(), "1280x720@30");
+        let stepped = observe_n(&mut controller, 2, report(0.1, 0.0));
+        assert_eq!(stepped[0].to_string(), "960x540@30");
+        // 最下段より下はない
+        assert!(observe_n(&mut controller, 10, report(0.1, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn test_steps_up_after_sustained_headroom() {
+        let mut controller = controller();
+        observe_n(&mut controller, 2, report(0.1, 0.0));
+        assert_eq!(controller.current().to_string(), "1280x720@30");
+
+        // 余裕とも詰まりとも言えない区間は数え直し
+        assert!(observe_n(&mut controller, STEP_UP_INTERVALS - 1, report(0.0, 0.0)).is_empty());
+        assert_eq!(controller.observe(&report(0.03, 0.0)), None);
+        assert!(observe_n(&mut controller, STEP_UP_INTERVALS - 1, report(0.0, 0.0)).is_empty());
+        let stepped = controller.observe(&report(0.0, 0.0)).unwrap();
+        assert_eq!(stepped.to_string(), "1920x1080@45");
+        // 最上段より上はない
+        assert!(observe_n(&mut controller, 100, report(0.0, 0.0)).is_empty());
+    }
+
+    #[test]
+    fn test_failed_step_up_doubles_the_wait() {
+        let mut controller = controller();
+        observe_n(&mut controller, 2, report(0.1, 0.0));
+        observe_n(&mut controller, STEP_UP_INTERVALS, report(0.0, 0.0));
+        assert_eq!(controller.current().to_string(), "1920x1080@45");
+
+        // 上げた直後に詰まったら戻し、次に上げるまでの待ちを倍にする
+        observe_n(&mut controller, 2, report(0.1, 0.0));
+        assert_eq!(controller.current().to_string(), "1280x720@30");
+        assert!(observe_n(&mut controller, STEP_UP_INTERVALS * 2 - 1, report(0.0, 0.0)).is_empty());
+        assert_eq!(
+            controller.observe(&report(0.0, 0.0)).unwrap().to_string(),
+            "1920x1080@45"
+        );
+
+        // 上げてからしばらく安定していた後の詰まりは失敗とはみなさない
+        observe_n(
+            &mut controller,
+            FAILED_PROBE_INTERVALS + 1,
+            report(0.0, 0.0),
+        );
+        observe_n(&mut controller, 2, report(0.1, 0.0));
+        assert!(observe_n(&mut controller, STEP_UP_INTERVALS * 2 - 1, report(0.0, 0.0)).is_empty());
+        assert_eq!(
+            controller.observe(&report(0.0, 0.0)).unwrap().to_string(),
+            "1920x1080@45"
+        );
+    }
+
+    #[test]
+    fn test_does_not_step_up_while_capture_is_throttled() {
+        let mut controller = controller();
+        observe_n(&mut controller, 2, report(0.1, 0.0));
+        assert_eq!(controller.current().to_string(), "1280x720@30");
+
+        // 背面でフレームレートを下げている間の余裕は数えない
+        controller.set_capture_throttled(true);
+        assert!(observe_n(&mut controller, STEP_UP_INTERVALS * 4, report(0.0, 0.0)).is_empty());
+        // 詰まれば下げる
+        let stepped = observe_n(&mut controller, 2, report(0.1, 0.0));
+        assert_eq!(stepped[0].to_string(), "960x540@30");
+
+        // 前面に戻ってから数え直す
+        controller.set_capture_throttled(false);
+        assert!(observe_n(&mut controller, STEP_UP_INTERVALS - 1, report(0.0, 0.0)).is_empty());
+        assert_eq!(
+            controller.observe(&report(0.0, 0.0)).unwrap().to_string(),
+            "1280x720@30"
+        );
+    }
+}
@@ -0,0 +1,422 @@
+// セッションの録画（映像と音声を fMP4 に書き出す）
+//
+// WebRTC への送出とは独立に、エンコード結果をそのまま fragmented MP4 に追記する。ファイルはキーフレームから
+// 始め、フラグメントはキーフレームごと（GOP が長い場合は MAX_FRAGMENT_DURATION ごと）に区切る。
+// 音声は同じフラグメントに、そのフラグメントの映像の終わりまでの分を入れる（タイムスタンプ順に並ぶ）。
+// 書き込みはメインループを止めないよう専用スレッドで行う。
+
+use anyhow::{bail, Context, Result};
+use core_types::{AudioEncodeResult, EncodeResult};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::fmp4::{self, FragmentSample, TrackRun};
+use crate::mp4::append_length_prefixed;
+
+/// 書き込みスレッドに溜められるサンプル数（映像 60fps + 音声 100 パケット/秒で数秒分）
+const QUEUE_CAPACITY: usize = 512;
+/// キーフレームが来なくてもフラグメントを区切る長さ
+const MAX_FRAGMENT_DURATION: Duration = Duration::from_secs(2);
+
+/// 録画するエンコード結果
+#[derive(Debug)]
+pub enum RecordSample {
+    Video(EncodeResult),
+    Audio(AudioEncodeResult),
+}
+
+/// 書き込みスレッドへの受け渡し口（drop すると残りを書き出してファイルを閉じる）
+pub struct Recorder {
+    tx: mpsc::SyncSender<RecordSample>,
+    path: PathBuf,
+    thread: std::thread::JoinHandle<()>,
+}
+
+impl Recorder {
+    /// `path` に録画ファイルを作成し、書き込みスレッドを起動する（`audio` が false なら映像だけ）
+    pub fn spawn(path: &Path, audio: bool) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).with_context(|| {
+                format!("Failed to create recording directory: {}", dir.display())
+            })?;
+        }
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create recording: {}", path.display()))?;
+        info!(
+            "Recording to {} ({})",
+            path.display(),
+            if audio { "video + audio" } else { "video only" }
+        );
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let worker_path = path.to_path_buf();
+        let thread = std::thread::Builder::new()
+            .name("recorder".to_string())
+            .spawn(move || write_samples(BufWriter::new(file), rx, audio, &worker_path))
+            .context("Failed to spawn recorder thread")?;
+        Ok(Self {
+            tx,
+            path: path.to_path_buf(),
+            thread,
+        })
+    }
+
+    /// `dir` に時刻入りの名前で録画を始める
+    pub fn spawn_in(dir: &Path, audio: bool) -> Result<Self> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Self::spawn(&dir.join(format!("recording_{}.mp4", millis)), audio)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// サンプルを書き込みスレッドに渡す（書き込みが追いつかない・書き込みに失敗した場合は false）
+    pub fn push(&self, sample: RecordSample) -> bool {
+        self.tx.try_send(sample).is_ok()
+    }
+
+    /// 録画を止め、ファイルを閉じるまで待つ
+    pub fn finish(self) {
+        drop(self.tx);
+        if self.thread.join().is_err() {
+            warn!("Recorder thread panicked: {}", self.path.display());
+        }
+    }
+}
+
+fn write_samples(
+    mut writer: BufWriter<File>,
+    rx: mpsc::Receiver<RecordSample>,
+    audio: bool,
+    path: &Path,
+) {
+    let mut muxer = RecordingMuxer::new(audio);
+    while let Ok(sample) = rx.recv() {
+        let result = muxer
+            .push(sample)
+            .and_then(|data| writer.write_all(&data).map_err(Into::into));
+        if let Err(e) = result {
+            warn!("Recording {} stopped: {:#}", path.display(), e);
+            break;
+        }
+    }
+    // 受信側を先に閉じ、以降の push を失敗させる
+    drop(rx);
+    // 途中で止まった場合も、それまでの分は再生できるよう書き出す
+    let result = writer
+        .write_all(&muxer.finish())
+        .and_then(|()| writer.flush());
+    match result {
+        Ok(()) => info!(
+            "Recording saved: {} ({:?})",
+            path.display(),
+            muxer.video_duration()
+        ),
+        Err(e) => warn!("Failed to write recording {}: {}", path.display(), e),
+    }
+}
+
+/// エンコード結果をファイルに書き込むバイト列に変換する
+///
+/// 両トラックとも最初のキーフレームのキャプチャ時刻を 0 とし、各サンプルのデコード時刻をキャプチャ時刻から求める。
+/// 音声と映像が別々の時刻に始まったり途中で途切れたりしても、同じ時刻のサンプルは同じ再生位置に並ぶ。
+struct RecordingMuxer {
+    audio: bool,
+    /// 初期化セグメントを書いた時の解像度（None ならまだキーフレームを待っている）
+    resolution: Option<(u32, u32)>,
+    /// 最初のキーフレームのキャプチャ時刻（マイクロ秒。両トラックの時刻 0）
+    origin_us: u64,
+    sequence_number: u32,
+    video: Vec<FragmentSample>,
+    /// 未書き出しの音声と、そのキャプチャ時刻（マイクロ秒）
+    audio_samples: Vec<(u64, FragmentSample)>,
+    /// 未書き出しの先頭の映像サンプルのデコード時刻
+    video_base: u64,
+    /// 最後の映像サンプルのデコード時刻
+    last_video_start: u64,
+    /// 書き出し済みの音声の終わりのデコード時刻
+    audio_end: u64,
+    /// 未書き出しの映像の長さ
+    pending_video: Duration,
+}
+
+impl RecordingMuxer {
+    fn new(audio: bool) -> Self {
+        Self {
+            audio,
+            resolution: None,
+            origin_us: 0,
+            sequence_number: 1,
+            video: Vec::new(),
+            audio_samples: Vec::new(),
+            video_base: 0,
+            last_video_start: 0,
+            audio_end: 0,
+            pending_video: Duration::ZERO,
+        }
+    }
+
+    /// キャプチャ時刻（マイクロ秒）をトラックのタイムスケールでの時刻 0 からの経過に変換する
+    fn ticks_since_origin(&self, timestamp_us: u64, timescale: u32) -> u64 {
+        timestamp_us.saturating_sub(self.origin_us) * timescale as u64 / 1_000_000
+    }
+
+    /// サンプルを追加し、書き出せるようになった分を返す
+    fn push(&mut self, sample: RecordSample) -> Result<Vec<u8>> {
+        match sample {
+            RecordSample::Video(result) => self.push_video(result),
+            RecordSample::Audio(result) => {
+                // 映像の最初のキーフレームより前の音声は捨てる（両トラックとも 0 から始める）
+                if self.audio && self.resolution.is_some() && result.timestamp_us >= self.origin_us
+                {
+                    let duration = result.duration.as_micros() as u64
+                        * fmp4::AUDIO_TIMESCALE as u64
+                        / 1_000_000;
+                    self.audio_samples.push((
+                        result.timestamp_us,
+                        FragmentSample {
+                            data: result.encoded_data,
+                            duration: duration as u32,
+                            is_sync: true,
+                        },
+                    ));
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn push_video(&mut self, result: EncodeResult) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let mut data = Vec::with_capacity(result.sample_data.len());
+        let (mut sps, mut pps) = (None, None);
+        append_length_prefixed(&result.sample_data, &mut data, &mut sps, &mut pps);
+        // capture_timestamp は 100ns 単位
+        let timestamp_us = result.capture_timestamp / 10;
+
+        match self.resolution {
+            None => {
+                if !result.is_keyframe {
+                    return Ok(out);
+                }
+                let (Some(sps), Some(pps)) = (sps, pps) else {
+                    bail!("SPS/PPS not found in the first keyframe");
+                };
+                if sps.len() < 4 {
+                    bail!("SPS is too short ({} bytes)", sps.len());
+                }
+                out = fmp4::init_segment(&sps, &pps, result.width, result.height, self.audio);
+                self.resolution = Some((result.width, result.height));
+                self.origin_us = timestamp_us;
+            }
+            Some(resolution) if resolution != (result.width, result.height) => {
+                // 初期化セグメントの SPS と合わなくなるので、同じファイルには続けられない
+                bail!(
+                    "Resolution changed from {}x{} to {}x{}",
+                    resolution.0,
+                    resolution.1,
+                    result.width,
+                    result.height
+                );
+            }
+            Some(_) => {}
+        }
+
+        // デコード時刻はキャプチャ時刻から求め、前のサンプルの長さを次のサンプルまでの間隔に合わせる
+        let ticks = self.ticks_since_origin(timestamp_us, fmp4::VIDEO_TIMESCALE);
+        let start = match self.video.last_mut() {
+            Some(last) => {
+                let start = ticks.max(self.last_video_start + 1);
+                last.duration = (start - self.last_video_start) as u32;
+                start
+            }
+            None => ticks.max(self.video_base),
+        };
+        if !self.video.is_empty()
+            && (result.is_keyframe || self.pending_video >= MAX_FRAGMENT_DURATION)
+        {
+            out = self.flush(false);
+        }
+        if self.video.is_empty() {
+            self.video_base = start;
+        }
+
+        // 最後のサンプルの長さは次のサンプルが来るまでエンコーダーの報告した長さにしておく
+        let duration = (result.duration.as_nanos() * fmp4::VIDEO_TIMESCALE as u128 / 1_000_000_000)
+            .max(1) as u32;
+        self.last_video_start = start;
+        self.pending_video += result.duration;
+        self.video.push(FragmentSample {
+            data,
+            duration,
+            is_sync: result.is_keyframe,
+        });
+        Ok(out)
+    }
+
+    /// 溜まっている映像と、その終わりまでに始まる音声をフラグメントにする（`all` なら音声も全て）
+    fn flush(&mut self, all: bool) -> Vec<u8> {
+        let video_end = self.video_base + self.video.iter().map(|s| s.duration as u64).sum::<u64>();
+        let audio_len = if all {
+            self.audio_samples.len()
+        } else {
+            // 音声の開始時刻が映像の終わり以降なら次のフラグメントに回す
+            self.audio_samples
+                .iter()
+                .take_while(|(timestamp_us, _)| {
+                    self.ticks_since_origin(*timestamp_us, fmp4::VIDEO_TIMESCALE) < video_end
+                })
+                .count()
+        };
+        let audio_base = self
+            .audio_samples
+            .first()
+            .map(|(timestamp_us, _)| self.ticks_since_origin(*timestamp_us, fmp4::AUDIO_TIMESCALE))
+            .unwrap_or(0)
+            .max(self.audio_end);
+        let audio: Vec<FragmentSample> = self
+            .audio_samples
+            .drain(..audio_len)
+            .map(|(_, sample)| sample)
+            .collect();
+
+        let data = fmp4::fragment(
+            self.sequence_number,
+            &[
+                TrackRun {
+                    track_id: fmp4::VIDEO_TRACK_ID,
+                    base_decode_time: self.video_base,
+                    samples: &self.video,
+                },
+                TrackRun {
+                    track_id: fmp4::AUDIO_TRACK_ID,
+                    base_decode_time: audio_base,
+                    samples: &audio,
+                },
+            ],
+        );
+        self.sequence_number += 1;
+        if !audio.is_empty() {
+            self.audio_end = audio_base + audio.iter().map(|s| s.duration as u64).sum::<u64>();
+        }
+        self.video_base = video_end;
+        self.video.clear();
+        self.pending_video = Duration::ZERO;
+        data
+    }
+
+    /// 残りを全て書き出す
+    fn finish(&mut self) -> Vec<u8> {
+        if self.video.is_empty() && self.audio_samples.is_empty() {
+            return Vec::new();
+        }
+        self.flush(true)
+    }
+
+    /// 書き出した（書き出す予定の）映像の長さ
+    fn video_duration(&self) -> Duration {
+        let ticks = self.video_base + self.video.iter().map(|s| s.duration as u64).sum::<u64>();
+        Duration::from_nanos(ticks * 1_000_000_000 / fmp4::VIDEO_TIMESCALE as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// キャプチャ時刻 `at_ms`（ミリ秒）の映像
+    fn video(is_keyframe: bool, at_ms: u64) -> RecordSample {
+        let mut sample_data = Vec::new();
+        if is_keyframe {
+            sample_data.extend_from_slice(&[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x1F, 0xDA]);
+            sample_data.extend_from_slice(&[0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80]);
+        }
+        sample_data.extend_from_slice(&[0, 0, 0, 1, if is_keyframe { 0x65 } else { 0x41 }, 0x88]);
+        RecordSample::Video(EncodeResult {
+            sample_data,
+            is_keyframe,
+            keyframe_reason: None,
+            duration: Duration::from_millis(100),
+            width: 640,
+            height: 480,
+            capture_timestamp: at_ms * 10_000,
+            average_qp: None,
+            sequence: 0,
+        })
+    }
+
+    /// キャプチャ時刻 `at_ms`（ミリ秒）に始まる 60ms の音声
+    fn audio(at_ms: u64) -> RecordSample {
+        RecordSample::Audio(AudioEncodeResult {
+            encoded_data: vec![0xFC, 0xFF, 0xFE],
+            duration: Duration::from_millis(60),
+            is_silent: false,
+            timestamp_us: at_ms * 1_000,
+        })
+    }
+
+    fn count(data: &[u8], kind: &[u8; 4]) -> usize {
+        data.windows(4).filter(|w| w == kind).count()
+    }
+
+    #[test]
+    fn test_starts_at_keyframe_and_interleaves_audio_by_time() {
+        let mut muxer = RecordingMuxer::new(true);
+        // キーフレームより前の映像と音声は捨てる
+        assert!(muxer.push(audio(900)).unwrap().is_empty());
+        assert!(muxer.push(video(false, 950)).unwrap().is_empty());
+
+        let init = muxer.push(video(true, 1_000)).unwrap();
+        assert_eq!(count(&init, b"moov"), 1);
+        assert_eq!(count(&init, b"moof"), 0);
+        // キーフレームより前に始まる音声も捨てる
+        muxer.push(audio(970)).unwrap();
+        // 映像のフレームが抜けた場合、前のサンプルの長さはキャプチャ時刻の間隔になる
+        assert!(muxer.push(video(false, 1_150)).unwrap().is_empty());
+        assert_eq!(muxer.video[0].duration, 13_500);
+        // 音声は映像より 30ms 遅れて始まる
+        for at_ms in [1_030, 1_090, 1_150, 1_210, 1_270] {
+            muxer.push(audio(at_ms)).unwrap();
+        }
+
+        // 次のキーフレームで前の GOP がフラグメントになる
+        let fragment = muxer.push(video(true, 1_200)).unwrap();
+        assert_eq!(count(&fragment, b"moof"), 1);
+        assert_eq!(count(&fragment, b"trun"), 2);
+        // 音声の tfdt は映像と同じキャプチャ時刻の 0 から数える (30ms)
+        let tfdts: Vec<usize> = (0..fragment.len() - 4)
+            .filter(|&i| &fragment[i..i + 4] == b"tfdt")
+            .collect();
+        let base = |i: usize| u64::from_be_bytes(fragment[i + 8..i + 16].try_into().unwrap());
+        assert_eq!(base(tfdts[0]), 0);
+        assert_eq!(base(tfdts[1]), 1_440);
+        // 映像の終わり (200ms) 以降の 210ms, 270ms に始まる音声は次のフラグメントに回る
+        assert_eq!(muxer.audio_samples.len(), 2);
+        assert_eq!(muxer.video_base, 18_000);
+        assert_eq!(muxer.audio_end, 1_440 + 3 * 2_880);
+
+        let rest = muxer.finish();
+        assert_eq!(count(&rest, b"moof"), 1);
+        assert!(muxer.audio_samples.is_empty());
+        assert_eq!(muxer.video_duration(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_stops_on_resolution_change() {
+        let mut muxer = RecordingMuxer::new(false);
+        muxer.push(video(true, 0)).unwrap();
+        let RecordSample::Video(mut result) = video(true, 100) else {
+            unreachable!();
+        };
+        result.width = 1280;
+        assert!(muxer.push(RecordSample::Video(result)).is_err());
+    }
+}
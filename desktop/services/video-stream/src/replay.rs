@@ -0,0 +1,261 @@
+// インスタントリプレイ用のリングバッファ
+//
+// 直近 N 秒分のエンコード結果を GOP 単位で保持し、保存要求で MP4 に書き出す。
+// 先頭は常にキーフレームになるよう、古いサンプルはキーフレーム単位で捨てる。
+
+use anyhow::{Context, Result};
+use core_types::EncodeResult;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::mp4::{self, Mp4Sample};
+
+/// キーフレームが来ないままこの倍率を超えたらバッファを破棄する（メモリ上限）
+const MAX_DURATION_FACTOR: u32 = 4;
+
+/// 保存用に取り出したクリップ
+#[derive(Debug, Clone)]
+pub struct ReplayClip {
+    pub samples: Vec<Mp4Sample>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ReplayClip {
+    pub fn duration(&self) -> Duration {
+        self.samples.iter().map(|s| s.duration).sum()
+    }
+}
+
+/// 直近 `max_duration` 分のサンプルを保持するリングバッファ
+pub struct ReplayBuffer {
+    max_duration: Duration,
+    samples: VecDeque<Mp4Sample>,
+    buffered: Duration,
+    /// 最後のキーフレームからの経過時間
+    since_keyframe: Duration,
+    /// キーフレームを要求済みで到着待ちか
+    keyframe_pending: bool,
+    width: u32,
+    height: u32,
+}
+
+impl ReplayBuffer {
+    pub fn new(max_duration: Duration) -> Self {
+        Self {
+            max_duration,
+            samples: VecDeque::new(),
+            buffered: Duration::ZERO,
+            since_keyframe: Duration::ZERO,
+            keyframe_pending: false,
+            width: 0,
+            height: 0,
+        }
+    }
+
+    pub fn push(&mut self, result: &EncodeResult) {
+        // 解像度が変わったら古い GOP は別の SPS なので捨てる
+        if (result.width, result.height) != (self.width, self.height) {
+            self.clear();
+            self.width = result.width;
+            self.height = result.height;
+        }
+
+        // 先頭はキーフレームから始める
+        if self.samples.is_empty() && !result.is_keyframe {
+            return;
+        }
+
+        if result.is_keyframe {
+            self.since_keyframe = Duration::ZERO;
+            self.keyframe_pending = false;
+        }
+        self.since_keyframe += result.duration;
+        self.buffered += result.duration;
+        self.samples.push_back(Mp4Sample {
+            data: result.sample_data.clone(),
+            is_keyframe: result.is_keyframe,
+            duration: result.duration,
+        });
+
+        self.trim();
+    }
+
+    /// 先頭の GOP を捨てても `max_duration` 以上残るなら捨てる
+    fn trim(&mut self) {
+        while let Some(next_keyframe) = self
+            .samples
+            .iter()
+            .skip(1)
+            .position(|s| s.is_keyframe)
+            .map(|i| i + 1)
+        {
+            let gop: Duration = self
+                .samples
+                .iter()
+                .take(next_keyframe)
+                .map(|s| s.duration)
+                .sum();
+            if self.buffered - gop < self.max_duration {
+                break;
+            }
+            self.samples.drain(..next_keyframe);
+            self.buffered -= gop;
+        }
+
+        // キーフレームが来ないまま膨らみ続ける場合の保険
+        if self.buffered > self.max_duration * MAX_DURATION_FACTOR {
+            warn!(
+                "Replay buffer exceeded {:?} without a keyframe, discarding",
+                self.buffered
+            );
+            self.clear();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.buffered = Duration::ZERO;
+        self.since_keyframe = Duration::ZERO;
+        self.keyframe_pending = false;
+    }
+
+    /// 古い GOP を捨てられるようにキーフレームを要求すべきか
+    /// true を返したら、次のキーフレームが届くまでは再度 true を返さない
+    pub fn take_keyframe_request(&mut self) -> bool {
+        if self.keyframe_pending {
+            return false;
+        }
+        if self.samples.is_empty() || self.since_keyframe >= self.max_duration {
+            self.keyframe_pending = true;
+            return true;
+        }
+        false
+    }
+
+    /// 現在のバッファ内容を取り出す（バッファは空にしない）
+    pub fn snapshot(&self) -> Option<ReplayClip> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(ReplayClip {
+            samples: self.samples.iter().cloned().collect(),
+            width: self.width,
+            height: self.height,
+        })
+    }
+}
+
+/// `dir` 以下にタイムスタンプ付きのファイル名で MP4 を保存し、パスを返す
+pub fn save_clip(clip: &ReplayClip, dir: &Path) -> Result<PathBuf> {
+    let data = mp4::mux_h264(&clip.samples, clip.width, clip.height)?;
+
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create replay directory: {}", dir.display()))?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = dir.join(format!("replay_{}.mp4", millis));
+    std::fs::write(&path, data)
+        .with_context(|| format!("Failed to write replay: {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(is_keyframe: bool) -> EncodeResult {
+        EncodeResult {
+            sample_data: vec![0, 0, 0, 1, if is_keyframe { 0x65 } else { 0x41 }, 0x88],
+            is_keyframe,
+            keyframe_reason: None,
+            duration: Duration::from_millis(100),
+            width: 640,
+            height: 480,
+            capture_timestamp: 0,
+            average_qp: None,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_starts_from_keyframe() {
+        let mut buffer = ReplayBuffer::new(Duration::from_secs(1));
+        buffer.push(&result(false));
+        assert!(buffer.snapshot().is_none());
+
+        buffer.push(&result(true));
+        buffer.push(&result(false));
+        let clip = buffer.snapshot().unwrap();
+        assert_eq!(clip.samples.len(), 2);
+        assert!(clip.samples[0].is_keyframe);
+        assert_eq!((clip.width, clip.height), (640, 480));
+    }
+
+    #[test]
+    fn test_trims_whole_gops() {
+        let mut buffer = ReplayBuffer::new(Duration::from_millis(500));
+        // GOP = 4 フレーム (400ms) を 3 つ
+        for _ in 0..3 {
+            buffer.push(&result(true));
+            for _ in 0..3 {
+                buffer.push(&result(false));
+            }
+        }
+        // 最初の GOP を捨てても 800ms 残るので捨てられるが、2 つ目を捨てると 400ms で足りない
+        let clip = buffer.snapshot().unwrap();
+        assert_eq!(clip.samples.len(), 8);
+        assert!(clip.samples[0].is_keyframe);
+        assert_eq!(clip.duration(), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn test_resolution_change_resets() {
+        let mut buffer = ReplayBuffer::new(Duration::from_secs(1));
+        buffer.push(&result(true));
+        buffer.push(&result(false));
+
+        let mut resized = result(true);
+        resized.width = 1280;
+        resized.height = 720;
+        buffer.push(&resized);
+
+        let clip = buffer.snapshot().unwrap();
+        assert_eq!(clip.samples.len(), 1);
+        assert_eq!((clip.width, clip.height), (1280, 720));
+    }
+
+    #[test]
+    fn test_keyframe_request() {
+        let mut buffer = ReplayBuffer::new(Duration::from_millis(300));
+        // 空のうちはキーフレームが必要
+        assert!(buffer.take_keyframe_request());
+        assert!(!buffer.take_keyframe_request());
+
+        buffer.push(&result(true));
+        buffer.push(&result(false));
+        assert!(!buffer.take_keyframe_request());
+
+        // 最後のキーフレームから 300ms 経過
+        buffer.push(&result(false));
+        assert!(buffer.take_keyframe_request());
+        assert!(!buffer.take_keyframe_request());
+
+        buffer.push(&result(true));
+        assert!(!buffer.take_keyframe_request());
+    }
+
+    #[test]
+    fn test_discards_when_keyframes_stop() {
+        let mut buffer = ReplayBuffer::new(Duration::from_millis(200));
+        buffer.push(&result(true));
+        for _ in 0..8 {
+            buffer.push(&result(false));
+        }
+        assert!(buffer.snapshot().is_none());
+    }
+}
@@ -0,0 +1,107 @@
+// 接続直後のキーフレームの追い打ち
+//
+// 接続直後の最初のキーフレームは ICE/DTLS が落ち着く前に送られて失われることがあり、
+// その場合ブラウザは PLI を送って次のキーフレームが届くまで何も表示できない。
+// 接続完了（connection_ready が true になった時点）から一定間隔でキーフレームを数回追加で要求し、
+// 最初の 1 枚が失われても PLI の往復を待たずに映像が出るようにする。
+
+use std::time::{Duration, Instant};
+
+/// 接続時に送るキーフレームの数（デフォルト、接続時の 1 枚を含む）
+pub const DEFAULT_STARTUP_KEYFRAME_COUNT: u32 = 2;
+/// 追加のキーフレームの間隔（デフォルト）
+pub const DEFAULT_STARTUP_KEYFRAME_SPACING: Duration = Duration::from_millis(300);
+
+#[derive(Debug)]
+pub struct StartupKeyframes {
+    /// 接続時に送るキーフレームの数（接続時に要求する 1 枚を含む）
+    count: u32,
+    spacing: Duration,
+    /// 接続完了後に追加で要求する残りの数
+    remaining: u32,
+    /// 次に要求する時刻（接続完了を待っている間は None）
+    next_at: Option<Instant>,
+}
+
+impl StartupKeyframes {
+    pub fn new(count: u32, spacing: Duration) -> Self {
+        Self {
+            count,
+            spacing,
+            remaining: 0,
+            next_at: None,
+        }
+    }
+
+    /// 新しい接続に切り替わった（最初の 1 枚は呼び出し側が要求する）
+    pub fn start(&mut self) {
+        self.remaining = self.count.saturating_sub(1);
+        self.next_at = None;
+    }
+
+    /// 追加の要求が残っているか
+    pub fn is_pending(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// 接続状態 `connected` を確認し、追加のキーフレームを要求すべきなら true を返す
+    pub fn poll(&mut self, connected: bool, now: Instant) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+        let Some(next_at) = self.next_at else {
+            // 接続完了から最初の間隔を数える
+            if connected {
+                self.next_at = Some(now + self.spacing);
+            }
+            return false;
+        };
+        if now < next_at {
+            return false;
+        }
+        self.remaining -= 1;
+        self.next_at = Some(now + self.spacing);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests_extra_keyframes_after_connection_ready() {
+        let spacing = Duration::from_millis(300);
+        let mut startup = StartupKeyframes::new(3, spacing);
+        let t0 = Instant::now();
+        assert!(!startup.is_pending());
+
+        startup.start();
+        assert!(startup.is_pending());
+        // 接続完了までは要求しない
+        assert!(!startup.poll(false, t0));
+        assert!(!startup.poll(false, t0 + spacing * 2));
+
+        // 接続完了から間隔ごとに 1 枚ずつ、合計で count - 1 枚
+        let ready = t0 + spacing * 3;
+        assert!(!startup.poll(true, ready));
+        assert!(!startup.poll(true, ready + spacing / 2));
+        assert!(startup.poll(true, ready + spacing));
+        assert!(!startup.poll(true, ready + spacing + spacing / 2));
+        assert!(startup.poll(true, ready + spacing * 2));
+        assert!(!startup.is_pending());
+        assert!(!startup.poll(true, ready + spacing * 10));
+
+        // 再接続でやり直す
+        startup.start();
+        assert!(startup.is_pending());
+    }
+
+    #[test]
+    fn test_single_keyframe_disables_burst() {
+        let mut startup = StartupKeyframes::new(1, Duration::from_millis(300));
+        startup.start();
+        assert!(!startup.is_pending());
+        assert!(!startup.poll(true, Instant::now() + Duration::from_secs(1)));
+    }
+}
@@ -2,34 +2,37 @@ use anyhow::Result;
 use bytes::Bytes;
 use core_types::EncodeResult;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::{error, span, Level};
 use webrtc_rs::media::Sample;
+use webrtc_rs::rtp::extension::HeaderExtension;
 use webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 
-/// エンコード結果をトラックに書き込む
+/// エンコード結果をトラックに書き込む（`extensions` はサンプルの全パケットに載せる）
 pub async fn write_encoded_sample(
     track: &Arc<TrackLocalStaticSample>,
     result: EncodeResult,
+    extensions: &[HeaderExtension],
 ) -> Result<()> {
     let sample_size = result.sample_data.len();
-    let sample = Sample {
-        data: Bytes::from(result.sample_data),
-        duration: result.duration,
-        ..Default::default()
-    };
+    let (width, height, is_keyframe) = (result.width, result.height, result.is_keyframe);
+    let sample = encoded_sample(result);
 
     // サンプル書き込みを span で計測
     let write_sample_span = span!(
         Level::DEBUG,
         "write_sample",
-        width = result.width,
-        height = result.height,
+        width = width,
+        height = height,
         sample_size = sample_size,
-        is_keyframe = result.is_keyframe
+        is_keyframe = is_keyframe
     );
     let _write_sample_guard = write_sample_span.enter();
 
-    match track.write_sample(&sample).await {
+    match track
+        .write_sample_with_extensions(&sample, extensions)
+        .await
+    {
         Ok(_) => {
             drop(_write_sample_guard);
             Ok(())
@@ -41,3 +44,265 @@ pub async fn write_encoded_sample(
         }
     }
 }
+
+/// 新しいトラック（セッション）に最初のキーフレームが届くまで差分フレームを止める
+///
+/// 配信の途中で接続したビューアーは、参照先のキーフレームを持たないまま差分フレームを受け取ると
+/// PLI でキーフレームが届くまで緑や崩れた絵を出す。接続時にキーフレームを要求した上で、
+/// それが届くまでの差分フレームはそのトラックに書き込まない。状態はトラックを受け取るたびに作り直す。
+#[derive(Debug)]
+pub struct KeyframeGate {
+    /// 最初のキーフレームを待っている
+    waiting: bool,
+    /// 待っている間に止めた差分フレームの数
+    suppressed: u64,
+}
+
+impl KeyframeGate {
+    /// `enabled` が false なら何も止めない
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            waiting: enabled,
+            suppressed: 0,
+        }
+    }
+
+    /// トラックに書き込んでよいか（キーフレームで待ちを終える）
+    pub fn admit(&mut self, is_keyframe: bool) -> bool {
+        if !self.waiting {
+            return true;
+        }
+        if is_keyframe {
+            self.waiting = false;
+            return true;
+        }
+        self.suppressed += 1;
+        false
+    }
+
+    /// 待ちを終えた後に、止めた差分フレームの数を取り出す（ログ用）
+    pub fn take_suppressed(&mut self) -> Option<u64> {
+        if self.waiting || self.suppressed == 0 {
+            return None;
+        }
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// 一時停止中にキーフレームを送り直すフレームレート（デフォルト）
+pub const DEFAULT_PAUSE_HEARTBEAT_FPS: u32 = 1;
+
+/// 一時停止中に最後のキーフレームを一定間隔で送り直す
+///
+/// 一時停止中はエンコーダーにフレームを流さないのでトラックに何も書かれず、長く続くと
+/// SFU やブラウザによっては映像のトランスポートを閉じてしまう。一時停止の直後に出る黒いフレームを
+/// キーフレームにしておき、それを低いレート（デフォルト 1fps）で書き込み続けてトラックを生かしておく。
+/// キーフレームなので単独でデコードでき、再開時は通常の Resume のキーフレームからすぐに続けられる。
+#[derive(Debug)]
+pub struct PauseHeartbeat {
+    interval: Duration,
+    /// 最後に受け取ったキーフレーム
+    last_keyframe: Option<EncodeResult>,
+    /// 最後にトラックに書き込んだ時刻（通常のフレームを含む）
+    last_written: Option<Instant>,
+}
+
+impl PauseHeartbeat {
+    /// `fps` が 0 なら None（送り直さない）
+    pub fn new(fps: u32) -> Option<Self> {
+        (fps > 0).then(|| Self {
+            interval: Duration::from_secs(1) / fps,
+            last_keyframe: None,
+            last_written: None,
+        })
+    }
+
+    /// 送り直しが必要かを確認する間隔（間隔より短くして、送り直しの間隔が延びないようにする）
+    pub fn poll_interval(&self) -> Duration {
+        self.interval / 4
+    }
+
+    /// エンコード結果を受け取った（キーフレームなら送り直す候補として保持する）
+    pub fn record(&mut self, result: &EncodeResult, now: Instant) {
+        if result.is_keyframe {
+            self.last_keyframe = Some(result.clone());
+        }
+        self.last_written = Some(now);
+    }
+
+    /// 一時停止中で前回の書き込みから間隔が空いていれば、送り直すキーフレームを返す
+    pub fn poll(&mut self, paused: bool, now: Instant) -> Option<EncodeResult> {
+        if !paused {
+            return None;
+        }
+        if self
+            .last_written
+            .is_some_and(|written| now.duration_since(written) < self.interval)
+        {
+            return None;
+        }
+        let keyframe = self.last_keyframe.clone()?;
+        self.last_written = Some(now);
+        // 次のサンプルの RTP タイムスタンプが実際の間隔だけ進むようにする
+        Some(EncodeResult {
+            duration: self.interval,
+            ..keyframe
+        })
+    }
+
+    /// 保持しているキーフレームを捨てる（一時停止した時とコーデックが変わった時に、古い絵を送らないようにする）
+    pub fn reset(&mut self) {
+        self.last_keyframe = None;
+    }
+}
+
+/// アクセスユニット全体（Annex B）を 1 サンプルにする
+/// MTU を超える NAL は webrtc-rs の H264Payloader が FU-A（packetization-mode=1）に分割するので、
+/// ここで分割してはいけない（分割したデータは Annex B として解釈できず packetizer が壊す）
+fn encoded_sample(result: EncodeResult) -> Sample {
+    Sample {
+        data: Bytes::from(result.sample_data),
+        duration: result.duration,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use webrtc_rs::rtp::codecs::h264::{H264Packet, H264Payloader};
+    use webrtc_rs::rtp::packetizer::{Depacketizer, Payloader};
+
+    /// webrtc-rs がサンプルを RTP に分割するときの MTU（RTP_OUTBOUND_MTU）から RTP ヘッダーを除いたもの
+    const RTP_PAYLOAD_MTU: usize = 1200 - 12;
+
+    #[test]
+    fn test_large_keyframe_is_one_sample_fragmented_by_rtp() {
+        let idr: Vec<u8> = std::iter::once(0x65)
+            .chain((0..4000u32).map(|i| (i % 251) as u8 + 1))
+            .collect();
+        let mut access_unit = Vec::new();
+        for nal in [
+            &[0x67, 0x64, 0x00, 0x33][..],
+            &[0x68, 0xee, 0x3c, 0x80],
+            &idr,
+        ] {
+            access_unit.extend_from_slice(&[0, 0, 0, 1]);
+            access_unit.extend_from_slice(nal);
+        }
+        assert!(access_unit.len() > 1500);
+
+        let sample = encoded_sample(EncodeResult {
+            sample_data: access_unit.clone(),
+            is_keyframe: true,
+            keyframe_reason: None,
+            duration: Duration::from_millis(16),
+            width: 3840,
+            height: 2160,
+            capture_timestamp: 0,
+            average_qp: None,
+            sequence: 0,
+        });
+        // アクセスユニットはそのまま 1 サンプルになる
+        assert_eq!(sample.data.as_ref(), access_unit.as_slice());
+
+        // RTP 層が MTU に収まるよう分割し、IDR は FU-A（type 28）になる
+        let payloads = H264Payloader::default()
+            .payload(RTP_PAYLOAD_MTU, &sample.data)
+            .unwrap();
+        assert!(payloads.len() > 3, "{} packets", payloads.len());
+        assert!(payloads.iter().all(|p| p.len() <= RTP_PAYLOAD_MTU));
+        let fu_a = payloads.iter().filter(|p| p[0] & 0x1f == 28).count();
+        assert_eq!(fu_a, payloads.len() - 1);
+
+        // 受信側で組み立て直すと IDR が元どおりになる
+        let mut depacketizer = H264Packet::default();
+        let mut received = Vec::new();
+        for payload in &payloads {
+            received.extend_from_slice(&depacketizer.depacketize(payload).unwrap());
+        }
+        assert!(received.ends_with(&idr));
+    }
+
+    fn encode_result(is_keyframe: bool) -> EncodeResult {
+        EncodeResult {
+            sample_data: vec![0, 0, 0, 1, if is_keyframe { 0x65 } else { 0x41 }],
+            is_keyframe,
+            keyframe_reason: None,
+            duration: Duration::from_millis(16),
+            width: 1280,
+            height: 720,
+            capture_timestamp: 0,
+            average_qp: None,
+            sequence: 0,
+        }
+    }
+
+    #[test]
+    fn test_pause_heartbeat_resends_keyframe_every_interval() {
+        assert!(PauseHeartbeat::new(0).is_none());
+        let mut heartbeat = PauseHeartbeat::new(2).unwrap();
+        let interval = Duration::from_millis(500);
+        let t0 = Instant::now();
+
+        // 配信中は送り直さない
+        heartbeat.record(&encode_result(true), t0);
+        heartbeat.record(&encode_result(false), t0 + interval / 2);
+        assert!(heartbeat.poll(false, t0 + interval * 4).is_none());
+
+        // 一時停止したら、黒いキーフレームが届くまで一時停止の前のキーフレームは送らない
+        let paused_at = t0 + interval;
+        heartbeat.reset();
+        assert!(heartbeat.poll(true, paused_at).is_none());
+
+        // 一時停止の直後の黒いキーフレーム
+        heartbeat.record(&encode_result(true), paused_at);
+
+        // 確認のたびに、前回の書き込みから間隔を超えて空くことがない
+        let mut last = paused_at;
+        let mut sent = 0;
+        let mut now = paused_at;
+        while now < paused_at + interval * 10 {
+            now += heartbeat.poll_interval();
+            if let Some(resent) = heartbeat.poll(true, now) {
+                assert!(resent.is_keyframe);
+                assert_eq!(resent.duration, interval);
+                last = now;
+                sent += 1;
+            }
+            assert!(now.duration_since(last) < interval, "{:?}", now - last);
+        }
+        assert_eq!(sent, 10);
+
+        // 再開したらすぐに止める
+        assert!(heartbeat.poll(false, now + interval).is_none());
+
+        // キーフレームがなければ送らない
+        heartbeat.reset();
+        assert!(heartbeat.poll(true, now + interval * 2).is_none());
+    }
+
+    #[test]
+    fn test_late_attach_waits_for_keyframe() {
+        // 配信中（差分フレームが流れている最中）に 2 つ目のセッションが接続する
+        let stream = [true, false, false, false, false, true, false];
+        let mut first = KeyframeGate::new(true);
+        let first_written: Vec<bool> = stream.iter().map(|&k| first.admit(k)).collect();
+        assert!(first_written.iter().all(|&w| w));
+
+        let mut late = KeyframeGate::new(true);
+        let late_written: Vec<bool> = stream[2..].iter().map(|&k| late.admit(k)).collect();
+        assert_eq!(late_written, [false, false, false, true, true]);
+        assert_eq!(late.take_suppressed(), Some(3));
+        assert_eq!(late.take_suppressed(), None);
+
+        // 後から接続したセッションの待ちは最初のセッションに影響しない
+        assert!(first.admit(false));
+
+        // 無効なら最初から差分フレームを書き込む
+        let mut disabled = KeyframeGate::new(false);
+        assert!(disabled.admit(false));
+        assert_eq!(disabled.take_suppressed(), None);
+    }
+}
@@ -0,0 +1,221 @@
+// エンコーダーに渡したフレームを無圧縮でファイルに書き出すデバッグ用シンク
+//
+// エンコーダーや色変換の不具合を、ユーザーが見ていたのと同じピクセルで再現できるようにする。
+// 書き込みは専用スレッドで行い、受け渡しはエンコーダーと同じ EncodeJobSlot（最新のフレームのみ保持）
+// なので、書き込みが追いつかない場合はフレームを捨ててキャプチャのタイミングには影響させない。
+//
+// ファイル形式: 先頭に `DUMP_MAGIC`、以降フレームごとに
+// width, height, fps, format (u32 LE, 0: RGBA / 1: BGRA), windows_timespan (u64 LE),
+// 画素データ (width * height * 4 バイト)
+
+use anyhow::{Context, Result};
+use core_types::{EncodeJob, EncodeJobSlot, Frame, PixelFormat};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// フレームのヘッダーに画素の並び（format）を追加した版
+const DUMP_MAGIC: &[u8; 8] = b"RRGVRAW2";
+/// format のない RGBA 固定の旧形式
+const DUMP_MAGIC_V1: &[u8; 8] = b"RRGVRAW1";
+/// フレームごとのヘッダーのバイト数
+const FRAME_HEADER_BYTES: u64 = 4 * 4 + 8;
+
+/// 書き込みスレッドへの受け渡し口（drop すると書き込みを終える）
+pub struct VideoDump {
+    slot: Arc<EncodeJobSlot>,
+}
+
+impl VideoDump {
+    /// `path` にダンプファイルを作成し、書き込みスレッドを起動する
+    /// 合計が `max_bytes` を超えるフレームは書き込まずにファイルを閉じる
+    pub fn spawn(path: &Path, max_bytes: u64) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create video dump: {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(DUMP_MAGIC)?;
+        info!(
+            "Dumping encoder input frames to {} (up to {} bytes)",
+            path.display(),
+            max_bytes
+        );
+
+        let slot = EncodeJobSlot::new();
+        let worker_slot = slot.clone();
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("video-dump".to_string())
+            .spawn(move || {
+                let jobs = std::iter::from_fn(|| worker_slot.take().ok());
+                write_frames(writer, jobs, &path, max_bytes)
+            })
+            .context("Failed to spawn video dump thread")?;
+        Ok(Self { slot })
+    }
+
+    /// フレームを書き込みスレッドに渡す（書き込み中なら前のフレームを置き換える）
+    pub fn push(&self, frame: &Frame) {
+        self.slot.set(dump_job(frame));
+    }
+}
+
+fn dump_job(frame: &Frame) -> EncodeJob {
+    EncodeJob {
+        width: frame.width,
+        height: frame.height,
+        rgba: frame.data.clone(),
+        timestamp: frame.windows_timespan,
+        enqueue_at: Instant::now(),
+        request_keyframe: None,
+        fps: frame.fps,
+        format: frame.format,
+        sequence: frame.sequence,
+        scale_to: frame.scale_to,
+        max_bitrate_bps: None,
+    }
+}
+
+impl Drop for VideoDump {
+    fn drop(&mut self) {
+        self.slot.shutdown();
+    }
+}
+
+/// `jobs` を書き込む（書き込みスレッドではスロットが shutdown されるまで続く）
+fn write_frames(
+    mut writer: BufWriter<File>,
+    jobs: impl IntoIterator<Item = EncodeJob>,
+    path: &Path,
+    max_bytes: u64,
+) {
+    let mut written_bytes = DUMP_MAGIC.len() as u64;
+    for (frames, job) in jobs.into_iter().enumerate() {
+        let frame_bytes = FRAME_HEADER_BYTES + job.rgba.len() as u64;
+        if written_bytes + frame_bytes > max_bytes {
+            info!(
+                "Video dump reached {} bytes ({} frames), stopping: {}",
+                written_bytes,
+                frames,
+                path.display()
+            );
+            break;
+        }
+        let result = writer
+            .write_all(&job.width.to_le_bytes())
+            .and_then(|_| writer.write_all(&job.height.to_le_bytes()))
+            .and_then(|_| writer.write_all(&job.fps.to_le_bytes()))
+            .and_then(|_| writer.write_all(&format_code(job.format).to_le_bytes()))
+            .and_then(|_| writer.write_all(&job.timestamp.to_le_bytes()))
+            .and_then(|_| writer.write_all(&job.rgba));
+        if let Err(e) = result {
+            warn!("Failed to write video dump {}: {}", path.display(), e);
+            break;
+        }
+        written_bytes += frame_bytes;
+    }
+
+    if let Err(e) = writer.flush() {
+        warn!("Failed to flush video dump {}: {}", path.display(), e);
+    }
+}
+
+fn format_code(format: PixelFormat) -> u32 {
+    match format {
+        PixelFormat::Rgba8 => 0,
+        PixelFormat::Bgra8 => 1,
+    }
+}
+
+/// ダンプファイルからフレームを読み出す（エンコーダーに同じ入力を与えて再現するため）
+pub fn read_video_dump(path: &Path) -> Result<Vec<Frame>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open video dump: {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(
+        &magic != DUMP_MAGIC_V1,
+        "Video dump uses the old RGBA-only format without pixel format: {}",
+        path.display()
+    );
+    anyhow::ensure!(&magic == DUMP_MAGIC, "Not a video dump: {}", path.display());
+
+    let mut frames = Vec::new();
+    let mut header = [0u8; FRAME_HEADER_BYTES as usize];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let (width, height, fps) = (u32_at(0), u32_at(4), u32_at(8));
+        let format = match u32_at(12) {
+            0 => PixelFormat::Rgba8,
+            1 => PixelFormat::Bgra8,
+            other => anyhow::bail!("Unknown pixel format in video dump: {}", other),
+        };
+        let windows_timespan = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let mut data = vec![0u8; width as usize * height as usize * 4];
+        reader
+            .read_exact(&mut data)
+            .context("Video dump ends in the middle of a frame")?;
+        frames.push(Frame {
+            width,
+            height,
+            data: Arc::new(data),
+            windows_timespan,
+            fps,
+            format,
+            dirty_fraction: None,
+            sequence: 0,
+            scale_to: None,
+        });
+    }
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_round_trip_with_size_cap() {
+        let path = std::env::temp_dir().join(format!("video_dump_test_{}.raw", std::process::id()));
+        let mut writer = BufWriter::new(File::create(&path).unwrap());
+        writer.write_all(DUMP_MAGIC).unwrap();
+        let jobs = (0..4u8).map(|i| {
+            dump_job(&Frame {
+                width: 4,
+                height: 2,
+                data: Arc::new(vec![i; 32]),
+                windows_timespan: i as u64 * 222_222,
+                fps: 45,
+                format: PixelFormat::Bgra8,
+                dirty_fraction: None,
+                sequence: i as u64 + 1,
+                scale_to: None,
+            })
+        });
+        // 4x2（32 バイト + ヘッダー 24 バイト）のフレーム 2 つ分まで
+        write_frames(writer, jobs, &path, 8 + 56 * 2);
+
+        let frames = read_video_dump(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            (frames[1].width, frames[1].height, frames[1].fps),
+            (4, 2, 45)
+        );
+        assert_eq!(frames[1].windows_timespan, 222_222);
+        assert_eq!(frames[1].format, PixelFormat::Bgra8);
+        assert_eq!(*frames[1].data, vec![1u8; 32]);
+
+        // ヘッダーの並びが異なる旧形式は読み違えずにエラーにする
+        std::fs::write(&path, DUMP_MAGIC_V1).unwrap();
+        assert!(read_video_dump(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
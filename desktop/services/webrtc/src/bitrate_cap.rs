@@ -0,0 +1,160 @@
+// 接続ごとのビットレートの上限
+//
+// ブラウザが Offer の b=AS / b=TIAS で示した帯域を、エンコーダーのビットレートの上限にする。
+// エンコーダーは全接続で 1 つなので、上限は接続ごとに覚えておき、接続中の上限の最小値を渡す。
+// 接続を差し替えると古い PeerConnection の Closed は新しい接続の Offer より後に届くことがあるので、
+// 「最後に届いた値」ではなく、接続が終わった時にその接続の上限だけを外す。
+// Offer の処理が途中で失敗した接続は終わりを知らせてこないので、追加した時のガードを落とした時に外す。
+
+use core_types::VideoStreamMessage;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+/// 接続ごとのビットレートの上限を持ち、変わったら最小値を VideoStreamService に渡す
+#[derive(Clone, Default)]
+pub struct BitrateCaps {
+    /// 送り終えるまでロックして、上限の更新が入れ替わらないようにする
+    state: Arc<Mutex<CapState>>,
+}
+
+#[derive(Default)]
+struct CapState {
+    /// 接続ごとの上限（上限を示さなかった接続は None）
+    caps: HashMap<u64, Option<u32>>,
+    next_id: u64,
+}
+
+impl BitrateCaps {
+    /// 接続を追加する（番号は `BitrateCapGuard::id` で `remove` に渡す）
+    /// 接続を確立できたらガードの `disarm` を呼ぶ。呼ばずに落とすとその接続の上限を外す
+    pub async fn add(
+        &self,
+        video_stream_msg_tx: &mpsc::Sender<VideoStreamMessage>,
+        bitrate_bps: Option<u32>,
+    ) -> BitrateCapGuard {
+        let mut state = self.state.lock().await;
+        let id = state.next_id;
+        state.next_id += 1;
+        state.caps.insert(id, bitrate_bps);
+        send_cap(video_stream_msg_tx, &state.caps).await;
+        BitrateCapGuard {
+            caps: self.clone(),
+            video_stream_msg_tx: video_stream_msg_tx.clone(),
+            id,
+            armed: true,
+        }
+    }
+
+    /// 接続が終わった（その接続の上限を外す。同じ番号で何度呼んでもよい）
+    pub async fn remove(&self, video_stream_msg_tx: &mpsc::Sender<VideoStreamMessage>, id: u64) {
+        let mut state = self.state.lock().await;
+        if state.caps.remove(&id).is_some() {
+            send_cap(video_stream_msg_tx, &state.caps).await;
+        }
+    }
+}
+
+/// `disarm` されないまま落とされたら、その接続の上限を外す（Offer の処理が途中で失敗した場合）
+pub struct BitrateCapGuard {
+    caps: BitrateCaps,
+    video_stream_msg_tx: mpsc::Sender<VideoStreamMessage>,
+    id: u64,
+    armed: bool,
+}
+
+impl BitrateCapGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// 接続を確立できた（以降は接続の終わりに `remove` で外す）
+    pub fn disarm(mut self) -> u64 {
+        self.armed = false;
+        self.id
+    }
+}
+
+impl Drop for BitrateCapGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+        let caps = self.caps.clone();
+        let video_stream_msg_tx = self.video_stream_msg_tx.clone();
+        let id = self.id;
+        tokio::spawn(async move { caps.remove(&video_stream_msg_tx, id).await });
+    }
+}
+
+/// 接続中の上限の最小値（どの接続も示していなければ None）
+fn min_cap(caps: &HashMap<u64, Option<u32>>) -> Option<u32> {
+    caps.values().flatten().copied().min()
+}
+
+async fn send_cap(
+    video_stream_msg_tx: &mpsc::Sender<VideoStreamMessage>,
+    caps: &HashMap<u64, Option<u32>>,
+) {
+    let bitrate_bps = min_cap(caps);
+    if let Some(bps) = bitrate_bps {
+        info!(
+            "Video bitrate cap: {} kbps ({} connection(s))",
+            bps / 1000,
+            caps.len()
+        );
+    }
+    if video_stream_msg_tx
+        .send(VideoStreamMessage::SetMaxBitrate { bitrate_bps })
+        .await
+        .is_err()
+    {
+        warn!("Failed to send max bitrate: video stream receiver dropped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next_cap(rx: &mut mpsc::Receiver<VideoStreamMessage>) -> Option<u32> {
+        match rx.try_recv() {
+            Ok(VideoStreamMessage::SetMaxBitrate { bitrate_bps }) => bitrate_bps,
+            other => panic!("unexpected message: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cap_is_minimum_over_active_connections() {
+        let (tx, mut rx) = mpsc::channel(16);
+        let caps = BitrateCaps::default();
+
+        let old = caps.add(&tx, Some(2_000_000)).await.disarm();
+        assert_eq!(next_cap(&mut rx), Some(2_000_000));
+        // 上限を示さない接続が加わっても、前の接続の上限は残る
+        let new = caps.add(&tx, None).await.disarm();
+        assert_eq!(next_cap(&mut rx), Some(2_000_000));
+        let narrow = caps.add(&tx, Some(500_000)).await.disarm();
+        assert_eq!(next_cap(&mut rx), Some(500_000));
+
+        // 遅れて届いた古い接続の終了は、その接続の上限だけを外す
+        caps.remove(&tx, narrow).await;
+        assert_eq!(next_cap(&mut rx), Some(2_000_000));
+        caps.remove(&tx, old).await;
+        assert_eq!(next_cap(&mut rx), None);
+        caps.remove(&tx, old).await;
+        assert!(rx.try_recv().is_err());
+        caps.remove(&tx, new).await;
+        assert_eq!(next_cap(&mut rx), None);
+
+        // 確立できなかった接続の上限は、ガードを落とした時に外す
+        let failed = caps.add(&tx, Some(300_000)).await;
+        assert_eq!(next_cap(&mut rx), Some(300_000));
+        drop(failed);
+        assert!(matches!(
+            rx.recv().await,
+            Some(VideoStreamMessage::SetMaxBitrate { bitrate_bps: None })
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+// 用途ごとのデータチャネル
+//
+// 入力や操作の応答は、クライアントが作る信頼性のある順序付きチャネル（"control"、従来の "input"）でやり取りする。
+// 統計は古い値を再送する意味がないので、再送しない "stats" で送る（開いていなければ control で送る）。
+// "stats" はホストが Answer の前に作り、クライアントには ondatachannel で届く。
+
+use core_types::DataChannelMessage;
+use std::sync::Arc;
+use webrtc_rs::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc_rs::data_channel::RTCDataChannel;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    /// 信頼性のある順序付きチャネル（入力・操作・応答）
+    Control,
+    /// 再送しないチャネル（ホストからの統計）
+    Stats,
+}
+
+impl ChannelKind {
+    /// ホストが作るチャネル
+    pub const HOST_CREATED: [ChannelKind; 1] = [ChannelKind::Stats];
+
+    /// ラベルから用途を決める（不明なラベルは従来どおり control として扱う）
+    pub fn from_label(label: &str) -> Self {
+        match label {
+            "stats" => Self::Stats,
+            _ => Self::Control,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Control => "control",
+            Self::Stats => "stats",
+        }
+    }
+
+    /// ホストが作るときの設定
+    pub fn init(self) -> RTCDataChannelInit {
+        match self {
+            Self::Control => RTCDataChannelInit {
+                ordered: Some(true),
+                ..Default::default()
+            },
+            // 統計は 1 秒ごとに届くので再送しないが、新旧が入れ替わらないよう順序は保つ
+            Self::Stats => RTCDataChannelInit {
+                ordered: Some(true),
+                max_retransmits: Some(0),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// このチャネルで受け付けるメッセージか（取りこぼしてはいけない入力を再送しないチャネルで受けない）
+    pub fn accepts(self, msg: &DataChannelMessage) -> bool {
+        match self {
+            Self::Control => true,
+            Self::Stats => matches!(
+                msg,
+                DataChannelMessage::Ping { .. } | DataChannelMessage::Pong { .. }
+            ),
+        }
+    }
+}
+
+/// Ping への応答（受け取ったチャネルでそのまま返し、クライアントがそのチャネルの往復時間を測れるようにする）
+pub fn pong_for(msg: &DataChannelMessage) -> Option<DataChannelMessage> {
+    match *msg {
+        DataChannelMessage::Ping { id, client_ts } => {
+            Some(DataChannelMessage::Pong { id, client_ts })
+        }
+        _ => None,
+    }
+}
+
+/// 送信に使う開いているチャネル
+#[derive(Default)]
+pub struct DataChannels {
+    control: Option<Arc<RTCDataChannel>>,
+    stats: Option<Arc<RTCDataChannel>>,
+}
+
+impl DataChannels {
+    pub fn set(&mut self, kind: ChannelKind, dc: Arc<RTCDataChannel>) {
+        match kind {
+            ChannelKind::Control => self.control = Some(dc),
+            ChannelKind::Stats => self.stats = Some(dc),
+        }
+    }
+
+    /// 閉じたチャネルを外す（新しい接続のチャネルに差し替わっていればそのまま）
+    pub fn remove(&mut self, kind: ChannelKind, dc: &Arc<RTCDataChannel>) {
+        let slot = match kind {
+            ChannelKind::Control => &mut self.control,
+            ChannelKind::Stats => &mut self.stats,
+        };
+        if slot
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, dc))
+        {
+            *slot = None;
+        }
+    }
+
+    pub fn control(&self) -> Option<Arc<RTCDataChannel>> {
+        self.control.clone()
+    }
+
+    /// メッセージを送るチャネル（統計は stats が開いていればそちらで送る）
+    pub fn for_message(&self, msg: &DataChannelMessage) -> Option<Arc<RTCDataChannel>> {
+        match msg {
+            DataChannelMessage::VideoStats { .. } => {
+                self.stats.clone().or_else(|| self.control.clone())
+            }
+            _ => self.control.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core_types::{KeyframeCounts, VideoStatsPayload};
+
+    #[test]
+    fn test_routes_messages_by_channel() {
+        assert_eq!(ChannelKind::from_label("input"), ChannelKind::Control);
+        assert_eq!(ChannelKind::from_label("control"), ChannelKind::Control);
+        assert_eq!(ChannelKind::from_label("stats"), ChannelKind::Stats);
+
+        let mouse_move = DataChannelMessage::MouseMove { x: 0.5, y: 0.5 };
+        let key = DataChannelMessage::Key {
+            key: "KeyW".to_string(),
+            down: true,
+        };
+        assert!(ChannelKind::Control.accepts(&mouse_move));
+        assert!(ChannelKind::Control.accepts(&key));
+        // 取りこぼしてはいけない入力を再送しない stats チャネルでは受けない
+        assert!(!ChannelKind::Stats.accepts(&mouse_move));
+        assert!(!ChannelKind::Stats.accepts(&key));
+
+        let init = ChannelKind::Stats.init();
+        assert_eq!(init.ordered, Some(true));
+        assert_eq!(init.max_retransmits, Some(0));
+    }
+
+    #[test]
+    fn test_ping_is_echoed_with_id_and_client_timestamp() {
+        let ping: DataChannelMessage =
+            serde_json::from_str(r#"{"Ping":{"id":42,"client_ts":1700000000123}}"#).unwrap();
+        let pong = pong_for(&ping).unwrap();
+        assert_eq!(
+            serde_json::to_string(&pong).unwrap(),
+            r#"{"Pong":{"id":42,"client_ts":1700000000123}}"#
+        );
+
+        // 以前のクライアントの生存確認もそのまま返す
+        let legacy: DataChannelMessage =
+            serde_json::from_str(r#"{"Ping":{"timestamp":5}}"#).unwrap();
+        assert!(matches!(
+            pong_for(&legacy),
+            Some(DataChannelMessage::Pong {
+                id: 0,
+                client_ts: 5
+            })
+        ));
+        assert!(pong_for(&pong).is_none());
+    }
+
+    #[test]
+    fn test_stats_fall_back_to_control_channel() {
+        let stats = DataChannelMessage::VideoStats {
+            payload: VideoStatsPayload {
+                frames: 60,
+                encoder_dropped: 0,
+                encoder_drop_rate: 0.0,
+                network_loss_rate: 0.0,
+                capture_fps: 60.0,
+                keyframes_last_minute: KeyframeCounts::default(),
+                average_qp: None,
+                max_qp: None,
+                capture_dropped: 0,
+                capture_to_encode: None,
+                encode_to_send: None,
+            },
+        };
+        let control = Arc::new(RTCDataChannel::default());
+        let stats_dc = Arc::new(RTCDataChannel::default());
+
+        let mut channels = DataChannels::default();
+        assert!(channels.for_message(&stats).is_none());
+        channels.set(ChannelKind::Control, control.clone());
+        assert!(Arc::ptr_eq(
+            &channels.for_message(&stats).unwrap(),
+            &control
+        ));
+
+        channels.set(ChannelKind::Stats, stats_dc.clone());
+        assert!(Arc::ptr_eq(
+            &channels.for_message(&stats).unwrap(),
+            &stats_dc
+        ));
+        assert!(Arc::ptr_eq(
+            &channels
+                .for_message(&DataChannelMessage::SaveReplay)
+                .unwrap(),
+            &control
+        ));
+
+        // 差し替わった後に古いチャネルが閉じても外さない
+        channels.remove(ChannelKind::Control, &Arc::new(RTCDataChannel::default()));
+        assert!(channels.control().is_some());
+        channels.remove(ChannelKind::Stats, &stats_dc);
+        assert!(Arc::ptr_eq(
+            &channels.for_message(&stats).unwrap(),
+            &control
+        ));
+    }
+}
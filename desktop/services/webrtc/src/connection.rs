@@ -1,5 +1,8 @@
 use anyhow::{Context, Result};
-use core_types::{DataChannelMessage, SignalingResponse, VideoCodec, VideoStreamMessage, WebRtcMessage};
+use core_types::{
+    DataChannelMessage, KeyframeReason, Metrics, PeerConnectionState, SignalingResponse,
+    VideoCodec, VideoStreamMessage, WebRtcMessage, ABS_CAPTURE_TIME_URI,
+};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -11,6 +14,9 @@ use webrtc_rs::api::setting_engine::SettingEngine;
 use webrtc_rs::api::APIBuilder;
 use webrtc_rs::data_channel::data_channel_message::DataChannelMessage as RTCDataChannelMessage;
 use webrtc_rs::data_channel::RTCDataChannel;
+use webrtc_rs::ice::network_type::NetworkType;
+use webrtc_rs::ice::udp_mux::UDPMux;
+use webrtc_rs::ice::udp_network::UDPNetwork;
 use webrtc_rs::ice_transport::ice_candidate::RTCIceCandidate;
 use webrtc_rs::ice_transport::ice_candidate::RTCIceCandidateInit;
 use webrtc_rs::ice_transport::ice_server::RTCIceServer;
@@ -21,11 +27,20 @@ use webrtc_rs::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc_rs::peer_connection::RTCPeerConnection;
 use webrtc_rs::rtcp::payload_feedbacks::full_intra_request::FullIntraRequest;
 use webrtc_rs::rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication;
-use webrtc_rs::rtp_transceiver::rtp_codec::RTCRtpCodecCapability;
+use webrtc_rs::rtp_transceiver::rtp_codec::{
+    RTCRtpCodecCapability, RTCRtpCodecParameters, RTCRtpHeaderExtensionCapability, RTPCodecType,
+};
 use webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender;
+use webrtc_rs::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc_rs::rtp_transceiver::RTCPFeedback;
 use webrtc_rs::track::track_local::track_local_static_sample::TrackLocalStaticSample;
 use webrtc_rs::track::track_local::TrackLocal;
 
+use crate::bitrate_cap::BitrateCaps;
+use crate::channels::{pong_for, ChannelKind, DataChannels};
+use crate::fmtp::{munge_answer_fmtp, VideoConstraints};
+use crate::on_demand::OnDemandCapture;
+
 /// RTCIceCandidateから完全なSDP candidate文字列を生成
 ///
 /// 注意: この関数はICE candidate送信では使用しない。
@@ -77,35 +92,294 @@ pub fn codec_to_mime_type(codec: VideoCodec) -> String {
     }
 }
 
+/// ホストがエンコードできるビデオコーデック（優先順）
+const HOST_VIDEO_CODECS: &[VideoCodec] = &[VideoCodec::H264];
+
+/// SDP の rtpmap に現れるエンコーディング名
+pub(crate) fn codec_rtpmap_name(codec: VideoCodec) -> &'static str {
+    match codec {
+        VideoCodec::H264 => "H264",
+    }
+}
+
+/// Offer SDP の video セクションに含まれるコーデック名（rtpmap のエンコーディング名）を列挙
+fn offered_video_codec_names(sdp: &str) -> Vec<String> {
+    let mut in_video = false;
+    let mut names: Vec<String> = Vec::new();
+    for line in sdp.lines().map(str::trim) {
+        if let Some(media) = line.strip_prefix("m=") {
+            in_video = media.starts_with("video");
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:").filter(|_| in_video) {
+            // a=rtpmap:<payload type> <encoding name>/<clock rate>[/<channels>]
+            let Some(name) = rtpmap
+                .split_whitespace()
+                .nth(1)
+                .and_then(|encoding| encoding.split('/').next())
+            else {
+                continue;
+            };
+            let name = name.to_ascii_uppercase();
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+    }
+    names
+}
+
+/// Offer SDP でブラウザが示した映像の帯域（bps）
+///
+/// video セクションの b=AS（kbps）/ b=TIAS（bps）を使い、無ければセッション全体の値を使う。
+/// 両方ある場合は小さい方（回線が細いことを知っているブラウザに合わせる）。0 は指定なしとみなす
+fn offered_video_bitrate_bps(sdp: &str) -> Option<u32> {
+    let mut session: Option<u32> = None;
+    let mut video: Option<u32> = None;
+    // None: セッションの記述、Some(true): video セクション、Some(false): それ以外のメディア
+    let mut in_video: Option<bool> = None;
+    for line in sdp.lines().map(str::trim) {
+        if let Some(media) = line.strip_prefix("m=") {
+            in_video = Some(media.starts_with("video"));
+            continue;
+        }
+        let Some(bandwidth) = line.strip_prefix("b=") else {
+            continue;
+        };
+        let bps = match bandwidth.split_once(':') {
+            Some(("AS", kbps)) => kbps
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .map(|kbps| kbps.saturating_mul(1000)),
+            Some(("TIAS", bps)) => bps.trim().parse::<u32>().ok(),
+            _ => None,
+        };
+        let Some(bps) = bps.filter(|bps| *bps > 0) else {
+            continue;
+        };
+        let target = match in_video {
+            None => &mut session,
+            Some(true) => &mut video,
+            Some(false) => continue,
+        };
+        *target = Some(target.map_or(bps, |current| current.min(bps)));
+    }
+    video.or(session)
+}
+
+/// 要求されたコーデックと Offer の内容から、ホストが送出するビデオコーデックを決める
+///
+/// 要求されたコーデックが使えない場合は Offer に含まれるホスト対応コーデックのうち
+/// 最優先のものに切り替え、共通のコーデックが一つも無い場合のみエラーにする。
+pub fn negotiate_video_codec(offer_sdp: &str, requested: Option<VideoCodec>) -> Result<VideoCodec> {
+    let offered = offered_video_codec_names(offer_sdp);
+    if offered.is_empty() {
+        // video セクションが無い（判定できない）場合は従来どおり要求コーデックを使う
+        warn!("Offer has no video codecs, skipping codec negotiation");
+        return Ok(requested.unwrap_or(HOST_VIDEO_CODECS[0]));
+    }
+
+    let is_offered = |codec: &VideoCodec| {
+        offered
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(codec_rtpmap_name(*codec)))
+    };
+
+    if let Some(codec) = requested {
+        if HOST_VIDEO_CODECS.contains(&codec) && is_offered(&codec) {
+            return Ok(codec);
+        }
+    }
+
+    match HOST_VIDEO_CODECS.iter().copied().find(is_offered) {
+        Some(codec) => {
+            if let Some(requested) = requested {
+                warn!(
+                    "Requested codec {} is not in the offer (offered: [{}]), downgrading to {}",
+                    requested,
+                    offered.join(", "),
+                    codec
+                );
+            }
+            Ok(codec)
+        }
+        None => anyhow::bail!(
+            "No common video codec: offer has [{}], host supports {:?}",
+            offered.join(", "),
+            HOST_VIDEO_CODECS
+        ),
+    }
+}
+
+/// H.264 の packetization-mode=1 の payload type（webrtc-rs の既定の登録から mode=0 を除いたもの）
+const H264_PAYLOAD_TYPES: &[(u8, &str)] = &[(102, "42001f"), (125, "42e01f"), (123, "640032")];
+
+/// ホストが送出するコーデックを MediaEngine に登録する
+///
+/// webrtc-rs の H264Payloader は MTU を超える NAL を FU-A に分割するため、packetization-mode=1 でしか
+/// 正しく送れない。既定の登録（register_default_codecs）には mode=0 も含まれ、Offer での並び順によっては
+/// mode=0 の payload type で送ってしまい、ブラウザが大きなキーフレームを捨てる。
+/// ここでは H.264 を mode=1 だけ登録し、mode=0 の payload type は Answer から外す。
+pub(crate) fn register_host_codecs(m: &mut MediaEngine) -> Result<()> {
+    m.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_owned(),
+                clock_rate: 48000,
+                channels: 2,
+                sdp_fmtp_line: "minptime=10;useinbandfec=1".to_owned(),
+                rtcp_feedback: vec![],
+            },
+            payload_type: 111,
+            ..Default::default()
+        },
+        RTPCodecType::Audio,
+    )?;
+
+    let video_rtcp_feedback = [
+        ("goog-remb", ""),
+        ("ccm", "fir"),
+        ("nack", ""),
+        ("nack", "pli"),
+    ]
+    .into_iter()
+    .map(|(typ, parameter)| RTCPFeedback {
+        typ: typ.to_owned(),
+        parameter: parameter.to_owned(),
+    })
+    .collect::<Vec<_>>();
+    for (payload_type, profile_level_id) in H264_PAYLOAD_TYPES {
+        m.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_H264.to_owned(),
+                    clock_rate: 90000,
+                    channels: 0,
+                    sdp_fmtp_line: format!(
+                        "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id={}",
+                        profile_level_id
+                    ),
+                    rtcp_feedback: video_rtcp_feedback.clone(),
+                },
+                payload_type: *payload_type,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+    }
+    Ok(())
+}
+
 /// SetOfferメッセージの処理結果
 pub struct SetOfferResult {
     pub peer_connection: Arc<RTCPeerConnection>,
+    /// ネゴシエーションの結果送出するビデオコーデック
+    pub video_codec: VideoCodec,
     pub video_track: Arc<TrackLocalStaticSample>,
     pub video_sender: Arc<RTCRtpSender>,
-    pub audio_track: Arc<TrackLocalStaticSample>,
-    pub audio_sender: Arc<RTCRtpSender>,
+    /// 音声のトラックと送信側（音声を送らない場合は None）
+    pub audio: Option<(Arc<TrackLocalStaticSample>, Arc<RTCRtpSender>)>,
+}
+
+/// クライアントに通知する接続状態（Unspecified は通知しない）
+fn peer_connection_state(state: RTCPeerConnectionState) -> Option<PeerConnectionState> {
+    match state {
+        RTCPeerConnectionState::New => Some(PeerConnectionState::New),
+        RTCPeerConnectionState::Connecting => Some(PeerConnectionState::Connecting),
+        RTCPeerConnectionState::Connected => Some(PeerConnectionState::Connected),
+        RTCPeerConnectionState::Disconnected => Some(PeerConnectionState::Disconnected),
+        RTCPeerConnectionState::Failed => Some(PeerConnectionState::Failed),
+        RTCPeerConnectionState::Closed => Some(PeerConnectionState::Closed),
+        RTCPeerConnectionState::Unspecified => None,
+    }
+}
+
+/// PeerConnection の状態の変化をシグナリング経由でクライアントに通知する
+/// webrtc-rs の状態のコールバックから呼ぶので待たない（シグナリングが詰まっていれば通知を捨てる）
+fn forward_connection_state(
+    signaling_tx: &mpsc::Sender<SignalingResponse>,
+    state: RTCPeerConnectionState,
+) {
+    let Some(state) = peer_connection_state(state) else {
+        return;
+    };
+    if let Err(e) = signaling_tx.try_send(SignalingResponse::ConnectionState { state }) {
+        debug!("Failed to send connection state {}: {}", state, e);
+    }
+}
+
+/// SetOffer の処理に使う、接続をまたいで共有するチャネルと設定
+#[derive(Clone)]
+pub struct SetOfferContext {
+    pub signaling_tx: mpsc::Sender<SignalingResponse>,
+    pub data_channel_tx: mpsc::Sender<DataChannelMessage>,
+    pub connection_ready: Arc<AtomicBool>,
+    pub video_stream_msg_tx: mpsc::Sender<VideoStreamMessage>,
+    pub webrtc_msg_tx: mpsc::Sender<WebRtcMessage>,
+    pub data_channels: Arc<std::sync::Mutex<DataChannels>>,
+    /// DSCP を付けたソケット（None ならソケットは webrtc-rs に任せる）
+    pub udp_mux: Option<Arc<dyn UDPMux + Send + Sync>>,
+    pub metrics: Arc<Metrics>,
+    /// false なら音声トラックなしで Answer を返す
+    pub audio_enabled: bool,
+    pub video_constraints: VideoConstraints,
+    pub on_demand_capture: Option<OnDemandCapture>,
+    pub bitrate_caps: BitrateCaps,
 }
 
 /// SetOfferメッセージを処理
 pub async fn handle_set_offer(
     sdp: String,
     codec: Option<VideoCodec>,
-    signaling_tx: mpsc::Sender<SignalingResponse>,
-    data_channel_tx: mpsc::Sender<DataChannelMessage>,
-    connection_ready: Arc<AtomicBool>,
-    video_stream_msg_tx: mpsc::Sender<VideoStreamMessage>,
-    webrtc_msg_tx: mpsc::Sender<WebRtcMessage>,
-    active_data_channel: Arc<std::sync::Mutex<Option<Arc<RTCDataChannel>>>>,
+    ctx: SetOfferContext,
 ) -> Result<SetOfferResult> {
     info!("SetOffer received, generating answer");
+    let SetOfferContext {
+        signaling_tx,
+        data_channel_tx,
+        connection_ready,
+        video_stream_msg_tx,
+        webrtc_msg_tx,
+        data_channels,
+        udp_mux,
+        metrics,
+        audio_enabled,
+        video_constraints,
+        on_demand_capture,
+        bitrate_caps,
+    } = ctx;
+
+    // Offer とホストの対応状況から video codec を決定（要求が無ければホストの最優先コーデック）
+    let selected_codec = negotiate_video_codec(&sdp, codec)?;
+    info!(
+        "Using video codec: {:?} (requested: {:?})",
+        selected_codec, codec
+    );
 
-    // video codec を選択（デフォルトは H264）
-    let selected_codec = codec.unwrap_or(VideoCodec::H264);
-    info!("Using video codec: {:?}", selected_codec);
+    // ブラウザが Offer で示した帯域をエンコーダーのビットレートの上限にする
+    // （回線が細いと分かっているブラウザには最初から控えめなストリームを送る。
+    // 上限は接続ごとに持ち、接続が終わったら外す）
+    let max_bitrate_bps = offered_video_bitrate_bps(&sdp);
+    if let Some(bps) = max_bitrate_bps {
+        info!("Offer limits video bandwidth to {} kbps", bps / 1000);
+    }
+    // 以降の処理が失敗して接続が終わりを知らせてこなくても、ガードを落とせば上限を外す
+    let bitrate_cap = bitrate_caps
+        .add(&video_stream_msg_tx, max_bitrate_bps)
+        .await;
+    let bitrate_cap_id = bitrate_cap.id();
 
     // webrtc-rsのAPIを初期化
     let mut m = MediaEngine::default();
-    m.register_default_codecs()?;
+    register_host_codecs(&mut m)?;
+    // クライアントが glass-to-glass の遅延を測れるよう、キャプチャ時刻の拡張を受け入れる
+    // （Offer に含まれていなければネゴシエートされず、送信もされない）
+    m.register_header_extension(
+        RTCRtpHeaderExtensionCapability {
+            uri: ABS_CAPTURE_TIME_URI.to_string(),
+        },
+        RTPCodecType::Video,
+        None,
+    )?;
 
     let mut registry = Registry::new();
     registry = register_default_interceptors(registry, &mut m)?;
@@ -130,6 +404,12 @@ pub async fn handle_set_offer(
         Some(Duration::from_secs(2)),   // keepalive_interval: 変更なし
     );
 
+    // DSCP を付けたソケットを使う場合、ホスト候補はすべてそのソケット（IPv4）を共有する
+    if let Some(udp_mux) = udp_mux {
+        setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
+        setting_engine.set_network_types(vec![NetworkType::Udp4]);
+    }
+
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_setting_engine(setting_engine)
@@ -179,23 +459,37 @@ pub async fn handle_set_offer(
 
     info!("Video track added to peer connection");
 
-    // 音声トラックを追加
-    info!("Adding audio track with Opus codec");
-    let audio_track = Arc::new(TrackLocalStaticSample::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_OPUS.to_string(),
-            ..Default::default()
-        },
-        "audio".to_string(),
-        "stream".to_string(),
-    ));
+    // 音声トラックを追加（音声が使えなければ追加せず、Answer の audio を inactive にする）
+    let audio = if audio_enabled {
+        info!("Adding audio track with Opus codec");
+        let audio_track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_OPUS.to_string(),
+                ..Default::default()
+            },
+            "audio".to_string(),
+            "stream".to_string(),
+        ));
 
-    let audio_sender: Arc<RTCRtpSender> = pc
-        .add_track(audio_track.clone() as Arc<dyn TrackLocal + Send + Sync>)
-        .await
-        .context("Failed to add audio track")?;
+        let audio_sender: Arc<RTCRtpSender> = pc
+            .add_track(audio_track.clone() as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .context("Failed to add audio track")?;
 
-    info!("Audio track added to peer connection");
+        info!("Audio track added to peer connection");
+        Some((audio_track, audio_sender))
+    } else {
+        warn!("Audio is unavailable, answering with video only");
+        // トラックがなくても webrtc-rs は sendonly と答えるので、送らないことを明示する
+        for transceiver in pc.get_transceivers().await {
+            if transceiver.kind() == RTPCodecType::Audio {
+                transceiver
+                    .set_direction(RTCRtpTransceiverDirection::Inactive)
+                    .await;
+            }
+        }
+        None
+    };
 
     // RTCP 受信ループを開始し、PLI/FIR を受けたら VideoStreamService にキーフレーム要求を送信
     let video_stream_msg_tx_rtcp = video_stream_msg_tx.clone();
@@ -213,7 +507,9 @@ pub async fn handle_set_offer(
                         {
                             debug!("RTCP feedback (PLI/FIR) received, requesting keyframe");
                             let _ = video_stream_msg_tx_rtcp
-                                .send(VideoStreamMessage::RequestKeyframe)
+                                .send(VideoStreamMessage::RequestKeyframe {
+                                    reason: KeyframeReason::Pli,
+                                })
                                 .await;
                         }
                     }
@@ -227,130 +523,50 @@ pub async fn handle_set_offer(
     });
 
     // DataChannelハンドラを設定
-    let dc_tx = data_channel_tx.clone();
-    // active_data_channel は呼び出し元の WebRtcService.run で管理されている
-    // ここでは Clone して move closure に渡す
-    let active_dc_clone = active_data_channel.clone();
+    // data_channels は呼び出し元の WebRtcService.run で管理されている
+    let channel_ctx = ChannelContext {
+        data_channel_tx: data_channel_tx.clone(),
+        webrtc_msg_tx: webrtc_msg_tx.clone(),
+        data_channels: data_channels.clone(),
+    };
 
+    // クライアントが作ったチャネル（control / 従来の input）
+    let channel_ctx_remote = channel_ctx.clone();
     pc.on_data_channel(Box::new(move |dc: Arc<RTCDataChannel>| {
-        let dc_tx = dc_tx.clone();
-        let active_dc_for_open = active_dc_clone.clone();
-
+        let ctx = channel_ctx_remote.clone();
         Box::pin(async move {
-            let label = dc.label();
-            let label_str = label.to_string();
-            info!("DataChannel opened: {}", label_str);
-
-            // Active Data Channelとして保存
-            if label_str == "input" { // "input" チャンネルのみを対象にする場合
-                 *active_dc_for_open.lock().unwrap() = Some(dc.clone());
-                 info!("DataChannel 'input' registered as active output channel");
-            } else {
-                // 必要なら他のチャンネルも
-                 *active_dc_for_open.lock().unwrap() = Some(dc.clone());
-            }
-
-            let dc_tx_on_msg = dc_tx.clone();
-            let dc_for_pong = dc.clone();
-            dc.on_message(Box::new(move |msg: RTCDataChannelMessage| {
-                let dc_tx_on_msg = dc_tx_on_msg.clone();
-                let dc_for_pong = dc_for_pong.clone();
-                Box::pin(async move {
-                    if msg.is_string {
-                        if let Ok(text) = String::from_utf8(msg.data.to_vec()) {
-                            match serde_json::from_str::<DataChannelMessage>(&text) {
-                                Ok(parsed) => {
-                                    match &parsed {
-                                        DataChannelMessage::Ping { timestamp } => {
-                                            debug!("Received keepalive ping from client (timestamp: {})", timestamp);
-                                            // Pingを受信したらPongを返信
-                                            let pong_msg = DataChannelMessage::Pong { timestamp: *timestamp };
-                                            if let Ok(pong_json) = serde_json::to_string(&pong_msg) {
-                                                if let Err(e) = dc_for_pong.send_text(pong_json).await {
-                                                    warn!("Failed to send pong: {}", e);
-                                                } else {
-                                                    debug!("Sent pong response (timestamp: {})", timestamp);
-                                                }
-                                            }
-                                        }
-                                        DataChannelMessage::Pong { timestamp } => {
-                                            debug!("Received keepalive pong from client (timestamp: {})", timestamp);
-                                            // Pongメッセージは処理不要（受信だけで十分）
-                                        }
-                                        _ => {
-                                            // その他のメッセージは従来通りinputサービスに転送
-                                            if let Err(e) = dc_tx_on_msg.send(parsed).await {
-                                                warn!("Failed to forward data channel message: {}", e);
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    warn!("Failed to parse data channel message: {}", e);
-                                }
-                            }
-                        } else {
-                            warn!("Received non-UTF8 data channel message");
-                        }
-                    } else {
-                        debug!("Ignoring binary data channel message");
-                    }
-                })
-            }));
-
-            // サーバー側から定期的にPingを送信するタスク
-            let dc_for_ping = dc.clone();
-            let ping_task_closed = Arc::new(AtomicBool::new(false));
-            let ping_task_closed_clone = ping_task_closed.clone();
-            tokio::spawn(async move {
-                let mut interval = tokio::time::interval(Duration::from_secs(3));
-                loop {
-                    interval.tick().await;
-                    // DataChannelが閉じられたかチェック
-                    if ping_task_closed_clone.load(Ordering::Relaxed) {
-                        debug!("DataChannel closed, stopping ping task");
-                        break;
-                    }
-                    let timestamp = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_millis() as u64;
-                    let ping_msg = DataChannelMessage::Ping { timestamp };
-                    if let Ok(ping_json) = serde_json::to_string(&ping_msg) {
-                        match dc_for_ping.send_text(ping_json).await {
-                            Ok(_) => {
-                                debug!("Sent keepalive ping to client (timestamp: {})", timestamp);
-                            }
-                            Err(e) => {
-                                warn!("Failed to send ping: {}", e);
-                                break; // 送信失敗時はループを終了
-                            }
-                        }
-                    }
-                }
-            });
-            let ping_task_closed_for_close = ping_task_closed.clone();
-            let active_dc_for_close = active_dc_for_open.clone(); // on_data_channel 内の active_dc_for_open をもう一度 clone
-            dc.on_close(Box::new(move || {
-                let label_str = label_str.clone();
-                let ping_task_closed_for_close = ping_task_closed_for_close.clone();
-                let active_dc_for_close = active_dc_for_close.clone();
-                Box::pin(async move {
-                    info!("DataChannel closed: {}", label_str);
-                    ping_task_closed_for_close.store(true, Ordering::Relaxed);
-                    // Close 時に active_data_channel をクリア
-                    *active_dc_for_close.lock().unwrap() = None;
-                })
-            }));
+            let kind = ChannelKind::from_label(dc.label());
+            register_data_channel(dc, kind, ctx);
         })
     }));
 
+    // ホストが作るチャネル（Answer の前に作れば SCTP の確立後に開く）
+    for kind in ChannelKind::HOST_CREATED {
+        let dc = pc
+            .create_data_channel(kind.label(), Some(kind.init()))
+            .await
+            .with_context(|| format!("Failed to create data channel '{}'", kind.label()))?;
+        let ctx = channel_ctx.clone();
+        let dc_for_open = Arc::downgrade(&dc);
+        dc.on_open(Box::new(move || {
+            Box::pin(async move {
+                if let Some(dc) = dc_for_open.upgrade() {
+                    register_data_channel(dc, kind, ctx);
+                }
+            })
+        }));
+    }
+
     // Answerを生成
     let answer = pc
         .create_answer(None)
         .await
         .context("Failed to create answer")?;
-    info!("Answer SDP generated:\n{}", answer.sdp);
+    // 選択コーデックの fmtp をエンコーダーの実際の設定に合わせる
+    // （webrtc-rs は create_answer の結果と異なる SDP を LocalDescription に設定できないので、
+    // 書き換えるのはクライアントに送る方だけにする）
+    let munged_answer_sdp = munge_answer_fmtp(&answer.sdp, selected_codec, &video_constraints);
+    info!("Answer SDP generated:\n{}", munged_answer_sdp);
 
     // ICE candidateのイベントハンドラを LocalDescription 設定前に登録して、
     // 初期ホスト候補を取りこぼさないようにする
@@ -409,13 +625,16 @@ pub async fn handle_set_offer(
     }));
 
     // LocalDescriptionとして設定
-    pc.set_local_description(answer.clone())
+    pc.set_local_description(answer)
         .await
         .context("Failed to set local description")?;
 
     // Answerをシグナリングサービスに送信
     if let Err(e) = signaling_tx
-        .send(SignalingResponse::Answer { sdp: answer.sdp })
+        .send(SignalingResponse::Answer {
+            sdp: munged_answer_sdp,
+            codec: selected_codec,
+        })
         .await
     {
         error!("Failed to send answer to signaling service: {}", e);
@@ -427,10 +646,60 @@ pub async fn handle_set_offer(
     let pc_for_state = pc.clone();
     let connection_ready_pc = connection_ready.clone();
     let video_stream_msg_tx_on_connect = video_stream_msg_tx.clone();
+    let data_channel_tx_state = data_channel_tx.clone();
+    let signaling_tx_state = signaling_tx.clone();
+    // connection_ready は ICE の状態でも切り替わるので、セッション数はこの PeerConnection の状態だけで数える
+    let session_active = Arc::new(AtomicBool::new(false));
     pc_for_state.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
         let connection_ready_pc = connection_ready_pc.clone();
         let video_stream_msg_tx_on_connect = video_stream_msg_tx_on_connect.clone();
+        let data_channel_tx_state = data_channel_tx_state.clone();
+        let signaling_tx_state = signaling_tx_state.clone();
+        let metrics = metrics.clone();
+        let session_active = session_active.clone();
+        let on_demand_capture = on_demand_capture.clone();
+        let bitrate_caps = bitrate_caps.clone();
         Box::pin(async move {
+            // 切断されたらクライアントの keyup は届かないので、押下中のキーを離させる
+            if matches!(
+                state,
+                RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Closed
+            ) {
+                let _ = data_channel_tx_state
+                    .send(DataChannelMessage::ReleaseAllKeys)
+                    .await;
+            }
+            forward_connection_state(&signaling_tx_state, state);
+            if matches!(
+                state,
+                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+            ) {
+                bitrate_caps
+                    .remove(&video_stream_msg_tx_on_connect, bitrate_cap_id)
+                    .await;
+            }
+            // Disconnected からは ICE が回復して Connected に戻ることがあるので、セッションの終わりには数えない
+            // （数えると回復のたびに sessions_started が増える）
+            if matches!(
+                state,
+                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+            ) && session_active.swap(false, Ordering::Relaxed)
+            {
+                metrics.session_ended();
+                if let Some(on_demand_capture) = &on_demand_capture {
+                    on_demand_capture.session_ended().await;
+                }
+            }
+            if state == RTCPeerConnectionState::Connected
+                && !session_active.swap(true, Ordering::Relaxed)
+            {
+                metrics.session_started();
+                if let Some(on_demand_capture) = &on_demand_capture {
+                    on_demand_capture.session_started().await;
+                }
+            }
             match state {
                 RTCPeerConnectionState::New => {
                     info!("PeerConnection state: New");
@@ -452,7 +721,9 @@ pub async fn handle_set_offer(
                     }
                     // 接続確立時に即座にキーフレーム送出を要求
                     let _ = video_stream_msg_tx_on_connect
-                        .send(VideoStreamMessage::RequestKeyframe)
+                        .send(VideoStreamMessage::RequestKeyframe {
+                            reason: KeyframeReason::Reconnect,
+                        })
                         .await;
                 }
                 RTCPeerConnectionState::Disconnected => {
@@ -517,7 +788,9 @@ pub async fn handle_set_offer(
                         info!("connection_ready flag set to true (ICE Connected)");
                         // ICE接続確立時にもキーフレーム送出を要求
                         let _ = video_stream_msg_tx_ice
-                            .send(VideoStreamMessage::RequestKeyframe)
+                            .send(VideoStreamMessage::RequestKeyframe {
+                                reason: KeyframeReason::Reconnect,
+                            })
                             .await;
                     }
                 }
@@ -587,15 +860,207 @@ pub async fn handle_set_offer(
         })
     }));
 
+    bitrate_cap.disarm();
     Ok(SetOfferResult {
         peer_connection: pc,
+        video_codec: selected_codec,
         video_track,
         video_sender: sender,
-        audio_track,
-        audio_sender,
+        audio,
     })
 }
 
+/// データチャネルのハンドラが使う送信先
+#[derive(Clone)]
+struct ChannelContext {
+    data_channel_tx: mpsc::Sender<DataChannelMessage>,
+    webrtc_msg_tx: mpsc::Sender<WebRtcMessage>,
+    data_channels: Arc<std::sync::Mutex<DataChannels>>,
+}
+
+/// 開いたデータチャネルを用途ごとに登録し、受信メッセージを振り分ける
+fn register_data_channel(dc: Arc<RTCDataChannel>, kind: ChannelKind, ctx: ChannelContext) {
+    let label_str = dc.label().to_string();
+    info!(
+        "DataChannel opened: {} ({:?}, ordered: {}, max_retransmits: {:?})",
+        label_str,
+        kind,
+        dc.ordered(),
+        dc.max_retransmits()
+    );
+    ctx.data_channels.lock().unwrap().set(kind, dc.clone());
+
+    let ctx_on_msg = ctx.clone();
+    let dc_for_pong = dc.clone();
+    let label_on_msg = label_str.clone();
+    dc.on_message(Box::new(move |msg: RTCDataChannelMessage| {
+        let dc_tx_on_msg = ctx_on_msg.data_channel_tx.clone();
+        let webrtc_msg_tx_dc = ctx_on_msg.webrtc_msg_tx.clone();
+        let dc_for_pong = dc_for_pong.clone();
+        let label_on_msg = label_on_msg.clone();
+        Box::pin(async move {
+            if !msg.is_string {
+                debug!("Ignoring binary data channel message");
+                return;
+            }
+            let Ok(text) = String::from_utf8(msg.data.to_vec()) else {
+                warn!("Received non-UTF8 data channel message");
+                return;
+            };
+            let parsed = match serde_json::from_str::<DataChannelMessage>(&text) {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    warn!("Failed to parse data channel message: {}", e);
+                    return;
+                }
+            };
+            if !kind.accepts(&parsed) {
+                warn!(
+                    "Ignoring message not allowed on data channel '{}': {:?}",
+                    label_on_msg, parsed
+                );
+                return;
+            }
+            match &parsed {
+                DataChannelMessage::Ping { id, client_ts } => {
+                    debug!(
+                        "Received ping from client on '{}' (id: {}, client_ts: {})",
+                        label_on_msg, id, client_ts
+                    );
+                    // 受け取ったチャネルですぐに返す（クライアントが入力経路の往復時間を測る）
+                    if let Some(pong_json) =
+                        pong_for(&parsed).and_then(|pong| serde_json::to_string(&pong).ok())
+                    {
+                        if let Err(e) = dc_for_pong.send_text(pong_json).await {
+                            warn!("Failed to send pong: {}", e);
+                        } else {
+                            debug!("Sent pong response (id: {})", id);
+                        }
+                    }
+                }
+                DataChannelMessage::Pong { id, client_ts } => {
+                    debug!(
+                        "Received keepalive pong from client (id: {}, client_ts: {})",
+                        id, client_ts
+                    );
+                    // Pongメッセージは処理不要（受信だけで十分）
+                }
+                DataChannelMessage::PauseStream { video, audio } => {
+                    // ストリーム制御は WebRtcService 経由で各ストリームサービスへ
+                    let _ = webrtc_msg_tx_dc
+                        .send(WebRtcMessage::PauseStream {
+                            video: *video,
+                            audio: *audio,
+                        })
+                        .await;
+                }
+                DataChannelMessage::ResumeStream { video, audio } => {
+                    let _ = webrtc_msg_tx_dc
+                        .send(WebRtcMessage::ResumeStream {
+                            video: *video,
+                            audio: *audio,
+                        })
+                        .await;
+                }
+                DataChannelMessage::SaveReplay => {
+                    let _ = webrtc_msg_tx_dc.send(WebRtcMessage::SaveReplay).await;
+                }
+                DataChannelMessage::StartRecording => {
+                    let _ = webrtc_msg_tx_dc.send(WebRtcMessage::StartRecording).await;
+                }
+                DataChannelMessage::StopRecording => {
+                    let _ = webrtc_msg_tx_dc.send(WebRtcMessage::StopRecording).await;
+                }
+                DataChannelMessage::SetAudioSourceGain {
+                    source,
+                    gain,
+                    muted,
+                } => {
+                    let _ = webrtc_msg_tx_dc
+                        .send(WebRtcMessage::SetAudioSourceGain {
+                            source: *source,
+                            gain: *gain,
+                            muted: *muted,
+                        })
+                        .await;
+                }
+                DataChannelMessage::RestartEncoder => {
+                    let _ = webrtc_msg_tx_dc.send(WebRtcMessage::RestartEncoder).await;
+                }
+                _ => {
+                    // その他のメッセージは従来通りinputサービスに転送
+                    if let Err(e) = dc_tx_on_msg.send(parsed).await {
+                        warn!("Failed to forward data channel message: {}", e);
+                    }
+                }
+            }
+        })
+    }));
+
+    // 生存確認の Ping と押下中のキーの解放は control チャネルだけで行う
+    let ping_task_closed = Arc::new(AtomicBool::new(false));
+    if kind == ChannelKind::Control {
+        // サーバー側から定期的にPingを送信するタスク
+        let dc_for_ping = dc.clone();
+        let ping_task_closed_clone = ping_task_closed.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(3));
+            let mut ping_id: u64 = 0;
+            loop {
+                interval.tick().await;
+                // DataChannelが閉じられたかチェック
+                if ping_task_closed_clone.load(Ordering::Relaxed) {
+                    debug!("DataChannel closed, stopping ping task");
+                    break;
+                }
+                let timestamp = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as u64;
+                ping_id += 1;
+                let ping_msg = DataChannelMessage::Ping {
+                    id: ping_id,
+                    client_ts: timestamp,
+                };
+                if let Ok(ping_json) = serde_json::to_string(&ping_msg) {
+                    match dc_for_ping.send_text(ping_json).await {
+                        Ok(_) => {
+                            debug!("Sent keepalive ping to client (timestamp: {})", timestamp);
+                        }
+                        Err(e) => {
+                            warn!("Failed to send ping: {}", e);
+                            break; // 送信失敗時はループを終了
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    let dc_for_close = Arc::downgrade(&dc);
+    dc.on_close(Box::new(move || {
+        let label_str = label_str.clone();
+        let ping_task_closed = ping_task_closed.clone();
+        let ctx = ctx.clone();
+        let dc_for_close = dc_for_close.clone();
+        Box::pin(async move {
+            info!("DataChannel closed: {}", label_str);
+            ping_task_closed.store(true, Ordering::Relaxed);
+            // Close 時に送信先から外す
+            if let Some(dc) = dc_for_close.upgrade() {
+                ctx.data_channels.lock().unwrap().remove(kind, &dc);
+            }
+            if kind == ChannelKind::Control {
+                // keyup を受け取れなくなるので押下中のキーを離させる
+                let _ = ctx
+                    .data_channel_tx
+                    .send(DataChannelMessage::ReleaseAllKeys)
+                    .await;
+            }
+        })
+    }));
+}
+
 /// ICE candidate追加処理
 pub async fn handle_add_ice_candidate(
     peer_connection: &Arc<RTCPeerConnection>,
@@ -618,3 +1083,368 @@ pub async fn handle_add_ice_candidate(
     debug!("ICE candidate added");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER_SDP: &str = "v=0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=rtpmap:111 opus/48000/2\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 97 102\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        a=rtpmap:97 rtx/90000\r\n\
+        a=rtpmap:102 H264/90000\r\n";
+
+    /// SDP の video セクションで `payload_type` の fmtp を返す
+    fn video_fmtp(sdp: &str, payload_type: u8) -> Option<&str> {
+        let prefix = format!("a=fmtp:{} ", payload_type);
+        sdp.lines()
+            .skip_while(|line| !line.starts_with("m=video"))
+            .find_map(|line| line.strip_prefix(prefix.as_str()))
+    }
+
+    #[tokio::test]
+    async fn test_answer_uses_h264_packetization_mode_1() {
+        use webrtc_rs::peer_connection::configuration::RTCConfiguration;
+        use webrtc_rs::rtp_transceiver::RTCRtpTransceiverInit;
+
+        // mode=0 を先に並べた Offer（受信専用のクライアント）
+        let mut offerer_engine = MediaEngine::default();
+        for (payload_type, mode) in [(97, 0), (126, 1)] {
+            offerer_engine
+                .register_codec(
+                    RTCRtpCodecParameters {
+                        capability: RTCRtpCodecCapability {
+                            mime_type: MIME_TYPE_H264.to_owned(),
+                            clock_rate: 90000,
+                            sdp_fmtp_line: format!(
+                                "level-asymmetry-allowed=1;packetization-mode={};profile-level-id=42e01f",
+                                mode
+                            ),
+                            ..Default::default()
+                        },
+                        payload_type,
+                        ..Default::default()
+                    },
+                    RTPCodecType::Video,
+                )
+                .unwrap();
+        }
+        let offerer = APIBuilder::new()
+            .with_media_engine(offerer_engine)
+            .build()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        offerer
+            .add_transceiver_from_kind(
+                RTPCodecType::Video,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: vec![],
+                }),
+            )
+            .await
+            .unwrap();
+        let offer = offerer.create_offer(None).await.unwrap();
+        assert!(video_fmtp(&offer.sdp, 97).is_some());
+
+        let mut answerer_engine = MediaEngine::default();
+        register_host_codecs(&mut answerer_engine).unwrap();
+        let answerer = APIBuilder::new()
+            .with_media_engine(answerer_engine)
+            .build()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        answerer.set_remote_description(offer).await.unwrap();
+        let track = Arc::new(TrackLocalStaticSample::new(
+            RTCRtpCodecCapability {
+                mime_type: codec_to_mime_type(VideoCodec::H264),
+                ..Default::default()
+            },
+            "video".to_string(),
+            "stream".to_string(),
+        ));
+        answerer
+            .add_track(track as Arc<dyn TrackLocal + Send + Sync>)
+            .await
+            .unwrap();
+        let answer = answerer.create_answer(None).await.unwrap();
+
+        // mode=0 の payload type は Answer から外れ、mode=1 だけが残る
+        assert!(video_fmtp(&answer.sdp, 97).is_none(), "{}", answer.sdp);
+        assert!(
+            video_fmtp(&answer.sdp, 126).is_some_and(|fmtp| fmtp.contains("packetization-mode=1"))
+        );
+
+        let _ = offerer.close().await;
+        let _ = answerer.close().await;
+    }
+
+    /// 音声ありの既定の設定（応答はすべて `signaling_tx` に流れ、他のチャネルの受信側は捨てる）
+    fn test_context(signaling_tx: mpsc::Sender<SignalingResponse>) -> SetOfferContext {
+        SetOfferContext {
+            signaling_tx,
+            data_channel_tx: mpsc::channel(100).0,
+            connection_ready: Arc::new(AtomicBool::new(false)),
+            video_stream_msg_tx: mpsc::channel(100).0,
+            webrtc_msg_tx: mpsc::channel(100).0,
+            data_channels: Default::default(),
+            udp_mux: None,
+            metrics: Arc::new(Metrics::default()),
+            audio_enabled: true,
+            video_constraints: VideoConstraints::default(),
+            on_demand_capture: None,
+            bitrate_caps: BitrateCaps::default(),
+        }
+    }
+
+    /// SDP の `kind`（"video" / "audio"）のセクションの方向
+    fn media_direction<'a>(sdp: &'a str, kind: &str) -> Option<&'a str> {
+        let section = format!("m={} ", kind);
+        sdp.lines()
+            .skip_while(|line| !line.starts_with(section.as_str()))
+            .skip(1)
+            .take_while(|line| !line.starts_with("m="))
+            .filter_map(|line| line.strip_prefix("a="))
+            .find(|attr| ["sendrecv", "sendonly", "recvonly", "inactive"].contains(attr))
+    }
+
+    #[tokio::test]
+    async fn test_failed_offer_clears_its_bitrate_cap() {
+        let client = crate::loopback::LoopbackClient::new().await.unwrap();
+        // b=AS で帯域を示し、fingerprint を落として setRemoteDescription で失敗させる
+        let offer: String = client
+            .create_offer()
+            .await
+            .unwrap()
+            .replace("m=video", "b=AS:500\r\nm=video")
+            .split_inclusive("\r\n")
+            .filter(|line| !line.starts_with("a=fingerprint:"))
+            .collect();
+
+        let (video_stream_msg_tx, mut video_stream_msg_rx) = mpsc::channel(100);
+        let result = handle_set_offer(
+            offer,
+            None,
+            SetOfferContext {
+                video_stream_msg_tx,
+                ..test_context(mpsc::channel(100).0)
+            },
+        )
+        .await;
+        assert!(result.is_err());
+
+        let mut caps = Vec::new();
+        while let Ok(Some(msg)) =
+            tokio::time::timeout(Duration::from_secs(1), video_stream_msg_rx.recv()).await
+        {
+            if let VideoStreamMessage::SetMaxBitrate { bitrate_bps } = msg {
+                caps.push(bitrate_bps);
+                if bitrate_bps.is_none() {
+                    break;
+                }
+            }
+        }
+        // 失敗した Offer の上限は、後から繋がるビューアーに残らない
+        assert_eq!(caps, [Some(500_000), None]);
+
+        let _ = client.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_answers_video_only_when_audio_is_unavailable() {
+        let client = crate::loopback::LoopbackClient::new().await.unwrap();
+        let offer = client.create_offer().await.unwrap();
+
+        let (signaling_tx, mut signaling_rx) = mpsc::channel(100);
+        let result = handle_set_offer(
+            offer,
+            None,
+            SetOfferContext {
+                audio_enabled: false,
+                ..test_context(signaling_tx)
+            },
+        )
+        .await
+        .unwrap();
+        assert!(result.audio.is_none());
+
+        let answer = loop {
+            match signaling_rx.recv().await {
+                Some(SignalingResponse::Answer { sdp, .. }) => break sdp,
+                Some(_) => continue,
+                None => panic!("no answer was sent"),
+            }
+        };
+        // 映像は送り、音声の m-line は残したまま送信しない
+        assert_eq!(media_direction(&answer, "video"), Some("sendonly"));
+        assert_eq!(media_direction(&answer, "audio"), Some("inactive"));
+
+        let _ = result.peer_connection.close().await;
+        let _ = client.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_connection_state_is_forwarded_to_signaling() {
+        let client = crate::loopback::LoopbackClient::new().await.unwrap();
+        let offer = client.create_offer().await.unwrap();
+
+        let (signaling_tx, mut signaling_rx) = mpsc::channel(100);
+        let result = handle_set_offer(
+            offer,
+            None,
+            SetOfferContext {
+                audio_enabled: false,
+                ..test_context(signaling_tx)
+            },
+        )
+        .await
+        .unwrap();
+
+        // ホスト側で閉じると Closed がシグナリングに流れる
+        let _ = result.peer_connection.close().await;
+        let state = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match signaling_rx.recv().await {
+                    Some(SignalingResponse::ConnectionState { state }) => break state,
+                    Some(_) => continue,
+                    None => panic!("no connection state was sent"),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(state, PeerConnectionState::Closed);
+        assert_eq!(
+            peer_connection_state(RTCPeerConnectionState::Unspecified),
+            None
+        );
+
+        let _ = client.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_on_demand_capture_follows_connection() {
+        use core_types::{CaptureMessage, CaptureTarget};
+
+        let client = crate::loopback::LoopbackClient::new().await.unwrap();
+        let offer = client.create_offer().await.unwrap();
+
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let on_demand = OnDemandCapture::new(capture_cmd_tx, || Some(CaptureTarget::Window(42)));
+        let (signaling_tx, mut signaling_rx) = mpsc::channel(100);
+        let result = handle_set_offer(
+            offer,
+            None,
+            SetOfferContext {
+                on_demand_capture: Some(on_demand),
+                ..test_context(signaling_tx)
+            },
+        )
+        .await
+        .unwrap();
+        // 接続するまではキャプチャを始めない
+        assert!(capture_cmd_rx.try_recv().is_err());
+
+        // Answer と ICE candidate を受け渡して、接続したらキャプチャが始まる
+        let command = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                tokio::select! {
+                    command = capture_cmd_rx.recv() => break command,
+                    msg = signaling_rx.recv() => match msg {
+                        Some(SignalingResponse::Answer { sdp, .. }) => {
+                            client.set_answer(sdp).await.unwrap();
+                        }
+                        Some(SignalingResponse::IceCandidate {
+                            candidate,
+                            sdp_mid,
+                            sdp_mline_index,
+                            username_fragment,
+                        }) => {
+                            let candidate = RTCIceCandidateInit {
+                                candidate,
+                                sdp_mid,
+                                sdp_mline_index,
+                                username_fragment,
+                            };
+                            let _ = client.add_ice_candidate(candidate).await;
+                        }
+                        Some(_) => {}
+                        None => panic!("signaling channel closed"),
+                    },
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            command,
+            Some(CaptureMessage::Start {
+                target: CaptureTarget::Window(42)
+            })
+        ));
+
+        // 切断されたらキャプチャを止める
+        let _ = result.peer_connection.close().await;
+        let command = tokio::time::timeout(Duration::from_secs(5), capture_cmd_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(command, Some(CaptureMessage::Stop)));
+
+        let _ = client.close().await;
+    }
+
+    #[test]
+    fn test_offered_video_codec_names() {
+        assert_eq!(
+            offered_video_codec_names(OFFER_SDP),
+            vec!["VP8".to_string(), "RTX".to_string(), "H264".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_offered_video_bitrate() {
+        assert_eq!(offered_video_bitrate_bps(OFFER_SDP), None);
+
+        // video セクションの b=AS は kbps
+        let offer = OFFER_SDP.replace(
+            "a=rtpmap:96 VP8/90000\r\n",
+            "b=AS:1000\r\na=rtpmap:96 VP8/90000\r\n",
+        );
+        assert_eq!(offered_video_bitrate_bps(&offer), Some(1_000_000));
+
+        // b=TIAS は bps で、小さい方を使う。audio セクションの値は使わない
+        let offer = OFFER_SDP
+            .replace("a=rtpmap:111", "b=AS:64\r\na=rtpmap:111")
+            .replace(
+                "a=rtpmap:96 VP8/90000\r\n",
+                "b=AS:1000\r\nb=TIAS:800000\r\na=rtpmap:96 VP8/90000\r\n",
+            );
+        assert_eq!(offered_video_bitrate_bps(&offer), Some(800_000));
+
+        // video セクションに無ければセッション全体の値。0 は指定なし
+        let offer = OFFER_SDP.replace("v=0\r\n", "v=0\r\nb=AS:2500\r\n");
+        assert_eq!(offered_video_bitrate_bps(&offer), Some(2_500_000));
+        let offer = OFFER_SDP.replace("v=0\r\n", "v=0\r\nb=AS:0\r\n");
+        assert_eq!(offered_video_bitrate_bps(&offer), None);
+    }
+
+    #[test]
+    fn test_negotiate_video_codec() {
+        assert_eq!(
+            negotiate_video_codec(OFFER_SDP, Some(VideoCodec::H264)).unwrap(),
+            VideoCodec::H264
+        );
+        assert_eq!(
+            negotiate_video_codec(OFFER_SDP, None).unwrap(),
+            VideoCodec::H264
+        );
+
+        // H.264 を含まない Offer は共通コーデックが無いのでエラー
+        let vp8_only = "m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+        assert!(negotiate_video_codec(vp8_only, Some(VideoCodec::H264)).is_err());
+    }
+}
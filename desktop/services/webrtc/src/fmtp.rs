@@ -0,0 +1,275 @@
+// Answer SDP の fmtp をエンコーダーの実際の設定に合わせて書き換える
+//
+// webrtc-rs が生成する Answer の fmtp は Offer の値をそのまま写したものなので、
+// ホストのエンコーダーが実際に出すストリーム（レベルなど）とは一致しない。
+// クライアントに送る Answer の選択コーデックの fmtp をここで書き換える。
+// キャプチャ設定から決まるフレームレートとフレームサイズの上限（max-fr / max-fs）も宣言し、
+// ブラウザや中継する SFU がホストの出せない解像度を前提にしないようにする。
+// コーデックを追加するときは `codec_fmtp` に分岐を足す。
+
+use core_types::{fit_to_max_pixels, CaptureConfig, CaptureSize, H264Profile, VideoCodec};
+use tracing::debug;
+
+use crate::connection::codec_rtpmap_name;
+
+/// H.264 の level_idc（5.1: 4K 30fps まで収まる上限として宣言する）
+const H264_LEVEL_IDC: u8 = 0x33;
+
+/// Answer の fmtp で宣言する映像の上限（RFC 6184 の max-fr / max-fs）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VideoConstraints {
+    /// 最大フレームレート
+    pub max_fr: Option<u32>,
+    /// 最大フレームサイズ（16x16 のマクロブロック数）
+    pub max_fs: Option<u32>,
+}
+
+impl VideoConstraints {
+    /// キャプチャ設定から決める（元画面のサイズを使う場合は接続時にサイズが分からないので max-fs を付けない）
+    pub fn from_capture_config(config: &CaptureConfig) -> Self {
+        let max_fs = match config.size {
+            CaptureSize::Custom { width, height } => {
+                let (width, height) = match config.max_encode_pixels {
+                    Some(max_pixels) => fit_to_max_pixels(width, height, max_pixels),
+                    None => (width, height),
+                };
+                Some(width.div_ceil(16) * height.div_ceil(16))
+            }
+            CaptureSize::UseSourceSize => None,
+        };
+        Self {
+            max_fr: (config.fps > 0).then_some(config.fps),
+            max_fs,
+        }
+    }
+}
+
+/// エンコーダーが実際に使う fmtp パラメータ（キーはこの順で出力する）
+pub fn codec_fmtp(
+    codec: VideoCodec,
+    constraints: &VideoConstraints,
+) -> Vec<(&'static str, String)> {
+    match codec {
+        VideoCodec::H264 => {
+            let profile = H264Profile::default();
+            let mut params = vec![("level-asymmetry-allowed", "1".to_string())];
+            if let Some(max_fr) = constraints.max_fr {
+                params.push(("max-fr", max_fr.to_string()));
+            }
+            if let Some(max_fs) = constraints.max_fs {
+                params.push(("max-fs", max_fs.to_string()));
+            }
+            params.push(("packetization-mode", "1".to_string()));
+            params.push((
+                "profile-level-id",
+                format!(
+                    "{:02x}{:02x}{:02x}",
+                    profile.profile_idc(),
+                    profile.profile_iop(),
+                    H264_LEVEL_IDC
+                ),
+            ));
+            params
+        }
+    }
+}
+
+/// Answer の 1 つの fmtp を書き換える。書き換えてはいけない payload type なら None
+///
+/// H.264 は profile-level-id のうちレベル以外と packetization-mode を Offer と
+/// 揃える必要がある（RFC 6184 8.2.2）ため、プロファイルが一致するものだけを対象にする。
+fn rewrite_fmtp(codec: VideoCodec, params: &str, constraints: &VideoConstraints) -> Option<String> {
+    let mut params = parse_fmtp(params);
+    match codec {
+        VideoCodec::H264 => {
+            let wanted = codec_fmtp(codec, constraints);
+            let get = |params: &[(String, String)], key: &str| {
+                params
+                    .iter()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, v)| v.clone())
+            };
+            if get(&params, "packetization-mode").as_deref() != Some("1") {
+                return None;
+            }
+            let offered = get(&params, "profile-level-id")?;
+            let wanted_plid = wanted
+                .iter()
+                .find(|(k, _)| *k == "profile-level-id")
+                .map(|(_, v)| v.clone())?;
+            if offered.len() != 6 || !offered[..2].eq_ignore_ascii_case(&wanted_plid[..2]) {
+                return None;
+            }
+
+            for (key, value) in wanted {
+                // constraint フラグは Offer のものを維持し、レベルだけ実際の値にする
+                let value = if key == "profile-level-id" {
+                    format!("{}{}", &offered[..4], &value[4..])
+                } else {
+                    value
+                };
+                match params.iter_mut().find(|(k, _)| k == key) {
+                    Some((_, v)) => *v = value,
+                    None => params.push((key.to_string(), value)),
+                }
+            }
+        }
+    }
+    params.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Some(
+        params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(";"),
+    )
+}
+
+/// `key=value;key=value` を分解する
+fn parse_fmtp(params: &str) -> Vec<(String, String)> {
+    params
+        .split(';')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| match p.split_once('=') {
+            Some((k, v)) => (k.trim().to_ascii_lowercase(), v.trim().to_string()),
+            None => (p.to_ascii_lowercase(), String::new()),
+        })
+        .collect()
+}
+
+/// Answer SDP の video セクションで、選択コーデックの fmtp を書き換える
+pub fn munge_answer_fmtp(sdp: &str, codec: VideoCodec, constraints: &VideoConstraints) -> String {
+    // 選択コーデックの payload type を集める（rtpmap は fmtp より後にあってもよい）
+    let mut in_video = false;
+    let mut payload_types: Vec<&str> = Vec::new();
+    for line in sdp.lines().map(str::trim) {
+        if let Some(media) = line.strip_prefix("m=") {
+            in_video = media.starts_with("video");
+        } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:").filter(|_| in_video) {
+            let mut parts = rtpmap.split_whitespace();
+            let (Some(pt), Some(encoding)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            let name = encoding.split('/').next().unwrap_or_default();
+            if name.eq_ignore_ascii_case(codec_rtpmap_name(codec)) {
+                payload_types.push(pt);
+            }
+        }
+    }
+
+    let mut in_video = false;
+    let mut munged = String::with_capacity(sdp.len());
+    for line in sdp.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+        let ending = &line[content.len()..];
+        if let Some(media) = content.strip_prefix("m=") {
+            in_video = media.starts_with("video");
+        } else if let Some((pt, params)) = content
+            .strip_prefix("a=fmtp:")
+            .filter(|_| in_video)
+            .and_then(|fmtp| fmtp.split_once(' '))
+        {
+            if payload_types.contains(&pt) {
+                if let Some(params) = rewrite_fmtp(codec, params, constraints) {
+                    debug!("Rewriting fmtp for payload type {}: {}", pt, params);
+                    munged.push_str(&format!("a=fmtp:{} {}{}", pt, params, ending));
+                    continue;
+                }
+            }
+        }
+        munged.push_str(line);
+    }
+    munged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ANSWER_SDP: &str = "v=0\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=rtpmap:111 opus/48000/2\r\n\
+        a=fmtp:111 minptime=10;useinbandfec=1\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 102 127 123 124\r\n\
+        a=rtpmap:102 H264/90000\r\n\
+        a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n\
+        a=rtpmap:127 H264/90000\r\n\
+        a=fmtp:127 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=640c1f\r\n\
+        a=rtpmap:123 H264/90000\r\n\
+        a=fmtp:123 packetization-mode=1;profile-level-id=640c1f\r\n\
+        a=rtpmap:124 rtx/90000\r\n\
+        a=fmtp:124 apt=123\r\n";
+
+    #[test]
+    fn test_h264_high_fmtp() {
+        assert_eq!(
+            codec_fmtp(VideoCodec::H264, &VideoConstraints::default()),
+            vec![
+                ("level-asymmetry-allowed", "1".to_string()),
+                ("packetization-mode", "1".to_string()),
+                ("profile-level-id", "640033".to_string()),
+            ]
+        );
+
+        let munged = munge_answer_fmtp(ANSWER_SDP, VideoCodec::H264, &VideoConstraints::default());
+        assert!(munged.contains(
+            "a=fmtp:123 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=640c33\r\n"
+        ));
+        // プロファイルやパケット化モードが異なる payload type と、他コーデックはそのまま
+        assert!(munged.contains(
+            "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n"
+        ));
+        assert!(munged.contains(
+            "a=fmtp:127 level-asymmetry-allowed=1;packetization-mode=0;profile-level-id=640c1f\r\n"
+        ));
+        assert!(munged.contains("a=fmtp:124 apt=123\r\n"));
+        assert!(munged.contains("a=fmtp:111 minptime=10;useinbandfec=1\r\n"));
+        assert_eq!(munged.lines().count(), ANSWER_SDP.lines().count());
+    }
+
+    #[test]
+    fn test_answer_declares_capture_fps_and_size() {
+        let config = CaptureConfig {
+            size: CaptureSize::Custom {
+                width: 1920,
+                height: 1080,
+            },
+            fps: 60,
+            ..Default::default()
+        };
+        let constraints = VideoConstraints::from_capture_config(&config);
+        // 1080 は 16 の倍数でないので 68 行のマクロブロックになる
+        assert_eq!(
+            constraints,
+            VideoConstraints {
+                max_fr: Some(60),
+                max_fs: Some(120 * 68),
+            }
+        );
+
+        let munged = munge_answer_fmtp(ANSWER_SDP, VideoCodec::H264, &constraints);
+        assert!(munged.contains(
+            "a=fmtp:123 level-asymmetry-allowed=1;max-fr=60;max-fs=8160;packetization-mode=1;profile-level-id=640c33\r\n"
+        ));
+        // 書き換えない payload type には付けない
+        assert!(munged.contains(
+            "a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n"
+        ));
+
+        // エンコード時の上限で縮小されるサイズを宣言する
+        let capped = CaptureConfig {
+            max_encode_pixels: Some(1280 * 720),
+            ..config.clone()
+        };
+        assert_eq!(
+            VideoConstraints::from_capture_config(&capped).max_fs,
+            Some(80 * 45)
+        );
+        // 元画面のサイズを使う場合はサイズの上限を宣言しない
+        let source_size = CaptureConfig::default();
+        let constraints = VideoConstraints::from_capture_config(&source_size);
+        assert_eq!(constraints.max_fr, Some(source_size.fps));
+        assert_eq!(constraints.max_fs, None);
+    }
+}
@@ -0,0 +1,184 @@
+// 接続直後のキーフレーム要求をまとめる
+//
+// 複数のビューアーがほぼ同時に接続すると（リンクを共有して全員が一斉に開いた場合など）、
+// 接続ごとに PeerConnection と ICE の両方から Reconnect のキーフレーム要求が出て、
+// 短い間隔でキーフレームが何枚も送られビットレートが跳ねる。接続による要求を受けたら一定時間待ち、
+// その間に届いた要求（PLI を含む）を 1 回の要求にまとめて VideoStreamService に渡す。
+// 接続と関係のない PLI などはこれまでどおりすぐに渡す（パケットロスからの復帰を遅らせない）。
+
+use core_types::{KeyframeReason, VideoStreamMessage};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// 接続によるキーフレーム要求をまとめる時間（デフォルト）
+pub const DEFAULT_KEYFRAME_COALESCE_WINDOW: Duration = Duration::from_millis(200);
+
+/// まとめている途中の要求
+#[derive(Debug)]
+struct PendingKeyframe {
+    deadline: Instant,
+    /// 要求の理由（`KeyframeReason` のビット）
+    reasons: u32,
+    requests: u32,
+}
+
+impl PendingKeyframe {
+    fn add(&mut self, reason: KeyframeReason) {
+        self.reasons |= 1 << reason as u32;
+        self.requests += 1;
+    }
+
+    /// まとめた要求の理由（重なっていれば `KeyframeReason::ALL` で先にあるもの）
+    fn reason(&self) -> KeyframeReason {
+        KeyframeReason::ALL
+            .into_iter()
+            .find(|reason| self.reasons & (1 << *reason as u32) != 0)
+            .unwrap_or(KeyframeReason::Reconnect)
+    }
+}
+
+/// `video_stream_msg_tx` の手前で接続によるキーフレーム要求を `window` だけまとめる送信口を作る
+/// （キーフレーム要求以外のメッセージはそのまま渡す）
+pub fn spawn_keyframe_coalescer(
+    window: Duration,
+    video_stream_msg_tx: mpsc::Sender<VideoStreamMessage>,
+) -> mpsc::Sender<VideoStreamMessage> {
+    let (tx, rx) = mpsc::channel(100);
+    tokio::spawn(run_keyframe_coalescer(window, rx, video_stream_msg_tx));
+    tx
+}
+
+async fn run_keyframe_coalescer(
+    window: Duration,
+    mut rx: mpsc::Receiver<VideoStreamMessage>,
+    tx: mpsc::Sender<VideoStreamMessage>,
+) {
+    let mut pending: Option<PendingKeyframe> = None;
+    loop {
+        let deadline = pending.as_ref().map(|p| p.deadline);
+        let msg = tokio::select! {
+            msg = rx.recv() => msg,
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                if let Some(done) = pending.take() {
+                    send_coalesced(&tx, done).await;
+                }
+                continue;
+            }
+        };
+        match msg {
+            Some(VideoStreamMessage::RequestKeyframe { reason }) => {
+                if let Some(pending) = pending.as_mut() {
+                    pending.add(reason);
+                    continue;
+                }
+                if reason != KeyframeReason::Reconnect {
+                    let _ = tx
+                        .send(VideoStreamMessage::RequestKeyframe { reason })
+                        .await;
+                    continue;
+                }
+                let mut started = PendingKeyframe {
+                    deadline: Instant::now() + window,
+                    reasons: 0,
+                    requests: 0,
+                };
+                started.add(reason);
+                pending = Some(started);
+            }
+            Some(other) => {
+                if tx.send(other).await.is_err() {
+                    warn!("Video stream message receiver dropped");
+                    break;
+                }
+            }
+            None => {
+                // 止めるときも溜めていた要求は渡しておく
+                if let Some(done) = pending.take() {
+                    send_coalesced(&tx, done).await;
+                }
+                break;
+            }
+        }
+    }
+}
+
+async fn send_coalesced(tx: &mpsc::Sender<VideoStreamMessage>, pending: PendingKeyframe) {
+    let reason = pending.reason();
+    if pending.requests > 1 {
+        debug!(
+            "Coalesced {} keyframe requests into one ({})",
+            pending.requests, reason
+        );
+    }
+    let _ = tx
+        .send(VideoStreamMessage::RequestKeyframe { reason })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(reason: KeyframeReason) -> VideoStreamMessage {
+        VideoStreamMessage::RequestKeyframe { reason }
+    }
+
+    /// `rx` に届いているメッセージのうちキーフレーム要求の理由
+    fn drain_keyframes(rx: &mut mpsc::Receiver<VideoStreamMessage>) -> Vec<KeyframeReason> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                VideoStreamMessage::RequestKeyframe { reason } => Some(reason),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// 時間を止めたまま `duration` 進め、コアレッサーのタスクに処理させる
+    async fn advance(duration: Duration) {
+        tokio::time::advance(duration).await;
+        for _ in 0..4 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_near_simultaneous_joins_share_one_keyframe() {
+        let window = Duration::from_millis(200);
+        let (out_tx, mut out_rx) = mpsc::channel(16);
+        let tx = spawn_keyframe_coalescer(window, out_tx);
+
+        // 3 人がほぼ同時に接続する（間に PLI も混ざる）
+        for _ in 0..3 {
+            tx.send(keyframe(KeyframeReason::Reconnect)).await.unwrap();
+            advance(Duration::from_millis(10)).await;
+        }
+        tx.send(keyframe(KeyframeReason::Pli)).await.unwrap();
+        // まとめている間も他のメッセージはすぐに渡す
+        tx.send(VideoStreamMessage::Pause).await.unwrap();
+        advance(Duration::from_millis(20)).await;
+        assert!(matches!(out_rx.try_recv(), Ok(VideoStreamMessage::Pause)));
+        assert!(drain_keyframes(&mut out_rx).is_empty());
+
+        // 最初の要求から `window` 経ったらまとめて 1 回だけ渡す
+        advance(window).await;
+        assert_eq!(drain_keyframes(&mut out_rx), [KeyframeReason::Pli]);
+
+        // 接続と関係のない PLI は待たずに渡す
+        tx.send(keyframe(KeyframeReason::Pli)).await.unwrap();
+        advance(Duration::ZERO).await;
+        assert_eq!(drain_keyframes(&mut out_rx), [KeyframeReason::Pli]);
+
+        // 送信側を閉じると溜めていた要求を渡して終わる
+        tx.send(keyframe(KeyframeReason::Reconnect)).await.unwrap();
+        drop(tx);
+        assert!(matches!(
+            out_rx.recv().await,
+            Some(VideoStreamMessage::RequestKeyframe {
+                reason: KeyframeReason::Reconnect
+            })
+        ));
+        assert!(out_rx.recv().await.is_none());
+    }
+}
@@ -1,17 +1,36 @@
+mod bitrate_cap;
+mod channels;
 mod connection;
+mod fmtp;
+mod keyframe_coalesce;
+pub mod loopback;
+mod on_demand;
+mod qos;
+mod session;
 
 use anyhow::Result;
-use core_types::VideoStreamMessage;
+use core_types::{AudioStreamMessage, CaptureConfig, Metrics, VideoStreamMessage};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
+use webrtc_rs::ice::udp_mux::UDPMux;
 use webrtc_rs::peer_connection::RTCPeerConnection;
 
+use core_types::{
+    DataChannelMessage, OutgoingDataChannelMessage, SignalingResponse, WebRtcMessage,
+};
 use std::sync::Mutex;
-use core_types::{DataChannelMessage, OutgoingDataChannelMessage, SignalingResponse, WebRtcMessage};
 
-use connection::{handle_add_ice_candidate, handle_set_offer};
+use bitrate_cap::BitrateCaps;
+use channels::DataChannels;
+use connection::{handle_add_ice_candidate, handle_set_offer, SetOfferContext};
+use fmtp::VideoConstraints;
+use session::{SessionTable, SESSION_TTL};
+
+pub use keyframe_coalesce::DEFAULT_KEYFRAME_COALESCE_WINDOW;
+pub use on_demand::OnDemandCapture;
+pub use qos::Dscp;
 
 /// WebRTCサービス
 pub struct WebRtcService {
@@ -33,6 +52,19 @@ pub struct WebRtcService {
             Arc<webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender>,
         )>,
     >,
+    audio_stream_msg_tx: Option<mpsc::Sender<AudioStreamMessage>>,
+    /// 送出パケットに付ける DSCP（None ならソケットは webrtc-rs に任せる）
+    dscp: Option<Dscp>,
+    /// 監視用のメトリクス（hostd の /metrics で公開する）
+    metrics: Arc<Metrics>,
+    /// 接続によるキーフレーム要求をまとめる時間（ゼロならまとめない）
+    keyframe_coalesce_window: std::time::Duration,
+    /// 音声を送れるか（false の間は音声トラックなしで Answer を返す）
+    audio_available: Arc<AtomicBool>,
+    /// Answer の fmtp で宣言する映像の上限（max-fr / max-fs）
+    video_constraints: VideoConstraints,
+    /// 視聴中だけキャプチャする場合の開始・停止（None ならキャプチャは接続と関係なく動かす）
+    on_demand_capture: Option<OnDemandCapture>,
 }
 
 impl WebRtcService {
@@ -54,6 +86,7 @@ impl WebRtcService {
                 Arc<webrtc_rs::rtp_transceiver::rtp_sender::RTCRtpSender>,
             )>,
         >,
+        audio_stream_msg_tx: Option<mpsc::Sender<AudioStreamMessage>>,
     ) -> (Self, mpsc::Sender<WebRtcMessage>) {
         let (message_tx, message_rx) = mpsc::channel(100);
         (
@@ -65,22 +98,66 @@ impl WebRtcService {
                 video_track_tx,
                 video_stream_msg_tx,
                 audio_track_tx,
+                audio_stream_msg_tx,
+                dscp: None,
+                metrics: Arc::new(Metrics::default()),
+                keyframe_coalesce_window: DEFAULT_KEYFRAME_COALESCE_WINDOW,
+                audio_available: Arc::new(AtomicBool::new(true)),
+                video_constraints: VideoConstraints::default(),
+                on_demand_capture: None,
             },
             message_tx,
         )
     }
 
+    /// 送出する UDP パケットに DSCP を付ける（Windows では QoS ポリシーが必要、qos.rs を参照）
+    pub fn with_dscp(mut self, dscp: Dscp) -> Self {
+        self.dscp = Some(dscp);
+        self
+    }
+
+    /// セッションの開始・終了を `metrics` に書き込む
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// 接続によるキーフレーム要求を受けてから `window` の間に届いた要求を 1 回にまとめる
+    /// （ほぼ同時に接続したビューアーで 1 枚のキーフレームを共有する。ゼロでまとめない）
+    pub fn with_keyframe_coalesce(mut self, window: std::time::Duration) -> Self {
+        self.keyframe_coalesce_window = window;
+        self
+    }
+
+    /// 音声を送れるかを `available` で共有する
+    /// （音声のキャプチャやエンコードが使えなくなったら、以降の接続は映像だけにする）
+    pub fn with_audio_available(mut self, available: Arc<AtomicBool>) -> Self {
+        self.audio_available = available;
+        self
+    }
+
+    /// キャプチャ設定のフレームレートとサイズを Answer の fmtp の max-fr / max-fs として宣言する
+    pub fn with_capture_config(mut self, config: &CaptureConfig) -> Self {
+        self.video_constraints = VideoConstraints::from_capture_config(config);
+        self
+    }
+
+    /// 最初のセッションが繋がったらキャプチャを開始し、最後のセッションが切れたら止める
+    pub fn with_on_demand_capture(mut self, on_demand_capture: OnDemandCapture) -> Self {
+        self.on_demand_capture = Some(on_demand_capture);
+        self
+    }
+
     /// ICE Restartを実行
-    async fn execute_ice_restart(
-        &self,
-        peer_connection: &Arc<RTCPeerConnection>,
-    ) -> Result<()> {
+    async fn execute_ice_restart(&self, peer_connection: &Arc<RTCPeerConnection>) -> Result<()> {
         use anyhow::Context;
 
         info!("Executing ICE Restart...");
 
         // 1. restart_ice()を呼び出し（新しいICE credentialsを生成）
-        peer_connection.restart_ice().await
+        peer_connection
+            .restart_ice()
+            .await
             .context("Failed to restart ICE")?;
 
         // 2. 新しいOfferを生成
@@ -107,16 +184,72 @@ impl WebRtcService {
         Ok(())
     }
 
+    /// 映像・音声の送出を一時停止/再開（PeerConnection はそのまま維持）
+    async fn set_stream_paused(&self, paused: bool, video: bool, audio: bool) {
+        if video {
+            if let Some(ref tx) = self.video_stream_msg_tx {
+                let msg = if paused {
+                    VideoStreamMessage::Pause
+                } else {
+                    VideoStreamMessage::Resume
+                };
+                if tx.send(msg).await.is_err() {
+                    warn!("Failed to send video pause/resume: receiver dropped");
+                }
+            }
+        }
+        if audio {
+            if let Some(ref tx) = self.audio_stream_msg_tx {
+                let msg = if paused {
+                    AudioStreamMessage::Pause
+                } else {
+                    AudioStreamMessage::Resume
+                };
+                if tx.send(msg).await.is_err() {
+                    warn!("Failed to send audio pause/resume: receiver dropped");
+                }
+            }
+        }
+    }
+
     pub async fn run(mut self, webrtc_msg_tx: mpsc::Sender<WebRtcMessage>) -> Result<()> {
         info!("WebRtcService started");
 
+        // 接続ごとのキーフレーム要求をまとめてから VideoStreamService に渡す
+        if !self.keyframe_coalesce_window.is_zero() {
+            self.video_stream_msg_tx = self.video_stream_msg_tx.take().map(|tx| {
+                keyframe_coalesce::spawn_keyframe_coalescer(self.keyframe_coalesce_window, tx)
+            });
+        }
+
         // ICE/DTLS が接続完了したかを共有するフラグ（接続前は送出しない）
         let connection_ready = Arc::new(AtomicBool::new(false));
 
-        // アクティブなデータチャネルを保持（outgoing用）
-        let active_data_channel = Arc::new(Mutex::new(None::<Arc<webrtc_rs::data_channel::RTCDataChannel>>));
+        // 開いているデータチャネルを保持（outgoing用、用途ごと）
+        let data_channels = Arc::new(Mutex::new(DataChannels::default()));
 
         let mut peer_connection: Option<Arc<RTCPeerConnection>> = None;
+        // ビューアーのセッション（再接続時にコーデックを引き継ぐ）
+        let mut sessions = SessionTable::new(SESSION_TTL);
+        // 接続ごとのビットレートの上限（接続中の最小値をエンコーダーに渡す）
+        let bitrate_caps = BitrateCaps::default();
+        // DSCP を付けた UDP ソケット（ピア接続をまたいで共有し、停止時に閉じる）
+        let udp_mux = match self.dscp {
+            Some(dscp) => match qos::create_dscp_udp_mux(dscp) {
+                Ok(mux) => {
+                    info!("Marking outgoing media packets with DSCP {}", dscp.value());
+                    Some(mux)
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to create DSCP-marked UDP socket, sending unmarked: {:#}",
+                        e
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
 
         loop {
             tokio::select! {
@@ -131,7 +264,10 @@ impl WebRtcService {
                 } => {
                     match msg {
                         Some(outgoing_msg) => {
-                             let dc_opt = active_data_channel.lock().unwrap().clone();
+                             let dc_opt = match &outgoing_msg {
+                                 OutgoingDataChannelMessage::Text(data_msg) => data_channels.lock().unwrap().for_message(data_msg),
+                                 OutgoingDataChannelMessage::Binary(_) => data_channels.lock().unwrap().control(),
+                             };
                              if let Some(dc) = dc_opt {
                                  match outgoing_msg {
                                      OutgoingDataChannelMessage::Text(data_msg) => {
@@ -163,8 +299,19 @@ impl WebRtcService {
                 // メッセージ受信
                 msg = self.message_rx.recv() => {
                     match msg {
-                        Some(WebRtcMessage::SetOffer { sdp, codec }) => {
-                            info!("Received SetOffer message (codec: {:?})", codec);
+                        Some(WebRtcMessage::SetOffer { sdp, codec, negotiation_id }) => {
+                            info!("Received SetOffer message (codec: {:?}, negotiation: {:?})", codec, negotiation_id);
+                            // 既知のセッションなら、要求がない限り同じコーデックで繋ぎ直す（エンコーダーを作り直さない）
+                            let codec = match sessions.begin(negotiation_id.as_deref(), std::time::Instant::now()) {
+                                Some(resumed) => {
+                                    info!(
+                                        "Resuming session {:?} (resume #{}, codec: {})",
+                                        negotiation_id, resumed.resumes, resumed.codec
+                                    );
+                                    codec.or(Some(resumed.codec))
+                                }
+                                None => codec,
+                            };
                             // 既存のPeerConnectionが存在する場合はクリーンアップ
                             if peer_connection.is_some() {
                                 info!("Cleaning up existing PeerConnection before creating new one");
@@ -180,8 +327,8 @@ impl WebRtcService {
 
                                 // connection_readyフラグをリセット
                                 connection_ready.store(false, std::sync::atomic::Ordering::Relaxed);
-                                // active_data_channelもリセット
-                                *active_data_channel.lock().unwrap() = None;
+                                // データチャネルもリセット
+                                *data_channels.lock().unwrap() = DataChannels::default();
                             }
 
                             // video_stream_msg_tx を取得（None の場合は後続処理をスキップ）
@@ -189,22 +336,29 @@ impl WebRtcService {
                                 Some(tx) => tx,
                                 None => {
                                     warn!("video_stream_msg_tx is None, skipping SetOffer");
+                                    sessions.abort();
                                     continue;
                                 }
                             };
 
-                            match handle_set_offer(
-                                sdp,
-                                codec,
-                                self.signaling_tx.clone(),
-                                self.data_channel_tx.clone(),
-                                connection_ready.clone(),
+                            let ctx = SetOfferContext {
+                                signaling_tx: self.signaling_tx.clone(),
+                                data_channel_tx: self.data_channel_tx.clone(),
+                                connection_ready: connection_ready.clone(),
                                 video_stream_msg_tx,
-                                webrtc_msg_tx.clone(),
-                                active_data_channel.clone(),
-                            ).await {
+                                webrtc_msg_tx: webrtc_msg_tx.clone(),
+                                data_channels: data_channels.clone(),
+                                udp_mux: udp_mux.clone().map(|mux| mux as Arc<dyn UDPMux + Send + Sync>),
+                                metrics: self.metrics.clone(),
+                                audio_enabled: self.audio_available.load(std::sync::atomic::Ordering::Relaxed),
+                                video_constraints: self.video_constraints,
+                                on_demand_capture: self.on_demand_capture.clone(),
+                                bitrate_caps: bitrate_caps.clone(),
+                            };
+                            match handle_set_offer(sdp, codec, ctx).await {
                                 Ok(result) => {
                                     peer_connection = Some(result.peer_connection.clone());
+                                    sessions.set_codec(result.video_codec);
 
                                     // ビデオトラック情報をVideoStreamServiceに送信
                                     if let Some(ref tx) = self.video_track_tx {
@@ -216,8 +370,8 @@ impl WebRtcService {
                                     }
 
                                     // 音声トラックをAudioStreamServiceに送信
-                                    if let Some(ref tx) = self.audio_track_tx {
-                                        if tx.send((result.audio_track, result.audio_sender)).await.is_ok() {
+                                    if let (Some(tx), Some(audio)) = (&self.audio_track_tx, result.audio) {
+                                        if tx.send(audio).await.is_ok() {
                                             info!("Audio track sent to AudioStreamService");
                                         } else {
                                             warn!("Failed to send audio track: receiver dropped");
@@ -226,6 +380,7 @@ impl WebRtcService {
                                 }
                                 Err(e) => {
                                     warn!("Failed to handle SetOffer: {}", e);
+                                    sessions.abort();
                                     let _ = self
                                         .signaling_tx
                                         .send(SignalingResponse::Error {
@@ -289,6 +444,58 @@ impl WebRtcService {
                                 warn!("Cannot set answer for ICE restart: no peer connection exists");
                             }
                         }
+                        Some(WebRtcMessage::PauseStream { video, audio }) => {
+                            info!("Received PauseStream message (video: {}, audio: {})", video, audio);
+                            self.set_stream_paused(true, video, audio).await;
+                        }
+                        Some(WebRtcMessage::ResumeStream { video, audio }) => {
+                            info!("Received ResumeStream message (video: {}, audio: {})", video, audio);
+                            self.set_stream_paused(false, video, audio).await;
+                        }
+                        Some(WebRtcMessage::SetAudioSourceGain { source, gain, muted }) => {
+                            info!("Received SetAudioSourceGain message (source: {}, gain: {}, muted: {})", source, gain, muted);
+                            if let Some(ref tx) = self.audio_stream_msg_tx {
+                                if tx.send(AudioStreamMessage::SetSourceGain { source, gain, muted }).await.is_err() {
+                                    warn!("Failed to send audio source gain: receiver dropped");
+                                }
+                            }
+                        }
+                        Some(WebRtcMessage::RestartEncoder) => {
+                            info!("Received RestartEncoder message");
+                            if let Some(ref tx) = self.video_stream_msg_tx {
+                                if tx.send(VideoStreamMessage::RestartEncoder).await.is_err() {
+                                    warn!("Failed to send encoder restart request: receiver dropped");
+                                }
+                            }
+                        }
+                        Some(WebRtcMessage::SaveReplay) => {
+                            info!("Received SaveReplay message");
+                            if let Some(ref tx) = self.video_stream_msg_tx {
+                                if tx.send(VideoStreamMessage::SaveReplay).await.is_err() {
+                                    warn!("Failed to send save replay request: receiver dropped");
+                                }
+                            }
+                        }
+                        Some(WebRtcMessage::StartRecording) => {
+                            info!("Received StartRecording message");
+                            if let Some(ref tx) = self.video_stream_msg_tx {
+                                if tx.send(VideoStreamMessage::StartRecording).await.is_err() {
+                                    warn!("Failed to send start recording request: receiver dropped");
+                                }
+                            }
+                        }
+                        Some(WebRtcMessage::StopRecording) => {
+                            info!("Received StopRecording message");
+                            if let Some(ref tx) = self.video_stream_msg_tx {
+                                if tx.send(VideoStreamMessage::StopRecording).await.is_err() {
+                                    warn!("Failed to send stop recording request: receiver dropped");
+                                }
+                            }
+                        }
+                        Some(WebRtcMessage::Shutdown) => {
+                            info!("Received Shutdown message");
+                            break;
+                        }
                         None => {
                             debug!("Message channel closed");
                             break;
@@ -302,6 +509,9 @@ impl WebRtcService {
         if let Some(pc) = peer_connection {
             let _ = pc.close().await;
         }
+        if let Some(mux) = udp_mux {
+            let _ = mux.close().await;
+        }
 
         info!("WebRtcService stopped");
         Ok(())
@@ -0,0 +1,335 @@
+// ブラウザなしでパイプライン全体を動かすためのループバックモード
+//
+// ホスト自身が受信側 PeerConnection を作って Offer を生成し、WebRtcService に渡して
+// Answer を受け取る。受信した RTP パケットは統計としてメモリ上に記録し、
+// キーフレームが届いているか・バイト数が妥当かを検証できるようにする。
+
+use anyhow::{bail, Context, Result};
+use core_types::{SignalingResponse, VideoCodec, WebRtcMessage};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use webrtc_rs::api::interceptor_registry::register_default_interceptors;
+use webrtc_rs::api::media_engine::MediaEngine;
+use webrtc_rs::api::APIBuilder;
+use webrtc_rs::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc_rs::interceptor::registry::Registry;
+use webrtc_rs::peer_connection::configuration::RTCConfiguration;
+use webrtc_rs::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc_rs::peer_connection::RTCPeerConnection;
+use webrtc_rs::rtp_transceiver::rtp_codec::RTPCodecType;
+use webrtc_rs::rtp_transceiver::rtp_transceiver_direction::RTCRtpTransceiverDirection;
+use webrtc_rs::rtp_transceiver::RTCRtpTransceiverInit;
+use webrtc_rs::track::track_remote::TrackRemote;
+
+/// ループバック受信側で集計した統計
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackStats {
+    pub video_packets: u64,
+    pub video_bytes: u64,
+    /// マーカービットで区切ったフレーム数
+    pub video_frames: u64,
+    /// IDR スライスを含むフレーム数
+    pub keyframes: u64,
+    pub audio_packets: u64,
+    pub audio_bytes: u64,
+}
+
+/// ブラウザの代わりに Offer を出す受信専用クライアント
+pub struct LoopbackClient {
+    peer_connection: Arc<RTCPeerConnection>,
+    stats: Arc<Mutex<LoopbackStats>>,
+}
+
+impl LoopbackClient {
+    pub async fn new() -> Result<Self> {
+        let mut m = MediaEngine::default();
+        m.register_default_codecs()?;
+
+        let mut registry = Registry::new();
+        registry = register_default_interceptors(registry, &mut m)?;
+
+        let api = APIBuilder::new()
+            .with_media_engine(m)
+            .with_interceptor_registry(registry)
+            .build();
+
+        // 同一ホスト内で完結するため STUN サーバーは使わない
+        let pc = Arc::new(
+            api.new_peer_connection(RTCConfiguration::default())
+                .await
+                .context("Failed to create loopback peer connection")?,
+        );
+
+        // ブラウザと同じく recvonly の video/audio と "input" データチャネルを用意
+        for kind in [RTPCodecType::Video, RTPCodecType::Audio] {
+            pc.add_transceiver_from_kind(
+                kind,
+                Some(RTCRtpTransceiverInit {
+                    direction: RTCRtpTransceiverDirection::Recvonly,
+                    send_encodings: vec![],
+                }),
+            )
+            .await
+            .context("Failed to add loopback transceiver")?;
+        }
+        pc.create_data_channel("input", None)
+            .await
+            .context("Failed to create loopback data channel")?;
+
+        let stats = Arc::new(Mutex::new(LoopbackStats::default()));
+        let stats_for_track = stats.clone();
+        pc.on_track(Box::new(
+            move |track: Arc<TrackRemote>, _receiver, _transceiver| {
+                let stats = stats_for_track.clone();
+                Box::pin(async move {
+                    info!("Loopback track received: {}", track.kind());
+                    tokio::spawn(read_track(track, stats));
+                })
+            },
+        ));
+
+        Ok(Self {
+            peer_connection: pc,
+            stats,
+        })
+    }
+
+    /// ICE gathering 完了まで待ってから Offer SDP を返す
+    pub async fn create_offer(&self) -> Result<String> {
+        let offer = self
+            .peer_connection
+            .create_offer(None)
+            .await
+            .context("Failed to create loopback offer")?;
+
+        let mut gather_complete = self.peer_connection.gathering_complete_promise().await;
+        self.peer_connection
+            .set_local_description(offer)
+            .await
+            .context("Failed to set loopback local description")?;
+        let _ = gather_complete.recv().await;
+
+        let local = self
+            .peer_connection
+            .local_description()
+            .await
+            .context("Loopback local description is missing")?;
+        Ok(local.sdp)
+    }
+
+    pub async fn set_answer(&self, sdp: String) -> Result<()> {
+        let answer = RTCSessionDescription::answer(sdp).context("Failed to parse answer SDP")?;
+        self.peer_connection
+            .set_remote_description(answer)
+            .await
+            .context("Failed to set loopback remote description")
+    }
+
+    pub async fn add_ice_candidate(&self, candidate: RTCIceCandidateInit) -> Result<()> {
+        self.peer_connection
+            .add_ice_candidate(candidate)
+            .await
+            .context("Failed to add ICE candidate to loopback peer")
+    }
+
+    pub fn stats(&self) -> LoopbackStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub async fn close(&self) -> Result<()> {
+        self.peer_connection
+            .close()
+            .await
+            .context("Failed to close loopback peer connection")
+    }
+}
+
+/// 受信トラックから RTP を読み続けて統計を更新
+async fn read_track(track: Arc<TrackRemote>, stats: Arc<Mutex<LoopbackStats>>) {
+    let kind = track.kind();
+    let mut frame_has_idr = false;
+    loop {
+        let packet = match track.read_rtp().await {
+            Ok((packet, _)) => packet,
+            Err(e) => {
+                debug!("Loopback track read finished: {}", e);
+                break;
+            }
+        };
+
+        let mut s = stats.lock().unwrap();
+        match kind {
+            RTPCodecType::Video => {
+                s.video_packets += 1;
+                s.video_bytes += packet.payload.len() as u64;
+                frame_has_idr |= h264_payload_has_idr(&packet.payload);
+                if packet.header.marker {
+                    s.video_frames += 1;
+                    if frame_has_idr {
+                        s.keyframes += 1;
+                    }
+                    frame_has_idr = false;
+                }
+            }
+            RTPCodecType::Audio => {
+                s.audio_packets += 1;
+                s.audio_bytes += packet.payload.len() as u64;
+            }
+            RTPCodecType::Unspecified => {}
+        }
+    }
+}
+
+/// H.264 RTP ペイロード (RFC 6184) に IDR スライスの先頭が含まれるか判定
+fn h264_payload_has_idr(payload: &[u8]) -> bool {
+    const NAL_IDR: u8 = 5;
+    const NAL_STAP_A: u8 = 24;
+    const NAL_FU_A: u8 = 28;
+
+    let Some(&header) = payload.first() else {
+        return false;
+    };
+    match header & 0x1F {
+        NAL_IDR => true,
+        NAL_STAP_A => {
+            // [header][size(2)][NAL]...[size(2)][NAL]
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                offset += 2;
+                if size == 0 || offset + size > payload.len() {
+                    break;
+                }
+                if payload[offset] & 0x1F == NAL_IDR {
+                    return true;
+                }
+                offset += size;
+            }
+            false
+        }
+        NAL_FU_A => {
+            // FU header の S ビットが立っている先頭フラグメントのみ数える
+            payload
+                .get(1)
+                .map(|fu| fu & 0x80 != 0 && fu & 0x1F == NAL_IDR)
+                .unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+/// シグナリングサーバーの代わりにループバッククライアントと WebRtcService を繋ぐ
+///
+/// `duration` の間ストリームを受信し、キーフレームが一つも届かなければエラーを返す。
+pub async fn run_loopback(
+    webrtc_tx: mpsc::Sender<WebRtcMessage>,
+    mut signaling_rx: mpsc::Receiver<SignalingResponse>,
+    duration: Duration,
+) -> Result<()> {
+    info!("Starting loopback session for {:?}", duration);
+
+    let client = LoopbackClient::new().await?;
+    let offer = client.create_offer().await?;
+    webrtc_tx
+        .send(WebRtcMessage::SetOffer {
+            sdp: offer,
+            codec: Some(VideoCodec::H264),
+            negotiation_id: None,
+        })
+        .await
+        .context("Failed to send loopback offer to WebRTC service")?;
+
+    // Answer 適用前に届いた ICE candidate は保留しておく
+    let mut answer_set = false;
+    let mut pending_candidates = Vec::new();
+
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+    let mut report_interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = report_interval.tick() => {
+                debug!("Loopback stats: {:?}", client.stats());
+            }
+            msg = signaling_rx.recv() => match msg {
+                Some(SignalingResponse::Answer { sdp, codec }) => {
+                    info!("Loopback answer received (codec: {})", codec);
+                    client.set_answer(sdp).await?;
+                    answer_set = true;
+                    for candidate in pending_candidates.drain(..) {
+                        client.add_ice_candidate(candidate).await?;
+                    }
+                }
+                Some(SignalingResponse::IceCandidate { candidate, sdp_mid, sdp_mline_index, username_fragment }) => {
+                    let candidate = RTCIceCandidateInit {
+                        candidate,
+                        sdp_mid,
+                        sdp_mline_index,
+                        username_fragment,
+                    };
+                    if answer_set {
+                        client.add_ice_candidate(candidate).await?;
+                    } else {
+                        pending_candidates.push(candidate);
+                    }
+                }
+                Some(SignalingResponse::IceCandidateComplete) => {
+                    debug!("Loopback: host ICE gathering complete");
+                }
+                Some(SignalingResponse::OfferForRestart { .. }) => {
+                    warn!("Loopback: ICE restart is not supported, ignoring");
+                }
+                Some(SignalingResponse::ConnectionState { state }) => {
+                    debug!("Loopback: host connection state {}", state);
+                }
+                Some(SignalingResponse::Error { message }) => {
+                    bail!("WebRTC service returned error in loopback: {}", message);
+                }
+                None => {
+                    warn!("Loopback: signaling channel closed");
+                    break;
+                }
+            }
+        }
+    }
+
+    let stats = client.stats();
+    let _ = client.close().await;
+    info!("Loopback finished: {:?}", stats);
+
+    if stats.keyframes == 0 {
+        bail!("No keyframe received in loopback ({:?})", stats);
+    }
+    if stats.video_bytes < stats.video_frames {
+        bail!("Video byte count looks wrong in loopback ({:?})", stats);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_h264_payload_has_idr() {
+        // 単一 NAL (IDR / non-IDR)
+        assert!(h264_payload_has_idr(&[0x65, 0x88]));
+        assert!(!h264_payload_has_idr(&[0x41, 0x9a]));
+        // STAP-A に SPS, PPS, IDR
+        assert!(h264_payload_has_idr(&[
+            0x78, 0x00, 0x02, 0x67, 0x42, 0x00, 0x02, 0x68, 0xce, 0x00, 0x02, 0x65, 0x88,
+        ]));
+        // STAP-A に SPS, PPS のみ
+        assert!(!h264_payload_has_idr(&[
+            0x78, 0x00, 0x02, 0x67, 0x42, 0x00, 0x02, 0x68, 0xce,
+        ]));
+        // FU-A: IDR の先頭フラグメントのみ true
+        assert!(h264_payload_has_idr(&[0x7c, 0x85, 0x88]));
+        assert!(!h264_payload_has_idr(&[0x7c, 0x05, 0x88]));
+        assert!(!h264_payload_has_idr(&[]));
+    }
+}
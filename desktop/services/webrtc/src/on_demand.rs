@@ -0,0 +1,220 @@
+// 視聴中だけキャプチャするモード（`--on-demand`）
+//
+// 常時起動のホストでは、誰も見ていない間もキャプチャとエンコードが GPU を使い続ける。
+// 接続中のセッションを数え、最初のセッションが繋がったらキャプチャを開始し、最後のセッションが
+// 切れたら止める。エンコードはキャプチャしたフレームにだけ行うので、キャプチャと一緒に止まる。
+// 音声はキャプチャを止めても途切れを埋めるフレームがエンコードされ続けるので、キャプチャを止めた上で
+// AudioStreamService も一時停止する（マイクのキャプチャは止めないが、一時停止でエンコードはされない）。
+// セッションはメトリクスの sessions_active と同じく PeerConnection の状態（Connected / 切断）で数える。
+
+use core_types::{AudioCaptureMessage, AudioStreamMessage, CaptureMessage, CaptureTarget};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{info, warn};
+
+/// キャプチャを開始するときの対象（まだ決まっていなければ None）
+type CaptureTargetFn = dyn Fn() -> Option<CaptureTarget> + Send + Sync;
+
+/// 音声のキャプチャを開始するときの指示（キャプチャする対象がなければ None）
+type AudioStartFn = dyn Fn() -> Option<AudioCaptureMessage> + Send + Sync;
+
+/// 視聴中だけ動かす音声のキャプチャとエンコード
+#[derive(Clone)]
+struct OnDemandAudio {
+    audio_capture_cmd_tx: mpsc::Sender<AudioCaptureMessage>,
+    audio_stream_msg_tx: mpsc::Sender<AudioStreamMessage>,
+    start: Arc<AudioStartFn>,
+}
+
+/// 接続中のセッション数に合わせてキャプチャを開始・停止する
+#[derive(Clone)]
+pub struct OnDemandCapture {
+    capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+    target: Arc<CaptureTargetFn>,
+    /// 音声も視聴中だけ動かす場合の送り先（None なら音声は接続と関係なく動かす）
+    audio: Option<OnDemandAudio>,
+    /// 接続中のセッション数（開始と停止の指示が入れ替わらないよう、送り終えるまでロックする）
+    sessions: Arc<Mutex<usize>>,
+}
+
+impl OnDemandCapture {
+    /// `target` は開始のたびに呼ぶ（クライアントが対象を切り替えていれば切り替え先を返す）
+    pub fn new(
+        capture_cmd_tx: mpsc::Sender<CaptureMessage>,
+        target: impl Fn() -> Option<CaptureTarget> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            capture_cmd_tx,
+            target: Arc::new(target),
+            audio: None,
+            sessions: Arc::new(Mutex::new(0)),
+        }
+    }
+
+    /// 音声のキャプチャとエンコードも視聴中だけ動かす
+    /// `start` は開始のたびに呼ぶ（キャプチャ対象のウィンドウが変わっていれば、その音声を返す）
+    pub fn with_audio(
+        mut self,
+        audio_capture_cmd_tx: mpsc::Sender<AudioCaptureMessage>,
+        audio_stream_msg_tx: mpsc::Sender<AudioStreamMessage>,
+        start: impl Fn() -> Option<AudioCaptureMessage> + Send + Sync + 'static,
+    ) -> Self {
+        self.audio = Some(OnDemandAudio {
+            audio_capture_cmd_tx,
+            audio_stream_msg_tx,
+            start: Arc::new(start),
+        });
+        self
+    }
+
+    /// セッションが繋がった（最初のセッションならキャプチャを開始する）
+    pub async fn session_started(&self) {
+        let mut sessions = self.sessions.lock().await;
+        *sessions += 1;
+        if *sessions > 1 {
+            return;
+        }
+        if let Some(audio) = &self.audio {
+            audio.resume().await;
+        }
+        let Some(target) = (self.target)() else {
+            info!("Viewer connected; waiting for client to select a capture target");
+            return;
+        };
+        info!("Viewer connected, starting capture for {:?}", target);
+        if self
+            .capture_cmd_tx
+            .send(CaptureMessage::Start { target })
+            .await
+            .is_err()
+        {
+            warn!("Failed to start capture: receiver dropped");
+        }
+    }
+
+    /// セッションが切れた（最後のセッションならキャプチャを止める）
+    pub async fn session_ended(&self) {
+        let mut sessions = self.sessions.lock().await;
+        if *sessions == 0 {
+            return;
+        }
+        *sessions -= 1;
+        if *sessions > 0 {
+            return;
+        }
+        info!("Last viewer disconnected, stopping capture");
+        if self
+            .capture_cmd_tx
+            .send(CaptureMessage::Stop)
+            .await
+            .is_err()
+        {
+            warn!("Failed to stop capture: receiver dropped");
+        }
+        if let Some(audio) = &self.audio {
+            audio.pause().await;
+        }
+    }
+}
+
+impl OnDemandAudio {
+    async fn resume(&self) {
+        if let Some(start) = (self.start)() {
+            if self.audio_capture_cmd_tx.send(start).await.is_err() {
+                warn!("Failed to start audio capture: receiver dropped");
+            }
+        }
+        if self
+            .audio_stream_msg_tx
+            .send(AudioStreamMessage::Resume)
+            .await
+            .is_err()
+        {
+            warn!("Failed to resume audio stream: receiver dropped");
+        }
+    }
+
+    async fn pause(&self) {
+        if self
+            .audio_capture_cmd_tx
+            .send(AudioCaptureMessage::Stop)
+            .await
+            .is_err()
+        {
+            warn!("Failed to stop audio capture: receiver dropped");
+        }
+        if self
+            .audio_stream_msg_tx
+            .send(AudioStreamMessage::Pause)
+            .await
+            .is_err()
+        {
+            warn!("Failed to pause audio stream: receiver dropped");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_starts_on_first_session_and_stops_after_last() {
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let on_demand = OnDemandCapture::new(capture_cmd_tx, || Some(CaptureTarget::Window(42)));
+
+        on_demand.session_started().await;
+        on_demand.session_started().await;
+        on_demand.session_ended().await;
+        assert!(matches!(
+            capture_cmd_rx.try_recv(),
+            Ok(CaptureMessage::Start {
+                target: CaptureTarget::Window(42)
+            })
+        ));
+        // 2 つ目の接続では開始せず、1 つ目が切れても残りがいれば止めない
+        assert!(capture_cmd_rx.try_recv().is_err());
+
+        on_demand.session_ended().await;
+        assert!(matches!(
+            capture_cmd_rx.try_recv(),
+            Ok(CaptureMessage::Stop)
+        ));
+        // 数え過ぎた切断は無視する
+        on_demand.session_ended().await;
+        assert!(capture_cmd_rx.try_recv().is_err());
+
+        // 音声もキャプチャと一緒に開始・停止し、止めている間はエンコードも一時停止する
+        let (capture_cmd_tx, _capture_cmd_rx) = mpsc::channel(10);
+        let (audio_capture_cmd_tx, mut audio_capture_cmd_rx) = mpsc::channel(10);
+        let (audio_stream_msg_tx, mut audio_stream_msg_rx) = mpsc::channel(10);
+        let on_demand = OnDemandCapture::new(capture_cmd_tx, || Some(CaptureTarget::Window(42)))
+            .with_audio(audio_capture_cmd_tx, audio_stream_msg_tx, || {
+                Some(AudioCaptureMessage::Start { hwnd: 42 })
+            });
+        on_demand.session_started().await;
+        assert!(matches!(
+            audio_capture_cmd_rx.try_recv(),
+            Ok(AudioCaptureMessage::Start { hwnd: 42 })
+        ));
+        assert!(matches!(
+            audio_stream_msg_rx.try_recv(),
+            Ok(AudioStreamMessage::Resume)
+        ));
+        on_demand.session_ended().await;
+        assert!(matches!(
+            audio_capture_cmd_rx.try_recv(),
+            Ok(AudioCaptureMessage::Stop)
+        ));
+        assert!(matches!(
+            audio_stream_msg_rx.try_recv(),
+            Ok(AudioStreamMessage::Pause)
+        ));
+
+        // 対象が決まっていなければ開始しない
+        let (capture_cmd_tx, mut capture_cmd_rx) = mpsc::channel(10);
+        let on_demand = OnDemandCapture::new(capture_cmd_tx, || None);
+        on_demand.session_started().await;
+        assert!(capture_cmd_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,136 @@
+// 送出パケットの DSCP マーキング（QoS）
+//
+// webrtc-rs は ICE の UDP ソケットを内部で作るため、ソケットオプションを直接設定できない。
+// そこで DSCP を設定した UDP ソケットを自前で作り、UDPMux として SettingEngine に渡す
+// （ホスト候補はすべてこのソケットを共有し、映像・音声・DataChannel が同じ DSCP で送られる）。
+//
+// 制限:
+// - STUN で得る srflx 候補は webrtc-rs が別のソケットを作るため、DSCP は付かない
+//   （LAN 内の接続で選ばれるホスト候補には付く）
+// - UDPMux は IPv4 の 1 ソケットなので、DSCP を指定した場合は IPv6 の候補を集めない
+// - Windows は IP_TOS の設定を既定で無視する。DSCP を実際に付けるには、グループポリシーの
+//   「ポリシーベースの QoS」で hostd.exe に DSCP 値を設定するか（管理者権限が必要）、
+//   レジストリ HKLM\SYSTEM\CurrentControlSet\Services\Tcpip\QoS の "Do not use NLA" を 1 にして
+//   ドメイン外のネットワークにもポリシーを適用させる。qWAVE（QOSSetFlow）による DSCP 設定も
+//   管理者権限が必要で、宛先ごとのフロー登録が要るため UDPMux の未接続ソケットには使えない
+// - ファイアウォールは DSCP を変更しないが、途中のスイッチ・ルーターが信頼しない
+//   （マーキングを消す）設定になっていることが多いので、ネットワーク側の設定も必要
+
+use anyhow::{Context, Result};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use webrtc_rs::ice::udp_mux::{UDPMuxDefault, UDPMuxParams};
+
+/// DSCP 値（0-63）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+impl Dscp {
+    /// Expedited Forwarding（リアルタイムの音声・映像向け）
+    pub const EF: Dscp = Dscp(46);
+
+    pub fn new(value: u8) -> Option<Self> {
+        (value < 64).then_some(Self(value))
+    }
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+
+    /// IP ヘッダの TOS バイト（上位 6bit が DSCP、下位 2bit は ECN）
+    fn tos(self) -> u32 {
+        (self.0 as u32) << 2
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = String;
+
+    /// 数値（0-63）か名前（"ef", "af11"-"af43", "cs0"-"cs7"）を受け付ける
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let invalid = || {
+            format!(
+                "Invalid DSCP: {} (expected 0-63, \"ef\", \"afXY\" or \"csN\")",
+                s
+            )
+        };
+        if s == "ef" {
+            return Ok(Self::EF);
+        }
+        if let Some(class) = s.strip_prefix("cs") {
+            let class: u8 = class.parse().map_err(|_| invalid())?;
+            return (class <= 7).then_some(Self(class << 3)).ok_or_else(invalid);
+        }
+        if let Some(af) = s.strip_prefix("af") {
+            let digits: Vec<u8> = af
+                .chars()
+                .map(|c| c.to_digit(10).map(|d| d as u8))
+                .collect::<Option<_>>()
+                .ok_or_else(invalid)?;
+            return match digits[..] {
+                [class @ 1..=4, drop @ 1..=3] => Ok(Self((class << 3) | (drop << 1))),
+                _ => Err(invalid()),
+            };
+        }
+        s.parse::<u8>().ok().and_then(Self::new).ok_or_else(invalid)
+    }
+}
+
+/// DSCP を設定した UDP ソケットで UDPMux を作る（tokio のランタイム上で呼ぶ）
+pub(crate) fn create_dscp_udp_mux(dscp: Dscp) -> Result<Arc<UDPMuxDefault>> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+        .context("Failed to create UDP socket")?;
+    socket
+        .set_tos(dscp.tos())
+        .with_context(|| format!("Failed to set DSCP {} on UDP socket", dscp.value()))?;
+    socket
+        .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())
+        .context("Failed to bind UDP socket")?;
+    socket
+        .set_nonblocking(true)
+        .context("Failed to make UDP socket non-blocking")?;
+
+    let socket = tokio::net::UdpSocket::from_std(socket.into())
+        .context("Failed to register UDP socket with tokio")?;
+    Ok(UDPMuxDefault::new(UDPMuxParams::new(socket)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_dscp() {
+        assert_eq!("ef".parse(), Ok(Dscp::EF));
+        assert_eq!("EF".parse(), Ok(Dscp(46)));
+        assert_eq!("af41".parse(), Ok(Dscp(34)));
+        assert_eq!("af11".parse(), Ok(Dscp(10)));
+        assert_eq!("cs5".parse(), Ok(Dscp(40)));
+        assert_eq!("cs0".parse(), Ok(Dscp(0)));
+        assert_eq!("26".parse(), Ok(Dscp(26)));
+
+        assert!("64".parse::<Dscp>().is_err());
+        assert!("af51".parse::<Dscp>().is_err());
+        assert!("af4".parse::<Dscp>().is_err());
+        assert!("cs8".parse::<Dscp>().is_err());
+        assert!("fast".parse::<Dscp>().is_err());
+    }
+
+    #[test]
+    fn test_tos_byte() {
+        assert_eq!(Dscp::EF.tos(), 0xb8);
+        assert_eq!(Dscp(0).tos(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_create_dscp_udp_mux() {
+        use webrtc_rs::ice::udp_mux::UDPMux;
+
+        let mux = create_dscp_udp_mux(Dscp::EF).expect("UDP mux should be created");
+        mux.close().await.expect("UDP mux should close");
+    }
+}
@@ -0,0 +1,171 @@
+// ビューアーのセッション管理（再接続時の引き継ぎ）
+//
+// モバイル回線などで接続が切れたビューアーは、同じ negotiation_id で Offer を送り直してくる。
+// （シグナリングの session_id はホストごとに 1 つで全ビューアーが共有するため、別のビューアーと区別できない）
+// 既知のセッションならピア接続だけを差し替え、ネゴシエート済みのコーデックを引き継ぐ
+// （エンコーダーを作り直さず、トラックの差し替えで要求されるキーフレームから送り直す）。
+// 接続していない状態で一定時間経ったセッションは捨てる。
+
+use core_types::VideoCodec;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// 接続していないセッションを引き継げる時間
+pub const SESSION_TTL: Duration = Duration::from_secs(300);
+
+/// 引き継いだセッションの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumedSession {
+    /// 前回ネゴシエートしたコーデック
+    pub codec: VideoCodec,
+    /// 引き継いだ回数（今回を含む）
+    pub resumes: u32,
+}
+
+#[derive(Debug)]
+struct Session {
+    /// ネゴシエート済みのコーデック（Answer を返すまでは None）
+    codec: Option<VideoCodec>,
+    /// 最後に接続していた時刻（接続中のセッションは使わない）
+    last_seen: Instant,
+    resumes: u32,
+}
+
+#[derive(Debug)]
+pub struct SessionTable {
+    sessions: HashMap<String, Session>,
+    /// 接続中のセッション
+    active: Option<String>,
+    ttl: Duration,
+}
+
+impl SessionTable {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            active: None,
+            ttl,
+        }
+    }
+
+    /// Offer を受け取った。既知のセッションなら引き継ぐ状態を返す
+    /// negotiation_id のない Offer は毎回新しいセッションとして扱う
+    pub fn begin(&mut self, negotiation_id: Option<&str>, now: Instant) -> Option<ResumedSession> {
+        // 接続中だったセッションは差し替えられるので、ここから TTL を数える
+        if let Some(previous) = self.active.take() {
+            if let Some(session) = self.sessions.get_mut(&previous) {
+                session.last_seen = now;
+            }
+        }
+        self.prune(now);
+
+        let negotiation_id = negotiation_id?;
+        self.active = Some(negotiation_id.to_string());
+        match self.sessions.get_mut(negotiation_id) {
+            Some(session) => {
+                session.resumes += 1;
+                session.codec.map(|codec| ResumedSession {
+                    codec,
+                    resumes: session.resumes,
+                })
+            }
+            None => {
+                self.sessions.insert(
+                    negotiation_id.to_string(),
+                    Session {
+                        codec: None,
+                        last_seen: now,
+                        resumes: 0,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    /// 接続中のセッションのコーデックを記録する（ネゴシエーション・コーデック切り替えの後）
+    pub fn set_codec(&mut self, codec: VideoCodec) {
+        if let Some(session) = self
+            .active
+            .as_ref()
+            .and_then(|id| self.sessions.get_mut(id))
+        {
+            session.codec = Some(codec);
+        }
+    }
+
+    /// 接続中のセッションの確立に失敗した（引き継げる状態がないので捨てる）
+    pub fn abort(&mut self) {
+        if let Some(id) = self.active.take() {
+            self.sessions.remove(&id);
+        }
+    }
+
+    /// TTL を過ぎたセッションを捨てる
+    fn prune(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.sessions
+            .retain(|_, session| now.duration_since(session.last_seen) < ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resumes_known_session_with_its_codec() {
+        let mut table = SessionTable::new(SESSION_TTL);
+        let now = Instant::now();
+
+        assert_eq!(table.begin(Some("viewer"), now), None);
+        // Answer を返す前に送り直された Offer は引き継ぐ状態がない
+        assert_eq!(
+            table.begin(Some("viewer"), now + Duration::from_secs(1)),
+            None
+        );
+        table.set_codec(VideoCodec::H264);
+
+        // 回線が切れて同じ negotiation_id で Offer が届いた
+        assert_eq!(
+            table.begin(Some("viewer"), now + Duration::from_secs(10)),
+            Some(ResumedSession {
+                codec: VideoCodec::H264,
+                resumes: 2,
+            })
+        );
+
+        // negotiation_id のない Offer は引き継がない
+        assert_eq!(table.begin(None, now + Duration::from_secs(11)), None);
+        assert_eq!(table.sessions.len(), 1);
+
+        // 確立に失敗したセッションは次の Offer で新しく始める
+        assert_eq!(table.begin(Some("other"), now), None);
+        table.abort();
+        assert_eq!(table.begin(Some("other"), now), None);
+    }
+
+    #[test]
+    fn test_prunes_stale_sessions() {
+        let mut table = SessionTable::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        table.begin(Some("a"), now);
+        table.set_codec(VideoCodec::H264);
+        // 接続中のセッションは TTL を過ぎても残る
+        table.begin(Some("b"), now + Duration::from_secs(30));
+        table.set_codec(VideoCodec::H264);
+        assert_eq!(table.sessions.len(), 2);
+
+        // a は b に差し替えられてから 60 秒経ったので捨てる。b はまだ接続中
+        assert_eq!(
+            table.begin(Some("b"), now + Duration::from_secs(200)),
+            Some(ResumedSession {
+                codec: VideoCodec::H264,
+                resumes: 1,
+            })
+        );
+        assert_eq!(table.sessions.len(), 1);
+        assert_eq!(table.begin(Some("a"), now + Duration::from_secs(201)), None);
+    }
+}